@@ -0,0 +1,84 @@
+//! Checks that a halted vCPU is parked rather than spinning, and that the ticks of its timer are
+//! delivered on time, with the guest of `guest/idle`: the CPU of its vCPU has to spend most of
+//! the run parked, and resume within [`MAX_JITTER_NS`] of each timer deadline. It prints the host
+//! CPU use of the idle guest, the share of the run its CPU was not parked. Built with the
+//! `idle-test` feature, after the guest and the NimbOS BIOS:
+//!
+//! ```sh
+//! make -C apps/hv/guest/nimbos/bios && make -C apps/hv/guest/idle
+//! make A=apps/hv ARCH=x86_64 HV=y APP_FEATURES=idle-test run
+//! ```
+
+use axvm::{VcpuDeviceConfig, VmBuilder, VmExit};
+use libax::time::Instant;
+
+static BIOS: &[u8] = include_bytes!("../guest/nimbos/bios/out/rvm-bios.bin");
+static GUEST: &[u8] = include_bytes!("../guest/idle/out/idle.bin");
+
+/// Where rvm-bios is loaded and entered, and where it enters the kernel.
+const BIOS_GPA: usize = 0x8000;
+const KERNEL_GPA: usize = 0x20_0000;
+/// The core the vCPU runs on.
+const CPU: usize = 0;
+/// The ticks `guest/idle/idle.S` takes before shutting down, with their count as its exit code,
+/// and their period.
+const TICKS: u64 = 100;
+const TICK_NS: u64 = 10_000_000;
+/// Share of the run the vCPU has to spend parked, in percent.
+const MIN_PARKED_PERCENT: u64 = 90;
+/// Bound of the delay between a timer deadline and the vCPU resuming.
+const MAX_JITTER_NS: u64 = 1_000_000;
+
+pub fn run() {
+    let vm = VmBuilder::new("idle")
+        .memory_mb(16)
+        .load_image(BIOS_GPA, BIOS)
+        .load_image(KERNEL_GPA, GUEST)
+        .entry(BIOS_GPA)
+        .vcpu_affinity(&[CPU], false)
+        .vcpu_devices(VcpuDeviceConfig::empty().with_virtual_apic())
+        .build()
+        .expect("failed to build the idle VM");
+    let before = axvm::park_stats(CPU);
+    let start = Instant::now();
+    let exit = vm
+        .spawn()
+        .and_then(|handle| handle.wait())
+        .expect("the idle VM failed to boot");
+    let elapsed_ns = start.elapsed().as_nanos() as u64;
+    let stats = axvm::park_stats(CPU);
+    assert_eq!(exit, VmExit::Shutdown(TICKS));
+    assert!(
+        elapsed_ns >= TICKS * TICK_NS,
+        "{} ns for {} ticks",
+        elapsed_ns,
+        TICKS
+    );
+
+    let parked_ns = stats.parked_ns - before.parked_ns;
+    let timeouts = stats.timeouts - before.timeouts;
+    let max_timer_jitter_ns = stats.max_timer_jitter_ns;
+    let busy_ns = elapsed_ns.saturating_sub(parked_ns);
+    println!(
+        "idle test: parked {} of {} ns, {} timeouts, timer jitter up to {} ns",
+        parked_ns, elapsed_ns, timeouts, max_timer_jitter_ns
+    );
+    println!(
+        "idle test: host CPU use {}.{:02}%",
+        busy_ns * 100 / elapsed_ns,
+        busy_ns * 10_000 / elapsed_ns % 100
+    );
+    assert!(
+        parked_ns * 100 >= elapsed_ns * MIN_PARKED_PERCENT,
+        "the halted vCPU spun: parked {} of {} ns",
+        parked_ns,
+        elapsed_ns
+    );
+    // Each tick ends a park, unless the vCPU was still in the guest at its deadline.
+    assert!(timeouts >= TICKS / 2, "only {} parks timed out", timeouts);
+    assert!(
+        max_timer_jitter_ns <= MAX_JITTER_NS,
+        "ticks delivered up to {} ns late",
+        max_timer_jitter_ns
+    );
+}
@@ -5,7 +5,15 @@ extern crate alloc;
 #[macro_use]
 extern crate libax;
 
+#[cfg(feature = "idle-test")]
+mod idle_test;
 mod linux;
+#[cfg(feature = "pio-bench")]
+mod pio_bench;
+#[cfg(feature = "spawn-test")]
+mod spawn_test;
+#[cfg(feature = "timer-jitter")]
+mod timer_jitter;
 
 #[cfg(feature = "type1_5")]
 #[no_mangle]
@@ -23,9 +31,22 @@ fn main() {
     println!("Hello, hv!");
     println!("Currently Linux inside VM is pinned on Core 0");
     // linux::boot_linux(0);
+    // The guests are booted and managed from the host console.
+    #[cfg(target_arch = "x86_64")]
+    axvm::spawn_shell();
+    #[cfg(feature = "spawn-test")]
+    spawn_test::run();
+    #[cfg(feature = "idle-test")]
+    idle_test::run();
+    #[cfg(feature = "pio-bench")]
+    pio_bench::run();
+    #[cfg(feature = "timer-jitter")]
+    timer_jitter::run();
 
     loop {
         libax::thread::sleep(libax::time::Duration::from_secs(1));
+        // Not printing a tick, which would break into the lines typed in the shell.
+        #[cfg(not(target_arch = "x86_64"))]
         println!("main tick");
     }
 }
@@ -0,0 +1,58 @@
+//! Counts the VMREADs of a port I/O exit, and its time, with the guest of `guest/pio_loop`
+//! writing a port of its COM1 in a tight loop. Built with the `pio-bench` feature, after the
+//! guest and the NimbOS BIOS:
+//!
+//! ```sh
+//! make -C apps/hv/guest/nimbos/bios && make -C apps/hv/guest/pio_loop
+//! make A=apps/hv ARCH=x86_64 HV=y APP_FEATURES=pio-bench run
+//! ```
+//!
+//! The VMREADs counted are the ones of the exit handlers of axvm, see [`axvm::vmcs_read_stats`]:
+//! the ones of hypercraft, which reads the exit reason, RIP and instruction length of every exit,
+//! are not.
+
+use axvm::{VmBuilder, VmExit};
+use libax::time::Instant;
+
+static BIOS: &[u8] = include_bytes!("../guest/nimbos/bios/out/rvm-bios.bin");
+static GUEST: &[u8] = include_bytes!("../guest/pio_loop/out/pio_loop.bin");
+
+/// Where rvm-bios is loaded and entered, and where it enters the kernel.
+const BIOS_GPA: usize = 0x8000;
+const KERNEL_GPA: usize = 0x20_0000;
+/// The core the vCPU runs on.
+const CPU: usize = 0;
+/// The port writes of `guest/pio_loop/pio_loop.S`, and its exit code.
+const ITERATIONS: u64 = 100_000;
+
+pub fn run() {
+    let vm = VmBuilder::new("pio_loop")
+        .memory_mb(16)
+        .load_image(BIOS_GPA, BIOS)
+        .load_image(KERNEL_GPA, GUEST)
+        .entry(BIOS_GPA)
+        .vcpu_affinity(&[CPU], false)
+        .build()
+        .expect("failed to build the pio_loop VM");
+    let before = axvm::vmcs_read_stats(CPU);
+    let start = Instant::now();
+    let exit = vm
+        .spawn()
+        .and_then(|handle| handle.wait())
+        .expect("the pio_loop VM failed to boot");
+    let elapsed_ns = start.elapsed().as_nanos() as u64;
+    let stats = axvm::vmcs_read_stats(CPU);
+    assert_eq!(exit, VmExit::Shutdown(ITERATIONS));
+
+    // The port writes, and the few exits of the boot of the guest.
+    let exits = stats.exits - before.exits;
+    let reads = stats.vmcs_reads - before.vmcs_reads;
+    assert!(exits >= ITERATIONS, "only {} exits", exits);
+    println!(
+        "pio bench: {} exits, {}.{:02} VMREADs and {} ns per exit, boot included",
+        exits,
+        reads / exits,
+        reads * 100 / exits % 100,
+        elapsed_ns / exits
+    );
+}
@@ -0,0 +1,34 @@
+//! Checks that [`axvm::spawn`] returns at once and that [`axvm::VmJoinHandle::wait`] returns the
+//! exit code of the guest, with the guest of `guest/exitcode`. Built with the `spawn-test`
+//! feature, after the guest and the NimbOS BIOS:
+//!
+//! ```sh
+//! make -C apps/hv/guest/nimbos/bios && make -C apps/hv/guest/exitcode
+//! make A=apps/hv ARCH=x86_64 HV=y APP_FEATURES=spawn-test run
+//! ```
+
+use axvm::{VmBuilder, VmExit};
+
+static BIOS: &[u8] = include_bytes!("../guest/nimbos/bios/out/rvm-bios.bin");
+static GUEST: &[u8] = include_bytes!("../guest/exitcode/out/exitcode.bin");
+
+/// Where rvm-bios is loaded and entered, and where it enters the kernel.
+const BIOS_GPA: usize = 0x8000;
+const KERNEL_GPA: usize = 0x20_0000;
+/// The exit code of `guest/exitcode/exitcode.S`.
+const EXIT_CODE: u64 = 42;
+
+pub fn run() {
+    let vm = VmBuilder::new("exitcode")
+        .memory_mb(16)
+        .load_image(BIOS_GPA, BIOS)
+        .load_image(KERNEL_GPA, GUEST)
+        .entry(BIOS_GPA)
+        .build()
+        .expect("failed to build the exitcode VM");
+    let handle = vm.spawn().expect("failed to spawn the exitcode VM");
+    let exit = handle.wait().expect("the exitcode VM failed to boot");
+    assert_eq!(exit, VmExit::Shutdown(EXIT_CODE));
+    assert!(matches!(handle.try_wait(), Some(Ok(e)) if e == exit));
+    println!("spawn test: VM [{}] exited with {:?}", handle.id(), exit);
+}
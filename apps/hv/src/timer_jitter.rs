@@ -0,0 +1,69 @@
+//! Measures how late the ticks of a 1 kHz guest timer are injected when the guest does not exit
+//! on its own, with the guest of `guest/timer_jitter` spinning between its ticks: only the
+//! VMX-preemption timer armed for the next deadline makes its vCPU exit in time. Built with the
+//! `timer-jitter` feature, after the guest and the NimbOS BIOS:
+//!
+//! ```sh
+//! make -C apps/hv/guest/nimbos/bios && make -C apps/hv/guest/timer_jitter
+//! make A=apps/hv ARCH=x86_64 HV=y APP_FEATURES=timer-jitter run
+//! ```
+//!
+//! The guest measures the delays itself and exits with the largest one. The ticks too late to be
+//! measured are skipped by the timer, and lengthen the run instead.
+
+use axvm::{VcpuDeviceConfig, VmBuilder, VmExit};
+use libax::time::Instant;
+
+static BIOS: &[u8] = include_bytes!("../guest/nimbos/bios/out/rvm-bios.bin");
+static GUEST: &[u8] = include_bytes!("../guest/timer_jitter/out/timer_jitter.bin");
+
+/// Where rvm-bios is loaded and entered, and where it enters the kernel.
+const BIOS_GPA: usize = 0x8000;
+const KERNEL_GPA: usize = 0x20_0000;
+/// The core the vCPU runs on.
+const CPU: usize = 0;
+/// The ticks `guest/timer_jitter/timer_jitter.S` takes before shutting down, and their period.
+const TICKS: u64 = 1000;
+const TICK_NS: u64 = 1_000_000;
+/// Bound of the delay between a deadline of the timer and the guest taking its interrupt.
+const MAX_JITTER_NS: u64 = 200_000;
+
+pub fn run() {
+    let vm = VmBuilder::new("timer_jitter")
+        .memory_mb(16)
+        .load_image(BIOS_GPA, BIOS)
+        .load_image(KERNEL_GPA, GUEST)
+        .entry(BIOS_GPA)
+        .vcpu_affinity(&[CPU], false)
+        .vcpu_devices(VcpuDeviceConfig::empty().with_virtual_apic())
+        .build()
+        .expect("failed to build the timer_jitter VM");
+    let start = Instant::now();
+    let exit = vm
+        .spawn()
+        .and_then(|handle| handle.wait())
+        .expect("the timer_jitter VM failed to boot");
+    let elapsed_ns = start.elapsed().as_nanos() as u64;
+    let max_delay_ns = match exit {
+        VmExit::Shutdown(max_delay_ns) => max_delay_ns,
+        exit => panic!("the timer_jitter VM exited with {:?}", exit),
+    };
+
+    // The boot of the guest is counted as missed ticks, they are a bound.
+    let missed = (elapsed_ns / TICK_NS).saturating_sub(TICKS);
+    println!(
+        "timer jitter: {} ticks of {} ns in {} ns, up to {} missed, delivered up to {} ns late",
+        TICKS, TICK_NS, elapsed_ns, missed, max_delay_ns
+    );
+    assert!(
+        max_delay_ns <= MAX_JITTER_NS,
+        "ticks delivered up to {} ns late",
+        max_delay_ns
+    );
+    assert!(
+        missed <= TICKS / 100,
+        "{} of {} ticks missed, boot included",
+        missed,
+        TICKS
+    );
+}
@@ -0,0 +1,234 @@
+//! Guest side of the console over hypercalls of the ArceOS hypervisor, for minimal x86_64 guests
+//! without a UART driver.
+//!
+//! Hypercalls are `vmcall` with the ID in `rax` and the arguments in `rbx`, `rcx` and `rdx`, the
+//! result being returned in `rax`. The IDs must match `modules/axvm/src/hvc.rs`.
+//!
+//! ```ignore
+//! static CONSOLE: Mutex<HvcConsole> = Mutex::new(HvcConsole::new(|va| va));
+//!
+//! writeln!(CONSOLE.lock(), "hello from the guest")?;
+//! let mut line = [0; 64];
+//! if CONSOLE.lock().poll(INPUT_VECTOR) == 0 {
+//!     // Wait for the interrupt at `INPUT_VECTOR`.
+//! }
+//! let len = CONSOLE.lock().read(&mut line);
+//! ```
+//!
+//! The crate is empty on other architectures.
+
+#![cfg_attr(not(test), no_std)]
+#![cfg(target_arch = "x86_64")]
+
+#[cfg(not(test))]
+use core::arch::asm;
+use core::fmt;
+
+pub const HVC_CONSOLE_WRITE: usize = 0x112;
+pub const HVC_CONSOLE_READ: usize = 0x113;
+pub const HVC_CONSOLE_POLL: usize = 0x114;
+/// Longest buffer of one `HVC_CONSOLE_WRITE` or `HVC_CONSOLE_READ`.
+pub const HVC_CONSOLE_IO_MAX: usize = 256;
+
+/// Issue hypercall `id`.
+///
+/// # Safety
+///
+/// The arguments must be valid for `id`, guest physical addresses must point to memory the
+/// hypervisor may access.
+#[cfg(not(test))]
+pub unsafe fn hypercall(id: usize, a0: usize, a1: usize, a2: usize) -> u32 {
+    let ret: usize;
+    // rbx is reserved by LLVM, it is swapped with the register holding the first argument.
+    asm!(
+        "xchg {a0}, rbx",
+        "vmcall",
+        "xchg {a0}, rbx",
+        a0 = inout(reg) a0 => _,
+        inout("rax") id => ret,
+        in("rcx") a1,
+        in("rdx") a2,
+        options(nostack),
+    );
+    ret as u32
+}
+
+/// The hypercalls of the tests are handled by the emulated hypervisor of [`tests`].
+///
+/// # Safety
+///
+/// As for the hypercalls of a guest.
+#[cfg(test)]
+pub unsafe fn hypercall(id: usize, a0: usize, a1: usize, a2: usize) -> u32 {
+    tests::handle_hypercall(id, a0, a1, a2)
+}
+
+/// The bounce buffer of the hypercalls, aligned on its size so that it never crosses a page.
+#[repr(C, align(256))]
+struct Buffer([u8; HVC_CONSOLE_IO_MAX]);
+
+pub struct HvcConsole {
+    buf: Buffer,
+    /// Translates the address of `buf` to a guest physical address.
+    virt_to_phys: fn(usize) -> usize,
+}
+
+impl HvcConsole {
+    /// The console of a guest whose virtual addresses are translated by `virt_to_phys`,
+    /// `|va| va` for an identity mapped guest.
+    pub const fn new(virt_to_phys: fn(usize) -> usize) -> Self {
+        Self {
+            buf: Buffer([0; HVC_CONSOLE_IO_MAX]),
+            virt_to_phys,
+        }
+    }
+
+    fn buf_gpa(&self) -> usize {
+        (self.virt_to_phys)(self.buf.0.as_ptr() as usize)
+    }
+
+    /// Write all of `bytes`, in batches of [`HVC_CONSOLE_IO_MAX`] bytes.
+    pub fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(HVC_CONSOLE_IO_MAX) {
+            self.buf.0[..chunk.len()].copy_from_slice(chunk);
+            unsafe { hypercall(HVC_CONSOLE_WRITE, self.buf_gpa(), chunk.len(), 0) };
+        }
+    }
+
+    /// Read the input ready for this VM into `bytes`, without blocking. Returns the number of
+    /// bytes read, 0 if there is none.
+    pub fn read(&mut self, bytes: &mut [u8]) -> usize {
+        let len = bytes.len().min(HVC_CONSOLE_IO_MAX);
+        let read = unsafe { hypercall(HVC_CONSOLE_READ, self.buf_gpa(), len, 0) } as usize;
+        // Hypervisors without the console return the hypercall ID.
+        if read > len {
+            return 0;
+        }
+        bytes[..read].copy_from_slice(&self.buf.0[..read]);
+        read
+    }
+
+    /// Returns the number of bytes ready to be read. If there is none and `vector` is not 0, the
+    /// interrupt `vector` is raised once input arrives, after which `poll` rearms it. `poll(0)`
+    /// disarms it.
+    pub fn poll(&mut self, vector: u8) -> usize {
+        unsafe { hypercall(HVC_CONSOLE_POLL, vector as usize, 0, 0) as usize }
+    }
+}
+
+impl fmt::Write for HvcConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::fmt::Write;
+    use std::vec::Vec;
+
+    /// The host side of the console of `modules/axvm/src/hvc_console.rs`, the guest physical
+    /// addresses being those of the test process.
+    #[derive(Default)]
+    struct Hypervisor {
+        /// Whether it has the console, the hypercall ID being returned otherwise.
+        console: bool,
+        output: Vec<u8>,
+        /// The length of each `HVC_CONSOLE_WRITE`.
+        writes: Vec<usize>,
+        input: VecDeque<u8>,
+        vector: Option<u8>,
+    }
+
+    thread_local! {
+        static HYPERVISOR: RefCell<Hypervisor> = RefCell::new(Hypervisor {
+            console: true,
+            ..Default::default()
+        });
+    }
+
+    pub(super) fn handle_hypercall(id: usize, a0: usize, a1: usize, _a2: usize) -> u32 {
+        HYPERVISOR.with(|hv| {
+            let mut hv = hv.borrow_mut();
+            if !hv.console {
+                return id as u32;
+            }
+            match id {
+                HVC_CONSOLE_WRITE | HVC_CONSOLE_READ => {
+                    assert!(a1 <= HVC_CONSOLE_IO_MAX);
+                    // The buffer must not cross a page.
+                    assert_eq!(a0 / 4096, (a0 + a1.max(1) - 1) / 4096);
+                    let buf = unsafe { core::slice::from_raw_parts_mut(a0 as *mut u8, a1) };
+                    if id == HVC_CONSOLE_WRITE {
+                        hv.output.extend_from_slice(buf);
+                        hv.writes.push(a1);
+                        a1 as u32
+                    } else {
+                        let len = a1.min(hv.input.len());
+                        for b in &mut buf[..len] {
+                            *b = hv.input.pop_front().unwrap();
+                        }
+                        len as u32
+                    }
+                }
+                HVC_CONSOLE_POLL => {
+                    hv.vector = (a0 != 0 && hv.input.is_empty()).then_some(a0 as u8);
+                    hv.input.len() as u32
+                }
+                _ => id as u32,
+            }
+        })
+    }
+
+    fn with_hypervisor<T>(f: impl FnOnce(&mut Hypervisor) -> T) -> T {
+        HYPERVISOR.with(|hv| f(&mut hv.borrow_mut()))
+    }
+
+    #[test]
+    fn test_write_in_batches() {
+        let mut console = HvcConsole::new(|va| va);
+        let bytes: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        console.write(&bytes);
+        console.write_str("hello 42").unwrap();
+        with_hypervisor(|hv| {
+            assert_eq!(hv.writes, [256, 256, 88, 8]);
+            assert_eq!(&hv.output[..600], &bytes[..]);
+            assert_eq!(&hv.output[600..], b"hello 42");
+        });
+    }
+
+    #[test]
+    fn test_read_and_poll() {
+        let mut console = HvcConsole::new(|va| va);
+        let mut line = [0; 4];
+        assert_eq!(console.poll(0x40), 0);
+        with_hypervisor(|hv| {
+            assert_eq!(hv.vector, Some(0x40));
+            hv.input.extend(b"abcdef");
+        });
+        assert_eq!(console.poll(0x40), 6);
+        with_hypervisor(|hv| assert_eq!(hv.vector, None));
+        assert_eq!(console.read(&mut line), 4);
+        assert_eq!(&line, b"abcd");
+        assert_eq!(console.read(&mut line), 2);
+        assert_eq!(&line[..2], b"ef");
+        assert_eq!(console.read(&mut line), 0);
+    }
+
+    #[test]
+    fn test_hypervisor_without_console() {
+        with_hypervisor(|hv| {
+            hv.console = false;
+            hv.input.extend(b"ignored");
+        });
+        let mut console = HvcConsole::new(|va| va);
+        let mut line = [0; 64];
+        // The hypercall ID is returned, which is more than the buffer.
+        assert_eq!(console.read(&mut line), 0);
+        assert_eq!(line, [0; 64]);
+    }
+}
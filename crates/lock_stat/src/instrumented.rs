@@ -0,0 +1,394 @@
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+/// Maximum number of lock classes, the classes created after the table is full
+/// share a slot with another class.
+const MAX_CLASSES: usize = 128;
+
+/// Statistics shared by all locks created at the same call site.
+struct LockClass {
+    site: AtomicPtr<Location<'static>>,
+    acquisitions: AtomicU64,
+    contentions: AtomicU64,
+    max_hold_cycles: AtomicU64,
+    max_hold_site: AtomicPtr<Location<'static>>,
+}
+
+impl LockClass {
+    const fn new() -> Self {
+        Self {
+            site: AtomicPtr::new(null_mut()),
+            acquisitions: AtomicU64::new(0),
+            contentions: AtomicU64::new(0),
+            max_hold_cycles: AtomicU64::new(0),
+            max_hold_site: AtomicPtr::new(null_mut()),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NEW_CLASS: LockClass = LockClass::new();
+static CLASSES: [LockClass; MAX_CLASSES] = [NEW_CLASS; MAX_CLASSES];
+
+fn site_ptr(site: &'static Location<'static>) -> *mut Location<'static> {
+    site as *const _ as *mut _
+}
+
+/// Find or allocate the class of locks created at `site`.
+fn class_of(site: &'static Location<'static>) -> &'static LockClass {
+    let start = (site.line() as usize * 31 + site.column() as usize) % MAX_CLASSES;
+    for i in 0..MAX_CLASSES {
+        let class = &CLASSES[(start + i) % MAX_CLASSES];
+        let existing = match class.site.compare_exchange(
+            null_mut(),
+            site_ptr(site),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return class,
+            Err(existing) => existing,
+        };
+        // The same call site may have several `Location`s, e.g. in generic code.
+        if unsafe { &*existing } == site {
+            return class;
+        }
+    }
+    &CLASSES[start]
+}
+
+#[inline]
+fn now_cycles() -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        core::arch::x86_64::_rdtsc()
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    0
+}
+
+/// A [`spin::Mutex`] recording statistics in the class of its creation site.
+pub struct Mutex<T: ?Sized> {
+    site: &'static Location<'static>,
+    class: AtomicPtr<LockClass>,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    /// Creates a new mutex, in the lock class of the caller.
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            site: Location::caller(),
+            class: AtomicPtr::new(null_mut()),
+            inner: spin::Mutex::new(data),
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    fn class(&self) -> &'static LockClass {
+        let class = self.class.load(Ordering::Acquire);
+        if !class.is_null() {
+            return unsafe { &*class };
+        }
+        let class = class_of(self.site);
+        self.class
+            .store(class as *const _ as *mut _, Ordering::Release);
+        class
+    }
+
+    fn guard<'a>(
+        &'a self,
+        inner: spin::MutexGuard<'a, T>,
+        site: &'static Location<'static>,
+    ) -> MutexGuard<'a, T> {
+        let class = self.class();
+        class.acquisitions.fetch_add(1, Ordering::Relaxed);
+        MutexGuard {
+            class,
+            site,
+            start_cycles: now_cycles(),
+            inner,
+        }
+    }
+
+    /// Locks the mutex, counting a contention if it is already locked.
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let inner = match self.inner.try_lock() {
+            Some(inner) => inner,
+            None => {
+                self.class().contentions.fetch_add(1, Ordering::Relaxed);
+                self.inner.lock()
+            }
+        };
+        self.guard(inner, Location::caller())
+    }
+
+    /// Tries to lock the mutex, a failure does not count as a contention.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let site = Location::caller();
+        self.inner.try_lock().map(|inner| self.guard(inner, site))
+    }
+
+    /// Returns `true` if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Force unlock this mutex.
+    ///
+    /// # Safety
+    ///
+    /// See [`spin::Mutex::force_unlock`].
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock()
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+/// A guard that provides mutable data access for [`Mutex`], and records the
+/// hold time when dropped.
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    class: &'static LockClass,
+    site: &'static Location<'static>,
+    start_cycles: u64,
+    inner: spin::MutexGuard<'a, T>,
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let hold = now_cycles().wrapping_sub(self.start_cycles);
+        if self
+            .class
+            .max_hold_cycles
+            .fetch_max(hold, Ordering::Relaxed)
+            < hold
+        {
+            self.class
+                .max_hold_site
+                .store(site_ptr(self.site), Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Snapshot {
+    site: *mut Location<'static>,
+    acquisitions: u64,
+    contentions: u64,
+    max_hold_cycles: u64,
+    max_hold_site: *mut Location<'static>,
+}
+
+pub(crate) fn dump(top: usize) {
+    let mut snapshots = [Snapshot {
+        site: null_mut(),
+        acquisitions: 0,
+        contentions: 0,
+        max_hold_cycles: 0,
+        max_hold_site: null_mut(),
+    }; MAX_CLASSES];
+    let mut count = 0;
+    for class in CLASSES.iter() {
+        let site = class.site.load(Ordering::Acquire);
+        if site.is_null() {
+            continue;
+        }
+        snapshots[count] = Snapshot {
+            site,
+            acquisitions: class.acquisitions.load(Ordering::Relaxed),
+            contentions: class.contentions.load(Ordering::Relaxed),
+            max_hold_cycles: class.max_hold_cycles.load(Ordering::Relaxed),
+            max_hold_site: class.max_hold_site.load(Ordering::Relaxed),
+        };
+        count += 1;
+    }
+    let snapshots = &mut snapshots[..count];
+    snapshots.sort_unstable_by(|a, b| {
+        (b.contentions, b.max_hold_cycles).cmp(&(a.contentions, a.max_hold_cycles))
+    });
+
+    log::info!("lock statistics, {} classes, most contended first:", count);
+    for s in snapshots.iter().take(top) {
+        let site = unsafe { &*s.site };
+        let held_at = match unsafe { s.max_hold_site.as_ref() } {
+            Some(held_at) => held_at as &dyn fmt::Display,
+            None => &"-",
+        };
+        log::info!(
+            "  {}: {} acquisitions, {} contended, max hold {} cycles at {}",
+            site,
+            s.acquisitions,
+            s.contentions,
+            s.max_hold_cycles,
+            held_at
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use std::vec::Vec;
+
+    fn counts<T: ?Sized>(lock: &Mutex<T>) -> (u64, u64) {
+        let class = lock.class();
+        (
+            class.acquisitions.load(Ordering::Relaxed),
+            class.contentions.load(Ordering::Relaxed),
+        )
+    }
+
+    #[test]
+    fn test_classes() {
+        let locks: Vec<Mutex<u32>> = (0..2).map(|_| Mutex::new(0)).collect();
+        let other = Mutex::new(0);
+        assert!(core::ptr::eq(locks[0].class(), locks[1].class()));
+        assert!(!core::ptr::eq(locks[0].class(), other.class()));
+        assert_eq!(locks[0].site.file(), file!());
+
+        *locks[0].lock() += 1;
+        *locks[1].lock() += 1;
+        assert_eq!(counts(&locks[0]), (2, 0));
+        assert_eq!(counts(&other), (0, 0));
+    }
+
+    #[test]
+    fn test_contention() {
+        let lock = Arc::new(Mutex::new(0));
+        let guard = lock.lock();
+        // A failed `try_lock` is not a contention.
+        assert!(lock.try_lock().is_none());
+        assert_eq!(counts(&*lock), (1, 0));
+
+        let waiter = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.lock() += 1)
+        };
+        while counts(&*lock).1 == 0 {
+            thread::yield_now();
+        }
+        drop(guard);
+        waiter.join().unwrap();
+        assert_eq!(*lock.lock(), 1);
+        assert_eq!(counts(&*lock), (3, 1));
+    }
+
+    #[test]
+    fn test_max_hold() {
+        let lock = Mutex::new(());
+        drop(lock.lock());
+        let (guard, line) = (lock.lock(), line!());
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(1) {}
+        drop(guard);
+        // A shorter hold keeps the site of the longest one.
+        drop(lock.lock());
+
+        let class = lock.class();
+        assert!(class.max_hold_cycles.load(Ordering::Relaxed) > 0);
+        let site = unsafe { &*class.max_hold_site.load(Ordering::Relaxed) };
+        assert_eq!((site.file(), site.line()), (file!(), line));
+    }
+
+    #[test]
+    fn test_dump() {
+        let lock = Mutex::new(0);
+        *lock.lock() += 1;
+        dump(MAX_CLASSES + 1);
+        dump(0);
+    }
+
+    /// The cost of an uncontended acquisition, with and without the statistics, and the share of
+    /// contended acquisitions of 4 threads sharing a lock. Run with
+    /// `cargo test --features instrument --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_lock() {
+        const ROUNDS: u32 = 1_000_000;
+        let plain = spin::Mutex::new(0u64);
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            *plain.lock() += 1;
+        }
+        let plain_ns = start.elapsed().as_nanos() / ROUNDS as u128;
+
+        let instrumented = Mutex::new(0u64);
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            *instrumented.lock() += 1;
+        }
+        let instrumented_ns = start.elapsed().as_nanos() / ROUNDS as u128;
+        std::println!("uncontended: spin {plain_ns} ns, instrumented {instrumented_ns} ns");
+
+        let shared = Arc::new(Mutex::new(0u64));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS / 4 {
+                        *shared.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let (acquisitions, contentions) = counts(&*shared);
+        std::println!(
+            "4 threads: {acquisitions} acquisitions, {contentions} contended, max hold {} cycles",
+            shared.class().max_hold_cycles.load(Ordering::Relaxed)
+        );
+    }
+}
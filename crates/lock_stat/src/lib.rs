@@ -0,0 +1,62 @@
+//! `no_std` spin mutex with optional acquisition, contention and hold-time
+//! statistics.
+//!
+//! # Cargo Features
+//!
+//! - `instrument`: Record statistics. Locks are grouped into lock classes by
+//!   the call site which created them; each class counts acquisitions and
+//!   contended acquisitions, and keeps the longest hold time together with the
+//!   call site that held the lock. [`dump`] prints the most contended classes.
+//!   Without this feature, [`Mutex`] is [`spin::Mutex`] and [`dump`] does
+//!   nothing.
+
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(feature = "instrument", feature(const_caller_location))]
+
+#[cfg(feature = "instrument")]
+mod instrumented;
+
+#[cfg(feature = "instrument")]
+pub use self::instrumented::{Mutex, MutexGuard};
+#[cfg(not(feature = "instrument"))]
+pub use spin::{Mutex, MutexGuard};
+
+/// Print the statistics of the `top` lock classes with the most contended
+/// acquisitions.
+pub fn dump(top: usize) {
+    #[cfg(feature = "instrument")]
+    instrumented::dump(top);
+    #[cfg(not(feature = "instrument"))]
+    let _ = top;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump, Mutex};
+    use std::sync::Arc;
+
+    static COUNTER: Mutex<u32> = Mutex::new(0);
+
+    #[test]
+    fn test_mutex() {
+        *COUNTER.lock() += 1;
+        let guard = COUNTER.lock();
+        assert!(COUNTER.is_locked());
+        assert!(COUNTER.try_lock().is_none());
+        drop(guard);
+        assert!(!COUNTER.is_locked());
+        assert_eq!(*COUNTER.try_lock().unwrap(), 1);
+
+        let mut lock = Mutex::new(3);
+        *lock.get_mut() += 1;
+        assert_eq!(lock.into_inner(), 4);
+        dump(4);
+    }
+
+    #[test]
+    fn test_unsized() {
+        // As the `Arc<Mutex<dyn PioOps>>` of the device lists.
+        let lock: Arc<Mutex<dyn Fn() -> u32 + Send>> = Arc::new(Mutex::new(|| 7));
+        assert_eq!((lock.lock())(), 7);
+    }
+}
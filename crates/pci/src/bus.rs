@@ -3,17 +3,21 @@ use alloc::string::String;
 use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU16, Ordering};
-use spin::Mutex;
+use lock_stat::Mutex;
 
 use crate::config::BarAllocTrait;
 use crate::{
     config::{
-        Bar, BRIDGE_CONTROL, BRIDGE_CTL_SEC_BUS_RESET, SECONDARY_BUS_NUM, SUBORDINATE_BUS_NUM,
+        Bar, BarLayout, BRIDGE_CONTROL, BRIDGE_CTL_SEC_BUS_RESET, HEADER_TYPE,
+        HEADER_TYPE_MULTIFUNC, MAX_FUNC, SECONDARY_BUS_NUM, SUBORDINATE_BUS_NUM,
     },
-    MsiIrqManager, PciDevOps,
+    pci_devfn, pci_slot, MsiIrqManager, PciDevOps, PCI_SLOT_MAX,
 };
 use hypercraft::{HyperError, HyperResult as Result, MmioOps, PciError, PioOps};
 
+/// Slot 0 is left to the host bridge, allocated devices start at slot 1.
+const FIRST_DEVICE_SLOT: u8 = 1;
+
 type DeviceBusInfo<B: BarAllocTrait> = (Arc<Mutex<PciBus<B>>>, Arc<Mutex<dyn PciDevOps<B>>>);
 
 /// PCI bus structure.
@@ -28,6 +32,8 @@ pub struct PciBus<B: BarAllocTrait> {
     pub parent_bridge: Option<Weak<Mutex<dyn PciDevOps<B>>>>,
     /// MSI interrupt manager.
     pub msi_irq_manager: Option<Arc<dyn MsiIrqManager>>,
+    /// Layout of the BARs of the devices on the bus.
+    pub bar_layout: Arc<BarLayout>,
 }
 
 impl<B: BarAllocTrait> PciBus<B> {
@@ -43,6 +49,7 @@ impl<B: BarAllocTrait> PciBus<B> {
             child_buses: Vec::new(),
             parent_bridge: None,
             msi_irq_manager,
+            bar_layout: Arc::new(BarLayout::new()),
         }
     }
 
@@ -74,6 +81,60 @@ impl<B: BarAllocTrait> PciBus<B> {
         None
     }
 
+    /// Function 0 of the first slot without any device, for a new device.
+    pub fn alloc_devfn(&self) -> Option<u8> {
+        (FIRST_DEVICE_SLOT..PCI_SLOT_MAX)
+            .find(|slot| self.slot_functions(*slot) == 0)
+            .map(|slot| pci_devfn(slot, 0))
+    }
+
+    /// Check that a new device can be placed at `devfn`: the function is free, and the slot is
+    /// either empty or holds a multi-function device which the new one is a function of.
+    pub fn check_devfn(&self, devfn: u8, multi_func: bool) -> Result<()> {
+        if let Some(dev) = self.devices.get(&devfn) {
+            return Err(HyperError::PciError(PciError::Other(format!(
+                "devfn {:#x} is already used by {}",
+                devfn,
+                dev.lock().name()
+            ))));
+        }
+        let slot = pci_slot(devfn);
+        if self.slot_functions(slot) == 0 {
+            return Ok(());
+        }
+        if !multi_func {
+            return Err(HyperError::PciError(PciError::Other(format!(
+                "slot {} is already used, a single-function device needs a slot of its own",
+                slot
+            ))));
+        }
+        let func0_multi = match self.devices.get(&pci_devfn(slot, 0)) {
+            Some(func0) => {
+                let mut header_type = [0_u8; 1];
+                func0
+                    .lock()
+                    .read_config(HEADER_TYPE as usize, &mut header_type);
+                header_type[0] & HEADER_TYPE_MULTIFUNC != 0
+            }
+            // Functions without function 0 are only found by the guest once it is added.
+            None => true,
+        };
+        if !func0_multi {
+            return Err(HyperError::PciError(PciError::Other(format!(
+                "slot {} holds a single-function device",
+                slot
+            ))));
+        }
+        Ok(())
+    }
+
+    /// Number of functions of `slot` in use.
+    fn slot_functions(&self, slot: u8) -> usize {
+        self.devices
+            .range(pci_devfn(slot, 0)..=pci_devfn(slot, MAX_FUNC - 1))
+            .count()
+    }
+
     /// Get which bar is mapped to the io_info_port.
     pub fn find_pio_bar(&self, port: u16) -> Option<Arc<Mutex<dyn PioOps>>> {
         for device in self.devices.values() {
@@ -100,6 +161,20 @@ impl<B: BarAllocTrait> PciBus<B> {
         None
     }
 
+    /// Collect the mapped BARs of all devices on the bus and its child buses.
+    ///
+    /// The BARs are copies, they stay valid until the generation of [`PciBus::bar_layout`]
+    /// changes.
+    pub fn collect_mapped_bars(&self, bars: &mut Vec<Bar>) {
+        for device in self.devices.values() {
+            let device = device.lock();
+            bars.extend(device.pci_base().config.mapped_bars().cloned());
+        }
+        for child_bus in &self.child_buses {
+            child_bus.lock().collect_mapped_bars(bars);
+        }
+    }
+
     fn in_range(&self, bus_num: u8) -> bool {
         if self.is_during_reset() {
             return false;
@@ -264,3 +339,118 @@ impl<B: BarAllocTrait> PciBus<B> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PciConfig, RegionType, PCI_CONFIG_SPACE_SIZE};
+    use crate::{AsAny, PciDevBase};
+    use core::any::Any;
+
+    #[derive(Clone)]
+    struct TestBarAlloc;
+
+    impl BarAllocTrait for TestBarAlloc {
+        fn alloc(_region_type: RegionType, _size: u64) -> Result<u64> {
+            Err(HyperError::NotSupported)
+        }
+
+        fn dealloc(_region_type: RegionType, _addr: u64, _size: u64) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A function whose header type tells whether it is part of a multi-function device.
+    struct TestDevice {
+        base: PciDevBase<TestBarAlloc>,
+    }
+
+    impl AsAny for TestDevice {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    impl PciDevOps<TestBarAlloc> for TestDevice {
+        fn name(&self) -> String {
+            self.base.id.clone()
+        }
+
+        fn pci_base(&self) -> &PciDevBase<TestBarAlloc> {
+            &self.base
+        }
+
+        fn pci_base_mut(&mut self) -> &mut PciDevBase<TestBarAlloc> {
+            &mut self.base
+        }
+
+        fn realize(self) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_config(&mut self, _offset: usize, _data: &[u8]) {}
+    }
+
+    type TestBus = PciBus<TestBarAlloc>;
+
+    /// Place a device at `devfn` as the devices do when they are realized, checking it first.
+    fn add(bus: &mut TestBus, name: &str, devfn: u8, multi_func: bool) -> Result<()> {
+        bus.check_devfn(devfn, multi_func)?;
+        let mut config = PciConfig::new(PCI_CONFIG_SPACE_SIZE, 6);
+        if multi_func {
+            config.config[HEADER_TYPE as usize] = HEADER_TYPE_MULTIFUNC;
+        }
+        let device = TestDevice {
+            base: PciDevBase {
+                id: String::from(name),
+                config,
+                devfn,
+                parent_bus: Weak::new(),
+            },
+        };
+        bus.devices.insert(devfn, Arc::new(Mutex::new(device)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_alloc_devfn() {
+        let mut bus = TestBus::new(String::from("pcie.0"), None);
+        // blk, console and rng get slots 1 to 3, function 0.
+        for (name, devfn) in [("blk", 0x08), ("console", 0x10), ("rng", 0x18)] {
+            assert_eq!(bus.alloc_devfn(), Some(devfn));
+            add(&mut bus, name, devfn, false).unwrap();
+        }
+        assert_eq!(bus.alloc_devfn(), Some(0x20));
+
+        // A slot holding any function is not free, even without function 0.
+        add(&mut bus, "func1", pci_devfn(4, 1), true).unwrap();
+        assert_eq!(bus.alloc_devfn(), Some(pci_devfn(5, 0)));
+
+        for slot in 5..PCI_SLOT_MAX {
+            add(&mut bus, "filler", pci_devfn(slot, 0), false).unwrap();
+        }
+        assert_eq!(bus.alloc_devfn(), None);
+    }
+
+    #[test]
+    fn test_check_devfn() {
+        let mut bus = TestBus::new(String::from("pcie.0"), None);
+        add(&mut bus, "blk", 0x08, false).unwrap();
+        // The same function, or another function of a single-function device.
+        assert!(add(&mut bus, "console", 0x08, false).is_err());
+        assert!(add(&mut bus, "console", 0x09, false).is_err());
+        assert!(add(&mut bus, "console", 0x09, true).is_err());
+
+        // The functions of a multi-function device share its slot, a single-function device
+        // does not join them.
+        add(&mut bus, "func0", 0x10, true).unwrap();
+        add(&mut bus, "func1", 0x11, true).unwrap();
+        assert!(add(&mut bus, "func1", 0x11, true).is_err());
+        assert!(add(&mut bus, "single", 0x12, false).is_err());
+        assert_eq!(bus.devices.len(), 3);
+    }
+}
@@ -13,11 +13,12 @@ use alloc::vec::Vec;
 use core::marker::PhantomData;
 use core::ops::Range;
 use core::ptr::{read, write};
+use core::sync::atomic::{AtomicU64, Ordering};
 use hashbrown::HashMap;
 use hypercraft::{HyperError, HyperResult as Result, PciError};
 use hypercraft::{HyperResult, MmioOps, PioOps, RegionOps};
 use lazy_static::lazy_static;
-use spin::Mutex;
+use lock_stat::Mutex;
 
 /// The mem64 base of emulated PCI devices bars.
 pub const PCI_EMUL_MEMBASE64: u64 = 0x4000_0000_0000; /* 256GB */
@@ -324,6 +325,43 @@ lazy_static! {
         Arc::new(Mutex::new(PciBarAllocator::new()));
 }
 
+/// Generation of the BAR layout of the devices of one PCI host, and thus of one VM.
+///
+/// Bumped whenever a BAR of one of the devices is registered, unregistered or moved, so that users
+/// caching the BAR layout (e.g. the device lookup index of a VM) know when to rebuild it. The
+/// BARs of the other VMs moving does not change it.
+#[derive(Debug, Default)]
+pub struct BarLayout {
+    generation: AtomicU64,
+}
+
+impl BarLayout {
+    pub const fn new() -> Self {
+        Self {
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Current generation, it changes whenever a BAR is added, removed or moved.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    fn changed(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Size of a BAR of `region_type` holding `len` bytes: the next power of two, and at least the
+/// minimum size of the type.
+pub fn bar_size(region_type: RegionType, len: u64) -> u64 {
+    let min = match region_type {
+        RegionType::Io => MINIMUM_BAR_SIZE_FOR_PIO,
+        RegionType::Mem32Bit | RegionType::Mem64Bit => MINIMUM_BAR_SIZE_FOR_MMIO,
+    } as u64;
+    len.next_power_of_two().max(min)
+}
+
 /// Type of bar region.
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum RegionType {
@@ -363,6 +401,17 @@ pub struct Bar {
     ops: Option<RegionOps>,
 }
 
+impl Bar {
+    pub fn region_type(&self) -> RegionType {
+        self.region_type
+    }
+
+    /// Whether the BAR is registered and currently mapped by the guest.
+    pub fn is_mapped(&self) -> bool {
+        self.size != 0 && self.address != BAR_SPACE_UNMAPPED
+    }
+}
+
 impl PioOps for Bar {
     fn port_range(&self) -> core::ops::Range<u16> {
         self.address as u16..(self.address + self.size) as u16
@@ -606,6 +655,9 @@ pub struct PciConfig<B: BarAllocTrait> {
     pub last_ext_cap_end: u16,
     /// MSI-X information.
     pub msix: Option<Arc<Mutex<Msix>>>,
+    /// Layout the BARs belong to, that of the bus of the device once it is realized, see
+    /// [`PciConfig::set_bar_layout`].
+    bar_layout: Arc<BarLayout>,
     /// Phantom data.
     _phantom: PhantomData<B>,
 }
@@ -638,10 +690,18 @@ impl<B: BarAllocTrait> PciConfig<B> {
             last_ext_cap_offset: 0,
             last_ext_cap_end: PCI_CONFIG_SPACE_SIZE as u16,
             msix: None,
+            bar_layout: Arc::new(BarLayout::new()),
             _phantom: PhantomData,
         }
     }
 
+    /// Make the BARs part of `layout`, the layout of the bus the device is attached to, before
+    /// they are registered.
+    pub fn set_bar_layout(&mut self, layout: Arc<BarLayout>) {
+        self.bar_layout = layout;
+        self.bar_layout.changed();
+    }
+
     /// Init write_mask for all kinds of PCI/PCIe devices, including bridges.
     pub fn init_common_write_mask(&mut self) -> Result<()> {
         self.write_mask[CACHE_LINE_SIZE as usize] = 0xff;
@@ -801,12 +861,53 @@ impl<B: BarAllocTrait> PciConfig<B> {
             }
         }
 
+        if cmd_overlap {
+            self.sync_bus_master();
+        }
+
         if let Some(msix) = &mut self.msix {
             msix.lock()
                 .write_config(&self.config, dev_id, old_offset, data);
         }
     }
 
+    /// The command register.
+    pub fn command(&self) -> u16 {
+        le_read_u16(&self.config, COMMAND as usize).unwrap()
+    }
+
+    /// Whether the function may initiate memory accesses: DMA and MSI-X messages.
+    pub fn bus_master_enabled(&self) -> bool {
+        self.command() & COMMAND_BUS_MASTER != 0
+    }
+
+    /// Whether the function is prevented from asserting its INTx pin.
+    pub fn intx_disabled(&self) -> bool {
+        self.command() & COMMAND_INTERRUPT_DISABLE != 0
+    }
+
+    /// The IRQ the guest routed the INTx pin of the function to, 0xff if it is not routed.
+    pub fn interrupt_line(&self) -> u8 {
+        self.config[INTERRUPT_LINE as usize]
+    }
+
+    /// Whether the command register enables the decoding of the BARs of `region_type`.
+    pub fn decodes(&self, region_type: RegionType) -> bool {
+        let enable = match region_type {
+            RegionType::Io => COMMAND_IO_SPACE,
+            RegionType::Mem32Bit | RegionType::Mem64Bit => COMMAND_MEMORY_SPACE,
+        };
+        self.command() & enable != 0
+    }
+
+    /// Propagate the bus master enable bit to the interrupt sources, once the command register
+    /// was written or restored.
+    pub fn sync_bus_master(&self) {
+        if let Some(msix) = &self.msix {
+            msix.lock().set_bus_master(self.bus_master_enabled());
+        }
+    }
+
     /// Reset type1 specific configuration space.
     pub fn reset_bridge_regs(&mut self) -> Result<()> {
         le_write_u32(&mut self.config, PRIMARY_BUS_NUM as usize, 0)?;
@@ -889,32 +990,42 @@ impl<B: BarAllocTrait> PciConfig<B> {
     pub fn get_bar_address(&self, id: usize) -> u64 {
         let command = le_read_u16(&self.config, COMMAND as usize).unwrap();
         let offset: usize = BAR_0 as usize + id * REG_SIZE;
-        if self.config[offset] & BAR_IO_SPACE > 0 {
+        let (addr, limit) = if self.config[offset] & BAR_IO_SPACE > 0 {
             if command & COMMAND_IO_SPACE == 0 {
                 return BAR_SPACE_UNMAPPED;
             }
             let bar_val = le_read_u32(&self.config, offset).unwrap();
-            return (bar_val & IO_BASE_ADDR_MASK) as u64;
-        }
-
-        if command & COMMAND_MEMORY_SPACE == 0 {
-            return BAR_SPACE_UNMAPPED;
-        }
-        match self.bars[id].region_type {
-            RegionType::Io => BAR_SPACE_UNMAPPED,
-            RegionType::Mem32Bit => {
-                let bar_val = le_read_u32(&self.config, offset).unwrap();
-                (bar_val & MEM_BASE_ADDR_MASK as u32) as u64
+            ((bar_val & IO_BASE_ADDR_MASK) as u64, 1 << 16)
+        } else {
+            if command & COMMAND_MEMORY_SPACE == 0 {
+                return BAR_SPACE_UNMAPPED;
             }
-            RegionType::Mem64Bit => {
-                let bar_val = le_read_u64(&self.config, offset).unwrap();
-                bar_val & MEM_BASE_ADDR_MASK
+            match self.bars[id].region_type {
+                RegionType::Io => return BAR_SPACE_UNMAPPED,
+                RegionType::Mem32Bit => {
+                    let bar_val = le_read_u32(&self.config, offset).unwrap();
+                    ((bar_val & MEM_BASE_ADDR_MASK as u32) as u64, 1 << 32)
+                }
+                RegionType::Mem64Bit => {
+                    let bar_val = le_read_u64(&self.config, offset).unwrap();
+                    (bar_val & MEM_BASE_ADDR_MASK, u64::MAX)
+                }
             }
+        };
+        // A BAR which does not end below the limit of its type is not decoded: the all ones the
+        // guest writes to size it, with decoding enabled, put the BAR right at the limit.
+        match addr.checked_add(self.bars[id].size) {
+            Some(end) if end < limit => addr,
+            _ => BAR_SPACE_UNMAPPED,
         }
     }
 
     /// Register a bar in PciConfig::bars.
     ///
+    /// The writable bits of the BAR are the ones above its size, so that the guest reads back
+    /// the size after writing all ones: a 64-bit BAR also takes the next slot, which holds the
+    /// upper 32 bits of the address and of the mask.
+    ///
     /// # Arguments
     ///
     /// * `id` - Index of the BAR.
@@ -931,6 +1042,10 @@ impl<B: BarAllocTrait> PciConfig<B> {
         size: u64,
     ) -> Result<()> {
         self.validate_bar_id(id)?;
+        if region_type == RegionType::Mem64Bit {
+            // The upper half.
+            self.validate_bar_id(id + 1)?;
+        }
         self.validate_bar_size(region_type, size)?;
         let offset: usize = BAR_0 as usize + id * REG_SIZE;
         let size = if region_type == RegionType::Io {
@@ -972,29 +1087,33 @@ impl<B: BarAllocTrait> PciConfig<B> {
 
         self.bars[id].ops = ops;
         self.bars[id].region_type = region_type;
-        // self.bars[id].address = BAR_SPACE_UNMAPPED;
-        self.bars[id].address = addr;
+        self.bars[id].address = BAR_SPACE_UNMAPPED;
         self.bars[id].actual_address = addr;
         self.bars[id].size = size;
 
         // Write the front part of addr into self.config[offset + 4] to self.bars[id].actual_address = actual_addr;self.config[offset + 31]
         let length = match region_type {
-            RegionType::Io => BAR_SPACE_UNMAPPED,
+            // Assigned by the guest.
+            RegionType::Io => 0,
             RegionType::Mem32Bit => 4,
             RegionType::Mem64Bit => 8,
         } as usize;
+        // The bits below the size read as zeros, for the guest to find the size.
+        let preset = addr & !(size - 1);
         for i in 0..length {
             if i == 0 {
-                self.config[offset + i] |= (addr as u8) & !0xf;
+                self.config[offset + i] |= (preset as u8) & !0xf;
             } else {
-                self.config[offset + i] |= (addr >> (i * 8)) as u8;
+                self.config[offset + i] |= (preset >> (i * 8)) as u8;
             }
         }
-        let get_addr = self.get_bar_address(id);
+        // Decoded once the guest enables the address space in the command register.
+        self.bars[id].address = self.get_bar_address(id);
+        self.bar_layout.changed();
         debug!(
             "after register content:: {:?} addr:{:#x}",
             &self.config[offset..(offset + 4)] as &[u8],
-            get_addr
+            self.bars[id].address
         );
         Ok(())
     }
@@ -1007,11 +1126,11 @@ impl<B: BarAllocTrait> PciConfig<B> {
     pub fn unregister_bars(&mut self, _bus: &Arc<Mutex<PciBus<B>>>) -> Result<()> {
         // let locked_bus = bus.lock();
         for (i, bar) in self.bars.iter_mut().enumerate() {
-            if bar.address == BAR_SPACE_UNMAPPED || bar.size == 0 {
+            if bar.size == 0 {
                 continue;
             }
             // Invalid the bar region
-            {
+            if bar.address != BAR_SPACE_UNMAPPED {
                 let mut allocator = PCI_BAR_ALLOCATOR.lock();
                 allocator.dealloc(bar.region_type, bar.address)?;
             }
@@ -1020,10 +1139,15 @@ impl<B: BarAllocTrait> PciConfig<B> {
             bar.actual_address = BAR_SPACE_UNMAPPED;
             bar.size = 0;
             bar.ops = None;
-            for j in 0..4 {
-                self.config[BAR_0 as usize + i + j] = 0;
-            }
+            let len = match bar.region_type {
+                RegionType::Mem64Bit => 2 * REG_SIZE,
+                _ => REG_SIZE,
+            };
+            let offset = BAR_0 as usize + i * REG_SIZE;
+            self.config[offset..offset + len].fill(0);
+            self.write_mask[offset..offset + len].fill(0);
         }
+        self.bar_layout.changed();
 
         Ok(())
     }
@@ -1045,6 +1169,7 @@ impl<B: BarAllocTrait> PciConfig<B> {
                 debug!("bar {} is not updated", id);
                 continue;
             }
+            self.bar_layout.changed();
 
             // first unmmap origin bar region
             if self.bars[id].address != BAR_SPACE_UNMAPPED {
@@ -1057,7 +1182,7 @@ impl<B: BarAllocTrait> PciConfig<B> {
             }
 
             if is_empty {
-                return Ok(());
+                continue;
             }
 
             // map new region
@@ -1081,19 +1206,26 @@ impl<B: BarAllocTrait> PciConfig<B> {
 
     /// Find a PIO BAR by Port.
     pub fn find_pio(&self, port: u16) -> Option<&Bar> {
-        self.bars
-            .iter()
+        self.mapped_bars()
             .find(|bar| bar.region_type == RegionType::Io && bar.port_range().contains(&port))
     }
 
     /// Find a MMIO BAR by Address.
     pub fn find_mmio(&self, addr: u64) -> Option<&Bar> {
-        self.bars.iter().find(|bar| {
-            bar.region_type == RegionType::Mem64Bit
-                || bar.region_type == RegionType::Mem32Bit && bar.mmio_range().contains(&addr)
+        self.mapped_bars().find(|bar| {
+            (bar.region_type == RegionType::Mem64Bit || bar.region_type == RegionType::Mem32Bit)
+                && bar.mmio_range().contains(&addr)
         })
     }
 
+    /// Iterate over the BARs which are registered, mapped by the guest and decoded, i.e. whose
+    /// address space is enabled in the command register.
+    pub fn mapped_bars(&self) -> impl Iterator<Item = &Bar> {
+        self.bars
+            .iter()
+            .filter(|bar| bar.is_mapped() && self.decodes(bar.region_type))
+    }
+
     /// Add a pci standard capability in the configuration space.
     ///
     /// # Arguments
@@ -1231,3 +1363,185 @@ impl<B: BarAllocTrait> PciConfig<B> {
         vector_nr < max_vector as u32
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct TestBarAlloc;
+
+    impl BarAllocTrait for TestBarAlloc {
+        fn alloc(_region_type: RegionType, _size: u64) -> HyperResult<u64> {
+            Err(HyperError::NotSupported)
+        }
+
+        fn dealloc(_region_type: RegionType, _addr: u64, _size: u64) -> HyperResult<()> {
+            Ok(())
+        }
+    }
+
+    fn new_config() -> PciConfig<TestBarAlloc> {
+        let mut config = PciConfig::new(PCI_CONFIG_SPACE_SIZE, BAR_NUM_MAX_FOR_ENDPOINT);
+        config.init_common_write_mask().unwrap();
+        config
+    }
+
+    fn read_u32(config: &mut PciConfig<TestBarAlloc>, offset: usize) -> u32 {
+        let mut buf = [0; 4];
+        config.read(offset, &mut buf);
+        u32::from_le_bytes(buf)
+    }
+
+    fn write_u32(config: &mut PciConfig<TestBarAlloc>, offset: usize, value: u32) {
+        config.write(offset, &value.to_le_bytes(), 0);
+    }
+
+    fn bar_offset(id: usize) -> usize {
+        BAR_0 as usize + id * REG_SIZE
+    }
+
+    fn set_command(config: &mut PciConfig<TestBarAlloc>, command: u16) {
+        config.write(COMMAND as usize, &command.to_le_bytes(), 0);
+    }
+
+    #[test]
+    fn test_bar_size() {
+        assert_eq!(bar_size(RegionType::Io, 1), 4);
+        assert_eq!(bar_size(RegionType::Io, 0x11), 0x20);
+        assert_eq!(bar_size(RegionType::Mem32Bit, 0x10), 0x1000);
+        assert_eq!(bar_size(RegionType::Mem64Bit, 0x1001), 0x2000);
+    }
+
+    #[test]
+    fn test_bar_layout_per_bus() {
+        let (own, other) = (Arc::new(BarLayout::new()), Arc::new(BarLayout::new()));
+        let mut config = new_config();
+        config.set_bar_layout(own.clone());
+        let (before, other_before) = (own.generation(), other.generation());
+        config
+            .register_bar(0, None, RegionType::Mem32Bit, false, 0x1000)
+            .unwrap();
+        write_u32(&mut config, bar_offset(0), 0xfebd_0000);
+        set_command(&mut config, COMMAND_MEMORY_SPACE);
+        let moved = own.generation();
+        assert!(moved > before);
+        // Unchanged by a write leaving the BAR where it is.
+        write_u32(&mut config, bar_offset(0), 0xfebd_0000);
+        assert_eq!(own.generation(), moved);
+        // The BARs of another bus, e.g. of another VM, are not part of the layout.
+        assert_eq!(other.generation(), other_before);
+    }
+
+    #[test]
+    fn test_mem32_bar_sizing() {
+        let mut config = new_config();
+        config
+            .register_bar(0, None, RegionType::Mem32Bit, true, 0x2000)
+            .unwrap();
+        write_u32(&mut config, bar_offset(0), 0xffff_ffff);
+        // The size in the writable bits, the type in the others.
+        assert_eq!(
+            read_u32(&mut config, bar_offset(0)),
+            0xffff_e000 | BAR_PREFETCH as u32
+        );
+        assert_eq!(config.get_bar_address(0), BAR_SPACE_UNMAPPED);
+    }
+
+    #[test]
+    fn test_mem32_bar_decoding() {
+        let mut config = new_config();
+        config
+            .register_bar(0, None, RegionType::Mem32Bit, false, 0x1000)
+            .unwrap();
+        write_u32(&mut config, bar_offset(0), 0xfebd_0000);
+        // Not decoded until the memory space is enabled.
+        assert_eq!(config.bars[0].address, BAR_SPACE_UNMAPPED);
+        assert!(config.find_mmio(0xfebd_0010).is_none());
+
+        set_command(&mut config, COMMAND_MEMORY_SPACE);
+        assert_eq!(config.bars[0].address, 0xfebd_0000);
+        assert!(config.find_mmio(0xfebd_0010).is_some());
+        assert!(config.find_mmio(0xfebd_1000).is_none());
+
+        // Sized with decoding enabled: the all ones are not an address.
+        write_u32(&mut config, bar_offset(0), 0xffff_ffff);
+        assert_eq!(read_u32(&mut config, bar_offset(0)), 0xffff_f000);
+        assert_eq!(config.bars[0].address, BAR_SPACE_UNMAPPED);
+        assert!(config.find_mmio(0xffff_f000).is_none());
+
+        write_u32(&mut config, bar_offset(0), 0xfebd_0000);
+        assert_eq!(config.bars[0].address, 0xfebd_0000);
+        set_command(&mut config, 0);
+        assert_eq!(config.mapped_bars().count(), 0);
+    }
+
+    #[test]
+    fn test_mem64_bar() {
+        let mut config = new_config();
+        config
+            .register_bar(2, None, RegionType::Mem64Bit, false, 0x4000)
+            .unwrap();
+        set_command(&mut config, COMMAND_MEMORY_SPACE);
+        write_u32(&mut config, bar_offset(2), 0xffff_ffff);
+        write_u32(&mut config, bar_offset(3), 0xffff_ffff);
+        assert_eq!(
+            read_u32(&mut config, bar_offset(2)),
+            0xffff_c000 | BAR_MEM_64BIT as u32
+        );
+        assert_eq!(read_u32(&mut config, bar_offset(3)), 0xffff_ffff);
+        assert_eq!(config.bars[2].address, BAR_SPACE_UNMAPPED);
+
+        write_u32(&mut config, bar_offset(2), 0);
+        write_u32(&mut config, bar_offset(3), 0x40);
+        assert_eq!(config.get_bar_address(2), 0x40_0000_0000);
+        assert!(config.find_mmio(0x40_0000_3ff8).is_some());
+    }
+
+    #[test]
+    fn test_io_bar() {
+        let mut config = new_config();
+        config
+            .register_bar(1, None, RegionType::Io, false, 0x20)
+            .unwrap();
+        assert_eq!(read_u32(&mut config, bar_offset(1)), BAR_IO_SPACE as u32);
+        write_u32(&mut config, bar_offset(1), 0xffff_ffff);
+        assert_eq!(
+            read_u32(&mut config, bar_offset(1)),
+            0xffff_ffe0 | BAR_IO_SPACE as u32
+        );
+
+        write_u32(&mut config, bar_offset(1), 0xc040);
+        assert!(config.find_pio(0xc050).is_none());
+        set_command(&mut config, COMMAND_IO_SPACE);
+        assert_eq!(config.bars[1].address, 0xc040);
+        assert!(config.find_pio(0xc05f).is_some());
+        assert!(config.find_pio(0xc060).is_none());
+        // Past the 64 KiB of I/O space.
+        write_u32(&mut config, bar_offset(1), 0xffff_ffff);
+        assert_eq!(config.bars[1].address, BAR_SPACE_UNMAPPED);
+    }
+
+    #[test]
+    fn test_command_bits() {
+        let mut config = new_config();
+        assert!(!config.bus_master_enabled());
+        assert!(!config.intx_disabled());
+        set_command(&mut config, COMMAND_BUS_MASTER | COMMAND_INTERRUPT_DISABLE);
+        assert!(config.bus_master_enabled());
+        assert!(config.intx_disabled());
+        // Read-only bits are kept.
+        set_command(&mut config, COMMAND_FAST_BACK);
+        assert_eq!(config.command(), 0);
+    }
+
+    #[test]
+    fn test_interrupt_line() {
+        let mut config = new_config();
+        config.config[INTERRUPT_PIN as usize] = 1;
+        config.write(INTERRUPT_LINE as usize, &[11, 4], 0);
+        assert_eq!(config.interrupt_line(), 11);
+        // The pin is read-only.
+        assert_eq!(config.config[INTERRUPT_PIN as usize], 1);
+    }
+}
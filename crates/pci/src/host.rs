@@ -1,7 +1,7 @@
 use alloc::string::String;
 use alloc::sync::Arc;
 use core::ops::Range;
-use spin::Mutex;
+use lock_stat::Mutex;
 use x86::io;
 
 use crate::{bus::PciBus, BarAllocTrait, MsiIrqManager, PciDevOps};
@@ -66,6 +66,17 @@ impl<B: BarAllocTrait> PciHost<B> {
         }
         None
     }
+
+    /// The configuration address register, selecting the device and offset of the data port.
+    #[cfg(target_arch = "x86_64")]
+    pub fn config_addr(&self) -> u32 {
+        self.config_addr
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_config_addr(&mut self, config_addr: u32) {
+        self.config_addr = config_addr;
+    }
 }
 
 impl<B: BarAllocTrait> PioOps for PciHost<B> {
@@ -79,7 +90,6 @@ impl<B: BarAllocTrait> PioOps for PciHost<B> {
         //     port, access_size
         // );
         let mut data = [0xffu8; 4]; // max access size is 4
-        let cloned_hb = self.clone();
         if PCI_CFG_ADDR_PORT.contains(&port) {
             // Read configuration address register.
             if port==0xcf8 && self.check_type1==2 {
@@ -91,26 +101,26 @@ impl<B: BarAllocTrait> PioOps for PciHost<B> {
             // }
             else {
                 // also deal with tmp = inl(0xCF8); in check type
-                le_write_u32(&mut data[..], 0, cloned_hb.config_addr).unwrap();
+                le_write_u32(&mut data[..], 0, self.config_addr).unwrap();
             }
         } else {
             // Read configuration data register.
-            if access_size > 4 || cloned_hb.config_addr & CONFIG_ADDRESS_ENABLE_MASK == 0 {
+            if access_size > 4 || self.config_addr & CONFIG_ADDRESS_ENABLE_MASK == 0 {
                 return Err(HyperError::InValidPioRead);
             }
-            let mut offset: u32 = (cloned_hb.config_addr & !CONFIG_ADDRESS_ENABLE_MASK)
+            let mut offset: u32 = (self.config_addr & !CONFIG_ADDRESS_ENABLE_MASK)
                 + (port - PCI_CFG_DATA_PORT.start) as u32;
             // debug!("in pci read: offset:{:#x}", offset);
             let bus_num = ((offset >> PIO_BUS_SHIFT) & CONFIG_BUS_MASK) as u8;
             let devfn = ((offset >> PIO_DEVFN_SHIFT) & CONFIG_DEVFN_MASK) as u8;
-            match cloned_hb.find_device(bus_num, devfn) {
+            match self.find_device(bus_num, devfn) {
                 Some(dev) => {
                     offset &= PIO_OFFSET_MASK;
                     dev.lock().read_config(offset as usize, &mut data[..]);
                 }
                 None => {
                     // debug!("cannot find device use passthrough to read data");
-                    // unsafe{io::outl(0xcf8, cloned_hb.config_addr);}
+                    // unsafe{io::outl(0xcf8, self.config_addr);}
                     // match access_size {
                     //     1 => return Ok(unsafe { io::inb(port) } as u32),
                     //     2 => return Ok(unsafe { io::inw(port) } as u32),
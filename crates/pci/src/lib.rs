@@ -24,7 +24,7 @@ pub use crate::config::BarAllocTrait;
 use alloc::string::String;
 use alloc::sync::{Arc, Weak};
 use core::mem::size_of;
-use spin::Mutex;
+use lock_stat::Mutex;
 
 use byteorder::{ByteOrder, LittleEndian};
 
@@ -141,6 +141,25 @@ fn pci_func(devfn: u8) -> u8 {
     devfn & 0x07
 }
 
+/// Bus number and devfn of a PCI function, displayed as `bus:slot.function`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciBdf {
+    pub bus: u8,
+    pub devfn: u8,
+}
+
+impl core::fmt::Display for PciBdf {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{}",
+            self.bus,
+            pci_slot(self.devfn),
+            pci_func(self.devfn)
+        )
+    }
+}
+
 pub fn pci_ext_cap_id(header: u32) -> u16 {
     (header & 0xffff) as u16
 }
@@ -366,4 +385,9 @@ pub trait MsiIrqManager: Send + Sync {
     fn trigger(&self, _vector: MsiVector, _dev_id: u32) -> Result<()> {
         Ok(())
     }
+
+    /// Raise `irq`, the interrupt line of a function asserting its INTx pin without MSI-X.
+    fn trigger_intx(&self, _irq: u8) -> Result<()> {
+        Ok(())
+    }
 }
@@ -1,11 +1,10 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use bit_field::BitField;
-use core::cmp::max;
 use core::sync::atomic::{AtomicU16, Ordering};
-use spin::Mutex;
+use lock_stat::Mutex;
 
-use crate::config::{CapId, RegionType, MINIMUM_BAR_SIZE_FOR_MMIO};
+use crate::config::{bar_size, CapId, RegionType};
 use crate::util::num_ops::{ranges_overlap, round_up};
 use crate::{le_read_u16, le_read_u64, le_write_u16, le_write_u32, le_write_u64, PciDevBase};
 use crate::{BarAllocTrait, MsiIrqManager};
@@ -176,6 +175,8 @@ pub struct Msix {
     pub msix_cap_offset: u16,
     pub dev_id: Arc<AtomicU16>,
     pub msi_irq_manager: Option<Arc<dyn MsiIrqManager>>,
+    /// Bus mastering of the function, without which the messages are left pending.
+    bus_master: bool,
 }
 impl Msix {
     /// Construct a new MSI-X structure.
@@ -201,6 +202,7 @@ impl Msix {
             msix_cap_offset,
             dev_id,
             msi_irq_manager,
+            bus_master: false,
         };
         msix.mask_all_vectors();
         msix
@@ -211,9 +213,19 @@ impl Msix {
         self.pba.fill(0);
         self.func_masked = true;
         self.enabled = true;
+        self.bus_master = false;
         self.mask_all_vectors();
     }
 
+    /// Follow the bus master enable bit of the command register, sending the messages which
+    /// became pending while it was clear.
+    pub fn set_bus_master(&mut self, enabled: bool) {
+        let was_enabled = core::mem::replace(&mut self.bus_master, enabled);
+        if enabled && !was_enabled {
+            self.send_pending_vectors(self.dev_id.load(Ordering::Acquire));
+        }
+    }
+
     pub fn is_enabled(&self, config: &[u8]) -> bool {
         let offset: usize = self.msix_cap_offset as usize + MSIX_CAP_CONTROL as usize;
         let msix_ctl = le_read_u16(config, offset).unwrap();
@@ -312,7 +324,8 @@ impl Msix {
         }
         // let masked = self.is_vector_masked(vector);
         // debug!("Vector {} is masked: {}.", vector, masked);
-        if self.is_vector_masked(vector) {
+        // A function which is not a bus master cannot write the message.
+        if self.is_vector_masked(vector) || !self.bus_master {
             self.set_pending_vector(vector);
             return;
         }
@@ -341,12 +354,19 @@ impl Msix {
 
         if mask_state_changed && (self.enabled && !self.func_masked) {
             // debug!("msix state changed because of message control");
-            let max_vectors_nr: u16 = self.table.len() as u16 / MSIX_TABLE_ENTRY_SIZE;
-            for v in 0..max_vectors_nr {
-                if !self.is_vector_masked(v) && self.is_vector_pending(v) {
-                    self.clear_pending_vector(v);
-                    self.send_msix(v, dev_id);
-                }
+            self.send_pending_vectors(dev_id);
+        }
+    }
+
+    fn send_pending_vectors(&mut self, dev_id: u16) {
+        if !self.bus_master {
+            return;
+        }
+        let max_vectors_nr: u16 = self.table.len() as u16 / MSIX_TABLE_ENTRY_SIZE;
+        for v in 0..max_vectors_nr {
+            if !self.is_vector_masked(v) && self.is_vector_pending(v) {
+                self.clear_pending_vector(v);
+                self.send_msix(v, dev_id);
             }
         }
     }
@@ -364,15 +384,18 @@ impl Msix {
             let mut data = [0u8; 8];
             let access_offset = offset as usize + access_size as usize;
             if access_offset > cloned_msix.lock().table.len() {
-                if access_offset > cloned_msix.lock().table.len() + cloned_msix.lock().pba.len() {
+                // An access across the end of the table is illegal as well.
+                if access_offset > cloned_msix.lock().table.len() + cloned_msix.lock().pba.len()
+                    || (offset as usize) < cloned_msix.lock().table.len()
+                {
                     error!(
                         "Fail to read msix table and pba, illegal data length {}, offset {}",
                         access_size, offset
                     );
                     return Err(HyperError::OutOfRange);
                 }
-                // deal with pba read
-                let offset = offset as usize;
+                // deal with pba read, the PBA following the table
+                let offset = offset as usize - cloned_msix.lock().table.len();
                 data[0..access_size as usize].copy_from_slice(
                     &cloned_msix.lock().pba[offset..(offset + access_size as usize)],
                 );
@@ -399,7 +422,7 @@ impl Msix {
                     );
                     return Err(HyperError::OutOfRange);
                 }
-                // deal with pba read
+                // the pba is read-only
                 return Ok(());
             }
             let mut locked_msix = cloned_msix.lock();
@@ -497,8 +520,7 @@ pub fn init_msix<B: BarAllocTrait>(
         dev_id.clone(),
         msi_irq_manager,
     )));
-    let mut bar_size = ((table_size + pba_size) as u64).next_power_of_two();
-    bar_size = max(bar_size, MINIMUM_BAR_SIZE_FOR_MMIO as u64);
+    let bar_size = bar_size(RegionType::Mem32Bit, (table_size + pba_size) as u64);
     let msix_region_ops = Msix::generate_region_ops(msix.clone(), dev_id).unwrap();
     config.register_bar(
         bar_id,
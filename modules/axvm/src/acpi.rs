@@ -0,0 +1,426 @@
+//! ACPI tables describing the vCPUs and the interrupt controllers of the guests built by
+//! [`VmBuilder`](crate::VmBuilder). (ref: ACPI Specification 6.0, Chapter 5)
+//!
+//! The tables are built with the VM from its vCPU count and its [`VcpuDeviceConfig`], and loaded
+//! with its images at [`ACPI_TABLES_GPA`], in the BIOS area where an OS looks for the RSDP:
+//!
+//! - the RSDP, first, pointing at the XSDT;
+//! - the XSDT, listing the FADT, the MADT and the HPET table;
+//! - the FADT, pointing at a DSDT without any device;
+//! - the MADT, with a local APIC per vCPU, whose APIC id is its vCPU id, the IO APIC, and the
+//!   interrupt source override of IRQ 0, which is GSI 0 as every ISA IRQ, see `irq_router`;
+//! - the HPET table, if the VM has an HPET.
+//!
+//! The FADT is a hardware-reduced one: there are no PM registers, no SCI and no FACS. A Linux
+//! guest then leaves the PICs alone and does not tick with the PIT or the HPET, but with its
+//! local APIC timer. It gets the address of the RSDP in its zero page, the other guests have the
+//! tables in their memory map, as [`MEMORY_MAP_ACPI`](crate::MEMORY_MAP_ACPI).
+//!
+//! [`VcpuDeviceConfig`]: crate::VcpuDeviceConfig
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{Error, GuestPhysAddr, Result};
+
+/// Guest physical address of the ACPI tables, the RSDP first.
+pub const ACPI_TABLES_GPA: GuestPhysAddr = 0xe_0000;
+/// Room for the ACPI tables, up to the BIOS area at 0xf_0000.
+pub const ACPI_TABLES_MAX: usize = 0x1_0000;
+
+const LOCAL_APIC_BASE: u32 = 0xfee0_0000;
+/// The id of the IO APIC after a reset.
+const IOAPIC_ID: u8 = 0;
+/// The CMOS register of the century.
+const CMOS_CENTURY: u8 = 0x32;
+
+const OEM_ID: &[u8; 6] = b"AXVM  ";
+const OEM_TABLE_ID: &[u8; 8] = b"AXVMACPI";
+const OEM_REVISION: u32 = 1;
+const CREATOR_ID: &[u8; 4] = b"AXVM";
+const CREATOR_REVISION: u32 = 1;
+
+/// The header of the system description tables, with the checksum of the table at this offset.
+const SDT_HEADER_SIZE: usize = 36;
+const SDT_CHECKSUM: usize = 9;
+
+/// The ACPI 2.0 RSDP. Its first 20 bytes, the ACPI 1.0 RSDP, have their own checksum.
+const RSDP_SIZE: usize = 36;
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_CHECKSUM: usize = 8;
+const RSDP_EXTENDED_CHECKSUM: usize = 32;
+const RSDP_REVISION: u8 = 2;
+
+const XSDT_REVISION: u8 = 1;
+/// 64-bit integers in AML.
+const DSDT_REVISION: u8 = 2;
+const HPET_REVISION: u8 = 1;
+
+/// The ACPI 6.0 FADT, its fields at these offsets.
+const FADT_SIZE: usize = 276;
+const FADT_REVISION: u8 = 6;
+const FADT_DSDT: usize = 40;
+const FADT_CENTURY: usize = 108;
+const FADT_IAPC_BOOT_ARCH: usize = 109;
+const FADT_FLAGS: usize = 112;
+const FADT_X_DSDT: usize = 140;
+
+/// FADT flags.
+const FADT_WBINVD: u32 = 1 << 0;
+const FADT_NO_POWER_BUTTON: u32 = 1 << 4;
+const FADT_NO_SLEEP_BUTTON: u32 = 1 << 5;
+const FADT_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+/// IA-PC boot architecture flags of the FADT.
+const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+const BOOT_ARCH_8042: u16 = 1 << 1;
+const BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+const BOOT_ARCH_CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
+const MADT_REVISION: u8 = 4;
+/// The PC has the dual 8259 PICs.
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+/// Types of the MADT entries.
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const ISA_BUS: u8 = 0;
+/// Polarity and trigger mode of the bus, edge-triggered and active high for ISA.
+const OVERRIDE_CONFORMING: u16 = 0;
+
+/// The address space of a generic address structure.
+const GAS_SYSTEM_MEMORY: u8 = 0;
+/// Ticks between the periodic interrupts of the HPET the guest can count on.
+const HPET_MIN_TICK: u16 = 0x80;
+
+/// The platform the ACPI tables of a VM describe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AcpiPlatform {
+    pub vcpus: usize,
+    pub pic: bool,
+    /// Guest physical address of the IO APIC.
+    pub ioapic: Option<u64>,
+    /// Guest physical address of the HPET and its event timer block id.
+    pub hpet: Option<(u64, u32)>,
+    pub cmos: bool,
+    pub i8042: bool,
+    pub vga: bool,
+}
+
+/// The ACPI tables of `platform`, to load at [`ACPI_TABLES_GPA`]. Fails with `NotSupported` if a
+/// vCPU id does not fit an 8-bit APIC id, and with `InvalidParam` if the tables are larger than
+/// [`ACPI_TABLES_MAX`].
+pub(crate) fn build_tables(platform: &AcpiPlatform) -> Result<Vec<u8>> {
+    if platform.vcpus > u8::MAX as usize {
+        warn!("ACPI tables for {} vCPUs not supported", platform.vcpus);
+        return Err(Error::NotSupported);
+    }
+    let mut tables = vec![0; RSDP_SIZE];
+    let dsdt = place(&mut tables, &sdt(b"DSDT", DSDT_REVISION, &[]));
+    let mut entries = vec![
+        place(&mut tables, &fadt(platform, dsdt)),
+        place(&mut tables, &madt(platform)),
+    ];
+    if let Some((base, block_id)) = platform.hpet {
+        entries.push(place(&mut tables, &hpet(base, block_id)));
+    }
+    let entries: Vec<u8> = entries.iter().flat_map(|gpa| gpa.to_le_bytes()).collect();
+    let xsdt = place(&mut tables, &sdt(b"XSDT", XSDT_REVISION, &entries));
+    tables[..RSDP_SIZE].copy_from_slice(&rsdp(xsdt));
+    if tables.len() > ACPI_TABLES_MAX {
+        warn!("ACPI tables of {:#x} bytes, too large", tables.len());
+        return Err(Error::InvalidParam);
+    }
+    Ok(tables)
+}
+
+/// Append `table` to `tables` on a 16-byte boundary, returns its guest physical address.
+fn place(tables: &mut Vec<u8>, table: &[u8]) -> u64 {
+    let offset = (tables.len() + 15) & !15;
+    tables.resize(offset, 0);
+    tables.extend_from_slice(table);
+    (ACPI_TABLES_GPA + offset) as u64
+}
+
+/// The byte making the sum of `bytes` and itself zero.
+fn checksum(bytes: &[u8]) -> u8 {
+    let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    sum.wrapping_neg()
+}
+
+fn rsdp(xsdt: u64) -> [u8; RSDP_SIZE] {
+    let mut rsdp = [0; RSDP_SIZE];
+    rsdp[0..8].copy_from_slice(b"RSD PTR ");
+    rsdp[9..15].copy_from_slice(OEM_ID);
+    rsdp[15] = RSDP_REVISION;
+    // No RSDT, the guest takes the XSDT.
+    rsdp[20..24].copy_from_slice(&(RSDP_SIZE as u32).to_le_bytes());
+    rsdp[24..32].copy_from_slice(&xsdt.to_le_bytes());
+    rsdp[RSDP_CHECKSUM] = checksum(&rsdp[..RSDP_V1_SIZE]);
+    rsdp[RSDP_EXTENDED_CHECKSUM] = checksum(&rsdp);
+    rsdp
+}
+
+/// The system description table `signature` with `body` after its header.
+fn sdt(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+    let mut table = Vec::with_capacity(SDT_HEADER_SIZE + body.len());
+    table.extend_from_slice(signature);
+    table.extend_from_slice(&((SDT_HEADER_SIZE + body.len()) as u32).to_le_bytes());
+    table.extend_from_slice(&[revision, 0]);
+    table.extend_from_slice(OEM_ID);
+    table.extend_from_slice(OEM_TABLE_ID);
+    table.extend_from_slice(&OEM_REVISION.to_le_bytes());
+    table.extend_from_slice(CREATOR_ID);
+    table.extend_from_slice(&CREATOR_REVISION.to_le_bytes());
+    table.extend_from_slice(body);
+    table[SDT_CHECKSUM] = checksum(&table);
+    table
+}
+
+fn fadt(platform: &AcpiPlatform, dsdt: u64) -> Vec<u8> {
+    let mut body = vec![0; FADT_SIZE - SDT_HEADER_SIZE];
+    let mut put = |offset: usize, value: &[u8]| {
+        body[offset - SDT_HEADER_SIZE..][..value.len()].copy_from_slice(value);
+    };
+    put(FADT_DSDT, &(dsdt as u32).to_le_bytes());
+    put(FADT_X_DSDT, &dsdt.to_le_bytes());
+    if platform.cmos {
+        put(FADT_CENTURY, &[CMOS_CENTURY]);
+    }
+    let mut boot_arch = 0;
+    if platform.pic {
+        boot_arch |= BOOT_ARCH_LEGACY_DEVICES;
+    }
+    if platform.i8042 {
+        boot_arch |= BOOT_ARCH_8042;
+    }
+    if !platform.vga {
+        boot_arch |= BOOT_ARCH_VGA_NOT_PRESENT;
+    }
+    if !platform.cmos {
+        boot_arch |= BOOT_ARCH_CMOS_RTC_NOT_PRESENT;
+    }
+    put(FADT_IAPC_BOOT_ARCH, &boot_arch.to_le_bytes());
+    let flags = FADT_WBINVD | FADT_NO_POWER_BUTTON | FADT_NO_SLEEP_BUTTON | FADT_HW_REDUCED_ACPI;
+    put(FADT_FLAGS, &flags.to_le_bytes());
+    sdt(b"FACP", FADT_REVISION, &body)
+}
+
+fn madt(platform: &AcpiPlatform) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&LOCAL_APIC_BASE.to_le_bytes());
+    let flags = if platform.pic { MADT_PCAT_COMPAT } else { 0 };
+    body.extend_from_slice(&flags.to_le_bytes());
+    for vcpu_id in 0..platform.vcpus as u8 {
+        // The processor UID and the APIC id.
+        body.extend_from_slice(&[MADT_LOCAL_APIC, 8, vcpu_id, vcpu_id]);
+        body.extend_from_slice(&LOCAL_APIC_ENABLED.to_le_bytes());
+    }
+    if let Some(base) = platform.ioapic {
+        body.extend_from_slice(&[MADT_IO_APIC, 12, IOAPIC_ID, 0]);
+        body.extend_from_slice(&(base as u32).to_le_bytes());
+        // Its first GSI.
+        body.extend_from_slice(&0u32.to_le_bytes());
+        // IRQ 0 is GSI 0, not GSI 2 as on most PCs.
+        body.extend_from_slice(&[MADT_INTERRUPT_OVERRIDE, 10, ISA_BUS, 0]);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&OVERRIDE_CONFORMING.to_le_bytes());
+    }
+    sdt(b"APIC", MADT_REVISION, &body)
+}
+
+fn hpet(base: u64, block_id: u32) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&block_id.to_le_bytes());
+    // The generic address of the registers: no register width, offset or access size.
+    body.extend_from_slice(&[GAS_SYSTEM_MEMORY, 0, 0, 0]);
+    body.extend_from_slice(&base.to_le_bytes());
+    // The HPET number, the minimum tick and no page protection.
+    body.push(0);
+    body.extend_from_slice(&HPET_MIN_TICK.to_le_bytes());
+    body.push(0);
+    sdt(b"HPET", HPET_REVISION, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLATFORM: AcpiPlatform = AcpiPlatform {
+        vcpus: 4,
+        pic: true,
+        ioapic: Some(0xfec0_0000),
+        hpet: Some((0xfed0_0000, 0x8086_a201)),
+        cmos: true,
+        i8042: true,
+        vga: false,
+    };
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn sum(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+    }
+
+    /// The table at `gpa`, as long as its header says, which must be in `tables`.
+    fn table_at(tables: &[u8], gpa: u64) -> &[u8] {
+        let offset = gpa as usize - ACPI_TABLES_GPA;
+        assert_eq!(offset % 16, 0);
+        &tables[offset..offset + u32_at(tables, offset + 4) as usize]
+    }
+
+    /// The tables the XSDT of `tables` points at.
+    fn xsdt_entries(tables: &[u8]) -> Vec<&[u8]> {
+        let xsdt = table_at(tables, u64_at(tables, 24));
+        assert_eq!(&xsdt[..4], b"XSDT");
+        xsdt[SDT_HEADER_SIZE..]
+            .chunks(8)
+            .map(|entry| table_at(tables, u64::from_le_bytes(entry.try_into().unwrap())))
+            .collect()
+    }
+
+    /// The entries of type `kind` of `madt`.
+    fn madt_entries(madt: &[u8], kind: u8) -> Vec<&[u8]> {
+        let mut entries = Vec::new();
+        let mut offset = SDT_HEADER_SIZE + 8;
+        while offset < madt.len() {
+            let entry = &madt[offset..offset + madt[offset + 1] as usize];
+            if entry[0] == kind {
+                entries.push(entry);
+            }
+            offset += entry.len();
+        }
+        assert_eq!(offset, madt.len());
+        entries
+    }
+
+    #[test]
+    fn test_checksums() {
+        let tables = build_tables(&PLATFORM).unwrap();
+        assert_eq!(&tables[..8], b"RSD PTR ");
+        assert_eq!(tables[15], RSDP_REVISION);
+        assert_eq!(u32_at(&tables, 20) as usize, RSDP_SIZE);
+        assert_eq!(sum(&tables[..RSDP_V1_SIZE]), 0);
+        assert_eq!(sum(&tables[..RSDP_SIZE]), 0);
+
+        let entries = xsdt_entries(&tables);
+        let dsdt = table_at(&tables, u64_at(entries[0], FADT_X_DSDT));
+        let xsdt = table_at(&tables, u64_at(&tables, 24));
+        for table in entries.iter().chain([&dsdt, &xsdt]) {
+            assert_eq!(sum(table), 0, "{:?}", core::str::from_utf8(&table[..4]));
+            assert_eq!(&table[10..16], OEM_ID);
+        }
+    }
+
+    #[test]
+    fn test_xsdt() {
+        let tables = build_tables(&PLATFORM).unwrap();
+        assert!(tables.len() <= ACPI_TABLES_MAX);
+        let signatures: Vec<_> = xsdt_entries(&tables)
+            .iter()
+            .map(|table| &table[..4])
+            .collect();
+        assert_eq!(signatures, [b"FACP", b"APIC", b"HPET"]);
+
+        let fadt = xsdt_entries(&tables)[0];
+        assert_eq!(fadt.len(), FADT_SIZE);
+        assert_eq!(fadt[8], FADT_REVISION);
+        let dsdt = u64_at(fadt, FADT_X_DSDT);
+        assert_eq!(u32_at(fadt, FADT_DSDT) as u64, dsdt);
+        assert_eq!(table_at(&tables, dsdt), sdt(b"DSDT", DSDT_REVISION, &[]));
+
+        let hpet = xsdt_entries(&tables)[2];
+        assert_eq!(u32_at(hpet, SDT_HEADER_SIZE), 0x8086_a201);
+        assert_eq!(u64_at(hpet, SDT_HEADER_SIZE + 8), 0xfed0_0000);
+
+        let platform = AcpiPlatform {
+            hpet: None,
+            ..PLATFORM
+        };
+        let tables = build_tables(&platform).unwrap();
+        let signatures: Vec<_> = xsdt_entries(&tables)
+            .iter()
+            .map(|table| &table[..4])
+            .collect();
+        assert_eq!(signatures, [b"FACP", b"APIC"]);
+    }
+
+    #[test]
+    fn test_fadt() {
+        let fadt = |platform: &AcpiPlatform| fadt(platform, 0xe_0040);
+        let flags = u32_at(&fadt(&PLATFORM), FADT_FLAGS);
+        assert_ne!(flags & FADT_HW_REDUCED_ACPI, 0);
+        let boot_arch = u16_at(&fadt(&PLATFORM), FADT_IAPC_BOOT_ARCH);
+        assert_eq!(
+            boot_arch,
+            BOOT_ARCH_LEGACY_DEVICES | BOOT_ARCH_8042 | BOOT_ARCH_VGA_NOT_PRESENT
+        );
+        assert_eq!(fadt(&PLATFORM)[FADT_CENTURY], CMOS_CENTURY);
+
+        let bare = AcpiPlatform {
+            pic: false,
+            cmos: false,
+            i8042: false,
+            vga: true,
+            ..PLATFORM
+        };
+        let boot_arch = u16_at(&fadt(&bare), FADT_IAPC_BOOT_ARCH);
+        assert_eq!(boot_arch, BOOT_ARCH_CMOS_RTC_NOT_PRESENT);
+        assert_eq!(fadt(&bare)[FADT_CENTURY], 0);
+    }
+
+    #[test]
+    fn test_madt() {
+        for vcpus in [1, 4, u8::MAX as usize] {
+            let tables = build_tables(&AcpiPlatform { vcpus, ..PLATFORM }).unwrap();
+            let madt = xsdt_entries(&tables)[1];
+            assert_eq!(u32_at(madt, SDT_HEADER_SIZE), LOCAL_APIC_BASE);
+            assert_eq!(u32_at(madt, SDT_HEADER_SIZE + 4), MADT_PCAT_COMPAT);
+            // One enabled local APIC per vCPU, whose APIC id is the vCPU id.
+            let local_apics = madt_entries(madt, MADT_LOCAL_APIC);
+            assert_eq!(local_apics.len(), vcpus);
+            for (vcpu_id, entry) in local_apics.iter().enumerate() {
+                assert_eq!(entry.len(), 8);
+                assert_eq!((entry[2], entry[3]), (vcpu_id as u8, vcpu_id as u8));
+                assert_eq!(u32_at(entry, 4), LOCAL_APIC_ENABLED);
+            }
+            let ioapics = madt_entries(madt, MADT_IO_APIC);
+            assert_eq!(ioapics.len(), 1);
+            assert_eq!(u32_at(ioapics[0], 4), 0xfec0_0000);
+            assert_eq!(u32_at(ioapics[0], 8), 0);
+            // IRQ 0 to GSI 0.
+            let overrides = madt_entries(madt, MADT_INTERRUPT_OVERRIDE);
+            assert_eq!(overrides.len(), 1);
+            assert_eq!((overrides[0][2], overrides[0][3]), (ISA_BUS, 0));
+            assert_eq!(u32_at(overrides[0], 4), 0);
+        }
+
+        let platform = AcpiPlatform {
+            pic: false,
+            ioapic: None,
+            ..PLATFORM
+        };
+        let madt = madt(&platform);
+        assert_eq!(u32_at(&madt, SDT_HEADER_SIZE + 4), 0);
+        assert_eq!(madt_entries(&madt, MADT_LOCAL_APIC).len(), 4);
+        assert!(madt_entries(&madt, MADT_IO_APIC).is_empty());
+        assert!(madt_entries(&madt, MADT_INTERRUPT_OVERRIDE).is_empty());
+
+        let platform = AcpiPlatform {
+            vcpus: u8::MAX as usize + 1,
+            ..PLATFORM
+        };
+        assert!(matches!(build_tables(&platform), Err(Error::NotSupported)));
+    }
+}
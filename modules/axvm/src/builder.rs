@@ -0,0 +1,687 @@
+//! Typed construction of guest VMs.
+//!
+//! [`VmBuilder`] collects the description of a guest, checks it as a whole, and only then
+//! allocates guest RAM, loads the images and registers the VM in the VM config table:
+//!
+//! ```ignore
+//! let vm = VmBuilder::new("nimbos")
+//!     .memory_mb(16)
+//!     .load_image(NIMBOS_BIOS_LOAD_GPA, BIOS)
+//!     .load_elf(KERNEL)
+//!     .entry(NIMBOS_VM_ENTRY)
+//!     .build()?;
+//! vm.start()?;
+//! ```
+//!
+//! The VMs the host Linux creates by hypercall, which [`boot_vm`] boots, are built by it too,
+//! with the memory layout of their type and the images the host copies to their RAM itself, see
+//! [`VmBuilder::memory_layout`] and [`VmBuilder::host_loaded_images`].
+
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use memory_addr::{align_up_4k, PAGE_SIZE_4K};
+use page_table_entry::MappingFlags;
+
+use crate::acpi::{self, ACPI_TABLES_GPA};
+use crate::config::entry::{
+    vm_cfg_add_vm_entry, vm_cfg_entry, BootSegment, PciEcamCfg, ResetPolicy, UnhandledMsrPolicy,
+    UnhandledPortPolicy, VMCfgEntry, VcpuAffinity, VirtioDeviceCfg, VmType,
+};
+use crate::device::{
+    add_exit_observer, remove_exit_observer, BlockBackend, ExitObserverFn, ObserverId,
+    ObserverPhase, VcpuDeviceConfig,
+};
+use crate::elf_loader;
+use crate::image::{GuestImageHeader, CMDLINE_GPA};
+use crate::linux_loader::LinuxKernel;
+use crate::mm::{copy_to_guest, fill_guest, GuestMemoryRegion};
+use crate::vm::{
+    boot_vm, kill_vm, pause_vm, resume_vm, spawn, vm_state, wait_vm_stopped, VmJoinHandle, VmState,
+};
+use crate::{Error, GuestPhysAddr, HostPhysAddr, Result};
+
+/// Guest RAM starts at guest physical address 0.
+const GUEST_RAM_BASE: GuestPhysAddr = 0;
+
+/// `boot_vm` runs each vCPU of a guest on its own CPU.
+const MAX_GUEST_VCPUS: usize = axconfig::SMP;
+
+/// Size of the queue of a block device added by [`VmBuilder::with_virtio_blk`].
+const VIRTIO_BLK_QUEUE_SIZE: u16 = 256;
+
+/// Where the host copies the images of a VM it created, see [`VmBuilder::host_loaded_images`].
+#[derive(Debug, Clone, Copy)]
+struct HostImages {
+    kernel_gpa: GuestPhysAddr,
+    bios_gpa: GuestPhysAddr,
+    ramdisk_gpa: GuestPhysAddr,
+}
+
+/// Builder of a guest VM, see the [module documentation](self).
+pub struct VmBuilder {
+    name: String,
+    vm_type: VmType,
+    cmdline: String,
+    /// Where the command line of a bundle is loaded, see [`VmBuilder::load_bundle`].
+    cmdline_gpa: Option<GuestPhysAddr>,
+    memory_size: usize,
+    /// The memory regions of the guest, instead of `memory_size` of RAM, see
+    /// [`VmBuilder::memory_layout`].
+    memory_layout: Option<fn(&mut Vec<GuestMemoryRegion>)>,
+    host_images: Option<HostImages>,
+    /// The guest RAM is populated on demand, see [`VmBuilder::memory_on_demand`].
+    memory_on_demand: bool,
+    vcpus: usize,
+    cpu_set: usize,
+    vcpu_affinity: VcpuAffinity,
+    segments: Vec<BootSegment>,
+    /// A kernel booted through the Linux boot protocol, loaded once the RAM is known.
+    linux: Option<LinuxKernel>,
+    entry: Option<GuestPhysAddr>,
+    device_regions: Vec<GuestMemoryRegion>,
+    virtio_devices: Vec<VirtioDeviceCfg>,
+    pci_ecam: Option<PciEcamCfg>,
+    unhandled_msr_policy: UnhandledMsrPolicy,
+    unhandled_port_policy: UnhandledPortPolicy,
+    reset_policy: ResetPolicy,
+    vcpu_devices: VcpuDeviceConfig,
+    /// The guest is given ACPI tables, see [`VmBuilder::acpi`].
+    acpi: bool,
+    /// The first error of the description, reported by [`VmBuilder::build`].
+    error: Option<Error>,
+}
+
+impl VmBuilder {
+    /// A NimbOS-type guest named `name`, with one vCPU on core 0 and no memory.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: String::from(name),
+            vm_type: VmType::VmTNimbOS,
+            cmdline: String::new(),
+            cmdline_gpa: None,
+            memory_size: 0,
+            memory_layout: None,
+            host_images: None,
+            memory_on_demand: false,
+            vcpus: 1,
+            cpu_set: 1,
+            vcpu_affinity: VcpuAffinity::default(),
+            segments: Vec::new(),
+            linux: None,
+            entry: None,
+            device_regions: Vec::new(),
+            virtio_devices: Vec::new(),
+            pci_ecam: Some(PciEcamCfg::default()),
+            unhandled_msr_policy: UnhandledMsrPolicy::default(),
+            unhandled_port_policy: UnhandledPortPolicy::default(),
+            reset_policy: ResetPolicy::default(),
+            vcpu_devices: VcpuDeviceConfig::default(),
+            acpi: true,
+            error: None,
+        }
+    }
+
+    pub fn vm_type(mut self, vm_type: VmType) -> Self {
+        self.vm_type = vm_type;
+        self
+    }
+
+    pub fn cmdline(mut self, cmdline: &str) -> Self {
+        self.cmdline = String::from(cmdline);
+        self
+    }
+
+    /// Size of the guest RAM, mapped at guest physical address 0.
+    pub fn memory_mb(mut self, size_mb: usize) -> Self {
+        self.memory_size = size_mb << 20;
+        self
+    }
+
+    /// The memory regions of the guest, RAM and passed through devices, as `setup` adds them,
+    /// instead of the RAM of [`VmBuilder::memory_mb`] at guest physical address 0. Cannot be
+    /// combined with [`VmBuilder::load_linux`], which places its images in that RAM.
+    pub fn memory_layout(mut self, setup: fn(&mut Vec<GuestMemoryRegion>)) -> Self {
+        self.memory_layout = Some(setup);
+        self
+    }
+
+    /// Allocate the guest RAM page by page, as the guest first touches it, rather than all of it
+    /// when the VM is built. The pages the images are loaded to are allocated by the build.
+    pub fn memory_on_demand(mut self) -> Self {
+        self.memory_on_demand = true;
+        self
+    }
+
+    /// Number of vCPUs, vCPU 0 boots the guest and the others wait for its STARTUP IPIs. Each
+    /// runs on its own core of the cpu set.
+    pub fn vcpus(mut self, vcpus: usize) -> Self {
+        self.vcpus = vcpus;
+        self
+    }
+
+    /// Cores the guest may run on, as seen by Linux in type 1.5 mode.
+    pub fn cpu_set(mut self, cpu_set: usize) -> Self {
+        self.cpu_set = cpu_set;
+        self
+    }
+
+    /// Pin vCPU `i` to core `cores[i]` of the cpu set, one core per vCPU. The VM then boots on
+    /// the core of vCPU 0 only, see [`VmHandle::spawn`].
+    ///
+    /// With `exclusive`, the cores are dedicated to the VM: booting it fails while a vCPU of
+    /// another guest runs on one of them, and the other guests do not run there while it runs.
+    pub fn vcpu_affinity(mut self, cores: &[usize], exclusive: bool) -> Self {
+        self.vcpu_affinity = VcpuAffinity {
+            cores: cores.to_vec(),
+            exclusive,
+        };
+        self
+    }
+
+    /// Copy `image` to guest RAM at `gpa`.
+    pub fn load_image(mut self, gpa: GuestPhysAddr, image: &'static [u8]) -> Self {
+        self.segments.push(BootSegment {
+            gpa,
+            data: Cow::Borrowed(image),
+            zeroed: 0,
+        });
+        self
+    }
+
+    /// Load the `PT_LOAD` segments of the ELF64 image `elf` at their physical addresses, and
+    /// enter the guest at its entry point unless [`VmBuilder::entry`] overrides it.
+    pub fn load_elf(mut self, elf: &'static [u8]) -> Self {
+        match elf_loader::parse(elf) {
+            Ok(image) => {
+                self.segments
+                    .extend(image.segments.into_iter().map(|segment| BootSegment {
+                        gpa: segment.gpa,
+                        data: Cow::Borrowed(segment.data),
+                        zeroed: segment.zeroed,
+                    }));
+                self.entry.get_or_insert(image.entry);
+            }
+            Err(err) => {
+                warn!("VM {}: invalid ELF image: {:?}", self.name, err);
+                self.error.get_or_insert(err);
+            }
+        }
+        self
+    }
+
+    /// Load the images of the guest image bundle `bundle` where its header says, see
+    /// [`crate::GuestImageHeader`], and enter the guest at its entry point unless
+    /// [`VmBuilder::entry`] overrides it. A bundle with a header sets the VM type, a legacy one is
+    /// loaded for the VM type set before. Its command line is copied to guest memory and passed
+    /// to the guest. A bundle starting with the ELF magic is an ELF kernel, see
+    /// [`VmBuilder::load_elf`].
+    pub fn load_bundle(mut self, bundle: &'static [u8]) -> Self {
+        if elf_loader::is_elf(bundle) {
+            return self.load_elf(bundle);
+        }
+        let header = match GuestImageHeader::parse(bundle, self.vm_type) {
+            Ok(header) => header,
+            Err(err) => {
+                warn!("VM {}: invalid guest image bundle: {:?}", self.name, err);
+                self.error.get_or_insert(err);
+                return self;
+            }
+        };
+        self.vm_type = header.vm_type;
+        for segment in header.segments.iter() {
+            self.segments.push(BootSegment {
+                gpa: segment.load_gpa,
+                data: Cow::Borrowed(header.segment_data(bundle, segment)),
+                zeroed: 0,
+            });
+        }
+        if let Some(cmdline) = header.cmdline(bundle) {
+            self.cmdline = String::from_utf8_lossy(cmdline).into_owned();
+            // NUL-terminated.
+            self.segments.push(BootSegment {
+                gpa: CMDLINE_GPA,
+                data: Cow::Borrowed(cmdline),
+                zeroed: 1,
+            });
+            self.cmdline_gpa = Some(CMDLINE_GPA);
+        }
+        self.entry.get_or_insert(header.entry);
+        self
+    }
+
+    /// Boot the bzImage `bzimage` through the Linux boot protocol, with `initrd` and the
+    /// command line of [`VmBuilder::cmdline`]. Makes the guest a
+    /// Linux one, entered in real mode at the stub of the loader unless [`VmBuilder::entry`]
+    /// overrides it.
+    pub fn load_linux(mut self, bzimage: &'static [u8], initrd: Option<&'static [u8]>) -> Self {
+        match LinuxKernel::parse(bzimage, initrd) {
+            Ok(kernel) => {
+                self.vm_type = VmType::VmTLinux;
+                self.entry.get_or_insert(kernel.entry());
+                self.linux = Some(kernel);
+            }
+            Err(err) => {
+                warn!("VM {}: invalid Linux kernel: {:?}", self.name, err);
+                self.error.get_or_insert(err);
+            }
+        }
+        self
+    }
+
+    /// The host copies the kernel, the BIOS and the ramdisk of the guest to its RAM once it is
+    /// built, at the host physical addresses of [`VmHandle::host_image_hpas`], rather than the
+    /// images being loaded by the build.
+    pub fn host_loaded_images(
+        mut self,
+        kernel_gpa: GuestPhysAddr,
+        bios_gpa: GuestPhysAddr,
+        ramdisk_gpa: GuestPhysAddr,
+    ) -> Self {
+        self.host_images = Some(HostImages {
+            kernel_gpa,
+            bios_gpa,
+            ramdisk_gpa,
+        });
+        self
+    }
+
+    pub fn entry(mut self, entry: GuestPhysAddr) -> Self {
+        self.entry = Some(entry);
+        self
+    }
+
+    /// Pass through the host physical range `hpa..hpa + size` to the guest at `gpa`.
+    pub fn device_region(mut self, gpa: GuestPhysAddr, hpa: HostPhysAddr, size: usize) -> Self {
+        self.device_regions.push(GuestMemoryRegion {
+            gpa,
+            hpa,
+            size,
+            flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
+        });
+        self
+    }
+
+    /// Add a virtio device, behind the PCI host emulated for the guest.
+    pub fn virtio_device(mut self, device: VirtioDeviceCfg) -> Self {
+        self.virtio_devices.push(device);
+        self
+    }
+
+    /// Add a virtio block device serving the requests from `backend`, in the first free slot,
+    /// see [`VirtioDeviceCfg::block`].
+    pub fn with_virtio_blk(self, backend: Arc<dyn BlockBackend>) -> Self {
+        let name = format!("{}-blk{}", self.name, self.virtio_devices.len());
+        self.virtio_device(VirtioDeviceCfg::block(
+            &name,
+            backend,
+            VIRTIO_BLK_QUEUE_SIZE,
+        ))
+    }
+
+    /// The guest console: a 16550 UART at `port`, 0x3f8 for COM1, on the host console. Added to
+    /// the devices of [`VmBuilder::vcpu_devices`] unless they already have it.
+    pub fn console(mut self, port: u16) -> Self {
+        if !self.vcpu_devices.has_uart(port) {
+            self.vcpu_devices = core::mem::take(&mut self.vcpu_devices).with_uart(port);
+        }
+        self
+    }
+
+    /// The ECAM window of the PCI host, all the buses at 0xe000_0000 by default. `None` leaves
+    /// the guest with the configuration ports 0xcf8 and 0xcfc.
+    pub fn pci_ecam(mut self, ecam: Option<PciEcamCfg>) -> Self {
+        self.pci_ecam = ecam;
+        self
+    }
+
+    /// The devices emulated for each vCPU, a PC by default.
+    pub fn vcpu_devices(mut self, config: VcpuDeviceConfig) -> Self {
+        self.vcpu_devices = config;
+        self
+    }
+
+    /// Whether the guest is given ACPI tables describing its vCPUs and its interrupt controllers,
+    /// in its RAM at [`ACPI_TABLES_GPA`](crate::ACPI_TABLES_GPA), which no image may then use.
+    /// On by default.
+    pub fn acpi(mut self, enabled: bool) -> Self {
+        self.acpi = enabled;
+        self
+    }
+
+    /// What the vCPUs do on an MSR no device implements, #GP(0) by default.
+    pub fn unhandled_msr_policy(mut self, policy: UnhandledMsrPolicy) -> Self {
+        self.unhandled_msr_policy = policy;
+        self
+    }
+
+    /// What the vCPUs do on a port no device claims, crash the VM by default.
+    pub fn unhandled_port_policy(mut self, policy: UnhandledPortPolicy) -> Self {
+        self.unhandled_port_policy = policy;
+        self
+    }
+
+    /// What the VM does when its guest resets it, destroy it by default.
+    pub fn reset_policy(mut self, policy: ResetPolicy) -> Self {
+        self.reset_policy = policy;
+        self
+    }
+
+    /// The guest physical ranges of the guest RAM.
+    fn ram_ranges(&self) -> Vec<Range<GuestPhysAddr>> {
+        match self.memory_layout {
+            Some(setup) => {
+                let mut regions = Vec::new();
+                setup(&mut regions);
+                regions
+                    .iter()
+                    .filter(|region| !region.flags.contains(MappingFlags::DEVICE))
+                    .map(|region| region.gpa..region.gpa + region.size)
+                    .collect()
+            }
+            None if self.memory_size != 0 => {
+                vec![GUEST_RAM_BASE..GUEST_RAM_BASE + self.memory_size]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Check the description before any resource is allocated for it.
+    fn check(&self) -> Result {
+        if self.vcpus == 0 || self.cpu_set == 0 {
+            warn!("VM {}: no vCPU or empty cpu set", self.name);
+            return Err(Error::InvalidParam);
+        }
+        if self.vcpus > MAX_GUEST_VCPUS {
+            warn!(
+                "VM {}: {} vCPUs, more than the {} CPUs",
+                self.name, self.vcpus, MAX_GUEST_VCPUS
+            );
+            return Err(Error::NotSupported);
+        }
+        if self.vcpus > self.cpu_set.count_ones() as usize {
+            warn!(
+                "VM {}: {} vCPUs on the {} cores of cpu set {:#x}",
+                self.name,
+                self.vcpus,
+                self.cpu_set.count_ones(),
+                self.cpu_set
+            );
+            return Err(Error::InvalidParam);
+        }
+        if !self.vcpu_affinity.fits(self.vcpus, self.cpu_set) {
+            warn!(
+                "VM {}: cannot pin {} vCPUs to cores {:?} of cpu set {:#x}",
+                self.name, self.vcpus, self.vcpu_affinity.cores, self.cpu_set
+            );
+            return Err(Error::InvalidParam);
+        }
+        let ram = self.ram_ranges();
+        if ram.is_empty() {
+            warn!("VM {}: no guest RAM", self.name);
+            return Err(Error::InvalidParam);
+        }
+        let in_ram = |gpa: GuestPhysAddr| ram.iter().any(|range| range.contains(&gpa));
+        for segment in self.segments.iter() {
+            let fits = |end| {
+                ram.iter()
+                    .any(|range| range.start <= segment.gpa && end <= range.end)
+            };
+            match segment.end() {
+                Some(end) if fits(end) => {}
+                _ => {
+                    warn!(
+                        "VM {}: image at {:#x} ({:#x} bytes) does not fit in guest RAM {:#x?}",
+                        self.name,
+                        segment.gpa,
+                        segment.data.len() + segment.zeroed,
+                        ram
+                    );
+                    return Err(Error::InvalidParam);
+                }
+            }
+        }
+        for (i, a) in self.segments.iter().enumerate() {
+            let overlapping = self.segments[i + 1..]
+                .iter()
+                .find(|b| a.gpa < b.end().unwrap() && b.gpa < a.end().unwrap());
+            if let Some(b) = overlapping {
+                warn!(
+                    "VM {}: images at {:#x} and {:#x} overlap",
+                    self.name, a.gpa, b.gpa
+                );
+                return Err(Error::InvalidParam);
+            }
+        }
+        match self.entry {
+            Some(entry) if in_ram(entry) => {}
+            entry => {
+                warn!("VM {}: entry {:#x?} not in guest RAM", self.name, entry);
+                return Err(Error::InvalidParam);
+            }
+        }
+        for region in self.device_regions.iter() {
+            let overlaps_ram = ram
+                .iter()
+                .any(|ram| region.gpa < ram.end && ram.start < region.gpa + region.size);
+            if overlaps_ram {
+                warn!(
+                    "VM {}: device region overlaps guest RAM\n\t{}",
+                    self.name, region
+                );
+                return Err(Error::InvalidParam);
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocate the guest RAM, load the images, and register the VM.
+    pub fn build(mut self) -> Result<VmHandle> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        let acpi_tables = if self.acpi {
+            let platform = self.vcpu_devices.acpi_platform(self.vcpus);
+            let tables = acpi::build_tables(&platform)?;
+            let size = align_up_4k(tables.len());
+            self.segments.push(BootSegment {
+                gpa: ACPI_TABLES_GPA,
+                data: Cow::Owned(tables),
+                zeroed: 0,
+            });
+            Some((ACPI_TABLES_GPA, size))
+        } else {
+            None
+        };
+        if let Some(kernel) = self.linux.take() {
+            if self.memory_layout.is_some() {
+                warn!("VM {}: a Linux kernel needs the RAM at 0", self.name);
+                return Err(Error::InvalidParam);
+            }
+            let ram = GUEST_RAM_BASE..GUEST_RAM_BASE + self.memory_size;
+            let mut reserved: Vec<_> = self
+                .device_regions
+                .iter()
+                .map(|region| region.gpa..region.gpa + region.size)
+                .collect();
+            reserved.extend(self.pci_ecam.map(|ecam| ecam.range()));
+            let acpi_rsdp = acpi_tables.map(|(gpa, _)| gpa);
+            for segment in kernel.segments(&self.cmdline, ram, &reserved, acpi_rsdp)? {
+                self.segments.push(BootSegment {
+                    gpa: segment.gpa,
+                    data: segment.data,
+                    zeroed: 0,
+                });
+            }
+        }
+        self.check()?;
+        let entry = self.entry.ok_or(Error::InvalidParam)?;
+
+        let images = self.host_images.unwrap_or(HostImages {
+            kernel_gpa: entry,
+            bios_gpa: entry,
+            ramdisk_gpa: entry,
+        });
+        let mut cfg = VMCfgEntry::new(
+            self.name.clone(),
+            self.vm_type,
+            self.cmdline.clone(),
+            self.cpu_set,
+            images.kernel_gpa,
+            entry,
+            images.bios_gpa,
+            images.ramdisk_gpa,
+        );
+        cfg.set_vcpus(self.vcpus);
+        cfg.set_vcpu_affinity(self.vcpu_affinity.clone());
+        cfg.set_cmdline_gpa(self.cmdline_gpa);
+        cfg.set_acpi_tables(acpi_tables);
+        let device_regions = self.device_regions;
+        let memory_size = self.memory_size;
+        let memory_on_demand = self.memory_on_demand;
+        let memory_layout = self.memory_layout;
+        cfg.memory_region_editor(|regions| {
+            match memory_layout {
+                Some(setup) => setup(regions),
+                None => regions.push(GuestMemoryRegion {
+                    gpa: GUEST_RAM_BASE,
+                    hpa: 0,
+                    size: memory_size,
+                    flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+                    populate_on_demand: memory_on_demand,
+                }),
+            }
+            regions.extend(device_regions);
+        });
+        for device in self.virtio_devices.drain(..) {
+            cfg.add_virtio_device(device);
+        }
+        cfg.set_pci_ecam(self.pci_ecam);
+        cfg.set_unhandled_msr_policy(self.unhandled_msr_policy);
+        cfg.set_unhandled_port_policy(self.unhandled_port_policy);
+        cfg.set_reset_policy(self.reset_policy);
+        cfg.set_vcpu_devices(core::mem::take(&mut self.vcpu_devices));
+        cfg.set_up_memory_region()?;
+        cfg.validate()?;
+        if self.host_images.is_some() {
+            cfg.get_img_load_info();
+        }
+
+        cfg.set_boot_segments(self.segments);
+        load_segments(&cfg)?;
+
+        let vm_id = vm_cfg_add_vm_entry(cfg)?;
+        info!(
+            "VM {} built with id {}, entry {:#x}",
+            self.name, vm_id, entry
+        );
+        Ok(VmHandle {
+            vm_id: vm_id as u32,
+        })
+    }
+}
+
+/// Host physical address of the guest RAM at `gpa` of `cfg`. The pages populated on demand are
+/// allocated as the segments are copied to them.
+fn translate(cfg: &VMCfgEntry, gpa: GuestPhysAddr) -> Result<HostPhysAddr> {
+    let offset = gpa % PAGE_SIZE_4K;
+    let hpa = cfg
+        .guest_ram_page_hpa(gpa - offset)
+        .ok_or(Error::NoMemory)?;
+    Ok(hpa + offset)
+}
+
+/// Copy the boot segments of `cfg` to its RAM.
+fn load_segments(cfg: &VMCfgEntry) -> Result {
+    for segment in cfg.boot_segments() {
+        copy_to_guest(segment.gpa, &segment.data, |gpa| translate(cfg, gpa), None)?;
+        let bss = segment.gpa + segment.data.len();
+        // The pages populated on demand start zeroed.
+        if segment.zeroed != 0 && cfg.demand_region(bss).is_none() {
+            fill_guest(bss, 0, segment.zeroed, |gpa| translate(cfg, gpa), None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Zero the RAM of the VM of `cfg` and load its boot segments again, for it to reboot once it
+/// stopped, see [`crate::reset_vm`]. The VM never shared its RAM with a fork.
+pub(crate) fn reload_segments(cfg: &VMCfgEntry) -> Result {
+    cfg.release_demand_pages();
+    for region in cfg.memory_regions() {
+        if !region.flags.contains(MappingFlags::DEVICE) && !region.populate_on_demand {
+            fill_guest(region.gpa, 0, region.size, |gpa| translate(cfg, gpa), None)?;
+        }
+    }
+    load_segments(cfg)
+}
+
+/// A VM registered by [`VmBuilder::build`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmHandle {
+    vm_id: u32,
+}
+
+impl VmHandle {
+    pub fn id(&self) -> u32 {
+        self.vm_id
+    }
+
+    /// The lifecycle state of the VM, `None` until it is started.
+    pub fn state(&self) -> Option<VmState> {
+        vm_state(self.vm_id)
+    }
+
+    /// Run the VM on the current CPU, see [`boot_vm`]. Returns once its vCPU stops.
+    pub fn start(&self) -> Result {
+        boot_vm(self.vm_id as usize)
+    }
+
+    /// Pause the running VM, see [`pause_vm`].
+    pub fn pause(&self) -> Result {
+        pause_vm(self.vm_id)
+    }
+
+    /// Resume the VM paused by [`VmHandle::pause`], see [`resume_vm`].
+    pub fn resume(&self) -> Result {
+        resume_vm(self.vm_id)
+    }
+
+    /// Stop the VM before the next VM entry of its vCPUs, without waiting for it, see
+    /// [`VmHandle::wait_exit`].
+    pub fn stop(&self) -> Result {
+        kill_vm(self.vm_id)
+    }
+
+    /// The host physical addresses the host copies the BIOS, the kernel and the ramdisk of the VM
+    /// to, see [`VmBuilder::host_loaded_images`]. Zero for an image outside of the guest RAM.
+    pub fn host_image_hpas(&self) -> Option<(HostPhysAddr, HostPhysAddr, HostPhysAddr)> {
+        vm_cfg_entry(self.vm_id as usize).map(|cfg| cfg.img_load_hpas())
+    }
+
+    /// Call `f` on the exits of the VM in `phase`, until it is removed or the VM stops.
+    pub fn add_exit_observer(&self, phase: ObserverPhase, f: ExitObserverFn) -> ObserverId {
+        add_exit_observer(self.vm_id, phase, f)
+    }
+
+    /// Remove an observer added by [`VmHandle::add_exit_observer`], which may be running.
+    pub fn remove_exit_observer(&self, id: ObserverId) -> bool {
+        remove_exit_observer(id)
+    }
+
+    /// Run the VM in a new task, see [`spawn`]. Returns at once.
+    pub fn spawn(&self) -> Result<VmJoinHandle> {
+        spawn(self.vm_id as usize)
+    }
+
+    /// Block until the VM started from another CPU has stopped, and return its final state.
+    pub fn wait_exit(&self) -> VmState {
+        wait_vm_stopped(self.vm_id)
+    }
+}
@@ -0,0 +1,380 @@
+//! Deferred completion of device operations.
+//!
+//! An exit handler must not wait: a device whose operation depends on a slow backend (e.g. a
+//! queue notification of a block device waiting on host storage) hands the rest of the work to
+//! the completion worker with [`submit`], and the vCPU re-enters the guest at once. The worker
+//! is a host task which runs the operations in submission order and publishes their results.
+//!
+//! A [`DeferredOp`] goes through three contexts, with these rules:
+//!
+//! - the vCPU, in the exit handler: the device decodes the request and captures everything the
+//!   operation needs (descriptor addresses, lengths, the interrupt callback) before submitting
+//!   it. Nothing the guest can change afterwards may be read later.
+//! - the worker, in [`DeferredOp::run`]: no device lock is held and the guest memory is not
+//!   touched, only the backend and the buffers owned by the operation.
+//! - the worker, in [`DeferredOp::complete`]: the operation takes the device lock like an exit
+//!   handler would, updates the guest-visible state (used ring, status registers) and raises the
+//!   interrupt of the device through its interrupt callback.
+//!
+//! The operations of a paused VM are held, neither run nor published until it is resumed, so
+//! that the guest state does not change under a paused VM. When the VM stops, its operations are
+//! cancelled, see [`cancel_vm`]: the queued ones are never run and the running ones are not
+//! published, [`DeferredOp::cancel`] being called instead.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use axtask::WaitQueue;
+use hashbrown::HashSet;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::vm::VmState;
+use crate::Result;
+
+/// Identifies an operation handed to the worker, for the logs and for cancellation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CompletionToken {
+    pub vm_id: u32,
+    pub id: u64,
+}
+
+/// The part of a device operation finished by the completion worker, see the module
+/// documentation for what each method may touch.
+pub trait DeferredOp: Send {
+    /// Wait for the backend and do the I/O, without any device lock.
+    fn run(&mut self) -> Result;
+
+    /// Publish `result` to the guest and raise the interrupt of the device.
+    fn complete(self: Box<Self>, result: Result);
+
+    /// The VM stopped before the operation was published.
+    fn cancel(self: Box<Self>) {}
+}
+
+struct Entry {
+    token: CompletionToken,
+    op: Box<dyn DeferredOp>,
+    /// The result of `run`, `None` until the operation ran.
+    result: Option<Result>,
+}
+
+/// The operations handed to a worker: the ones of the completion worker, or of a test.
+struct Completions {
+    /// Operations to run or to publish, in submission order.
+    queue: Mutex<VecDeque<Entry>>,
+    /// Tokens of the operations submitted and not yet published or cancelled.
+    in_flight: Mutex<HashSet<CompletionToken>>,
+    /// Whether `queue` may hold entries the worker can make progress on, i.e. of VMs not paused.
+    work_available: AtomicBool,
+    next_id: AtomicU64,
+}
+
+impl Completions {
+    fn new() -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            in_flight: Mutex::new(HashSet::new()),
+            work_available: AtomicBool::new(false),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    fn submit(&self, vm_id: u32, op: Box<dyn DeferredOp>) -> CompletionToken {
+        let token = CompletionToken {
+            vm_id,
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+        };
+        self.in_flight.lock().insert(token);
+        self.queue.lock().push_back(Entry {
+            token,
+            op,
+            result: None,
+        });
+        token
+    }
+
+    fn is_pending(&self, token: CompletionToken) -> bool {
+        self.in_flight.lock().contains(&token)
+    }
+
+    fn cancel_vm(&self, vm_id: u32) {
+        let cancelled: VecDeque<Entry> = {
+            let mut queue = self.queue.lock();
+            let (cancelled, kept) = core::mem::take(&mut *queue)
+                .into_iter()
+                .partition(|entry| entry.token.vm_id == vm_id);
+            *queue = kept;
+            cancelled
+        };
+        // The operation the worker is running, if it belongs to the VM, is cancelled by the
+        // worker.
+        self.in_flight.lock().retain(|token| token.vm_id != vm_id);
+        for entry in cancelled {
+            debug!("completion {:?} cancelled", entry.token);
+            entry.op.cancel();
+        }
+    }
+
+    /// Whether operations of `vm_id` are queued.
+    fn has_queued(&self, vm_id: u32) -> bool {
+        self.queue
+            .lock()
+            .iter()
+            .any(|entry| entry.token.vm_id == vm_id)
+    }
+
+    /// Take the first entry whose VM is not paused.
+    fn take_ready(&self, is_paused: &impl Fn(u32) -> bool) -> Option<Entry> {
+        let mut queue = self.queue.lock();
+        let index = queue.iter().position(|entry| !is_paused(entry.token.vm_id));
+        match index {
+            Some(index) => queue.remove(index),
+            None => {
+                // Empty or held, sleep until a submission or a resume. Both signal after taking
+                // the queue lock, so none is missed.
+                self.work_available.store(false, Ordering::Release);
+                None
+            }
+        }
+    }
+
+    /// Run and publish the operations of the VMs not paused, until none is left.
+    fn run_ready(&self, is_paused: impl Fn(u32) -> bool) {
+        while let Some(mut entry) = self.take_ready(&is_paused) {
+            if entry.result.is_none() {
+                entry.result = Some(entry.op.run());
+                if is_paused(entry.token.vm_id) && self.is_pending(entry.token) {
+                    debug!("completion {:?} held, VM paused", entry.token);
+                    // Ahead of the later entries of the VM, which were not taken since the VM is
+                    // paused, so that they are published in order.
+                    self.queue.lock().push_front(entry);
+                    continue;
+                }
+            }
+            if self.in_flight.lock().remove(&entry.token) {
+                entry.op.complete(entry.result.unwrap());
+            } else {
+                debug!("completion {:?} cancelled", entry.token);
+                entry.op.cancel();
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref COMPLETIONS: Completions = Completions::new();
+}
+static WORKER_STARTED: AtomicBool = AtomicBool::new(false);
+static WORKER_QUEUE: WaitQueue = WaitQueue::new();
+
+/// Hand `op` to the completion worker on behalf of VM `vm_id`.
+pub fn submit(vm_id: u32, op: Box<dyn DeferredOp>) -> CompletionToken {
+    let token = COMPLETIONS.submit(vm_id, op);
+    start_worker();
+    wake_worker();
+    token
+}
+
+/// Whether the operation of `token` is still to be published.
+pub fn is_pending(token: CompletionToken) -> bool {
+    COMPLETIONS.is_pending(token)
+}
+
+/// Drop the operations of `vm_id`, once the VM stopped.
+pub(crate) fn cancel_vm(vm_id: u32) {
+    COMPLETIONS.cancel_vm(vm_id);
+}
+
+/// Publish the operations held while `vm_id` was paused.
+pub(crate) fn release_vm(vm_id: u32) {
+    if COMPLETIONS.has_queued(vm_id) {
+        wake_worker();
+    }
+}
+
+fn wake_worker() {
+    COMPLETIONS.work_available.store(true, Ordering::Release);
+    WORKER_QUEUE.notify_one(true);
+}
+
+fn start_worker() {
+    if WORKER_STARTED
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+    {
+        axtask::spawn_raw(
+            run_worker,
+            "axvm-completion".into(),
+            axconfig::TASK_STACK_SIZE,
+        );
+    }
+}
+
+fn is_paused(vm_id: u32) -> bool {
+    crate::vm::vm_state(vm_id) == Some(VmState::Paused)
+}
+
+fn run_worker() {
+    loop {
+        WORKER_QUEUE.wait_until(|| COMPLETIONS.work_available.load(Ordering::Acquire));
+        COMPLETIONS.run_ready(is_paused);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicUsize;
+
+    const VM: u32 = 1;
+    const OTHER_VM: u32 = 2;
+
+    /// The guest-visible state of a device, as `complete` publishes it.
+    #[derive(Default)]
+    struct MockDevice {
+        used: Mutex<Vec<u32>>,
+        interrupts: AtomicUsize,
+        cancelled: Mutex<Vec<u32>>,
+    }
+
+    /// An operation on a backend which answers once the test releases it.
+    struct SlowOp {
+        id: u32,
+        device: Arc<MockDevice>,
+        started: Arc<AtomicBool>,
+        released: Arc<AtomicBool>,
+        /// Run on the worker once the backend answered: a pause or a stop of the VM racing with
+        /// the operation.
+        on_run: Option<Box<dyn FnOnce() + Send>>,
+    }
+
+    impl SlowOp {
+        fn new(id: u32, device: &Arc<MockDevice>) -> Self {
+            Self {
+                id,
+                device: device.clone(),
+                started: Arc::new(AtomicBool::new(false)),
+                released: Arc::new(AtomicBool::new(true)),
+                on_run: None,
+            }
+        }
+    }
+
+    impl DeferredOp for SlowOp {
+        fn run(&mut self) -> Result {
+            self.started.store(true, Ordering::Release);
+            while !self.released.load(Ordering::Acquire) {
+                std::thread::yield_now();
+            }
+            if let Some(on_run) = self.on_run.take() {
+                on_run();
+            }
+            Ok(())
+        }
+
+        fn complete(self: Box<Self>, result: Result) {
+            assert!(result.is_ok());
+            self.device.used.lock().push(self.id);
+            self.device.interrupts.fetch_add(1, Ordering::Release);
+        }
+
+        fn cancel(self: Box<Self>) {
+            self.device.cancelled.lock().push(self.id);
+        }
+    }
+
+    #[test]
+    fn test_vcpu_runs_while_in_flight() {
+        let completions = Arc::new(Completions::new());
+        let device = Arc::new(MockDevice::default());
+        let op = SlowOp::new(1, &device);
+        let started = op.started.clone();
+        let released = op.released.clone();
+        released.store(false, Ordering::Release);
+
+        // The exit handler submits and returns at once.
+        let token = completions.submit(VM, Box::new(op));
+        let worker = {
+            let completions = completions.clone();
+            std::thread::spawn(move || completions.run_ready(|_| false))
+        };
+
+        // The vCPU keeps running the guest while the backend holds the operation.
+        let mut guest_steps = 0u64;
+        while guest_steps < 1000 || !started.load(Ordering::Acquire) {
+            guest_steps += 1;
+            assert!(completions.is_pending(token));
+            assert!(device.used.lock().is_empty());
+            std::thread::yield_now();
+        }
+        assert_eq!(device.interrupts.load(Ordering::Acquire), 0);
+
+        released.store(true, Ordering::Release);
+        worker.join().unwrap();
+        assert!(!completions.is_pending(token));
+        assert_eq!(*device.used.lock(), [1]);
+        assert_eq!(device.interrupts.load(Ordering::Acquire), 1);
+    }
+
+    #[test]
+    fn test_paused_vm() {
+        let completions = Arc::new(Completions::new());
+        let device = Arc::new(MockDevice::default());
+        let paused = Arc::new(AtomicBool::new(false));
+        let is_paused = |vm_id| vm_id == VM && paused.load(Ordering::Acquire);
+
+        // Paused while the backend runs the first operation: it is held with the next one.
+        let mut first = SlowOp::new(1, &device);
+        first.on_run = Some(Box::new({
+            let paused = paused.clone();
+            move || paused.store(true, Ordering::Release)
+        }));
+        let second = SlowOp::new(2, &device);
+        let second_started = second.started.clone();
+        completions.submit(VM, Box::new(first));
+        completions.submit(VM, Box::new(second));
+        completions.submit(OTHER_VM, Box::new(SlowOp::new(3, &device)));
+        completions.run_ready(is_paused);
+        assert_eq!(*device.used.lock(), [3]);
+        assert!(!second_started.load(Ordering::Acquire));
+        assert!(completions.has_queued(VM));
+
+        // Resumed, they are published in submission order.
+        paused.store(false, Ordering::Release);
+        completions.run_ready(is_paused);
+        assert_eq!(*device.used.lock(), [3, 1, 2]);
+        assert!(!completions.has_queued(VM));
+    }
+
+    #[test]
+    fn test_cancel_vm() {
+        let completions = Arc::new(Completions::new());
+        let device = Arc::new(MockDevice::default());
+
+        // Stopped while the backend runs the first operation: it is run but not published, and
+        // the queued one is never run.
+        let mut first = SlowOp::new(1, &device);
+        first.on_run = Some(Box::new({
+            let completions = completions.clone();
+            move || completions.cancel_vm(VM)
+        }));
+        let second = SlowOp::new(2, &device);
+        let second_started = second.started.clone();
+        let first = completions.submit(VM, Box::new(first));
+        let second = completions.submit(VM, Box::new(second));
+        let other = completions.submit(OTHER_VM, Box::new(SlowOp::new(3, &device)));
+        completions.run_ready(|_| false);
+
+        assert!(!completions.is_pending(first));
+        assert!(!completions.is_pending(second));
+        assert!(!completions.is_pending(other));
+        assert!(!second_started.load(Ordering::Acquire));
+        assert_eq!(*device.cancelled.lock(), [2, 1]);
+        assert_eq!(*device.used.lock(), [3]);
+        assert_eq!(device.interrupts.load(Ordering::Acquire), 1);
+    }
+}
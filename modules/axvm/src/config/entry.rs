@@ -1,22 +1,28 @@
+use alloc::borrow::Cow;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use spin::Mutex;
 
 use axalloc::GlobalPage;
-use axhal::mem::virt_to_phys;
+use axhal::mem::{memory_regions, phys_to_virt, virt_to_phys, MemRegionFlags};
 use hypercraft::{GuestPhysAddr, HostPhysAddr, HostVirtAddr};
 use memory_addr::PAGE_SIZE_4K;
 use page_table_entry::MappingFlags;
 
+#[cfg(target_arch = "x86_64")]
+use crate::device::VcpuDeviceConfig;
+use crate::device::{BlockBackend, NetBackend};
 use crate::mm::{GuestMemoryRegion, GuestPhysMemorySet};
 use crate::{Error, Result};
 
 // VM_ID = 0 reserved for host Linux.
 const CONFIG_VM_ID_START: usize = 1;
-const CONFIG_VM_NUM_MAX: usize = 8;
+pub(crate) const CONFIG_VM_NUM_MAX: usize = 8;
 
 #[inline]
 const fn align_up_4k(pos: usize) -> usize {
@@ -60,6 +66,87 @@ pub enum VmType {
     VmTLinux = 2,
 }
 
+/// What a vCPU does on a RDMSR or WRMSR of an MSR no device implements.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UnhandledMsrPolicy {
+    /// Panic the hypervisor, to find the MSRs a guest needs during bring-up.
+    Panic,
+    /// Inject #GP(0), like hardware does for an MSR it does not implement.
+    #[default]
+    InjectGp,
+    /// Read as zero and ignore writes, logging the access.
+    IgnoreRdZeroWrDrop,
+}
+
+/// What a vCPU does on a port I/O no device claims.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UnhandledPortPolicy {
+    /// Crash the VM, as for any VM exit nobody handles.
+    #[default]
+    Fatal,
+    /// Read as all ones and ignore writes, like a port with nothing behind it on a PC. Each port
+    /// is logged once, and listed by [`ignored_ports`](crate::ignored_ports).
+    IgnoreRdOnesWrDrop,
+}
+
+/// What a guest resetting itself does, through port 0xcf9, the i8042 or a triple fault, see
+/// [`reset_vm`](crate::reset_vm).
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetPolicy {
+    /// Boot the VM again in place, from its images, if it was built by
+    /// [`VmBuilder`](crate::VmBuilder) and never forked. It is destroyed otherwise.
+    Reboot,
+    /// Stop the VM, as a shutdown does.
+    #[default]
+    Destroy,
+}
+
+/// The cores the vCPUs of a VM are pinned to, see
+/// [`VmBuilder::vcpu_affinity`](crate::VmBuilder::vcpu_affinity).
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
+pub struct VcpuAffinity {
+    /// The core of each vCPU, by vCPU id, as seen by Linux like the cpu set. Empty if the vCPUs
+    /// may run on any core of the cpu set: vCPU 0 on the one booting the VM.
+    pub cores: Vec<usize>,
+    /// The cores are dedicated to the VM: no vCPU of another guest runs on them while it runs.
+    pub exclusive: bool,
+}
+
+impl VcpuAffinity {
+    /// Whether the cores are those of `vcpus` vCPUs, each on its own core of `cpu_set`.
+    pub(crate) fn fits(&self, vcpus: usize, cpu_set: usize) -> bool {
+        if self.cores.is_empty() {
+            return true;
+        }
+        let mut pinned = 0usize;
+        let mut fits = self.cores.len() == vcpus;
+        for &core in self.cores.iter() {
+            let bit = 1usize.checked_shl(core.try_into().unwrap_or(u32::MAX));
+            let bit = bit.unwrap_or(0);
+            fits &= cpu_set & bit != 0 && pinned & bit == 0;
+            pinned |= bit;
+        }
+        fits
+    }
+}
+
+/// Data loaded into guest RAM when the VM is built, and again when it reboots: `data` at `gpa`,
+/// followed by `zeroed` zero bytes.
+#[derive(Debug, Clone)]
+pub(crate) struct BootSegment {
+    pub gpa: GuestPhysAddr,
+    pub data: Cow<'static, [u8]>,
+    pub zeroed: usize,
+}
+
+impl BootSegment {
+    pub fn end(&self) -> Option<GuestPhysAddr> {
+        self.gpa
+            .checked_add(self.data.len())?
+            .checked_add(self.zeroed)
+    }
+}
+
 impl From<usize> for VmType {
     fn from(value: usize) -> Self {
         match value {
@@ -71,7 +158,7 @@ impl From<usize> for VmType {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 struct VMImgCfg {
     kernel_load_gpa: GuestPhysAddr,
     vm_entry_point: GuestPhysAddr,
@@ -102,6 +189,111 @@ impl VMImgCfg {
     }
 }
 
+/// Most queues of a virtio device, bounded by the saved state of its transport.
+const VIRTIO_QUEUE_NUM_MAX: usize = 32;
+/// Device ids of the virtio network, block and console devices.
+const VIRTIO_TYPE_NET: u32 = 1;
+const VIRTIO_TYPE_BLOCK: u32 = 2;
+const VIRTIO_TYPE_CONSOLE: u32 = 3;
+
+/// A virtio device of a VM, on the root bus of the PCI host emulated for it. A VM without any
+/// gets a dummy block device at 00:03.0.
+#[derive(Debug, Clone)]
+pub struct VirtioDeviceCfg {
+    pub name: String,
+    /// Device id of the virtio specification, e.g. 2 for a block device.
+    pub device_type: u32,
+    pub queue_num: usize,
+    pub queue_size: u16,
+    /// Function on the root bus, the first free slot being allocated if `None`.
+    pub devfn: Option<u8>,
+    /// The disk of a block device. Without one the device is a dummy, which never completes a
+    /// request.
+    pub block_backend: Option<Arc<dyn BlockBackend>>,
+    /// The link of a network device, and its MAC address. Without one the device is a dummy.
+    pub net_backend: Option<(Arc<dyn NetBackend>, [u8; 6])>,
+}
+
+impl VirtioDeviceCfg {
+    pub fn new(name: &str, device_type: u32, queue_num: usize, queue_size: u16) -> Self {
+        Self {
+            name: String::from(name),
+            device_type,
+            queue_num,
+            queue_size,
+            devfn: None,
+            block_backend: None,
+            net_backend: None,
+        }
+    }
+
+    /// A block device with one queue of `queue_size`, serving the requests from `backend`.
+    pub fn block(name: &str, backend: Arc<dyn BlockBackend>, queue_size: u16) -> Self {
+        let mut cfg = Self::new(name, VIRTIO_TYPE_BLOCK, 1, queue_size);
+        cfg.block_backend = Some(backend);
+        cfg
+    }
+
+    /// A network card with MAC address `mac`, a receive and a transmit queue of `queue_size`, on
+    /// `backend`.
+    pub fn net(name: &str, backend: Arc<dyn NetBackend>, mac: [u8; 6], queue_size: u16) -> Self {
+        let mut cfg = Self::new(name, VIRTIO_TYPE_NET, 2, queue_size);
+        cfg.net_backend = Some((backend, mac));
+        cfg
+    }
+
+    /// A console with a receive and a transmit queue of `queue_size`, on the host console.
+    pub fn console(name: &str, queue_size: u16) -> Self {
+        Self::new(name, VIRTIO_TYPE_CONSOLE, 2, queue_size)
+    }
+
+    /// Place the device at `devfn` instead of the first free slot.
+    pub fn devfn(mut self, devfn: u8) -> Self {
+        self.devfn = Some(devfn);
+        self
+    }
+}
+
+/// Default base of the ECAM window, above the guest RAM and below the IO APIC.
+pub const PCI_ECAM_DEFAULT_BASE: GuestPhysAddr = 0xe000_0000;
+/// Configuration space of a bus in the ECAM window: 32 devices of 8 functions of 4 KiB.
+pub const PCI_ECAM_BUS_SIZE: usize = 1 << 20;
+/// Buses an ECAM window can cover.
+pub const PCI_ECAM_MAX_BUSES: usize = 256;
+
+/// The ECAM (MMCONFIG) window of the PCI host emulated for a VM: the configuration space of bus
+/// `b`, device `d` and function `f` is the 4 KiB at `base + (b << 20 | d << 15 | f << 12)`.
+///
+/// No MCFG table is generated for the guest, it must be told `base` and `buses` by its own
+/// configuration, e.g. its kernel command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciEcamCfg {
+    pub base: GuestPhysAddr,
+    /// Buses 0 to `buses - 1` are decoded.
+    pub buses: usize,
+}
+
+impl PciEcamCfg {
+    pub fn new(base: GuestPhysAddr, buses: usize) -> Self {
+        Self { base, buses }
+    }
+
+    pub fn size(&self) -> usize {
+        self.buses * PCI_ECAM_BUS_SIZE
+    }
+
+    pub fn range(&self) -> Range<GuestPhysAddr> {
+        self.base..self.base + self.size()
+    }
+}
+
+impl Default for PciEcamCfg {
+    /// All the buses, 256 MiB at [`PCI_ECAM_DEFAULT_BASE`].
+    fn default() -> Self {
+        Self::new(PCI_ECAM_DEFAULT_BASE, PCI_ECAM_MAX_BUSES)
+    }
+}
+
 #[derive(Debug)]
 pub struct VMCfgEntry {
     vm_id: usize,
@@ -109,16 +301,44 @@ pub struct VMCfgEntry {
     vm_type: VmType,
 
     cmdline: String,
+    /// Where the command line was copied in guest memory, passed to vCPU 0 in RSI.
+    cmdline_gpa: Option<GuestPhysAddr>,
+    /// Where the ACPI tables were loaded in guest memory, and their size.
+    acpi_tables: Option<(GuestPhysAddr, usize)>,
+    /// The images loaded into guest RAM by [`VmBuilder`](crate::VmBuilder), for a reboot.
+    boot_segments: Vec<BootSegment>,
     /// The cpu_set here refers to the `core_id` from Linux's perspective. \
     /// Therefore, when looking for the corresponding `cpu_id`, 
     /// we need to perform a conversion using `core_id_to_cpu_id`.
     cpu_set: usize,
+    /// Number of vCPUs, each running on its own core of `cpu_set`.
+    vcpus: usize,
+    vcpu_affinity: VcpuAffinity,
 
     img_cfg: VMImgCfg,
 
     memory_regions: Vec<GuestMemoryRegion>,
-    physical_pages: BTreeMap<usize, GlobalPage>,
+    /// The RAM of the regions allocated eagerly, by region index, shared with the forks.
+    physical_pages: BTreeMap<usize, Arc<GlobalPage>>,
+    /// The single pages, by guest physical address: those of the regions populated on demand
+    /// allocated so far, and the private copies of the pages shared with a fork, which replace
+    /// those of their region.
+    single_pages: Mutex<BTreeMap<GuestPhysAddr, Arc<GlobalPage>>>,
+    /// Number of nested page faults which mapped a page populated on demand.
+    faulted_pages: AtomicUsize,
+    /// Number of pages shared with a fork this VM copied.
+    copied_pages: AtomicUsize,
+    /// Whether the RAM was ever shared with a fork, its write faults breaking the sharing.
+    copy_on_write: AtomicBool,
     memory_set: Option<GuestPhysMemorySet>,
+    virtio_devices: Vec<VirtioDeviceCfg>,
+    /// The ECAM window of the PCI host, none if the guest only has the configuration ports.
+    pci_ecam: Option<PciEcamCfg>,
+    unhandled_msr_policy: UnhandledMsrPolicy,
+    unhandled_port_policy: UnhandledPortPolicy,
+    reset_policy: ResetPolicy,
+    #[cfg(target_arch = "x86_64")]
+    vcpu_devices: VcpuDeviceConfig,
 }
 
 impl VMCfgEntry {
@@ -137,7 +357,12 @@ impl VMCfgEntry {
             name,
             vm_type,
             cmdline,
+            cmdline_gpa: None,
+            acpi_tables: None,
+            boot_segments: Vec::new(),
             cpu_set,
+            vcpus: 1,
+            vcpu_affinity: VcpuAffinity::default(),
             img_cfg: VMImgCfg::new(
                 kernel_load_gpa,
                 vm_entry_point,
@@ -146,24 +371,141 @@ impl VMCfgEntry {
             ),
             memory_regions: Vec::new(),
             physical_pages: BTreeMap::new(),
+            single_pages: Mutex::new(BTreeMap::new()),
+            faulted_pages: AtomicUsize::new(0),
+            copied_pages: AtomicUsize::new(0),
+            copy_on_write: AtomicBool::new(false),
             memory_set: None,
+            virtio_devices: Vec::new(),
+            pci_ecam: Some(PciEcamCfg::default()),
+            unhandled_msr_policy: UnhandledMsrPolicy::default(),
+            unhandled_port_policy: UnhandledPortPolicy::default(),
+            reset_policy: ResetPolicy::default(),
+            #[cfg(target_arch = "x86_64")]
+            vcpu_devices: VcpuDeviceConfig::default(),
         }
     }
 
+    pub fn get_vm_id(&self) -> usize {
+        self.vm_id
+    }
+
     pub fn get_cpu_set(&self) -> usize {
         self.cpu_set
     }
 
+    pub fn get_vcpus(&self) -> usize {
+        self.vcpus
+    }
+
+    pub fn set_vcpus(&mut self, vcpus: usize) {
+        self.vcpus = vcpus;
+    }
+
+    pub fn vcpu_affinity(&self) -> &VcpuAffinity {
+        &self.vcpu_affinity
+    }
+
+    pub fn set_vcpu_affinity(&mut self, affinity: VcpuAffinity) {
+        self.vcpu_affinity = affinity;
+    }
+
     pub fn get_vm_type(&self) -> VmType {
         self.vm_type
     }
 
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
     pub fn get_vm_entry(&self) -> GuestPhysAddr {
         self.img_cfg.vm_entry_point
     }
 
+    /// The guest physical address of the command line, if it was copied to guest memory.
+    pub fn cmdline_gpa(&self) -> Option<GuestPhysAddr> {
+        self.cmdline_gpa
+    }
+
+    pub fn set_cmdline_gpa(&mut self, gpa: Option<GuestPhysAddr>) {
+        self.cmdline_gpa = gpa;
+    }
+
+    /// The guest physical address and the size of the ACPI tables, the RSDP first, if the guest
+    /// was given some.
+    pub fn acpi_tables(&self) -> Option<(GuestPhysAddr, usize)> {
+        self.acpi_tables
+    }
+
+    pub fn set_acpi_tables(&mut self, tables: Option<(GuestPhysAddr, usize)>) {
+        self.acpi_tables = tables;
+    }
+
+    pub(crate) fn boot_segments(&self) -> &[BootSegment] {
+        &self.boot_segments
+    }
+
+    pub(crate) fn set_boot_segments(&mut self, segments: Vec<BootSegment>) {
+        self.boot_segments = segments;
+    }
+
+    pub fn virtio_devices(&self) -> &[VirtioDeviceCfg] {
+        &self.virtio_devices
+    }
+
+    pub fn add_virtio_device(&mut self, device: VirtioDeviceCfg) {
+        self.virtio_devices.push(device);
+    }
+
+    pub fn pci_ecam(&self) -> Option<PciEcamCfg> {
+        self.pci_ecam
+    }
+
+    pub fn set_pci_ecam(&mut self, ecam: Option<PciEcamCfg>) {
+        self.pci_ecam = ecam;
+    }
+
+    pub fn unhandled_msr_policy(&self) -> UnhandledMsrPolicy {
+        self.unhandled_msr_policy
+    }
+
+    pub fn set_unhandled_msr_policy(&mut self, policy: UnhandledMsrPolicy) {
+        self.unhandled_msr_policy = policy;
+    }
+
+    pub fn unhandled_port_policy(&self) -> UnhandledPortPolicy {
+        self.unhandled_port_policy
+    }
+
+    pub fn set_unhandled_port_policy(&mut self, policy: UnhandledPortPolicy) {
+        self.unhandled_port_policy = policy;
+    }
+
+    pub fn reset_policy(&self) -> ResetPolicy {
+        self.reset_policy
+    }
+
+    pub fn set_reset_policy(&mut self, policy: ResetPolicy) {
+        self.reset_policy = policy;
+    }
+
+    /// The devices emulated for each vCPU, a PC by default.
+    #[cfg(target_arch = "x86_64")]
+    pub fn vcpu_devices(&self) -> &VcpuDeviceConfig {
+        &self.vcpu_devices
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_vcpu_devices(&mut self, config: VcpuDeviceConfig) {
+        self.vcpu_devices = config;
+    }
+
     pub fn add_physical_pages(&mut self, index: usize, pages: GlobalPage) {
-        self.physical_pages.insert(index, pages);
+        self.physical_pages.insert(index, Arc::new(pages));
+    }
+
+    pub fn memory_regions(&self) -> &[GuestMemoryRegion] {
+        &self.memory_regions
     }
 
     pub fn memory_region_editor<F>(&mut self, f: F)
@@ -177,8 +519,9 @@ impl VMCfgEntry {
     /// Alloc physical memory region for guest ram memory.
     pub fn set_up_memory_region(&mut self) -> Result {
         for (index, region) in self.memory_regions.iter_mut().enumerate() {
-            // We do not need to alloc physical memory region for device regions.
-            if region.flags.contains(MappingFlags::DEVICE) {
+            // We do not need to alloc physical memory region for device regions, nor for the RAM
+            // populated on demand.
+            if region.flags.contains(MappingFlags::DEVICE) || region.populate_on_demand {
                 continue;
             }
             let ram_size = align_up_4k(region.size);
@@ -201,23 +544,436 @@ impl VMCfgEntry {
                 region
             );
 
-            self.physical_pages.insert(index, physical_pages);
+            self.physical_pages.insert(index, Arc::new(physical_pages));
         }
 
         Ok(())
     }
 
+    /// Sanity check the configuration before a VM is built from it.
+    ///
+    /// Each vCPU needs its own core of the cpu set, the one it is pinned to if any. Every region
+    /// must be non-empty and must not wrap around the address space, RAM regions
+    /// must be backed by the pages allocated for them, device regions must not map hypervisor
+    /// memory, and the entry point must lie in guest RAM. The regions populated on demand must be
+    /// RAM, page aligned. The ECAM window must be 1 MiB aligned
+    /// and must not overlap a region.
+    pub fn validate(&self) -> Result {
+        if self.cpu_set == 0 {
+            warn!("VM [{}] has an empty cpu set", self.vm_id);
+            return Err(Error::InvalidParam);
+        }
+        if self.vcpus == 0
+            || self.vcpus > axconfig::SMP
+            || self.vcpus > self.cpu_set.count_ones() as usize
+        {
+            warn!(
+                "VM [{}] cannot run {} vCPU(s) on cpu set {:#x}",
+                self.vm_id, self.vcpus, self.cpu_set
+            );
+            return Err(Error::InvalidParam);
+        }
+        if !self.vcpu_affinity.fits(self.vcpus, self.cpu_set) {
+            warn!(
+                "VM [{}] cannot pin its {} vCPU(s) to cores {:?} of cpu set {:#x}",
+                self.vm_id, self.vcpus, self.vcpu_affinity.cores, self.cpu_set
+            );
+            return Err(Error::InvalidParam);
+        }
+        for (index, region) in self.memory_regions.iter().enumerate() {
+            if region.size == 0
+                || region.gpa.checked_add(region.size).is_none()
+                || region.hpa.checked_add(region.size).is_none()
+            {
+                warn!("VM [{}] invalid memory region\n\t{}", self.vm_id, region);
+                return Err(Error::InvalidParam);
+            }
+            if region.populate_on_demand {
+                if region.flags.contains(MappingFlags::DEVICE)
+                    || region.gpa % PAGE_SIZE_4K != 0
+                    || region.size % PAGE_SIZE_4K != 0
+                {
+                    warn!(
+                        "VM [{}] region populated on demand is not page aligned RAM\n\t{}",
+                        self.vm_id, region
+                    );
+                    return Err(Error::InvalidParam);
+                }
+            } else if region.flags.contains(MappingFlags::DEVICE) {
+                let overlapped = memory_regions()
+                    .filter(|r| !r.flags.contains(MemRegionFlags::DEVICE))
+                    .find(|r| {
+                        let start = r.paddr.as_usize();
+                        region.hpa < start + r.size && start < region.hpa + region.size
+                    });
+                if let Some(r) = overlapped {
+                    warn!(
+                        "VM [{}] device region overlaps hypervisor memory {:?}\n\t{}",
+                        self.vm_id, r, region
+                    );
+                    return Err(Error::InvalidParam);
+                }
+            } else {
+                match self.physical_pages.get(&index) {
+                    Some(pages)
+                        if pages.start_paddr(virt_to_phys).as_usize() == region.hpa
+                            && pages.size() >= region.size => {}
+                    _ => {
+                        warn!(
+                            "VM [{}] RAM region is not backed by allocated memory\n\t{}",
+                            self.vm_id, region
+                        );
+                        return Err(Error::InvalidParam);
+                    }
+                }
+            }
+        }
+        if self.ram_region(self.img_cfg.vm_entry_point).is_none() {
+            warn!(
+                "VM [{}] entry {:#x} not in ram memory range",
+                self.vm_id, self.img_cfg.vm_entry_point
+            );
+            return Err(Error::InvalidParam);
+        }
+        if let Some(ecam) = self.pci_ecam {
+            if ecam.buses == 0
+                || ecam.buses > PCI_ECAM_MAX_BUSES
+                || ecam.base % PCI_ECAM_BUS_SIZE != 0
+                || ecam.base.checked_add(ecam.size()).is_none()
+            {
+                warn!("VM [{}] invalid PCI ECAM window {:#x?}", self.vm_id, ecam);
+                return Err(Error::InvalidParam);
+            }
+            let range = ecam.range();
+            if let Some(region) = self.overlapping_region(range.clone()) {
+                warn!(
+                    "VM [{}] PCI ECAM window {:#x?} overlaps a memory region\n\t{}",
+                    self.vm_id, range, region
+                );
+                return Err(Error::InvalidParam);
+            }
+        }
+        for (index, device) in self.virtio_devices.iter().enumerate() {
+            if device.queue_num == 0
+                || device.queue_num > VIRTIO_QUEUE_NUM_MAX
+                || !device.queue_size.is_power_of_two()
+            {
+                warn!(
+                    "VM [{}] virtio device {} has invalid queues: {:?}",
+                    self.vm_id, device.name, device
+                );
+                return Err(Error::InvalidParam);
+            }
+            if device.block_backend.is_some()
+                && (device.device_type != VIRTIO_TYPE_BLOCK || device.queue_num != 1)
+            {
+                warn!(
+                    "VM [{}] virtio device {} has a disk but is not a single queue block device",
+                    self.vm_id, device.name
+                );
+                return Err(Error::InvalidParam);
+            }
+            if let Some((_, mac)) = &device.net_backend {
+                // Neither multicast nor all zeros.
+                if device.device_type != VIRTIO_TYPE_NET
+                    || device.queue_num != 2
+                    || mac[0] & 1 != 0
+                    || mac.iter().all(|&byte| byte == 0)
+                {
+                    warn!(
+                        "VM [{}] virtio device {} has a link but is not a network card with a \
+                         receive and a transmit queue and a unicast MAC address",
+                        self.vm_id, device.name
+                    );
+                    return Err(Error::InvalidParam);
+                }
+            }
+            if device.device_type == VIRTIO_TYPE_CONSOLE && device.queue_num != 2 {
+                warn!(
+                    "VM [{}] virtio console {} needs a receive and a transmit queue",
+                    self.vm_id, device.name
+                );
+                return Err(Error::InvalidParam);
+            }
+            if self.virtio_devices[..index]
+                .iter()
+                .any(|other| other.name == device.name)
+            {
+                warn!(
+                    "VM [{}] has two virtio devices named {}",
+                    self.vm_id, device.name
+                );
+                return Err(Error::InvalidParam);
+            }
+        }
+        Ok(())
+    }
+
     pub fn generate_guest_phys_memory_set(&self) -> Result<GuestPhysMemorySet> {
         info!("Create VM [{}] nested page table", self.vm_id);
 
         // create nested page table and add mapping
         let mut gpm = GuestPhysMemorySet::new()?;
-        for r in &self.memory_regions {
-            gpm.map_region(r.clone().into())?;
+        // The RAM populated on demand is mapped page by page, see `crate::mm::handle_ram_fault`,
+        // and the RAM shared with a fork read-only.
+        for (index, r) in self.memory_regions.iter().enumerate() {
+            if r.populate_on_demand {
+                continue;
+            }
+            let mut r = r.clone();
+            if self
+                .physical_pages
+                .get(&index)
+                .map_or(false, |pages| self.is_shared(pages))
+            {
+                r.flags.remove(MappingFlags::WRITE);
+            }
+            gpm.map_region(r.into())?;
+        }
+        // The private copies of the pages of these regions replace them.
+        for (&gpa, page) in self.single_pages.lock().iter() {
+            let Some(region) = self.ram_region(gpa).filter(|r| !r.populate_on_demand) else {
+                continue;
+            };
+            let mut flags = region.flags;
+            if self.is_shared(page) {
+                flags.remove(MappingFlags::WRITE);
+            }
+            gpm.map_page(gpa, page.start_paddr(virt_to_phys).as_usize(), flags)?;
         }
         Ok(gpm)
     }
 
+    /// A memory region over part of `range`, mapped in the nested page table: the guest accesses
+    /// to `range` do not all exit.
+    pub fn overlapping_region(&self, range: Range<GuestPhysAddr>) -> Option<&GuestMemoryRegion> {
+        self.memory_regions
+            .iter()
+            .find(|r| r.gpa < range.end && range.start < r.gpa + r.size)
+    }
+
+    /// Host physical address of the guest page at `gpa`, if the whole page is guest RAM. A page
+    /// populated on demand is allocated if it was not yet, a page shared with a fork copied and
+    /// the page marked dirty if the dirty pages of the VM are tracked: the hypervisor may write
+    /// to it. See [`VMCfgEntry::ram_page`] to only read it.
+    pub fn guest_ram_page_hpa(&self, gpa: GuestPhysAddr) -> Option<HostPhysAddr> {
+        let (hpa, shared) = self.ram_page(gpa)?;
+        #[cfg(target_arch = "x86_64")]
+        {
+            crate::mm::mark_dirty(self.get_vm_id() as u32, gpa);
+            if shared {
+                return crate::mm::unshare_page(self, gpa).ok();
+            }
+        }
+        Some(hpa)
+    }
+
+    /// Host physical address of the RAM page at `gpa`, page aligned, and whether it is shared
+    /// with a fork. A page populated on demand is allocated if it was not yet.
+    pub(crate) fn ram_page(&self, gpa: GuestPhysAddr) -> Option<(HostPhysAddr, bool)> {
+        if self.demand_region(gpa).is_some() {
+            self.populate_page(gpa).ok()?;
+        }
+        self.present_ram_page(gpa)
+    }
+
+    /// As [`VMCfgEntry::ram_page`], `None` for a page populated on demand not allocated yet.
+    pub(crate) fn present_ram_page(&self, gpa: GuestPhysAddr) -> Option<(HostPhysAddr, bool)> {
+        if gpa % PAGE_SIZE_4K != 0 {
+            return None;
+        }
+        if let Some(page) = self.single_pages.lock().get(&gpa) {
+            return Some((
+                page.start_paddr(virt_to_phys).as_usize(),
+                self.is_shared(page),
+            ));
+        }
+        let (index, region) = self.memory_regions.iter().enumerate().find(|(_, region)| {
+            !region.flags.contains(MappingFlags::DEVICE)
+                && (region.gpa..region.gpa + region.size).contains(&gpa)
+        })?;
+        let pages = self.physical_pages.get(&index)?;
+        let offset = gpa - region.gpa;
+        (gpa + PAGE_SIZE_4K <= region.gpa + region.size && offset + PAGE_SIZE_4K <= pages.size())
+            .then(|| {
+                (
+                    pages.start_paddr(virt_to_phys).as_usize() + offset,
+                    self.is_shared(pages),
+                )
+            })
+    }
+
+    /// Make the RAM page at `gpa`, page aligned, private to this VM, copying it if it is shared
+    /// with a fork. Fails with `InvalidParam` if `gpa` is not a RAM page, with `NoMemory` if no
+    /// page is left for the copy.
+    ///
+    /// The caller serializes the calls for a VM, see `crate::mm::unshare_page`.
+    pub(crate) fn unshare_page(&self, gpa: GuestPhysAddr) -> Result<UnsharedPage> {
+        let (hpa, shared) = self.ram_page(gpa).ok_or(Error::InvalidParam)?;
+        if !shared {
+            return Ok(UnsharedPage {
+                hpa,
+                copied: false,
+                old: None,
+            });
+        }
+        let mut copy = GlobalPage::alloc().map_err(|e| {
+            warn!(
+                "VM [{}] failed to copy shared guest page {:#x}, err {:?}",
+                self.vm_id, gpa, e
+            );
+            Error::NoMemory
+        })?;
+        // The page stays allocated meanwhile, this VM referencing it until it is replaced below.
+        let page =
+            unsafe { core::slice::from_raw_parts(phys_to_virt(hpa.into()).as_ptr(), PAGE_SIZE_4K) };
+        copy.as_slice_mut().copy_from_slice(page);
+        let copy_hpa = copy.start_paddr(virt_to_phys).as_usize();
+        let old = self.single_pages.lock().insert(gpa, Arc::new(copy));
+        self.copied_pages.fetch_add(1, Ordering::Relaxed);
+        Ok(UnsharedPage {
+            hpa: copy_hpa,
+            copied: true,
+            old,
+        })
+    }
+
+    /// Whether `pages` of this VM also back the RAM of another VM.
+    fn is_shared(&self, pages: &Arc<GlobalPage>) -> bool {
+        self.copy_on_write() && Arc::strong_count(pages) > 1
+    }
+
+    /// The RAM region populated on demand containing `gpa`.
+    pub fn demand_region(&self, gpa: GuestPhysAddr) -> Option<&GuestMemoryRegion> {
+        self.ram_region(gpa)
+            .filter(|region| region.populate_on_demand)
+    }
+
+    /// Host physical address of the page at `gpa` of a region populated on demand, allocating
+    /// it zeroed if it was not yet. Fails with `InvalidParam` if `gpa` is not such a page, with
+    /// `NoMemory` if no page is left.
+    pub fn populate_page(&self, gpa: GuestPhysAddr) -> Result<HostPhysAddr> {
+        if gpa % PAGE_SIZE_4K != 0 || self.demand_region(gpa).is_none() {
+            return Err(Error::InvalidParam);
+        }
+        let mut pages = self.single_pages.lock();
+        if let Some(page) = pages.get(&gpa) {
+            return Ok(page.start_paddr(virt_to_phys).as_usize());
+        }
+        let page = GlobalPage::alloc_zero().map_err(|e| {
+            warn!(
+                "VM [{}] failed to populate guest page {:#x}, err {:?}",
+                self.vm_id, gpa, e
+            );
+            Error::NoMemory
+        })?;
+        let hpa = page.start_paddr(virt_to_phys).as_usize();
+        pages.insert(gpa, Arc::new(page));
+        Ok(hpa)
+    }
+
+    /// Release the pages of the regions populated on demand, of a VM which stopped and never
+    /// shared its RAM with a fork. They are allocated zeroed again as they are touched.
+    pub(crate) fn release_demand_pages(&self) {
+        self.single_pages.lock().clear();
+    }
+
+    /// Number of pages of the regions populated on demand allocated so far, by the guest or by
+    /// the hypervisor writing to them.
+    pub fn populated_pages(&self) -> usize {
+        self.single_pages
+            .lock()
+            .keys()
+            .filter(|&&gpa| self.demand_region(gpa).is_some())
+            .count()
+    }
+
+    /// Number of pages populated on demand the guest faulted in.
+    pub fn faulted_pages(&self) -> usize {
+        self.faulted_pages.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_faulted_page(&self) {
+        self.faulted_pages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of pages shared with a fork this VM copied, on its writes or those of the
+    /// hypervisor.
+    pub fn copied_pages(&self) -> usize {
+        self.copied_pages.load(Ordering::Relaxed)
+    }
+
+    /// Whether the RAM of this VM was ever shared with a fork.
+    pub(crate) fn copy_on_write(&self) -> bool {
+        self.copy_on_write.load(Ordering::Relaxed)
+    }
+
+    /// A configuration named `name` for a copy of this VM, whose RAM shares the pages of this
+    /// one. Both are to map them read-only, see `crate::vm::fork_vm`. The virtio devices are not
+    /// copied, their backends being this VM's.
+    pub(crate) fn fork(&self, name: String) -> VMCfgEntry {
+        self.copy_on_write.store(true, Ordering::Relaxed);
+        let single_pages = self.single_pages.lock().clone();
+        self.copy(name, self.physical_pages.clone(), single_pages, true)
+    }
+
+    /// A configuration named `name` for a VM as this one with RAM of its own, zeroed, e.g. for
+    /// `crate::vm::restore_vm`. The virtio devices are not copied.
+    pub(crate) fn blank_copy(&self, name: String) -> Result<VMCfgEntry> {
+        let mut cfg = self.copy(name, BTreeMap::new(), BTreeMap::new(), false);
+        cfg.set_up_memory_region()?;
+        for pages in cfg.physical_pages.values_mut() {
+            if let Some(pages) = Arc::get_mut(pages) {
+                pages.zero();
+            }
+        }
+        Ok(cfg)
+    }
+
+    fn copy(
+        &self,
+        name: String,
+        physical_pages: BTreeMap<usize, Arc<GlobalPage>>,
+        single_pages: BTreeMap<GuestPhysAddr, Arc<GlobalPage>>,
+        copy_on_write: bool,
+    ) -> VMCfgEntry {
+        VMCfgEntry {
+            vm_id: 0xdeaf_beef,
+            name,
+            vm_type: self.vm_type,
+            cmdline: self.cmdline.clone(),
+            cmdline_gpa: self.cmdline_gpa,
+            acpi_tables: self.acpi_tables,
+            boot_segments: self.boot_segments.clone(),
+            cpu_set: self.cpu_set,
+            vcpus: self.vcpus,
+            vcpu_affinity: self.vcpu_affinity.clone(),
+            img_cfg: self.img_cfg.clone(),
+            memory_regions: self.memory_regions.clone(),
+            physical_pages,
+            single_pages: Mutex::new(single_pages),
+            faulted_pages: AtomicUsize::new(0),
+            copied_pages: AtomicUsize::new(0),
+            copy_on_write: AtomicBool::new(copy_on_write),
+            memory_set: None,
+            virtio_devices: Vec::new(),
+            pci_ecam: self.pci_ecam,
+            unhandled_msr_policy: self.unhandled_msr_policy,
+            unhandled_port_policy: self.unhandled_port_policy,
+            reset_policy: self.reset_policy,
+            #[cfg(target_arch = "x86_64")]
+            vcpu_devices: self.vcpu_devices.clone(),
+        }
+    }
+
+    /// The RAM region containing `gpa`.
+    pub(crate) fn ram_region(&self, gpa: GuestPhysAddr) -> Option<&GuestMemoryRegion> {
+        self.memory_regions.iter().find(|region| {
+            !region.flags.contains(MappingFlags::DEVICE)
+                && (region.gpa..region.gpa + region.size).contains(&gpa)
+        })
+    }
+
     fn gpa_to_hpa_inside_ram_memory_region(&self, addr: GuestPhysAddr) -> Option<HostPhysAddr> {
         for (index, region) in self.memory_regions.iter().enumerate() {
             if region.flags.contains(MappingFlags::DEVICE) {
@@ -289,6 +1045,24 @@ impl VMCfgEntry {
             self.img_cfg.ramdisk_load_hpa,
         )
     }
+
+    /// The host physical addresses found by [`VMCfgEntry::get_img_load_info`].
+    pub fn img_load_hpas(&self) -> (HostPhysAddr, HostPhysAddr, HostPhysAddr) {
+        (
+            self.img_cfg.bios_load_hpa,
+            self.img_cfg.kernel_load_hpa,
+            self.img_cfg.ramdisk_load_hpa,
+        )
+    }
+}
+
+/// A RAM page made private to its VM by [`VMCfgEntry::unshare_page`].
+pub(crate) struct UnsharedPage {
+    pub hpa: HostPhysAddr,
+    /// Whether the page was copied, `old` being the memory it was shared in: the caller releases
+    /// it once the nested page table no longer maps it.
+    pub copied: bool,
+    pub old: Option<Arc<GlobalPage>>,
 }
 
 static GLOBAL_VM_CFG_TABLE: Mutex<VmConfigTable> = Mutex::new(VmConfigTable::new());
@@ -298,6 +1072,16 @@ pub fn vm_cfg_entry(vm_id: usize) -> Option<Arc<VMCfgEntry>> {
     return vm_configs.entries.get(&vm_id).cloned();
 }
 
+/// All the VM config entries, by VM id.
+pub fn vm_cfg_entries() -> Vec<Arc<VMCfgEntry>> {
+    GLOBAL_VM_CFG_TABLE
+        .lock()
+        .entries
+        .values()
+        .cloned()
+        .collect()
+}
+
 /* Add VM config entry to DEF_VM_CONFIG_TABLE
  *
  * @param[in] vm_cfg_entry: new added VM config entry.
@@ -1,5 +1,5 @@
 use super::{BIOS_ENTRY, BIOS_PADDR, BIOS_SIZE, GUEST_PHYS_MEMORY_BASE, GUEST_PHYS_MEMORY_SIZE};
-use crate::mm::{GuestMemoryRegion, GuestPhysMemorySet};
+use crate::mm::{copy_to_guest, GuestMemoryRegion, GuestPhysMemorySet};
 use crate::{phys_to_virt, virt_to_phys, Result as HyperResult};
 use hypercraft::{GuestPhysAddr, HostPhysAddr, HostVirtAddr};
 
@@ -20,7 +20,12 @@ fn gpa_as_mut_ptr(id: usize, guest_paddr: GuestPhysAddr) -> *mut u8 {
 }
 
 #[cfg(target_arch = "x86_64")]
-fn load_guest_image(id: usize, hpa: HostPhysAddr, load_gpa: GuestPhysAddr, size: usize) {
+fn load_guest_image(
+    id: usize,
+    hpa: HostPhysAddr,
+    load_gpa: GuestPhysAddr,
+    size: usize,
+) -> HyperResult {
     let image_ptr = usize::from(phys_to_virt(hpa.into())) as *const u8;
     let image = unsafe { core::slice::from_raw_parts(image_ptr, size) };
 
@@ -31,19 +36,22 @@ fn load_guest_image(id: usize, hpa: HostPhysAddr, load_gpa: GuestPhysAddr, size:
         size
     );
 
-    unsafe {
-        core::slice::from_raw_parts_mut(gpa_as_mut_ptr(id, load_gpa), size).copy_from_slice(image)
-    }
+    let translate = |gpa| -> HyperResult<HostPhysAddr> {
+        Ok(virt_to_phys((gpa_as_mut_ptr(id, gpa) as HostVirtAddr).into()).into())
+    };
+    let mut progress =
+        |done: usize, total: usize| trace!("loaded {:#x} of {:#x} bytes", done, total);
+    copy_to_guest(load_gpa, image, translate, Some(&mut progress))
 }
 
 #[cfg(target_arch = "x86_64")]
 pub fn setup_gpm(id: usize) -> HyperResult<GuestPhysMemorySet> {
     // copy BIOS and guest images
 
-    load_guest_image(id, BIOS_PADDR, BIOS_ENTRY, BIOS_SIZE);
+    load_guest_image(id, BIOS_PADDR, BIOS_ENTRY, BIOS_SIZE)?;
     #[cfg(feature = "guest_nimbos")]
     {
-        load_guest_image(id, GUEST_IMAGE_PADDR, GUEST_ENTRY, GUEST_IMAGE_SIZE);
+        load_guest_image(id, GUEST_IMAGE_PADDR, GUEST_ENTRY, GUEST_IMAGE_SIZE)?;
     }
 
     // create nested page table and add mapping
@@ -56,6 +64,7 @@ pub fn setup_gpm(id: usize) -> HyperResult<GuestPhysMemorySet> {
                 .into(),
             size: GUEST_PHYS_MEMORY_SIZE,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+            populate_on_demand: false,
         },
         #[cfg(feature = "guest_linux")]
         GuestMemoryRegion {
@@ -64,6 +73,7 @@ pub fn setup_gpm(id: usize) -> HyperResult<GuestPhysMemorySet> {
             hpa: 0x6000_0000,
             size: 0x700_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+            populate_on_demand: false,
         },
         #[cfg(feature = "guest_linux")]
         GuestMemoryRegion {
@@ -72,6 +82,7 @@ pub fn setup_gpm(id: usize) -> HyperResult<GuestPhysMemorySet> {
             hpa: 0x6800_0000,
             size: 0x800_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             // syscall forwarder region
@@ -79,6 +90,7 @@ pub fn setup_gpm(id: usize) -> HyperResult<GuestPhysMemorySet> {
             hpa: 0x700_0000,
             size: 0x0100_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+            populate_on_demand: false,
         },
         #[cfg(feature = "guest_linux")]
         GuestMemoryRegion {
@@ -87,6 +99,7 @@ pub fn setup_gpm(id: usize) -> HyperResult<GuestPhysMemorySet> {
             hpa: 0x7000_0000,
             size: 0x1000_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             // PCI
@@ -94,18 +107,21 @@ pub fn setup_gpm(id: usize) -> HyperResult<GuestPhysMemorySet> {
             hpa: 0x8000_0000,
             size: 0x1000_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             gpa: 0xfe00_0000,
             hpa: 0xfe00_0000,
             size: 0x1_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             gpa: 0xfeb0_0000,
             hpa: 0xfeb0_0000,
             size: 0x10_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             // IO APIC
@@ -113,6 +129,7 @@ pub fn setup_gpm(id: usize) -> HyperResult<GuestPhysMemorySet> {
             hpa: 0xfec0_0000,
             size: 0x1000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             // HPET
@@ -120,6 +137,7 @@ pub fn setup_gpm(id: usize) -> HyperResult<GuestPhysMemorySet> {
             hpa: 0xfed0_0000,
             size: 0x1000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             // Local APIC
@@ -127,6 +145,7 @@ pub fn setup_gpm(id: usize) -> HyperResult<GuestPhysMemorySet> {
             hpa: 0xfee0_0000,
             size: 0x1000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         // SCF: memory region for shared memory should be configged here.
     ];
@@ -5,7 +5,7 @@ use page_table_entry::MappingFlags;
 use ranges::Ranges;
 use x86::current;
 
-use crate::mm::{GuestMemoryRegion, GuestPhysMemorySet};
+use crate::mm::{copy_to_guest, GuestMemoryRegion, GuestPhysMemorySet};
 use crate::{phys_to_virt, virt_to_phys};
 use crate::{Error, GuestPageTable, Result as HyperResult};
 
@@ -15,6 +15,11 @@ pub fn root_gpm() -> &'static GuestPhysMemorySet {
     ROOT_GPM.get().expect("Uninitialized root gpm!")
 }
 
+/// The root GPM, once another CPU has initialized it with [`init_root_gpm`].
+pub fn wait_root_gpm() -> &'static GuestPhysMemorySet {
+    ROOT_GPM.wait()
+}
+
 pub fn setup_root_gpm() -> HyperResult<GuestPhysMemorySet> {
     let sys_config = HvSystemConfig::get();
     let cell_config = sys_config.root_cell.config();
@@ -35,6 +40,7 @@ pub fn setup_root_gpm() -> HyperResult<GuestPhysMemorySet> {
             hpa: hv_phys_start as HostPhysAddr,
             size: hv_phys_size,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+            populate_on_demand: false,
         }
         .into(),
     )?;
@@ -85,6 +91,7 @@ pub fn setup_root_gpm() -> HyperResult<GuestPhysMemorySet> {
                     hpa: start_hpa as HostPhysAddr,
                     size: region_size,
                     flags: region.flags.into(),
+                    populate_on_demand: false,
                 }
                 .into(),
             )?;
@@ -95,6 +102,7 @@ pub fn setup_root_gpm() -> HyperResult<GuestPhysMemorySet> {
                     hpa: start_hpa as HostPhysAddr,
                     size: hv_phys_start - start_gpa,
                     flags: region.flags.into(),
+                    populate_on_demand: false,
                 }
                 .into(),
             )?;
@@ -104,6 +112,7 @@ pub fn setup_root_gpm() -> HyperResult<GuestPhysMemorySet> {
                     hpa: (hv_phys_start + hv_phys_size) as HostPhysAddr,
                     size: start_gpa + region_size - (hv_phys_start + hv_phys_size),
                     flags: region.flags.into(),
+                    populate_on_demand: false,
                 }
                 .into(),
             )?;
@@ -139,7 +148,7 @@ fn gpa_as_mut_ptr(guest_paddr: GuestPhysAddr) -> *mut u8 {
 }
 
 #[cfg(target_arch = "x86_64")]
-fn load_guest_image(hpa: HostPhysAddr, load_gpa: GuestPhysAddr, size: usize) {
+fn load_guest_image(hpa: HostPhysAddr, load_gpa: GuestPhysAddr, size: usize) -> HyperResult {
     let image_ptr = usize::from(phys_to_virt(hpa.into())) as *const u8;
     // let image = unsafe { core::slice::from_raw_parts(image_ptr, 110) };
     // info!("first 110 byte: {:#x?}", image);
@@ -152,7 +161,10 @@ fn load_guest_image(hpa: HostPhysAddr, load_gpa: GuestPhysAddr, size: usize) {
         size
     );
 
-    unsafe {
-        core::slice::from_raw_parts_mut(gpa_as_mut_ptr(load_gpa), size).copy_from_slice(image)
-    }
+    let translate = |gpa| -> HyperResult<HostPhysAddr> {
+        Ok(virt_to_phys((gpa_as_mut_ptr(gpa) as HostVirtAddr).into()).into())
+    };
+    let mut progress =
+        |done: usize, total: usize| trace!("loaded {:#x} of {:#x} bytes", done, total);
+    copy_to_guest(load_gpa, image, translate, Some(&mut progress))
 }
@@ -16,6 +16,7 @@ pub fn linux_memory_regions_setup(regions: &mut Vec<GuestMemoryRegion>) {
             hpa: 0,
             size: GUEST_PHYS_MEMORY_SIZE,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+            populate_on_demand: false,
         },
         // 0x0100_0000 ~ 0x1000_0000 (16m ~ 256m)
         GuestMemoryRegion {
@@ -24,6 +25,7 @@ pub fn linux_memory_regions_setup(regions: &mut Vec<GuestMemoryRegion>) {
             hpa: 0,
             size: 0xf00_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+            populate_on_demand: false,
         },
         // 0x7000_0000 ~ 0x8000_0000
         GuestMemoryRegion {
@@ -32,6 +34,7 @@ pub fn linux_memory_regions_setup(regions: &mut Vec<GuestMemoryRegion>) {
             hpa: 0,
             size: 0x1000_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             // PCI
@@ -39,18 +42,21 @@ pub fn linux_memory_regions_setup(regions: &mut Vec<GuestMemoryRegion>) {
             hpa: 0x8000_0000,
             size: 0x1000_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             gpa: 0xfe00_0000,
             hpa: 0xfe00_0000,
             size: 0x1_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             gpa: 0xfeb0_0000,
             hpa: 0xfeb0_0000,
             size: 0x10_0000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             // IO APIC
@@ -58,6 +64,7 @@ pub fn linux_memory_regions_setup(regions: &mut Vec<GuestMemoryRegion>) {
             hpa: 0xfec0_0000,
             size: 0x1000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             // HPET
@@ -65,6 +72,7 @@ pub fn linux_memory_regions_setup(regions: &mut Vec<GuestMemoryRegion>) {
             hpa: 0xfed0_0000,
             size: 0x1000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             // Local APIC
@@ -72,6 +80,7 @@ pub fn linux_memory_regions_setup(regions: &mut Vec<GuestMemoryRegion>) {
             hpa: 0xfee0_0000,
             size: 0x1000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
     ];
     for r in guest_memory_regions {
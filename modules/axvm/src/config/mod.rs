@@ -46,7 +46,7 @@ mod gpm_def;
 mod gpm_def;
 
 #[cfg(feature = "type1_5")]
-pub use gpm_def::{init_root_gpm, root_gpm};
+pub use gpm_def::{init_root_gpm, root_gpm, wait_root_gpm};
 
 pub mod linux_cfg_def;
 pub mod nimbos_cfg_def;
@@ -14,6 +14,7 @@ pub fn nimbos_memory_regions_setup(regions: &mut Vec<GuestMemoryRegion>) {
             hpa: 0,
             size: GUEST_PHYS_MEMORY_SIZE,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+            populate_on_demand: false,
         },
         // GuestMemoryRegion {
         //     // PCI
@@ -34,19 +35,14 @@ pub fn nimbos_memory_regions_setup(regions: &mut Vec<GuestMemoryRegion>) {
         //     size: 0x10_0000,
         //     flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
         // },
-        GuestMemoryRegion {
-            // IO APIC
-            gpa: 0xfec0_0000,
-            hpa: 0xfec0_0000,
-            size: 0x1000,
-            flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
-        },
+        // The IO APIC at 0xfec0_0000 is emulated, see `VcpuDeviceConfig::with_ioapic`.
         GuestMemoryRegion {
             // HPET
             gpa: 0xfed0_0000,
             hpa: 0xfed0_0000,
             size: 0x1000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
         GuestMemoryRegion {
             // Local APIC
@@ -54,6 +50,7 @@ pub fn nimbos_memory_regions_setup(regions: &mut Vec<GuestMemoryRegion>) {
             hpa: 0xfee0_0000,
             size: 0x1000,
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            populate_on_demand: false,
         },
     ];
     for r in guest_memory_regions {
@@ -0,0 +1,373 @@
+//! Routing of the host console input between the host shell and the guests.
+//!
+//! By default the input is shared: whichever emulated console polls first gets the byte. Once
+//! the host shell runs, the input goes to the shell until it gives the focus to one VM, see
+//! [`set_console_focus`]; that VM then gets all the input until [`CONSOLE_ESCAPE`] is typed,
+//! which gives the focus back to the shell. The shell keeps reading the input then, and hands it
+//! on to the VM, so that the escape is seen even if the VM never reads its console, see
+//! [`host_getchar`]. Only a running VM gets the focus, and a VM stopping gives it back. Output is
+//! not routed, all consoles write to the host console; while several guests run, each piece of a
+//! line is tagged with the VM which wrote it, see [`guest_putchar`].
+//!
+//! The host console raises no interrupt, its input is polled by the emulated consoles before each
+//! VM entry. A halted vCPU of a VM getting the input wakes up every [`INPUT_POLL_INTERVAL_NS`] to
+//! poll it, see [`input_poll_deadline`]; the other VMs do not poll.
+//!
+//! The UARTs given a [`MultiplexConsole`] route both ways: Ctrl-A then a digit gives the focus to
+//! that VM, and only the output of the VM with the focus is written live, the others keep theirs
+//! in a bounded history, shown when they get the focus.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use spin::Mutex;
+
+/// The byte giving the focus back to the host shell: Ctrl-A.
+pub const CONSOLE_ESCAPE: u8 = 0x01;
+
+/// Who reads the host console input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleFocus {
+    /// Every guest console, as if there was no host shell.
+    Shared,
+    /// The host shell.
+    Host,
+    /// The consoles of one VM.
+    Vm(u32),
+}
+
+const FOCUS_SHARED: u32 = u32::MAX;
+const FOCUS_HOST: u32 = u32::MAX - 1;
+
+static FOCUS: AtomicU32 = AtomicU32::new(FOCUS_SHARED);
+
+/// Interval between two polls of the host console input by a halted vCPU.
+const INPUT_POLL_INTERVAL_NS: u64 = 10_000_000;
+
+/// Whether the host shell was started, so that the focus can be given back to it.
+static HOST_SHELL: AtomicBool = AtomicBool::new(false);
+
+/// The bytes of output kept for each VM by a [`MultiplexConsole`].
+pub const CONSOLE_HISTORY_LEN: usize = 4096;
+
+/// The VM which wrote the last guest output byte, and whether that byte ended a line.
+static LAST_WRITER: Mutex<Option<(u32, bool)>> = Mutex::new(None);
+
+/// The input read by the host shell for the VM with the focus, escape sequences handled.
+static FORWARDED: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+/// The bytes kept in [`FORWARDED`], later ones are dropped until the VM reads.
+const FORWARDED_CAPACITY: usize = 64;
+/// The host shell read Ctrl-A, and the next byte chooses the focus.
+static HOST_ESCAPE: AtomicBool = AtomicBool::new(false);
+
+pub fn console_focus() -> ConsoleFocus {
+    match FOCUS.load(Ordering::Acquire) {
+        FOCUS_SHARED => ConsoleFocus::Shared,
+        FOCUS_HOST => ConsoleFocus::Host,
+        vm_id => ConsoleFocus::Vm(vm_id),
+    }
+}
+
+pub fn set_console_focus(focus: ConsoleFocus) {
+    let value = match focus {
+        ConsoleFocus::Shared => FOCUS_SHARED,
+        ConsoleFocus::Host => FOCUS_HOST,
+        ConsoleFocus::Vm(vm_id) => vm_id,
+    };
+    FOCUS.store(value, Ordering::Release);
+    // Typed for the VM which had the focus.
+    FORWARDED.lock().clear();
+    // Halted, its vCPUs start polling the input.
+    if let ConsoleFocus::Vm(vm_id) = focus {
+        crate::vm::wake_vcpus(vm_id);
+    }
+}
+
+/// Who gets the focus when a VM gives it back: the host shell, or every guest if there is none.
+fn home_focus() -> ConsoleFocus {
+    if HOST_SHELL.load(Ordering::Acquire) {
+        ConsoleFocus::Host
+    } else {
+        ConsoleFocus::Shared
+    }
+}
+
+/// Whether `vm_id` may get the focus, only a running VM reads its console.
+pub(crate) fn can_focus(vm_id: u32) -> bool {
+    crate::vm::vm_state(vm_id) == Some(crate::vm::VmState::Running)
+}
+
+/// Give the focus back if `vm_id`, which stopped, has it.
+pub(crate) fn vm_exited(vm_id: u32) {
+    if console_focus() == ConsoleFocus::Vm(vm_id) {
+        set_console_focus(home_focus());
+    }
+}
+
+/// Handle the byte `c` typed after Ctrl-A: a digit `n` gives the focus to VM `n` if it runs,
+/// Ctrl-A is passed on as is, and any other byte gives the focus back. Returns the byte to pass
+/// on to the VM with the focus.
+fn handle_escape(c: u8) -> Option<u8> {
+    match c {
+        CONSOLE_ESCAPE => Some(c),
+        b'0'..=b'9' => {
+            let target = (c - b'0') as u32;
+            if can_focus(target) {
+                set_console_focus(ConsoleFocus::Vm(target));
+            }
+            // The input which followed is for the VM with the focus now.
+            None
+        }
+        _ => {
+            set_console_focus(home_focus());
+            None
+        }
+    }
+}
+
+/// When a halted vCPU of `vm_id`, parked at `now_ns`, wakes up to poll the host console input,
+/// `None` if the VM does not get the input.
+pub(crate) fn input_poll_deadline(vm_id: u32, now_ns: u64) -> Option<u64> {
+    match console_focus() {
+        ConsoleFocus::Host => None,
+        ConsoleFocus::Vm(focus) if focus != vm_id => None,
+        ConsoleFocus::Shared | ConsoleFocus::Vm(_) => Some(now_ns + INPUT_POLL_INTERVAL_NS),
+    }
+}
+
+/// The next input byte for the guest consoles of the VM running on the current CPU.
+pub(crate) fn guest_getchar() -> Option<u8> {
+    match console_focus() {
+        ConsoleFocus::Shared => axhal::console::getchar(),
+        ConsoleFocus::Host => None,
+        ConsoleFocus::Vm(vm_id) => {
+            if crate::vm::current_vm_id() != Some(vm_id) {
+                return None;
+            }
+            if HOST_SHELL.load(Ordering::Acquire) {
+                return FORWARDED.lock().pop_front();
+            }
+            match axhal::console::getchar()? {
+                CONSOLE_ESCAPE => {
+                    set_console_focus(home_focus());
+                    None
+                }
+                c => Some(c),
+            }
+        }
+    }
+}
+
+/// Write an output byte of the guest consoles of `vm_id`, the current VM if `None`.
+///
+/// With a single guest, the byte goes as is. With several, a line gets a `[vm N]` tag when it
+/// starts, and the line of another VM left unfinished is broken first, so that the output of two
+/// VMs is never mixed within a line.
+pub(crate) fn guest_putchar(vm_id: Option<u32>, c: u8) {
+    let vm_id = match vm_id.or_else(crate::vm::current_vm_id) {
+        Some(vm_id) if crate::vm::guest_vm_count() > 1 => vm_id,
+        _ => return axhal::console::putchar(c),
+    };
+    let mut last = LAST_WRITER.lock();
+    let tag = match *last {
+        Some((writer, line_ended)) if writer == vm_id => line_ended,
+        Some((_, false)) => {
+            axhal::console::putchar(b'\n');
+            true
+        }
+        _ => true,
+    };
+    if tag {
+        axhal::console::write_bytes(alloc::format!("[vm {}] ", vm_id).as_bytes());
+    }
+    axhal::console::putchar(c);
+    *last = Some((vm_id, c == b'\n'));
+}
+
+/// The next input byte for the host shell. While a VM has the focus, the input is read for it
+/// instead, see [`forward_input`], and there is none for the shell.
+pub(crate) fn host_getchar() -> Option<u8> {
+    match console_focus() {
+        ConsoleFocus::Host => axhal::console::getchar(),
+        ConsoleFocus::Vm(_) => {
+            forward_input();
+            None
+        }
+        ConsoleFocus::Shared => None,
+    }
+}
+
+/// Read the input for the VM with the focus into [`FORWARDED`], handling the escape sequences as
+/// a [`MultiplexConsole`] does.
+fn forward_input() {
+    while let Some(c) = axhal::console::getchar() {
+        let c = if HOST_ESCAPE.swap(false, Ordering::AcqRel) {
+            match handle_escape(c) {
+                Some(c) => c,
+                // The focus changed, the following input is read for its new owner.
+                None => return,
+            }
+        } else if c == CONSOLE_ESCAPE {
+            HOST_ESCAPE.store(true, Ordering::Release);
+            continue;
+        } else {
+            c
+        };
+        let mut forwarded = FORWARDED.lock();
+        if forwarded.len() < FORWARDED_CAPACITY {
+            forwarded.push_back(c);
+        }
+    }
+}
+
+/// Give the console input to the host shell, which was just started.
+pub(crate) fn start_host_shell() {
+    HOST_SHELL.store(true, Ordering::Release);
+    set_console_focus(ConsoleFocus::Host);
+}
+
+/// The output of a VM kept by a [`MultiplexConsole`].
+#[derive(Debug, Default)]
+struct VmOutput {
+    /// The last [`CONSOLE_HISTORY_LEN`] bytes written.
+    history: VecDeque<u8>,
+    /// The number of bytes at the end of `history` written while another VM had the focus.
+    unseen: usize,
+}
+
+impl VmOutput {
+    fn push(&mut self, c: u8, seen: bool) {
+        if self.history.len() == CONSOLE_HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(c);
+        self.unseen = if seen {
+            0
+        } else {
+            (self.unseen + 1).min(CONSOLE_HISTORY_LEN)
+        };
+    }
+}
+
+/// The host console, shared by the UARTs of all the VMs, see
+/// [`VcpuDeviceConfig::with_console`](crate::VcpuDeviceConfig::with_console).
+///
+/// Created once, and handed to each VM:
+///
+/// ```ignore
+/// let console = Arc::new(MultiplexConsole::new());
+/// let devices = VcpuDeviceConfig::pc().with_console(console.clone());
+/// ```
+///
+/// Ctrl-A then a digit `n` gives the input to VM `n` if it runs, Ctrl-A twice sends a Ctrl-A to
+/// the VM with the focus, and Ctrl-A then any other byte gives the focus back to the host shell,
+/// or shares the input again if there is none. Once the host shell runs, it reads the input and
+/// handles the sequences, see [`host_getchar`]. While a VM has the focus, the output of the other VMs is
+/// only kept, and written out once they get it; otherwise the output is written live, tagged as
+/// by [`guest_putchar`].
+#[derive(Debug, Default)]
+pub struct MultiplexConsole {
+    output: Mutex<BTreeMap<u32, VmOutput>>,
+    /// Ctrl-A was read, and the next byte chooses the focus.
+    escape: AtomicBool,
+}
+
+impl MultiplexConsole {
+    pub const fn new() -> Self {
+        Self {
+            output: Mutex::new(BTreeMap::new()),
+            escape: AtomicBool::new(false),
+        }
+    }
+
+    /// The last [`CONSOLE_HISTORY_LEN`] bytes of output of `vm_id`, including the ones written
+    /// while it did not have the focus. Still readable once the VM stopped.
+    pub fn history(&self, vm_id: u32) -> Vec<u8> {
+        self.output
+            .lock()
+            .get(&vm_id)
+            .map_or_else(Vec::new, |output| output.history.iter().copied().collect())
+    }
+
+    /// Write an output byte of the consoles of the VM running on the current CPU.
+    pub(crate) fn putchar(&self, c: u8) {
+        let Some(vm_id) = crate::vm::current_vm_id() else {
+            return axhal::console::putchar(c);
+        };
+        let focus = console_focus();
+        let mut output = self.output.lock();
+        let vm_output = output.entry(vm_id).or_default();
+        match focus {
+            ConsoleFocus::Vm(focused) if focused == vm_id => {
+                Self::show_unseen(vm_id, vm_output);
+                write_untagged(vm_id, c);
+                vm_output.push(c, true);
+            }
+            ConsoleFocus::Vm(_) => vm_output.push(c, false),
+            _ => {
+                guest_putchar(Some(vm_id), c);
+                vm_output.push(c, true);
+            }
+        }
+    }
+
+    /// The next input byte for the consoles of the VM running on the current CPU, once the
+    /// escape sequences are handled.
+    pub(crate) fn getchar(&self) -> Option<u8> {
+        let vm_id = crate::vm::current_vm_id()?;
+        match console_focus() {
+            ConsoleFocus::Shared => {}
+            ConsoleFocus::Vm(focused) if focused == vm_id => {
+                if let Some(vm_output) = self.output.lock().get_mut(&vm_id) {
+                    Self::show_unseen(vm_id, vm_output);
+                }
+            }
+            _ => return None,
+        }
+        if HOST_SHELL.load(Ordering::Acquire) {
+            return FORWARDED.lock().pop_front();
+        }
+        let c = axhal::console::getchar()?;
+        if !self.escape.swap(false, Ordering::AcqRel) {
+            if c == CONSOLE_ESCAPE {
+                self.escape.store(true, Ordering::Release);
+                return None;
+            }
+            return Some(c);
+        }
+        handle_escape(c)
+    }
+
+    /// Write the output of `vm_id` kept while it did not have the focus, which it has now.
+    fn show_unseen(vm_id: u32, vm_output: &mut VmOutput) {
+        if vm_output.unseen == 0 {
+            return;
+        }
+        let start = vm_output.history.len() - vm_output.unseen;
+        for &c in vm_output.history.range(start..) {
+            write_untagged(vm_id, c);
+        }
+        vm_output.unseen = 0;
+    }
+}
+
+/// Consoles are only equal to themselves, VM configurations sharing one compare equal.
+impl PartialEq for MultiplexConsole {
+    fn eq(&self, other: &Self) -> bool {
+        core::ptr::eq(self, other)
+    }
+}
+
+impl Eq for MultiplexConsole {}
+
+/// Write an output byte of `vm_id`, which has the focus, without a tag: its output is the only
+/// one written.
+fn write_untagged(vm_id: u32, c: u8) {
+    let mut last = LAST_WRITER.lock();
+    if matches!(*last, Some((writer, false)) if writer != vm_id) {
+        axhal::console::putchar(b'\n');
+    }
+    axhal::console::putchar(c);
+    *last = Some((vm_id, c == b'\n'));
+}
@@ -0,0 +1,410 @@
+//! Exit-less guest console.
+//!
+//! The guest registers one page of its RAM with `HVC_CONSOLE_RING_SETUP`, writes console bytes
+//! into it and bumps the producer index, without any VM exit. The hypervisor drains the ring into
+//! the host console before every port I/O exit of the VM (so that ring output and UART output
+//! stay in order), from `check_events`, when the VM is paused and when it stops. A guest which
+//! finds the ring full issues `HVC_CONSOLE_RING_KICK` to have it drained at once.
+//!
+//! Layout of the page, shared with the guest driver:
+//!
+//! | offset | size                  | field                                  |
+//! |--------|-----------------------|----------------------------------------|
+//! | 0      | 4                     | producer index, written by the guest   |
+//! | 4      | 4                     | consumer index, written by the host    |
+//! | 64     | [`CONSOLE_RING_SIZE`] | ring data                              |
+//!
+//! Both indexes are free-running, the ring holds `producer - consumer` bytes and byte `i` is
+//! stored at `i % CONSOLE_RING_SIZE`.
+//!
+//! The rings are found by VM id in a fixed table, without any global lock: the port I/O exits of
+//! the VMs without a ring only load [`ACTIVE_RINGS`], and those of a VM with one only contend with
+//! the other CPUs draining the same ring. A CPU accesses the page only while it holds the
+//! `draining` flag of its slot, and [`unregister`] waits for that flag, so once it returns no CPU
+//! touches the page of the VM.
+
+use core::hint::spin_loop;
+use core::ptr::{addr_of, null_mut};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+
+use crate::config::entry::CONFIG_VM_NUM_MAX;
+use crate::{Error, Result};
+
+/// Size of the data area of the ring.
+pub const CONSOLE_RING_SIZE: usize = 2048;
+
+#[repr(C)]
+struct ConsoleRingPage {
+    prod: AtomicU32,
+    cons: AtomicU32,
+    _reserved: [u8; 56],
+    data: [u8; CONSOLE_RING_SIZE],
+}
+
+/// Write the pending bytes of the ring at `page` to `out`, returns the number of bytes drained.
+///
+/// # Safety
+///
+/// `page` must stay mapped until this returns. The guest may write the data area concurrently,
+/// so it is only accessed through volatile reads.
+unsafe fn drain_page(page: *mut ConsoleRingPage, out: &mut dyn FnMut(u8)) -> usize {
+    let data = addr_of!((*page).data) as *const u8;
+    let page = &*page;
+    let prod = page.prod.load(Ordering::Acquire);
+    let cons = page.cons.load(Ordering::Relaxed);
+    let pending = prod.wrapping_sub(cons) as usize;
+    if pending > CONSOLE_RING_SIZE {
+        warn!(
+            "console ring: producer {:#x} ahead of consumer {:#x}, dropped",
+            prod, cons
+        );
+        page.cons.store(prod, Ordering::Release);
+        return 0;
+    }
+    for i in 0..pending {
+        let index = cons.wrapping_add(i as u32) as usize % CONSOLE_RING_SIZE;
+        out(data.add(index).read_volatile());
+    }
+    page.cons.store(prod, Ordering::Release);
+    pending
+}
+
+/// The ring of one VM: its page, null without one, and whether a CPU is accessing it.
+struct RingSlot {
+    page: AtomicPtr<ConsoleRingPage>,
+    draining: AtomicBool,
+}
+
+/// Holds the `draining` flag of a [`RingSlot`].
+struct DrainGuard<'a>(&'a AtomicBool);
+
+impl Drop for DrainGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl RingSlot {
+    const fn new() -> Self {
+        Self {
+            page: AtomicPtr::new(null_mut()),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    fn try_acquire(&self) -> Option<DrainGuard<'_>> {
+        self.draining
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| DrainGuard(&self.draining))
+    }
+
+    fn acquire(&self) -> DrainGuard<'_> {
+        loop {
+            if let Some(guard) = self.try_acquire() {
+                return guard;
+            }
+            while self.draining.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+    }
+
+    /// Drain the ring to `out` under `_guard`.
+    fn drain_locked(&self, _guard: &DrainGuard, out: &mut dyn FnMut(u8)) -> usize {
+        let page = self.page.load(Ordering::Acquire);
+        if page.is_null() {
+            return 0;
+        }
+        // SAFETY: the page stays registered while the flag is held, see `replace`.
+        unsafe { drain_page(page, out) }
+    }
+
+    /// Drain the ring to `out`, waiting for another CPU draining it unless `wait` is false.
+    fn drain(&self, wait: bool, out: &mut dyn FnMut(u8)) -> usize {
+        let guard = if wait {
+            self.acquire()
+        } else {
+            match self.try_acquire() {
+                Some(guard) => guard,
+                None => return 0,
+            }
+        };
+        self.drain_locked(&guard, out)
+    }
+
+    /// Make `page` the ring, null for none, once the previous one was drained to `out`. Returns
+    /// whether there was a previous ring.
+    fn replace(&self, page: *mut ConsoleRingPage, out: &mut dyn FnMut(u8)) -> bool {
+        let guard = self.acquire();
+        self.drain_locked(&guard, out);
+        !self.page.swap(page, Ordering::AcqRel).is_null()
+    }
+}
+
+const NO_RING: RingSlot = RingSlot::new();
+static CONSOLE_RINGS: [RingSlot; CONFIG_VM_NUM_MAX] = [NO_RING; CONFIG_VM_NUM_MAX];
+/// Number of registered rings, lets the hot paths skip the lookup when there is none.
+static ACTIVE_RINGS: AtomicUsize = AtomicUsize::new(0);
+
+fn slot(vm_id: u32) -> Option<&'static RingSlot> {
+    CONSOLE_RINGS.get(vm_id as usize)
+}
+
+fn output(vm_id: u32) -> impl FnMut(u8) {
+    move |c| crate::console_mux::guest_putchar(Some(vm_id), c)
+}
+
+/// Register the ring page of `vm_id`, mapped at `page` in the hypervisor.
+///
+/// A ring registered before is drained and replaced.
+pub fn register(vm_id: u32, page: *mut u8) -> Result {
+    let slot = slot(vm_id).ok_or(Error::InvalidParam)?;
+    if page.is_null() || page as usize % core::mem::align_of::<ConsoleRingPage>() != 0 {
+        return Err(Error::InvalidParam);
+    }
+    let page = page as *mut ConsoleRingPage;
+    // Start from the current producer index, whatever the page contained before.
+    let ring = unsafe { &*page };
+    ring.cons
+        .store(ring.prod.load(Ordering::Acquire), Ordering::Release);
+
+    if !slot.replace(page, &mut output(vm_id)) {
+        ACTIVE_RINGS.fetch_add(1, Ordering::Release);
+    }
+    info!("VM [{}] console ring registered", vm_id);
+    Ok(())
+}
+
+/// Drain the ring of `vm_id`, waiting for another CPU draining it.
+pub fn drain(vm_id: u32) -> usize {
+    if ACTIVE_RINGS.load(Ordering::Acquire) == 0 {
+        return 0;
+    }
+    slot(vm_id).map_or(0, |slot| slot.drain(true, &mut output(vm_id)))
+}
+
+/// Drain the ring of the VM running on the current CPU, unless another CPU is draining it.
+pub(crate) fn poll() {
+    if ACTIVE_RINGS.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let Some(vm_id) = crate::vm::current_vm_id() else {
+        return;
+    };
+    if let Some(slot) = slot(vm_id) {
+        slot.drain(false, &mut output(vm_id));
+    }
+}
+
+/// Drain and forget the ring of `vm_id`, once the VM stopped and its memory may be released.
+///
+/// Waits for the CPUs draining the ring meanwhile, from a hypercall, a virtio console or
+/// [`poll`]: the page is not accessed any more once this returns.
+pub fn unregister(vm_id: u32) {
+    let Some(slot) = slot(vm_id) else {
+        return;
+    };
+    if slot.replace(null_mut(), &mut output(vm_id)) {
+        ACTIVE_RINGS.fetch_sub(1, Ordering::Release);
+        debug!("VM [{}] console ring unregistered", vm_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    extern crate std;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+
+    fn new_page() -> *mut ConsoleRingPage {
+        Box::into_raw(Box::new(ConsoleRingPage {
+            prod: AtomicU32::new(0),
+            cons: AtomicU32::new(0),
+            _reserved: [0; 56],
+            data: [0; CONSOLE_RING_SIZE],
+        }))
+    }
+
+    fn free_page(page: *mut ConsoleRingPage) {
+        drop(unsafe { Box::from_raw(page) });
+    }
+
+    /// Write `bytes` and bump the producer index, as the guest driver does.
+    fn produce(page: *mut ConsoleRingPage, bytes: &[u8]) {
+        let ring = unsafe { &*page };
+        let prod = ring.prod.load(Ordering::Relaxed);
+        let data = unsafe { addr_of!((*page).data) as *mut u8 };
+        for (i, &c) in bytes.iter().enumerate() {
+            let index = prod.wrapping_add(i as u32) as usize % CONSOLE_RING_SIZE;
+            unsafe { data.add(index).write_volatile(c) };
+        }
+        ring.prod
+            .store(prod.wrapping_add(bytes.len() as u32), Ordering::Release);
+    }
+
+    fn drain_to_vec(slot: &RingSlot) -> Vec<u8> {
+        let mut out = Vec::new();
+        let drained = slot.drain(true, &mut |c| out.push(c));
+        assert_eq!(drained, out.len());
+        out
+    }
+
+    #[test]
+    fn test_drain_in_order() {
+        let (slot, page) = (RingSlot::new(), new_page());
+        assert!(!slot.replace(page, &mut |_| unreachable!()));
+        produce(page, b"hello, ");
+        produce(page, b"world");
+        assert_eq!(drain_to_vec(&slot), b"hello, world");
+        assert!(drain_to_vec(&slot).is_empty());
+        let ring = unsafe { &*page };
+        assert_eq!(
+            ring.cons.load(Ordering::Relaxed),
+            ring.prod.load(Ordering::Relaxed)
+        );
+        slot.replace(null_mut(), &mut |_| unreachable!());
+        free_page(page);
+    }
+
+    #[test]
+    fn test_wraparound() {
+        let (slot, page) = (RingSlot::new(), new_page());
+        // Both the indexes and the data area wrap within these bytes.
+        let ring = unsafe { &*page };
+        ring.prod.store(u32::MAX - 2, Ordering::Relaxed);
+        ring.cons.store(u32::MAX - 2, Ordering::Relaxed);
+        slot.replace(page, &mut |_| unreachable!());
+        produce(page, b"abcdef");
+        assert_eq!(drain_to_vec(&slot), b"abcdef");
+        assert_eq!(ring.cons.load(Ordering::Relaxed), 3);
+
+        // A full ring is drained at once.
+        let full: Vec<u8> = (0..CONSOLE_RING_SIZE).map(|i| i as u8).collect();
+        produce(page, &full);
+        assert_eq!(drain_to_vec(&slot), full);
+        slot.replace(null_mut(), &mut |_| unreachable!());
+        free_page(page);
+    }
+
+    #[test]
+    fn test_overrun_dropped() {
+        let (slot, page) = (RingSlot::new(), new_page());
+        slot.replace(page, &mut |_| unreachable!());
+        // A producer index further ahead than the ring holds is not trusted.
+        let ring = unsafe { &*page };
+        ring.prod
+            .store(CONSOLE_RING_SIZE as u32 + 1, Ordering::Relaxed);
+        assert!(drain_to_vec(&slot).is_empty());
+        assert_eq!(
+            ring.cons.load(Ordering::Relaxed),
+            CONSOLE_RING_SIZE as u32 + 1
+        );
+        produce(page, b"ok");
+        assert_eq!(drain_to_vec(&slot), b"ok");
+        slot.replace(null_mut(), &mut |_| unreachable!());
+        free_page(page);
+    }
+
+    #[test]
+    fn test_replace_drains() {
+        let (slot, old, new) = (RingSlot::new(), new_page(), new_page());
+        slot.replace(old, &mut |_| unreachable!());
+        produce(old, b"before");
+        let mut out = Vec::new();
+        assert!(slot.replace(new, &mut |c| out.push(c)));
+        assert_eq!(out, b"before");
+        produce(old, b"stale");
+        produce(new, b"after");
+        assert_eq!(drain_to_vec(&slot), b"after");
+
+        // Unregistered: drained a last time, then not accessed.
+        produce(new, b"last");
+        out.clear();
+        assert!(slot.replace(null_mut(), &mut |c| out.push(c)));
+        assert_eq!(out, b"last");
+        free_page(new);
+        assert!(drain_to_vec(&slot).is_empty());
+        free_page(old);
+    }
+
+    #[test]
+    fn test_poll_skips_busy_ring() {
+        let (slot, page) = (RingSlot::new(), new_page());
+        slot.replace(page, &mut |_| unreachable!());
+        produce(page, b"x");
+        let guard = slot.acquire();
+        assert_eq!(slot.drain(false, &mut |_| unreachable!()), 0);
+        drop(guard);
+        assert_eq!(slot.drain(false, &mut |_| {}), 1);
+        slot.replace(null_mut(), &mut |_| unreachable!());
+        free_page(page);
+    }
+
+    #[test]
+    fn test_unregister_waits_for_drains() {
+        static SLOT: RingSlot = RingSlot::new();
+        static STOP: AtomicBool = AtomicBool::new(false);
+        let page = new_page();
+        SLOT.replace(page, &mut |_| unreachable!());
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let drainers: Vec<_> = (0..4)
+            .map(|i| {
+                let out = out.clone();
+                std::thread::spawn(move || {
+                    while !STOP.load(Ordering::Acquire) {
+                        SLOT.drain(i % 2 == 0, &mut |c| out.lock().unwrap().push(c));
+                    }
+                })
+            })
+            .collect();
+        let bytes: Vec<u8> = (0..64u8).collect();
+        for _ in 0..100 {
+            produce(page, &bytes);
+            while unsafe { &*page }.cons.load(Ordering::Acquire)
+                != unsafe { &*page }.prod.load(Ordering::Acquire)
+            {
+                std::thread::yield_now();
+            }
+        }
+        SLOT.replace(null_mut(), &mut |c| out.lock().unwrap().push(c));
+        // Nobody reads the page once it is unregistered, the drainers still running.
+        free_page(page);
+        STOP.store(true, Ordering::Release);
+        for drainer in drainers {
+            drainer.join().unwrap();
+        }
+        let out = out.lock().unwrap();
+        assert_eq!(out.len(), 100 * bytes.len());
+        assert!(out.chunks(bytes.len()).all(|chunk| chunk == bytes));
+    }
+
+    /// Bytes per second drained from a full ring. Run with `--ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_drain() {
+        use std::time::Instant;
+
+        let (slot, page) = (RingSlot::new(), new_page());
+        slot.replace(page, &mut |_| unreachable!());
+        let full = [b'x'; CONSOLE_RING_SIZE];
+        let (rounds, mut sum) = (100_000, 0u64);
+        let start = Instant::now();
+        for _ in 0..rounds {
+            produce(page, &full);
+            slot.drain(true, &mut |c| sum += c as u64);
+        }
+        let secs = start.elapsed().as_secs_f64();
+        assert_eq!(sum, rounds as u64 * CONSOLE_RING_SIZE as u64 * b'x' as u64);
+        std::println!(
+            "console ring drain: {:.1} MB/s",
+            (rounds * CONSOLE_RING_SIZE) as f64 / secs / 1e6
+        );
+        slot.replace(null_mut(), &mut |_| unreachable!());
+        free_page(page);
+    }
+}
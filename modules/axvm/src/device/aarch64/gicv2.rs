@@ -0,0 +1,693 @@
+//! Emulated GICv2 distributor and CPU interface. (ref: ARM IHI 0048B, GICv2 architecture
+//! specification)
+//!
+//! Only the registers used by the Linux `irq-gic` driver are implemented: the interrupts are
+//! all group 1 and edge-triggered, level-sensitive sources such as the PL011 re-assert their
+//! line through [`Gicv2::set_level`]. The GIC state is shared by the [`GicDistributor`] and the
+//! [`GicCpuInterface`] of each vCPU, both banked for it.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axconfig::SMP;
+use hypercraft::{HyperError, HyperResult, MmioOps};
+use lock_stat::Mutex;
+
+/// Interrupts handled by the distributor: 16 SGIs, 16 PPIs and 224 SPIs.
+pub const GIC_MAX_IRQS: usize = 256;
+/// SGIs and PPIs, banked per CPU interface.
+const GIC_PRIVATE_IRQS: usize = 32;
+const GIC_SGIS: usize = 16;
+const IRQ_WORDS: usize = GIC_MAX_IRQS / 32;
+
+// CPU target masks are 8 bits wide.
+#[allow(clippy::assertions_on_constants)]
+const _: () = assert!(SMP <= 8, "GICv2 supports up to 8 CPU interfaces");
+
+/// Interrupt ID read from GICC_IAR when nothing is pending.
+const SPURIOUS_IRQ: u32 = 1023;
+/// Priority of an idle CPU interface, lower than any interrupt.
+const IDLE_PRIORITY: u8 = 0xff;
+
+const GICD_SIZE: u64 = 0x1000;
+const GICC_SIZE: u64 = 0x2000;
+
+// Distributor registers.
+const GICD_CTLR: u64 = 0x000;
+const GICD_TYPER: u64 = 0x004;
+const GICD_IIDR: u64 = 0x008;
+const GICD_IGROUPR: Range<u64> = 0x080..0x100;
+const GICD_ISENABLER: Range<u64> = 0x100..0x180;
+const GICD_ICENABLER: Range<u64> = 0x180..0x200;
+const GICD_ISPENDR: Range<u64> = 0x200..0x280;
+const GICD_ICPENDR: Range<u64> = 0x280..0x300;
+const GICD_ISACTIVER: Range<u64> = 0x300..0x380;
+const GICD_ICACTIVER: Range<u64> = 0x380..0x400;
+const GICD_IPRIORITYR: Range<u64> = 0x400..0x400 + GIC_MAX_IRQS as u64;
+const GICD_ITARGETSR: Range<u64> = 0x800..0x800 + GIC_MAX_IRQS as u64;
+const GICD_ICFGR: Range<u64> = 0xc00..0xc00 + GIC_MAX_IRQS as u64 / 4;
+const GICD_SGIR: u64 = 0xf00;
+const GICD_PIDR2: u64 = 0xfe8;
+
+// CPU interface registers.
+const GICC_CTLR: u64 = 0x00;
+const GICC_PMR: u64 = 0x04;
+const GICC_BPR: u64 = 0x08;
+const GICC_IAR: u64 = 0x0c;
+const GICC_EOIR: u64 = 0x10;
+const GICC_RPR: u64 = 0x14;
+const GICC_HPPIR: u64 = 0x18;
+const GICC_IIDR: u64 = 0xfc;
+const GICC_DIR: u64 = 0x1000;
+
+/// Product ID 0x0, variant 0, revision 2, implementer ARM.
+const GIC_IIDR: u32 = 0x0000_043b;
+
+fn bit(irq: usize) -> (usize, u32) {
+    (irq / 32, 1 << (irq % 32))
+}
+
+/// The one-bit-per-interrupt state of the distributor.
+#[derive(Debug, Clone, Copy)]
+enum BitReg {
+    Enabled,
+    Pending,
+    Active,
+}
+
+/// The set and clear registers of each [`BitReg`].
+const BIT_REGS: [(Range<u64>, BitReg, bool); 6] = [
+    (GICD_ISENABLER, BitReg::Enabled, true),
+    (GICD_ICENABLER, BitReg::Enabled, false),
+    (GICD_ISPENDR, BitReg::Pending, true),
+    (GICD_ICPENDR, BitReg::Pending, false),
+    (GICD_ISACTIVER, BitReg::Active, true),
+    (GICD_ICACTIVER, BitReg::Active, false),
+];
+
+/// The state of the SGIs and PPIs of one CPU interface, and of the interface itself.
+#[derive(Clone)]
+struct GicCpuState {
+    enabled: u32,
+    pending: u32,
+    active: u32,
+    priority: [u8; GIC_PRIVATE_IRQS],
+    /// CPU interfaces which sent each pending SGI, as a target mask.
+    sgi_sources: [u8; GIC_SGIS],
+    ctlr: u32,
+    pmr: u8,
+    bpr: u8,
+    /// Acknowledged interrupts not yet completed, with the priority each preempted.
+    active_stack: Vec<(u32, u8)>,
+}
+
+impl GicCpuState {
+    fn new() -> Self {
+        Self {
+            enabled: 0,
+            pending: 0,
+            active: 0,
+            priority: [0; GIC_PRIVATE_IRQS],
+            sgi_sources: [0; GIC_SGIS],
+            ctlr: 0,
+            pmr: 0,
+            bpr: 0,
+            active_stack: Vec::new(),
+        }
+    }
+
+    fn running_priority(&self) -> u8 {
+        self.active_stack
+            .last()
+            .map_or(IDLE_PRIORITY, |&(_, priority)| priority)
+    }
+}
+
+/// The state of an emulated GICv2.
+pub struct Gicv2 {
+    ctlr: u32,
+    /// SPI state, word 0 is banked in the [`GicCpuState`]s.
+    enabled: [u32; IRQ_WORDS],
+    pending: [u32; IRQ_WORDS],
+    active: [u32; IRQ_WORDS],
+    priority: [u8; GIC_MAX_IRQS],
+    targets: [u8; GIC_MAX_IRQS],
+    config: [u32; GIC_MAX_IRQS / 16],
+    cpus: [GicCpuState; SMP],
+}
+
+impl Gicv2 {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            ctlr: 0,
+            enabled: [0; IRQ_WORDS],
+            pending: [0; IRQ_WORDS],
+            active: [0; IRQ_WORDS],
+            priority: [0; GIC_MAX_IRQS],
+            targets: [0; GIC_MAX_IRQS],
+            config: [0; GIC_MAX_IRQS / 16],
+            cpus: core::array::from_fn(|_| GicCpuState::new()),
+        }
+    }
+
+    /// Word `word` of `reg` as seen by CPU interface `cpu`.
+    fn bits(&self, reg: BitReg, cpu: usize, word: usize) -> u32 {
+        let state = &self.cpus[cpu];
+        match (reg, word) {
+            (BitReg::Enabled, 0) => state.enabled,
+            (BitReg::Pending, 0) => state.pending,
+            (BitReg::Active, 0) => state.active,
+            (BitReg::Enabled, _) => self.enabled[word],
+            (BitReg::Pending, _) => self.pending[word],
+            (BitReg::Active, _) => self.active[word],
+        }
+    }
+
+    fn bits_mut(&mut self, reg: BitReg, cpu: usize, word: usize) -> &mut u32 {
+        let state = &mut self.cpus[cpu];
+        match (reg, word) {
+            (BitReg::Enabled, 0) => &mut state.enabled,
+            (BitReg::Pending, 0) => &mut state.pending,
+            (BitReg::Active, 0) => &mut state.active,
+            (BitReg::Enabled, _) => &mut self.enabled[word],
+            (BitReg::Pending, _) => &mut self.pending[word],
+            (BitReg::Active, _) => &mut self.active[word],
+        }
+    }
+
+    fn is_set(&self, reg: BitReg, cpu: usize, irq: usize) -> bool {
+        let (word, mask) = bit(irq);
+        self.bits(reg, cpu, word) & mask != 0
+    }
+
+    fn irq_priority(&self, cpu: usize, irq: usize) -> u8 {
+        match irq {
+            0..GIC_PRIVATE_IRQS => self.cpus[cpu].priority[irq],
+            _ => self.priority[irq],
+        }
+    }
+
+    fn targets_cpu(&self, cpu: usize, irq: usize) -> bool {
+        irq < GIC_PRIVATE_IRQS || self.targets[irq] & (1 << cpu) != 0
+    }
+
+    /// Make SPI `irq` pending, as if its source raised its line.
+    pub fn set_pending(&mut self, irq: usize) {
+        if (GIC_PRIVATE_IRQS..GIC_MAX_IRQS).contains(&irq) {
+            let (word, mask) = bit(irq);
+            self.pending[word] |= mask;
+        } else {
+            warn!("GICv2: cannot raise interrupt {}", irq);
+        }
+    }
+
+    /// Make PPI `irq` of CPU interface `cpu` pending, e.g. the virtual timer.
+    pub fn set_private_pending(&mut self, cpu: usize, irq: usize) {
+        if (GIC_SGIS..GIC_PRIVATE_IRQS).contains(&irq) {
+            self.cpus[cpu].pending |= 1 << irq;
+        } else {
+            warn!("GICv2: cannot raise PPI {} on CPU {}", irq, cpu);
+        }
+    }
+
+    /// Drive the line of the level-sensitive SPI `irq`: while it is high and the interrupt is not
+    /// being handled, it stays pending.
+    pub fn set_level(&mut self, irq: usize, level: bool) {
+        if !(GIC_PRIVATE_IRQS..GIC_MAX_IRQS).contains(&irq) {
+            warn!("GICv2: cannot drive interrupt {}", irq);
+            return;
+        }
+        let (word, mask) = bit(irq);
+        if !level {
+            self.pending[word] &= !mask;
+        } else if self.active[word] & mask == 0 {
+            self.pending[word] |= mask;
+        }
+    }
+
+    /// The highest priority interrupt which CPU interface `cpu` would signal, with its priority.
+    fn highest_pending(&self, cpu: usize) -> Option<(usize, u8)> {
+        if self.ctlr & 1 == 0 {
+            return None;
+        }
+        (0..GIC_MAX_IRQS)
+            .filter(|&irq| {
+                self.is_set(BitReg::Pending, cpu, irq)
+                    && self.is_set(BitReg::Enabled, cpu, irq)
+                    && !self.is_set(BitReg::Active, cpu, irq)
+                    && self.targets_cpu(cpu, irq)
+            })
+            .map(|irq| (irq, self.irq_priority(cpu, irq)))
+            .min_by_key(|&(_, priority)| priority)
+    }
+
+    /// Whether CPU interface `cpu` signals an interrupt to its vCPU.
+    pub fn irq_pending(&self, cpu: usize) -> bool {
+        let state = &self.cpus[cpu];
+        state.ctlr & 1 != 0
+            && self.highest_pending(cpu).map_or(false, |(_, priority)| {
+                priority < state.pmr && priority < state.running_priority()
+            })
+    }
+
+    fn acknowledge(&mut self, cpu: usize) -> u32 {
+        if !self.irq_pending(cpu) {
+            return SPURIOUS_IRQ;
+        }
+        let (irq, priority) = self.highest_pending(cpu).unwrap();
+        let (word, mask) = bit(irq);
+        let mut iar = irq as u32;
+        let mut still_pending = false;
+        if irq < GIC_SGIS {
+            // One source at a time, the SGI stays pending for the others.
+            let sources = &mut self.cpus[cpu].sgi_sources[irq];
+            let source = sources.trailing_zeros();
+            *sources &= !(1 << source);
+            still_pending = *sources != 0;
+            iar |= source << 10;
+        }
+        if !still_pending {
+            *self.bits_mut(BitReg::Pending, cpu, word) &= !mask;
+        }
+        *self.bits_mut(BitReg::Active, cpu, word) |= mask;
+        self.cpus[cpu].active_stack.push((iar, priority));
+        iar
+    }
+
+    fn end_of_interrupt(&mut self, cpu: usize, iar: u32) {
+        let irq = (iar & 0x3ff) as usize;
+        let active_stack = &mut self.cpus[cpu].active_stack;
+        let Some(index) = active_stack.iter().rposition(|&(id, _)| id == iar) else {
+            warn!("GICv2: EOI of inactive interrupt {} on CPU {}", irq, cpu);
+            return;
+        };
+        active_stack.remove(index);
+        let (word, mask) = bit(irq);
+        *self.bits_mut(BitReg::Active, cpu, word) &= !mask;
+    }
+
+    fn send_sgi(&mut self, cpu: usize, sgir: u32) {
+        let irq = (sgir & 0xf) as usize;
+        let targets = match (sgir >> 24) & 0x3 {
+            0 => (sgir >> 16) as u8,
+            1 => !(1u8 << cpu),
+            2 => 1 << cpu,
+            _ => return,
+        };
+        for (target, state) in self.cpus.iter_mut().enumerate() {
+            if targets & (1 << target) != 0 {
+                state.pending |= 1 << irq;
+                state.sgi_sources[irq] |= 1 << cpu;
+            }
+        }
+    }
+
+    fn distributor_read(&self, cpu: usize, offset: u64, access_size: u8) -> u64 {
+        let word = |range: Range<u64>| ((offset - range.start) / 4) as usize;
+        let value = match offset {
+            GICD_CTLR => self.ctlr,
+            GICD_TYPER => ((SMP as u32 - 1) << 5) | (IRQ_WORDS as u32 - 1),
+            GICD_IIDR => GIC_IIDR,
+            GICD_PIDR2 => 0x2 << 4,
+            o if GICD_IGROUPR.contains(&o) => 0,
+            o if (GICD_ISENABLER.start..GICD_ICACTIVER.end).contains(&o) => {
+                let word = ((o & 0x7f) / 4) as usize;
+                if word >= IRQ_WORDS {
+                    return 0;
+                }
+                let &(_, reg, _) = BIT_REGS
+                    .iter()
+                    .find(|(regs, ..)| regs.contains(&o))
+                    .unwrap();
+                self.bits(reg, cpu, word)
+            }
+            o if GICD_IPRIORITYR.contains(&o) || GICD_ITARGETSR.contains(&o) => {
+                // Byte-accessible, one byte per interrupt.
+                let first = (offset & 0x3ff) as usize;
+                let mut value = 0;
+                for i in (0..access_size as usize).rev() {
+                    let irq = first + i;
+                    let byte = if GICD_IPRIORITYR.contains(&o) {
+                        self.irq_priority(cpu, irq)
+                    } else if irq < GIC_PRIVATE_IRQS {
+                        // Read-only, the CPU interface reading them.
+                        1 << cpu
+                    } else {
+                        self.targets[irq]
+                    };
+                    value = (value << 8) | byte as u32;
+                }
+                value
+            }
+            o if GICD_ICFGR.contains(&o) => self.config[word(GICD_ICFGR)],
+            _ => {
+                debug!("GICv2: read of unknown distributor register {:#x}", offset);
+                0
+            }
+        };
+        value as u64
+    }
+
+    fn distributor_write(&mut self, cpu: usize, offset: u64, access_size: u8, value: u32) {
+        let word = |range: Range<u64>| ((offset - range.start) / 4) as usize;
+        match offset {
+            GICD_CTLR => self.ctlr = value & 1,
+            GICD_SGIR => self.send_sgi(cpu, value),
+            o if (GICD_ISENABLER.start..GICD_ICACTIVER.end).contains(&o) => {
+                let word = ((o & 0x7f) / 4) as usize;
+                if word >= IRQ_WORDS {
+                    return;
+                }
+                let &(_, reg, set) = BIT_REGS
+                    .iter()
+                    .find(|(regs, ..)| regs.contains(&o))
+                    .unwrap();
+                // The SGIs are made pending through GICD_SGIR, with their source.
+                let value = match reg {
+                    BitReg::Pending if word == 0 => value & !0xffff,
+                    _ => value,
+                };
+                let bits = self.bits_mut(reg, cpu, word);
+                if set {
+                    *bits |= value;
+                } else {
+                    *bits &= !value;
+                }
+                if let (BitReg::Pending, false, 0) = (reg, set, word) {
+                    let state = &mut self.cpus[cpu];
+                    for irq in (0..GIC_SGIS).filter(|irq| value & (1 << irq) != 0) {
+                        state.sgi_sources[irq] = 0;
+                    }
+                }
+            }
+            o if GICD_IPRIORITYR.contains(&o) || GICD_ITARGETSR.contains(&o) => {
+                let first = (offset & 0x3ff) as usize;
+                for i in 0..access_size as usize {
+                    let irq = first + i;
+                    let byte = (value >> (i * 8)) as u8;
+                    if GICD_IPRIORITYR.contains(&o) {
+                        match irq {
+                            0..GIC_PRIVATE_IRQS => self.cpus[cpu].priority[irq] = byte,
+                            _ => self.priority[irq] = byte,
+                        }
+                    } else if irq >= GIC_PRIVATE_IRQS {
+                        self.targets[irq] = byte;
+                    }
+                }
+            }
+            // The SGI configuration is read-only.
+            o if GICD_ICFGR.contains(&o) && o != GICD_ICFGR.start => {
+                self.config[word(GICD_ICFGR)] = value
+            }
+            o if GICD_IGROUPR.contains(&o) || GICD_ICFGR.contains(&o) => {}
+            _ => debug!(
+                "GICv2: write of unknown distributor register {:#x}: {:#x}",
+                offset, value
+            ),
+        }
+    }
+
+    fn cpu_interface_read(&mut self, cpu: usize, offset: u64) -> u64 {
+        let value = match offset {
+            GICC_CTLR => self.cpus[cpu].ctlr,
+            GICC_PMR => self.cpus[cpu].pmr as u32,
+            GICC_BPR => self.cpus[cpu].bpr as u32,
+            GICC_IAR => self.acknowledge(cpu),
+            GICC_RPR => self.cpus[cpu].running_priority() as u32,
+            GICC_HPPIR => self
+                .highest_pending(cpu)
+                .map_or(SPURIOUS_IRQ, |(irq, _)| irq as u32),
+            GICC_IIDR => (0x2 << 16) | GIC_IIDR,
+            _ => {
+                debug!(
+                    "GICv2: read of unknown CPU interface register {:#x}",
+                    offset
+                );
+                0
+            }
+        };
+        value as u64
+    }
+
+    fn cpu_interface_write(&mut self, cpu: usize, offset: u64, value: u32) {
+        let state = &mut self.cpus[cpu];
+        match offset {
+            GICC_CTLR => state.ctlr = value & 1,
+            GICC_PMR => state.pmr = value as u8,
+            GICC_BPR => state.bpr = (value & 0x7) as u8,
+            GICC_EOIR => self.end_of_interrupt(cpu, value & 0x1fff),
+            // Only used with GICC_CTLR.EOImodeNS set, which is not implemented.
+            GICC_DIR => {}
+            _ => debug!(
+                "GICv2: write of unknown CPU interface register {:#x}: {:#x}",
+                offset, value
+            ),
+        }
+    }
+}
+
+fn check_gic_access(range: Range<u64>, addr: u64, access_size: u8) -> HyperResult {
+    let aligned = matches!(access_size, 1 | 2 | 4) && addr % access_size as u64 == 0;
+    if !aligned || addr + access_size as u64 > range.end {
+        warn!(
+            "Invalid GICv2 access size {} @ {:#x}, range {:#x?}",
+            access_size, addr, range
+        );
+        return Err(HyperError::InvalidParam);
+    }
+    Ok(())
+}
+
+/// The distributor of a [`Gicv2`] as seen by one vCPU.
+///
+/// Its banked registers are the ones of CPU interface `cpu`, and it sends SGIs from it.
+pub struct GicDistributor {
+    base: u64,
+    cpu: usize,
+    gic: Arc<Mutex<Gicv2>>,
+}
+
+impl GicDistributor {
+    pub fn new(base: u64, cpu: usize, gic: Arc<Mutex<Gicv2>>) -> Self {
+        Self { base, cpu, gic }
+    }
+}
+
+impl MmioOps for GicDistributor {
+    fn mmio_range(&self) -> Range<u64> {
+        self.base..self.base + GICD_SIZE
+    }
+
+    fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+        check_gic_access(self.mmio_range(), addr, access_size)?;
+        Ok(self
+            .gic
+            .lock()
+            .distributor_read(self.cpu, addr - self.base, access_size))
+    }
+
+    fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+        check_gic_access(self.mmio_range(), addr, access_size)?;
+        self.gic
+            .lock()
+            .distributor_write(self.cpu, addr - self.base, access_size, value as u32);
+        Ok(())
+    }
+}
+
+/// The CPU interface of a [`Gicv2`] banked for one vCPU.
+pub struct GicCpuInterface {
+    base: u64,
+    cpu: usize,
+    gic: Arc<Mutex<Gicv2>>,
+}
+
+impl GicCpuInterface {
+    pub fn new(base: u64, cpu: usize, gic: Arc<Mutex<Gicv2>>) -> Self {
+        Self { base, cpu, gic }
+    }
+
+    /// Whether the interface signals an interrupt to its vCPU.
+    pub fn irq_pending(&self) -> bool {
+        self.gic.lock().irq_pending(self.cpu)
+    }
+}
+
+impl MmioOps for GicCpuInterface {
+    fn mmio_range(&self) -> Range<u64> {
+        self.base..self.base + GICC_SIZE
+    }
+
+    fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+        check_gic_access(self.mmio_range(), addr, access_size)?;
+        Ok(self
+            .gic
+            .lock()
+            .cpu_interface_read(self.cpu, addr - self.base))
+    }
+
+    fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+        check_gic_access(self.mmio_range(), addr, access_size)?;
+        self.gic
+            .lock()
+            .cpu_interface_write(self.cpu, addr - self.base, value as u32);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x0800_0000;
+    const SPI: usize = 40;
+
+    fn gic() -> Arc<Mutex<Gicv2>> {
+        Arc::new(Mutex::new(Gicv2::new()))
+    }
+
+    /// The distributor and CPU interface of CPU 0, both enabled and unmasked.
+    fn enabled(gic: &Arc<Mutex<Gicv2>>) -> (GicDistributor, GicCpuInterface) {
+        let mut gicd = GicDistributor::new(BASE, 0, gic.clone());
+        let mut gicc = GicCpuInterface::new(BASE + GICD_SIZE, 0, gic.clone());
+        gicd.write(BASE + GICD_CTLR, 4, 1).unwrap();
+        gicc.write(BASE + GICD_SIZE + GICC_CTLR, 4, 1).unwrap();
+        gicc.write(BASE + GICD_SIZE + GICC_PMR, 4, 0xf0).unwrap();
+        (gicd, gicc)
+    }
+
+    fn iar(gicc: &mut GicCpuInterface) -> u32 {
+        gicc.read(BASE + GICD_SIZE + GICC_IAR, 4).unwrap() as u32
+    }
+
+    fn eoi(gicc: &mut GicCpuInterface, iar: u32) {
+        gicc.write(BASE + GICD_SIZE + GICC_EOIR, 4, iar as u64)
+            .unwrap();
+    }
+
+    /// Enable `irq` with `priority`, targeting CPU 0.
+    fn enable(gicd: &mut GicDistributor, irq: usize, priority: u8) {
+        let (word, mask) = bit(irq);
+        let isenabler = BASE + GICD_ISENABLER.start + 4 * word as u64;
+        gicd.write(isenabler, 4, mask as u64).unwrap();
+        gicd.write(
+            BASE + GICD_IPRIORITYR.start + irq as u64,
+            1,
+            priority as u64,
+        )
+        .unwrap();
+        gicd.write(BASE + GICD_ITARGETSR.start + irq as u64, 1, 1)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_spi() {
+        let gic = gic();
+        let (mut gicd, mut gicc) = enabled(&gic);
+        gic.lock().set_pending(SPI);
+        assert!(!gicc.irq_pending(), "signaled while disabled");
+
+        enable(&mut gicd, SPI, 0x80);
+        assert!(gicc.irq_pending());
+        let (word, mask) = bit(SPI);
+        let ispendr = BASE + GICD_ISPENDR.start + 4 * word as u64;
+        assert_eq!(gicd.read(ispendr, 4).unwrap() as u32 & mask, mask);
+
+        // Acknowledged, the SPI is active and no longer signaled until its EOI.
+        assert_eq!(iar(&mut gicc), SPI as u32);
+        assert!(!gicc.irq_pending());
+        assert_eq!(iar(&mut gicc), SPURIOUS_IRQ);
+        let rpr = BASE + GICD_SIZE + GICC_RPR;
+        assert_eq!(gicc.read(rpr, 4).unwrap(), 0x80);
+        eoi(&mut gicc, SPI as u32);
+        assert_eq!(gicc.read(rpr, 4).unwrap(), IDLE_PRIORITY as u64);
+
+        // Disabled again with ICENABLER, a pending SPI is not signaled.
+        gic.lock().set_pending(SPI);
+        let icenabler = BASE + GICD_ICENABLER.start + 4 * word as u64;
+        gicd.write(icenabler, 4, mask as u64).unwrap();
+        assert!(!gicc.irq_pending());
+    }
+
+    #[test]
+    fn test_priority() {
+        let gic = gic();
+        let (mut gicd, mut gicc) = enabled(&gic);
+        enable(&mut gicd, SPI, 0xa0);
+        enable(&mut gicd, SPI + 1, 0x40);
+        gic.lock().set_pending(SPI);
+        gic.lock().set_pending(SPI + 1);
+
+        // The highest priority first, then the other one only once it completed.
+        assert_eq!(iar(&mut gicc), SPI as u32 + 1);
+        assert!(!gicc.irq_pending(), "preempted by a lower priority");
+        eoi(&mut gicc, SPI as u32 + 1);
+        assert_eq!(iar(&mut gicc), SPI as u32);
+        eoi(&mut gicc, SPI as u32);
+
+        // Masked by the priority mask.
+        gic.lock().set_pending(SPI);
+        gicc.write(BASE + GICD_SIZE + GICC_PMR, 4, 0xa0).unwrap();
+        assert!(!gicc.irq_pending());
+    }
+
+    #[test]
+    fn test_level() {
+        let gic = gic();
+        let (mut gicd, mut gicc) = enabled(&gic);
+        enable(&mut gicd, SPI, 0x80);
+        gic.lock().set_level(SPI, true);
+        assert_eq!(iar(&mut gicc), SPI as u32);
+        // Not made pending again while it is handled, but again after its EOI.
+        gic.lock().set_level(SPI, true);
+        assert!(!gicc.irq_pending());
+        eoi(&mut gicc, SPI as u32);
+        gic.lock().set_level(SPI, true);
+        assert!(gicc.irq_pending());
+        gic.lock().set_level(SPI, false);
+        assert!(!gicc.irq_pending());
+    }
+
+    #[test]
+    fn test_sgi() {
+        let gic = gic();
+        let (mut gicd, mut gicc) = enabled(&gic);
+        enable(&mut gicd, 3, 0x80);
+        // To all CPUs but the sender.
+        gicd.write(BASE + GICD_SGIR, 4, 1 << 24 | 3).unwrap();
+        assert!(!gicc.irq_pending());
+        // To the sender, with its CPU ID in GICC_IAR.
+        gicd.write(BASE + GICD_SGIR, 4, 2 << 24 | 3).unwrap();
+        assert_eq!(iar(&mut gicc), 3);
+        eoi(&mut gicc, 3);
+        assert!(!gicc.irq_pending());
+        // SGIs are not made pending through GICD_ISPENDR.
+        gicd.write(BASE + GICD_ISPENDR.start, 4, 1 << 3).unwrap();
+        assert!(!gicc.irq_pending());
+    }
+
+    #[test]
+    fn test_registers() {
+        let gic = gic();
+        let (mut gicd, mut gicc) = enabled(&gic);
+        let typer = gicd.read(BASE + GICD_TYPER, 4).unwrap() as u32;
+        assert_eq!(typer & 0x1f, IRQ_WORDS as u32 - 1);
+        assert_eq!((typer >> 5) & 0x7, SMP as u32 - 1);
+        // One byte per interrupt, the targets of the private interrupts being the reader.
+        let itargetsr = BASE + GICD_ITARGETSR.start;
+        assert_eq!(gicd.read(itargetsr, 4).unwrap(), 0x0101_0101);
+        gicd.write(BASE + GICD_IPRIORITYR.start + SPI as u64, 4, 0x4030_2010)
+            .unwrap();
+        let priorityr = BASE + GICD_IPRIORITYR.start + SPI as u64;
+        assert_eq!(gicd.read(priorityr + 1, 1).unwrap(), 0x20);
+        assert_eq!(gicd.read(priorityr + 2, 2).unwrap(), 0x4030);
+        assert_eq!(gicc.read(BASE + GICD_SIZE + GICC_HPPIR, 4).unwrap(), 1023);
+
+        // Unaligned or out of range accesses are rejected.
+        assert!(gicd.read(BASE + GICD_CTLR + 1, 4).is_err());
+        assert!(gicd.read(BASE + GICD_CTLR, 8).is_err());
+        assert!(gicc.read(BASE + GICD_SIZE + GICC_SIZE - 2, 4).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+//! Emulated devices of aarch64 guests: a GICv2 and a PL011 UART, at the addresses of the QEMU
+//! `virt` machine.
+//!
+//! hypercraft reports the MMIO accesses of aarch64 guests as data aborts, decoded from the
+//! syndrome register rather than from the instruction, so the devices are reached through
+//! [`handle_mmio`](super::handle_mmio) with the decoded access. Like on x86_64, the per-vCPU
+//! devices, the GIC distributor and CPU interface banked for the vCPU, are tried first.
+
+mod gicv2;
+mod pl011;
+
+use alloc::sync::Arc;
+
+use axconfig::SMP;
+use hypercraft::{HyperError, HyperResult};
+use lock_stat::Mutex;
+
+use super::mmio::{MmioAccess, MmioDevices, MmioVcpuDevices, MmioVmDevices};
+pub use gicv2::{GicCpuInterface, GicDistributor, Gicv2, GIC_MAX_IRQS};
+pub use pl011::Pl011;
+
+/// Base of the GICv2 distributor.
+pub const GICD_BASE: u64 = 0x0800_0000;
+/// Base of the GICv2 CPU interface.
+pub const GICC_BASE: u64 = 0x0801_0000;
+/// Base of the PL011 UART.
+pub const PL011_BASE: u64 = 0x0900_0000;
+/// Interrupt of the PL011 UART, SPI 1.
+pub const PL011_IRQ: usize = 33;
+
+/// The devices of one vCPU: the GIC distributor and CPU interface banked for it.
+pub struct Aarch64VcpuDevices {
+    cpu_interface: Arc<Mutex<GicCpuInterface>>,
+    devices: MmioDevices,
+}
+
+impl MmioVcpuDevices for Aarch64VcpuDevices {
+    type VmDevices = Aarch64VmDevices;
+
+    /// Fails with `InvalidParam` if the GIC has no CPU interface for `vcpu_id`.
+    fn new(vcpu_id: usize, vm_devices: &Aarch64VmDevices) -> HyperResult<Self> {
+        if vcpu_id >= SMP {
+            warn!(
+                "VM [{}] vCPU {}: the GICv2 has {} CPU interfaces",
+                vm_devices.vm_id, vcpu_id, SMP
+            );
+            return Err(HyperError::InvalidParam);
+        }
+        let cpu_interface = Arc::new(Mutex::new(GicCpuInterface::new(
+            GICC_BASE,
+            vcpu_id,
+            vm_devices.gic.clone(),
+        )));
+        let mut devices = MmioDevices::new();
+        devices.add(Arc::new(Mutex::new(GicDistributor::new(
+            GICD_BASE,
+            vcpu_id,
+            vm_devices.gic.clone(),
+        ))))?;
+        devices.add(cpu_interface.clone())?;
+        Ok(Self {
+            cpu_interface,
+            devices,
+        })
+    }
+
+    fn handle_mmio(&mut self, access: MmioAccess) -> Option<HyperResult<u64>> {
+        self.devices.handle_mmio(access)
+    }
+}
+
+impl Aarch64VcpuDevices {
+    /// Whether the virtual IRQ line of the vCPU is to be asserted at the next VM entry.
+    pub fn irq_pending(&self) -> bool {
+        self.cpu_interface.lock().irq_pending()
+    }
+}
+
+/// The devices shared by all vCPUs of a VM: the GIC state and the UART.
+pub struct Aarch64VmDevices {
+    vm_id: u32,
+    gic: Arc<Mutex<Gicv2>>,
+    uart: Arc<Mutex<Pl011>>,
+    devices: MmioDevices,
+}
+
+impl MmioVmDevices for Aarch64VmDevices {
+    fn new(vm_id: u32) -> HyperResult<Self> {
+        let gic = Arc::new(Mutex::new(Gicv2::new()));
+        let uart = Arc::new(Mutex::new(Pl011::new(PL011_BASE)));
+        let mut devices = MmioDevices::new();
+        devices.add(uart.clone())?;
+        Ok(Self {
+            vm_id,
+            gic,
+            uart,
+            devices,
+        })
+    }
+
+    fn handle_mmio(&self, access: MmioAccess) -> Option<HyperResult<u64>> {
+        self.devices.handle_mmio(access)
+    }
+}
+
+impl Aarch64VmDevices {
+    pub fn gic(&self) -> Arc<Mutex<Gicv2>> {
+        self.gic.clone()
+    }
+
+    pub fn uart(&self) -> Arc<Mutex<Pl011>> {
+        self.uart.clone()
+    }
+
+    /// Forward the level of the device interrupt lines to the GIC, before each VM entry.
+    pub fn check_events(&self) {
+        let level = self.uart.lock().irq_level();
+        self.gic.lock().set_level(PL011_IRQ, level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::handle_mmio;
+
+    fn read(addr: u64) -> MmioAccess {
+        MmioAccess {
+            addr,
+            access_size: 4,
+            write: None,
+        }
+    }
+
+    #[test]
+    fn test_devices() {
+        let vm = Aarch64VmDevices::new(0).unwrap();
+        let mut vcpu = Aarch64VcpuDevices::new(0, &vm).unwrap();
+        assert!(Aarch64VcpuDevices::new(SMP, &vm).is_err());
+
+        // The GIC is reached through the per-vCPU devices, the UART through the per-VM ones.
+        let ctlr = MmioAccess {
+            write: Some(1),
+            ..read(GICD_BASE)
+        };
+        assert_eq!(vcpu.handle_mmio(ctlr).unwrap().unwrap(), 0);
+        assert_eq!(handle_mmio(&mut vcpu, &vm, read(GICD_BASE)).unwrap(), 1);
+        assert!(vcpu.handle_mmio(read(PL011_BASE)).is_none());
+        // UARTFR, both FIFOs empty.
+        assert_eq!(
+            handle_mmio(&mut vcpu, &vm, read(PL011_BASE + 0x18)).unwrap(),
+            0x90
+        );
+        assert!(matches!(
+            handle_mmio(&mut vcpu, &vm, read(0x0a00_0000)),
+            Err(HyperError::InValidMmioRead)
+        ));
+    }
+
+    #[test]
+    fn test_uart_irq() {
+        let vm = Aarch64VmDevices::new(0).unwrap();
+        let mut vcpu = Aarch64VcpuDevices::new(0, &vm).unwrap();
+        let write = |addr, value| MmioAccess {
+            write: Some(value),
+            ..read(addr)
+        };
+        let (word, mask) = (PL011_IRQ / 32, 1u64 << (PL011_IRQ % 32));
+        for access in [
+            write(GICD_BASE, 1),
+            write(GICD_BASE + 0x100 + 4 * word as u64, mask),
+            write(
+                GICD_BASE + 0x800 + (PL011_IRQ as u64 & !3),
+                1 << (8 * (PL011_IRQ % 4)),
+            ),
+            write(GICC_BASE, 1),
+            write(GICC_BASE + 0x4, 0xf0),
+            // TX interrupt unmasked, raised as nothing is being sent.
+            write(PL011_BASE + 0x38, 1 << 5),
+        ] {
+            handle_mmio(&mut vcpu, &vm, access).unwrap();
+        }
+        assert!(!vcpu.irq_pending());
+        vm.check_events();
+        assert!(vcpu.irq_pending());
+        assert_eq!(
+            handle_mmio(&mut vcpu, &vm, read(GICC_BASE + 0xc)).unwrap(),
+            PL011_IRQ as u64
+        );
+        handle_mmio(&mut vcpu, &vm, write(GICC_BASE + 0x10, PL011_IRQ as u64)).unwrap();
+        // Cleared at the UART, the line is low at the next check.
+        handle_mmio(&mut vcpu, &vm, write(PL011_BASE + 0x44, 1 << 5)).unwrap();
+        vm.check_events();
+        assert!(!vcpu.irq_pending());
+    }
+}
@@ -0,0 +1,293 @@
+//! Emulated PL011 UART. (ref: ARM DDI 0183G, PrimeCell UART (PL011) Technical Reference Manual)
+//!
+//! Like the 16550 on x86_64, the UART writes to and reads from a [`VirtualConsoleBackend`]. Its
+//! interrupt line is level-sensitive: [`Pl011::irq_level`] is forwarded to the GIC before each
+//! VM entry.
+
+use core::ops::Range;
+
+use hypercraft::{HyperError, HyperResult, MmioOps};
+
+use crate::device::console_backend::{DefaultConsoleBackend, Fifo, VirtualConsoleBackend};
+
+const PL011_SIZE: u64 = 0x1000;
+const PL011_FIFO_CAPACITY: usize = 32;
+
+const UARTDR: u64 = 0x000;
+const UARTRSR: u64 = 0x004;
+const UARTFR: u64 = 0x018;
+const UARTILPR: u64 = 0x020;
+const UARTIBRD: u64 = 0x024;
+const UARTFBRD: u64 = 0x028;
+const UARTLCR_H: u64 = 0x02c;
+const UARTCR: u64 = 0x030;
+const UARTIFLS: u64 = 0x034;
+const UARTIMSC: u64 = 0x038;
+const UARTRIS: u64 = 0x03c;
+const UARTMIS: u64 = 0x040;
+const UARTICR: u64 = 0x044;
+const UARTDMACR: u64 = 0x048;
+/// UARTPeriphID0-3 and UARTPCellID0-3, checked by the AMBA bus driver of Linux.
+const UART_ID: Range<u64> = 0xfe0..0x1000;
+const UART_ID_VALUES: [u8; 8] = [0x11, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+bitflags::bitflags! {
+    /// Flag register
+    #[derive(Clone, Copy)]
+    struct FlagFlags: u32 {
+        const BUSY = 1 << 3;
+        const RX_FIFO_EMPTY = 1 << 4;
+        const TX_FIFO_FULL = 1 << 5;
+        const RX_FIFO_FULL = 1 << 6;
+        const TX_FIFO_EMPTY = 1 << 7;
+    }
+}
+
+bitflags::bitflags! {
+    /// Interrupt mask, raw and masked status registers
+    #[derive(Clone, Copy)]
+    struct IntFlags: u32 {
+        const RX = 1 << 4;
+        const TX = 1 << 5;
+        const RX_TIMEOUT = 1 << 6;
+    }
+}
+
+pub struct Pl011<B: VirtualConsoleBackend = DefaultConsoleBackend> {
+    base: u64,
+    backend: B,
+    fifo: Fifo<PL011_FIFO_CAPACITY>,
+    ibrd: u32,
+    fbrd: u32,
+    lcr_h: u32,
+    cr: u32,
+    ifls: u32,
+    imsc: IntFlags,
+    ris: IntFlags,
+    dmacr: u32,
+}
+
+impl<B: VirtualConsoleBackend> MmioOps for Pl011<B> {
+    fn mmio_range(&self) -> Range<u64> {
+        self.base..self.base + PL011_SIZE
+    }
+
+    fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+        self.check_access(addr, access_size)?;
+        self.poll_input();
+        let offset = addr - self.base;
+        let value = match offset {
+            UARTDR => {
+                let value = if self.fifo.is_empty() {
+                    0
+                } else {
+                    self.fifo.pop() as u32
+                };
+                self.update_rx_interrupt();
+                value
+            }
+            UARTRSR => 0,
+            UARTFR => {
+                let mut flags = FlagFlags::TX_FIFO_EMPTY;
+                if self.fifo.is_empty() {
+                    flags |= FlagFlags::RX_FIFO_EMPTY;
+                }
+                if self.fifo.is_full() {
+                    flags |= FlagFlags::RX_FIFO_FULL;
+                }
+                flags.bits()
+            }
+            UARTILPR => 0,
+            UARTIBRD => self.ibrd,
+            UARTFBRD => self.fbrd,
+            UARTLCR_H => self.lcr_h,
+            UARTCR => self.cr,
+            UARTIFLS => self.ifls,
+            UARTIMSC => self.imsc.bits(),
+            UARTRIS => self.ris.bits(),
+            UARTMIS => (self.ris & self.imsc).bits(),
+            UARTDMACR => self.dmacr,
+            o if UART_ID.contains(&o) => UART_ID_VALUES[((o - UART_ID.start) / 4) as usize] as u32,
+            _ => {
+                debug!("PL011: read of unknown register {:#x}", offset);
+                0
+            }
+        };
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+        self.check_access(addr, access_size)?;
+        let offset = addr - self.base;
+        let value = value as u32;
+        match offset {
+            UARTDR => {
+                self.backend.putchar(value as u8);
+                // Transmitted at once, the TX FIFO is empty again.
+                self.ris |= IntFlags::TX;
+            }
+            // Writing the receive status register clears the (never set) errors.
+            UARTRSR => {}
+            UARTILPR => {}
+            UARTIBRD => self.ibrd = value & 0xffff,
+            UARTFBRD => self.fbrd = value & 0x3f,
+            UARTLCR_H => self.lcr_h = value & 0xff,
+            UARTCR => self.cr = value & 0xffff,
+            UARTIFLS => self.ifls = value & 0x3f,
+            UARTIMSC => self.imsc = IntFlags::from_bits_truncate(value),
+            UARTICR => self.ris &= !IntFlags::from_bits_truncate(value),
+            UARTDMACR => self.dmacr = value & 0x7,
+            _ => debug!(
+                "PL011: write of unknown register {:#x}: {:#x}",
+                offset, value
+            ),
+        }
+        Ok(())
+    }
+}
+
+impl<B: VirtualConsoleBackend> Pl011<B> {
+    pub fn new(base: u64) -> Self {
+        Self {
+            base,
+            backend: B::new(),
+            fifo: Fifo::new(),
+            ibrd: 0,
+            fbrd: 0,
+            lcr_h: 0,
+            // UARTEN clear, TXE and RXE set.
+            cr: 0x300,
+            // Both FIFOs interrupt when half full.
+            ifls: 0x12,
+            imsc: IntFlags::empty(),
+            ris: IntFlags::TX,
+            dmacr: 0,
+        }
+    }
+
+    fn check_access(&self, addr: u64, access_size: u8) -> HyperResult {
+        let range = self.mmio_range();
+        if !matches!(access_size, 1 | 2 | 4) || addr + access_size as u64 > range.end {
+            warn!("Invalid PL011 access size {} @ {:#x}", access_size, addr);
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Move the pending input of the backend to the receive FIFO.
+    fn poll_input(&mut self) {
+        while !self.fifo.is_full() {
+            match self.backend.getchar() {
+                Some(c) => self.fifo.push(c),
+                None => break,
+            }
+        }
+        self.update_rx_interrupt();
+    }
+
+    fn update_rx_interrupt(&mut self) {
+        self.ris
+            .set(IntFlags::RX | IntFlags::RX_TIMEOUT, !self.fifo.is_empty());
+    }
+
+    /// Whether the UART asserts its interrupt line, after polling the backend for input.
+    pub fn irq_level(&mut self) -> bool {
+        self.poll_input();
+        !(self.ris & self.imsc).is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::vec::Vec;
+
+    const BASE: u64 = 0x0900_0000;
+
+    /// The other end of the serial line: records what the UART sends, and gives it `input`.
+    #[derive(Default)]
+    struct MockBackend {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl VirtualConsoleBackend for MockBackend {
+        fn new() -> Self {
+            Self::default()
+        }
+
+        fn putchar(&mut self, c: u8) {
+            self.output.push(c);
+        }
+
+        fn getchar(&mut self) -> Option<u8> {
+            self.input.pop_front()
+        }
+    }
+
+    fn read(uart: &mut Pl011<MockBackend>, reg: u64) -> u32 {
+        uart.read(BASE + reg, 4).unwrap() as u32
+    }
+
+    fn write(uart: &mut Pl011<MockBackend>, reg: u64, value: u32) {
+        uart.write(BASE + reg, 4, value as u64).unwrap();
+    }
+
+    #[test]
+    fn test_transmit() {
+        let mut uart = Pl011::<MockBackend>::new(BASE);
+        for &c in b"hi" {
+            write(&mut uart, UARTDR, c as u32);
+        }
+        assert_eq!(uart.backend.output, b"hi");
+        let flags = FlagFlags::from_bits_truncate(read(&mut uart, UARTFR));
+        assert!(flags.contains(FlagFlags::TX_FIFO_EMPTY | FlagFlags::RX_FIFO_EMPTY));
+        assert!(!flags.contains(FlagFlags::TX_FIFO_FULL));
+
+        // The TX interrupt is raised once unmasked, until cleared.
+        assert!(!uart.irq_level());
+        write(&mut uart, UARTIMSC, IntFlags::TX.bits());
+        assert!(uart.irq_level());
+        assert_eq!(read(&mut uart, UARTMIS), IntFlags::TX.bits());
+        write(&mut uart, UARTICR, IntFlags::TX.bits());
+        assert!(!uart.irq_level());
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut uart = Pl011::<MockBackend>::new(BASE);
+        write(
+            &mut uart,
+            UARTIMSC,
+            (IntFlags::RX | IntFlags::RX_TIMEOUT).bits(),
+        );
+        assert!(!uart.irq_level());
+        uart.backend.input.extend(b"ok");
+        assert!(uart.irq_level());
+        let flags = FlagFlags::from_bits_truncate(read(&mut uart, UARTFR));
+        assert!(!flags.contains(FlagFlags::RX_FIFO_EMPTY));
+        assert_eq!(read(&mut uart, UARTDR), b'o' as u32);
+        assert_eq!(read(&mut uart, UARTDR), b'k' as u32);
+        // Drained, the RX interrupt is lowered.
+        assert!(!uart.irq_level());
+        let flags = FlagFlags::from_bits_truncate(read(&mut uart, UARTFR));
+        assert!(flags.contains(FlagFlags::RX_FIFO_EMPTY));
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut uart = Pl011::<MockBackend>::new(BASE);
+        write(&mut uart, UARTIBRD, 0x1_0027);
+        write(&mut uart, UARTLCR_H, 0x70);
+        assert_eq!(read(&mut uart, UARTIBRD), 0x27);
+        assert_eq!(read(&mut uart, UARTLCR_H), 0x70);
+        // The AMBA identification of a PL011.
+        let ids: Vec<u8> = (0..8)
+            .map(|i| read(&mut uart, UART_ID.start + 4 * i) as u8)
+            .collect();
+        assert_eq!(ids, UART_ID_VALUES);
+        assert!(uart.read(BASE + UARTDR, 8).is_err());
+        assert!(uart.read(BASE + PL011_SIZE - 2, 4).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! Backing stores of the emulated disks.
+//!
+//! A disk is a [`BlockBackend`]: a RAM disk in host memory set aside for it, see [`RamDisk`],
+//! or, with the `virtio-blk-file` feature, a disk image in the host file system, see
+//! [`FileDisk`].
+
+use core::fmt;
+use core::ptr;
+
+use hypercraft::{HyperError, HyperResult};
+
+use crate::{phys_to_virt, HostPhysAddr, PhysAddr};
+
+/// The contents of an emulated disk, addressed in bytes.
+///
+/// The requests are checked against [`BlockBackend::len`] before reaching the backend. They
+/// may come from several vCPUs and workers at once.
+pub trait BlockBackend: Send + Sync + fmt::Debug {
+    /// Size of the disk in bytes.
+    fn len(&self) -> u64;
+
+    /// Fill `buf` with the bytes at `offset`.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> HyperResult;
+
+    /// Write `buf` at `offset`.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> HyperResult;
+
+    /// Make the writes completed so far durable.
+    fn flush(&self) -> HyperResult {
+        Ok(())
+    }
+
+    /// Whether the writes are refused, the guest then sees a read-only disk.
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/// Whether the `len` bytes at `offset` are on a disk of `disk_len` bytes.
+fn in_disk(offset: u64, len: usize, disk_len: u64) -> bool {
+    offset
+        .checked_add(len as u64)
+        .map_or(false, |end| end <= disk_len)
+}
+
+/// A disk in host physical memory, e.g. an image loaded by the boot loader.
+#[derive(Debug)]
+pub struct RamDisk {
+    paddr: HostPhysAddr,
+    len: usize,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// The disk of the `len` bytes at `paddr`.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be RAM mapped in the linear mapping of the host, and be used by nothing
+    /// else while the disk exists.
+    pub unsafe fn new(paddr: HostPhysAddr, len: usize) -> Self {
+        Self {
+            paddr,
+            len,
+            read_only: false,
+        }
+    }
+
+    /// Refuse the writes.
+    pub fn with_read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    fn ptr(&self, offset: u64) -> *mut u8 {
+        phys_to_virt(PhysAddr::from(self.paddr + offset as usize)).as_mut_ptr()
+    }
+}
+
+impl BlockBackend for RamDisk {
+    fn len(&self) -> u64 {
+        self.len as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> HyperResult {
+        if !in_disk(offset, buf.len(), self.len()) {
+            return Err(HyperError::OutOfRange);
+        }
+        // SAFETY: in the memory given to `new`.
+        unsafe { ptr::copy_nonoverlapping(self.ptr(offset), buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> HyperResult {
+        if self.read_only {
+            return Err(HyperError::NotSupported);
+        }
+        if !in_disk(offset, buf.len(), self.len()) {
+            return Err(HyperError::OutOfRange);
+        }
+        // SAFETY: as in `read_at`.
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr(offset), buf.len()) };
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+/// A disk image in the host file system. Its size is the size of the file when opened, rounded
+/// down to a whole sector by the devices.
+#[cfg(feature = "virtio-blk-file")]
+pub struct FileDisk {
+    path: alloc::string::String,
+    file: axfs::fops::File,
+    len: u64,
+    read_only: bool,
+}
+
+#[cfg(feature = "virtio-blk-file")]
+impl FileDisk {
+    /// Open the image at `path`, for reading only unless `writable`.
+    pub fn open(path: &str, writable: bool) -> HyperResult<Self> {
+        let mut opts = axfs::fops::OpenOptions::new();
+        opts.read(true);
+        opts.write(writable);
+        let open_error = |err| {
+            warn!("failed to open disk image {}: {:?}", path, err);
+            HyperError::InvalidParam
+        };
+        let file = axfs::fops::File::open(path, &opts).map_err(open_error)?;
+        let len = file.get_attr().map_err(open_error)?.size();
+        info!("disk image {}: {} bytes", path, len);
+        Ok(Self {
+            path: alloc::string::String::from(path),
+            file,
+            len,
+            read_only: !writable,
+        })
+    }
+
+    fn io_error(&self, op: &str, offset: u64, err: impl fmt::Debug) -> HyperError {
+        warn!("disk image {}: {} at {:#x} failed: {:?}", self.path, op, offset, err);
+        HyperError::Internal
+    }
+}
+
+#[cfg(feature = "virtio-blk-file")]
+impl fmt::Debug for FileDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileDisk")
+            .field("path", &self.path)
+            .field("len", &self.len)
+            .field("read_only", &self.read_only)
+            .finish()
+    }
+}
+
+#[cfg(feature = "virtio-blk-file")]
+impl BlockBackend for FileDisk {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> HyperResult {
+        if !in_disk(offset, buf.len(), self.len) {
+            return Err(HyperError::OutOfRange);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            match self.file.read_at(pos, &mut buf[done..]) {
+                // The file shrank since it was opened.
+                Ok(0) => return Err(self.io_error("read", pos, "end of file")),
+                Ok(len) => done += len,
+                Err(err) => return Err(self.io_error("read", pos, err)),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> HyperResult {
+        if self.read_only {
+            return Err(HyperError::NotSupported);
+        }
+        if !in_disk(offset, buf.len(), self.len) {
+            return Err(HyperError::OutOfRange);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            match self.file.write_at(pos, &buf[done..]) {
+                Ok(0) => return Err(self.io_error("write", pos, "no progress")),
+                Ok(len) => done += len,
+                Err(err) => return Err(self.io_error("write", pos, err)),
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> HyperResult {
+        if self.read_only {
+            return Ok(());
+        }
+        self.file
+            .flush()
+            .map_err(|err| self.io_error("flush", 0, err))
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
@@ -0,0 +1,72 @@
+//! Backends of the emulated UARTs, shared by the UART models of all architectures.
+
+/// FIFO queue for caching bytes read.
+pub struct Fifo<const CAP: usize> {
+    buf: [u8; CAP],
+    head: usize,
+    num: usize,
+}
+
+impl<const CAP: usize> Fifo<CAP> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; CAP],
+            head: 0,
+            num: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.num
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.num == CAP
+    }
+
+    pub fn push(&mut self, value: u8) {
+        assert!(self.num < CAP);
+        self.buf[(self.head + self.num) % CAP] = value;
+        self.num += 1;
+    }
+
+    pub fn pop(&mut self) -> u8 {
+        assert!(self.num > 0);
+        let ret = self.buf[self.head];
+        self.head += 1;
+        self.head %= CAP;
+        self.num -= 1;
+        ret
+    }
+
+    /// The queued bytes, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.num).map(move |i| self.buf[(self.head + i) % CAP])
+    }
+}
+
+pub trait VirtualConsoleBackend: Send + Sync + Sized {
+    fn new() -> Self;
+    fn putchar(&mut self, c: u8);
+    fn getchar(&mut self) -> Option<u8>;
+}
+
+pub struct DefaultConsoleBackend;
+
+impl VirtualConsoleBackend for DefaultConsoleBackend {
+    fn new() -> Self {
+        Self
+    }
+
+    fn putchar(&mut self, c: u8) {
+        crate::console_mux::guest_putchar(None, c)
+    }
+
+    fn getchar(&mut self) -> Option<u8> {
+        crate::console_mux::guest_getchar()
+    }
+}
@@ -3,6 +3,7 @@ use alloc::sync::{Arc, Weak};
 use core::any::Any;
 use core::sync::atomic::{AtomicU16, Ordering};
 use hypercraft::{HyperResult, RegionOps};
+use lock_stat::Mutex;
 use pci::config::{
     BarAllocTrait, RegionType, DEVICE_ID, PCI_CAP_ID_VNDR, PCI_CAP_VNDR_AND_NEXT_SIZE, REVISION_ID,
     SUBSYSTEM_ID, SUBSYSTEM_VENDOR_ID, SUB_CLASS_CODE, VENDOR_ID,
@@ -10,7 +11,6 @@ use pci::config::{
 use pci::{
     le_write_u16, le_write_u32, msix::init_msix, AsAny, PciBus, PciConfig, PciDevBase, PciDevOps,
 };
-use spin::Mutex;
 
 #[derive(Clone)]
 pub struct DummyPciDevice<B: BarAllocTrait> {
@@ -69,6 +69,8 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for DummyPciDevice<B> {
     }
 
     fn realize(mut self) -> HyperResult<()> {
+        let bar_layout = self.base.parent_bus.upgrade().unwrap().lock().bar_layout.clone();
+        self.base.config.set_bar_layout(bar_layout);
         self.init_write_mask(false)?;
         self.init_write_clear_mask(false)?;
         let device_type = self.device_type();
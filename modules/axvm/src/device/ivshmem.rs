@@ -0,0 +1,504 @@
+//! Memory shared by two guests, each reaching it through an ivshmem PCI device.
+//!
+//! [`create_shmem_channel`](crate::create_shmem_channel) allocates the memory of a channel between
+//! two guest VMs not started yet, and each of them gets a device for it on its root bus whenever
+//! its devices are created. The device has the layout of the ivshmem-doorbell device of QEMU, so
+//! that its guest drivers work unchanged:
+//!
+//! - BAR 0, the registers below;
+//! - BAR 1, the MSI-X table;
+//! - BAR 2, the shared memory, mapped in the nested page table of the VM wherever the guest
+//!   places the BAR, its accesses not exiting.
+//!
+//! | Offset | Register         | Access                                                     |
+//! |--------|------------------|------------------------------------------------------------|
+//! | 0x00   | Interrupt mask   | reads 0, there is no INTx                                  |
+//! | 0x04   | Interrupt status | reads 0                                                    |
+//! | 0x08   | IV position      | the peer id of the VM: 0 for the first VM given, else 1    |
+//! | 0x0c   | Doorbell         | writing `peer << 16 \| vector` raises `vector` of `peer`   |
+//! | 0x10   | Peer status      | bit `peer` set while the VM of `peer` runs                 |
+//!
+//! The peer status is not part of QEMU's device. A VM stopping clears its bit, the doorbells rung
+//! for it are dropped meanwhile, and the memory stays mapped in the other VM: it is freed with the
+//! last device and the channel, which is forgotten once neither VM may run again.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+
+use axalloc::GlobalPage;
+use axhal::mem::virt_to_phys;
+use hypercraft::{HyperError, HyperResult, PciError, RegionOps};
+use lock_stat::Mutex;
+use memory_addr::PAGE_SIZE_4K;
+use page_table_entry::MappingFlags;
+use pci::config::{
+    BarAllocTrait, PciConfig, RegionType, BAR_SPACE_UNMAPPED, DEVICE_ID, PCIE_CONFIG_SPACE_SIZE,
+    PCI_CLASS_MEMORY_RAM, PCI_VENDOR_ID_REDHAT_QUMRANET, REG_SIZE, REVISION_ID, SUBSYSTEM_ID,
+    SUBSYSTEM_VENDOR_ID, SUB_CLASS_CODE, VENDOR_ID,
+};
+use pci::{init_msix, le_write_u16, AsAny, Msix, PciBdf, PciBus, PciDevBase, PciDevOps};
+
+/// Largest channel, in bytes.
+pub const MAX_SHMEM_CHANNEL_SIZE: usize = 256 << 20;
+/// MSI-X vectors of each device, those a doorbell may raise.
+pub const IVSHMEM_MSIX_VECTORS: u32 = 4;
+
+const IVSHMEM_DEVICE_ID: u16 = 0x1110;
+const IVSHMEM_REVISION: u8 = 1;
+const IVSHMEM_BAR_NUM: u8 = 6;
+const REGS_BAR: usize = 0;
+const MSIX_BAR: usize = 1;
+/// 64 bits, its upper half in BAR 3.
+const MEMORY_BAR: usize = 2;
+const REGS_SIZE: u64 = 0x100;
+
+const REG_IV_POSITION: u64 = 0x08;
+const REG_DOORBELL: u64 = 0x0c;
+const REG_PEER_STATUS: u64 = 0x10;
+
+/// Where the doorbells rung for a peer raise its interrupts.
+#[derive(Clone)]
+struct Doorbell {
+    msix: Arc<Mutex<Msix>>,
+    dev_id: Arc<AtomicU16>,
+}
+
+/// One of the two VMs of a channel.
+struct Peer {
+    vm_id: u32,
+    /// The device of the VM while it runs, `None` before it boots and once it stopped.
+    doorbell: spin::Mutex<Option<Doorbell>>,
+    /// The VM stopped for good.
+    stopped: AtomicBool,
+}
+
+/// The memory shared by two VMs, and the doorbells of their devices.
+struct ShmemChannel {
+    id: u32,
+    memory: GlobalPage,
+    peers: [Peer; 2],
+}
+
+impl ShmemChannel {
+    fn size(&self) -> usize {
+        self.memory.size()
+    }
+
+    /// The bits of the peers whose VM runs.
+    fn peer_status(&self) -> u32 {
+        self.peers
+            .iter()
+            .enumerate()
+            .filter(|(_, peer)| peer.doorbell.lock().is_some())
+            .fold(0, |status, (id, _)| status | 1 << id)
+    }
+
+    /// Raise `vector` of the device of `peer`, dropped if its VM does not run.
+    fn ring(&self, peer: usize, vector: u16) {
+        let doorbell = match self.peers.get(peer) {
+            Some(peer) => peer.doorbell.lock().clone(),
+            None => {
+                debug!("shmem channel {}: doorbell of no peer {}", self.id, peer);
+                return;
+            }
+        };
+        match doorbell {
+            Some(doorbell) => doorbell
+                .msix
+                .lock()
+                .notify(vector, doorbell.dev_id.load(Ordering::Acquire)),
+            None => debug!(
+                "shmem channel {}: peer {} does not run, doorbell dropped",
+                self.id, peer
+            ),
+        }
+    }
+
+    /// Read `access_size` bytes at `offset` of the memory, for an access the guest made before
+    /// the memory was mapped.
+    fn read(&self, offset: u64, access_size: u8) -> HyperResult<u64> {
+        let ptr = self.host_ptr(offset, access_size)?;
+        // SAFETY: the access is inside the memory, checked by `host_ptr`; the guests may write
+        // it concurrently.
+        let value = unsafe {
+            match access_size {
+                1 => read_volatile(ptr) as u64,
+                2 => read_volatile(ptr as *const u16) as u64,
+                4 => read_volatile(ptr as *const u32) as u64,
+                8 => read_volatile(ptr as *const u64),
+                _ => return Err(HyperError::InValidMmioRead),
+            }
+        };
+        Ok(value)
+    }
+
+    /// Write `data` at `offset` of the memory, see [`ShmemChannel::read`].
+    fn write(&self, offset: u64, data: &[u8]) -> HyperResult {
+        let ptr = self.host_ptr(offset, data.len() as u8)?;
+        let mut bytes = [0; 8];
+        bytes[..data.len()].copy_from_slice(data);
+        let value = u64::from_le_bytes(bytes);
+        // SAFETY: as for `read`.
+        unsafe {
+            match data.len() {
+                1 => write_volatile(ptr, value as u8),
+                2 => write_volatile(ptr as *mut u16, value as u16),
+                4 => write_volatile(ptr as *mut u32, value as u32),
+                8 => write_volatile(ptr as *mut u64, value),
+                _ => return Err(HyperError::InValidMmioWrite),
+            }
+        }
+        Ok(())
+    }
+
+    fn host_ptr(&self, offset: u64, access_size: u8) -> HyperResult<*mut u8> {
+        match offset.checked_add(access_size as u64) {
+            Some(end) if end <= self.size() as u64 && access_size <= 8 => {
+                Ok((self.memory.start_vaddr().as_usize() + offset as usize) as *mut u8)
+            }
+            _ => Err(HyperError::OutOfRange),
+        }
+    }
+}
+
+static CHANNELS: spin::Mutex<BTreeMap<u32, Arc<ShmemChannel>>> = spin::Mutex::new(BTreeMap::new());
+static NEXT_CHANNEL_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Allocate a channel of `size` bytes, a power of two of at least a page, zeroed, shared by the
+/// guest VMs `vm_a` and `vm_b`, and return its id. The states of the VMs are checked by the
+/// caller, see [`create_shmem_channel`](crate::create_shmem_channel).
+pub(crate) fn create_channel(vm_a: u32, vm_b: u32, size: usize) -> HyperResult<u32> {
+    if size < PAGE_SIZE_4K || !size.is_power_of_two() || size > MAX_SHMEM_CHANNEL_SIZE {
+        warn!("invalid shmem channel size {:#x}", size);
+        return Err(HyperError::InvalidParam);
+    }
+    let mut memory =
+        GlobalPage::alloc_contiguous(size / PAGE_SIZE_4K, PAGE_SIZE_4K).map_err(|e| {
+            warn!(
+                "failed to allocate {:#x} bytes for a shmem channel: {:?}",
+                size, e
+            );
+            HyperError::NoMemory
+        })?;
+    memory.zero();
+    let id = NEXT_CHANNEL_ID.fetch_add(1, Ordering::Relaxed);
+    let peer = |vm_id| Peer {
+        vm_id,
+        doorbell: spin::Mutex::new(None),
+        stopped: AtomicBool::new(false),
+    };
+    let channel = Arc::new(ShmemChannel {
+        id,
+        memory,
+        peers: [peer(vm_a), peer(vm_b)],
+    });
+    CHANNELS.lock().insert(id, channel);
+    info!(
+        "shmem channel {}: {:#x} bytes shared by VM [{}] and VM [{}]",
+        id, size, vm_a, vm_b
+    );
+    Ok(id)
+}
+
+/// Add to `root_bus` of VM `vm_id` an ivshmem device for each channel of the VM.
+pub(crate) fn add_devices<B: BarAllocTrait + 'static>(
+    vm_id: u32,
+    root_bus: &Arc<Mutex<PciBus<B>>>,
+) -> HyperResult {
+    let channels = CHANNELS
+        .lock()
+        .values()
+        .filter(|channel| channel.peers.iter().any(|peer| peer.vm_id == vm_id))
+        .cloned()
+        .collect::<Vec<_>>();
+    for channel in channels {
+        // The two VMs of a channel differ.
+        let peer = channel
+            .peers
+            .iter()
+            .position(|peer| peer.vm_id == vm_id)
+            .unwrap();
+        let devfn = root_bus.lock().alloc_devfn().ok_or_else(|| {
+            HyperError::PciError(PciError::Other(format!(
+                "no free PCI slot for shmem channel {}",
+                channel.id
+            )))
+        })?;
+        let name = format!("ivshmem{}", channel.id);
+        IvshmemDevice::new(
+            name,
+            devfn,
+            Arc::downgrade(root_bus),
+            vm_id,
+            channel.clone(),
+            peer,
+        )
+        .realize()?;
+        info!(
+            "VM {}: shmem channel {} as peer {} at {}",
+            vm_id,
+            channel.id,
+            peer,
+            PciBdf { bus: 0, devfn }
+        );
+    }
+    Ok(())
+}
+
+/// Unlink the devices of `vm_id` from its channels, the VM having stopped, and forget the
+/// channels neither VM may run again. A VM about to `reboot` gets its devices again.
+pub(crate) fn close_vm(vm_id: u32, reboot: bool) {
+    CHANNELS.lock().retain(|_, channel| {
+        for peer in channel.peers.iter().filter(|peer| peer.vm_id == vm_id) {
+            peer.doorbell.lock().take();
+            if !reboot {
+                peer.stopped.store(true, Ordering::Release);
+            }
+        }
+        let done = channel
+            .peers
+            .iter()
+            .all(|peer| peer.stopped.load(Ordering::Acquire));
+        if done {
+            debug!("shmem channel {} closed", channel.id);
+        }
+        !done
+    });
+}
+
+/// The ivshmem device of a VM for one of its channels.
+pub struct IvshmemDevice<B: BarAllocTrait> {
+    base: PciDevBase<B>,
+    dev_id: Arc<AtomicU16>,
+    vm_id: u32,
+    channel: Arc<ShmemChannel>,
+    /// The peer id of the VM in the channel.
+    peer: usize,
+    /// Where the memory is mapped in the nested page table of the VM.
+    mapped: Option<u64>,
+}
+
+impl<B: BarAllocTrait + 'static> IvshmemDevice<B> {
+    fn new(
+        name: String,
+        devfn: u8,
+        parent_bus: Weak<Mutex<PciBus<B>>>,
+        vm_id: u32,
+        channel: Arc<ShmemChannel>,
+        peer: usize,
+    ) -> Self {
+        Self {
+            base: PciDevBase {
+                id: name,
+                config: PciConfig::<B>::new(PCIE_CONFIG_SPACE_SIZE, IVSHMEM_BAR_NUM),
+                devfn,
+                parent_bus,
+            },
+            dev_id: Arc::new(AtomicU16::new(0)),
+            vm_id,
+            channel,
+            peer,
+            mapped: None,
+        }
+    }
+
+    /// Map the memory where the guest placed BAR 2, unmapping it from where it was. Left to the
+    /// exits, and emulated, where it cannot be mapped, e.g. over guest RAM.
+    fn map_memory(&mut self) {
+        let addr = self.base.config.get_bar_address(MEMORY_BAR);
+        let addr = Some(addr).filter(|&addr| addr != BAR_SPACE_UNMAPPED);
+        if self.mapped == addr {
+            return;
+        }
+        let size = self.channel.size();
+        if let Some(old) = self.mapped.take() {
+            if let Err(err) = crate::mm::unmap_device_memory(self.vm_id, old as usize, size) {
+                warn!(
+                    "VM [{}] failed to unmap shmem channel {} from {:#x}: {:?}",
+                    self.vm_id, self.channel.id, old, err
+                );
+            }
+        }
+        let Some(addr) = addr else {
+            return;
+        };
+        let hpa = self.channel.memory.start_paddr(virt_to_phys).as_usize();
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+        match crate::mm::map_device_memory(self.vm_id, addr as usize, hpa, size, flags) {
+            Ok(()) => self.mapped = Some(addr),
+            Err(err) => warn!(
+                "VM [{}] failed to map shmem channel {} at {:#x}: {:?}",
+                self.vm_id, self.channel.id, addr, err
+            ),
+        }
+    }
+
+    fn register_ops(channel: Arc<ShmemChannel>, peer: usize) -> RegionOps {
+        let read_channel = channel.clone();
+        let read = move |offset: u64, access_size: u8| -> HyperResult<u64> {
+            if access_size != 4 {
+                debug!(
+                    "ivshmem register {:#x} read as {} bytes",
+                    offset, access_size
+                );
+                return Ok(0);
+            }
+            Ok(match offset {
+                REG_IV_POSITION => peer as u64,
+                REG_PEER_STATUS => read_channel.peer_status() as u64,
+                _ => 0,
+            })
+        };
+        let write = move |offset: u64, access_size: u8, data: &[u8]| -> HyperResult {
+            if offset != REG_DOORBELL || access_size != 4 {
+                debug!("ivshmem register {:#x} write ignored", offset);
+                return Ok(());
+            }
+            let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            channel.ring((value >> 16) as usize, value as u16);
+            Ok(())
+        };
+        RegionOps {
+            read: Arc::new(read),
+            write: Arc::new(write),
+        }
+    }
+
+    /// The accesses to the memory exit until it is mapped: the first one maps it.
+    fn memory_ops(dev: Weak<Mutex<Self>>, channel: Arc<ShmemChannel>) -> RegionOps {
+        let read_dev = dev.clone();
+        let read_channel = channel.clone();
+        let read = move |offset: u64, access_size: u8| -> HyperResult<u64> {
+            if let Some(dev) = read_dev.upgrade() {
+                dev.lock().map_memory();
+            }
+            read_channel.read(offset, access_size)
+        };
+        let write = move |offset: u64, _access_size: u8, data: &[u8]| -> HyperResult {
+            if let Some(dev) = dev.upgrade() {
+                dev.lock().map_memory();
+            }
+            channel.write(offset, data)
+        };
+        RegionOps {
+            read: Arc::new(read),
+            write: Arc::new(write),
+        }
+    }
+}
+
+impl<B: BarAllocTrait + 'static> AsAny for IvshmemDevice<B> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl<B: BarAllocTrait + 'static> PciDevOps<B> for IvshmemDevice<B> {
+    fn name(&self) -> String {
+        self.base.id.clone()
+    }
+
+    fn pci_base(&self) -> &PciDevBase<B> {
+        &self.base
+    }
+
+    fn pci_base_mut(&mut self) -> &mut PciDevBase<B> {
+        &mut self.base
+    }
+
+    fn realize(mut self) -> HyperResult<()> {
+        let parent_bus = self.base.parent_bus.upgrade().unwrap();
+        parent_bus.lock().check_devfn(self.base.devfn, false)?;
+        let bar_layout = parent_bus.lock().bar_layout.clone();
+        self.base.config.set_bar_layout(bar_layout);
+
+        self.init_write_mask(false)?;
+        self.init_write_clear_mask(false)?;
+        let config = &mut self.base.config.config;
+        le_write_u16(config, VENDOR_ID as usize, PCI_VENDOR_ID_REDHAT_QUMRANET)?;
+        le_write_u16(config, DEVICE_ID as usize, IVSHMEM_DEVICE_ID)?;
+        config[REVISION_ID] = IVSHMEM_REVISION;
+        le_write_u16(config, SUB_CLASS_CODE as usize, PCI_CLASS_MEMORY_RAM)?;
+        le_write_u16(config, SUBSYSTEM_VENDOR_ID, PCI_VENDOR_ID_REDHAT_QUMRANET)?;
+        le_write_u16(config, SUBSYSTEM_ID, IVSHMEM_DEVICE_ID)?;
+        self.dev_id
+            .store(self.set_dev_id(0, self.base.devfn), Ordering::Release);
+
+        let regs_ops = Self::register_ops(self.channel.clone(), self.peer);
+        self.base.config.register_bar(
+            REGS_BAR,
+            Some(regs_ops),
+            RegionType::Mem32Bit,
+            false,
+            REGS_SIZE,
+        )?;
+        init_msix(
+            &mut self.base,
+            MSIX_BAR,
+            IVSHMEM_MSIX_VECTORS,
+            self.dev_id.clone(),
+            None,
+        )?;
+        let doorbell = Doorbell {
+            msix: self.base.config.msix.clone().unwrap(),
+            dev_id: self.dev_id.clone(),
+        };
+
+        let (devfn, channel, peer) = (self.base.devfn, self.channel.clone(), self.peer);
+        let size = channel.size() as u64;
+        let dev = Arc::new(Mutex::new(self));
+        let memory_ops = Self::memory_ops(Arc::downgrade(&dev), channel.clone());
+        dev.lock().base.config.register_bar(
+            MEMORY_BAR,
+            Some(memory_ops),
+            RegionType::Mem64Bit,
+            true,
+            size,
+        )?;
+
+        let mut locked_bus = parent_bus.lock();
+        if let Some(used) = locked_bus.devices.get(&devfn) {
+            error!(
+                "Devfn {:?} has been used by {:?}",
+                devfn,
+                used.lock().name()
+            );
+            return Err(HyperError::InvalidParam);
+        }
+        locked_bus.devices.insert(devfn, dev);
+        *channel.peers[peer].doorbell.lock() = Some(doorbell);
+        Ok(())
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        if offset + data.len() > PCIE_CONFIG_SPACE_SIZE || data.len() > REG_SIZE {
+            error!(
+                "Failed to write pcie config space at offset 0x{:x} with data size {}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+        self.base
+            .config
+            .write(offset, data, self.dev_id.load(Ordering::Acquire));
+        // The command register or BAR 2 may have moved the memory.
+        self.map_memory();
+    }
+
+    fn reset(&mut self, _reset_child_device: bool) -> HyperResult<()> {
+        self.base.config.reset()?;
+        self.map_memory();
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+//! Decoded MMIO accesses.
+//!
+//! An MMIO exit is decoded into an [`MmioAccess`] once, which the fast MMIO handlers and the
+//! emulated MMIO devices then get.
+//!
+//! On aarch64 and riscv64 the trap information gives the address, size and data register of an
+//! MMIO access, so unlike on x86_64 no instruction is decoded, and a device list is a plain sorted
+//! index of MMIO devices, [`MmioDevices`]. The devices of these guests implement [`MmioVcpuDevices`] and
+//! [`MmioVmDevices`] rather than `PerCpuDevices` and `PerVmDevices`, whose exits are the VMX
+//! ones, and are reached through [`handle_mmio`].
+
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+use alloc::sync::Arc;
+
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+use hypercraft::{HyperError, HyperResult, MmioOps};
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+use lock_stat::Mutex;
+
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+use super::range_index::RangeIndex;
+
+/// A decoded MMIO access to an emulated device.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioAccess {
+    pub addr: u64,
+    /// 1, 2, 4 or 8 bytes.
+    pub access_size: u8,
+    /// The value written, `None` for a read.
+    pub write: Option<u64>,
+}
+
+/// MMIO devices sorted by range.
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+pub(crate) struct MmioDevices {
+    devices: RangeIndex<Arc<Mutex<dyn MmioOps>>>,
+}
+
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+impl MmioDevices {
+    pub fn new() -> Self {
+        Self {
+            devices: RangeIndex::new(),
+        }
+    }
+
+    /// Add `device`, failing with `InvalidParam` if its range overlaps the one of another device.
+    pub fn add(&mut self, device: Arc<Mutex<dyn MmioOps>>) -> HyperResult {
+        let range = device.lock().mmio_range();
+        self.devices.insert(range, device).map_err(|(range, _)| {
+            warn!("MMIO device {:#x?} overlaps another device", range);
+            HyperError::InvalidParam
+        })
+    }
+
+    /// Perform `access` on the device claiming its address, and return the value read.
+    ///
+    /// Returns `None` if no device claims the address.
+    pub fn handle_mmio(&self, access: MmioAccess) -> Option<HyperResult<u64>> {
+        let device = self.devices.find(access.addr)?;
+        let mut device = device.lock();
+        Some(match access.write {
+            Some(value) => device
+                .write(access.addr, access.access_size, value)
+                .map(|_| 0),
+            None => device.read(access.addr, access.access_size),
+        })
+    }
+}
+
+/// The devices of one vCPU of a guest whose MMIO accesses are reported decoded.
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+pub trait MmioVcpuDevices: Sized {
+    type VmDevices: MmioVmDevices;
+
+    /// The devices of vCPU `vcpu_id` of the VM owning `vm_devices`.
+    fn new(vcpu_id: usize, vm_devices: &Self::VmDevices) -> HyperResult<Self>;
+
+    /// Perform `access`, `None` if it is left to the per-VM devices.
+    fn handle_mmio(&mut self, access: MmioAccess) -> Option<HyperResult<u64>>;
+}
+
+/// The devices shared by all vCPUs of a VM whose MMIO accesses are reported decoded.
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+pub trait MmioVmDevices: Sized + Send + Sync {
+    fn new(vm_id: u32) -> HyperResult<Self>;
+
+    /// Perform `access` declined by the per-vCPU devices, `None` if no device claims it.
+    fn handle_mmio(&self, access: MmioAccess) -> Option<HyperResult<u64>>;
+}
+
+/// Perform `access` of a vCPU on its devices, then on the devices of its VM, and return the
+/// value read. Fails with `InValidMmioRead` or `InValidMmioWrite` if no device claims it.
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+pub fn handle_mmio<D: MmioVcpuDevices>(
+    vcpu_devices: &mut D,
+    vm_devices: &D::VmDevices,
+    access: MmioAccess,
+) -> HyperResult<u64> {
+    if let Some(result) = vcpu_devices.handle_mmio(access) {
+        return result;
+    }
+    vm_devices.handle_mmio(access).unwrap_or_else(|| {
+        warn!("unhandled MMIO access {:#x?}", access);
+        Err(match access.write {
+            Some(_) => HyperError::InValidMmioWrite,
+            None => HyperError::InValidMmioRead,
+        })
+    })
+}
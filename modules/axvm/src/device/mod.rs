@@ -1,13 +1,63 @@
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::*;
 use pci::config::{BarAllocTrait, RegionType};
+#[cfg(target_arch = "riscv64")]
+pub use riscv64::*;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::*;
 
+mod block_backend;
+mod console_backend;
+#[cfg(feature = "virtio-pci")]
 mod dummy_pci;
+#[cfg(feature = "virtio-pci")]
+mod ivshmem;
+mod mmio;
+mod net_backend;
+mod range_index;
+mod state;
+#[cfg(feature = "virtio-pci")]
 mod virtio;
 
+#[cfg(feature = "virtio-blk-file")]
+pub use block_backend::FileDisk;
+pub use block_backend::{BlockBackend, RamDisk};
+pub(crate) use console_backend::Fifo;
+pub use console_backend::{DefaultConsoleBackend, VirtualConsoleBackend};
+#[cfg(feature = "virtio-pci")]
+pub(crate) use ivshmem::{
+    add_devices as add_shmem_devices, close_vm as close_shmem_channels,
+    create_channel as create_shmem_channel,
+};
+#[cfg(feature = "virtio-pci")]
+pub use ivshmem::{IvshmemDevice, IVSHMEM_MSIX_VECTORS, MAX_SHMEM_CHANNEL_SIZE};
+pub use net_backend::{MemoryNet, NetBackend, MAX_FRAME_LEN};
+pub use state::{DeviceState, StateReader, StateWriter};
+#[cfg(feature = "virtio-pci")]
+pub(crate) use virtio::device::console::{
+    close_vm as close_virtio_consoles, poll_consoles as poll_virtio_consoles,
+};
+#[cfg(feature = "virtio-pci")]
+pub(crate) use virtio::device::net::{
+    close_vm as close_virtio_nets, poll_nets as poll_virtio_nets,
+};
+#[cfg(feature = "virtio-pci")]
+pub(crate) use virtio::worker::close_vm as close_virtio_queues;
+#[cfg(feature = "virtio-pci")]
+pub use virtio::{
+    set_virtio_workers, virtio_net_stats, virtio_worker_stats, VirtioNetStats, VirtioWorkerStats,
+};
+pub use mmio::MmioAccess;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+pub use mmio::{handle_mmio, MmioVcpuDevices, MmioVmDevices};
+
 use axalloc::global_allocator;
 use hypercraft::{HyperError, HyperResult, MmioOps, PioOps, RegionOps, VirtMsrOps};
 
@@ -40,3 +90,32 @@ impl BarAllocTrait for BarAllocImpl {
         }
     }
 }
+
+/// A port I/O device taking the elements of a `rep outs` at once rather than one write at a
+/// time, e.g. a console flushing a whole line to its backend.
+///
+/// [`PioOps`] belongs to hypercraft, so the device lists keep the devices implementing this
+/// beside their [`PioOps`] handles.
+pub trait PioBatchOps: PioOps {
+    /// Write `data`, elements of `access_size` bytes in little-endian order, to `port`. As many
+    /// writes by default.
+    fn write_batch(&mut self, port: u16, access_size: u8, data: &[u8]) -> HyperResult {
+        write_each(self, port, access_size, data)
+    }
+}
+
+/// Write the elements of `data` to `port` of `device` one by one, see
+/// [`PioBatchOps::write_batch`].
+pub(crate) fn write_each<D: PioOps + ?Sized>(
+    device: &mut D,
+    port: u16,
+    access_size: u8,
+    data: &[u8],
+) -> HyperResult {
+    for element in data.chunks(access_size as usize) {
+        let mut bytes = [0; 4];
+        bytes[..element.len()].copy_from_slice(element);
+        device.write(port, access_size, u32::from_le_bytes(bytes))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,153 @@
+//! Links of the emulated network cards.
+//!
+//! A card sends and receives Ethernet frames through a [`NetBackend`]: an end of a pair of frame
+//! queues in host memory, see [`MemoryNet`], which links two VMs on the host, or a VM and the
+//! host holding the other end, or loops the frames of a VM back to it.
+//!
+//! The host network stack, `axnet`, offers its sockets only, not the frames of its interface, so
+//! no backend bridges a card to it.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use hypercraft::{HyperError, HyperResult};
+use spin::Mutex;
+
+use crate::park::VcpuWaker;
+
+/// Longest Ethernet frame the cards send and receive, with a VLAN tag and without the FCS.
+pub const MAX_FRAME_LEN: usize = 1518;
+
+/// The link of an emulated network card.
+///
+/// The frames are whole Ethernet frames, of at most [`MAX_FRAME_LEN`] bytes. A backend may be
+/// used from several vCPUs at once, and is polled for the frames it received, see
+/// [`NetBackend::recv`], by the vCPUs running: one receiving a frame while the vCPUs of its card
+/// are halted wakes them, see [`NetBackend::set_waker`].
+pub trait NetBackend: Send + Sync + fmt::Debug {
+    /// Send `frame`. Fails, the frame being dropped, with `InvalidParam` if it is too long, with
+    /// `NoMemory` if there is no room for it.
+    fn send(&self, frame: &[u8]) -> HyperResult;
+
+    /// Move the next frame received to `buf` and return its length, `None` if none is waiting.
+    /// The frames longer than `buf` are dropped.
+    fn recv(&self, buf: &mut [u8]) -> Option<usize>;
+
+    /// Wake the vCPU of `waker` when a frame is received, none if `None`. Set by the card when it
+    /// is attached to its VM, and cleared when the VM stops.
+    fn set_waker(&self, _waker: Option<VcpuWaker>) {}
+}
+
+/// The frames waiting in one direction of a [`MemoryNet`] link.
+#[derive(Debug)]
+struct FrameQueue {
+    frames: Mutex<VecDeque<Vec<u8>>>,
+    capacity: usize,
+    /// Frames dropped, the queue being full or the frame too long for the receiver.
+    dropped: AtomicU64,
+    /// The vCPU of the receiver, woken when a frame is queued.
+    waker: Mutex<Option<VcpuWaker>>,
+}
+
+impl FrameQueue {
+    fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            frames: Mutex::new(VecDeque::new()),
+            capacity,
+            dropped: AtomicU64::new(0),
+            waker: Mutex::new(None),
+        })
+    }
+}
+
+/// An end of a link in host memory: what is sent through it is received from the other end, up
+/// to a number of frames waiting in each direction.
+///
+/// ```ignore
+/// let (guest, host) = MemoryNet::pair(64);
+/// let vm = VmBuilder::new("net")
+///     .virtio_device(VirtioDeviceCfg::net("eth0", Arc::new(guest), MAC, 256))
+///     .build()?;
+/// host.send(&arp_request)?;
+/// ```
+#[derive(Debug)]
+pub struct MemoryNet {
+    rx: Arc<FrameQueue>,
+    tx: Arc<FrameQueue>,
+}
+
+impl MemoryNet {
+    /// A link receiving what it sends itself, up to `capacity` frames waiting.
+    pub fn loopback(capacity: usize) -> Self {
+        let queue = FrameQueue::new(capacity);
+        Self {
+            rx: queue.clone(),
+            tx: queue,
+        }
+    }
+
+    /// The two ends of a link, up to `capacity` frames waiting in each direction.
+    pub fn pair(capacity: usize) -> (Self, Self) {
+        let (a_to_b, b_to_a) = (FrameQueue::new(capacity), FrameQueue::new(capacity));
+        (
+            Self {
+                rx: b_to_a.clone(),
+                tx: a_to_b.clone(),
+            },
+            Self {
+                rx: a_to_b,
+                tx: b_to_a,
+            },
+        )
+    }
+
+    /// Number of frames sent through this end and dropped.
+    pub fn dropped(&self) -> u64 {
+        self.tx.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames waiting to be received from this end.
+    pub fn pending(&self) -> usize {
+        self.rx.frames.lock().len()
+    }
+}
+
+impl NetBackend for MemoryNet {
+    fn send(&self, frame: &[u8]) -> HyperResult {
+        let mut frames = self.tx.frames.lock();
+        let err = if frame.len() > MAX_FRAME_LEN {
+            HyperError::InvalidParam
+        } else if frames.len() >= self.tx.capacity {
+            HyperError::NoMemory
+        } else {
+            frames.push_back(frame.to_vec());
+            drop(frames);
+            if let Some(waker) = *self.tx.waker.lock() {
+                waker.wake();
+            }
+            return Ok(());
+        };
+        self.tx.dropped.fetch_add(1, Ordering::Relaxed);
+        Err(err)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut frames = self.rx.frames.lock();
+        while let Some(frame) = frames.pop_front() {
+            if frame.len() > buf.len() {
+                self.rx.dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            buf[..frame.len()].copy_from_slice(&frame);
+            return Some(frame.len());
+        }
+        None
+    }
+
+    fn set_waker(&self, waker: Option<VcpuWaker>) {
+        *self.rx.waker.lock() = waker;
+    }
+}
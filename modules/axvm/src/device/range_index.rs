@@ -0,0 +1,154 @@
+//! Sorted index of the address ranges claimed by devices.
+//!
+//! Looking up the device of an exit used to lock every registered device to ask for its range,
+//! with the index it is a binary search over ranges captured at registration time.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Non-overlapping ranges sorted by start address, each mapped to a device handle.
+#[derive(Clone)]
+pub(crate) struct RangeIndex<T> {
+    entries: Vec<(Range<u64>, T)>,
+}
+
+impl<T> RangeIndex<T> {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Position of the first entry starting above `addr`.
+    fn upper_bound(&self, addr: u64) -> usize {
+        self.entries
+            .partition_point(|(range, _)| range.start <= addr)
+    }
+
+    /// Insert a device claiming `range`.
+    ///
+    /// A range overlapping an existing entry is rejected and handed back, the device registered
+    /// first keeps the overlapping addresses, as it did with the linear lookup.
+    pub fn insert(&mut self, range: Range<u64>, value: T) -> Result<(), (Range<u64>, T)> {
+        if range.is_empty() {
+            return Err((range, value));
+        }
+        let pos = self.upper_bound(range.start);
+        let overlaps_prev = pos > 0 && self.entries[pos - 1].0.end > range.start;
+        let overlaps_next = pos < self.entries.len() && self.entries[pos].0.start < range.end;
+        if overlaps_prev || overlaps_next {
+            return Err((range, value));
+        }
+        self.entries.insert(pos, (range, value));
+        Ok(())
+    }
+
+    /// Remove the first entry whose value matches `pred`.
+    pub fn remove_by(&mut self, pred: impl Fn(&T) -> bool) -> Option<(Range<u64>, T)> {
+        let pos = self.entries.iter().position(|(_, value)| pred(value))?;
+        Some(self.entries.remove(pos))
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The registered ranges, in ascending order.
+    pub fn ranges(&self) -> impl Iterator<Item = &Range<u64>> {
+        self.entries.iter().map(|(range, _)| range)
+    }
+
+    /// A registered range overlapping `range`.
+    pub fn overlapping(&self, range: &Range<u64>) -> Option<&Range<u64>> {
+        let pos = self.upper_bound(range.start);
+        [pos.checked_sub(1), Some(pos)]
+            .into_iter()
+            .flatten()
+            .filter_map(|pos| self.entries.get(pos))
+            .map(|(claimed, _)| claimed)
+            .find(|claimed| claimed.start < range.end && range.start < claimed.end)
+    }
+
+    /// Find the device whose range contains `addr`.
+    pub fn find(&self, addr: u64) -> Option<&T> {
+        self.find_entry(addr).map(|(_, value)| value)
+    }
+
+    /// Find the device whose range contains `addr`, together with the range.
+    pub fn find_entry(&self, addr: u64) -> Option<&(Range<u64>, T)> {
+        match self.upper_bound(addr) {
+            0 => None,
+            pos => {
+                let entry = &self.entries[pos - 1];
+                entry.0.contains(&addr).then_some(entry)
+            }
+        }
+    }
+}
+
+/// The entry found by the previous lookup, tagged with the generation of the index it was found
+/// in, so that it is not used any more once the index changes.
+pub(crate) struct LastHit<T> {
+    generation: u64,
+    entry: Option<(Range<u64>, T)>,
+}
+
+impl<T: Clone> LastHit<T> {
+    pub const fn new() -> Self {
+        Self {
+            generation: 0,
+            entry: None,
+        }
+    }
+
+    pub fn get(&self, generation: u64, addr: u64) -> Option<T> {
+        match &self.entry {
+            Some((range, value)) if self.generation == generation && range.contains(&addr) => {
+                Some(value.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, generation: u64, entry: &(Range<u64>, T)) {
+        self.generation = generation;
+        self.entry = Some(entry.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_wins() {
+        let mut index = RangeIndex::new();
+        index.insert(0x10..0x20, 1).unwrap();
+        index.insert(0x30..0x40, 2).unwrap();
+        // Overlapping the start, the end, or both existing ranges: handed back.
+        assert_eq!(index.insert(0x18..0x28, 3), Err((0x18..0x28, 3)));
+        assert_eq!(index.insert(0x28..0x38, 4), Err((0x28..0x38, 4)));
+        assert_eq!(index.insert(0x00..0x50, 5), Err((0x00..0x50, 5)));
+        assert_eq!(index.insert(0x24..0x24, 6), Err((0x24..0x24, 6)));
+        assert_eq!(index.find(0x18), Some(&1));
+        assert_eq!(index.find(0x30), Some(&2));
+        assert_eq!(index.find(0x20), None);
+        // Adjacent ranges do not overlap.
+        index.insert(0x20..0x30, 7).unwrap();
+        assert_eq!(index.find(0x2f), Some(&7));
+        assert_eq!(index.overlapping(&(0x3f..0x41)), Some(&(0x30..0x40)));
+        assert_eq!(index.overlapping(&(0x40..0x41)), None);
+    }
+
+    #[test]
+    fn test_last_hit() {
+        let mut index = RangeIndex::new();
+        index.insert(0x10..0x20, 1).unwrap();
+        let mut last_hit = LastHit::new();
+        last_hit.set(1, index.find_entry(0x10).unwrap());
+        assert_eq!(last_hit.get(1, 0x1f), Some(1));
+        assert_eq!(last_hit.get(1, 0x20), None);
+        // Stale once the index changed.
+        assert_eq!(last_hit.get(2, 0x10), None);
+    }
+}
@@ -0,0 +1,295 @@
+//! Emulated devices of riscv64 guests: a PLIC at the address of the QEMU `virt` machine, and the
+//! console and timer SBI calls.
+//!
+//! Guest page faults on a device are reported with the transformed instruction, which gives the
+//! access directly, so the devices are reached through [`handle_mmio`](super::handle_mmio) with
+//! the decoded access. SBI calls go through [`handle_sbi`], to the per-vCPU devices first, as
+//! exits do on x86_64.
+
+mod plic;
+mod sbi;
+
+use alloc::sync::Arc;
+use core::arch::asm;
+
+use axconfig::SMP;
+use hypercraft::{HyperError, HyperResult};
+use lock_stat::Mutex;
+
+use super::console_backend::{DefaultConsoleBackend, VirtualConsoleBackend};
+use super::mmio::{MmioAccess, MmioDevices, MmioVcpuDevices, MmioVmDevices};
+pub use plic::{supervisor_context, Plic, PLIC_SOURCES};
+use sbi::*;
+pub use sbi::{SbiCall, SbiRet};
+
+/// Base of the PLIC.
+pub const PLIC_BASE: u64 = 0x0c00_0000;
+
+/// Bits of the virtual supervisor interrupts in `hvip`.
+const HVIP_VSTIP: usize = 1 << 6;
+const HVIP_VSEIP: usize = 1 << 10;
+
+/// The virtual interrupts to assert to a vCPU at the next VM entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VirtualInterrupts {
+    pub timer: bool,
+    pub external: bool,
+}
+
+impl VirtualInterrupts {
+    /// Reflect the interrupts in `hvip` of the current hart, which must be the one of the vCPU.
+    pub fn apply(self) {
+        let mut set = 0;
+        if self.timer {
+            set |= HVIP_VSTIP;
+        }
+        if self.external {
+            set |= HVIP_VSEIP;
+        }
+        let clear = (HVIP_VSTIP | HVIP_VSEIP) & !set;
+        // hvip is CSR 0x645.
+        unsafe {
+            asm!("csrs 0x645, {}", in(reg) set, options(nomem, nostack));
+            asm!("csrc 0x645, {}", in(reg) clear, options(nomem, nostack));
+        }
+    }
+}
+
+/// The devices of one vCPU: its SBI timer.
+pub struct Riscv64VcpuDevices {
+    hart_id: usize,
+    /// Deadline set by the guest, in `time` ticks.
+    timer_deadline: Option<u64>,
+}
+
+impl MmioVcpuDevices for Riscv64VcpuDevices {
+    type VmDevices = Riscv64VmDevices;
+
+    /// The devices of vCPU `vcpu_id`, whose supervisor mode PLIC context is the one of hart
+    /// `vcpu_id`. Fails with `InvalidParam` if the PLIC has no context for it.
+    fn new(vcpu_id: usize, vm_devices: &Riscv64VmDevices) -> HyperResult<Self> {
+        if vcpu_id >= SMP {
+            warn!(
+                "VM [{}] vCPU {}: the PLIC has contexts for {} harts",
+                vm_devices.vm_id, vcpu_id, SMP
+            );
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(Self {
+            hart_id: vcpu_id,
+            timer_deadline: None,
+        })
+    }
+
+    /// The PLIC is not banked, all the devices are per-VM ones.
+    fn handle_mmio(&mut self, _access: MmioAccess) -> Option<HyperResult<u64>> {
+        None
+    }
+}
+
+impl Riscv64VcpuDevices {
+    /// Handle the timer SBI calls, `None` for the other calls.
+    ///
+    /// With the Sstc extension, guests write `vstimecmp` directly and the timer interrupt is
+    /// delivered by the hart without exiting.
+    pub fn handle_sbi(&mut self, call: SbiCall) -> Option<SbiRet> {
+        match (call.extension, call.function) {
+            (EID_LEGACY_SET_TIMER, _) => {
+                self.set_timer(call.args[0] as u64);
+                Some(SbiRet::legacy(0))
+            }
+            (EID_TIME, FID_TIME_SET_TIMER) => {
+                self.set_timer(call.args[0] as u64);
+                Some(SbiRet::success(0))
+            }
+            _ => None,
+        }
+    }
+
+    /// Setting the timer clears the pending timer interrupt until the new deadline.
+    fn set_timer(&mut self, deadline: u64) {
+        trace!("vCPU {} SBI set_timer({:#x})", self.hart_id, deadline);
+        self.timer_deadline = Some(deadline);
+    }
+
+    /// The virtual interrupts of the vCPU, computed before each VM entry.
+    ///
+    /// The host timer is not armed for the guest deadline: as for x86_64 guests without the
+    /// preemption timer, the exits forced by host interrupts bound the timer latency.
+    pub fn check_events(&mut self, vm_devices: &Riscv64VmDevices) -> VirtualInterrupts {
+        self.interrupts_at(vm_devices, axhal::time::current_ticks())
+    }
+
+    /// The virtual interrupts of the vCPU at `now`, in `time` ticks.
+    fn interrupts_at(&self, vm_devices: &Riscv64VmDevices, now: u64) -> VirtualInterrupts {
+        VirtualInterrupts {
+            timer: self
+                .timer_deadline
+                .map_or(false, |deadline| now >= deadline),
+            external: vm_devices
+                .plic
+                .lock()
+                .context_pending(supervisor_context(self.hart_id)),
+        }
+    }
+}
+
+/// The devices shared by all vCPUs of a VM: the PLIC and the SBI console.
+pub struct Riscv64VmDevices {
+    vm_id: u32,
+    plic: Arc<Mutex<Plic>>,
+    console: Mutex<DefaultConsoleBackend>,
+    devices: MmioDevices,
+}
+
+impl MmioVmDevices for Riscv64VmDevices {
+    fn new(vm_id: u32) -> HyperResult<Self> {
+        let plic = Arc::new(Mutex::new(Plic::new(PLIC_BASE)));
+        let mut devices = MmioDevices::new();
+        devices.add(plic.clone())?;
+        Ok(Self {
+            vm_id,
+            plic,
+            console: Mutex::new(DefaultConsoleBackend::new()),
+            devices,
+        })
+    }
+
+    fn handle_mmio(&self, access: MmioAccess) -> Option<HyperResult<u64>> {
+        self.devices.handle_mmio(access)
+    }
+}
+
+impl Riscv64VmDevices {
+    pub fn plic(&self) -> Arc<Mutex<Plic>> {
+        self.plic.clone()
+    }
+
+    /// Handle the console SBI calls, and fail the calls left by the per-vCPU devices.
+    pub fn handle_sbi(&self, call: SbiCall) -> SbiRet {
+        match (call.extension, call.function) {
+            (EID_LEGACY_CONSOLE_PUTCHAR, _) => {
+                self.console.lock().putchar(call.args[0] as u8);
+                SbiRet::legacy(0)
+            }
+            (EID_LEGACY_CONSOLE_GETCHAR, _) => {
+                let c = self.console.lock().getchar();
+                SbiRet::legacy(c.map_or(-1, |c| c as isize))
+            }
+            (EID_DBCN, FID_DBCN_CONSOLE_WRITE_BYTE) => {
+                self.console.lock().putchar(call.args[0] as u8);
+                SbiRet::success(0)
+            }
+            _ => {
+                warn!(
+                    "VM [{}] unsupported SBI call {:#x}:{:#x}",
+                    self.vm_id, call.extension, call.function
+                );
+                SbiRet::not_supported()
+            }
+        }
+    }
+}
+
+/// Handle the `ecall` `call` of a vCPU, with its devices then the devices of its VM, and return
+/// the result to write to its `a0` and `a1`.
+pub fn handle_sbi(
+    vcpu_devices: &mut Riscv64VcpuDevices,
+    vm_devices: &Riscv64VmDevices,
+    call: SbiCall,
+) -> SbiRet {
+    vcpu_devices
+        .handle_sbi(call)
+        .unwrap_or_else(|| vm_devices.handle_sbi(call))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::handle_mmio;
+
+    fn call(extension: usize, function: usize, a0: usize) -> SbiCall {
+        SbiCall {
+            extension,
+            function,
+            args: [a0, 0, 0, 0, 0, 0],
+        }
+    }
+
+    fn devices() -> (Riscv64VcpuDevices, Riscv64VmDevices) {
+        let vm = Riscv64VmDevices::new(0).unwrap();
+        (Riscv64VcpuDevices::new(0, &vm).unwrap(), vm)
+    }
+
+    #[test]
+    fn test_timer() {
+        let (mut vcpu, vm) = devices();
+        assert!(Riscv64VcpuDevices::new(SMP, &vm).is_err());
+        assert_eq!(
+            vcpu.interrupts_at(&vm, u64::MAX),
+            VirtualInterrupts::default()
+        );
+
+        let ret = handle_sbi(&mut vcpu, &vm, call(EID_LEGACY_SET_TIMER, 0, 100));
+        assert_eq!(ret, SbiRet::legacy(0));
+        assert!(!vcpu.interrupts_at(&vm, 99).timer);
+        assert!(vcpu.interrupts_at(&vm, 100).timer);
+        // A new deadline clears the pending timer interrupt until it is reached.
+        let ret = handle_sbi(&mut vcpu, &vm, call(EID_TIME, FID_TIME_SET_TIMER, 200));
+        assert_eq!(ret, SbiRet::success(0));
+        assert!(!vcpu.interrupts_at(&vm, 150).timer);
+        assert!(vcpu.interrupts_at(&vm, 200).timer);
+    }
+
+    #[test]
+    fn test_console() {
+        let (mut vcpu, vm) = devices();
+        let ret = handle_sbi(
+            &mut vcpu,
+            &vm,
+            call(EID_LEGACY_CONSOLE_PUTCHAR, 0, b'x' as usize),
+        );
+        assert_eq!(ret, SbiRet::legacy(0));
+        let ret = handle_sbi(
+            &mut vcpu,
+            &vm,
+            call(EID_DBCN, FID_DBCN_CONSOLE_WRITE_BYTE, b'x' as usize),
+        );
+        assert_eq!(ret, SbiRet::success(0));
+        // No input, and the other calls are not supported.
+        let ret = handle_sbi(&mut vcpu, &vm, call(EID_LEGACY_CONSOLE_GETCHAR, 0, 0));
+        assert_eq!(ret, SbiRet::legacy(-1));
+        let ret = handle_sbi(&mut vcpu, &vm, call(EID_DBCN, 0, 0));
+        assert_eq!(ret, SbiRet::not_supported());
+    }
+
+    #[test]
+    fn test_external() {
+        let (mut vcpu, vm) = devices();
+        let write = |addr, value| MmioAccess {
+            addr: PLIC_BASE + addr,
+            access_size: 4,
+            write: Some(value),
+        };
+        let enable = 0x2000 + 0x80 * supervisor_context(0) as u64;
+        for access in [write(4 * 10, 1), write(enable, 1 << 10)] {
+            handle_mmio(&mut vcpu, &vm, access).unwrap();
+        }
+        vm.plic().lock().set_pending(10);
+        assert!(vcpu.interrupts_at(&vm, 0).external);
+        let claim = MmioAccess {
+            write: None,
+            ..write(0x20_0004 + 0x1000 * supervisor_context(0) as u64, 0)
+        };
+        assert_eq!(handle_mmio(&mut vcpu, &vm, claim).unwrap(), 10);
+        assert!(!vcpu.interrupts_at(&vm, 0).external);
+        let outside = MmioAccess {
+            write: None,
+            ..write(0x60_0000, 0)
+        };
+        assert!(matches!(
+            handle_mmio(&mut vcpu, &vm, outside),
+            Err(HyperError::InValidMmioRead)
+        ));
+    }
+}
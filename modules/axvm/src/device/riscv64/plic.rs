@@ -0,0 +1,318 @@
+//! Emulated PLIC. (ref: RISC-V Platform-Level Interrupt Controller Specification 1.0.0)
+//!
+//! The layout is the one of the QEMU `virt` machine: two contexts per hart, the machine mode one
+//! and the supervisor mode one, of which only the latter is delivered to the guest, as its
+//! virtual supervisor external interrupt.
+
+use core::ops::Range;
+
+use axconfig::SMP;
+use hypercraft::{HyperError, HyperResult, MmioOps};
+
+/// Interrupt sources, source 0 meaning "no interrupt".
+pub const PLIC_SOURCES: usize = 128;
+const SOURCE_WORDS: usize = PLIC_SOURCES / 32;
+/// Contexts, two per hart.
+const PLIC_CONTEXTS: usize = 2 * SMP;
+
+const PLIC_SIZE: u64 = 0x60_0000;
+const PRIORITY: Range<u64> = 0x0..0x4 * PLIC_SOURCES as u64;
+const PENDING: Range<u64> = 0x1000..0x1000 + 4 * SOURCE_WORDS as u64;
+const ENABLE_BASE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT_BASE: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const CONTEXT_THRESHOLD: u64 = 0x0;
+const CONTEXT_CLAIM: u64 = 0x4;
+
+/// Priorities are 3 bits wide, as on QEMU.
+const PRIORITY_MASK: u32 = 0x7;
+
+fn bit(source: usize) -> (usize, u32) {
+    (source / 32, 1 << (source % 32))
+}
+
+/// The context of the supervisor mode of `hart`.
+pub const fn supervisor_context(hart: usize) -> usize {
+    2 * hart + 1
+}
+
+pub struct Plic {
+    base: u64,
+    priority: [u32; PLIC_SOURCES],
+    pending: [u32; SOURCE_WORDS],
+    /// Claimed and not yet completed, they are not signaled again until completion.
+    claimed: [u32; SOURCE_WORDS],
+    enable: [[u32; SOURCE_WORDS]; PLIC_CONTEXTS],
+    threshold: [u32; PLIC_CONTEXTS],
+}
+
+impl Plic {
+    pub fn new(base: u64) -> Self {
+        Self {
+            base,
+            priority: [0; PLIC_SOURCES],
+            pending: [0; SOURCE_WORDS],
+            claimed: [0; SOURCE_WORDS],
+            enable: [[0; SOURCE_WORDS]; PLIC_CONTEXTS],
+            threshold: [0; PLIC_CONTEXTS],
+        }
+    }
+
+    fn valid_source(source: usize) -> bool {
+        (1..PLIC_SOURCES).contains(&source)
+    }
+
+    /// Make `source` pending, as if its device signaled an edge.
+    pub fn set_pending(&mut self, source: usize) {
+        if !Self::valid_source(source) {
+            warn!("PLIC: cannot raise source {}", source);
+            return;
+        }
+        let (word, mask) = bit(source);
+        self.pending[word] |= mask;
+    }
+
+    /// Drive the level-sensitive line of `source`: while it is high and the source is not being
+    /// handled, it stays pending.
+    pub fn set_level(&mut self, source: usize, level: bool) {
+        if !Self::valid_source(source) {
+            warn!("PLIC: cannot drive source {}", source);
+            return;
+        }
+        let (word, mask) = bit(source);
+        if !level {
+            self.pending[word] &= !mask;
+        } else if self.claimed[word] & mask == 0 {
+            self.pending[word] |= mask;
+        }
+    }
+
+    /// The pending source of the highest priority above the threshold of `context`.
+    fn best_source(&self, context: usize) -> Option<usize> {
+        (1..PLIC_SOURCES)
+            .filter(|&source| {
+                let (word, mask) = bit(source);
+                self.pending[word] & self.enable[context][word] & !self.claimed[word] & mask != 0
+                    && self.priority[source] > self.threshold[context]
+            })
+            // Ties go to the lowest source.
+            .max_by_key(|&source| (self.priority[source], core::cmp::Reverse(source)))
+    }
+
+    /// Whether `context` signals an external interrupt to its hart.
+    pub fn context_pending(&self, context: usize) -> bool {
+        context < PLIC_CONTEXTS && self.best_source(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best_source(context) {
+            Some(source) => {
+                let (word, mask) = bit(source);
+                self.pending[word] &= !mask;
+                self.claimed[word] |= mask;
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: u32) {
+        let source = source as usize;
+        if !Self::valid_source(source) {
+            return;
+        }
+        let (word, mask) = bit(source);
+        // Completions of sources not enabled for the context are ignored.
+        if self.enable[context][word] & mask != 0 {
+            self.claimed[word] &= !mask;
+        }
+    }
+
+    /// The context and the register offset in it of a context register.
+    fn context_register(offset: u64) -> Option<(usize, u64)> {
+        let context = ((offset - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+        (context < PLIC_CONTEXTS).then_some((context, (offset - CONTEXT_BASE) % CONTEXT_STRIDE))
+    }
+
+    /// The context and the word of an enable register.
+    fn enable_register(offset: u64) -> Option<(usize, usize)> {
+        let context = ((offset - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+        let word = ((offset - ENABLE_BASE) % ENABLE_STRIDE / 4) as usize;
+        (context < PLIC_CONTEXTS && word < SOURCE_WORDS).then_some((context, word))
+    }
+}
+
+impl MmioOps for Plic {
+    fn mmio_range(&self) -> Range<u64> {
+        self.base..self.base + PLIC_SIZE
+    }
+
+    fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+        if access_size != 4 || addr % 4 != 0 {
+            warn!("Invalid PLIC access size {} @ {:#x}", access_size, addr);
+            return Err(HyperError::InvalidParam);
+        }
+        let offset = addr - self.base;
+        let value = match offset {
+            o if PRIORITY.contains(&o) => self.priority[(o / 4) as usize],
+            o if PENDING.contains(&o) => self.pending[((o - PENDING.start) / 4) as usize],
+            o if (ENABLE_BASE..CONTEXT_BASE).contains(&o) => match Self::enable_register(o) {
+                Some((context, word)) => self.enable[context][word],
+                None => 0,
+            },
+            o if o >= CONTEXT_BASE => match Self::context_register(o) {
+                Some((context, CONTEXT_THRESHOLD)) => self.threshold[context],
+                Some((context, CONTEXT_CLAIM)) => self.claim(context),
+                _ => 0,
+            },
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+        if access_size != 4 || addr % 4 != 0 {
+            warn!("Invalid PLIC access size {} @ {:#x}", access_size, addr);
+            return Err(HyperError::InvalidParam);
+        }
+        let offset = addr - self.base;
+        let value = value as u32;
+        match offset {
+            // Source 0 does not exist, its priority is hardwired to 0.
+            o if PRIORITY.contains(&o) && o != 0 => {
+                self.priority[(o / 4) as usize] = value & PRIORITY_MASK
+            }
+            o if (ENABLE_BASE..CONTEXT_BASE).contains(&o) => {
+                if let Some((context, word)) = Self::enable_register(o) {
+                    // Source 0 cannot be enabled.
+                    let value = if word == 0 { value & !1 } else { value };
+                    self.enable[context][word] = value;
+                }
+            }
+            o if o >= CONTEXT_BASE => match Self::context_register(o) {
+                Some((context, CONTEXT_THRESHOLD)) => {
+                    self.threshold[context] = value & PRIORITY_MASK
+                }
+                Some((context, CONTEXT_CLAIM)) => self.complete(context, value),
+                _ => {}
+            },
+            // The pending bits are read-only.
+            _ => debug!(
+                "PLIC: write of read-only register {:#x}: {:#x}",
+                offset, value
+            ),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x0c00_0000;
+    /// The supervisor context of hart 0.
+    const CONTEXT: usize = supervisor_context(0);
+
+    fn write(plic: &mut Plic, offset: u64, value: u32) {
+        plic.write(BASE + offset, 4, value as u64).unwrap();
+    }
+
+    fn read(plic: &mut Plic, offset: u64) -> u32 {
+        plic.read(BASE + offset, 4).unwrap() as u32
+    }
+
+    fn claim_offset(context: usize) -> u64 {
+        CONTEXT_BASE + CONTEXT_STRIDE * context as u64 + CONTEXT_CLAIM
+    }
+
+    /// A PLIC with `sources` enabled for [`CONTEXT`], with `priority`.
+    fn enabled(sources: &[usize], priority: u32) -> Plic {
+        let mut plic = Plic::new(BASE);
+        let mut enable = 0;
+        for &source in sources {
+            write(&mut plic, 4 * source as u64, priority);
+            enable |= 1 << source;
+        }
+        write(
+            &mut plic,
+            ENABLE_BASE + ENABLE_STRIDE * CONTEXT as u64,
+            enable,
+        );
+        plic
+    }
+
+    #[test]
+    fn test_claim_complete() {
+        let mut plic = enabled(&[5], 3);
+        assert!(!plic.context_pending(CONTEXT));
+        plic.set_pending(5);
+        assert!(plic.context_pending(CONTEXT));
+        assert!(!plic.context_pending(supervisor_context(1)));
+        assert_eq!(read(&mut plic, PENDING.start), 1 << 5);
+
+        assert_eq!(read(&mut plic, claim_offset(CONTEXT)), 5);
+        assert_eq!(read(&mut plic, PENDING.start), 0);
+        assert_eq!(read(&mut plic, claim_offset(CONTEXT)), 0);
+        // Raised again while claimed, it is only signaled once completed.
+        plic.set_pending(5);
+        assert!(!plic.context_pending(CONTEXT));
+        write(&mut plic, claim_offset(CONTEXT), 5);
+        assert!(plic.context_pending(CONTEXT));
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut plic = enabled(&[3, 7, 9], 2);
+        write(&mut plic, 4 * 9, 5);
+        for source in [3, 7, 9] {
+            plic.set_pending(source);
+        }
+        // The highest priority first, then the lowest source of the ties.
+        assert_eq!(read(&mut plic, claim_offset(CONTEXT)), 9);
+        assert_eq!(read(&mut plic, claim_offset(CONTEXT)), 3);
+        // At the threshold, a source is masked.
+        let threshold = CONTEXT_BASE + CONTEXT_STRIDE * CONTEXT as u64 + CONTEXT_THRESHOLD;
+        write(&mut plic, threshold, 2);
+        assert_eq!(read(&mut plic, threshold), 2);
+        assert!(!plic.context_pending(CONTEXT));
+        write(&mut plic, threshold, 1);
+        assert_eq!(read(&mut plic, claim_offset(CONTEXT)), 7);
+    }
+
+    #[test]
+    fn test_level() {
+        let mut plic = enabled(&[10], 1);
+        plic.set_level(10, true);
+        assert_eq!(read(&mut plic, claim_offset(CONTEXT)), 10);
+        // Still high while claimed, the source is pending again once completed.
+        plic.set_level(10, true);
+        assert!(!plic.context_pending(CONTEXT));
+        write(&mut plic, claim_offset(CONTEXT), 10);
+        plic.set_level(10, true);
+        assert!(plic.context_pending(CONTEXT));
+        plic.set_level(10, false);
+        assert!(!plic.context_pending(CONTEXT));
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut plic = Plic::new(BASE);
+        // Source 0 has no priority and cannot be enabled, the priorities are 3 bits wide.
+        write(&mut plic, 0, 7);
+        assert_eq!(read(&mut plic, 0), 0);
+        write(&mut plic, 4, 0xff);
+        assert_eq!(read(&mut plic, 4), PRIORITY_MASK);
+        let enable = ENABLE_BASE + ENABLE_STRIDE * CONTEXT as u64;
+        write(&mut plic, enable, u32::MAX);
+        assert_eq!(read(&mut plic, enable), u32::MAX - 1);
+        // The pending bits are read-only.
+        write(&mut plic, PENDING.start, u32::MAX);
+        assert_eq!(read(&mut plic, PENDING.start), 0);
+        // Past the last context.
+        assert_eq!(read(&mut plic, claim_offset(PLIC_CONTEXTS)), 0);
+        assert!(plic.read(BASE + 2, 4).is_err());
+        assert!(plic.read(BASE, 1).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+//! The SBI calls emulated by the hypervisor devices: the console and the timer.
+//! (ref: RISC-V Supervisor Binary Interface Specification 2.0)
+
+/// Legacy extensions, with the function in the extension ID and the result in `a0` only.
+pub const EID_LEGACY_SET_TIMER: usize = 0x00;
+pub const EID_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+pub const EID_LEGACY_CONSOLE_GETCHAR: usize = 0x02;
+/// Timer extension, "TIME".
+pub const EID_TIME: usize = 0x5449_4d45;
+pub const FID_TIME_SET_TIMER: usize = 0;
+/// Debug console extension, "DBCN".
+pub const EID_DBCN: usize = 0x4442_434e;
+pub const FID_DBCN_CONSOLE_WRITE_BYTE: usize = 2;
+
+pub const SBI_SUCCESS: isize = 0;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+
+/// An `ecall` of the guest: `a7`, `a6` and `a0` to `a5`.
+#[derive(Debug, Clone, Copy)]
+pub struct SbiCall {
+    pub extension: usize,
+    pub function: usize,
+    pub args: [usize; 6],
+}
+
+/// The result of an SBI call, returned to the guest in `a0` and `a1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
+impl SbiRet {
+    pub const fn success(value: usize) -> Self {
+        Self {
+            error: SBI_SUCCESS,
+            value,
+        }
+    }
+
+    pub const fn not_supported() -> Self {
+        Self {
+            error: SBI_ERR_NOT_SUPPORTED,
+            value: 0,
+        }
+    }
+
+    /// The result of a legacy call, `a0` being its only return value.
+    pub const fn legacy(a0: isize) -> Self {
+        Self {
+            error: a0,
+            value: 0,
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! Saving and restoring the state of the emulated devices.
+//!
+//! The state of a device is encoded as a 16-bit version followed by the length of the payload
+//! and the payload, all little-endian:
+//!
+//! ```text
+//! | version: u16 | len: u32 | payload: len bytes |
+//! ```
+//!
+//! A device bumps its version whenever it changes its payload, so that a state saved by another
+//! build is rejected instead of being misread. Nested states, e.g. the devices of a
+//! [`DeviceList`](super::DeviceList), are written as length-prefixed byte strings.
+//!
+//! Timestamps are saved relative to the time of the save and rebased on restore, the host clock
+//! of the restoring side being unrelated.
+
+use alloc::vec::Vec;
+
+use crate::{Error as HyperError, Result as HyperResult};
+
+const HEADER_LEN: usize = 6;
+
+/// A device whose state can be extracted and reinstated, for snapshots, resets and device
+/// replacement.
+///
+/// The state only covers what the guest can observe: the backend of a device (the host console,
+/// the host clock) is not part of it.
+pub trait DeviceState {
+    /// The state of the device, see [the module documentation](self) for the encoding.
+    fn save(&self) -> Vec<u8>;
+
+    /// Reinstate a state returned by [`DeviceState::save`]. The device is left unchanged if the
+    /// state is rejected.
+    fn restore(&mut self, state: &[u8]) -> HyperResult<()>;
+}
+
+/// Builds an encoded state.
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(version: u16) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&version.to_le_bytes());
+        // Length of the payload, filled in by `finish`.
+        buf.extend_from_slice(&[0; 4]);
+        Self { buf }
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub fn bool(&mut self, value: bool) -> &mut Self {
+        self.u8(value as u8)
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// A byte string, prefixed by its length.
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.u32(bytes.len() as u32);
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    /// Timestamp `ns`, 0 meaning unset, saved as its distance to `now_ns`.
+    pub fn timestamp(&mut self, ns: u64, now_ns: u64) -> &mut Self {
+        match ns {
+            0 => self.u8(0).u64(0),
+            ns if ns >= now_ns => self.u8(1).u64(ns - now_ns),
+            ns => self.u8(2).u64(now_ns - ns),
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let len = (self.buf.len() - HEADER_LEN) as u32;
+        self.buf[2..HEADER_LEN].copy_from_slice(&len.to_le_bytes());
+        self.buf
+    }
+}
+
+/// Reads an encoded state, failing with [`HyperError::InvalidParam`] on truncated or trailing
+/// data.
+pub struct StateReader<'a> {
+    payload: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Check the header of `state`, which must be at `version`.
+    pub fn new(state: &'a [u8], version: u16) -> HyperResult<Self> {
+        if state.len() < HEADER_LEN {
+            return Err(HyperError::InvalidParam);
+        }
+        let saved_version = u16::from_le_bytes([state[0], state[1]]);
+        if saved_version != version {
+            warn!(
+                "device state version {} while {} is expected",
+                saved_version, version
+            );
+            return Err(HyperError::NotSupported);
+        }
+        let len = u32::from_le_bytes([state[2], state[3], state[4], state[5]]) as usize;
+        if state.len() - HEADER_LEN != len {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(Self {
+            payload: &state[HEADER_LEN..],
+        })
+    }
+
+    fn take(&mut self, len: usize) -> HyperResult<&'a [u8]> {
+        if self.payload.len() < len {
+            return Err(HyperError::InvalidParam);
+        }
+        let (taken, rest) = self.payload.split_at(len);
+        self.payload = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> HyperResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> HyperResult<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    pub fn u16(&mut self) -> HyperResult<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> HyperResult<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> HyperResult<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bytes(&mut self) -> HyperResult<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /// A timestamp written by [`StateWriter::timestamp`], rebased on `now_ns`.
+    pub fn timestamp(&mut self, now_ns: u64) -> HyperResult<u64> {
+        let kind = self.u8()?;
+        let distance = self.u64()?;
+        match kind {
+            0 => Ok(0),
+            // Past the end of the clock, the state is corrupt.
+            1 => now_ns.checked_add(distance).ok_or(HyperError::InvalidParam),
+            // Before the boot of this host, the earliest time will do.
+            2 => Ok(now_ns.saturating_sub(distance).max(1)),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    /// Check that the whole payload was read.
+    pub fn finish(self) -> HyperResult<()> {
+        if self.payload.is_empty() {
+            Ok(())
+        } else {
+            Err(HyperError::InvalidParam)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new(3);
+        writer
+            .u8(0xab)
+            .bool(true)
+            .u16(0x1234)
+            .u32(0xdead_beef)
+            .u64(u64::MAX - 1)
+            .bytes(b"nested")
+            .bytes(&[]);
+        let state = writer.finish();
+        assert_eq!(state[..2], [3, 0]);
+        assert_eq!(
+            state[2..HEADER_LEN],
+            ((state.len() - HEADER_LEN) as u32).to_le_bytes()
+        );
+
+        let mut reader = StateReader::new(&state, 3).unwrap();
+        assert_eq!(reader.u8().unwrap(), 0xab);
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.u16().unwrap(), 0x1234);
+        assert_eq!(reader.u32().unwrap(), 0xdead_beef);
+        assert_eq!(reader.u64().unwrap(), u64::MAX - 1);
+        assert_eq!(reader.bytes().unwrap(), b"nested");
+        assert_eq!(reader.bytes().unwrap(), b"");
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_rejected_states() {
+        let mut writer = StateWriter::new(1);
+        writer.u32(7).u8(2);
+        let state = writer.finish();
+
+        assert!(matches!(
+            StateReader::new(&state, 2),
+            Err(HyperError::NotSupported)
+        ));
+        assert!(StateReader::new(&state[..HEADER_LEN - 1], 1).is_err());
+        // The length of the payload does not match.
+        assert!(StateReader::new(&state[..state.len() - 1], 1).is_err());
+        let mut longer = state.clone();
+        longer.push(0);
+        assert!(StateReader::new(&longer, 1).is_err());
+
+        // Trailing, truncated and invalid values.
+        assert!(StateReader::new(&state, 1).unwrap().finish().is_err());
+        assert!(StateReader::new(&state, 1).unwrap().u64().is_err());
+        let mut reader = StateReader::new(&state, 1).unwrap();
+        reader.u32().unwrap();
+        assert!(reader.bool().is_err());
+
+        // A byte string longer than the payload.
+        let mut writer = StateWriter::new(1);
+        writer.u32(100).u8(0);
+        let state = writer.finish();
+        assert!(StateReader::new(&state, 1).unwrap().bytes().is_err());
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let save_ns = 1_000_000;
+        let mut writer = StateWriter::new(1);
+        writer
+            .timestamp(0, save_ns)
+            .timestamp(save_ns + 500, save_ns)
+            .timestamp(save_ns, save_ns)
+            .timestamp(save_ns - 300, save_ns)
+            .timestamp(1, save_ns);
+        let state = writer.finish();
+
+        // Rebased on the clock of the restoring side, unset staying unset.
+        let restore_ns = 5_000;
+        let mut reader = StateReader::new(&state, 1).unwrap();
+        assert_eq!(reader.timestamp(restore_ns).unwrap(), 0);
+        assert_eq!(reader.timestamp(restore_ns).unwrap(), restore_ns + 500);
+        assert_eq!(reader.timestamp(restore_ns).unwrap(), restore_ns);
+        assert_eq!(reader.timestamp(restore_ns).unwrap(), restore_ns - 300);
+        // Before the boot of the restoring host.
+        assert_eq!(reader.timestamp(restore_ns).unwrap(), 1);
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_timestamps() {
+        // A deadline past the end of the clock.
+        let mut writer = StateWriter::new(1);
+        writer.u8(1).u64(u64::MAX);
+        let state = writer.finish();
+        let mut reader = StateReader::new(&state, 1).unwrap();
+        assert!(matches!(reader.timestamp(1), Err(HyperError::InvalidParam)));
+        assert_eq!(
+            StateReader::new(&state, 1).unwrap().timestamp(0).unwrap(),
+            u64::MAX
+        );
+
+        let mut writer = StateWriter::new(1);
+        writer.u8(3).u64(0);
+        let state = writer.finish();
+        assert!(StateReader::new(&state, 1).unwrap().timestamp(0).is_err());
+    }
+}
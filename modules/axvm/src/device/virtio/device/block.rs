@@ -0,0 +1,527 @@
+//! Virtio block device, serving the requests of the driver from a [`BlockBackend`].
+//!
+//! The device has one request queue. A notification pops the available requests and decodes
+//! them on the vCPU, copying the data of the writes out of the guest; the backend does the I/O
+//! on the completion worker, which then copies the data of the reads to the guest, writes the
+//! status bytes, returns the requests in the used ring and raises the MSI-X interrupt of the
+//! queue, see [`crate::completion`].
+//!
+//! A request the device cannot serve, e.g. out of the disk or malformed, completes with
+//! `VIRTIO_BLK_S_IOERR`, an unknown request type with `VIRTIO_BLK_S_UNSUPP`.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cmp::min;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use hypercraft::{HyperError, HyperResult as Result};
+use lock_stat::Mutex;
+use pci::util::byte_code::ByteCode;
+use pci::AsAny;
+
+use crate::completion::DeferredOp;
+use crate::device::block_backend::BlockBackend;
+use crate::device::virtio::{
+    read_config_default, report_virtio_error, ElemIovec, Element, Queue, VirtioBase, VirtioDevice,
+    VirtioInterrupt, VirtioInterruptType, VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_FLUSH,
+    VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR,
+    VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1, VIRTIO_TYPE_BLOCK,
+};
+use crate::mm::GuestRam;
+
+/// The unit of the sector numbers of the requests.
+pub const SECTOR_SHIFT: u32 = 9;
+pub const SECTOR_SIZE: u64 = 1 << SECTOR_SHIFT;
+/// The device has a single request queue.
+const QUEUE_NUM_BLK: usize = 1;
+
+/// Header of a request, in the first device-readable bytes of its descriptor chain.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct RequestHeader {
+    req_type: u32,
+    _reserved: u32,
+    sector: u64,
+}
+
+impl ByteCode for RequestHeader {}
+
+/// Configuration space of the device, up to `num_queues`, refer to Virtio Spec.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtioBlkConfig {
+    /// Size of the disk in sectors.
+    pub capacity: u64,
+    pub size_max: u32,
+    /// Most data segments of a request.
+    pub seg_max: u32,
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+    pub blk_size: u32,
+    pub physical_block_exp: u8,
+    pub alignment_offset: u8,
+    pub min_io_size: u16,
+    pub opt_io_size: u32,
+    pub writeback: u8,
+    _unused0: u8,
+    pub num_queues: u16,
+    _padding: u32,
+}
+
+impl ByteCode for VirtioBlkConfig {}
+
+/// The part of `iovec` after its first `skip` bytes.
+fn iov_skip(iovec: &[ElemIovec], mut skip: u64) -> Vec<ElemIovec> {
+    let mut rest = Vec::new();
+    for iov in iovec {
+        let len = u64::from(iov.len);
+        if skip >= len {
+            skip -= len;
+            continue;
+        }
+        rest.push(ElemIovec {
+            addr: iov.addr + skip,
+            len: (len - skip) as u32,
+        });
+        skip = 0;
+    }
+    rest
+}
+
+/// The first `len` bytes of `iovec`.
+fn iov_take(iovec: &[ElemIovec], mut len: u64) -> Vec<ElemIovec> {
+    let mut head = Vec::new();
+    for iov in iovec {
+        if len == 0 {
+            break;
+        }
+        let taken = min(u64::from(iov.len), len);
+        head.push(ElemIovec {
+            addr: iov.addr,
+            len: taken as u32,
+        });
+        len -= taken;
+    }
+    head
+}
+
+/// Gather the guest buffers of `iovec` into `buf`, returning the number of bytes read.
+pub(super) fn iov_read(mem: &GuestRam, iovec: &[ElemIovec], buf: &mut [u8]) -> Result<usize> {
+    let mut done = 0;
+    for iov in iovec {
+        if done == buf.len() {
+            break;
+        }
+        let len = min(iov.len as usize, buf.len() - done);
+        mem.read(iov.addr, &mut buf[done..done + len])?;
+        done += len;
+    }
+    Ok(done)
+}
+
+/// Scatter `buf` to the guest buffers of `iovec`, returning the number of bytes written.
+pub(super) fn iov_write(mem: &GuestRam, iovec: &[ElemIovec], buf: &[u8]) -> Result<usize> {
+    let mut done = 0;
+    for iov in iovec {
+        if done == buf.len() {
+            break;
+        }
+        let len = min(iov.len as usize, buf.len() - done);
+        mem.write(iov.addr, &buf[done..done + len])?;
+        done += len;
+    }
+    Ok(done)
+}
+
+/// A request decoded from its descriptor chain.
+struct BlkRequest {
+    /// Head of the descriptor chain, returned in the used ring.
+    index: u16,
+    req_type: u32,
+    /// Position on the disk in bytes.
+    offset: u64,
+    /// The data buffers of the driver, device-writable for the reads.
+    data: Vec<ElemIovec>,
+    /// The data of a write copied from the guest, or of a read to copy to the guest.
+    buf: Vec<u8>,
+    /// Guest address of the status byte, `None` if the chain has no room for it.
+    status_addr: Option<u64>,
+    /// The status so far, only a request still `VIRTIO_BLK_S_OK` reaches the backend.
+    status: u8,
+}
+
+impl BlkRequest {
+    fn new(mem: &GuestRam, elem: &Element, disk_len: u64, read_only: bool) -> Self {
+        let mut req = BlkRequest {
+            index: elem.index,
+            req_type: 0,
+            offset: 0,
+            data: Vec::new(),
+            buf: Vec::new(),
+            status_addr: None,
+            status: VIRTIO_BLK_S_IOERR,
+        };
+        // The status is the last byte of the device-writable part of the chain.
+        let in_len = Element::iovec_size(&elem.in_iovec);
+        match elem.in_iovec.last() {
+            Some(last) => req.status_addr = Some(last.addr + u64::from(last.len) - 1),
+            None => {
+                warn!("virtio-blk request {} without status byte", elem.index);
+                return req;
+            }
+        }
+        let mut header = RequestHeader::default();
+        match iov_read(mem, &elem.out_iovec, header.as_mut_bytes()) {
+            Ok(len) if len == header.as_bytes().len() => {}
+            _ => {
+                warn!("virtio-blk request {} without header", elem.index);
+                return req;
+            }
+        }
+        req.req_type = header.req_type;
+        req.status = match header.req_type {
+            VIRTIO_BLK_T_IN => {
+                req.data = iov_take(&elem.in_iovec, in_len - 1);
+                req.check_range(header.sector, in_len - 1, disk_len)
+            }
+            VIRTIO_BLK_T_OUT => {
+                req.data = iov_skip(&elem.out_iovec, header.as_bytes().len() as u64);
+                let len = Element::iovec_size(&req.data);
+                match req.check_range(header.sector, len, disk_len) {
+                    _ if read_only => VIRTIO_BLK_S_IOERR,
+                    VIRTIO_BLK_S_OK => {
+                        // Copied now, the backend runs without access to the guest memory.
+                        req.buf = vec![0; len as usize];
+                        match iov_read(mem, &req.data, &mut req.buf) {
+                            Ok(_) => VIRTIO_BLK_S_OK,
+                            Err(_) => VIRTIO_BLK_S_IOERR,
+                        }
+                    }
+                    status => status,
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => VIRTIO_BLK_S_OK,
+            VIRTIO_BLK_T_GET_ID => {
+                req.data = iov_take(&elem.in_iovec, in_len - 1);
+                VIRTIO_BLK_S_OK
+            }
+            req_type => {
+                debug!("virtio-blk request type {} not supported", req_type);
+                VIRTIO_BLK_S_UNSUPP
+            }
+        };
+        req
+    }
+
+    /// Check the `len` bytes at `sector` against a disk of `disk_len` bytes.
+    fn check_range(&mut self, sector: u64, len: u64, disk_len: u64) -> u8 {
+        let in_disk = sector
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|offset| offset.checked_add(len).map(|end| (offset, end)))
+            .filter(|&(_, end)| end <= disk_len);
+        match in_disk {
+            Some((offset, _)) if len % SECTOR_SIZE == 0 => {
+                self.offset = offset;
+                VIRTIO_BLK_S_OK
+            }
+            _ => {
+                debug!(
+                    "virtio-blk request {} of {} bytes at sector {} out of the disk",
+                    self.index, len, sector
+                );
+                VIRTIO_BLK_S_IOERR
+            }
+        }
+    }
+}
+
+/// The requests popped by a notification, finished on the completion worker.
+struct BlkRequests {
+    requests: Vec<BlkRequest>,
+    backend: Arc<dyn BlockBackend>,
+    serial: [u8; VIRTIO_BLK_ID_BYTES as usize],
+    mem: GuestRam,
+    queue: Arc<Mutex<Queue>>,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    features: u64,
+    broken: Arc<AtomicBool>,
+    /// The activation of the device the requests belong to, see [`VirtioBlk::epoch`].
+    epoch: Arc<AtomicU32>,
+    submitted_epoch: u32,
+}
+
+impl DeferredOp for BlkRequests {
+    fn run(&mut self) -> Result {
+        for req in self.requests.iter_mut() {
+            if req.status != VIRTIO_BLK_S_OK {
+                continue;
+            }
+            let result = match req.req_type {
+                VIRTIO_BLK_T_IN => {
+                    req.buf = vec![0; Element::iovec_size(&req.data) as usize];
+                    self.backend.read_at(req.offset, &mut req.buf)
+                }
+                VIRTIO_BLK_T_OUT => self.backend.write_at(req.offset, &req.buf),
+                VIRTIO_BLK_T_FLUSH => self.backend.flush(),
+                VIRTIO_BLK_T_GET_ID => {
+                    req.buf = self.serial.to_vec();
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+            if let Err(err) = result {
+                debug!("virtio-blk request {} failed: {:?}", req.index, err);
+                req.status = VIRTIO_BLK_S_IOERR;
+            }
+        }
+        Ok(())
+    }
+
+    fn complete(self: Box<Self>, _result: Result) {
+        let BlkRequests {
+            requests,
+            mem,
+            queue,
+            interrupt_cb,
+            features,
+            broken,
+            epoch,
+            submitted_epoch,
+            ..
+        } = *self;
+        let mut queue = queue.lock();
+        // Reset since the requests were popped, the rings may not be the driver's any more.
+        if epoch.load(Ordering::Acquire) != submitted_epoch {
+            debug!(
+                "virtio-blk device reset, {} requests dropped",
+                requests.len()
+            );
+            return;
+        }
+        for mut req in requests {
+            let mut used_len = 0;
+            if req.status == VIRTIO_BLK_S_OK
+                && (req.req_type == VIRTIO_BLK_T_IN || req.req_type == VIRTIO_BLK_T_GET_ID)
+            {
+                match iov_write(&mem, &req.data, &req.buf) {
+                    Ok(len) => used_len = len as u32,
+                    Err(_) => req.status = VIRTIO_BLK_S_IOERR,
+                }
+            }
+            if let Some(status_addr) = req.status_addr {
+                if mem.write_obj(status_addr, &req.status).is_ok() {
+                    used_len += 1;
+                }
+            }
+            if let Err(err) = queue.vring.add_used(&mem, req.index, used_len) {
+                error!(
+                    "virtio-blk failed to return request {}: {:?}",
+                    req.index, err
+                );
+                report_virtio_error(interrupt_cb, features, &broken);
+                return;
+            }
+        }
+        if queue.vring.should_notify(&mem, features) {
+            if let Err(err) = interrupt_cb(&VirtioInterruptType::Vring, Some(&*queue), false) {
+                error!("virtio-blk failed to raise its interrupt: {:?}", err);
+            }
+        }
+    }
+}
+
+/// A virtio block device of a VM.
+pub struct VirtioBlk {
+    base: VirtioBase,
+    vm_id: u32,
+    backend: Arc<dyn BlockBackend>,
+    /// Answer of `VIRTIO_BLK_T_GET_ID`, NUL-padded.
+    serial: [u8; VIRTIO_BLK_ID_BYTES as usize],
+    config: VirtioBlkConfig,
+    /// The RAM of the VM and the interrupt callback, while activated.
+    mem: Option<GuestRam>,
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    /// Bumped on every deactivation, the requests of an earlier activation are not completed.
+    epoch: Arc<AtomicU32>,
+}
+
+impl VirtioBlk {
+    /// A device of VM `vm_id` on `backend`, with a request queue of `queue_size` and `serial`
+    /// as its id, truncated to 20 bytes.
+    pub fn new(vm_id: u32, serial: &str, backend: Arc<dyn BlockBackend>, queue_size: u16) -> Self {
+        let mut id = [0; VIRTIO_BLK_ID_BYTES as usize];
+        let len = min(serial.len(), id.len());
+        id[..len].copy_from_slice(&serial.as_bytes()[..len]);
+        Self {
+            base: VirtioBase::new(VIRTIO_TYPE_BLOCK, QUEUE_NUM_BLK, queue_size),
+            vm_id,
+            backend,
+            serial: id,
+            config: VirtioBlkConfig::default(),
+            mem: None,
+            interrupt_cb: None,
+            epoch: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    /// The name of the device in the logs.
+    fn name(&self) -> String {
+        let len = self
+            .serial
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.serial.len());
+        String::from_utf8_lossy(&self.serial[..len]).into_owned()
+    }
+}
+
+impl AsAny for VirtioBlk {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn virtio_base(&self) -> &VirtioBase {
+        &self.base
+    }
+
+    fn virtio_base_mut(&mut self) -> &mut VirtioBase {
+        &mut self.base
+    }
+
+    fn realize(&mut self) -> Result<()> {
+        self.init_config_features()?;
+        let mode = if self.backend.read_only() { "ro" } else { "rw" };
+        info!(
+            "VM [{}] virtio-blk {}: {} sectors, {}",
+            self.vm_id,
+            self.name(),
+            self.config.capacity,
+            mode
+        );
+        Ok(())
+    }
+
+    fn init_config_features(&mut self) -> Result<()> {
+        let mut features = 1u64 << VIRTIO_F_VERSION_1
+            | 1u64 << VIRTIO_F_RING_EVENT_IDX
+            | 1u64 << VIRTIO_BLK_F_SEG_MAX
+            | 1u64 << VIRTIO_BLK_F_BLK_SIZE
+            | 1u64 << VIRTIO_BLK_F_FLUSH;
+        if self.backend.read_only() {
+            features |= 1u64 << VIRTIO_BLK_F_RO;
+        }
+        self.base.device_features = features;
+
+        self.config.capacity = self.backend.len() >> SECTOR_SHIFT;
+        // The header and the status take a descriptor each.
+        self.config.seg_max = u32::from(self.queue_size_max()).saturating_sub(2).max(1);
+        self.config.blk_size = SECTOR_SIZE as u32;
+        self.config.num_queues = QUEUE_NUM_BLK as u16;
+        Ok(())
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        read_config_default(self.config.as_bytes(), offset, data)
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        // Without VIRTIO_BLK_F_CONFIG_WCE nothing is writable.
+        warn!(
+            "virtio-blk {}: write of {} bytes to config offset {:#x} ignored",
+            self.name(),
+            data.len(),
+            offset
+        );
+        Ok(())
+    }
+
+    fn activate(&mut self, interrupt_cb: Arc<VirtioInterrupt>) -> Result<()> {
+        self.mem = Some(GuestRam::new(self.vm_id)?);
+        self.interrupt_cb = Some(interrupt_cb);
+        Ok(())
+    }
+
+    fn notify_queue(&mut self, queue_index: u16) -> Result<Option<Box<dyn DeferredOp>>> {
+        let (mem, interrupt_cb) = match (&self.mem, &self.interrupt_cb) {
+            (Some(mem), Some(interrupt_cb)) if self.device_activated() => {
+                (mem.clone(), interrupt_cb.clone())
+            }
+            _ => {
+                debug!("virtio-blk {}: notified before activation", self.name());
+                return Ok(None);
+            }
+        };
+        if self.base.broken.load(Ordering::Acquire) {
+            return Ok(None);
+        }
+        let queue = match self.base.queues.get(queue_index as usize) {
+            Some(queue) => queue.clone(),
+            None => {
+                warn!("virtio-blk {}: no queue {}", self.name(), queue_index);
+                return Err(HyperError::InvalidParam);
+            }
+        };
+        let features = self.base.driver_features;
+        let disk_len = self.config.capacity << SECTOR_SHIFT;
+        let read_only = self.backend.read_only();
+
+        let mut requests = Vec::new();
+        loop {
+            let elem = match queue.lock().vring.pop_avail(&mem, features) {
+                Ok(elem) => elem,
+                Err(err) => {
+                    error!("virtio-blk {}: bad request queue: {:?}", self.name(), err);
+                    report_virtio_error(interrupt_cb, features, &self.base.broken);
+                    return Err(err);
+                }
+            };
+            if elem.desc_num == 0 {
+                break;
+            }
+            requests.push(BlkRequest::new(&mem, &elem, disk_len, read_only));
+        }
+        if requests.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(BlkRequests {
+            requests,
+            backend: self.backend.clone(),
+            serial: self.serial,
+            mem,
+            queue,
+            interrupt_cb,
+            features,
+            broken: self.base.broken.clone(),
+            epoch: self.epoch.clone(),
+            submitted_epoch: self.epoch.load(Ordering::Acquire),
+        })))
+    }
+
+    fn deactivate(&mut self) -> Result<()> {
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        // Wait for a completion publishing requests of this activation, the later ones see the
+        // new epoch.
+        for queue in self.base.queues.iter() {
+            drop(queue.lock());
+        }
+        self.mem = None;
+        self.interrupt_cb = None;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.deactivate()
+    }
+}
@@ -0,0 +1,382 @@
+//! Virtio console device, a single port on the host console.
+//!
+//! The guest output goes to the host console through the backend of the emulated UARTs, after
+//! what the VM wrote to its console ring, so that the operator sees every console of a VM in
+//! order. The transmit queue is drained on its notifications, at most [`TX_BUDGET`] bytes at a
+//! time: the buffers beyond stay in the available ring, and the guest, running out of
+//! descriptors, waits for the used ring to move on. The rest is drained before the next VM
+//! entries, see [`poll_consoles`].
+//!
+//! The host input is read before the VM entries as well, see [`crate::console_mux`], into the
+//! buffers of the receive queue. Input the guest has no buffer for yet is kept, and the host
+//! console is no longer read once [`INPUT_FIFO_CAPACITY`] bytes are waiting.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cmp::min;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use hypercraft::{HyperError, HyperResult as Result};
+use lock_stat::Mutex;
+use pci::util::byte_code::ByteCode;
+use pci::AsAny;
+
+use crate::completion::DeferredOp;
+use crate::device::console_backend::{DefaultConsoleBackend, Fifo, VirtualConsoleBackend};
+use crate::device::virtio::{
+    read_config_default, report_virtio_error, Queue, VirtioBase, VirtioDevice, VirtioInterrupt,
+    VirtioInterruptType, VIRTIO_CONSOLE_F_EMERG_WRITE, VIRTIO_F_VERSION_1, VIRTIO_TYPE_CONSOLE,
+};
+use crate::mm::GuestRam;
+
+/// The receiveq and the transmitq of port 0, without `VIRTIO_CONSOLE_F_MULTIPORT`.
+const QUEUE_NUM_CONSOLE: usize = 2;
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// Most bytes of guest output written to the host console at once.
+pub const TX_BUDGET: usize = 4096;
+/// Most bytes of host input waiting for receive buffers.
+pub const INPUT_FIFO_CAPACITY: usize = 256;
+
+/// Configuration space of the device, refer to Virtio Spec.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtioConsoleConfig {
+    pub cols: u16,
+    pub rows: u16,
+    pub max_nr_ports: u32,
+    /// Written by the driver to output a byte before the queues are set up.
+    pub emerg_wr: u32,
+}
+
+impl ByteCode for VirtioConsoleConfig {}
+
+/// Offset of `emerg_wr` in the configuration space.
+const EMERG_WR_OFFSET: u64 = 8;
+
+/// What the port of an activated device uses.
+struct ActiveQueues {
+    mem: GuestRam,
+    rx: Arc<Mutex<Queue>>,
+    tx: Arc<Mutex<Queue>>,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    features: u64,
+    broken: Arc<AtomicBool>,
+}
+
+/// The port of a console, shared by the device and [`poll_consoles`].
+struct ConsolePort {
+    vm_id: u32,
+    name: String,
+    backend: DefaultConsoleBackend,
+    /// Host input waiting for receive buffers.
+    input: Fifo<INPUT_FIFO_CAPACITY>,
+    queues: Option<ActiveQueues>,
+    /// The transmitq still had buffers when its budget ran out.
+    tx_backlog: bool,
+}
+
+impl ConsolePort {
+    fn raise_interrupt(&self, queues: &ActiveQueues, queue: &Queue) {
+        if let Err(err) = (queues.interrupt_cb)(&VirtioInterruptType::Vring, Some(queue), false) {
+            error!(
+                "virtio-console {} failed to raise its interrupt: {:?}",
+                self.name, err
+            );
+        }
+    }
+
+    fn fail(&self, queues: &ActiveQueues, err: HyperError) {
+        error!("virtio-console {}: bad queue: {:?}", self.name, err);
+        report_virtio_error(queues.interrupt_cb.clone(), queues.features, &queues.broken);
+    }
+
+    /// Write up to [`TX_BUDGET`] bytes of the transmitq to the host console, returning the
+    /// buffers only once written.
+    fn transmit(&mut self) {
+        let queues = match &self.queues {
+            Some(queues) if !queues.broken.load(Ordering::Acquire) => queues,
+            _ => return,
+        };
+        let mut queue = queues.tx.lock();
+        let mut budget = TX_BUDGET;
+        let mut used = false;
+        let mut buf = [0u8; 256];
+        self.tx_backlog = false;
+        crate::console_ring::drain(self.vm_id);
+        loop {
+            if budget == 0 {
+                self.tx_backlog = true;
+                break;
+            }
+            let elem = match queue.vring.pop_avail(&queues.mem, queues.features) {
+                Ok(elem) => elem,
+                Err(err) => return self.fail(queues, err),
+            };
+            if elem.desc_num == 0 {
+                break;
+            }
+            for iov in elem.out_iovec.iter() {
+                let mut done = 0;
+                while done < iov.len as usize {
+                    let len = min(buf.len(), iov.len as usize - done);
+                    if let Err(err) = queues.mem.read(iov.addr + done as u64, &mut buf[..len]) {
+                        return self.fail(queues, err);
+                    }
+                    buf[..len].iter().for_each(|&c| self.backend.putchar(c));
+                    done += len;
+                }
+                budget = budget.saturating_sub(iov.len as usize);
+            }
+            if let Err(err) = queue.vring.add_used(&queues.mem, elem.index, 0) {
+                return self.fail(queues, err);
+            }
+            used = true;
+        }
+        if used && queue.vring.should_notify(&queues.mem, queues.features) {
+            self.raise_interrupt(queues, &queue);
+        }
+    }
+
+    /// Read the host input, only for the VM running on the current CPU.
+    fn read_input(&mut self) {
+        while !self.input.is_full() {
+            match self.backend.getchar() {
+                Some(c) => self.input.push(c),
+                None => break,
+            }
+        }
+    }
+
+    /// Move the waiting input to the receive buffers of the guest.
+    fn receive(&mut self) {
+        let queues = match &self.queues {
+            Some(queues) if !queues.broken.load(Ordering::Acquire) => queues,
+            _ => return,
+        };
+        if self.input.is_empty() {
+            return;
+        }
+        let mut queue = queues.rx.lock();
+        let mut used = false;
+        let mut buf = [0u8; INPUT_FIFO_CAPACITY];
+        while !self.input.is_empty() {
+            let elem = match queue.vring.pop_avail(&queues.mem, queues.features) {
+                Ok(elem) => elem,
+                Err(err) => return self.fail(queues, err),
+            };
+            if elem.desc_num == 0 {
+                break;
+            }
+            let mut written = 0u32;
+            for iov in elem.in_iovec.iter() {
+                let len = min(iov.len as usize, self.input.len());
+                if len == 0 {
+                    break;
+                }
+                // The bytes leave the FIFO once in guest memory.
+                for (dst, c) in buf[..len].iter_mut().zip(self.input.iter()) {
+                    *dst = c;
+                }
+                if let Err(err) = queues.mem.write(iov.addr, &buf[..len]) {
+                    return self.fail(queues, err);
+                }
+                (0..len).for_each(|_| {
+                    self.input.pop();
+                });
+                written += len as u32;
+            }
+            if let Err(err) = queue.vring.add_used(&queues.mem, elem.index, written) {
+                return self.fail(queues, err);
+            }
+            used = true;
+        }
+        if used && queue.vring.should_notify(&queues.mem, queues.features) {
+            self.raise_interrupt(queues, &queue);
+        }
+    }
+}
+
+static CONSOLES: Mutex<BTreeMap<u32, Vec<Arc<Mutex<ConsolePort>>>>> = Mutex::new(BTreeMap::new());
+/// Number of activated consoles, lets `check_events` skip the lookup when there is none.
+static ACTIVE_CONSOLES: AtomicUsize = AtomicUsize::new(0);
+
+/// Transmit the backlog and receive the host input of the consoles of the VM running on the
+/// current CPU. Called from `check_events`, skips the consoles if another CPU holds them.
+pub(crate) fn poll_consoles() {
+    if ACTIVE_CONSOLES.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let vm_id = match crate::vm::current_vm_id() {
+        Some(vm_id) => vm_id,
+        None => return,
+    };
+    let consoles = match CONSOLES.try_lock() {
+        Some(consoles) => consoles,
+        None => return,
+    };
+    for port in consoles.get(&vm_id).into_iter().flatten() {
+        let mut port = match port.try_lock() {
+            Some(port) => port,
+            None => continue,
+        };
+        if port.queues.is_none() {
+            continue;
+        }
+        if port.tx_backlog {
+            port.transmit();
+        }
+        port.read_input();
+        port.receive();
+    }
+}
+
+/// Forget the consoles of `vm_id`, once the VM stopped.
+pub(crate) fn close_vm(vm_id: u32) {
+    if let Some(ports) = CONSOLES.lock().remove(&vm_id) {
+        for port in ports {
+            if port.lock().queues.take().is_some() {
+                ACTIVE_CONSOLES.fetch_sub(1, Ordering::Release);
+            }
+        }
+    }
+}
+
+/// A virtio console device of a VM.
+pub struct VirtioConsole {
+    base: VirtioBase,
+    config: VirtioConsoleConfig,
+    port: Arc<Mutex<ConsolePort>>,
+}
+
+impl VirtioConsole {
+    /// A console of VM `vm_id` named `name` in the logs, with queues of `queue_size`.
+    pub fn new(vm_id: u32, name: &str, queue_size: u16) -> Self {
+        let port = Arc::new(Mutex::new(ConsolePort {
+            vm_id,
+            name: String::from(name),
+            backend: DefaultConsoleBackend::new(),
+            input: Fifo::new(),
+            queues: None,
+            tx_backlog: false,
+        }));
+        CONSOLES.lock().entry(vm_id).or_default().push(port.clone());
+        Self {
+            base: VirtioBase::new(VIRTIO_TYPE_CONSOLE, QUEUE_NUM_CONSOLE, queue_size),
+            config: VirtioConsoleConfig::default(),
+            port,
+        }
+    }
+}
+
+impl AsAny for VirtioConsole {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn virtio_base(&self) -> &VirtioBase {
+        &self.base
+    }
+
+    fn virtio_base_mut(&mut self) -> &mut VirtioBase {
+        &mut self.base
+    }
+
+    fn realize(&mut self) -> Result<()> {
+        self.init_config_features()?;
+        let port = self.port.lock();
+        info!("VM [{}] virtio-console {}", port.vm_id, port.name);
+        Ok(())
+    }
+
+    fn init_config_features(&mut self) -> Result<()> {
+        self.base.device_features =
+            1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_CONSOLE_F_EMERG_WRITE;
+        self.config.max_nr_ports = 1;
+        Ok(())
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        read_config_default(self.config.as_bytes(), offset, data)
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let mut port = self.port.lock();
+        if offset == EMERG_WR_OFFSET && !data.is_empty() {
+            crate::console_ring::drain(port.vm_id);
+            port.backend.putchar(data[0]);
+        } else {
+            warn!(
+                "virtio-console {}: write of {} bytes to config offset {:#x} ignored",
+                port.name,
+                data.len(),
+                offset
+            );
+        }
+        Ok(())
+    }
+
+    fn activate(&mut self, interrupt_cb: Arc<VirtioInterrupt>) -> Result<()> {
+        let (rx, tx) = match (
+            self.base.queues.get(RX_QUEUE),
+            self.base.queues.get(TX_QUEUE),
+        ) {
+            (Some(rx), Some(tx)) => (rx.clone(), tx.clone()),
+            _ => return Err(HyperError::InvalidParam),
+        };
+        let mut port = self.port.lock();
+        let queues = ActiveQueues {
+            mem: GuestRam::new(port.vm_id)?,
+            rx,
+            tx,
+            interrupt_cb,
+            features: self.base.driver_features,
+            broken: self.base.broken.clone(),
+        };
+        if port.queues.replace(queues).is_none() {
+            ACTIVE_CONSOLES.fetch_add(1, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    fn notify_queue(&mut self, queue_index: u16) -> Result<Option<Box<dyn DeferredOp>>> {
+        let mut port = self.port.lock();
+        if port.queues.is_none() {
+            debug!("virtio-console {}: notified before activation", port.name);
+            return Ok(None);
+        }
+        match queue_index as usize {
+            RX_QUEUE => port.receive(),
+            TX_QUEUE => port.transmit(),
+            _ => {
+                warn!("virtio-console {}: no queue {}", port.name, queue_index);
+                return Err(HyperError::InvalidParam);
+            }
+        }
+        Ok(None)
+    }
+
+    fn deactivate(&mut self) -> Result<()> {
+        let mut port = self.port.lock();
+        if port.queues.take().is_some() {
+            ACTIVE_CONSOLES.fetch_sub(1, Ordering::Release);
+        }
+        port.tx_backlog = false;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.deactivate()
+    }
+}
@@ -1,5 +1,6 @@
-// pub mod block;
-// pub mod net;
+pub mod block;
+pub mod console;
+pub mod net;
 // pub mod serial;
 pub mod dummy;
 
@@ -0,0 +1,549 @@
+//! Virtio network device, a card on a [`NetBackend`].
+//!
+//! The transmit queue is drained on its notifications, at most [`TX_BUDGET`] frames at a time,
+//! each frame being sent to the backend before its buffers return to the used ring; the rest is
+//! drained before the next VM entries, see [`poll_nets`]. The frames received by the backend are
+//! polled for before the VM entries and on the notifications of the receive queue, and written
+//! to its buffers, across several of them with `VIRTIO_NET_F_MRG_RXBUF`. A frame the guest has
+//! no buffer for yet is kept, and the backend is no longer read meanwhile.
+//!
+//! The device only offers its MAC address and the mergeable receive buffers: the guest computes
+//! the checksums and segments the frames itself. A frame too long for the device, or for the
+//! receive buffers without `VIRTIO_NET_F_MRG_RXBUF`, and the descriptor chains the device cannot
+//! use, e.g. without room for the header or out of guest RAM, are dropped and counted, see
+//! [`virtio_net_stats`]. A queue whose rings cannot be read breaks the device, as for the other
+//! virtio devices.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cmp::min;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use hypercraft::{HyperError, HyperResult as Result};
+use lock_stat::Mutex;
+use pci::util::byte_code::ByteCode;
+use pci::AsAny;
+
+use super::block::{iov_read, iov_write};
+use crate::completion::DeferredOp;
+use crate::device::net_backend::{NetBackend, MAX_FRAME_LEN};
+use crate::device::virtio::{
+    read_config_default, report_virtio_error, virtio_has_feature, Element, Queue, VirtioBase,
+    VirtioDevice, VirtioInterrupt, VirtioInterruptType, VIRTIO_F_RING_EVENT_IDX,
+    VIRTIO_F_VERSION_1, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MRG_RXBUF, VIRTIO_TYPE_NET,
+};
+use crate::mm::GuestRam;
+use crate::park::VcpuWaker;
+
+/// The receiveq and the transmitq of the only queue pair, without `VIRTIO_NET_F_MQ`.
+const QUEUE_NUM_NET: usize = 2;
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// Most frames sent at once.
+pub const TX_BUDGET: usize = 256;
+
+/// Header of each frame, in the first bytes of its descriptor chain, `num_buffers` included
+/// with `VIRTIO_F_VERSION_1`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct VirtioNetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+    /// Receive buffers the frame spans.
+    num_buffers: u16,
+}
+
+impl ByteCode for VirtioNetHdr {}
+
+const NET_HDR_LEN: usize = core::mem::size_of::<VirtioNetHdr>();
+
+/// Configuration space of the device, refer to Virtio Spec.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtioNetConfig {
+    pub mac: [u8; 6],
+    pub status: u16,
+    pub max_virtqueue_pairs: u16,
+    pub mtu: u16,
+}
+
+impl ByteCode for VirtioNetConfig {}
+
+/// Counters of a virtio network device, see [`virtio_net_stats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VirtioNetStats {
+    /// Frames sent to the backend, and their bytes.
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    /// Frames the backend failed to send.
+    pub tx_dropped: u64,
+    /// Frames written to the receive buffers, and their bytes.
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    /// Frames received longer than the receive buffers.
+    pub rx_dropped: u64,
+    /// Frames to send longer than [`MAX_FRAME_LEN`].
+    pub oversized: u64,
+    /// Descriptor chains returned unused.
+    pub bad_chains: u64,
+}
+
+/// What the card of an activated device uses.
+struct ActiveQueues {
+    mem: GuestRam,
+    rx: Arc<Mutex<Queue>>,
+    tx: Arc<Mutex<Queue>>,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    features: u64,
+    broken: Arc<AtomicBool>,
+}
+
+/// What became of a frame received.
+enum Delivery {
+    /// Written to the receive buffers, or dropped.
+    Done,
+    /// Kept for buffers the driver has not made available yet.
+    NoBuffers,
+}
+
+/// The card of a device, shared by the device and [`poll_nets`].
+struct NetCard {
+    vm_id: u32,
+    name: String,
+    backend: Arc<dyn NetBackend>,
+    queues: Option<ActiveQueues>,
+    /// A frame received, waiting for receive buffers.
+    pending: Option<Vec<u8>>,
+    /// The transmitq still had buffers when its budget ran out.
+    tx_backlog: bool,
+    stats: VirtioNetStats,
+}
+
+impl NetCard {
+    fn raise_interrupt(&self, queues: &ActiveQueues, queue: &Queue) {
+        if let Err(err) = (queues.interrupt_cb)(&VirtioInterruptType::Vring, Some(queue), false) {
+            error!(
+                "virtio-net {} failed to raise its interrupt: {:?}",
+                self.name, err
+            );
+        }
+    }
+
+    fn fail(&self, queues: &ActiveQueues, err: HyperError) {
+        error!("virtio-net {}: bad queue: {:?}", self.name, err);
+        report_virtio_error(queues.interrupt_cb.clone(), queues.features, &queues.broken);
+    }
+
+    /// The next chain of the available ring of `queue`, `None` if there is none. A chain which
+    /// cannot be assembled is returned unused and skipped.
+    fn pop_chain(
+        stats: &mut VirtioNetStats,
+        queues: &ActiveQueues,
+        queue: &mut Queue,
+    ) -> Result<Option<Element>> {
+        loop {
+            let err = match queue.vring.pop_avail(&queues.mem, queues.features) {
+                Ok(elem) if elem.desc_num == 0 => return Ok(None),
+                Ok(elem) => return Ok(Some(elem)),
+                Err(err) => err,
+            };
+            // Unless the ring itself cannot be read, only the descriptors of the chain are bad.
+            if queue.vring.avail_ring_len(&queues.mem).is_err() {
+                return Err(err);
+            }
+            let index = queue.vring.skip_avail(&queues.mem, queues.features)?;
+            debug!("virtio-net: descriptor chain {} skipped: {:?}", index, err);
+            queue.vring.add_used(&queues.mem, index, 0)?;
+            stats.bad_chains += 1;
+        }
+    }
+
+    /// Send up to [`TX_BUDGET`] frames of the transmitq to the backend.
+    fn transmit(&mut self) {
+        let queues = match &self.queues {
+            Some(queues) if !queues.broken.load(Ordering::Acquire) => queues,
+            _ => return,
+        };
+        let mut queue = queues.tx.lock();
+        let mut used = false;
+        let mut buf = vec![0u8; NET_HDR_LEN + MAX_FRAME_LEN];
+        self.tx_backlog = false;
+        for sent in 0.. {
+            if sent == TX_BUDGET {
+                self.tx_backlog = true;
+                break;
+            }
+            let elem = match Self::pop_chain(&mut self.stats, queues, &mut queue) {
+                Ok(Some(elem)) => elem,
+                Ok(None) => break,
+                Err(err) => return self.fail(queues, err),
+            };
+            Self::send_chain(&mut self.stats, &*self.backend, queues, &elem, &mut buf);
+            // The buffers are the guest's again once the frame was sent.
+            if let Err(err) = queue.vring.add_used(&queues.mem, elem.index, 0) {
+                return self.fail(queues, err);
+            }
+            used = true;
+        }
+        if used && queue.vring.should_notify(&queues.mem, queues.features) {
+            self.raise_interrupt(queues, &queue);
+        }
+    }
+
+    /// Send the frame of the transmit chain `elem` to `backend`, read to `buf`.
+    fn send_chain(
+        stats: &mut VirtioNetStats,
+        backend: &dyn NetBackend,
+        queues: &ActiveQueues,
+        elem: &Element,
+        buf: &mut [u8],
+    ) {
+        let len = Element::iovec_size(&elem.out_iovec) as usize;
+        if !elem.in_iovec.is_empty() || len < NET_HDR_LEN {
+            stats.bad_chains += 1;
+        } else if len > buf.len() {
+            stats.oversized += 1;
+        } else if iov_read(&queues.mem, &elem.out_iovec, &mut buf[..len]).is_err() {
+            stats.bad_chains += 1;
+        } else {
+            let frame = &buf[NET_HDR_LEN..len];
+            match backend.send(frame) {
+                Ok(()) => {
+                    stats.tx_frames += 1;
+                    stats.tx_bytes += frame.len() as u64;
+                }
+                Err(_) => stats.tx_dropped += 1,
+            }
+        }
+    }
+
+    /// Write the frames received by the backend to the receive buffers of the guest.
+    fn receive(&mut self) {
+        let queues = match &self.queues {
+            Some(queues) if !queues.broken.load(Ordering::Acquire) => queues,
+            _ => return,
+        };
+        let mut queue = queues.rx.lock();
+        let mut used = false;
+        loop {
+            let frame = match self.pending.take() {
+                Some(frame) => frame,
+                None => {
+                    let mut frame = vec![0u8; MAX_FRAME_LEN];
+                    match self.backend.recv(&mut frame) {
+                        Some(len) => {
+                            frame.truncate(len);
+                            frame
+                        }
+                        None => break,
+                    }
+                }
+            };
+            match Self::deliver(&mut self.stats, queues, &mut queue, &frame, &mut used) {
+                Ok(Delivery::Done) => {}
+                Ok(Delivery::NoBuffers) => {
+                    self.pending = Some(frame);
+                    break;
+                }
+                Err(err) => return self.fail(queues, err),
+            }
+        }
+        if used && queue.vring.should_notify(&queues.mem, queues.features) {
+            self.raise_interrupt(queues, &queue);
+        }
+    }
+
+    /// Write `frame` to the receive buffers of `queue`, `used` being set if any returned to the
+    /// used ring.
+    fn deliver(
+        stats: &mut VirtioNetStats,
+        queues: &ActiveQueues,
+        queue: &mut Queue,
+        frame: &[u8],
+        used: &mut bool,
+    ) -> Result<Delivery> {
+        let mergeable = virtio_has_feature(queues.features, VIRTIO_NET_F_MRG_RXBUF);
+        let total = NET_HDR_LEN + frame.len();
+        if mergeable && queue.vring.get_avail_bytes(&queues.mem, total, true)? < total {
+            return Ok(Delivery::NoBuffers);
+        }
+        // The buffers the frame spans, and the bytes of the frame each takes.
+        let mut chains: Vec<(Element, usize)> = Vec::new();
+        let mut room = 0;
+        while room < total {
+            let Some(elem) = Self::pop_chain(stats, queues, queue)? else {
+                if chains.is_empty() {
+                    return Ok(Delivery::NoBuffers);
+                }
+                // Some of the buffers counted unusable, the frame does not fit in the others.
+                let unused: Vec<_> = chains.iter().map(|(elem, _)| (elem.index, 0)).collect();
+                *used = true;
+                queue.vring.add_used_batch(&queues.mem, &unused)?;
+                stats.rx_dropped += 1;
+                return Ok(Delivery::Done);
+            };
+            let len = Element::iovec_size(&elem.in_iovec) as usize;
+            if !elem.out_iovec.is_empty() || len == 0 {
+                *used = true;
+                queue.vring.add_used(&queues.mem, elem.index, 0)?;
+                stats.bad_chains += 1;
+                continue;
+            }
+            if !mergeable && len < total {
+                *used = true;
+                queue.vring.add_used(&queues.mem, elem.index, 0)?;
+                stats.rx_dropped += 1;
+                return Ok(Delivery::Done);
+            }
+            let taken = min(len, total - room);
+            room += taken;
+            chains.push((elem, taken));
+        }
+
+        let hdr = VirtioNetHdr {
+            num_buffers: chains.len() as u16,
+            ..Default::default()
+        };
+        let mut data = Vec::with_capacity(total);
+        data.extend_from_slice(hdr.as_bytes());
+        data.extend_from_slice(frame);
+        let mut written = 0;
+        let mut done = Vec::with_capacity(chains.len());
+        let mut bad = false;
+        for (elem, taken) in chains.iter() {
+            let chunk = &data[written..written + taken];
+            bad |= iov_write(&queues.mem, &elem.in_iovec, chunk).is_err();
+            done.push((elem.index, *taken as u32));
+            written += taken;
+        }
+        *used = true;
+        if bad {
+            // The driver drops the buffers shorter than the header.
+            done.iter_mut().for_each(|(_, len)| *len = 0);
+            stats.bad_chains += 1;
+        } else {
+            stats.rx_frames += 1;
+            stats.rx_bytes += frame.len() as u64;
+        }
+        queue.vring.add_used_batch(&queues.mem, &done)?;
+        Ok(Delivery::Done)
+    }
+}
+
+static NETS: Mutex<BTreeMap<u32, Vec<Arc<Mutex<NetCard>>>>> = Mutex::new(BTreeMap::new());
+/// Number of activated cards, lets `check_events` skip the lookup when there is none.
+static ACTIVE_NETS: AtomicUsize = AtomicUsize::new(0);
+
+/// Transmit the backlog and receive the frames of the cards of the VM running on the current
+/// CPU. Called from `check_events`, skips the cards if another CPU holds them.
+pub(crate) fn poll_nets() {
+    if ACTIVE_NETS.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let vm_id = match crate::vm::current_vm_id() {
+        Some(vm_id) => vm_id,
+        None => return,
+    };
+    let nets = match NETS.try_lock() {
+        Some(nets) => nets,
+        None => return,
+    };
+    for card in nets.get(&vm_id).into_iter().flatten() {
+        let mut card = match card.try_lock() {
+            Some(card) => card,
+            None => continue,
+        };
+        if card.queues.is_none() {
+            continue;
+        }
+        if card.tx_backlog {
+            card.transmit();
+        }
+        card.receive();
+    }
+}
+
+/// Forget the cards of `vm_id`, once the VM stopped.
+pub(crate) fn close_vm(vm_id: u32) {
+    if let Some(cards) = NETS.lock().remove(&vm_id) {
+        for card in cards {
+            let mut card = card.lock();
+            card.backend.set_waker(None);
+            if card.queues.take().is_some() {
+                ACTIVE_NETS.fetch_sub(1, Ordering::Release);
+            }
+        }
+    }
+}
+
+/// The counters of the virtio network devices of VM `vm_id`, by name.
+pub fn virtio_net_stats(vm_id: u32) -> Vec<(String, VirtioNetStats)> {
+    NETS.lock()
+        .get(&vm_id)
+        .into_iter()
+        .flatten()
+        .map(|card| {
+            let card = card.lock();
+            (card.name.clone(), card.stats)
+        })
+        .collect()
+}
+
+/// A virtio network device of a VM.
+pub struct VirtioNet {
+    base: VirtioBase,
+    config: VirtioNetConfig,
+    card: Arc<Mutex<NetCard>>,
+}
+
+impl VirtioNet {
+    /// A card of VM `vm_id` named `name` in the logs, with MAC address `mac`, on `backend`, with
+    /// queues of `queue_size`.
+    pub fn new(
+        vm_id: u32,
+        name: &str,
+        backend: Arc<dyn NetBackend>,
+        mac: [u8; 6],
+        queue_size: u16,
+    ) -> Self {
+        // Its receive queue is polled by the vCPUs running, vCPU 0 takes the frames which arrive
+        // while they are halted.
+        backend.set_waker(Some(VcpuWaker::new(vm_id, 0)));
+        let card = Arc::new(Mutex::new(NetCard {
+            vm_id,
+            name: String::from(name),
+            backend,
+            queues: None,
+            pending: None,
+            tx_backlog: false,
+            stats: VirtioNetStats::default(),
+        }));
+        NETS.lock().entry(vm_id).or_default().push(card.clone());
+        Self {
+            base: VirtioBase::new(VIRTIO_TYPE_NET, QUEUE_NUM_NET, queue_size),
+            config: VirtioNetConfig {
+                mac,
+                ..Default::default()
+            },
+            card,
+        }
+    }
+}
+
+impl AsAny for VirtioNet {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn virtio_base(&self) -> &VirtioBase {
+        &self.base
+    }
+
+    fn virtio_base_mut(&mut self) -> &mut VirtioBase {
+        &mut self.base
+    }
+
+    fn realize(&mut self) -> Result<()> {
+        self.init_config_features()?;
+        let card = self.card.lock();
+        let mac = self.config.mac;
+        info!(
+            "VM [{}] virtio-net {}: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} on {:?}",
+            card.vm_id, card.name, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5], card.backend
+        );
+        Ok(())
+    }
+
+    fn init_config_features(&mut self) -> Result<()> {
+        self.base.device_features = 1u64 << VIRTIO_F_VERSION_1
+            | 1u64 << VIRTIO_F_RING_EVENT_IDX
+            | 1u64 << VIRTIO_NET_F_MAC
+            | 1u64 << VIRTIO_NET_F_MRG_RXBUF;
+        Ok(())
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        read_config_default(self.config.as_bytes(), offset, data)
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        // Without VIRTIO_NET_F_CTRL_MAC_ADDR the MAC address is read-only for a modern driver.
+        warn!(
+            "virtio-net {}: write of {} bytes to config offset {:#x} ignored",
+            self.card.lock().name,
+            data.len(),
+            offset
+        );
+        Ok(())
+    }
+
+    fn activate(&mut self, interrupt_cb: Arc<VirtioInterrupt>) -> Result<()> {
+        let (rx, tx) = match (
+            self.base.queues.get(RX_QUEUE),
+            self.base.queues.get(TX_QUEUE),
+        ) {
+            (Some(rx), Some(tx)) => (rx.clone(), tx.clone()),
+            _ => return Err(HyperError::InvalidParam),
+        };
+        let mut card = self.card.lock();
+        let queues = ActiveQueues {
+            mem: GuestRam::new(card.vm_id)?,
+            rx,
+            tx,
+            interrupt_cb,
+            features: self.base.driver_features,
+            broken: self.base.broken.clone(),
+        };
+        if card.queues.replace(queues).is_none() {
+            ACTIVE_NETS.fetch_add(1, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    fn notify_queue(&mut self, queue_index: u16) -> Result<Option<Box<dyn DeferredOp>>> {
+        let mut card = self.card.lock();
+        if card.queues.is_none() {
+            debug!("virtio-net {}: notified before activation", card.name);
+            return Ok(None);
+        }
+        match queue_index as usize {
+            RX_QUEUE => card.receive(),
+            TX_QUEUE => card.transmit(),
+            _ => {
+                warn!("virtio-net {}: no queue {}", card.name, queue_index);
+                return Err(HyperError::InvalidParam);
+            }
+        }
+        Ok(None)
+    }
+
+    fn deactivate(&mut self) -> Result<()> {
+        let mut card = self.card.lock();
+        if card.queues.take().is_some() {
+            ACTIVE_NETS.fetch_sub(1, Ordering::Release);
+        }
+        card.tx_backlog = false;
+        card.pending = None;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.deactivate()
+    }
+}
@@ -2,13 +2,16 @@ pub mod device;
 
 mod queue;
 mod transport;
+pub(crate) mod worker;
 
 pub use crate::device::virtio::device::dummy::DummyVirtioDevice;
-// pub use device::block::{Block, BlockState, VirtioBlkConfig};
-// pub use device::net::*;
+pub use device::block::{VirtioBlk, VirtioBlkConfig};
+pub use device::console::{VirtioConsole, VirtioConsoleConfig};
+pub use device::net::{virtio_net_stats, VirtioNet, VirtioNetConfig, VirtioNetStats};
 // pub use device::serial::{find_port_by_nr, get_max_nr, Serial, SerialPort, VirtioSerialState};
 pub use queue::*;
-pub use transport::virtio_pci::{VirtioPciDevice, GLOBAL_VIRTIO_PCI_CFG_REQ};
+pub use transport::virtio_pci::{take_virtio_pci_cfg_req, VirtioPciDevice, VirtioPciLayout};
+pub use worker::{set_virtio_workers, virtio_worker_stats, VirtioWorkerStats};
 
 use alloc::boxed::Box;
 use alloc::format;
@@ -18,49 +21,67 @@ use alloc::vec::Vec;
 
 use core::cmp;
 use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering};
-use spin::Mutex;
-use x86::msr::{rdmsr, wrmsr, IA32_X2APIC_ICR};
+use lock_stat::Mutex;
 
-use axhal::irq::ApicIcr;
 use hypercraft::{HyperError, HyperResult as Result, VirtioError};
 use pci::util::byte_code::ByteCode;
 use pci::util::num_ops::{read_u32, write_u32};
 use pci::util::AsAny;
 use pci::{MsiAddrReg, MsiDataReg, MsiIrqManager, MsiVector, MSI_ADDR_BASE, MSI_ADDR_DESTMODE_PHYS};
 
-use crate::vcpu2pcpu;
+use crate::completion::DeferredOp;
+use crate::device::{deliver_msi, StateReader, StateWriter};
+
+/// Delivery modes of the data of an MSI.
+const MSI_DATA_DELIVERY_FIXED: u8 = 0;
+const MSI_DATA_DELIVERY_LOWEST_PRIORITY: u8 = 1;
 
 pub struct VirtioMsiIrqManager {
     pub vm_id: u32,
 }
 impl MsiIrqManager for VirtioMsiIrqManager {
-    fn trigger(&self, vector: MsiVector, dev_id: u32) -> Result<()> {
+    /// Send `vector`, read from the MSI-X table when the interrupt is raised: an entry the guest
+    /// rewrote, to move the interrupt to another vCPU, sends to its new destination.
+    fn trigger(&self, vector: MsiVector, _dev_id: u32) -> Result<()> {
         debug!("Trigger MSI: {:#?}", vector);
         let msi_addr_reg: MsiAddrReg = vector.msi_addr.into();
-        let is_phys = msi_addr_reg.dest_mode() == MSI_ADDR_DESTMODE_PHYS;
-        let dest: u32;
-        if is_phys {
-            let vdest = msi_addr_reg.dest_field();
-            // suppose only one core
-            let dest_option = vcpu2pcpu(self.vm_id, vdest);
-            if dest_option.is_none() {
-                error!("Invalid vdest: {}", vdest);
-                return Err(HyperError::BadState);
+        if msi_addr_reg.addr_base() != MSI_ADDR_BASE {
+            error!("Invalid MSI address: {:#x}", vector.msi_addr);
+            return Err(HyperError::InvalidParam);
+        }
+        let msi_data_reg: MsiDataReg = (vector.msi_data as u32).into();
+        let lowest_priority = match msi_data_reg.delivery_mode() {
+            MSI_DATA_DELIVERY_FIXED => false,
+            MSI_DATA_DELIVERY_LOWEST_PRIORITY => true,
+            mode => {
+                error!("MSI delivery mode {} is not supported", mode);
+                return Err(HyperError::NotSupported);
             }
-            dest = dest_option.unwrap() as u32;
-        }else {
-            panic!("MSI_ADDR_DESTMODE_LOGICAL is not supported");
+        };
+        deliver_msi(
+            self.vm_id,
+            msi_addr_reg.dest_field() as u8,
+            msi_addr_reg.dest_mode() != MSI_ADDR_DESTMODE_PHYS,
+            lowest_priority,
+            msi_data_reg.vector(),
+        );
+        Ok(())
+    }
+
+    /// Raise `irq` through the IO APIC or the PICs of the VM, which only have the legacy PC
+    /// devices.
+    fn trigger_intx(&self, irq: u8) -> Result<()> {
+        debug!("Trigger INTx: IRQ {}", irq);
+        #[cfg(feature = "legacy-pc-devices")]
+        {
+            crate::device::raise_vm_irq(self.vm_id, irq);
+            Ok(())
         }
-        debug!("MSI Dest:{:#x}", dest);
-        if msi_addr_reg.addr_base() == MSI_ADDR_BASE {
-            let msi_data_reg: MsiDataReg = (vector.msi_data as u32).into();
-            let mut icr = ApicIcr::new(0);
-            icr.set_dest_field(dest);
-            icr.set_vector(msi_data_reg.vector());
-            icr.set_delivery_mode(msi_data_reg.delivery_mode());
-            unsafe { wrmsr(IA32_X2APIC_ICR, icr.value()) };
+        #[cfg(not(feature = "legacy-pc-devices"))]
+        {
+            error!("INTx IRQ {} raised without an interrupt controller", irq);
+            Err(HyperError::NotSupported)
         }
-        Ok(())
     }
 }
 
@@ -369,6 +390,7 @@ pub struct VirtioBase {
 #[derive(Copy, Clone)]
 struct VirtioBaseState {
     device_activated: bool,
+    driver_features: u64,
     hfeatures_sel: u32,
     gfeatures_sel: u32,
     interrupt_status: u32,
@@ -417,6 +439,7 @@ impl VirtioBase {
     fn get_state(&self) -> VirtioBaseState {
         let mut state = VirtioBaseState {
             device_activated: self.device_activated.load(Ordering::Acquire),
+            driver_features: self.driver_features,
             hfeatures_sel: self.hfeatures_sel,
             gfeatures_sel: self.gfeatures_sel,
             interrupt_status: self.interrupt_status.load(Ordering::Acquire),
@@ -443,6 +466,7 @@ impl VirtioBase {
     fn set_state(&mut self, state: &VirtioBaseState, interrupt_cb: Arc<VirtioInterrupt>) {
         self.device_activated
             .store(state.device_activated, Ordering::SeqCst);
+        self.driver_features = state.driver_features;
         self.hfeatures_sel = state.hfeatures_sel;
         self.gfeatures_sel = state.gfeatures_sel;
         self.interrupt_status
@@ -476,6 +500,57 @@ impl VirtioBase {
         }
         self.queues = queues;
     }
+
+    /// Append the state of [`VirtioBase::get_state`] to `state`.
+    fn save(&self, state: &mut StateWriter) {
+        let base = self.get_state();
+        state
+            .bool(base.device_activated)
+            .u64(base.driver_features)
+            .u32(base.hfeatures_sel)
+            .u32(base.gfeatures_sel)
+            .u32(base.interrupt_status)
+            .u32(base.device_status)
+            .u8(base.config_generation)
+            .u16(base.queue_select)
+            .u16(base.config_vector)
+            .u16(base.queue_type)
+            .u32(base.queue_num as u32)
+            .u32(self.queue_num as u32);
+        for queue_config in base.queues_config[..self.queue_num].iter() {
+            queue_config.save(state);
+        }
+    }
+
+    /// Read a state appended by [`VirtioBase::save`], to be applied with
+    /// [`VirtioBase::set_state`].
+    fn load(&self, state: &mut StateReader) -> Result<VirtioBaseState> {
+        let mut base = VirtioBaseState {
+            device_activated: state.bool()?,
+            driver_features: state.u64()?,
+            hfeatures_sel: state.u32()?,
+            gfeatures_sel: state.u32()?,
+            interrupt_status: state.u32()?,
+            device_status: state.u32()?,
+            config_generation: state.u8()?,
+            queue_select: state.u16()?,
+            config_vector: state.u16()?,
+            queues_config: [QueueConfig::default(); 32],
+            queue_type: state.u16()?,
+            queue_num: state.u32()? as usize,
+        };
+        // The queues are created by the device, a state of another model is rejected.
+        if state.u32()? as usize != self.queue_num
+            || base.queue_num > self.queue_num
+            || base.queue_type != QUEUE_TYPE_SPLIT_VRING
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        for queue_config in base.queues_config[..self.queue_num].iter_mut() {
+            *queue_config = QueueConfig::load(state, self.queue_size_max)?;
+        }
+        Ok(base)
+    }
 }
 
 /// The trait for virtio device operations.
@@ -694,6 +769,13 @@ pub trait VirtioDevice: Send + AsAny {
     /// * `queue_evts` - The notifier events from guest.
     fn activate(&mut self, interrupt_cb: Arc<VirtioInterrupt>) -> Result<()>;
 
+    /// Handle the notification of queue `queue_index` by the driver. A device whose backend
+    /// does not finish at once returns the rest of the work, which the completion worker runs
+    /// while the guest goes on, see [`crate::completion`].
+    fn notify_queue(&mut self, _queue_index: u16) -> Result<Option<Box<dyn DeferredOp>>> {
+        Ok(None)
+    }
+
     /// Deactivate virtio device, this function remove event fd
     /// of device out of the event loop.
     fn deactivate(&mut self) -> Result<()> {
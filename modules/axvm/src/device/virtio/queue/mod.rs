@@ -8,6 +8,8 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use hypercraft::{HyperError, HyperResult as Result, VirtioError};
 
+use crate::mm::GuestRam;
+
 /// Split Virtqueue.
 pub const QUEUE_TYPE_SPLIT_VRING: u16 = 1;
 /// Packed Virtqueue.
@@ -80,45 +82,65 @@ pub trait VringOps {
     ///
     /// # Arguments
     ///
-    /// * `sys_mem` - Address space to which the vring belongs.
-    fn is_valid(&self) -> bool;
+    /// * `mem` - The RAM the vring belongs to.
+    /// * `features` - Bit mask of features negotiated by the backend and the frontend.
+    fn is_valid(&self, mem: &GuestRam, features: u64) -> bool;
 
-    /// Assemble an IO request element with descriptors from the available vring.
+    /// Assemble an IO request element with descriptors from the available vring. The element
+    /// has no descriptor if none is available.
     ///
     /// # Arguments
     ///
-    /// * `sys_mem` - Address space to which the vring belongs.
+    /// * `mem` - The RAM the vring belongs to.
     /// * `features` - Bit mask of features negotiated by the backend and the frontend.
-    fn pop_avail(&mut self, features: u64) -> Result<Element>;
+    fn pop_avail(&mut self, mem: &GuestRam, features: u64) -> Result<Element>;
 
     /// Rollback the entry which is pop from available queue by `pop_avail`.
     fn push_back(&mut self);
 
+    /// Skip the next descriptor chain of the available ring, which `pop_avail` failed to
+    /// assemble, and return the index of its head, for the chain to be returned unused.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The RAM the vring belongs to.
+    /// * `features` - Bit mask of features negotiated by the backend and the frontend.
+    fn skip_avail(&mut self, mem: &GuestRam, features: u64) -> Result<u16>;
+
     /// Fill the used vring after processing the IO request.
     ///
     /// # Arguments
     ///
-    /// * `sys_mem` - Address space to which the vring belongs.
+    /// * `mem` - The RAM the vring belongs to.
     /// * `index` - Index of descriptor in the virqueue descriptor table.
     /// * `len` - Total length of the descriptor chain which was used (written to).
-    fn add_used(&mut self, index: u16, len: u32) -> Result<()>;
+    fn add_used(&mut self, mem: &GuestRam, index: u16, len: u32) -> Result<()>;
+
+    /// As `add_used` for each of the `(index, len)` of `used`, the used index being published
+    /// once all of them are in the ring.
+    fn add_used_batch(&mut self, mem: &GuestRam, used: &[(u16, u32)]) -> Result<()>;
 
     /// Return true if guest needed to be notified.
     ///
     /// # Arguments
     ///
-    /// * `sys_mem` - Address space to which the vring belongs.
+    /// * `mem` - The RAM the vring belongs to.
     /// * `features` - Bit mask of features negotiated by the backend and the frontend.
-    fn should_notify(&mut self, features: u64) -> bool;
+    fn should_notify(&mut self, mem: &GuestRam, features: u64) -> bool;
 
     /// Give guest a hint to suppress virtqueue notification.
     ///
     /// # Arguments
     ///
-    /// * `sys_mem` - Address space to which the vring belongs.
+    /// * `mem` - The RAM the vring belongs to.
     /// * `features` - Bit mask of features negotiated by the backend and the frontend.
     /// * `suppress` - Suppress virtqueue notification or not.
-    fn suppress_queue_notify(&mut self, features: u64, suppress: bool) -> Result<()>;
+    fn suppress_queue_notify(
+        &mut self,
+        mem: &GuestRam,
+        features: u64,
+        suppress: bool,
+    ) -> Result<()>;
 
     /// Get the actual size of the vring.
     fn actual_size(&self) -> u16;
@@ -127,19 +149,19 @@ pub trait VringOps {
     fn get_queue_config(&self) -> QueueConfig;
 
     /// The number of descriptor chains in the available ring.
-    fn avail_ring_len(&mut self) -> Result<u16>;
+    fn avail_ring_len(&mut self, mem: &GuestRam) -> Result<u16>;
 
     /// Get the avail index of the vring.
-    fn get_avail_idx(&self) -> Result<u16>;
+    fn get_avail_idx(&self, mem: &GuestRam) -> Result<u16>;
 
     /// Get the used index of the vring.
-    fn get_used_idx(&self) -> Result<u16>;
+    fn get_used_idx(&self, mem: &GuestRam) -> Result<u16>;
 
     /// Get the region cache information of the SplitVring.
     fn get_cache(&self) -> &Option<u32>;
 
     /// Get the available bytes of the vring to read from or write to the guest
-    fn get_avail_bytes(&mut self, max_size: usize, is_in: bool) -> Result<usize>;
+    fn get_avail_bytes(&mut self, mem: &GuestRam, max_size: usize, is_in: bool) -> Result<usize>;
 }
 
 /// Virtio queue.
@@ -178,8 +200,9 @@ impl Queue {
     ///
     /// # Arguments
     ///
-    /// * `sys_mem` - Address space to which the vring belongs.
-    pub fn is_valid(&self) -> bool {
-        self.vring.is_valid()
+    /// * `mem` - The RAM the vring belongs to.
+    /// * `features` - Bit mask of features negotiated by the backend and the frontend.
+    pub fn is_valid(&self, mem: &GuestRam, features: u64) -> bool {
+        self.vring.is_valid(mem, features)
     }
 }
@@ -1,18 +1,20 @@
 use super::{
-    checked_offset_mem, ElemIovec, Element, VringOps, INVALID_VECTOR_NUM, VIRTQ_DESC_F_INDIRECT,
-    VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+    ElemIovec, Element, VringOps, INVALID_VECTOR_NUM, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT,
+    VIRTQ_DESC_F_WRITE,
 };
 use crate::device::virtio::{
     report_virtio_error, virtio_has_feature, VirtioInterrupt, VIRTIO_F_RING_EVENT_IDX,
 };
+use crate::device::{StateReader, StateWriter};
+use crate::mm::GuestRam;
 use alloc::format;
 use alloc::sync::Arc;
-use core::cmp::{max, min, Ordering};
+use core::cmp::min;
 use core::mem::size_of;
 use core::num::Wrapping;
 use core::ops::Deref;
 use core::ops::DerefMut;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{fence, AtomicBool, Ordering as MemOrdering};
 use hypercraft::{HyperError, HyperResult as Result, VirtioError};
 use pci::util::byte_code::ByteCode;
 
@@ -130,6 +132,44 @@ impl QueueConfig {
         *self = Self::new(self.max_size);
     }
 
+    /// Append the guest-visible configuration to `state`, the host address cache is rebuilt on
+    /// restore.
+    pub fn save(&self, state: &mut StateWriter) {
+        state
+            .u64(self.desc_table)
+            .u64(self.avail_ring)
+            .u64(self.used_ring)
+            .u16(self.size)
+            .bool(self.ready)
+            .u16(self.vector)
+            .u16(self.next_avail.0)
+            .u16(self.next_used.0)
+            .u16(self.last_signal_used.0)
+            .bool(self.signal_used_valid);
+    }
+
+    /// Read a configuration appended by [`QueueConfig::save`], for a queue of `max_size`.
+    pub fn load(state: &mut StateReader, max_size: u16) -> Result<Self> {
+        let config = QueueConfig {
+            desc_table: state.u64()?,
+            avail_ring: state.u64()?,
+            used_ring: state.u64()?,
+            addr_cache: VirtioAddrCache::default(),
+            max_size,
+            size: state.u16()?,
+            ready: state.bool()?,
+            vector: state.u16()?,
+            next_avail: Wrapping(state.u16()?),
+            next_used: Wrapping(state.u16()?),
+            last_signal_used: Wrapping(state.u16()?),
+            signal_used_valid: state.bool()?,
+        };
+        if config.size > max_size {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(config)
+    }
+
     pub fn set_addr_cache(
         &mut self,
         interrupt_cb: Arc<VirtioInterrupt>,
@@ -162,8 +202,8 @@ struct SplitVringFlagsIdx {
 impl ByteCode for SplitVringFlagsIdx {}
 
 struct DescInfo {
-    /// The host virtual address of the descriptor table.
-    table_host: u64,
+    /// The guest physical address of the descriptor table.
+    table: u64,
     /// The size of the descriptor table.
     size: u16,
     /// The index of the current descriptor table.
@@ -187,33 +227,62 @@ pub struct SplitVringDesc {
 }
 
 impl SplitVringDesc {
-    /// Create a descriptor of split vring.
+    /// Read a descriptor of split vring from guest memory.
     ///
     /// # Arguments
     ///
+    /// * `mem` - The RAM the vring belongs to.
     /// * `desc_table` - Guest address of virtqueue descriptor table.
     /// * `queue_size` - Size of virtqueue.
     /// * `index` - Index of descriptor in the virqueue descriptor table.
-    fn new(desc_table_host: u64, queue_size: u16, index: u16) -> Result<Self> {
-        let desc_addr = desc_table_host
+    fn new(mem: &GuestRam, desc_table: u64, queue_size: u16, index: u16) -> Result<Self> {
+        if index >= queue_size {
+            return Err(HyperError::VirtioError(VirtioError::Other(format!(
+                "descriptor index {} out of a table of {}",
+                index, queue_size
+            ))));
+        }
+        let desc_addr = desc_table
             .checked_add(u64::from(index) * DESCRIPTOR_LEN)
             .ok_or_else(|| {
                 HyperError::VirtioError(VirtioError::AddressOverflow(
                     "creating a descriptor",
-                    desc_table_host,
+                    desc_table,
                     u64::from(index) * DESCRIPTOR_LEN,
                 ))
             })?;
-        Ok(SplitVringDesc {
-            addr: desc_addr,
-            len: 0,
-            flags: 0,
-            next: 0,
-        })
+        let desc: SplitVringDesc = mem.read_obj(desc_addr)?;
+        if desc.is_valid(mem, queue_size) {
+            Ok(desc)
+        } else {
+            Err(HyperError::VirtioError(VirtioError::Other(format!(
+                "invalid descriptor {} at {:#x}",
+                index, desc_addr
+            ))))
+        }
     }
 
     /// Return true if the descriptor is valid.
-    fn is_valid(&self, queue_size: u16) -> bool {
+    fn is_valid(&self, mem: &GuestRam, queue_size: u16) -> bool {
+        if self.len == 0 {
+            error!("Zero sized buffers are not allowed");
+            return false;
+        }
+        if self.has_next() && self.next >= queue_size {
+            error!(
+                "The next index {} exceed queue size {}",
+                self.next, queue_size
+            );
+            return false;
+        }
+        if !mem.contains(self.addr, u64::from(self.len)) {
+            error!(
+                "The buffer {:#x}..{:#x} is not in guest RAM",
+                self.addr,
+                self.addr.wrapping_add(u64::from(self.len))
+            );
+            return false;
+        }
         true
     }
 
@@ -223,8 +292,13 @@ impl SplitVringDesc {
     }
 
     /// Get the next descriptor in descriptor chain.
-    fn next_desc(desc_table_host: u64, queue_size: u16, index: u16) -> Result<SplitVringDesc> {
-        SplitVringDesc::new(desc_table_host, queue_size, index)
+    fn next_desc(
+        mem: &GuestRam,
+        desc_table: u64,
+        queue_size: u16,
+        index: u16,
+    ) -> Result<SplitVringDesc> {
+        SplitVringDesc::new(mem, desc_table, queue_size, index)
     }
 
     /// Check whether this descriptor is write-only or read-only.
@@ -261,16 +335,103 @@ impl SplitVringDesc {
     }
 
     /// Get element from descriptor chain.
-    fn get_element(desc_info: &DescInfo, elem: &mut Element) -> Result<()> {
-        let mut desc_table_host = desc_info.table_host;
+    fn get_element(mem: &GuestRam, desc_info: &DescInfo, elem: &mut Element) -> Result<()> {
+        let mut desc_table = desc_info.table;
         let mut desc_size = desc_info.size;
         let mut desc = desc_info.desc;
         elem.index = desc_info.index;
+        // Descriptors walked in the current table, a chain longer than its table loops.
         let mut queue_size = desc_size;
+        // The range of the descriptor table of the vring, which no indirect table may overlap.
+        let ring_table = (
+            desc_info.table,
+            desc_info.table + u64::from(desc_info.size) * DESCRIPTOR_LEN,
+        );
         let mut indirect: bool = false;
         let mut write_elem_count: u32 = 0;
         let mut desc_total_len: u64 = 0;
 
+        loop {
+            if queue_size == 0 {
+                error!("The descriptor chain {} loops", desc_info.index);
+                return Err(HyperError::VirtioError(VirtioError::Other(format!(
+                    "descriptor chain {} loops",
+                    desc_info.index
+                ))));
+            }
+            queue_size -= 1;
+
+            if desc.is_indirect_desc() {
+                if indirect || !desc.is_valid_indirect_desc() {
+                    return Err(HyperError::VirtioError(VirtioError::Other(format!(
+                        "invalid indirect descriptor in chain {}",
+                        desc_info.index
+                    ))));
+                }
+                // The descriptor is in guest RAM, the end of its table does not overflow.
+                let table_end = desc.addr + u64::from(desc.len);
+                if SplitVring::is_overlap(desc.addr, table_end, ring_table.0, ring_table.1) {
+                    error!(
+                        "The indirect table {:#x}..{:#x} overlaps the descriptor table",
+                        desc.addr, table_end
+                    );
+                    return Err(HyperError::VirtioError(VirtioError::Other(format!(
+                        "indirect table of chain {} in the descriptor table",
+                        desc_info.index
+                    ))));
+                }
+                indirect = true;
+                desc_table = desc.addr;
+                desc_size = desc.get_desc_num();
+                queue_size = desc_size;
+                desc = SplitVringDesc::new(mem, desc_table, desc_size, 0)?;
+                continue;
+            }
+
+            // No chain, an indirect table included, is longer than the queue.
+            if elem.desc_num >= desc_info.size {
+                error!(
+                    "The descriptor chain {} is longer than the queue",
+                    desc_info.index
+                );
+                return Err(HyperError::VirtioError(VirtioError::Other(format!(
+                    "descriptor chain {} longer than {} descriptors",
+                    desc_info.index, desc_info.size
+                ))));
+            }
+            desc_total_len += u64::from(desc.len);
+            if desc_total_len > DESC_CHAIN_MAX_TOTAL_LEN {
+                error!("Find a too long descriptor chain {}", desc_total_len);
+                return Err(HyperError::VirtioError(VirtioError::Other(format!(
+                    "descriptor chain {} of {} bytes",
+                    desc_info.index, desc_total_len
+                ))));
+            }
+
+            let iovec = ElemIovec {
+                addr: desc.addr,
+                len: desc.len,
+            };
+            if desc.write_only() {
+                elem.in_iovec.push(iovec);
+                write_elem_count += 1;
+            } else {
+                if write_elem_count > 0 {
+                    error!("Invalid order of the descriptor specification");
+                    return Err(HyperError::VirtioError(VirtioError::Other(format!(
+                        "readable descriptor after a writable one in chain {}",
+                        desc_info.index
+                    ))));
+                }
+                elem.out_iovec.push(iovec);
+            }
+            elem.desc_num += 1;
+
+            if !desc.has_next() {
+                break;
+            }
+            desc = SplitVringDesc::next_desc(mem, desc_table, desc_size, desc.next)?;
+        }
         Ok(())
     }
 }
@@ -313,92 +474,151 @@ impl SplitVring {
     }
 
     /// Get the flags and idx of the available ring from guest memory.
-    fn get_avail_flags_idx(&self) -> Result<SplitVringFlagsIdx> {
-        Ok(SplitVringFlagsIdx { flags: 0, idx: 0 })
+    fn get_avail_flags_idx(&self, mem: &GuestRam) -> Result<SplitVringFlagsIdx> {
+        mem.read_obj(self.avail_ring)
     }
 
     /// Get the idx of the available ring from guest memory.
-    fn get_avail_idx(&self) -> Result<u16> {
-        let flags_idx = self.get_avail_flags_idx()?;
+    fn get_avail_idx(&self, mem: &GuestRam) -> Result<u16> {
+        let flags_idx = self.get_avail_flags_idx(mem)?;
         Ok(flags_idx.idx)
     }
 
     /// Get the flags of the available ring from guest memory.
-    fn get_avail_flags(&self) -> Result<u16> {
-        let flags_idx = self.get_avail_flags_idx()?;
+    fn get_avail_flags(&self, mem: &GuestRam) -> Result<u16> {
+        let flags_idx = self.get_avail_flags_idx(mem)?;
         Ok(flags_idx.flags)
     }
 
     /// Get the flags and idx of the used ring from guest memory.
-    fn get_used_flags_idx(&self) -> Result<SplitVringFlagsIdx> {
-        Ok(SplitVringFlagsIdx { flags: 0, idx: 0 })
+    fn get_used_flags_idx(&self, mem: &GuestRam) -> Result<SplitVringFlagsIdx> {
+        mem.read_obj(self.used_ring)
     }
 
     /// Get the index of the used ring from guest memory.
-    fn get_used_idx(&self) -> Result<u16> {
-        let flag_idx = self.get_used_flags_idx()?;
+    fn get_used_idx(&self, mem: &GuestRam) -> Result<u16> {
+        let flag_idx = self.get_used_flags_idx(mem)?;
         Ok(flag_idx.idx)
     }
 
     /// Set the used flags to suppress virtqueue notification or not
-    fn set_used_flags(&self, suppress: bool) -> Result<()> {
-        let mut flags_idx = self.get_used_flags_idx()?;
+    fn set_used_flags(&self, mem: &GuestRam, suppress: bool) -> Result<()> {
+        let mut flags_idx = self.get_used_flags_idx(mem)?;
 
         if suppress {
             flags_idx.flags |= VRING_USED_F_NO_NOTIFY;
         } else {
             flags_idx.flags &= !VRING_USED_F_NO_NOTIFY;
         }
-        Ok(())
+        mem.write_obj(self.used_ring, &flags_idx.flags)
     }
 
     /// Set the avail idx to the field of the event index for the available ring.
-    fn set_avail_event(&self, event_idx: u16) -> Result<()> {
+    fn set_avail_event(&self, mem: &GuestRam, event_idx: u16) -> Result<()> {
         let avail_event_offset =
             VRING_FLAGS_AND_IDX_LEN + USEDELEM_LEN * u64::from(self.actual_size());
+        mem.write_obj(self.used_ring + avail_event_offset, &event_idx)?;
+        // The device must see the notifications of the buffers made available from now on.
+        fence(MemOrdering::SeqCst);
         Ok(())
     }
 
     /// Get the event index of the used ring from guest memory.
-    fn get_used_event(&self) -> Result<u16> {
-        Ok(0)
+    fn get_used_event(&self, mem: &GuestRam) -> Result<u16> {
+        let used_event_offset =
+            VRING_FLAGS_AND_IDX_LEN + AVAILELEM_LEN * u64::from(self.actual_size());
+        mem.read_obj(self.avail_ring + used_event_offset)
     }
 
     /// Return true if VRING_AVAIL_F_NO_INTERRUPT is set.
-    fn is_avail_ring_no_interrupt(&self) -> bool {
-        true
+    fn is_avail_ring_no_interrupt(&self, mem: &GuestRam) -> bool {
+        match self.get_avail_flags(mem) {
+            Ok(flags) => (flags & VRING_AVAIL_F_NO_INTERRUPT) != 0,
+            Err(e) => {
+                warn!("Failed to get the available ring flags: {:?}", e);
+                false
+            }
+        }
     }
 
     /// Return true if it's required to trigger interrupt for the used vring.
-    fn used_ring_need_event(&mut self) -> bool {
-        true
+    fn used_ring_need_event(&mut self, mem: &GuestRam) -> bool {
+        let old = self.last_signal_used;
+        let new = self.next_used;
+        let valid = self.signal_used_valid;
+        self.signal_used_valid = true;
+        self.last_signal_used = new;
+        if !valid {
+            return true;
+        }
+        let used_event_idx = match self.get_used_event(mem) {
+            Ok(idx) => Wrapping(idx),
+            Err(e) => {
+                warn!("Failed to get the used event index: {:?}", e);
+                return true;
+            }
+        };
+        // Whether `used_event` is in `old..new`, see vring_need_event() of the Virtio Spec.
+        (new - used_event_idx - Wrapping(1)) < (new - old)
     }
 
     fn is_overlap(start1: u64, end1: u64, start2: u64, end2: u64) -> bool {
         !(start1 >= end2 || start2 >= end1)
     }
 
-    fn is_invalid_memory(&self, actual_size: u64) -> bool {
-        true
+    fn is_invalid_memory(&self, mem: &GuestRam, features: u64) -> bool {
+        let (desc, avail, used) = (self.desc_table, self.avail_ring, self.used_ring);
+        let desc_size = self.get_desc_size();
+        let avail_size = self.get_avail_size(features);
+        let used_size = self.get_used_size(features);
+        if !mem.contains(desc, desc_size)
+            || !mem.contains(avail, avail_size)
+            || !mem.contains(used, used_size)
+        {
+            error!(
+                "The vring is not in guest RAM, desc {:#x} avail {:#x} used {:#x}",
+                desc, avail, used
+            );
+            return true;
+        }
+        if desc % 16 != 0 || avail % 2 != 0 || used % 4 != 0 {
+            error!(
+                "The vring is misaligned, desc {:#x} avail {:#x} used {:#x}",
+                desc, avail, used
+            );
+            return true;
+        }
+        // The ranges are in guest RAM, their ends do not overflow.
+        let (desc_end, avail_end, used_end) =
+            (desc + desc_size, avail + avail_size, used + used_size);
+        if Self::is_overlap(desc, desc_end, avail, avail_end)
+            || Self::is_overlap(desc, desc_end, used, used_end)
+            || Self::is_overlap(avail, avail_end, used, used_end)
+        {
+            error!("The parts of the vring overlap");
+            return true;
+        }
+        false
     }
 
-    fn get_desc_info(&mut self, next_avail: Wrapping<u16>, features: u64) -> Result<DescInfo> {
+    fn get_desc_info(
+        &mut self,
+        mem: &GuestRam,
+        next_avail: Wrapping<u16>,
+        features: u64,
+    ) -> Result<DescInfo> {
         let index_offset =
             VRING_FLAGS_AND_IDX_LEN + AVAILELEM_LEN * u64::from(next_avail.0 % self.actual_size());
-        // The GPA of avail_ring_host with avail table length has been checked in
+        // The GPA of avail_ring with avail table length has been checked in
         // is_invalid_memory which must not be overflowed.
-        let desc_index_addr = self.addr_cache.avail_ring_host + index_offset;
-        let desc_index = 0;
+        let desc_index_addr = self.avail_ring + index_offset;
+        let desc_index: u16 = mem.read_obj(desc_index_addr)?;
 
-        let desc = SplitVringDesc::new(
-            self.addr_cache.desc_table_host,
-            self.actual_size(),
-            desc_index,
-        )?;
+        let desc = SplitVringDesc::new(mem, self.desc_table, self.actual_size(), desc_index)?;
 
         // Suppress queue notification related to current processing desc chain.
         if virtio_has_feature(features, VIRTIO_F_RING_EVENT_IDX) {
-            self.set_avail_event((next_avail + Wrapping(1)).0)
+            self.set_avail_event(mem, (next_avail + Wrapping(1)).0)
                 .or_else(|_| {
                     Err(HyperError::VirtioError(VirtioError::Other(format!(
                         "Failed to set avail event for popping avail ring"
@@ -407,21 +627,27 @@ impl SplitVring {
         }
 
         Ok(DescInfo {
-            table_host: self.addr_cache.desc_table_host,
+            table: self.desc_table,
             size: self.actual_size(),
             index: desc_index,
             desc,
         })
     }
 
-    fn get_vring_element(&mut self, features: u64, elem: &mut Element) -> Result<()> {
-        let desc_info = self.get_desc_info(self.next_avail, features)?;
+    fn get_vring_element(
+        &mut self,
+        mem: &GuestRam,
+        features: u64,
+        elem: &mut Element,
+    ) -> Result<()> {
+        let desc_info = self.get_desc_info(mem, self.next_avail, features)?;
 
-        SplitVringDesc::get_element(&desc_info, elem).or_else(|_| {
-            Err(HyperError::VirtioError(VirtioError::Other(format!(
+        SplitVringDesc::get_element(mem, &desc_info, elem).map_err(|err| {
+            error!("Invalid descriptor chain {}: {:?}", desc_info.index, err);
+            HyperError::VirtioError(VirtioError::Other(format!(
                 "Failed to get element from descriptor chain {}, table addr: 0x{:X}, size: {}",
-                desc_info.index, desc_info.table_host, desc_info.size,
-            ))))
+                desc_info.index, desc_info.table, desc_info.size,
+            )))
         })?;
         self.next_avail += Wrapping(1);
 
@@ -434,8 +660,7 @@ impl VringOps for SplitVring {
         self.ready
     }
 
-    fn is_valid(&self) -> bool {
-        let size = u64::from(self.actual_size());
+    fn is_valid(&self, mem: &GuestRam, features: u64) -> bool {
         if !self.ready {
             error!("The configuration of vring is not ready\n");
             false
@@ -447,13 +672,19 @@ impl VringOps for SplitVring {
             );
             false
         } else {
-            !self.is_invalid_memory(size)
+            !self.is_invalid_memory(mem, features)
         }
     }
 
-    fn pop_avail(&mut self, features: u64) -> Result<Element> {
+    fn pop_avail(&mut self, mem: &GuestRam, features: u64) -> Result<Element> {
         let mut element = Element::new(0);
+        if !self.is_enabled() || self.avail_ring_len(mem)? == 0 {
+            return Ok(element);
+        }
+        // The descriptors are read after the index which made them available.
+        fence(MemOrdering::Acquire);
 
+        self.get_vring_element(mem, features, &mut element)?;
         Ok(element)
     }
 
@@ -461,16 +692,72 @@ impl VringOps for SplitVring {
         self.next_avail -= Wrapping(1);
     }
 
-    fn add_used(&mut self, index: u16, len: u32) -> Result<()> {
-        Ok(())
+    fn skip_avail(&mut self, mem: &GuestRam, features: u64) -> Result<u16> {
+        let index_offset = VRING_FLAGS_AND_IDX_LEN
+            + AVAILELEM_LEN * u64::from(self.next_avail.0 % self.actual_size());
+        let desc_index: u16 = mem.read_obj(self.avail_ring + index_offset)?;
+        self.next_avail += Wrapping(1);
+        // As if popped, the driver notifies the next chains.
+        if virtio_has_feature(features, VIRTIO_F_RING_EVENT_IDX) {
+            self.set_avail_event(mem, self.next_avail.0)?;
+        }
+        Ok(desc_index)
     }
 
-    fn should_notify(&mut self, features: u64) -> bool {
-        true
+    fn add_used(&mut self, mem: &GuestRam, index: u16, len: u32) -> Result<()> {
+        self.add_used_batch(mem, &[(index, len)])
     }
 
-    fn suppress_queue_notify(&mut self, features: u64, suppress: bool) -> Result<()> {
-        Ok(())
+    fn add_used_batch(&mut self, mem: &GuestRam, used: &[(u16, u32)]) -> Result<()> {
+        for &(index, len) in used {
+            if index >= self.actual_size() {
+                return Err(HyperError::VirtioError(VirtioError::Other(format!(
+                    "used index {} out of a queue of {}",
+                    index,
+                    self.actual_size()
+                ))));
+            }
+
+            let next_used = u64::from(self.next_used.0 % self.actual_size());
+            let used_elem_addr =
+                self.used_ring + VRING_FLAGS_AND_IDX_LEN + next_used * USEDELEM_LEN;
+            let used_elem = UsedElem {
+                id: u32::from(index),
+                len,
+            };
+            mem.write_obj(used_elem_addr, &used_elem)?;
+
+            self.next_used += Wrapping(1);
+        }
+        // The driver must see the elements before the index which publishes them.
+        fence(MemOrdering::Release);
+        mem.write_obj(self.used_ring + VRING_IDX_POSITION, &self.next_used.0)
+    }
+
+    fn should_notify(&mut self, mem: &GuestRam, features: u64) -> bool {
+        // The used index is published before the flags or the event index are read.
+        fence(MemOrdering::SeqCst);
+        if virtio_has_feature(features, VIRTIO_F_RING_EVENT_IDX) {
+            self.used_ring_need_event(mem)
+        } else {
+            !self.is_avail_ring_no_interrupt(mem)
+        }
+    }
+
+    fn suppress_queue_notify(
+        &mut self,
+        mem: &GuestRam,
+        features: u64,
+        suppress: bool,
+    ) -> Result<()> {
+        if virtio_has_feature(features, VIRTIO_F_RING_EVENT_IDX) {
+            if !suppress {
+                self.set_avail_event(mem, self.get_avail_idx(mem)?)?;
+            }
+            Ok(())
+        } else {
+            self.set_used_flags(mem, suppress)
+        }
     }
 
     fn actual_size(&self) -> u16 {
@@ -484,25 +771,312 @@ impl VringOps for SplitVring {
     }
 
     /// The number of descriptor chains in the available ring.
-    fn avail_ring_len(&mut self) -> Result<u16> {
-        let avail_idx = self.get_avail_idx().map(Wrapping)?;
-
-        Ok((avail_idx - self.next_avail).0)
+    fn avail_ring_len(&mut self, mem: &GuestRam) -> Result<u16> {
+        let avail_idx = self.get_avail_idx(mem).map(Wrapping)?;
+        let len = (avail_idx - self.next_avail).0;
+        if len > self.actual_size() {
+            return Err(HyperError::VirtioError(VirtioError::Other(format!(
+                "{} descriptor chains available in a queue of {}",
+                len,
+                self.actual_size()
+            ))));
+        }
+        Ok(len)
     }
 
-    fn get_avail_idx(&self) -> Result<u16> {
-        SplitVring::get_avail_idx(self)
+    fn get_avail_idx(&self, mem: &GuestRam) -> Result<u16> {
+        SplitVring::get_avail_idx(self, mem)
     }
 
-    fn get_used_idx(&self) -> Result<u16> {
-        SplitVring::get_used_idx(self)
+    fn get_used_idx(&self, mem: &GuestRam) -> Result<u16> {
+        SplitVring::get_used_idx(self, mem)
     }
 
     fn get_cache(&self) -> &Option<u32> {
         &None
     }
 
-    fn get_avail_bytes(&mut self, max_size: usize, is_in: bool) -> Result<usize> {
-        Ok(0)
+    fn get_avail_bytes(&mut self, mem: &GuestRam, max_size: usize, is_in: bool) -> Result<usize> {
+        // Walk the available chains on a copy of the vring, which leaves them available.
+        let mut vring = *self;
+        let mut avail_bytes = 0;
+        while avail_bytes < max_size && vring.avail_ring_len(mem)? != 0 {
+            fence(MemOrdering::Acquire);
+            let mut elem = Element::new(0);
+            vring.get_vring_element(mem, 0, &mut elem)?;
+            let iovec = if is_in {
+                &elem.in_iovec
+            } else {
+                &elem.out_iovec
+            };
+            avail_bytes += Element::iovec_size(iovec) as usize;
+        }
+        Ok(min(avail_bytes, max_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::virtio::{Queue, VirtioInterruptType, VIRTIO_F_VERSION_1};
+    use alloc::boxed::Box;
+    use core::sync::atomic::AtomicUsize;
+
+    const RAM_SIZE: usize = 0x10000;
+    const QUEUE_SIZE: u16 = 8;
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+    /// Where the tests put their indirect tables, and their buffers.
+    const INDIRECT_TABLE: u64 = 0x4000;
+    const BUF: u64 = 0x8000;
+
+    /// A ready queue of `QUEUE_SIZE` in a RAM read and written by the driver, the tests.
+    fn new_vring() -> (GuestRam, SplitVring) {
+        let mem = GuestRam::host(RAM_SIZE);
+        let mut config = QueueConfig::new(QUEUE_SIZE);
+        config.desc_table = DESC_TABLE;
+        config.avail_ring = AVAIL_RING;
+        config.used_ring = USED_RING;
+        config.ready = true;
+        let vring = SplitVring::new(config);
+        assert!(vring.is_valid(&mem, 0));
+        (mem, vring)
+    }
+
+    fn set_desc(mem: &GuestRam, table: u64, index: u16, desc: SplitVringDesc) {
+        let addr = table + u64::from(index) * DESCRIPTOR_LEN;
+        mem.write_obj(addr, &desc).unwrap();
+    }
+
+    fn desc(addr: u64, len: u32, flags: u16, next: u16) -> SplitVringDesc {
+        SplitVringDesc {
+            addr,
+            len,
+            flags,
+            next,
+        }
+    }
+
+    /// Make the chains of `heads` available, after those already made available.
+    fn make_avail(mem: &GuestRam, heads: &[u16]) {
+        let idx: u16 = mem.read_obj(AVAIL_RING + VRING_IDX_POSITION).unwrap();
+        for (i, &head) in heads.iter().enumerate() {
+            let slot = u64::from(idx.wrapping_add(i as u16) % QUEUE_SIZE);
+            let addr = AVAIL_RING + VRING_FLAGS_AND_IDX_LEN + slot * AVAILELEM_LEN;
+            mem.write_obj(addr, &head).unwrap();
+        }
+        let idx = idx.wrapping_add(heads.len() as u16);
+        mem.write_obj(AVAIL_RING + VRING_IDX_POSITION, &idx)
+            .unwrap();
+    }
+
+    /// Pop a chain expected to be rejected, which stays at the head of the available ring.
+    fn assert_rejected(mem: &GuestRam, vring: &mut SplitVring) {
+        let next_avail = vring.next_avail;
+        assert!(vring.pop_avail(mem, 0).is_err());
+        assert_eq!(vring.next_avail, next_avail);
+        assert!(vring.pop_avail(mem, 0).is_err());
+    }
+
+    /// Skip the rejected chain at `head`, then pop the valid one made available after it.
+    fn skip_rejected(mem: &GuestRam, vring: &mut SplitVring, head: u16, valid: u16) {
+        assert_eq!(vring.skip_avail(mem, 0).unwrap(), head);
+        let elem = vring.pop_avail(mem, 0).unwrap();
+        assert_eq!(elem.index, valid);
+        assert_eq!(elem.desc_num, 1);
+    }
+
+    #[test]
+    fn test_valid_chain() {
+        let (mem, mut vring) = new_vring();
+        set_desc(&mem, DESC_TABLE, 0, desc(BUF, 16, VIRTQ_DESC_F_NEXT, 1));
+        set_desc(
+            &mem,
+            DESC_TABLE,
+            1,
+            desc(BUF + 0x100, 512, VIRTQ_DESC_F_WRITE, 0),
+        );
+        make_avail(&mem, &[0]);
+
+        let elem = vring.pop_avail(&mem, 0).unwrap();
+        assert_eq!((elem.index, elem.desc_num), (0, 2));
+        assert_eq!(elem.out_iovec.len(), 1);
+        assert_eq!(
+            (elem.in_iovec[0].addr, elem.in_iovec[0].len),
+            (BUF + 0x100, 512)
+        );
+        // Nothing more is available.
+        assert_eq!(vring.pop_avail(&mem, 0).unwrap().desc_num, 0);
+
+        vring.add_used(&mem, elem.index, 512).unwrap();
+        assert_eq!(vring.get_used_idx(&mem).unwrap(), 1);
+        let used: UsedElem = mem.read_obj(USED_RING + VRING_FLAGS_AND_IDX_LEN).unwrap();
+        assert_eq!((used.id, used.len), (0, 512));
+        assert!(vring.add_used(&mem, QUEUE_SIZE, 0).is_err());
+    }
+
+    #[test]
+    fn test_loops() {
+        let (mem, mut vring) = new_vring();
+        // A descriptor chained to itself, and two chained to each other.
+        set_desc(&mem, DESC_TABLE, 0, desc(BUF, 16, VIRTQ_DESC_F_NEXT, 0));
+        set_desc(&mem, DESC_TABLE, 1, desc(BUF, 16, VIRTQ_DESC_F_NEXT, 2));
+        set_desc(&mem, DESC_TABLE, 2, desc(BUF, 16, VIRTQ_DESC_F_NEXT, 1));
+        set_desc(&mem, DESC_TABLE, 3, desc(BUF, 16, 0, 0));
+        make_avail(&mem, &[0, 3, 1, 3]);
+
+        assert_rejected(&mem, &mut vring);
+        skip_rejected(&mem, &mut vring, 0, 3);
+        assert_rejected(&mem, &mut vring);
+        skip_rejected(&mem, &mut vring, 1, 3);
+    }
+
+    #[test]
+    fn test_overlong_chains() {
+        let (mem, mut vring) = new_vring();
+        // Indirect tables of QUEUE_SIZE and QUEUE_SIZE + 1 descriptors, all chained.
+        let long = INDIRECT_TABLE + 0x400;
+        for (table, len) in [(INDIRECT_TABLE, QUEUE_SIZE), (long, QUEUE_SIZE + 1)] {
+            for i in 0..len - 1 {
+                set_desc(&mem, table, i, desc(BUF, 16, VIRTQ_DESC_F_NEXT, i + 1));
+            }
+            set_desc(&mem, table, len - 1, desc(BUF, 16, 0, 0));
+            let table_len = u32::from(len) * DESCRIPTOR_LEN as u32;
+            set_desc(
+                &mem,
+                DESC_TABLE,
+                len - QUEUE_SIZE,
+                desc(table, table_len, VIRTQ_DESC_F_INDIRECT, 0),
+            );
+        }
+        set_desc(&mem, DESC_TABLE, 2, desc(BUF, 16, 0, 0));
+        make_avail(&mem, &[0, 1, 2]);
+
+        // As long as the queue, the longest chain a driver may make.
+        let elem = vring.pop_avail(&mem, 0).unwrap();
+        assert_eq!(elem.desc_num, QUEUE_SIZE);
+        assert_rejected(&mem, &mut vring);
+        skip_rejected(&mem, &mut vring, 1, 2);
+    }
+
+    #[test]
+    fn test_out_of_ram() {
+        let (mem, mut vring) = new_vring();
+        let end = RAM_SIZE as u64;
+        let rejected = [
+            // Across the end of RAM, and wrapping around the address space.
+            desc(end - 8, 16, 0, 0),
+            desc(u64::MAX - 7, 16, 0, 0),
+            // Empty, and chained past the end of the table.
+            desc(BUF, 0, 0, 0),
+            desc(BUF, 16, VIRTQ_DESC_F_NEXT, QUEUE_SIZE),
+            // A readable descriptor after a writable one.
+            desc(BUF, 16, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 5),
+        ];
+        for (i, &d) in rejected.iter().enumerate() {
+            set_desc(&mem, DESC_TABLE, i as u16, d);
+        }
+        set_desc(&mem, DESC_TABLE, 5, desc(BUF, 16, 0, 0));
+        set_desc(
+            &mem,
+            DESC_TABLE,
+            6,
+            desc(end - 16, 16, VIRTQ_DESC_F_WRITE, 0),
+        );
+        for head in 0..rejected.len() as u16 {
+            make_avail(&mem, &[head, 6]);
+            assert_rejected(&mem, &mut vring);
+            skip_rejected(&mem, &mut vring, head, 6);
+        }
+
+        // A head out of the table.
+        make_avail(&mem, &[QUEUE_SIZE, 6]);
+        assert_rejected(&mem, &mut vring);
+        skip_rejected(&mem, &mut vring, QUEUE_SIZE, 6);
+    }
+
+    #[test]
+    fn test_indirect_tables() {
+        let (mem, mut vring) = new_vring();
+        let table_len = u32::from(QUEUE_SIZE) * DESCRIPTOR_LEN as u32;
+        set_desc(&mem, INDIRECT_TABLE, 0, desc(BUF, 16, 0, 0));
+        set_desc(
+            &mem,
+            INDIRECT_TABLE + 0x100,
+            0,
+            desc(INDIRECT_TABLE, 16, VIRTQ_DESC_F_INDIRECT, 0),
+        );
+        let rejected = [
+            // The descriptor table of the queue itself, and its last descriptor alone, which is
+            // valid.
+            desc(DESC_TABLE, table_len, VIRTQ_DESC_F_INDIRECT, 0),
+            desc(DESC_TABLE + 0x70, 16, VIRTQ_DESC_F_INDIRECT, 0),
+            // An indirect table in an indirect table.
+            desc(INDIRECT_TABLE + 0x100, 16, VIRTQ_DESC_F_INDIRECT, 0),
+            // Not a whole number of descriptors, and chained to a next descriptor.
+            desc(INDIRECT_TABLE, 24, VIRTQ_DESC_F_INDIRECT, 0),
+            desc(
+                INDIRECT_TABLE,
+                16,
+                VIRTQ_DESC_F_INDIRECT | VIRTQ_DESC_F_NEXT,
+                7,
+            ),
+        ];
+        for (i, &d) in rejected.iter().enumerate() {
+            set_desc(&mem, DESC_TABLE, i as u16, d);
+        }
+        set_desc(
+            &mem,
+            DESC_TABLE,
+            6,
+            desc(INDIRECT_TABLE, 16, VIRTQ_DESC_F_INDIRECT, 0),
+        );
+        set_desc(&mem, DESC_TABLE, 7, desc(BUF, 16, 0, 0));
+        for head in 0..rejected.len() as u16 {
+            make_avail(&mem, &[head, 6]);
+            assert_rejected(&mem, &mut vring);
+            skip_rejected(&mem, &mut vring, head, 6);
+        }
+    }
+
+    #[test]
+    fn test_avail_ring_overrun() {
+        let (mem, mut vring) = new_vring();
+        set_desc(&mem, DESC_TABLE, 0, desc(BUF, 16, 0, 0));
+        // More chains available than the queue holds.
+        mem.write_obj(AVAIL_RING + VRING_IDX_POSITION, &(QUEUE_SIZE + 1))
+            .unwrap();
+        assert!(vring.avail_ring_len(&mem).is_err());
+        assert!(vring.pop_avail(&mem, 0).is_err());
+        assert!(vring.get_avail_bytes(&mem, 4096, false).is_err());
+    }
+
+    #[test]
+    fn test_broken_queue() {
+        let (mem, mut vring) = new_vring();
+        set_desc(&mem, DESC_TABLE, 0, desc(BUF, 16, VIRTQ_DESC_F_NEXT, 0));
+        make_avail(&mem, &[0]);
+
+        // What the devices do with a chain they cannot assemble: the device needs a reset,
+        // which the driver is told of by a configuration change interrupt.
+        let config_irqs = Arc::new(AtomicUsize::new(0));
+        let irqs = config_irqs.clone();
+        let interrupt_cb: Arc<VirtioInterrupt> = Arc::new(Box::new(
+            move |kind: &VirtioInterruptType, _queue: Option<&Queue>, _needs_reset: bool| {
+                if let VirtioInterruptType::Config = kind {
+                    irqs.fetch_add(1, MemOrdering::SeqCst);
+                }
+                Ok(())
+            },
+        ));
+        let broken = Arc::new(AtomicBool::new(false));
+        if vring.pop_avail(&mem, 0).is_err() {
+            report_virtio_error(interrupt_cb, 1 << VIRTIO_F_VERSION_1, &broken);
+        }
+        assert!(broken.load(MemOrdering::SeqCst));
+        assert_eq!(config_irqs.load(MemOrdering::SeqCst), 1);
+        // Nothing was used.
+        assert_eq!(vring.get_used_idx(&mem).unwrap(), 0);
     }
 }
@@ -1,21 +1,26 @@
 use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
 use alloc::fmt::format;
 use alloc::format;
 use alloc::string::String;
 use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
+use axconfig::SMP;
+use axhal::current_cpu_id;
 use core::any::Any;
 use core::cmp::{max, min};
 use core::mem::size_of;
-use core::sync::atomic::{AtomicU16, Ordering};
-use lazy_static::lazy_static;
-use spin::{mutex, rwlock::RwLock, Mutex};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
+use lock_stat::Mutex;
+use spin::mutex;
 use x86_64::registers::debug;
 
 use byteorder::{ByteOrder, LittleEndian};
 
 use crate::device::virtio::{
-    virtio_has_feature, Queue, VirtioBaseState, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
+    virtio_has_feature, worker, Queue, VirtioBaseState, VirtioDevice, VirtioInterrupt,
+    VirtioInterruptType,
 };
 use crate::device::virtio::{
     CONFIG_STATUS_ACKNOWLEDGE, CONFIG_STATUS_DRIVER, CONFIG_STATUS_DRIVER_OK, CONFIG_STATUS_FAILED,
@@ -24,12 +29,14 @@ use crate::device::virtio::{
     VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING, VIRTIO_TYPE_BLOCK, VIRTIO_TYPE_CONSOLE,
     VIRTIO_TYPE_FS, VIRTIO_TYPE_GPU, VIRTIO_TYPE_NET, VIRTIO_TYPE_SCSI,
 };
+use crate::device::{DeviceState, StateReader, StateWriter};
+use crate::mm::GuestRam;
 use hypercraft::{HyperError, HyperResult, MmioOps, PciError, PioOps, RegionOps, VirtioError};
 use pci::config::{
-    BarAllocTrait, RegionType, BAR_SPACE_UNMAPPED, DEVICE_ID, MINIMUM_BAR_SIZE_FOR_MMIO,
-    PCIE_CONFIG_SPACE_SIZE, PCI_SUBDEVICE_ID_QEMU, PCI_VENDOR_ID_REDHAT_QUMRANET, REG_SIZE,
-    REVISION_ID, STATUS, STATUS_INTERRUPT, SUBSYSTEM_ID, SUBSYSTEM_VENDOR_ID, SUB_CLASS_CODE,
-    VENDOR_ID,
+    bar_size, BarAllocTrait, RegionType, BAR_SPACE_UNMAPPED, DEVICE_ID, INTERRUPT_PIN,
+    MINIMUM_BAR_SIZE_FOR_MMIO, PCIE_CONFIG_SPACE_SIZE, PCI_SUBDEVICE_ID_QEMU,
+    PCI_VENDOR_ID_REDHAT_QUMRANET, REG_SIZE, REVISION_ID, STATUS, STATUS_INTERRUPT, SUBSYSTEM_ID,
+    SUBSYSTEM_VENDOR_ID, SUB_CLASS_CODE, VENDOR_ID,
 };
 use pci::offset_of;
 use pci::util::{
@@ -39,7 +46,7 @@ use pci::util::{
 use pci::{
     config::{PciConfig, PCI_CAP_ID_VNDR, PCI_CAP_VNDR_AND_NEXT_SIZE},
     init_msix, init_multifunction, le_write_u16, le_write_u32, AsAny, PciBus, PciDevBase,
-    PciDevOps,
+    PciDevOps, PciHost,
 };
 
 const VIRTIO_QUEUE_MAX: u32 = 1024;
@@ -47,6 +54,10 @@ const VIRTIO_QUEUE_MAX: u32 = 1024;
 const VIRTIO_PCI_VENDOR_ID: u16 = PCI_VENDOR_ID_REDHAT_QUMRANET;
 const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
 const VIRTIO_PCI_ABI_VERSION: u8 = 1;
+
+const VIRTIO_PCI_STATE_VERSION: u16 = 1;
+const PCI_HOST_STATE_VERSION: u16 = 1;
+
 const VIRTIO_PCI_CLASS_ID_NET: u16 = 0x0280;
 const VIRTIO_PCI_CLASS_ID_BLOCK: u16 = 0x0100;
 const VIRTIO_PCI_CLASS_ID_STORAGE_OTHER: u16 = 0x0180;
@@ -54,16 +65,14 @@ const VIRTIO_PCI_CLASS_ID_COMMUNICATION_OTHER: u16 = 0x0780;
 const VIRTIO_PCI_CLASS_ID_DISPLAY_VGA: u16 = 0x0300;
 const VIRTIO_PCI_CLASS_ID_OTHERS: u16 = 0x00ff;
 
-const VIRTIO_PCI_CAP_COMMON_OFFSET: u32 = 0x0;
 const VIRTIO_PCI_CAP_COMMON_LENGTH: u32 = 0x1000;
-const VIRTIO_PCI_CAP_ISR_OFFSET: u32 = 0x1000;
 const VIRTIO_PCI_CAP_ISR_LENGTH: u32 = 0x1000;
-const VIRTIO_PCI_CAP_DEVICE_OFFSET: u32 = 0x2000;
 const VIRTIO_PCI_CAP_DEVICE_LENGTH: u32 = 0x1000;
-const VIRTIO_PCI_CAP_NOTIFY_OFFSET: u32 = 0x3000;
-const VIRTIO_PCI_CAP_NOTIFY_LENGTH: u32 = 0x1000;
-const VIRTIO_PCI_CAP_NOTIFY_END: u32 = 0x4000;
 const VIRTIO_PCI_CAP_NOTIFY_OFF_MULTIPLIER: u32 = 4;
+/// Each region of the memory BAR starts on its own page.
+const VIRTIO_PCI_REGION_ALIGN: u32 = MINIMUM_BAR_SIZE_FOR_MMIO as u32;
+/// Length of the common configuration structure, up to `queue_device`.
+const VIRTIO_PCI_COMMON_CFG_LEN: u32 = COMMON_Q_USEDHI_REG as u32 + 4;
 
 const VIRTIO_PCI_BAR_MAX: u8 = 3;
 const VIRTIO_PCI_MSIX_BAR_IDX: u8 = 1;
@@ -113,8 +122,19 @@ const COMMON_Q_USEDHI_REG: u64 = 0x34;
 ///   1: select feature bits 32 to 63.
 const MAX_FEATURES_SELECT_NUM: u32 = 2;
 
-lazy_static! {
-    pub static ref GLOBAL_VIRTIO_PCI_CFG_REQ: RwLock<Option<MmioReq>> = RwLock::new(None);
+const NO_CFG_REQ: spin::Mutex<Option<MmioReq>> = spin::Mutex::new(None);
+/// The access through the virtio pci cfg access cap made by the port I/O being handled on each
+/// CPU, completed by the device list once the port I/O returns. Per CPU, as the VMs exit
+/// concurrently and each must only complete its own.
+static VIRTIO_PCI_CFG_REQS: [spin::Mutex<Option<MmioReq>>; SMP] = [NO_CFG_REQ; SMP];
+
+/// Take the access recorded by the virtio pci cfg access cap on the current CPU, if any.
+pub fn take_virtio_pci_cfg_req() -> Option<MmioReq> {
+    VIRTIO_PCI_CFG_REQS[current_cpu_id()].lock().take()
+}
+
+fn set_virtio_pci_cfg_req(req: MmioReq) {
+    *VIRTIO_PCI_CFG_REQS[current_cpu_id()].lock() = Some(req);
 }
 
 /// Virtio mmio req
@@ -253,6 +273,148 @@ impl VirtioPciNotifyCap {
     }
 }
 
+/// The regions of the memory BAR of a virtio PCI device, and its MSI-X vectors.
+///
+/// The regions are placed in this order, each on its own page, and the BAR is sized to the next
+/// power of two holding them, see [`VirtioPciRegions`].
+#[derive(Debug, Clone, Copy)]
+pub struct VirtioPciLayout {
+    /// Length of the common configuration region.
+    pub common_len: u32,
+    /// Length of the ISR status region.
+    pub isr_len: u32,
+    /// Length of the device-specific configuration region.
+    pub device_len: u32,
+    /// Distance between the notification addresses of two queues, 0 for a single address
+    /// shared by all queues. The notify region holds one address per queue.
+    pub notify_off_multiplier: u32,
+    /// Number of MSI-X vectors, `None` for one per queue plus one for configuration changes.
+    pub msix_vectors: Option<u32>,
+    /// Whether the memory BAR is a 64-bit one.
+    pub mem64: bool,
+}
+
+impl Default for VirtioPciLayout {
+    fn default() -> Self {
+        Self {
+            common_len: VIRTIO_PCI_CAP_COMMON_LENGTH,
+            isr_len: VIRTIO_PCI_CAP_ISR_LENGTH,
+            device_len: VIRTIO_PCI_CAP_DEVICE_LENGTH,
+            notify_off_multiplier: VIRTIO_PCI_CAP_NOTIFY_OFF_MULTIPLIER,
+            msix_vectors: None,
+            mem64: true,
+        }
+    }
+}
+
+impl VirtioPciLayout {
+    /// Place the regions for a device with `queue_num` queues.
+    fn place(&self, queue_num: usize) -> HyperResult<VirtioPciRegions> {
+        let invalid = |what: &str, value: u32| {
+            HyperError::PciError(PciError::InvalidConf(
+                String::from(what),
+                format!("{}", value),
+            ))
+        };
+        if self.common_len < VIRTIO_PCI_COMMON_CFG_LEN {
+            return Err(invalid("Virtio common cfg length", self.common_len));
+        }
+        if self.isr_len == 0 {
+            return Err(invalid("Virtio ISR cfg length", self.isr_len));
+        }
+        // Notifications are 16-bit writes.
+        let notify_len = max(self.notify_off_multiplier * queue_num as u32, 2);
+
+        let mut end = 0;
+        let mut next = |len: u32| {
+            let start = end;
+            end = (start + len + VIRTIO_PCI_REGION_ALIGN - 1) & !(VIRTIO_PCI_REGION_ALIGN - 1);
+            start..start + len
+        };
+        let common = next(self.common_len);
+        let isr = next(self.isr_len);
+        let device = next(self.device_len);
+        let notify = next(notify_len);
+        let region_type = if self.mem64 {
+            RegionType::Mem64Bit
+        } else {
+            RegionType::Mem32Bit
+        };
+        Ok(VirtioPciRegions {
+            common,
+            isr,
+            device,
+            notify,
+            region_type,
+            bar_size: bar_size(region_type, end as u64),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VirtioPciRegion {
+    Common,
+    Isr,
+    Device,
+    Notify,
+}
+
+/// Where the regions of a [`VirtioPciLayout`] are in the memory BAR, as offsets from its base.
+#[derive(Debug, Clone)]
+pub struct VirtioPciRegions {
+    pub common: Range<u32>,
+    pub isr: Range<u32>,
+    pub device: Range<u32>,
+    pub notify: Range<u32>,
+    pub region_type: RegionType,
+    /// Size of the BAR, a power of two.
+    pub bar_size: u64,
+}
+
+impl VirtioPciRegions {
+    /// The region holding `offset` of the BAR, and the offset within that region.
+    fn find(&self, offset: u64) -> Option<(VirtioPciRegion, u64)> {
+        [
+            (VirtioPciRegion::Common, &self.common),
+            (VirtioPciRegion::Isr, &self.isr),
+            (VirtioPciRegion::Device, &self.device),
+            (VirtioPciRegion::Notify, &self.notify),
+        ]
+        .into_iter()
+        .find(|(_, range)| (range.start as u64..range.end as u64).contains(&offset))
+        .map(|(region, range)| (region, offset - range.start as u64))
+    }
+}
+
+/// The INTx pin of the device, raised without MSI-X. The command register and the interrupt line
+/// are mirrored on config writes, for the interrupt callback.
+struct IntxState {
+    disabled: AtomicBool,
+    line: AtomicU8,
+}
+
+impl IntxState {
+    fn new() -> Self {
+        Self {
+            disabled: AtomicBool::new(false),
+            line: AtomicU8::new(0),
+        }
+    }
+
+    fn sync<B: BarAllocTrait>(&self, config: &PciConfig<B>) {
+        self.disabled
+            .store(config.intx_disabled(), Ordering::Release);
+        self.line.store(config.interrupt_line(), Ordering::Release);
+    }
+
+    /// The IRQ to raise, `None` if the pin is disabled or the guest did not route it.
+    fn line(&self) -> Option<u8> {
+        let line = self.line.load(Ordering::Acquire);
+        let routed = line != 0 && line != 0xff;
+        (routed && !self.disabled.load(Ordering::Acquire)).then_some(line)
+    }
+}
+
 /// Virtio-PCI device structure
 #[derive(Clone)]
 pub struct VirtioPciDevice<B: BarAllocTrait> {
@@ -265,6 +427,16 @@ pub struct VirtioPciDevice<B: BarAllocTrait> {
     cfg_cap_offset: usize,
     /// The function for interrupt triggering
     interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    /// The INTx pin, for the interrupt callback.
+    intx: Arc<IntxState>,
+    /// The queues notified while bus mastering was disabled, processed once it is enabled.
+    pending_notifies: Arc<Mutex<BTreeSet<u16>>>,
+    /// Whether the device is a function of a multi-function device.
+    multi_func: bool,
+    /// The requested layout of the memory BAR.
+    layout: VirtioPciLayout,
+    /// The regions of the memory BAR, placed by `realize`.
+    regions: Option<VirtioPciRegions>,
 }
 
 impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
@@ -274,6 +446,7 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
         device: Arc<Mutex<dyn VirtioDevice>>,
         parent_bus: Weak<Mutex<PciBus<B>>>,
         multi_func: bool,
+        layout: VirtioPciLayout,
     ) -> Self {
         let queue_num = device.lock().queue_num();
         VirtioPciDevice {
@@ -287,9 +460,19 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
             dev_id: Arc::new(AtomicU16::new(0)),
             cfg_cap_offset: 0,
             interrupt_cb: None,
+            intx: Arc::new(IntxState::new()),
+            pending_notifies: Arc::new(Mutex::new(BTreeSet::new())),
+            multi_func,
+            layout,
+            regions: None,
         }
     }
 
+    /// The regions of the memory BAR, once the device is realized.
+    pub fn regions(&self) -> Option<&VirtioPciRegions> {
+        self.regions.as_ref()
+    }
+
     fn assign_interrupt_cb(&mut self) {
         let locked_dev = self.device.lock();
         let virtio_base = locked_dev.virtio_base();
@@ -300,6 +483,7 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
 
         let cloned_msix = self.base.config.msix.as_ref().unwrap().clone();
         let dev_id = self.dev_id.clone();
+        let intx = self.intx.clone();
 
         let cb = Arc::new(Box::new(
             move |int_type: &VirtioInterruptType, queue: Option<&Queue>, needs_reset: bool| {
@@ -330,8 +514,15 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
                 let mut locked_msix = cloned_msix.lock();
                 if locked_msix.enabled {
                     locked_msix.notify(vector, dev_id.load(Ordering::Acquire));
+                } else if let Some(line) = intx.line() {
+                    // Reading the ISR tells the driver why, and acknowledges the interrupt.
+                    if let Some(irq_manager) = &locked_msix.msi_irq_manager {
+                        if let Err(e) = irq_manager.trigger_intx(line) {
+                            error!("Failed to raise INTx IRQ {}, error is {:?}", line, e);
+                        }
+                    }
                 } else {
-                    error!("MSI-X is not enabled, failed to notify interrupt.");
+                    debug!("Neither MSI-X nor INTx is enabled, interrupt not sent");
                 }
 
                 Ok(())
@@ -364,6 +555,14 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
         let queue_type = locked_dev.queue_type();
         let features = locked_dev.virtio_base().driver_features;
         let broken = locked_dev.virtio_base().broken.clone();
+        // The driver activates the device from a vCPU of its VM.
+        let mem = match crate::vm::current_vm_id().map(GuestRam::new) {
+            Some(Ok(mem)) => mem,
+            _ => {
+                error!("Failed to activate device: no VM running");
+                return false;
+            }
+        };
 
         let mut queues = Vec::new();
         let queues_config = &mut locked_dev.virtio_base_mut().queues_config;
@@ -374,7 +573,7 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
                 q_config.set_addr_cache(self.interrupt_cb.clone().unwrap(), features, &broken);
             }
             let queue = Queue::new(*q_config, queue_type).unwrap();
-            if q_config.ready && !queue.is_valid() {
+            if q_config.ready && !queue.is_valid(&mem, features) {
                 error!("Failed to activate device: Invalid queue");
                 return false;
             }
@@ -408,10 +607,33 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
         if let Some(msix) = &self.base.config.msix {
             msix.lock().clear_pending_vectors();
         }
+        self.pending_notifies.lock().clear();
 
         true
     }
 
+    /// Process the queues notified while bus mastering was disabled, once it is enabled.
+    fn replay_notifies(&self) {
+        if !self.base.config.bus_master_enabled() {
+            return;
+        }
+        let queues = core::mem::take(&mut *self.pending_notifies.lock());
+        if queues.is_empty() {
+            return;
+        }
+        let Some(vm_id) = crate::vm::current_vm_id() else {
+            return;
+        };
+        for queue_index in queues {
+            if let Err(e) = worker::notify(vm_id, self.device.clone(), queue_index) {
+                error!(
+                    "Failed to notify virtio queue {}, error is {:?}",
+                    queue_index, e
+                );
+            }
+        }
+    }
+
     /// Read data from the common config of virtio device.
     /// Return the config value in u32.
     ///
@@ -638,15 +860,18 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
     }
 
     // build pci cfg cap ops(common_cfg, isr_cfg, device_cfg, notify_cfg)
-    fn build_pci_cfg_cap_ops(virtio_pci: Arc<Mutex<VirtioPciDevice<B>>>) -> RegionOps {
+    fn build_pci_cfg_cap_ops(
+        virtio_pci: Arc<Mutex<VirtioPciDevice<B>>>,
+        regions: VirtioPciRegions,
+    ) -> RegionOps {
         let cloned_virtio_pci = virtio_pci.clone();
+        let read_regions = regions.clone();
         let read = move |offset: u64, access_size: u8| -> HyperResult<u64> {
             let mut data = [0u8; 8];
-            match offset as u32 {
+            match read_regions.find(offset) {
                 // read pci common cfg
-                VIRTIO_PCI_CAP_COMMON_OFFSET..VIRTIO_PCI_CAP_ISR_OFFSET => {
+                Some((VirtioPciRegion::Common, common_offset)) => {
                     debug!("read pci common cfg, offset is {:#x}", offset);
-                    let common_offset = offset - VIRTIO_PCI_CAP_COMMON_OFFSET as u64;
                     let value = match cloned_virtio_pci.lock().read_common_config(common_offset) {
                         Ok(v) => v,
                         Err(e) => {
@@ -661,7 +886,7 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
                     write_data_u64(&mut data[..], value);
                 }
                 // read pci isr cfg
-                VIRTIO_PCI_CAP_ISR_OFFSET..VIRTIO_PCI_CAP_DEVICE_OFFSET => {
+                Some((VirtioPciRegion::Isr, _)) => {
                     debug!("read pci isr cfg, offset is {}", offset);
                     let cloned_virtio_dev = cloned_virtio_pci.lock().device.clone();
                     if let Some(val) = data.get_mut(0) {
@@ -673,10 +898,9 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
                     }
                 }
                 // read pci device cfg
-                VIRTIO_PCI_CAP_DEVICE_OFFSET..VIRTIO_PCI_CAP_NOTIFY_OFFSET => {
+                Some((VirtioPciRegion::Device, device_offset)) => {
                     debug!("read pci device cfg, offset is {}", offset);
                     let cloned_virtio_dev = cloned_virtio_pci.lock().device.clone();
-                    let device_offset = offset - VIRTIO_PCI_CAP_DEVICE_OFFSET as u64;
                     if let Err(e) = cloned_virtio_dev
                         .lock()
                         .read_config(device_offset, &mut data[..])
@@ -686,11 +910,11 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
                     };
                 }
                 // read pci notify cfg
-                VIRTIO_PCI_CAP_NOTIFY_OFFSET..VIRTIO_PCI_CAP_NOTIFY_END => {
+                Some((VirtioPciRegion::Notify, _)) => {
                     debug!("read pci notify cfg, offset is {}", offset);
                     // todo: need to notify hv to get the virtio request
                 }
-                _ => {
+                None => {
                     error!("Invalid offset for pci cfg cap, offset is {}", offset);
                     return Err(HyperError::InValidMmioRead);
                 }
@@ -699,14 +923,13 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
         };
         let cloned_virtio_pci = virtio_pci.clone();
         let write = move |offset: u64, access_size: u8, data: &[u8]| -> HyperResult {
-            match offset as u32 {
+            match regions.find(offset) {
                 // write pci common cfg
-                VIRTIO_PCI_CAP_COMMON_OFFSET..VIRTIO_PCI_CAP_ISR_OFFSET => {
+                Some((VirtioPciRegion::Common, common_offset)) => {
                     debug!(
                         "write pci common cfg, write data:{:?} offset is {:#x}",
                         data, offset
                     );
-                    let common_offset = offset - VIRTIO_PCI_CAP_COMMON_OFFSET as u64;
                     let mut value = 0;
                     if !read_data_u32(data, &mut value) {
                         return Err(HyperError::InValidMmioWrite);
@@ -724,20 +947,19 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
                     }
                 }
                 // write pci isr cfg
-                VIRTIO_PCI_CAP_ISR_OFFSET..VIRTIO_PCI_CAP_DEVICE_OFFSET => {
+                Some((VirtioPciRegion::Isr, _)) => {
                     debug!(
                         "write pci isr cfg, write data:{:?} offset is {:#x}",
                         data, offset
                     );
                 }
                 // write pci device cfg
-                VIRTIO_PCI_CAP_DEVICE_OFFSET..VIRTIO_PCI_CAP_NOTIFY_OFFSET => {
+                Some((VirtioPciRegion::Device, device_offset)) => {
                     debug!(
                         "write pci device cfg, write data:{:?} offset is {:#x}",
                         data, offset
                     );
                     let cloned_virtio_dev = cloned_virtio_pci.lock().device.clone();
-                    let device_offset = offset - VIRTIO_PCI_CAP_DEVICE_OFFSET as u64;
                     if let Err(e) = cloned_virtio_dev
                         .lock()
                         .write_config(device_offset, &data[..])
@@ -747,14 +969,44 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
                     };
                 }
                 // write pci notify cfg
-                VIRTIO_PCI_CAP_NOTIFY_OFFSET..VIRTIO_PCI_CAP_NOTIFY_END => {
+                Some((VirtioPciRegion::Notify, _)) => {
                     debug!(
                         "write pci notify cfg, write data:{:?} offset is {:#x}",
                         data, offset
                     );
-                    // todo: need to notify hv to get the virtio request
+                    // The driver writes the index of the queue.
+                    let mut queue_index = [0u8; 2];
+                    let len = min(data.len(), 2);
+                    queue_index[..len].copy_from_slice(&data[..len]);
+                    let queue_index = u16::from_le_bytes(queue_index);
+                    let locked_virtio_pci = cloned_virtio_pci.lock();
+                    let cloned_virtio_dev = locked_virtio_pci.device.clone();
+                    // The rings are in guest memory: without bus mastering the queue is processed
+                    // once the driver enables it, under the same lock as this check.
+                    if !locked_virtio_pci.base.config.bus_master_enabled() {
+                        if queue_index < cloned_virtio_dev.lock().queue_num() as u16 {
+                            debug!(
+                                "virtio-pci notify of queue {} latched, bus mastering is disabled",
+                                queue_index
+                            );
+                            locked_virtio_pci
+                                .pending_notifies
+                                .lock()
+                                .insert(queue_index);
+                        }
+                        return Ok(());
+                    }
+                    drop(locked_virtio_pci);
+                    let vm_id = crate::vm::current_vm_id().ok_or(HyperError::BadState)?;
+                    worker::notify(vm_id, cloned_virtio_dev, queue_index).map_err(|e| {
+                        error!(
+                            "Failed to notify virtio queue {}, error is {:?}",
+                            queue_index, e
+                        );
+                        HyperError::InValidMmioWrite
+                    })?;
                 }
-                _ => {
+                None => {
                     error!("Invalid offset for pci cfg cap, offset is {:#x}", offset);
                     return Err(HyperError::InValidMmioRead);
                 }
@@ -846,6 +1098,140 @@ impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
     }
 }
 
+impl<B: BarAllocTrait + 'static> VirtioPciDevice<B> {
+    /// Read a state returned by [`DeviceState::save`], to be applied with `apply_state`.
+    fn load_state(&self, state: &[u8]) -> HyperResult<(u16, VirtioBaseState)> {
+        // Not realized yet, there is no way to notify the guest.
+        if self.interrupt_cb.is_none() {
+            return Err(HyperError::BadState);
+        }
+        let mut state = StateReader::new(state, VIRTIO_PCI_STATE_VERSION)?;
+        let dev_id = state.u16()?;
+        let base = self.device.lock().virtio_base().load(&mut state)?;
+        state.finish()?;
+        Ok((dev_id, base))
+    }
+
+    /// Replace the state of the device, restarting its backend if the state was activated.
+    fn apply_state(&mut self, dev_id: u16, base: &VirtioBaseState) -> HyperResult<()> {
+        if !self.deactivate_device() {
+            return Err(HyperError::BadState);
+        }
+        self.dev_id.store(dev_id, Ordering::SeqCst);
+        self.intx.sync(&self.base.config);
+
+        let interrupt_cb = self.interrupt_cb.clone().unwrap();
+        let mut locked_dev = self.device.lock();
+        locked_dev
+            .virtio_base_mut()
+            .set_state(base, interrupt_cb.clone());
+        if base.device_activated {
+            if let Err(e) = locked_dev.activate(interrupt_cb) {
+                error!(
+                    "Failed to activate restored virtio device, error is {:?}",
+                    e
+                );
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The configuration space of the device is saved by the [`PciHost`], and the MSI-X table is not
+/// saved: the guest has to program it again.
+impl<B: BarAllocTrait + 'static> DeviceState for VirtioPciDevice<B> {
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(VIRTIO_PCI_STATE_VERSION);
+        state.u16(self.dev_id.load(Ordering::Acquire));
+        self.device.lock().virtio_base().save(&mut state);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult<()> {
+        let (dev_id, base) = self.load_state(state)?;
+        self.apply_state(dev_id, &base)
+    }
+}
+
+/// The devices of the root bus, in devfn order: the configuration space of each one, followed by
+/// the state of the virtio ones. The devices behind bridges are not saved.
+impl<B: BarAllocTrait + 'static> DeviceState for PciHost<B> {
+    fn save(&self) -> Vec<u8> {
+        let devices = root_bus_devices(self);
+        let mut state = StateWriter::new(PCI_HOST_STATE_VERSION);
+        state.u32(self.config_addr()).u32(devices.len() as u32);
+        for (devfn, dev) in devices.iter() {
+            let locked_dev = dev.lock();
+            state.u8(*devfn).bytes(&locked_dev.pci_base().config.config);
+            match locked_dev.as_any().downcast_ref::<VirtioPciDevice<B>>() {
+                Some(virtio) => state.bool(true).bytes(&virtio.save()),
+                None => state.bool(false),
+            };
+        }
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult<()> {
+        let devices = root_bus_devices(self);
+        let mut state = StateReader::new(state, PCI_HOST_STATE_VERSION)?;
+        let config_addr = state.u32()?;
+        if state.u32()? as usize != devices.len() {
+            return Err(HyperError::InvalidParam);
+        }
+        // Everything is checked before the first device is touched.
+        let mut restored = Vec::with_capacity(devices.len());
+        for (devfn, dev) in devices.iter() {
+            let locked_dev = dev.lock();
+            let saved_devfn = state.u8()?;
+            let config = state.bytes()?;
+            let virtio_state = if state.bool()? {
+                Some(state.bytes()?)
+            } else {
+                None
+            };
+            if saved_devfn != *devfn || config.len() != locked_dev.pci_base().config.config.len() {
+                return Err(HyperError::InvalidParam);
+            }
+            let virtio = locked_dev.as_any().downcast_ref::<VirtioPciDevice<B>>();
+            let virtio_base = match (virtio, virtio_state) {
+                (Some(virtio), Some(virtio_state)) => Some(virtio.load_state(virtio_state)?),
+                (None, None) => None,
+                _ => return Err(HyperError::InvalidParam),
+            };
+            restored.push((config, virtio_base));
+        }
+        state.finish()?;
+
+        self.set_config_addr(config_addr);
+        for ((_, dev), (config, virtio_base)) in devices.iter().zip(restored) {
+            let mut locked_dev = dev.lock();
+            let pci_config = &mut locked_dev.pci_base_mut().config;
+            pci_config.config.copy_from_slice(config);
+            pci_config.update_bar_mapping(false)?;
+            if let Some((dev_id, base)) = virtio_base {
+                let virtio = locked_dev.as_any_mut().downcast_mut::<VirtioPciDevice<B>>();
+                virtio.unwrap().apply_state(dev_id, &base)?;
+            }
+            locked_dev.pci_base().config.sync_bus_master();
+        }
+        Ok(())
+    }
+}
+
+/// The devices of the root bus, taken out of the bus lock: the devices lock their bus on config
+/// writes.
+fn root_bus_devices<B: BarAllocTrait>(
+    host: &PciHost<B>,
+) -> Vec<(u8, Arc<Mutex<dyn PciDevOps<B>>>)> {
+    let locked_root_bus = host.root_bus.lock();
+    locked_root_bus
+        .devices
+        .iter()
+        .map(|(devfn, dev)| (*devfn, dev.clone()))
+        .collect()
+}
+
 impl<B: BarAllocTrait + 'static> AsAny for VirtioPciDevice<B> {
     fn as_any(&self) -> &dyn Any {
         self
@@ -870,6 +1256,14 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for VirtioPciDevice<B> {
     }
 
     fn realize(mut self) -> HyperResult<()> {
+        // Before anything is allocated for the device.
+        let parent_bus = self.base.parent_bus.upgrade().unwrap();
+        parent_bus
+            .lock()
+            .check_devfn(self.base.devfn, self.multi_func)?;
+        let bar_layout = parent_bus.lock().bar_layout.clone();
+        self.base.config.set_bar_layout(bar_layout);
+
         self.init_write_mask(false)?;
         self.init_write_clear_mask(false)?;
 
@@ -885,6 +1279,8 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for VirtioPciDevice<B> {
             VIRTIO_PCI_DEVICE_ID_BASE + device_type as u16,
         )?;
         self.base.config.config[REVISION_ID] = VIRTIO_PCI_ABI_VERSION;
+        // INTA#, raised when the driver does not enable MSI-X.
+        self.base.config.config[INTERRUPT_PIN as usize] = 1;
         let class_id = get_virtio_class_id(device_type);
         le_write_u16(
             &mut self.base.config.config,
@@ -903,13 +1299,21 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for VirtioPciDevice<B> {
             0x40 + device_type as u16
         };
         le_write_u16(&mut self.base.config.config, SUBSYSTEM_ID, subsysid)?;
+        init_multifunction(
+            self.multi_func,
+            &mut self.base.config.config,
+            self.base.devfn,
+            self.base.parent_bus.clone(),
+        )?;
 
+        let queue_num = self.device.lock().queue_num();
+        let regions = self.layout.place(queue_num)?;
         let common_cap = VirtioPciCap::new(
             size_of::<VirtioPciCap>() as u8 + PCI_CAP_VNDR_AND_NEXT_SIZE,
             VirtioPciCapType::Common as u8,
             VIRTIO_PCI_MEM_BAR_IDX,
-            VIRTIO_PCI_CAP_COMMON_OFFSET,
-            VIRTIO_PCI_CAP_COMMON_LENGTH,
+            regions.common.start,
+            regions.common.len() as u32,
         );
         self.modern_mem_region_cap_add(common_cap)?;
 
@@ -917,8 +1321,8 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for VirtioPciDevice<B> {
             size_of::<VirtioPciCap>() as u8 + PCI_CAP_VNDR_AND_NEXT_SIZE,
             VirtioPciCapType::ISR as u8,
             VIRTIO_PCI_MEM_BAR_IDX,
-            VIRTIO_PCI_CAP_ISR_OFFSET,
-            VIRTIO_PCI_CAP_ISR_LENGTH,
+            regions.isr.start,
+            regions.isr.len() as u32,
         );
         self.modern_mem_region_cap_add(isr_cap)?;
 
@@ -926,8 +1330,8 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for VirtioPciDevice<B> {
             size_of::<VirtioPciCap>() as u8 + PCI_CAP_VNDR_AND_NEXT_SIZE,
             VirtioPciCapType::Device as u8,
             VIRTIO_PCI_MEM_BAR_IDX,
-            VIRTIO_PCI_CAP_DEVICE_OFFSET,
-            VIRTIO_PCI_CAP_DEVICE_LENGTH,
+            regions.device.start,
+            regions.device.len() as u32,
         );
         self.modern_mem_region_cap_add(device_cap)?;
 
@@ -935,9 +1339,9 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for VirtioPciDevice<B> {
             size_of::<VirtioPciNotifyCap>() as u8 + PCI_CAP_VNDR_AND_NEXT_SIZE,
             VirtioPciCapType::Notify as u8,
             VIRTIO_PCI_MEM_BAR_IDX,
-            VIRTIO_PCI_CAP_NOTIFY_OFFSET,
-            VIRTIO_PCI_CAP_NOTIFY_LENGTH,
-            VIRTIO_PCI_CAP_NOTIFY_OFF_MULTIPLIER,
+            regions.notify.start,
+            regions.notify.len() as u32,
+            self.layout.notify_off_multiplier,
         );
         self.modern_mem_region_cap_add(notify_cap)?;
 
@@ -958,11 +1362,11 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for VirtioPciDevice<B> {
             !0,
         )?;
 
-        let nvectors = self.device.lock().queue_num() + 1;
+        let nvectors = self.layout.msix_vectors.unwrap_or(queue_num as u32 + 1);
         init_msix(
             &mut self.base,
             VIRTIO_PCI_MSIX_BAR_IDX as usize,
-            nvectors as u32,
+            nvectors,
             self.dev_id.clone(),
             None,
         )?;
@@ -977,19 +1381,16 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for VirtioPciDevice<B> {
 
         let name = self.name();
         let devfn = self.base.devfn;
+        self.regions = Some(regions.clone());
         let dev = Arc::new(Mutex::new(self));
-        let mut mem_region_size = ((VIRTIO_PCI_CAP_NOTIFY_OFFSET + VIRTIO_PCI_CAP_NOTIFY_LENGTH)
-            as u64)
-            .next_power_of_two();
-        mem_region_size = max(mem_region_size, MINIMUM_BAR_SIZE_FOR_MMIO as u64);
-        let pci_cfg_cap_ops = Self::build_pci_cfg_cap_ops(dev.clone());
+        let pci_cfg_cap_ops = Self::build_pci_cfg_cap_ops(dev.clone(), regions.clone());
 
         dev.lock().base.config.register_bar(
             VIRTIO_PCI_MEM_BAR_IDX as usize,
             Some(pci_cfg_cap_ops),
-            RegionType::Mem64Bit,
+            regions.region_type,
             false,
-            mem_region_size,
+            regions.bar_size,
         )?;
 
         // Register device to pci bus. Now set it to the root bus.
@@ -1028,9 +1429,8 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for VirtioPciDevice<B> {
             offset,
             data.len()
         );
-        let mmio_req = self.do_cfg_access(offset, offset + data.len(), false);
-        if mmio_req.is_some() {
-            *GLOBAL_VIRTIO_PCI_CFG_REQ.write() = mmio_req;
+        if let Some(mmio_req) = self.do_cfg_access(offset, offset + data.len(), false) {
+            set_virtio_pci_cfg_req(mmio_req);
             return;
         }
         self.base.config.read(offset, data);
@@ -1057,9 +1457,11 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for VirtioPciDevice<B> {
         self.base
             .config
             .write(offset, data, self.dev_id.clone().load(Ordering::Acquire));
-        let mmio_req = self.do_cfg_access(offset, end, true);
-        if mmio_req.is_some() {
-            *GLOBAL_VIRTIO_PCI_CFG_REQ.write() = mmio_req;
+        drop(locked_parent_bus);
+        self.intx.sync(&self.base.config);
+        self.replay_notifies();
+        if let Some(mmio_req) = self.do_cfg_access(offset, end, true) {
+            set_virtio_pci_cfg_req(mmio_req);
         }
     }
 
@@ -1071,6 +1473,7 @@ impl<B: BarAllocTrait + 'static> PciDevOps<B> for VirtioPciDevice<B> {
             ))))
         })?;
         self.base.config.reset()?;
+        self.intx.sync(&self.base.config);
 
         Ok(())
     }
@@ -0,0 +1,570 @@
+//! Host tasks processing the virtio queues, off the CPUs of the vCPUs.
+//!
+//! A queue notification from the driver is posted to the ring of its VM, a bounded lock-free
+//! ring, and the vCPU re-enters the guest at once. The workers walk the queues and call the
+//! backends through [`VirtioDevice::notify_queue`], an operation left pending going on to the
+//! completion worker (see [`crate::completion`]).
+//!
+//! - Fairness: a worker takes at most one event of each VM per pass over the VMs, starting with
+//!   a different VM every pass, so that a busy VM cannot starve the others.
+//! - Backpressure: when the ring of a VM is full, or when the pool is disabled, the vCPU
+//!   processes the notification itself, which throttles the guest flooding it.
+//! - Shutdown: when a VM stops, its ring is closed, the events still queued are dropped and
+//!   [`close_vm`] waits for the workers processing one of its events, so that the devices of the
+//!   VM are no longer used once it returns.
+//!
+//! The workers are `axtask` tasks, each pinned to a CPU no VM may run a vCPU on: the CPUs out
+//! of the cpu sets of the VMs configured when the workers start. `axtask` has a single run queue
+//! and no affinity, so a worker picked by another CPU, e.g. one whose vCPU parks, yields until
+//! its own CPU runs it, like the vCPUs (see [`crate::park::move_to_cpu`]), and never processes a
+//! queue on the CPU of a vCPU. Without such a CPU, the pool is not started and the vCPUs process
+//! their notifications themselves.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use axconfig::SMP;
+use axhal::current_cpu_id;
+use axhal::time::current_time_nanos;
+use axtask::WaitQueue;
+use lazy_static::lazy_static;
+use lock_stat::Mutex;
+use spin::RwLock;
+
+use super::VirtioDevice;
+use hypercraft::HyperResult as Result;
+
+/// Number of workers started by default, see [`set_virtio_workers`].
+const DEFAULT_WORKERS: usize = 1;
+/// Capacity of the notification ring of each VM, a power of two.
+const VM_RING_SIZE: usize = 256;
+
+/// Statistics of the virtio workers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VirtioWorkerStats {
+    /// Notifications posted to the workers.
+    pub posted: u64,
+    /// Notifications processed by the vCPU, the pool being disabled or the ring full.
+    pub inline: u64,
+    /// Notifications dropped because their VM stopped.
+    pub dropped: u64,
+    /// Sum and maximum of the delays between posting a notification and a worker taking it.
+    pub total_queue_latency_ns: u64,
+    pub max_queue_latency_ns: u64,
+}
+
+/// The counters of [`VirtioWorkerStats`], updated without a lock by the vCPUs and the workers.
+struct WorkerCounters {
+    posted: AtomicU64,
+    inline: AtomicU64,
+    dropped: AtomicU64,
+    total_queue_latency_ns: AtomicU64,
+    max_queue_latency_ns: AtomicU64,
+}
+
+impl WorkerCounters {
+    const fn new() -> Self {
+        Self {
+            posted: AtomicU64::new(0),
+            inline: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            total_queue_latency_ns: AtomicU64::new(0),
+            max_queue_latency_ns: AtomicU64::new(0),
+        }
+    }
+
+    fn record_latency(&self, latency_ns: u64) {
+        self.total_queue_latency_ns
+            .fetch_add(latency_ns, Ordering::Relaxed);
+        self.max_queue_latency_ns
+            .fetch_max(latency_ns, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> VirtioWorkerStats {
+        VirtioWorkerStats {
+            posted: self.posted.load(Ordering::Relaxed),
+            inline: self.inline.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            total_queue_latency_ns: self.total_queue_latency_ns.load(Ordering::Relaxed),
+            max_queue_latency_ns: self.max_queue_latency_ns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct Slot<T> {
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Bounded multi-producer multi-consumer ring: slot `i` is free for the push at position `pos`
+/// when its sequence is `pos`, and holds the value for the pop at `pos` when it is `pos + 1`.
+struct EventRing<T> {
+    slots: Vec<Slot<T>>,
+    mask: usize,
+    push_pos: AtomicUsize,
+    pop_pos: AtomicUsize,
+}
+
+// SAFETY: a value is only accessed by the thread which won the slot by moving the position.
+unsafe impl<T: Send> Send for EventRing<T> {}
+unsafe impl<T: Send> Sync for EventRing<T> {}
+
+impl<T> EventRing<T> {
+    fn new(size: usize) -> Self {
+        debug_assert!(size.is_power_of_two());
+        Self {
+            slots: (0..size)
+                .map(|i| Slot {
+                    seq: AtomicUsize::new(i),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                })
+                .collect(),
+            mask: size - 1,
+            push_pos: AtomicUsize::new(0),
+            pop_pos: AtomicUsize::new(0),
+        }
+    }
+
+    /// Give `value` back if the ring is full.
+    fn push(&self, value: T) -> core::result::Result<(), T> {
+        let mut pos = self.push_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match (seq as isize).wrapping_sub(pos as isize) {
+                0 => match self.push_pos.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: the slot is ours until its sequence is bumped.
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.seq.store(pos + 1, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                },
+                diff if diff < 0 => return Err(value),
+                _ => pos = self.push_pos.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        let mut pos = self.pop_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match (seq as isize).wrapping_sub(pos.wrapping_add(1) as isize) {
+                0 => match self.pop_pos.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: the slot was written by the push at `pos` and is ours until
+                        // its sequence is bumped.
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.seq.store(pos + self.mask + 1, Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => pos = current,
+                },
+                diff if diff < 0 => return None,
+                _ => pos = self.pop_pos.load(Ordering::Relaxed),
+            }
+        }
+    }
+}
+
+impl<T> Drop for EventRing<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+struct QueueEvent {
+    device: Arc<Mutex<dyn VirtioDevice>>,
+    queue_index: u16,
+    posted_ns: u64,
+}
+
+struct VmQueues {
+    vm_id: u32,
+    ring: EventRing<QueueEvent>,
+    /// Set once the VM stopped, the workers no longer take its events.
+    closed: AtomicBool,
+    /// Workers which may be processing an event of the VM.
+    busy: AtomicUsize,
+}
+
+lazy_static! {
+    /// The rings of the VMs which posted notifications and did not stop.
+    static ref VM_QUEUES: RwLock<Vec<Arc<VmQueues>>> = RwLock::new(Vec::new());
+}
+static STATS: WorkerCounters = WorkerCounters::new();
+/// Events in all the rings, the workers sleep while there is none.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+/// First VM of the next pass over the VMs.
+static NEXT_VM: AtomicUsize = AtomicUsize::new(0);
+static WORKER_COUNT: AtomicUsize = AtomicUsize::new(DEFAULT_WORKERS);
+static WORKERS_STARTED: AtomicBool = AtomicBool::new(false);
+/// Workers running, set once they started: 0 if none could be pinned off the vCPUs.
+static WORKERS_RUNNING: AtomicUsize = AtomicUsize::new(0);
+static WORKER_QUEUE: WaitQueue = WaitQueue::new();
+
+/// Set the number of workers, 0 for the vCPUs to process their notifications themselves. Only
+/// effective before the first notification is posted, the workers being started then.
+pub fn set_virtio_workers(count: usize) {
+    if WORKERS_STARTED.load(Ordering::Acquire) {
+        warn!("virtio workers already started, count {} ignored", count);
+        return;
+    }
+    WORKER_COUNT.store(count, Ordering::Release);
+}
+
+pub fn virtio_worker_stats() -> VirtioWorkerStats {
+    STATS.snapshot()
+}
+
+/// Process the notification of queue `queue_index` of `device` by the driver of VM `vm_id`,
+/// on a worker if possible.
+pub(crate) fn notify(
+    vm_id: u32,
+    device: Arc<Mutex<dyn VirtioDevice>>,
+    queue_index: u16,
+) -> Result {
+    start_workers();
+    if WORKERS_RUNNING.load(Ordering::Acquire) == 0 {
+        STATS.inline.fetch_add(1, Ordering::Relaxed);
+        return process(vm_id, &device, queue_index);
+    }
+    let event = QueueEvent {
+        device,
+        queue_index,
+        posted_ns: current_time_nanos(),
+    };
+    match vm_queues(vm_id).ring.push(event) {
+        Ok(()) => {
+            PENDING.fetch_add(1, Ordering::AcqRel);
+            STATS.posted.fetch_add(1, Ordering::Relaxed);
+            WORKER_QUEUE.notify_one(true);
+            Ok(())
+        }
+        Err(event) => {
+            STATS.inline.fetch_add(1, Ordering::Relaxed);
+            process(vm_id, &event.device, event.queue_index)
+        }
+    }
+}
+
+/// Drop the pending notifications of `vm_id` and wait for the ones being processed, once the VM
+/// stopped.
+pub(crate) fn close_vm(vm_id: u32) {
+    let queues = {
+        let mut vms = VM_QUEUES.write();
+        match vms.iter().position(|queues| queues.vm_id == vm_id) {
+            Some(index) => vms.swap_remove(index),
+            None => return,
+        }
+    };
+    // Sequentially consistent with the worker side: either the worker sees the ring closed, or
+    // this sees the worker busy.
+    queues.closed.store(true, Ordering::SeqCst);
+    while queues.busy.load(Ordering::SeqCst) != 0 {
+        axtask::yield_now();
+    }
+    let mut dropped = 0;
+    while queues.ring.pop().is_some() {
+        dropped += 1;
+    }
+    PENDING.fetch_sub(dropped, Ordering::AcqRel);
+    if dropped != 0 {
+        debug!("VM [{}] stopped, {} virtio notifications dropped", vm_id, dropped);
+        STATS.dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+    }
+}
+
+fn vm_queues(vm_id: u32) -> Arc<VmQueues> {
+    if let Some(queues) = VM_QUEUES.read().iter().find(|queues| queues.vm_id == vm_id) {
+        return queues.clone();
+    }
+    let mut vms = VM_QUEUES.write();
+    if let Some(queues) = vms.iter().find(|queues| queues.vm_id == vm_id) {
+        return queues.clone();
+    }
+    let queues = Arc::new(VmQueues {
+        vm_id,
+        ring: EventRing::new(VM_RING_SIZE),
+        closed: AtomicBool::new(false),
+        busy: AtomicUsize::new(0),
+    });
+    vms.push(queues.clone());
+    queues
+}
+
+fn process(vm_id: u32, device: &Arc<Mutex<dyn VirtioDevice>>, queue_index: u16) -> Result {
+    let op = device.lock().notify_queue(queue_index)?;
+    if let Some(op) = op {
+        let token = crate::completion::submit(vm_id, op);
+        debug!("virtio queue {} notify pending as {:?}", queue_index, token);
+    }
+    Ok(())
+}
+
+/// The CPUs out of `vcpu_cpus`, a mask of the CPUs which may run a vCPU, among `cpus`.
+fn free_cpus(vcpu_cpus: u64, cpus: usize) -> Vec<usize> {
+    (0..cpus)
+        .filter(|&cpu| cpu < 64 && vcpu_cpus & (1 << cpu) == 0)
+        .collect()
+}
+
+/// The mask of the CPUs in the cpu set of a configured VM.
+fn vcpu_cpus() -> u64 {
+    let mut mask = 0u64;
+    for entry in crate::config::entry::vm_cfg_entries() {
+        let cpu_set = entry.get_cpu_set();
+        for core in (0..usize::BITS as usize).filter(|&core| cpu_set & (1 << core) != 0) {
+            // A core unknown to the hypervisor may be any of its CPUs.
+            match axhal::core_id_to_cpu_id(core) {
+                Some(cpu) if cpu < 64 => mask |= 1 << cpu,
+                Some(_) => {}
+                None => mask = u64::MAX,
+            }
+        }
+    }
+    mask
+}
+
+/// Start the workers on the first notification, each pinned to a CPU without vCPUs, the pool
+/// staying disabled if there is none.
+fn start_workers() {
+    if WORKERS_STARTED
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+    let count = WORKER_COUNT.load(Ordering::Acquire);
+    if count == 0 {
+        return;
+    }
+    let cpus = free_cpus(vcpu_cpus(), SMP);
+    if cpus.is_empty() {
+        warn!(
+            "no CPU without vCPUs for the {} virtio workers, queues processed by the vCPUs",
+            count
+        );
+        return;
+    }
+    info!("starting {} virtio workers on CPUs {:?}", count, cpus);
+    for i in 0..count {
+        let cpu = cpus[i % cpus.len()];
+        axtask::spawn_raw(
+            move || run_worker(cpu),
+            alloc::format!("axvm-virtio-{}", i),
+            axconfig::TASK_STACK_SIZE,
+        );
+    }
+    WORKERS_RUNNING.store(count, Ordering::Release);
+}
+
+/// Take one event of each VM, starting with the next one in turn. Returns whether there was any.
+fn pass() -> bool {
+    let vms = VM_QUEUES.read().clone();
+    if vms.is_empty() {
+        return false;
+    }
+    let first = NEXT_VM.fetch_add(1, Ordering::Relaxed);
+    let mut processed = false;
+    for i in 0..vms.len() {
+        let queues = &vms[(first + i) % vms.len()];
+        queues.busy.fetch_add(1, Ordering::SeqCst);
+        if !queues.closed.load(Ordering::SeqCst) {
+            if let Some(event) = queues.ring.pop() {
+                PENDING.fetch_sub(1, Ordering::AcqRel);
+                STATS.record_latency(current_time_nanos().saturating_sub(event.posted_ns));
+                if let Err(err) = process(queues.vm_id, &event.device, event.queue_index) {
+                    warn!(
+                        "VM [{}] virtio queue {} failed: {:?}",
+                        queues.vm_id, event.queue_index, err
+                    );
+                }
+                processed = true;
+            }
+        }
+        queues.busy.fetch_sub(1, Ordering::SeqCst);
+    }
+    processed
+}
+
+/// Process the events on CPU `cpu`, going back to it whenever another CPU picked the task.
+fn run_worker(cpu: usize) {
+    loop {
+        WORKER_QUEUE.wait_until(|| PENDING.load(Ordering::Acquire) != 0);
+        crate::park::move_to_cpu(cpu, None);
+        while current_cpu_id() == cpu && pass() {}
+        // Events of closed rings are counted until `close_vm` drops them, let it run.
+        if PENDING.load(Ordering::Acquire) != 0 {
+            axtask::yield_now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_ring_order() {
+        let ring = EventRing::new(4);
+        assert_eq!(ring.pop(), None::<u32>);
+        // Wrapping around the slots several times.
+        for round in 0..3 {
+            for i in 0..4 {
+                ring.push(round * 4 + i).unwrap();
+            }
+            assert_eq!(ring.push(99), Err(99));
+            for i in 0..4 {
+                assert_eq!(ring.pop(), Some(round * 4 + i));
+            }
+            assert_eq!(ring.pop(), None);
+        }
+    }
+
+    #[test]
+    fn test_ring_drop() {
+        let value = Arc::new(());
+        let ring = EventRing::new(8);
+        for _ in 0..5 {
+            ring.push(value.clone()).unwrap();
+        }
+        ring.pop();
+        drop(ring);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn test_ring_concurrent() {
+        const PRODUCERS: u64 = 4;
+        const EVENTS: u64 = 10_000;
+        let ring = Arc::new(EventRing::new(64));
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|producer| {
+                let ring = ring.clone();
+                std::thread::spawn(move || {
+                    for i in 0..EVENTS {
+                        let mut event = producer << 32 | i;
+                        while let Err(back) = ring.push(event) {
+                            event = back;
+                            std::thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..2)
+            .map(|_| {
+                let ring = ring.clone();
+                std::thread::spawn(move || {
+                    let mut taken = Vec::new();
+                    while taken.len() < (PRODUCERS * EVENTS / 2) as usize {
+                        match ring.pop() {
+                            Some(event) => taken.push(event),
+                            None => std::thread::yield_now(),
+                        }
+                    }
+                    taken
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut last = [None; PRODUCERS as usize];
+        let mut count = 0;
+        for consumer in consumers {
+            let mut seen = [None; PRODUCERS as usize];
+            for event in consumer.join().unwrap() {
+                let (producer, i) = ((event >> 32) as usize, event & 0xffff_ffff);
+                // The events of a producer are taken in order by each consumer.
+                assert!(seen[producer].map_or(true, |prev| prev < i));
+                seen[producer] = Some(i);
+                last[producer] = last[producer].max(Some(i));
+                count += 1;
+            }
+        }
+        assert_eq!(count, PRODUCERS * EVENTS);
+        assert!(last.iter().all(|&last| last == Some(EVENTS - 1)));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn test_free_cpus() {
+        assert_eq!(free_cpus(0b0110, 5), [0, 3, 4]);
+        assert_eq!(free_cpus(0b1111, 4), []);
+        assert_eq!(free_cpus(u64::MAX, 8), []);
+        // Beyond the mask, a CPU is not known to be free.
+        assert_eq!(free_cpus(0, 66).len(), 64);
+    }
+
+    #[test]
+    fn test_counters() {
+        let counters = WorkerCounters::new();
+        counters.posted.fetch_add(3, Ordering::Relaxed);
+        counters.record_latency(100);
+        counters.record_latency(300);
+        counters.record_latency(200);
+        let stats = counters.snapshot();
+        assert_eq!(stats.posted, 3);
+        assert_eq!(stats.total_queue_latency_ns, 600);
+        assert_eq!(stats.max_queue_latency_ns, 300);
+    }
+
+    /// Events moved from a vCPU to a worker per second, through the ring alone, without the
+    /// backends and the wakeups: `cargo test -- --ignored bench_ring`.
+    #[test]
+    #[ignore]
+    fn bench_ring() {
+        const EVENTS: u64 = 1_000_000;
+        let ring = Arc::new(EventRing::new(VM_RING_SIZE));
+        let consumer = {
+            let ring = ring.clone();
+            std::thread::spawn(move || {
+                let mut taken = 0;
+                while taken < EVENTS {
+                    match ring.pop() {
+                        Some(_) => taken += 1,
+                        None => std::thread::yield_now(),
+                    }
+                }
+            })
+        };
+        let start = Instant::now();
+        for i in 0..EVENTS {
+            let mut event = i;
+            while let Err(back) = ring.push(event) {
+                event = back;
+                std::thread::yield_now();
+            }
+        }
+        consumer.join().unwrap();
+        let elapsed = start.elapsed();
+        std::println!(
+            "{} events in {:?}, {:.1} M events/s",
+            EVENTS,
+            elapsed,
+            EVENTS as f64 / elapsed.as_secs_f64() / 1e6
+        );
+    }
+}
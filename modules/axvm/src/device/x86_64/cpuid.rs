@@ -0,0 +1,462 @@
+//! CPUID as seen by a guest, filtered through a [`CpuidPolicy`].
+//!
+//! The vCPU executes CPUID on its CPU and applies the rules of the policy of its VM to the
+//! result: the bits of a rule are cleared, then its forced bits set. The policy may also answer
+//! the hypervisor leaves 0x4000_0000 to 0x4000_00ff itself, as KVM does, with the signature of
+//! this hypervisor instead of whatever the CPU returns there:
+//!
+//! | leaf          | EAX                          | EBX, ECX, EDX                     |
+//! |---------------|------------------------------|-----------------------------------|
+//! | `0x4000_0000` | highest hypervisor leaf      | [`HYPERVISOR_SIGNATURE`]          |
+//! | `0x4000_0001` | features, `HV_FEATURE_*`     | 0                                 |
+//!
+//! The other hypervisor leaves read as 0.
+//!
+//! Whatever the policy, the XSAVE features are those of the extended state the vCPU saves, see
+//! [`super::xstate`]: leaf 0xd lists its components, the features of leaf 7 whose state is not
+//! saved, AVX-512, AMX, MPX, PKU and CET, are cleared, and OSXSAVE of leaf 1 follows the CR4
+//! of the guest, as on the CPU.
+//!
+//! The APIC ids are those of the vCPU, not of the CPU it runs on: the initial APIC id of leaf 1,
+//! EBX bits 31:24, and the x2APIC id of the topology leaves 0xb and 0x1f, in EDX, are its vCPU
+//! id, as the MADT and the IPIs have it, see `ipi`.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid_count;
+
+use x86::vmx::vmcs;
+
+use super::exit_vcpu::ExitVcpu;
+use super::vmexit::vmcs_read;
+use super::xstate::{self, LEAF_XSAVE};
+use crate::config::entry::VmType;
+use crate::Result as HyperResult;
+
+/// The vendor signature of the hypervisor leaf 0x4000_0000, in EBX, ECX and EDX.
+pub const HYPERVISOR_SIGNATURE: &[u8; 12] = b"ArceOSAxVM\0\0";
+/// `HVC_PVCLOCK_SETUP` is available.
+pub const HV_FEATURE_PVCLOCK: u32 = 1 << 0;
+/// `HVC_GET_MEMORY_MAP` is available.
+pub const HV_FEATURE_MEMORY_MAP: u32 = 1 << 1;
+/// The console hypercalls, `HVC_CONSOLE_WRITE` and the console ring, are available.
+pub const HV_FEATURE_CONSOLE: u32 = 1 << 2;
+/// `HVC_LOG` is available.
+pub const HV_FEATURE_LOG: u32 = 1 << 3;
+
+const HYPERVISOR_LEAF_BASE: u32 = 0x4000_0000;
+const HYPERVISOR_LEAF_MAX: u32 = 0x4000_0001;
+const HYPERVISOR_LEAF_END: u32 = 0x4000_00ff;
+
+// Leaf 1.
+const LEAF_FEATURES: u32 = 1;
+const ECX_DTES64: u32 = 1 << 2;
+const ECX_MONITOR: u32 = 1 << 3;
+const ECX_DS_CPL: u32 = 1 << 4;
+const ECX_VMX: u32 = 1 << 5;
+const ECX_SMX: u32 = 1 << 6;
+const ECX_EIST: u32 = 1 << 7;
+const ECX_TM2: u32 = 1 << 8;
+const ECX_XTPR: u32 = 1 << 14;
+const ECX_PDCM: u32 = 1 << 15;
+const ECX_XSAVE: u32 = 1 << 26;
+const ECX_OSXSAVE: u32 = 1 << 27;
+const ECX_HYPERVISOR: u32 = 1 << 31;
+const EDX_DS: u32 = 1 << 21;
+const EDX_ACPI: u32 = 1 << 22;
+const EDX_TM: u32 = 1 << 29;
+const EDX_PBE: u32 = 1 << 31;
+const EBX_APIC_ID_SHIFT: u32 = 24;
+const EBX_APIC_ID: u32 = 0xff << EBX_APIC_ID_SHIFT;
+/// Leaf 6, thermal and power management.
+const LEAF_POWER: u32 = 6;
+// Leaf 7, subleaf 0.
+const LEAF_EXT_FEATURES: u32 = 7;
+const EBX_SGX: u32 = 1 << 2;
+const ECX_WAITPKG: u32 = 1 << 5;
+const ECX_SGX_LC: u32 = 1 << 30;
+/// MPX, bit 14, and AVX-512 F, DQ, IFMA, PF, ER, CD, BW and VL, bits 16, 17, 21, 26 to 28, 30
+/// and 31.
+const EBX_UNSAVED_XSTATE: u32 = 0xdc23_4000;
+/// AVX-512 VBMI, bit 1, PKU and OSPKE, bits 3 and 4, AVX-512 VBMI2, bit 6, CET shadow stacks,
+/// bit 7, and AVX-512 VNNI, BITALG and VPOPCNTDQ, bits 11, 12 and 14.
+const ECX_UNSAVED_XSTATE: u32 = 0x58da;
+/// AVX-512 4VNNIW, 4FMAPS and VP2INTERSECT, bits 2, 3 and 8, CET indirect branch tracking, bit
+/// 20, AMX BF16, bit 22, AVX-512 FP16, bit 23, and AMX TILE and INT8, bits 24 and 25.
+const EDX_UNSAVED_XSTATE: u32 = 0x03d0_010c;
+/// CR4.OSXSAVE.
+const CR4_OSXSAVE: u64 = 1 << 18;
+/// Leaf 0xa, architectural performance monitoring.
+const LEAF_PERFMON: u32 = 0xa;
+/// Leaves 0xb and 0x1f, the topology of the CPU, with the x2APIC id in EDX.
+const LEAF_TOPOLOGY: u32 = 0xb;
+const LEAF_TOPOLOGY_V2: u32 = 0x1f;
+// Leaf 0x8000_0001.
+const LEAF_EXT_AMD: u32 = 0x8000_0001;
+const ECX_SVM: u32 = 1 << 2;
+
+/// A register of the result of CPUID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuidReg {
+    Eax = 0,
+    Ebx = 1,
+    Ecx = 2,
+    Edx = 3,
+}
+
+/// The bits of `reg` cleared, then forced, for leaf `leaf` and subleaf `subleaf`, all the
+/// subleaves if `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuidRule {
+    pub leaf: u32,
+    pub subleaf: Option<u32>,
+    pub reg: CpuidReg,
+    pub clear: u32,
+    pub force: u32,
+}
+
+/// What CPUID returns to the guests of a VM, see the [module documentation](self).
+///
+/// The default hides what the guests cannot use: VMX, SMX and SVM, SGX, MONITOR/MWAIT and
+/// WAITPKG, the debug store, the thermal and power management and the performance monitoring,
+/// whose MSRs are not emulated unless the vCPU is given an emulated PMU, see
+/// [`VcpuDeviceConfig::with_pmu`](crate::VcpuDeviceConfig::with_pmu), which answers leaf 0xa
+/// itself. It sets the hypervisor-present bit and answers the hypervisor
+/// leaves:
+///
+/// ```ignore
+/// let policy = CpuidPolicy::default().clear(7, Some(0), CpuidReg::Ebx, 1 << 5);
+/// let devices = VcpuDeviceConfig::pc().with_cpuid_policy(policy);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuidPolicy {
+    rules: Vec<CpuidRule>,
+    hypervisor_leaves: bool,
+}
+
+impl CpuidPolicy {
+    /// CPUID as the CPU answers it.
+    pub const fn passthrough() -> Self {
+        Self {
+            rules: Vec::new(),
+            hypervisor_leaves: false,
+        }
+    }
+
+    /// The default policy, see [`CpuidPolicy`].
+    pub fn curated() -> Self {
+        use CpuidReg::*;
+        Self::passthrough()
+            .with_hypervisor_leaves()
+            .clear(
+                LEAF_FEATURES,
+                None,
+                Ecx,
+                ECX_DTES64
+                    | ECX_MONITOR
+                    | ECX_DS_CPL
+                    | ECX_VMX
+                    | ECX_SMX
+                    | ECX_EIST
+                    | ECX_TM2
+                    | ECX_XTPR
+                    | ECX_PDCM,
+            )
+            .force(LEAF_FEATURES, None, Ecx, ECX_HYPERVISOR)
+            .clear(
+                LEAF_FEATURES,
+                None,
+                Edx,
+                EDX_DS | EDX_ACPI | EDX_TM | EDX_PBE,
+            )
+            .clear(LEAF_POWER, None, Eax, u32::MAX)
+            .clear(LEAF_POWER, None, Ecx, u32::MAX)
+            .clear(LEAF_EXT_FEATURES, Some(0), Ebx, EBX_SGX)
+            .clear(LEAF_EXT_FEATURES, Some(0), Ecx, ECX_WAITPKG | ECX_SGX_LC)
+            .clear(LEAF_PERFMON, None, Eax, u32::MAX)
+            .clear(LEAF_PERFMON, None, Ebx, u32::MAX)
+            .clear(LEAF_PERFMON, None, Edx, u32::MAX)
+            .clear(LEAF_EXT_AMD, None, Ecx, ECX_SVM)
+    }
+
+    /// The policy `boot_vm` gives the guests of type `vm_type` which were not configured one:
+    /// the curated one for the guests this hypervisor knows, CPUID as the CPU answers it for
+    /// the others.
+    pub fn for_vm_type(vm_type: VmType) -> Self {
+        match vm_type {
+            VmType::VmTNimbOS | VmType::VmTLinux => Self::curated(),
+            VmType::VmTUnknown => Self::passthrough(),
+        }
+    }
+
+    /// Answer the hypervisor leaves with the signature of this hypervisor.
+    pub fn with_hypervisor_leaves(mut self) -> Self {
+        self.hypervisor_leaves = true;
+        self
+    }
+
+    /// Clear the bits `mask` of `reg` for `leaf` and `subleaf`, all the subleaves if `None`.
+    pub fn clear(self, leaf: u32, subleaf: Option<u32>, reg: CpuidReg, mask: u32) -> Self {
+        self.rule(CpuidRule {
+            leaf,
+            subleaf,
+            reg,
+            clear: mask,
+            force: 0,
+        })
+    }
+
+    /// Set the bits `mask` of `reg` for `leaf` and `subleaf`, all the subleaves if `None`.
+    pub fn force(self, leaf: u32, subleaf: Option<u32>, reg: CpuidReg, mask: u32) -> Self {
+        self.rule(CpuidRule {
+            leaf,
+            subleaf,
+            reg,
+            clear: 0,
+            force: mask,
+        })
+    }
+
+    /// Add `rule`, applied after the rules already there.
+    pub fn rule(mut self, rule: CpuidRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// What CPUID returns to the guest for `leaf` and `subleaf`, as EAX, EBX, ECX and EDX.
+    pub fn cpuid(&self, leaf: u32, subleaf: u32) -> [u32; 4] {
+        let mut regs = if (HYPERVISOR_LEAF_BASE..=HYPERVISOR_LEAF_END).contains(&leaf)
+            && self.hypervisor_leaves
+        {
+            hypervisor_leaf(leaf)
+        } else {
+            let result = unsafe { __cpuid_count(leaf, subleaf) };
+            [result.eax, result.ebx, result.ecx, result.edx]
+        };
+        let rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.leaf == leaf && rule.subleaf.map_or(true, |s| s == subleaf));
+        for rule in rules {
+            let reg = &mut regs[rule.reg as usize];
+            *reg = (*reg & !rule.clear) | rule.force;
+        }
+        regs
+    }
+}
+
+impl Default for CpuidPolicy {
+    fn default() -> Self {
+        Self::curated()
+    }
+}
+
+fn hypervisor_leaf(leaf: u32) -> [u32; 4] {
+    let signature =
+        |i: usize| u32::from_le_bytes(HYPERVISOR_SIGNATURE[i * 4..i * 4 + 4].try_into().unwrap());
+    match leaf {
+        HYPERVISOR_LEAF_BASE => [
+            HYPERVISOR_LEAF_MAX,
+            signature(0),
+            signature(1),
+            signature(2),
+        ],
+        HYPERVISOR_LEAF_MAX => [
+            HV_FEATURE_PVCLOCK | HV_FEATURE_MEMORY_MAP | HV_FEATURE_CONSOLE | HV_FEATURE_LOG,
+            0,
+            0,
+            0,
+        ],
+        _ => [0; 4],
+    }
+}
+
+/// The CPUID exits of a vCPU.
+pub(super) struct CpuidHandler {
+    policy: Arc<CpuidPolicy>,
+    /// Leaf 0xa of the emulated PMU of the vCPU, in place of what the policy answers.
+    perfmon: Option<[u32; 4]>,
+    /// The APIC id of the vCPU, its vCPU id.
+    apic_id: u32,
+}
+
+impl CpuidHandler {
+    pub fn new(policy: Arc<CpuidPolicy>, perfmon: Option<[u32; 4]>, apic_id: u32) -> Self {
+        Self {
+            policy,
+            perfmon,
+            apic_id,
+        }
+    }
+
+    /// Answer the CPUID of `vcpu`, of `instr_len` bytes, from the policy, for a guest of XCR0
+    /// `xcr0`.
+    pub fn handle<V: ExitVcpu>(&self, vcpu: &mut V, instr_len: u8, xcr0: u64) -> HyperResult {
+        let regs = vcpu.regs();
+        let (leaf, subleaf) = (regs.rax as u32, regs.rcx as u32);
+        let [eax, ebx, ecx, edx] = self.cpuid(leaf, subleaf, xcr0, vmcs_read(vmcs::guest::CR4));
+        let regs = vcpu.regs_mut();
+        regs.rax = eax as u64;
+        regs.rbx = ebx as u64;
+        regs.rcx = ecx as u64;
+        regs.rdx = edx as u64;
+        vcpu.advance_rip(instr_len)
+    }
+
+    /// What CPUID returns to the guest for `leaf` and `subleaf`, its XCR0 being `xcr0` and its
+    /// CR4 `cr4`.
+    fn cpuid(&self, leaf: u32, subleaf: u32, xcr0: u64, cr4: u64) -> [u32; 4] {
+        let [eax, mut ebx, mut ecx, mut edx] = match self.perfmon {
+            Some(perfmon) if leaf == LEAF_PERFMON => perfmon,
+            _ if leaf == LEAF_XSAVE => xstate::xsave_leaf(subleaf, xcr0),
+            _ => self.policy.cpuid(leaf, subleaf),
+        };
+        match (leaf, subleaf) {
+            (LEAF_FEATURES, _) => {
+                ecx &= !ECX_OSXSAVE;
+                if cr4 & CR4_OSXSAVE != 0 && ecx & ECX_XSAVE != 0 {
+                    ecx |= ECX_OSXSAVE;
+                }
+                ebx = (ebx & !EBX_APIC_ID) | ((self.apic_id << EBX_APIC_ID_SHIFT) & EBX_APIC_ID);
+            }
+            (LEAF_TOPOLOGY | LEAF_TOPOLOGY_V2, _) => edx = self.apic_id,
+            (LEAF_EXT_FEATURES, 0) => {
+                ebx &= !EBX_UNSAVED_XSTATE;
+                ecx &= !ECX_UNSAVED_XSTATE;
+                edx &= !EDX_UNSAVED_XSTATE;
+            }
+            _ => {}
+        }
+        [eax, ebx, ecx, edx]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CpuidReg::*;
+
+    fn host(leaf: u32, subleaf: u32) -> [u32; 4] {
+        let result = unsafe { __cpuid_count(leaf, subleaf) };
+        [result.eax, result.ebx, result.ecx, result.edx]
+    }
+
+    #[test]
+    fn test_rules() {
+        // In order, for the subleaves they name.
+        let policy = CpuidPolicy::passthrough()
+            .clear(LEAF_EXT_FEATURES, None, Ebx, u32::MAX)
+            .force(LEAF_EXT_FEATURES, Some(1), Ebx, 0x10)
+            .force(LEAF_EXT_FEATURES, Some(0), Ebx, 0x3)
+            .clear(LEAF_EXT_FEATURES, Some(0), Ebx, 0x1);
+        assert_eq!(policy.cpuid(LEAF_EXT_FEATURES, 0)[Ebx as usize], 0x2);
+        assert_eq!(policy.cpuid(LEAF_EXT_FEATURES, 1)[Ebx as usize], 0x10);
+        assert_eq!(policy.cpuid(LEAF_EXT_FEATURES, 2)[Ebx as usize], 0);
+        // The other registers and leaves as the CPU answers them.
+        let [eax, _, ecx, edx] = host(LEAF_EXT_FEATURES, 0);
+        let [guest_eax, _, guest_ecx, guest_edx] = policy.cpuid(LEAF_EXT_FEATURES, 0);
+        assert_eq!([guest_eax, guest_ecx, guest_edx], [eax, ecx, edx]);
+        assert_eq!(policy.cpuid(0, 0), host(0, 0));
+        assert_eq!(CpuidPolicy::passthrough().cpuid(0, 0), host(0, 0));
+    }
+
+    #[test]
+    fn test_hypervisor_leaves() {
+        let passthrough = CpuidPolicy::passthrough();
+        assert_eq!(
+            passthrough.cpuid(HYPERVISOR_LEAF_BASE, 0),
+            host(HYPERVISOR_LEAF_BASE, 0)
+        );
+
+        let policy = passthrough.with_hypervisor_leaves().clear(
+            HYPERVISOR_LEAF_MAX,
+            None,
+            Eax,
+            HV_FEATURE_LOG,
+        );
+        let [max, ebx, ecx, edx] = policy.cpuid(HYPERVISOR_LEAF_BASE, 0);
+        assert_eq!(max, HYPERVISOR_LEAF_MAX);
+        let signature: Vec<u8> = [ebx, ecx, edx]
+            .iter()
+            .flat_map(|reg| reg.to_le_bytes())
+            .collect();
+        assert_eq!(signature, HYPERVISOR_SIGNATURE);
+        assert_eq!(
+            policy.cpuid(HYPERVISOR_LEAF_MAX, 0),
+            [
+                HV_FEATURE_PVCLOCK | HV_FEATURE_MEMORY_MAP | HV_FEATURE_CONSOLE,
+                0,
+                0,
+                0
+            ]
+        );
+        assert_eq!(policy.cpuid(HYPERVISOR_LEAF_MAX + 1, 0), [0; 4]);
+        assert_eq!(policy.cpuid(HYPERVISOR_LEAF_END, 0), [0; 4]);
+    }
+
+    #[test]
+    fn test_curated() {
+        let policy = CpuidPolicy::for_vm_type(VmType::VmTLinux);
+        assert_eq!(policy, CpuidPolicy::default());
+        assert_eq!(
+            CpuidPolicy::for_vm_type(VmType::VmTUnknown),
+            CpuidPolicy::passthrough()
+        );
+
+        let [_, _, ecx, edx] = policy.cpuid(LEAF_FEATURES, 0);
+        assert_eq!(
+            ecx & (ECX_VMX | ECX_SMX | ECX_MONITOR | ECX_HYPERVISOR),
+            ECX_HYPERVISOR
+        );
+        assert_eq!(edx & (EDX_DS | EDX_ACPI | EDX_TM | EDX_PBE), 0);
+        let [_, ebx, ecx, _] = policy.cpuid(LEAF_EXT_FEATURES, 0);
+        assert_eq!((ebx & EBX_SGX, ecx & (ECX_WAITPKG | ECX_SGX_LC)), (0, 0));
+        assert_eq!(policy.cpuid(LEAF_POWER, 0)[Eax as usize], 0);
+        assert_eq!(
+            policy.cpuid(LEAF_PERFMON, 0),
+            [0, 0, host(LEAF_PERFMON, 0)[2], 0]
+        );
+        assert_eq!(policy.cpuid(LEAF_EXT_AMD, 0)[Ecx as usize] & ECX_SVM, 0);
+        assert_eq!(
+            policy.cpuid(HYPERVISOR_LEAF_BASE, 0)[0],
+            HYPERVISOR_LEAF_MAX
+        );
+    }
+
+    #[test]
+    fn test_apic_id() {
+        let policy = Arc::new(CpuidPolicy::passthrough());
+        for apic_id in [0, 5, 0x1234] {
+            let handler = CpuidHandler::new(policy.clone(), None, apic_id);
+            let ebx = handler.cpuid(LEAF_FEATURES, 0, 0, 0)[Ebx as usize];
+            assert_eq!(ebx >> EBX_APIC_ID_SHIFT, apic_id & 0xff);
+            assert_eq!(ebx & !EBX_APIC_ID, host(LEAF_FEATURES, 0)[1] & !EBX_APIC_ID);
+            for leaf in [LEAF_TOPOLOGY, LEAF_TOPOLOGY_V2] {
+                for subleaf in 0..3 {
+                    let [eax, ebx, ecx, edx] = handler.cpuid(leaf, subleaf, 0, 0);
+                    let [host_eax, host_ebx, host_ecx, _] = host(leaf, subleaf);
+                    assert_eq!(
+                        [eax, ebx, ecx, edx],
+                        [host_eax, host_ebx, host_ecx, apic_id]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_osxsave() {
+        let handler = CpuidHandler::new(Arc::new(CpuidPolicy::passthrough()), None, 0);
+        let xsave = host(LEAF_FEATURES, 0)[2] & ECX_XSAVE != 0;
+        let ecx = |cr4| handler.cpuid(LEAF_FEATURES, 0, 0, cr4)[Ecx as usize] & ECX_OSXSAVE;
+        assert_eq!(ecx(0), 0);
+        assert_eq!(ecx(CR4_OSXSAVE) != 0, xsave);
+        // Not without XSAVE.
+        let policy = CpuidPolicy::passthrough().clear(LEAF_FEATURES, None, Ecx, ECX_XSAVE);
+        let handler = CpuidHandler::new(Arc::new(policy), None, 0);
+        assert_eq!(
+            handler.cpuid(LEAF_FEATURES, 0, 0, CR4_OSXSAVE)[Ecx as usize] & ECX_OSXSAVE,
+            0
+        );
+    }
+}
@@ -0,0 +1,502 @@
+//! The control register accesses of the guests: MOV to and from CR0, CR3 and CR4, CLTS and LMSW.
+//!
+//! The bits of CR0 and CR4 set in their guest/host masks belong to the host: the guest reads them
+//! from the read shadows, and the writes changing them exit (SDM Vol. 3C, Section 25.6.6). Besides
+//! the bits VMX fixes, [`CrAccess::start`] puts CR0.PE and CR0.PG in the masks, so that the
+//! [`GuestMode`] of the vCPU follows the mode transitions of its guest, and the bits of its
+//! [`CrPinning`]. The exits are emulated with the checks of the CPU, the guest value going to the
+//! read shadow and a value VMX accepts to the guest register, and EFER.LMA and the IA-32e mode
+//! guest entry control following CR0.PG when EFER.LME is set. A MOV from a control register reads
+//! what the guest would natively, the read shadow for the bits of the host.
+//!
+//! As the CPU would, an emulated write invalidates the TLB entries of the vCPU and, in PAE
+//! paging, loads the PDPTEs from CR3 into the VMCS (Vol. 3A, Section 4.4.1), where VM entries take
+//! them from with EPT. CR8 is left to hypercraft.
+
+use bit_field::BitField;
+use hypercraft::GeneralRegisters;
+use memory_addr::PAGE_SIZE_4K;
+use x86::bits64::vmx::{invvpid, vmwrite, InvVpidType};
+use x86::msr::{rdmsr, IA32_VMX_CR0_FIXED0, IA32_VMX_CR0_FIXED1};
+use x86::msr::{IA32_VMX_CR4_FIXED0, IA32_VMX_CR4_FIXED1};
+use x86::vmx::vmcs;
+use x86::vmx::vmcs::control::{EntryControls, SecondaryControls};
+
+use super::exit_vcpu::ExitVcpu;
+use super::inject_gp;
+use super::vmexit::{vmcs_read, ExitContext};
+use crate::config::entry::vm_cfg_entry;
+use crate::{phys_to_virt, Error as HyperError, PhysAddr, Result as HyperResult};
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_TS: u64 = 1 << 3;
+const CR0_WP: u64 = 1 << 16;
+const CR0_NW: u64 = 1 << 29;
+const CR0_CD: u64 = 1 << 30;
+const CR0_PG: u64 = 1 << 31;
+/// The bits LMSW loads: PE, MP, EM and TS.
+const CR0_LMSW: u64 = 0xf;
+/// The bits of CR0 which, changed in PAE paging, reload the PDPTEs.
+const CR0_PDPTE_RELOAD: u64 = CR0_PG | CR0_CD | CR0_NW;
+
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_PGE: u64 = 1 << 7;
+const CR4_UMIP: u64 = 1 << 11;
+const CR4_VMXE: u64 = 1 << 13;
+const CR4_PCIDE: u64 = 1 << 17;
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+/// The bits of CR4 which, changed in PAE paging, reload the PDPTEs.
+const CR4_PDPTE_RELOAD: u64 = CR4_PAE | CR4_PGE | CR4_PSE | CR4_SMEP;
+
+/// Bit 63 of a CR3 written with CR4.PCIDE set, keeping the TLB entries of the PCID. Not stored.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+/// The PCID of CR3, which must be 0 when CR4.PCIDE is set.
+const CR3_PCID: u64 = 0xfff;
+/// The PDPT of PAE paging, 32-byte aligned below 4G.
+const CR3_PAE_PDPT: u64 = 0xffff_ffe0;
+
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+
+/// A PAE PDPTE: present, and its reserved bits 1, 2 and 5 to 8.
+const PDPTE_PRESENT: u64 = 1 << 0;
+const PDPTE_RESERVED: u64 = 0x1e6;
+
+/// The operating mode of a guest, on which the size of the operands and addresses of its code
+/// depends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GuestMode {
+    /// CR0.PE clear: 16-bit code.
+    Real,
+    /// Protected mode, with paging or not: 16 or 32-bit code, or virtual-8086 mode.
+    Protected,
+    /// IA-32e mode, EFER.LMA set: 64-bit code, or compatibility mode.
+    Long,
+}
+
+impl GuestMode {
+    fn new(cr0: u64, efer: u64) -> Self {
+        if efer & EFER_LMA != 0 {
+            Self::Long
+        } else if cr0 & CR0_PE != 0 {
+            Self::Protected
+        } else {
+            Self::Real
+        }
+    }
+
+    /// The mode of the guest of the VMCS loaded on the current CPU.
+    pub fn current() -> Self {
+        Self::new(
+            vmcs_read(vmcs::guest::CR0),
+            vmcs_read(vmcs::guest::IA32_EFER_FULL),
+        )
+    }
+
+    /// The size of the operands and addresses of the code run in this mode with the CS access
+    /// rights `cs_access_rights`, in bits: CS.L selects 64-bit code in IA-32e mode, CS.D 32-bit
+    /// code out of real mode. Virtual-8086 mode runs with CS.D clear.
+    pub fn code_bitness(self, cs_access_rights: u64) -> u32 {
+        match self {
+            Self::Long if cs_access_rights.get_bit(13) => 64,
+            Self::Real => 16,
+            _ if cs_access_rights.get_bit(14) => 32,
+            _ => 16,
+        }
+    }
+}
+
+/// The bits of CR0 and CR4 a guest cannot clear once it set them, as Linux pins CR0.WP and the
+/// CR4 protections so that a compromised kernel cannot turn them off: a write clearing one takes
+/// #GP. The bits the guest never set are not forced on, and the guest keeps them set until it is
+/// reset, rebooting through kexec included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CrPinning {
+    pub cr0: u64,
+    pub cr4: u64,
+}
+
+impl CrPinning {
+    /// CR0.WP, CR4.SMEP, CR4.SMAP and CR4.UMIP, for a hardened guest.
+    pub const fn hardened() -> Self {
+        Self {
+            cr0: CR0_WP,
+            cr4: CR4_SMEP | CR4_SMAP | CR4_UMIP,
+        }
+    }
+}
+
+/// The bits of CR0 or CR4 VMX fixes in VMX operation (SDM Vol. 3D, Appendix A.7 and A.8).
+#[derive(Debug, Clone, Copy)]
+struct FixedBits {
+    must_be_1: u64,
+    may_be_1: u64,
+}
+
+impl FixedBits {
+    /// PE and PG are free with the unrestricted guests hypercraft requires. NW and CD are kept
+    /// clear in the guest register, as hypercraft does: they would stay set in VMX root operation.
+    fn cr0() -> Self {
+        let (fixed0, fixed1) = unsafe { (rdmsr(IA32_VMX_CR0_FIXED0), rdmsr(IA32_VMX_CR0_FIXED1)) };
+        Self {
+            must_be_1: fixed0 & !(CR0_PE | CR0_PG),
+            may_be_1: fixed1 & !(CR0_NW | CR0_CD),
+        }
+    }
+
+    fn cr4() -> Self {
+        let (fixed0, fixed1) = unsafe { (rdmsr(IA32_VMX_CR4_FIXED0), rdmsr(IA32_VMX_CR4_FIXED1)) };
+        Self {
+            must_be_1: fixed0,
+            may_be_1: fixed1,
+        }
+    }
+
+    /// The guest register holding the guest value `value`.
+    fn apply(self, value: u64) -> u64 {
+        (value & self.may_be_1) | self.must_be_1
+    }
+
+    /// The bits in which the guest register may differ from the guest value.
+    fn host_owned(self) -> u64 {
+        self.must_be_1 | !self.may_be_1
+    }
+}
+
+/// The fields of CR0 and CR4 in the VMCS.
+struct CrFields {
+    guest: u32,
+    read_shadow: u32,
+    mask: u32,
+}
+
+const CR0_FIELDS: CrFields = CrFields {
+    guest: vmcs::guest::CR0,
+    read_shadow: vmcs::control::CR0_READ_SHADOW,
+    mask: vmcs::control::CR0_GUEST_HOST_MASK,
+};
+const CR4_FIELDS: CrFields = CrFields {
+    guest: vmcs::guest::CR4,
+    read_shadow: vmcs::control::CR4_READ_SHADOW,
+    mask: vmcs::control::CR4_GUEST_HOST_MASK,
+};
+
+impl CrFields {
+    /// The value the guest reads.
+    fn read(&self) -> u64 {
+        let mask = vmcs_read(self.mask);
+        (vmcs_read(self.guest) & !mask) | (vmcs_read(self.read_shadow) & mask)
+    }
+
+    /// Give the host the bits of `bits`, their read shadow taking the value the guest reads.
+    fn own(&self, bits: u64) -> HyperResult {
+        let mask = vmcs_read(self.mask);
+        let shadow = (vmcs_read(self.read_shadow) & mask) | (vmcs_read(self.guest) & !mask);
+        vmcs_write(self.read_shadow, shadow)?;
+        vmcs_write(self.mask, mask | bits)
+    }
+}
+
+fn vmcs_write(field: u32, value: u64) -> HyperResult {
+    unsafe { vmwrite(field, value) }.map_err(|err| {
+        warn!("failed to write VMCS field {:#x}: {:?}", field, err);
+        HyperError::BadState
+    })
+}
+
+/// The control register accesses of a vCPU, see the [module documentation](self).
+pub(super) struct CrAccess {
+    pinning: CrPinning,
+    mode: GuestMode,
+    cr0_fixed: FixedBits,
+    cr4_fixed: FixedBits,
+}
+
+impl CrAccess {
+    pub fn new(pinning: CrPinning) -> Self {
+        Self {
+            pinning,
+            mode: GuestMode::Real,
+            cr0_fixed: FixedBits::cr0(),
+            cr4_fixed: FixedBits::cr4(),
+        }
+    }
+
+    /// The mode of the guest as of its last control register write.
+    pub fn mode(&self) -> GuestMode {
+        self.mode
+    }
+
+    /// Have the writes to CR0.PE, CR0.PG and the pinned bits exit, on the VMCS of the vCPU loaded
+    /// on the current CPU, and take the mode of the guest from it. Called before the first VM
+    /// entry of the vCPU, once its state is restored if it is.
+    pub fn start(&mut self) {
+        let cr0 = self.cr0_fixed.host_owned() | CR0_PE | CR0_PG | self.pinning.cr0;
+        let cr4 = self.cr4_fixed.host_owned() | self.pinning.cr4;
+        if CR0_FIELDS.own(cr0).and(CR4_FIELDS.own(cr4)).is_err() {
+            warn!("failed to set the CR0 and CR4 guest/host masks");
+        }
+        self.mode = GuestMode::current();
+    }
+
+    /// Handle a control register access exit of `vcpu` (SDM Vol. 3C, Table 28-3), `None` for the
+    /// CR8 accesses, left to hypercraft.
+    pub fn handle<V: ExitVcpu>(&mut self, vcpu: &mut V, ctx: &ExitContext) -> Option<HyperResult> {
+        let qual = ctx.qualification;
+        let cr = qual.get_bits(0..4) as u8;
+        let gpr = qual.get_bits(8..12) as u8;
+        // The operand of MOV is 32-bit out of 64-bit code.
+        let operand_mask = if self.in_64bit_code() {
+            u64::MAX
+        } else {
+            0xffff_ffff
+        };
+        let value = match (qual.get_bits(4..6), cr) {
+            // MOV to CR.
+            (0, 0 | 3 | 4) => read_gpr(vcpu.regs(), gpr) & operand_mask,
+            // MOV from CR.
+            (1, 0 | 3 | 4) => {
+                let value = self.read(cr) & operand_mask;
+                return Some(
+                    write_gpr(vcpu.regs_mut(), gpr, value)
+                        .and_then(|_| vcpu.advance_rip(ctx.exit_instruction_length as _)),
+                );
+            }
+            // CLTS.
+            (2, 0) => CR0_FIELDS.read() & !CR0_TS,
+            // LMSW, which sets PE but does not clear it.
+            (3, 0) => {
+                let old = CR0_FIELDS.read();
+                (old & !CR0_LMSW) | (qual.get_bits(16..20) & CR0_LMSW) | (old & CR0_PE)
+            }
+            _ => return None,
+        };
+        let pdptes = match self.check_write(cr, value) {
+            Ok(pdptes) => pdptes,
+            Err(reason) => {
+                warn!("guest write of {:#x} to CR{}: {}", value, cr, reason);
+                inject_gp(vcpu);
+                return Some(Ok(()));
+            }
+        };
+        Some(
+            self.write(cr, value, pdptes)
+                .and_then(|_| vcpu.advance_rip(ctx.exit_instruction_length as _)),
+        )
+    }
+
+    /// Whether the guest runs 64-bit code.
+    fn in_64bit_code(&self) -> bool {
+        self.mode
+            .code_bitness(vmcs_read(vmcs::guest::CS_ACCESS_RIGHTS))
+            == 64
+    }
+
+    /// The value the guest reads from CR`cr`, 0, 3 or 4.
+    fn read(&self, cr: u8) -> u64 {
+        match cr {
+            0 => CR0_FIELDS.read(),
+            4 => CR4_FIELDS.read(),
+            _ => vmcs_read(vmcs::guest::CR3),
+        }
+    }
+
+    /// Why writing `value` to CR`cr` takes #GP, if it does (SDM Vol. 2B, MOV to control
+    /// registers), or clears a pinned bit. Otherwise the PDPTEs the write loads, if any.
+    fn check_write(&self, cr: u8, value: u64) -> Result<Option<[u64; 4]>, &'static str> {
+        let old = self.read(cr);
+        let long_mode = self.mode == GuestMode::Long;
+        let efer = vmcs_read(vmcs::guest::IA32_EFER_FULL);
+        match cr {
+            0 => {
+                if value >> 32 != 0 {
+                    return Err("reserved bits set");
+                }
+                if value & CR0_PG != 0 && value & CR0_PE == 0 {
+                    return Err("paging without protection");
+                }
+                if value & CR0_NW != 0 && value & CR0_CD == 0 {
+                    return Err("not-write-through with caching");
+                }
+                let paging_on = value & !old & CR0_PG != 0;
+                if paging_on && efer & EFER_LME != 0 && self.read(4) & CR4_PAE == 0 {
+                    return Err("IA-32e mode without PAE");
+                }
+                if old & !value & CR0_PG != 0 && self.in_64bit_code() {
+                    return Err("paging off in 64-bit code");
+                }
+                if old & !value & self.pinning.cr0 != 0 {
+                    return Err("pinned bits cleared");
+                }
+            }
+            4 => {
+                // No nested VMX.
+                if value & (!self.cr4_fixed.may_be_1 | CR4_VMXE) != 0 {
+                    return Err("reserved bits set");
+                }
+                if long_mode && value & CR4_PAE == 0 {
+                    return Err("PAE off in IA-32e mode");
+                }
+                let pcid_on = value & !old & CR4_PCIDE != 0;
+                if pcid_on && (!long_mode || vmcs_read(vmcs::guest::CR3) & CR3_PCID != 0) {
+                    return Err("PCIDs out of IA-32e mode or with a PCID");
+                }
+                if old & !value & self.pinning.cr4 != 0 {
+                    return Err("pinned bits cleared");
+                }
+            }
+            _ => {}
+        }
+        // CR`n` once `value` is written.
+        let after = |n: u8| if n == cr { value } else { self.read(n) };
+        let reload = match cr {
+            0 => (old ^ value) & CR0_PDPTE_RELOAD != 0,
+            4 => (old ^ value) & CR4_PDPTE_RELOAD != 0,
+            _ => true,
+        };
+        // Paging in IA-32e mode once CR0.PG is written, as LMA then follows LME.
+        let lma = match cr {
+            0 => efer & EFER_LME != 0,
+            _ => efer & EFER_LMA != 0,
+        };
+        if reload && after(0) & CR0_PG != 0 && after(4) & CR4_PAE != 0 && !lma {
+            return read_pdptes(after(3)).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Write `value`, checked, to CR`cr`, loading `pdptes` into the VMCS if given.
+    fn write(&mut self, cr: u8, value: u64, pdptes: Option<[u64; 4]>) -> HyperResult {
+        let old = self.read(cr);
+        let mut efer = vmcs_read(vmcs::guest::IA32_EFER_FULL);
+        match cr {
+            0 => {
+                if (old ^ value) & CR0_PG != 0 && efer & EFER_LME != 0 {
+                    efer.set_bit(10, value & CR0_PG != 0);
+                    set_ia32e_mode(efer)?;
+                }
+                vmcs_write(vmcs::guest::CR0, self.cr0_fixed.apply(value))?;
+                vmcs_write(vmcs::control::CR0_READ_SHADOW, value)?;
+                self.mode = GuestMode::new(value, efer);
+            }
+            4 => {
+                vmcs_write(vmcs::guest::CR4, self.cr4_fixed.apply(value))?;
+                vmcs_write(vmcs::control::CR4_READ_SHADOW, value)?;
+            }
+            _ => {
+                let value = match self.read(4) & CR4_PCIDE {
+                    0 => value,
+                    _ => value & !CR3_NO_FLUSH,
+                };
+                vmcs_write(vmcs::guest::CR3, value)?;
+            }
+        }
+        if let Some(pdptes) = pdptes {
+            let fields = [
+                vmcs::guest::PDPTE0_FULL,
+                vmcs::guest::PDPTE1_FULL,
+                vmcs::guest::PDPTE2_FULL,
+                vmcs::guest::PDPTE3_FULL,
+            ];
+            for (field, pdpte) in fields.into_iter().zip(pdptes) {
+                vmcs_write(field, pdpte)?;
+            }
+        }
+        flush_tlb();
+        Ok(())
+    }
+}
+
+/// Write `efer` to the guest EFER, and its LMA to the IA-32e mode guest entry control.
+fn set_ia32e_mode(efer: u64) -> HyperResult {
+    let ia32e_mode = EntryControls::IA32E_MODE_GUEST.bits() as u64;
+    let controls = vmcs_read(vmcs::control::VMENTRY_CONTROLS);
+    let controls = match efer & EFER_LMA {
+        0 => controls & !ia32e_mode,
+        _ => controls | ia32e_mode,
+    };
+    vmcs_write(vmcs::guest::IA32_EFER_FULL, efer)?;
+    vmcs_write(vmcs::control::VMENTRY_CONTROLS, controls)
+}
+
+/// The PDPTEs of the PAE PDPT of `cr3`, the reason the write of `cr3` or of CR0 or CR4 takes #GP
+/// if one has reserved bits set. A PDPT out of the RAM of the VM is rejected the same way.
+fn read_pdptes(cr3: u64) -> Result<[u64; 4], &'static str> {
+    let pdpt = (cr3 & CR3_PAE_PDPT) as usize;
+    let hpa = crate::vm::current_vm_id()
+        .and_then(|vm_id| vm_cfg_entry(vm_id as usize))
+        .and_then(|cfg| cfg.ram_page(pdpt & !(PAGE_SIZE_4K - 1)))
+        .map(|(hpa, _)| hpa + pdpt % PAGE_SIZE_4K)
+        .ok_or("PDPT out of RAM")?;
+    let ptr = phys_to_virt(PhysAddr::from(hpa)).as_ptr() as *const u64;
+    let mut pdptes = [0; 4];
+    for (i, pdpte) in pdptes.iter_mut().enumerate() {
+        // SAFETY: the 32-byte aligned PDPT is within the page, which is guest RAM. The guest may
+        // write it concurrently.
+        *pdpte = unsafe { ptr.add(i).read_volatile() };
+        if *pdpte & PDPTE_PRESENT != 0 && *pdpte & PDPTE_RESERVED != 0 {
+            return Err("PDPTE reserved bits set");
+        }
+    }
+    Ok(pdptes)
+}
+
+/// Invalidate the TLB entries of the VPID of the vCPU, if it has one: without, every VM entry
+/// does.
+fn flush_tlb() {
+    let enable_vpid = SecondaryControls::ENABLE_VPID.bits() as u64;
+    if vmcs_read(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS) & enable_vpid == 0 {
+        return;
+    }
+    let vpid = vmcs_read(vmcs::control::VPID) as u16;
+    if let Err(err) = unsafe { invvpid(InvVpidType::SingleContext(vpid)) } {
+        warn!("INVVPID({}) failed: {:?}", vpid, err);
+    }
+}
+
+/// General-purpose register `index` of a control register access, RSP being in the VMCS.
+fn read_gpr(regs: &GeneralRegisters, index: u8) -> u64 {
+    match index {
+        0 => regs.rax,
+        1 => regs.rcx,
+        2 => regs.rdx,
+        3 => regs.rbx,
+        4 => vmcs_read(vmcs::guest::RSP),
+        5 => regs.rbp,
+        6 => regs.rsi,
+        7 => regs.rdi,
+        8 => regs.r8,
+        9 => regs.r9,
+        10 => regs.r10,
+        11 => regs.r11,
+        12 => regs.r12,
+        13 => regs.r13,
+        14 => regs.r14,
+        _ => regs.r15,
+    }
+}
+
+fn write_gpr(regs: &mut GeneralRegisters, index: u8, value: u64) -> HyperResult {
+    let gpr = match index {
+        0 => &mut regs.rax,
+        1 => &mut regs.rcx,
+        2 => &mut regs.rdx,
+        3 => &mut regs.rbx,
+        4 => return vmcs_write(vmcs::guest::RSP, value),
+        5 => &mut regs.rbp,
+        6 => &mut regs.rsi,
+        7 => &mut regs.rdi,
+        8 => &mut regs.r8,
+        9 => &mut regs.r9,
+        10 => &mut regs.r10,
+        11 => &mut regs.r11,
+        12 => &mut regs.r12,
+        13 => &mut regs.r13,
+        14 => &mut regs.r14,
+        _ => &mut regs.r15,
+    };
+    *gpr = value;
+    Ok(())
+}
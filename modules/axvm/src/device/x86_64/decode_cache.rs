@@ -0,0 +1,181 @@
+//! The MMIO instructions decoded recently, by where their code is.
+//!
+//! MMIO-heavy guests exit on the same few instructions, at the same RIPs, over and over. Each CPU
+//! keeps, in the per-VM [`DeviceList`], what the MMIO emulation needs of the last
+//! [`DECODE_CACHE_ENTRIES`] instructions it decoded: the [`MmioInstr`], with the bytes it was
+//! decoded from and the host address of these bytes, which stands for their guest physical
+//! address. An exit at a cached address is emulated without decoding the instruction again.
+//!
+//! The bytes at RIP are always read, and an entry is only used if they are the bytes it was
+//! decoded from, so code rewritten by the guest is decoded again, whatever its page tables or
+//! how it was written. The least recently used entry is replaced.
+//!
+//! [`DeviceList`]: super::DeviceList
+
+use memory_addr::PAGE_SIZE_4K;
+use x86::vmx::vmcs;
+
+use super::string_io::{GuestLinearMemory, VmxGuestMemory};
+use super::vmexit::{vmcs_read, ExitContext};
+use super::{Operand, MAX_INSTR_LEN};
+
+/// Instructions cached on each CPU.
+pub const DECODE_CACHE_ENTRIES: usize = 16;
+
+/// What an emulated MMIO instruction does with the value accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum MmioOp {
+    /// `mov`, or `movzx` for a read: the value is stored or loaded as is, zero-extended.
+    Mov,
+    /// `movsx` or `movsxd`, a read only: the value is loaded sign-extended.
+    Movsx,
+    /// `test`, a read only: the flags are set from the value and the operand.
+    Test,
+}
+
+/// An MMIO instruction, as much of it as the emulation needs: the memory operand itself comes
+/// from the EPT violation.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct MmioInstr {
+    pub len: u8,
+    pub access_size: u8,
+    /// The register loaded, the register or immediate stored, or the other operand of `test`.
+    pub operand: Operand,
+    pub op: MmioOp,
+    pub is_write: bool,
+}
+
+/// The bytes at guest RIP of the current exit.
+#[derive(Clone)]
+pub(super) struct GuestCode {
+    bytes: [u8; MAX_INSTR_LEN],
+    /// Bytes read, up to the end of the page of RIP.
+    len: usize,
+    /// The size of the operands and addresses of the code, 16, 32 or 64 bits.
+    pub bitness: u32,
+    /// The host address of the first byte.
+    addr: u64,
+}
+
+impl GuestCode {
+    /// Read the instruction at guest RIP, up to the end of its page, in the mode of the guest
+    /// as of `ctx`. `None` if RIP cannot be translated.
+    pub fn peek(vm_id: u32, ctx: &ExitContext) -> Option<Self> {
+        let bitness = ctx
+            .guest_mode()
+            .code_bitness(vmcs_read(vmcs::guest::CS_ACCESS_RIGHTS));
+        let rip = vmcs_read(vmcs::guest::CS_BASE).wrapping_add(ctx.guest_rip as u64);
+        let mut mem = VmxGuestMemory::current(vm_id)?;
+        let ptr = mem.translate(rip, false).ok()?;
+        let len = MAX_INSTR_LEN.min(PAGE_SIZE_4K - rip as usize % PAGE_SIZE_4K);
+        let mut bytes = [0; MAX_INSTR_LEN];
+        for (i, byte) in bytes[..len].iter_mut().enumerate() {
+            // The guest may rewrite its code concurrently, as with the decoder.
+            *byte = unsafe { ptr.add(i).read_volatile() };
+        }
+        Some(Self {
+            bytes,
+            len,
+            bitness,
+            addr: ptr as u64,
+        })
+    }
+
+    /// The code `bytes`, at host address `addr`, of `bitness`, as if read from guest RIP.
+    #[cfg(test)]
+    pub fn from_bytes(bytes: &[u8], bitness: u32, addr: u64) -> Self {
+        let len = bytes.len().min(MAX_INSTR_LEN);
+        let mut code = [0; MAX_INSTR_LEN];
+        code[..len].copy_from_slice(&bytes[..len]);
+        Self {
+            bytes: code,
+            len,
+            bitness,
+            addr,
+        }
+    }
+
+    /// The bytes read.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CacheEntry {
+    addr: u64,
+    bitness: u32,
+    bytes: [u8; MAX_INSTR_LEN],
+    instr: MmioInstr,
+    last_used: u64,
+}
+
+impl CacheEntry {
+    /// Whether this entry was decoded from `code`.
+    fn matches(&self, code: &GuestCode) -> bool {
+        let len = self.instr.len as usize;
+        self.addr == code.addr
+            && self.bitness == code.bitness
+            && code.bytes().get(..len) == Some(&self.bytes[..len])
+    }
+}
+
+/// The instructions decoded on one CPU, see the [module documentation](self).
+pub(super) struct DecodeCache {
+    entries: [Option<CacheEntry>; DECODE_CACHE_ENTRIES],
+    /// Incremented by each lookup, the age of the entries.
+    clock: u64,
+}
+
+impl DecodeCache {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; DECODE_CACHE_ENTRIES],
+            clock: 0,
+        }
+    }
+
+    /// The instruction decoded from `code`, if cached.
+    pub fn get(&mut self, code: &GuestCode) -> Option<MmioInstr> {
+        self.clock += 1;
+        let entry = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|e| e.matches(code))?;
+        entry.last_used = self.clock;
+        Some(entry.instr)
+    }
+
+    /// Cache `instr`, decoded from `code`, in place of the entry of the same address or of the
+    /// least recently used one. Not if it is longer than the bytes read.
+    pub fn insert(&mut self, code: &GuestCode, instr: MmioInstr) {
+        let len = instr.len as usize;
+        if len > code.len {
+            return;
+        }
+        let slot = self
+            .entries
+            .iter()
+            .position(|e| matches!(e, Some(e) if e.addr == code.addr))
+            .or_else(|| self.entries.iter().position(Option::is_none))
+            .or_else(|| {
+                let lru = self
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, e)| e.map_or(0, |e| e.last_used));
+                lru.map(|(index, _)| index)
+            })
+            .unwrap_or(0);
+        let mut bytes = [0; MAX_INSTR_LEN];
+        bytes[..len].copy_from_slice(&code.bytes[..len]);
+        self.entries[slot] = Some(CacheEntry {
+            addr: code.addr,
+            bitness: code.bitness,
+            bytes,
+            instr,
+            last_used: self.clock,
+        });
+    }
+}
@@ -1,15 +1,28 @@
 //! Emulated Local APIC. (SDM Vol. 3A, Chapter 10)
 
 #![allow(dead_code)]
+use crate::device::{DeviceState, StateReader, StateWriter};
 use crate::{Error as HyperError, Result as HyperResult};
-use axhal::time::current_time_nanos;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use axhal::time::{current_time_nanos, ticks_to_nanos};
 use bit_field::BitField;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lock_stat::Mutex;
 
+use super::super::ipi::{logical_apic_id, write_icr};
+use super::super::{guest_tsc, IA32_TSC_DEADLINE};
 use super::{msr_proxy_factory, msr_proxy_struct};
 use hypercraft::VirtMsrOps;
 
-const APIC_FREQ_MHZ: u64 = 1000; // 1000 MHz
-const APIC_CYCLE_NANOS: u64 = 1000 / APIC_FREQ_MHZ;
+/// Frequency the emulated timer counts at before its divider, unless the vCPU is configured with
+/// another one.
+pub const DEFAULT_APIC_BUS_FREQ_HZ: u64 = 1_000_000_000;
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+const LOCAL_APIC_STATE_VERSION: u16 = 3;
+const APIC_BASE_STATE_VERSION: u16 = 1;
 
 /// Local APIC timer modes.
 #[derive(Debug, Copy, Clone)]
@@ -24,42 +37,139 @@ pub enum TimerMode {
     TscDeadline = 0b10,
 }
 
+/// Polls of a [`TimerDeadline`] and the polls which had to lock the timer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ApicTimerStats {
+    pub polls: u64,
+    pub lock_acquisitions: u64,
+}
+
+/// The next interrupt time of an [`ApicTimer`] or of the PIT, readable without locking the timer.
+///
+/// It is only updated by the timer itself, on guest writes of the timer registers and on expiry.
+pub struct TimerDeadline {
+    /// Next interrupt time in nanoseconds, 0 if the timer is disarmed or masked.
+    ns: AtomicU64,
+    polls: AtomicU64,
+    lock_acquisitions: AtomicU64,
+}
+
+impl TimerDeadline {
+    pub(super) fn new() -> Self {
+        Self {
+            ns: AtomicU64::new(0),
+            polls: AtomicU64::new(0),
+            lock_acquisitions: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn set(&self, ns: Option<u64>) {
+        self.ns.store(ns.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Time of the next timer interrupt, if one is armed and not masked.
+    pub fn get(&self) -> Option<u64> {
+        // The timer is only programmed from its own vCPU, which is pinned to the polling CPU.
+        match self.ns.load(Ordering::Relaxed) {
+            0 => None,
+            ns => Some(ns),
+        }
+    }
+
+    /// Whether the timer must be locked and checked for an interrupt, i.e. its deadline passed.
+    pub fn expired(&self) -> bool {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        let expired = self
+            .get()
+            .map_or(false, |deadline_ns| current_time_nanos() >= deadline_ns);
+        if expired {
+            self.lock_acquisitions.fetch_add(1, Ordering::Relaxed);
+        }
+        expired
+    }
+
+    pub fn stats(&self) -> ApicTimerStats {
+        ApicTimerStats {
+            polls: self.polls.load(Ordering::Relaxed),
+            lock_acquisitions: self.lock_acquisitions.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// A virtual local APIC timer. (SDM Vol. 3C, Section 10.5.4)
+///
+/// It counts down at the bus frequency divided by the Divide Configuration Register in the
+/// one-shot and periodic modes, and compares IA32_TSC_DEADLINE with the guest TSC in the
+/// TSC-deadline mode. A masked timer keeps counting, its interrupts are lost.
 pub struct ApicTimer {
     lvt_timer_bits: u32,
     divide_shift: u8,
     initial_count: u32,
+    /// IA32_TSC_DEADLINE, in guest TSC ticks, 0 once it fired.
+    tsc_deadline: u64,
     last_start_ns: u64,
     deadline_ns: u64,
     tpr: u32,
+    bus_freq_hz: u64,
+    next_deadline: Arc<TimerDeadline>,
 }
 
 impl ApicTimer {
-    pub(crate) const fn new() -> Self {
+    pub(crate) fn new(bus_freq_hz: u64) -> Self {
         Self {
             lvt_timer_bits: 0x1_0000, // masked
             divide_shift: 0,
             initial_count: 0,
+            tsc_deadline: 0,
             last_start_ns: 0,
             deadline_ns: 0,
             tpr: 0,
+            bus_freq_hz: bus_freq_hz.max(1),
+            next_deadline: Arc::new(TimerDeadline::new()),
         }
     }
 
     /// Check if an interrupt generated. if yes, update it's states.
     pub fn check_interrupt(&mut self) -> bool {
-        if self.deadline_ns == 0 {
-            false
-        } else if current_time_nanos() >= self.deadline_ns {
-            if self.is_periodic() {
-                self.deadline_ns += self.interval_ns();
-            } else {
-                self.deadline_ns = 0;
-            }
-            !self.is_masked()
+        let now_ns = current_time_nanos();
+        if self.deadline_ns == 0 || now_ns < self.deadline_ns {
+            return false;
+        }
+        self.expire(now_ns);
+        !self.is_masked()
+    }
+
+    /// The deadline passed at `now_ns`: a periodic timer reloads, the others are disarmed.
+    fn expire(&mut self, now_ns: u64) {
+        if self.is_periodic() {
+            // The deadlines are counted from the start, rounding does not accumulate. The
+            // periods missed while the vCPU did not run are skipped rather than injected in a
+            // burst.
+            let elapsed_cycles = self.ns_to_cycles(now_ns.saturating_sub(self.last_start_ns));
+            let periods = elapsed_cycles / self.initial_count as u64 + 1;
+            let cycles = periods * self.initial_count as u64;
+            self.deadline_ns = self.last_start_ns + self.cycles_to_ns(cycles);
         } else {
-            false
+            self.deadline_ns = 0;
+            self.tsc_deadline = 0;
         }
+        self.publish_deadline();
+    }
+
+    /// The lock-free view of [`Self::next_interrupt_ns`].
+    pub fn next_deadline(&self) -> Arc<TimerDeadline> {
+        self.next_deadline.clone()
+    }
+
+    fn publish_deadline(&self) {
+        // A masked timer is restarted when it is unmasked, its deadline does not matter
+        // meanwhile.
+        self.next_deadline.set(self.next_interrupt_ns());
+    }
+
+    /// Time of the next timer interrupt, if one is armed and not masked.
+    pub fn next_interrupt_ns(&self) -> Option<u64> {
+        (self.deadline_ns != 0 && !self.is_masked()).then_some(self.deadline_ns)
     }
 
     /// Whether the timer interrupt is masked.
@@ -67,10 +177,18 @@ impl ApicTimer {
         self.lvt_timer_bits & (1 << 16) != 0
     }
 
+    const fn timer_mode(&self) -> u32 {
+        (self.lvt_timer_bits >> 17) & 0b11
+    }
+
     /// Whether the timer mode is periodic.
     pub const fn is_periodic(&self) -> bool {
-        let timer_mode = (self.lvt_timer_bits >> 17) & 0b11;
-        timer_mode == TimerMode::Periodic as _
+        self.timer_mode() == TimerMode::Periodic as _
+    }
+
+    /// Whether the timer mode is TSC-deadline.
+    pub const fn is_tsc_deadline(&self) -> bool {
+        self.timer_mode() == TimerMode::TscDeadline as _
     }
 
     /// The timer interrupt vector number.
@@ -94,10 +212,22 @@ impl ApicTimer {
         self.initial_count
     }
 
-    /// Current Count Register.
+    /// IA32_TSC_DEADLINE, which reads as 0 outside of the TSC-deadline mode.
+    pub const fn tsc_deadline(&self) -> u64 {
+        if self.is_tsc_deadline() {
+            self.tsc_deadline
+        } else {
+            0
+        }
+    }
+
+    /// Current Count Register, 0 in the TSC-deadline mode.
     pub fn current_counter(&self) -> u32 {
-        let elapsed_ns = current_time_nanos() - self.last_start_ns;
-        let elapsed_cycles = (elapsed_ns / APIC_CYCLE_NANOS) >> self.divide_shift;
+        if self.is_tsc_deadline() || self.initial_count == 0 {
+            return 0;
+        }
+        let elapsed_ns = current_time_nanos().saturating_sub(self.last_start_ns);
+        let elapsed_cycles = self.ns_to_cycles(elapsed_ns);
         if self.is_periodic() {
             self.initial_count - (elapsed_cycles % self.initial_count as u64) as u32
         } else if elapsed_cycles < self.initial_count as u64 {
@@ -107,45 +237,107 @@ impl ApicTimer {
         }
     }
 
-    /// Set LVT Timer Register.
+    /// Set LVT Timer Register. The count goes on, but switching to or from the TSC-deadline
+    /// mode disarms the timer. (SDM Vol. 3A, Section 10.5.4.1)
     pub fn set_lvt_timer(&mut self, bits: u32) -> HyperResult {
         let timer_mode = bits.get_bits(17..19);
-        if timer_mode == TimerMode::TscDeadline as _ {
-            return Err(HyperError::NotSupported); // TSC deadline mode was not supported
-        } else if timer_mode == 0b11 {
+        if timer_mode == 0b11 {
             return Err(HyperError::InvalidParam); // reserved
         }
+        let was_tsc_deadline = self.is_tsc_deadline();
+        let was_masked = self.is_masked();
         self.lvt_timer_bits = bits;
-        self.start_timer();
+        if self.is_tsc_deadline() != was_tsc_deadline {
+            self.initial_count = 0;
+            self.tsc_deadline = 0;
+            self.deadline_ns = 0;
+        } else if was_masked && !self.is_masked() && self.deadline_ns != 0 {
+            // The interrupt of a deadline which passed while masked is lost.
+            let now_ns = current_time_nanos();
+            if now_ns >= self.deadline_ns {
+                self.expire(now_ns);
+            }
+        }
+        self.publish_deadline();
         Ok(())
     }
 
-    /// Set Initial Count Register.
+    /// Set Initial Count Register, which restarts the count. Ignored in the TSC-deadline mode.
     pub fn set_initial_count(&mut self, initial: u32) -> HyperResult {
+        if self.is_tsc_deadline() {
+            return Ok(());
+        }
         self.initial_count = initial;
         self.start_timer();
         Ok(())
     }
 
-    /// Set Divide Configuration Register.
+    /// Set Divide Configuration Register. An armed timer goes on from its current count, at the
+    /// new rate.
     pub fn set_divide(&mut self, dcr: u32) -> HyperResult {
         let shift = (dcr & 0b11) | ((dcr & 0b1000) >> 1);
+        let remaining = self.current_counter() as u64;
         self.divide_shift = (shift + 1) as u8 & 0b111;
-        self.start_timer();
+        if self.deadline_ns != 0 && !self.is_tsc_deadline() {
+            let now_ns = current_time_nanos();
+            let counted = self.initial_count as u64 - remaining;
+            self.last_start_ns = now_ns.saturating_sub(self.cycles_to_ns(counted));
+            self.deadline_ns = now_ns + self.cycles_to_ns(remaining);
+            self.publish_deadline();
+        }
         Ok(())
     }
 
-    const fn interval_ns(&self) -> u64 {
-        (self.initial_count as u64 * APIC_CYCLE_NANOS) << self.divide_shift
+    /// Set IA32_TSC_DEADLINE to `deadline`, the guest TSC being `guest_tsc`. 0 disarms the
+    /// timer, a deadline already passed fires at the next check. Ignored outside of the
+    /// TSC-deadline mode.
+    pub fn set_tsc_deadline(&mut self, deadline: u64, guest_tsc: u64) {
+        if !self.is_tsc_deadline() {
+            return;
+        }
+        self.tsc_deadline = deadline;
+        self.deadline_ns = match deadline {
+            0 => 0,
+            _ => current_time_nanos() + ticks_to_nanos(deadline.saturating_sub(guest_tsc)),
+        };
+        self.publish_deadline();
+    }
+
+    /// Nanoseconds the timer takes to count `cycles`, at the current divider.
+    fn cycles_to_ns(&self, cycles: u64) -> u64 {
+        let ns = ((cycles as u128 * NANOS_PER_SEC) << self.divide_shift) / self.bus_freq_hz as u128;
+        u64::try_from(ns).unwrap_or(u64::MAX)
+    }
+
+    /// Cycles the timer counts in `ns` nanoseconds, at the current divider.
+    fn ns_to_cycles(&self, ns: u64) -> u64 {
+        ((ns as u128 * self.bus_freq_hz as u128 / NANOS_PER_SEC) >> self.divide_shift) as u64
+    }
+
+    fn interval_ns(&self) -> u64 {
+        self.cycles_to_ns(self.initial_count as u64)
     }
 
     fn start_timer(&mut self) {
         if self.initial_count != 0 {
             self.last_start_ns = current_time_nanos();
-            self.deadline_ns = self.last_start_ns + self.interval_ns();
+            // Even a count shorter than a nanosecond fires.
+            self.deadline_ns = self.last_start_ns + self.interval_ns().max(1);
         } else {
             self.deadline_ns = 0;
         }
+        self.publish_deadline();
+    }
+
+    /// Move the timer `delta_ns` later, so that neither the current count nor the next
+    /// interrupt account for the time the VM was paused. The guest TSC being moved back as
+    /// much, IA32_TSC_DEADLINE is left as is.
+    pub fn shift_time(&mut self, delta_ns: u64) {
+        self.last_start_ns += delta_ns;
+        if self.deadline_ns != 0 {
+            self.deadline_ns += delta_ns;
+        }
+        self.publish_deadline();
     }
 
     pub fn tpr(&self) -> u32 {
@@ -155,6 +347,48 @@ impl ApicTimer {
     pub fn set_tpr(&mut self, value: u32) {
         self.tpr = value;
     }
+
+    fn save(&self, state: &mut StateWriter) {
+        let now_ns = current_time_nanos();
+        state
+            .u32(self.lvt_timer_bits)
+            .u8(self.divide_shift)
+            .u32(self.initial_count)
+            .u64(self.tsc_deadline)
+            .timestamp(self.last_start_ns, now_ns)
+            .timestamp(self.deadline_ns, now_ns)
+            .u32(self.tpr);
+    }
+
+    /// Read a state written by [`ApicTimer::save`], the deadline is published by the caller once
+    /// it is applied. The bus frequency is the one of this timer, it is configured rather than
+    /// saved.
+    fn load(&self, state: &mut StateReader) -> HyperResult<Self> {
+        let now_ns = current_time_nanos();
+        let timer = Self {
+            lvt_timer_bits: state.u32()?,
+            divide_shift: state.u8()?,
+            initial_count: state.u32()?,
+            tsc_deadline: state.u64()?,
+            last_start_ns: state.timestamp(now_ns)?,
+            deadline_ns: state.timestamp(now_ns)?,
+            tpr: state.u32()?,
+            bus_freq_hz: self.bus_freq_hz,
+            next_deadline: self.next_deadline.clone(),
+        };
+        // Only the modes accepted by `set_lvt_timer`.
+        if timer.timer_mode() == 0b11 {
+            return Err(HyperError::InvalidParam);
+        }
+        // A periodic deadline is always followed by another one.
+        if timer.is_periodic() && timer.deadline_ns != 0 && timer.initial_count == 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        if timer.divide_shift > 0b111 || timer.last_start_ns > now_ns {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(timer)
+    }
 }
 
 /// ID register.
@@ -163,6 +397,8 @@ const APICID: u32 = 0x2;
 const VERSION: u32 = 0x3;
 /// Task priority register.
 const TPR: u32 = 0x8;
+/// Processor priority register.
+const PPR: u32 = 0xA;
 /// EOI register.
 const EOI: u32 = 0xB;
 /// Logical Destination Register.
@@ -185,6 +421,10 @@ const ISR5: u32 = 0x15;
 const ISR6: u32 = 0x16;
 /// In-Service Register 7
 const ISR7: u32 = 0x17;
+/// Trigger Mode Register 0
+const TMR0: u32 = 0x18;
+/// Trigger Mode Register 7
+const TMR7: u32 = 0x1F;
 /// Interrupt Request Register 0
 const IRR0: u32 = 0x20;
 /// Interrupt Request Register 1
@@ -203,6 +443,8 @@ const IRR6: u32 = 0x26;
 const IRR7: u32 = 0x27;
 /// Error Status Register.
 const ESR: u32 = 0x28;
+/// LVT Corrected Machine Check Interrupt register.
+const LVT_CMCI: u32 = 0x2F;
 /// Interrupt Command register.
 const ICR: u32 = 0x30;
 /// LVT Timer Interrupt register.
@@ -223,36 +465,160 @@ const INIT_COUNT: u32 = 0x38;
 const CUR_COUNT: u32 = 0x39;
 /// Divide Configuration register.
 const DIV_CONF: u32 = 0x3E;
+/// Self IPI register.
+const SELF_IPI: u32 = 0x3F;
+
+/// The APIC software enable bit of the Spurious Interrupt Vector register.
+const SIVR_APIC_ENABLED: usize = 8;
+/// The focus processor checking and EOI-broadcast suppression bits are not supported.
+const SIVR_WRITABLE: u32 = 0x1ff;
+/// The mask bit of an LVT entry.
+const LVT_MASKED: u32 = 1 << 16;
+/// Vector, delivery mode, polarity, trigger mode and mask of an LVT entry. The delivery status
+/// and the remote IRR are read-only.
+const LVT_WRITABLE: u32 = 0x1_a7ff;
+/// Received Illegal Vector bit of the Error Status register.
+const ESR_RECEIVE_ILLEGAL_VECTOR: u32 = 1 << 6;
+/// Delivery Status bit of the ICR, always idle.
+const ICR_SEND_PENDING: u64 = 1 << 12;
 
 /// Proxy LocalApic operation in x2apic mode.
-pub struct ProxyLocalApic {}
+///
+/// The host vCPUs run on their own CPUs, with their physical APIC ids. A guest vCPU sees its
+/// vCPU id as APIC id, and its IPIs go through `ipi::write_icr` to address the other vCPUs.
+pub struct ProxyLocalApic {
+    vcpu_id: u32,
+    msrs: core::ops::Range<u32>,
+}
 
 impl ProxyLocalApic {
-    pub fn new() -> Self {
-        Self {}
+    /// The MSRs of all the x2APIC registers.
+    pub const MSRS: core::ops::Range<u32> = 0x800..0x840;
+    /// The MSR of the EOI register, which is forwarded as is.
+    pub const EOI_MSR: u32 = 0x800 + EOI;
+
+    pub fn new(vcpu_id: u32) -> Self {
+        Self::with_msrs(vcpu_id, Self::MSRS)
+    }
+
+    /// Proxy the registers of `msrs` only, a part of [`Self::MSRS`].
+    pub fn with_msrs(vcpu_id: u32, msrs: core::ops::Range<u32>) -> Self {
+        Self { vcpu_id, msrs }
+    }
+
+    /// The guest VM running on this CPU, `None` for the host.
+    fn guest_vm_id() -> Option<u32> {
+        crate::vm::current_vm_id().filter(|&vm_id| vm_id != crate::vm::HOST_VM_ID)
     }
 }
 
 impl VirtMsrOps for ProxyLocalApic {
     fn msr_range(&self) -> core::ops::Range<u32> {
-        0x800..0x840
+        self.msrs.clone()
     }
 
     fn read(&mut self, msr: u32) -> HyperResult<u64> {
-        let value = unsafe { x86::msr::rdmsr(msr) };
+        let value = match msr - 0x800 {
+            APICID if Self::guest_vm_id().is_some() => self.vcpu_id as u64,
+            LDR if Self::guest_vm_id().is_some() => logical_apic_id(self.vcpu_id) as u64,
+            _ => unsafe { x86::msr::rdmsr(msr) },
+        };
         debug!("ProxyLocalApic msr read {:#x} get value {:#x}", msr, value);
         return Ok(value);
     }
 
     fn write(&mut self, msr: u32, value: u64) -> HyperResult {
         debug!("ProxyLocalApic msr write {:#x} value {:#x}", msr, value);
-        unsafe { x86::msr::wrmsr(msr, value) };
+        match Self::guest_vm_id() {
+            Some(vm_id) if msr - 0x800 == ICR => write_icr(vm_id, self.vcpu_id, value),
+            _ => unsafe { x86::msr::wrmsr(msr, value) },
+        }
         return Ok(());
     }
 }
 
+/// Vectors sent to a [`VirtLocalApic`] from outside of its vCPU, IPIs and interrupts of the IO
+/// APIC, taken into its IRR before the next VM entry of the vCPU.
+#[derive(Default)]
+pub struct PendingVectors {
+    bits: [AtomicU64; 4],
+}
+
+impl PendingVectors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn post(&self, vector: u8) {
+        self.bits[vector as usize / 64].fetch_or(1 << (vector % 64), Ordering::AcqRel);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bits
+            .iter()
+            .all(|bits| bits.load(Ordering::Acquire) == 0)
+    }
+
+    fn peek(&self) -> [u64; 4] {
+        core::array::from_fn(|i| self.bits[i].load(Ordering::Acquire))
+    }
+
+    fn take(&self) -> [u64; 4] {
+        core::array::from_fn(|i| self.bits[i].swap(0, Ordering::AcqRel))
+    }
+}
+
+/// A 256-bit register of the local APIC, ISR or IRR, as its eight 32-bit registers.
+#[derive(Debug, Clone, Copy, Default)]
+struct VectorBits([u32; 8]);
+
+impl VectorBits {
+    fn set(&mut self, vector: u8) {
+        self.0[vector as usize / 32].set_bit(vector as usize % 32, true);
+    }
+
+    fn clear(&mut self, vector: u8) {
+        self.0[vector as usize / 32].set_bit(vector as usize % 32, false);
+    }
+
+    fn highest(&self) -> Option<u8> {
+        let (index, &bits) = self
+            .0
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|&(_, &bits)| bits != 0)?;
+        Some((index as u32 * 32 + 31 - bits.leading_zeros()) as u8)
+    }
+
+    /// Add the vectors of `pending`, four 64-bit words.
+    fn merge(&mut self, pending: [u64; 4]) {
+        for (i, bits) in pending.into_iter().enumerate() {
+            self.0[2 * i] |= bits as u32;
+            self.0[2 * i + 1] |= (bits >> 32) as u32;
+        }
+    }
+}
+
+/// A virtual x2APIC, whose timer is `inner`.
+///
+/// Only the timer registers reach it when the vCPU is configured with an emulated APIC timer, the
+/// others being proxied to the local APIC of the CPU. With a virtual local APIC, see
+/// `VcpuDeviceConfig::with_virtual_apic`, every register does: the interrupts of the vCPU are
+/// accepted in its IRR, and [`VirtLocalApic::take_interrupt`] moves the one to inject to its ISR
+/// until the guest writes the EOI register. Every interrupt is edge-triggered.
 pub struct VirtLocalApic {
     pub inner: ApicTimer,
+    vcpu_id: u32,
+    sivr: u32,
+    /// The LVT CMCI, thermal sensor, performance monitor, LINT0, LINT1 and error entries, the timer
+    /// entry being in `inner`.
+    lvt: [u32; 6],
+    esr: u32,
+    icr: u64,
+    isr: VectorBits,
+    irr: VectorBits,
+    pending: Arc<PendingVectors>,
 }
 
 msr_proxy_struct!(
@@ -264,10 +630,56 @@ msr_proxy_struct!(
     write_msr
 );
 
+/// Some of the MSRs of a [`VirtLocalApic`], see [`VirtLocalApic::timer_msr_proxies`].
+pub struct VirtLocalApicTimerMsrs {
+    parent: Arc<Mutex<VirtLocalApic>>,
+    msrs: Range<u32>,
+}
+
+impl VirtMsrOps for VirtLocalApicTimerMsrs {
+    fn msr_range(&self) -> Range<u32> {
+        self.msrs.clone()
+    }
+
+    fn read(&mut self, msr: u32) -> HyperResult<u64> {
+        self.parent.lock().read_msr(msr)
+    }
+
+    fn write(&mut self, msr: u32, value: u64) -> HyperResult {
+        self.parent.lock().write_msr(msr, value)
+    }
+}
+
+/// Index of an LVT entry other than the timer in [`VirtLocalApic::lvt`].
+const fn lvt_index(offset: u32) -> usize {
+    match offset {
+        LVT_CMCI => 0,
+        _ => (offset - LVT_THERMAL) as usize + 1,
+    }
+}
+
 impl VirtLocalApic {
-    pub fn new() -> Self {
+    /// The x2APIC registers of the timer: the LVT timer, the initial and current counts and the
+    /// divide configuration.
+    pub const TIMER_MSRS: [Range<u32>; 3] = [
+        0x800 + LVT_TIMER..0x800 + LVT_TIMER + 1,
+        0x800 + INIT_COUNT..0x800 + CUR_COUNT + 1,
+        0x800 + DIV_CONF..0x800 + DIV_CONF + 1,
+    ];
+
+    /// The local APIC of vCPU `vcpu_id`, its APIC id, in its reset state, with a timer counting
+    /// at `bus_freq_hz` before its divider.
+    pub fn new(vcpu_id: u32, bus_freq_hz: u64) -> Self {
         Self {
-            inner: ApicTimer::new(),
+            inner: ApicTimer::new(bus_freq_hz),
+            vcpu_id,
+            sivr: 0xff,
+            lvt: [LVT_MASKED; 6],
+            esr: 0,
+            icr: 0,
+            isr: VectorBits::default(),
+            irr: VectorBits::default(),
+            pending: Arc::new(PendingVectors::new()),
         }
     }
 
@@ -275,31 +687,151 @@ impl VirtLocalApic {
         0x800..0x840
     }
 
+    /// Devices for the [`Self::TIMER_MSRS`] and IA32_TSC_DEADLINE of `apic`, emulating its timer
+    /// while the other x2APIC registers are left to the local APIC of the CPU.
+    pub fn timer_msr_proxies(apic: &Arc<Mutex<Self>>) -> Vec<VirtLocalApicTimerMsrs> {
+        Self::TIMER_MSRS
+            .into_iter()
+            .map(|msrs| VirtLocalApicTimerMsrs {
+                parent: apic.clone(),
+                msrs,
+            })
+            .chain([Self::tsc_deadline_msr_proxy(apic)])
+            .collect()
+    }
+
+    /// A device for IA32_TSC_DEADLINE, arming the timer of `apic`.
+    pub fn tsc_deadline_msr_proxy(apic: &Arc<Mutex<Self>>) -> VirtLocalApicTimerMsrs {
+        VirtLocalApicTimerMsrs {
+            parent: apic.clone(),
+            msrs: IA32_TSC_DEADLINE..IA32_TSC_DEADLINE + 1,
+        }
+    }
+
+    /// The vectors sent to this local APIC from the other CPUs.
+    pub fn pending(&self) -> Arc<PendingVectors> {
+        self.pending.clone()
+    }
+
+    /// Accept `vector` in the IRR. Vectors 0 to 15 are illegal, and only reported in the ESR.
+    pub fn accept_interrupt(&mut self, vector: u8) {
+        if vector < 16 {
+            self.esr |= ESR_RECEIVE_ILLEGAL_VECTOR;
+        } else {
+            self.irr.set(vector);
+        }
+    }
+
+    fn take_pending(&mut self) {
+        if !self.pending.is_empty() {
+            let pending = self.pending.take();
+            self.irr.merge(pending);
+        }
+    }
+
+    fn software_enabled(&self) -> bool {
+        self.sivr.get_bit(SIVR_APIC_ENABLED)
+    }
+
+    /// Processor Priority Register. (SDM Vol. 3A, Section 10.8.3.1)
+    fn ppr(&self) -> u32 {
+        let tpr = self.inner.tpr() & 0xff;
+        let isrv = self.isr.highest().unwrap_or(0) as u32;
+        if tpr >> 4 >= isrv >> 4 {
+            tpr
+        } else {
+            isrv & 0xf0
+        }
+    }
+
+    /// The highest vector of the IRR, if its priority class is above the processor priority.
+    fn deliverable(&self) -> Option<u8> {
+        let vector = self.irr.highest()?;
+        (self.software_enabled() && vector as u32 >> 4 > self.ppr() >> 4).then_some(vector)
+    }
+
+    /// The vector [`Self::take_interrupt`] would return, without taking it.
+    pub fn next_interrupt(&mut self) -> Option<u8> {
+        self.take_pending();
+        self.deliverable()
+    }
+
+    /// Whether an interrupt waits to be injected, e.g. to wake up the vCPU from HLT.
+    pub fn has_interrupt(&mut self) -> bool {
+        self.next_interrupt().is_some()
+    }
+
+    /// Move the interrupt to inject, if any, from the IRR to the ISR and return its vector for
+    /// the caller to inject. The interrupts of the same priority class or below wait for its EOI.
+    pub fn take_interrupt(&mut self) -> Option<u8> {
+        self.take_pending();
+        let vector = self.deliverable()?;
+        self.irr.clear(vector);
+        self.isr.set(vector);
+        Some(vector)
+    }
+
+    fn eoi(&mut self) {
+        if let Some(vector) = self.isr.highest() {
+            self.isr.clear(vector);
+        }
+    }
+
+    /// Set the Spurious Interrupt Vector register, disabling the APIC masks every LVT entry.
+    fn set_sivr(&mut self, value: u32) -> HyperResult {
+        self.sivr = value & SIVR_WRITABLE;
+        if !self.software_enabled() {
+            self.lvt.iter_mut().for_each(|lvt| *lvt |= LVT_MASKED);
+            self.inner
+                .set_lvt_timer(self.inner.lvt_timer() | LVT_MASKED)?;
+        }
+        Ok(())
+    }
+
+    /// Send the IPI of `icr`, to the vCPUs of the VM running on this CPU.
+    fn send_ipi(&mut self, icr: u64) {
+        self.icr = icr & !ICR_SEND_PENDING;
+        match crate::vm::current_vm_id() {
+            Some(vm_id) => write_icr(vm_id, self.vcpu_id, icr),
+            None => warn!("vCPU {} sent IPI {:#x} outside of a VM", self.vcpu_id, icr),
+        }
+    }
+
     fn read_msr(&mut self, msr: u32) -> HyperResult<u64> {
-        let apic_timer = &mut self.inner;
+        if msr == IA32_TSC_DEADLINE {
+            return Ok(self.inner.tsc_deadline());
+        }
         let offset = msr - 0x800;
-        match offset {
-            SIVR => Ok(0x1ff), // SDM Vol. 3A, Section 10.9, Figure 10-23 (with Software Enable bit)
-            LVT_THERMAL | LVT_PMI | LVT_LINT0 | LVT_LINT1 | LVT_ERR => {
-                Ok(0x1_0000) // SDM Vol. 3A, Section 10.5.1, Figure 10-8 (with Mask bit)
+        let value = match offset {
+            APICID => self.vcpu_id,
+            VERSION => 0b0000000_0_00000000_00000110_00010101, // Suppress EOI-broadcasts: false, Max LVT Entry: 6, Version: 0x15
+            TPR => self.inner.tpr(),
+            PPR => self.ppr(),
+            LDR => logical_apic_id(self.vcpu_id),
+            SIVR => self.sivr,
+            ISR0..=ISR7 => self.isr.0[(offset - ISR0) as usize],
+            TMR0..=TMR7 => 0, // every interrupt is edge-triggered
+            IRR0..=IRR7 => {
+                self.take_pending();
+                self.irr.0[(offset - IRR0) as usize]
             }
-            IRR0..=IRR7 => Ok(0),
-            ISR0..=ISR7 => Ok(0),
-            LVT_TIMER => Ok(apic_timer.lvt_timer() as u64),
-            INIT_COUNT => Ok(apic_timer.initial_count() as u64),
-            DIV_CONF => Ok(apic_timer.divide() as u64),
-            CUR_COUNT => Ok(apic_timer.current_counter() as u64),
-            LDR => Ok(0),
-            TPR => Ok(apic_timer.tpr() as u64),
-            VERSION => Ok(0b0000000_0_00000000_00000110_00010101), // Suppress EOI-broadcasts: false, Max LVT Entry: 6, Version: 0x15
-            ESR => Ok(0),
-            APICID => Ok(0),
-            _ => Err(HyperError::NotSupported),
-        }
+            ESR => self.esr,
+            ICR => return Ok(self.icr),
+            LVT_TIMER => self.inner.lvt_timer(),
+            LVT_CMCI | LVT_THERMAL..=LVT_ERR => self.lvt[lvt_index(offset)],
+            INIT_COUNT => self.inner.initial_count(),
+            CUR_COUNT => self.inner.current_counter(),
+            DIV_CONF => self.inner.divide(),
+            _ => return Err(HyperError::NotSupported),
+        };
+        Ok(value as u64)
     }
 
     fn write_msr(&mut self, msr: u32, value: u64) -> HyperResult {
-        let apic_timer = &mut self.inner;
+        if msr == IA32_TSC_DEADLINE {
+            self.inner.set_tsc_deadline(value, guest_tsc());
+            return Ok(());
+        }
         let offset = msr - 0x800;
 
         if offset != ICR && (value >> 32) != 0 {
@@ -310,23 +842,35 @@ impl VirtLocalApic {
                 if value != 0 {
                     Err(HyperError::InvalidParam) // write a non-zero value causes #GP
                 } else {
+                    self.eoi();
                     Ok(())
                 }
             }
-            SIVR | LVT_THERMAL | LVT_PMI | LVT_LINT0 | LVT_LINT1 | LVT_ERR => {
-                Ok(()) // ignore these register writes
+            TPR => Ok(self.inner.set_tpr(value as u32 & 0xff)),
+            SIVR => self.set_sivr(value as u32),
+            LVT_CMCI | LVT_THERMAL..=LVT_ERR => {
+                self.lvt[lvt_index(offset)] = value as u32 & LVT_WRITABLE;
+                Ok(())
             }
-            LVT_TIMER => apic_timer.set_lvt_timer(value as u32),
-            INIT_COUNT => apic_timer.set_initial_count(value as u32),
-            DIV_CONF => apic_timer.set_divide(value as u32),
-            TPR => Ok(apic_timer.set_tpr(value as u32)),
+            LVT_TIMER => self.inner.set_lvt_timer(value as u32),
+            INIT_COUNT => self.inner.set_initial_count(value as u32),
+            DIV_CONF => self.inner.set_divide(value as u32),
             ESR => {
                 if value == 0 {
+                    self.esr = 0;
                     Ok(())
                 } else {
                     Err(HyperError::InvalidParam)
                 }
             }
+            ICR => {
+                self.send_ipi(value);
+                Ok(())
+            }
+            SELF_IPI => {
+                self.accept_interrupt(value as u8);
+                Ok(())
+            }
             _ => Err(HyperError::NotSupported),
         }
     }
@@ -334,11 +878,62 @@ impl VirtLocalApic {
     msr_proxy_factory!(msr_proxy, VirtLocalApicMsrProxy);
 }
 
-pub struct ApicBaseMsrHandler;
+/// The vectors sent from other CPUs and not taken yet are saved in the IRR.
+impl DeviceState for VirtLocalApic {
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(LOCAL_APIC_STATE_VERSION);
+        self.inner.save(&mut state);
+        let mut irr = self.irr;
+        irr.merge(self.pending.peek());
+        state.u32(self.sivr);
+        for &lvt in &self.lvt {
+            state.u32(lvt);
+        }
+        state.u32(self.esr).u64(self.icr);
+        for &bits in self.isr.0.iter().chain(irr.0.iter()) {
+            state.u32(bits);
+        }
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let mut state = StateReader::new(state, LOCAL_APIC_STATE_VERSION)?;
+        let timer = self.inner.load(&mut state)?;
+        let sivr = state.u32()?;
+        let mut lvt = [0; 6];
+        for entry in lvt.iter_mut() {
+            *entry = state.u32()?;
+        }
+        let esr = state.u32()?;
+        let icr = state.u64()?;
+        let mut isr = VectorBits::default();
+        let mut irr = VectorBits::default();
+        for bits in isr.0.iter_mut().chain(irr.0.iter_mut()) {
+            *bits = state.u32()?;
+        }
+        state.finish()?;
+        // Keeps the `TimerDeadline` and the `PendingVectors` shared with the device list.
+        self.inner = timer;
+        self.inner.publish_deadline();
+        self.sivr = sivr & SIVR_WRITABLE;
+        self.lvt = lvt.map(|entry| entry & LVT_WRITABLE);
+        self.esr = esr;
+        self.icr = icr & !ICR_SEND_PENDING;
+        self.isr = isr;
+        self.irr = irr;
+        self.pending.take();
+        Ok(())
+    }
+}
+
+pub struct ApicBaseMsrHandler {
+    /// Value written by the guest, already validated by the WRMSR dispatcher.
+    value: Option<u64>,
+}
 
 impl ApicBaseMsrHandler {
     pub fn new() -> Self {
-        Self
+        Self { value: None }
     }
 }
 
@@ -349,6 +944,9 @@ impl VirtMsrOps for ApicBaseMsrHandler {
 
     fn read(&mut self, msr: u32) -> HyperResult<u64> {
         let _ = msr;
+        if let Some(value) = self.value {
+            return Ok(value);
+        }
         let mut apic_base = unsafe { x86::msr::rdmsr(x86::msr::IA32_APIC_BASE) };
 
         debug!("Get IA32_APIC_BASE {:#x}", apic_base);
@@ -361,8 +959,28 @@ impl VirtMsrOps for ApicBaseMsrHandler {
         Ok(apic_base)
     }
 
-    fn write(&mut self, _msr: u32, _value: u64) -> HyperResult {
-        debug!("write IA32_APIC_BASE to {:#x}", _value);
+    fn write(&mut self, _msr: u32, value: u64) -> HyperResult {
+        debug!("write IA32_APIC_BASE to {:#x}", value);
+        self.value = Some(value);
+        Ok(())
+    }
+}
+
+impl DeviceState for ApicBaseMsrHandler {
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(APIC_BASE_STATE_VERSION);
+        state
+            .bool(self.value.is_some())
+            .u64(self.value.unwrap_or(0));
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let mut state = StateReader::new(state, APIC_BASE_STATE_VERSION)?;
+        let written = state.bool()?;
+        let value = state.u64()?;
+        state.finish()?;
+        self.value = written.then_some(value);
         Ok(())
     }
 }
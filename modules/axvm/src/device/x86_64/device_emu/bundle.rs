@@ -1,9 +1,13 @@
 /// Bundle for CMOS, NMI, PIT and Speaker
 extern crate alloc;
+use super::cmos::Cmos;
 use super::pit::PIT;
+use super::TimerDeadline;
 use super::{pmio_proxy_factory, pmio_proxy_struct};
+use crate::device::{DeviceState, StateReader, StateWriter};
 use crate::{Error as HyperError, Result as HyperResult};
-use bit_field::BitField;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use x86::io;
 
@@ -16,6 +20,8 @@ pub const PORT_CMOS_DATA: u16 = 0x71;
 pub const PORT_PIT_CHANNEL_DATA_BASE: u16 = 0x40;
 pub const PORT_PIT_COMMAND: u16 = 0x43;
 
+const BUNDLE_STATE_VERSION: u16 = 3;
+
 bitflags::bitflags! {
     #[derive(Clone, Copy, Debug)]
     pub struct SystemControlPortB: u8 {
@@ -36,8 +42,9 @@ bitflags::bitflags! {
 
 pub struct Bundle {
     // about cmos
-    cmos_selected_reg: Option<u8>,
-    // about nmi
+    cmos_selected_reg: u8,
+    cmos: Cmos,
+    // about nmi, disabled by bit 7 of the writes to port 0x70
     nmi_enabled: bool,
     //
     scp_b_writable: SystemControlPortB,
@@ -48,13 +55,39 @@ pub struct Bundle {
 impl Bundle {
     pub fn new() -> Self {
         Self {
-            cmos_selected_reg: None,
+            cmos_selected_reg: 0,
+            cmos: Cmos::new(),
             nmi_enabled: true,
             scp_b_writable: SystemControlPortB::empty(),
             pit: PIT::new(),
         }
     }
 
+    /// The time of the next IRQ 0 of the PIT, readable without locking the bundle.
+    pub fn pit_deadline(&self) -> Arc<TimerDeadline> {
+        self.pit.deadline()
+    }
+
+    /// Whether the PIT raised IRQ 0 since the last call, see [`PIT::pending_irq`].
+    pub fn pit_pending_irq(&mut self) -> bool {
+        self.pit.pending_irq()
+    }
+
+    /// The time of the next IRQ 8 of the RTC, readable without locking the bundle.
+    pub fn rtc_deadline(&self) -> Arc<TimerDeadline> {
+        self.cmos.deadline()
+    }
+
+    /// Whether the RTC raised IRQ 8 since the last call, see [`Cmos::pending_irq`].
+    pub fn rtc_pending_irq(&mut self) -> bool {
+        self.cmos.pending_irq()
+    }
+
+    /// Hide `delta_ns` from the PIT, see [`PIT::shift_time`]. The RTC keeps the wall clock.
+    pub fn shift_time(&mut self, delta_ns: u64) {
+        self.pit.shift_time(delta_ns);
+    }
+
     fn read_system_control_a(&mut self, _port: u16, _access_size: u8) -> HyperResult<u32> {
         let value = unsafe { io::inb(_port) };
         debug!("SystemControlPortA read port {_port:#x} size {_access_size:#x} value {value:#x}");
@@ -87,49 +120,33 @@ impl Bundle {
             & !SystemControlPortB::READONLY_MASK;
 
         self.pit
-            .set_enabled(2, value.contains(SystemControlPortB::TIMER2_ENABLED))?;
+            .set_gate(2, value.contains(SystemControlPortB::TIMER2_ENABLED))?;
         self.scp_b_writable = value;
 
         Ok(())
     }
 
     fn read_cmos(&mut self, port: u16, _access_size: u8) -> HyperResult<u32> {
-        debug!("read_cmos port {port:#x} size {_access_size:#x}");
+        // debug!("read_cmos port {port:#x} size {_access_size:#x}");
         if port == PORT_CMOS_ADDRESS {
-            Err(HyperError::NotSupported)
+            // Write-only.
+            Ok(0xff)
         } else {
-            match self.cmos_selected_reg {
-                None => Err(HyperError::InvalidParam),
-                Some(selected) => {
-                    self.cmos_selected_reg = None;
-                    warn!("cmos read from reg {:#x} ignored", selected);
-                    Ok(0)
-                }
-            }
+            // The register stays selected.
+            Ok(self.cmos.read(self.cmos_selected_reg) as u32)
         }
     }
 
     fn write_cmos(&mut self, port: u16, _access_size: u8, value: u32) -> HyperResult {
-        debug!("write_cmos port {port:#x} value {value:#x} size {_access_size:#x}");
+        // debug!("write_cmos port {port:#x} value {value:#x} size {_access_size:#x}");
         if port == PORT_CMOS_ADDRESS {
-            self.cmos_selected_reg = Some((value & 0x7f) as u8);
+            self.cmos_selected_reg = (value & 0x7f) as u8;
             self.nmi_enabled = (value & 0x80) == 0;
-
-            Ok(())
         } else {
             // port == PORT_CMOS_DATA
-            match self.cmos_selected_reg {
-                None => Err(HyperError::InvalidParam),
-                Some(selected) => {
-                    self.cmos_selected_reg = None;
-                    warn!(
-                        "cmos write to reg {:#x}(value {:#x}) ignored",
-                        selected, value
-                    );
-                    Ok(())
-                }
-            }
+            self.cmos.write(self.cmos_selected_reg, value as u8);
         }
+        Ok(())
     }
 
     fn read_pit(&mut self, port: u16, _access_size: u8) -> HyperResult<u32> {
@@ -151,12 +168,7 @@ impl Bundle {
         // debug!("pit write, port {port:#x}, value {value:#x}");
 
         if port == PORT_PIT_COMMAND {
-            self.pit.command(
-                value.get_bits(6..8),
-                value.get_bits(4..6),
-                value.get_bits(1..4),
-                value.get_bit(0),
-            )
+            self.pit.command(value)
         } else {
             self.pit
                 .write((port - PORT_PIT_CHANNEL_DATA_BASE) as u8, value)
@@ -164,6 +176,43 @@ impl Bundle {
     }
 }
 
+/// The RTC is saved with the offset of its guest to the wall clock, which it keeps once
+/// restored.
+impl DeviceState for Bundle {
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(BUNDLE_STATE_VERSION);
+        state.u8(self.cmos_selected_reg);
+        self.cmos.save(&mut state);
+        state.bool(self.nmi_enabled).u8(self.scp_b_writable.bits());
+        self.pit.save(&mut state);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let mut state = StateReader::new(state, BUNDLE_STATE_VERSION)?;
+        let cmos_selected_reg = state.u8()?;
+        let cmos = self.cmos.load(&mut state)?;
+        let nmi_enabled = state.bool()?;
+        let scp_b_writable = SystemControlPortB::from_bits(state.u8()?)
+            .filter(|scp_b| (*scp_b & SystemControlPortB::READONLY_MASK).is_empty())
+            .ok_or(HyperError::InvalidParam)?;
+        let pit = self.pit.load(&mut state)?;
+        state.finish()?;
+        if cmos_selected_reg > 0x7f {
+            return Err(HyperError::InvalidParam);
+        }
+
+        self.cmos_selected_reg = cmos_selected_reg;
+        self.cmos = cmos;
+        self.cmos.publish_deadline();
+        self.nmi_enabled = nmi_enabled;
+        self.scp_b_writable = scp_b_writable;
+        self.pit = pit;
+        self.pit.publish_deadline();
+        Ok(())
+    }
+}
+
 // following are proxies
 
 pmio_proxy_struct!(
@@ -205,3 +254,32 @@ impl Bundle {
     pmio_proxy_factory!(proxy_cmos, BundleCMOSProxy);
     pmio_proxy_factory!(proxy_pit, BundlePITProxy);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cmos_address_nmi_mask() {
+        // Every register read below needs the wall clock, not the RTC of the machine.
+        crate::wall_clock::set_wall_clock(946_684_800 * 1_000_000_000);
+        let mut bundle = Bundle::new();
+        assert!(bundle.nmi_enabled);
+
+        // Bit 7 disables NMIs and is not part of the register selected.
+        bundle.write_cmos(PORT_CMOS_ADDRESS, 1, 0x8b).unwrap();
+        assert!(!bundle.nmi_enabled);
+        assert_eq!(bundle.cmos_selected_reg, 0x0b);
+        assert_eq!(bundle.read_cmos(PORT_CMOS_DATA, 1).unwrap(), 0x02);
+        // The register stays selected, the address port reads back nothing.
+        assert_eq!(bundle.read_cmos(PORT_CMOS_DATA, 1).unwrap(), 0x02);
+        assert_eq!(bundle.read_cmos(PORT_CMOS_ADDRESS, 1).unwrap(), 0xff);
+
+        bundle.write_cmos(PORT_CMOS_ADDRESS, 1, 0x0e).unwrap();
+        assert!(bundle.nmi_enabled);
+        bundle.write_cmos(PORT_CMOS_DATA, 1, 0x5a).unwrap();
+        bundle.write_cmos(PORT_CMOS_ADDRESS, 1, 0x8e).unwrap();
+        assert_eq!(bundle.read_cmos(PORT_CMOS_DATA, 1).unwrap(), 0x5a);
+        assert!(!bundle.nmi_enabled);
+    }
+}
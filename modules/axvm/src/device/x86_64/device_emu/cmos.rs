@@ -0,0 +1,546 @@
+//! Emulated Motorola MC146818 RTC and CMOS RAM. (ref: https://wiki.osdev.org/CMOS)
+//!
+//! The clock of the guest is the wall clock of the hypervisor, see [`crate::wall_clock`], plus
+//! an offset the guest changes by writing the time registers. It is computed when read, as are
+//! the flags of register C: since they were last updated, the periodic interrupt ticked if a
+//! tick of its rate passed, and the clock was updated if a second passed.
+//!
+//! The update-in-progress bit of register A is set for the last 244 µs of each second, the
+//! registers then switch to the next second at once. The interrupt output is IRQ 8, raised when
+//! the IRQF flag of register C is set, and kept until register C is read. Its next time is
+//! published in a [`TimerDeadline`] for `check_events`, which takes it with
+//! [`Cmos::pending_irq`].
+
+use crate::{Error as HyperError, Result as HyperResult};
+use alloc::sync::Arc;
+use axhal::time::current_time_nanos;
+use bit_field::BitField;
+
+use super::TimerDeadline;
+use crate::device::{StateReader, StateWriter};
+use crate::wall_clock::{bcd_to_binary, boot_epoch_ns, DateTime};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Registers 0x00 to 0x7f, the clock and status registers and the battery-backed RAM.
+const CMOS_SIZE: usize = 128;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_WEEKDAY: u8 = 0x06;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_A: u8 = 0x0a;
+const REG_B: u8 = 0x0b;
+const REG_C: u8 = 0x0c;
+const REG_D: u8 = 0x0d;
+/// The century, where the FADT of ACPI declares it on PCs.
+const REG_CENTURY: u8 = 0x32;
+
+/// Register A: update in progress, the divider, reset to 32.768 kHz, and the periodic rate.
+const A_UIP: usize = 7;
+const A_DIVIDER: core::ops::Range<usize> = 4..7;
+const A_DIVIDER_32KHZ: u8 = 0b010;
+const A_RATE: core::ops::Range<usize> = 0..4;
+/// 32.768 kHz, 1024 Hz periodic interrupt.
+const A_RESET: u8 = 0x26;
+
+/// Register B: updates stopped, periodic, alarm and update-ended interrupts, binary instead of
+/// BCD, 24 instead of 12 hours.
+const B_SET: usize = 7;
+const B_PIE: usize = 6;
+const B_AIE: usize = 5;
+const B_UIE: usize = 4;
+const B_BINARY: usize = 2;
+const B_24_HOURS: usize = 1;
+const B_RESET: u8 = 1 << B_24_HOURS;
+
+/// Register C: interrupt request, periodic, alarm and update-ended flags.
+const C_IRQF: usize = 7;
+const C_PF: usize = 6;
+const C_AF: usize = 5;
+const C_UF: usize = 4;
+
+/// Register D: the RAM and the time are valid.
+const D_VRT: u8 = 1 << 7;
+
+/// The update-in-progress bit is set this long before the end of each second.
+const UIP_NS: u64 = 244_000;
+
+pub struct Cmos {
+    ram: [u8; CMOS_SIZE],
+    reg_a: u8,
+    reg_b: u8,
+    reg_c: u8,
+    /// Seconds the clock of the guest is ahead of the wall clock.
+    offset_secs: i64,
+    /// The time of the guest, written while the SET bit of register B stops the updates.
+    set_time: Option<DateTime>,
+    /// The flags of register C account for the periodic ticks and the updates until this time,
+    /// in nanoseconds of [`current_time_nanos`].
+    flags_ns: u64,
+    /// IRQF rose since the last `pending_irq`.
+    irq_raised: bool,
+    /// The next IRQ 8, for `check_events`.
+    deadline: Arc<TimerDeadline>,
+}
+
+fn binary_to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+impl Cmos {
+    pub fn new() -> Self {
+        Self {
+            ram: [0; CMOS_SIZE],
+            reg_a: A_RESET,
+            reg_b: B_RESET,
+            reg_c: 0,
+            offset_secs: 0,
+            set_time: None,
+            flags_ns: current_time_nanos(),
+            irq_raised: false,
+            deadline: Arc::new(TimerDeadline::new()),
+        }
+    }
+
+    /// Whether the divider runs the clock and the periodic interrupt.
+    fn oscillator_running(&self) -> bool {
+        self.reg_a.get_bits(A_DIVIDER) == A_DIVIDER_32KHZ
+    }
+
+    /// Whether the time registers are updated every second.
+    fn updating(&self) -> bool {
+        self.oscillator_running() && !self.reg_b.get_bit(B_SET)
+    }
+
+    /// The periodic interrupt rate, a tick every `1 << (rate - 1)` cycles of 32.768 kHz.
+    fn periodic_rate(&self) -> Option<u32> {
+        match self.reg_a.get_bits(A_RATE) as u32 {
+            0 => None,
+            _ if !self.oscillator_running() => None,
+            // Rates 1 and 2 are the ones of 8 and 9 with a 32.768 kHz time base.
+            rate @ 1..=2 => Some(rate + 7),
+            rate => Some(rate),
+        }
+    }
+
+    /// Periodic ticks from the start of [`current_time_nanos`] to `ns`.
+    fn periodic_ticks(rate: u32, ns: u64) -> u64 {
+        ((ns as u128 * 32768) / ((NANOS_PER_SEC as u128) << (rate - 1))) as u64
+    }
+
+    /// Time of periodic tick `tick`, rounded up.
+    fn periodic_tick_ns(rate: u32, tick: u64) -> u64 {
+        ((tick as u128 * ((NANOS_PER_SEC as u128) << (rate - 1))).div_ceil(32768)) as u64
+    }
+
+    /// Seconds of the wall clock at `ns`, and the nanoseconds into the second.
+    fn wall_clock_at(ns: u64) -> (u64, u64) {
+        let wall_ns = boot_epoch_ns() + ns;
+        (wall_ns / NANOS_PER_SEC, wall_ns % NANOS_PER_SEC)
+    }
+
+    /// The time of the guest at `now_ns`, and the nanoseconds into the second.
+    fn guest_time(&self, now_ns: u64) -> (DateTime, u64) {
+        match self.set_time {
+            Some(time) => (time, 0),
+            None => {
+                let (secs, subsec_ns) = Self::wall_clock_at(now_ns);
+                let secs = secs as i64 + self.offset_secs;
+                (DateTime::from_unix_secs(secs), subsec_ns)
+            }
+        }
+    }
+
+    /// Make `time` the time of the guest at `now_ns`.
+    fn set_guest_time(&mut self, time: DateTime, now_ns: u64) {
+        let (secs, _) = Self::wall_clock_at(now_ns);
+        self.offset_secs = time.to_unix_secs() - secs as i64;
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.reg_b.get_bit(B_BINARY) {
+            value
+        } else {
+            binary_to_bcd(value)
+        }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.reg_b.get_bit(B_BINARY) {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    }
+
+    fn encode_hours(&self, hours: u8) -> u8 {
+        if self.reg_b.get_bit(B_24_HOURS) {
+            self.encode(hours)
+        } else {
+            // 12 AM is midnight, bit 7 is PM.
+            let pm = if hours >= 12 { 0x80 } else { 0 };
+            self.encode((hours + 11) % 12 + 1) | pm
+        }
+    }
+
+    fn decode_hours(&self, value: u8) -> u8 {
+        if self.reg_b.get_bit(B_24_HOURS) {
+            self.decode(value)
+        } else {
+            let pm = if value.get_bit(7) { 12 } else { 0 };
+            self.decode(value & 0x7f) % 12 + pm
+        }
+    }
+
+    /// Whether the alarm registers match `time`, 0xc0 to 0xff matching any value.
+    fn alarm_matches(&self, time: &DateTime) -> bool {
+        [
+            (REG_SECONDS_ALARM, self.encode(time.second)),
+            (REG_MINUTES_ALARM, self.encode(time.minute)),
+            (REG_HOURS_ALARM, self.encode_hours(time.hour)),
+        ]
+        .into_iter()
+        .all(|(alarm, value)| {
+            let alarm = self.ram[alarm as usize];
+            alarm & 0xc0 == 0xc0 || alarm == value
+        })
+    }
+
+    /// Set the flags of register C for what happened until `now_ns`.
+    fn update_flags(&mut self, now_ns: u64) {
+        let since_ns = self.flags_ns;
+        self.flags_ns = since_ns.max(now_ns);
+        if now_ns > since_ns {
+            if let Some(rate) = self.periodic_rate() {
+                if Self::periodic_ticks(rate, now_ns) > Self::periodic_ticks(rate, since_ns) {
+                    self.reg_c.set_bit(C_PF, true);
+                }
+            }
+            if self.updating() && Self::wall_clock_at(now_ns).0 > Self::wall_clock_at(since_ns).0 {
+                self.reg_c.set_bit(C_UF, true);
+                // Only the last update is checked against the alarm.
+                if self.alarm_matches(&self.guest_time(now_ns).0) {
+                    self.reg_c.set_bit(C_AF, true);
+                }
+            }
+        }
+        self.update_irqf();
+    }
+
+    /// Raise IRQF if an enabled interrupt has its flag set.
+    fn update_irqf(&mut self) {
+        let requested = [(B_PIE, C_PF), (B_AIE, C_AF), (B_UIE, C_UF)]
+            .into_iter()
+            .any(|(enable, flag)| self.reg_b.get_bit(enable) && self.reg_c.get_bit(flag));
+        if requested && !self.reg_c.get_bit(C_IRQF) {
+            self.reg_c.set_bit(C_IRQF, true);
+            self.irq_raised = true;
+        }
+    }
+
+    /// Time at which IRQF may rise, there is no new interrupt before register C is read.
+    fn next_irq_ns(&self) -> Option<u64> {
+        if self.reg_c.get_bit(C_IRQF) {
+            return None;
+        }
+        let periodic = self
+            .periodic_rate()
+            .filter(|_| self.reg_b.get_bit(B_PIE))
+            .map(|rate| {
+                Self::periodic_tick_ns(rate, Self::periodic_ticks(rate, self.flags_ns) + 1)
+            });
+        let update = (self.updating() && (self.reg_b.get_bit(B_UIE) || self.reg_b.get_bit(B_AIE)))
+            .then(|| {
+                let (_, subsec_ns) = Self::wall_clock_at(self.flags_ns);
+                self.flags_ns + (NANOS_PER_SEC - subsec_ns)
+            });
+        match (periodic, update) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub(super) fn publish_deadline(&self) {
+        self.deadline.set(self.next_irq_ns());
+    }
+
+    /// The lock-free view of the time of the next IRQ 8.
+    pub fn deadline(&self) -> Arc<TimerDeadline> {
+        self.deadline.clone()
+    }
+
+    /// Whether IRQF rose since the last call, i.e. IRQ 8 is to be raised.
+    pub fn pending_irq(&mut self) -> bool {
+        self.update_flags(current_time_nanos());
+        self.publish_deadline();
+        core::mem::take(&mut self.irq_raised)
+    }
+
+    /// Read register `reg`.
+    pub fn read(&mut self, reg: u8) -> u8 {
+        self.read_at(reg, current_time_nanos())
+    }
+
+    fn read_at(&mut self, reg: u8, now_ns: u64) -> u8 {
+        let (time, subsec_ns) = self.guest_time(now_ns);
+        match reg {
+            REG_SECONDS => self.encode(time.second),
+            REG_MINUTES => self.encode(time.minute),
+            REG_HOURS => self.encode_hours(time.hour),
+            // 1 is Sunday.
+            REG_WEEKDAY => self.encode(time.weekday() + 1),
+            REG_DAY => self.encode(time.day),
+            REG_MONTH => self.encode(time.month),
+            REG_YEAR => self.encode((time.year % 100) as u8),
+            REG_CENTURY => self.encode((time.year / 100) as u8),
+            REG_A => {
+                let mut reg_a = self.reg_a;
+                reg_a.set_bit(
+                    A_UIP,
+                    self.updating() && subsec_ns >= NANOS_PER_SEC - UIP_NS,
+                );
+                reg_a
+            }
+            REG_B => self.reg_b,
+            REG_C => {
+                // Reading clears the flags and lowers the interrupt output.
+                self.update_flags(now_ns);
+                let flags = core::mem::take(&mut self.reg_c);
+                self.publish_deadline();
+                flags
+            }
+            REG_D => D_VRT,
+            reg => self.ram[(reg as usize) % CMOS_SIZE],
+        }
+    }
+
+    /// Write `value` to register `reg`.
+    pub fn write(&mut self, reg: u8, value: u8) {
+        self.write_at(reg, value, current_time_nanos())
+    }
+
+    fn write_at(&mut self, reg: u8, value: u8, now_ns: u64) {
+        // The flags account for the time before the change.
+        self.update_flags(now_ns);
+        match reg {
+            REG_SECONDS | REG_MINUTES | REG_HOURS | REG_DAY | REG_MONTH | REG_YEAR
+            | REG_CENTURY => {
+                let (mut time, _) = self.guest_time(now_ns);
+                match reg {
+                    REG_SECONDS => time.second = self.decode(value),
+                    REG_MINUTES => time.minute = self.decode(value),
+                    REG_HOURS => time.hour = self.decode_hours(value),
+                    REG_DAY => time.day = self.decode(value),
+                    REG_MONTH => time.month = self.decode(value),
+                    REG_YEAR => time.year = time.year / 100 * 100 + self.decode(value) as u16,
+                    _ => time.year = self.decode(value) as u16 * 100 + time.year % 100,
+                }
+                if self.set_time.is_some() {
+                    self.set_time = Some(time);
+                } else {
+                    self.set_guest_time(time, now_ns);
+                }
+            }
+            // Derived from the date.
+            REG_WEEKDAY => {}
+            REG_A => {
+                self.reg_a = value & !(1 << A_UIP);
+            }
+            REG_B => {
+                let mut value = value;
+                if value.get_bit(B_SET) {
+                    // Stopping the updates disables their interrupt.
+                    value.set_bit(B_UIE, false);
+                    if self.set_time.is_none() {
+                        self.set_time = Some(self.guest_time(now_ns).0);
+                    }
+                } else if let Some(time) = self.set_time.take() {
+                    self.set_guest_time(time, now_ns);
+                }
+                self.reg_b = value;
+                self.update_irqf();
+            }
+            // Read-only.
+            REG_C | REG_D => {}
+            reg => self.ram[(reg as usize) % CMOS_SIZE] = value,
+        }
+        self.publish_deadline();
+    }
+
+    /// Append the state of the RTC and the RAM to `state`, see [`crate::device::DeviceState`].
+    pub fn save(&self, state: &mut StateWriter) {
+        let set_time = self.set_time.unwrap_or(DateTime::from_unix_secs(0));
+        state
+            .bytes(&self.ram)
+            .u8(self.reg_a)
+            .u8(self.reg_b)
+            .u8(self.reg_c)
+            .u64(self.offset_secs as u64)
+            .bool(self.set_time.is_some())
+            .u16(set_time.year)
+            .u8(set_time.month)
+            .u8(set_time.day)
+            .u8(set_time.hour)
+            .u8(set_time.minute)
+            .u8(set_time.second)
+            .timestamp(self.flags_ns, current_time_nanos())
+            .bool(self.irq_raised);
+    }
+
+    /// Read a state appended by [`Cmos::save`], keeping the deadline of this RTC. The deadline
+    /// is published once the result replaces this RTC, see [`Cmos::publish_deadline`].
+    pub fn load(&self, state: &mut StateReader) -> HyperResult<Self> {
+        let now_ns = current_time_nanos();
+        let ram = state.bytes()?;
+        let (reg_a, reg_b, reg_c) = (state.u8()?, state.u8()?, state.u8()?);
+        let offset_secs = state.u64()? as i64;
+        let set = state.bool()?;
+        let set_time = DateTime {
+            year: state.u16()?,
+            month: state.u8()?,
+            day: state.u8()?,
+            hour: state.u8()?,
+            minute: state.u8()?,
+            second: state.u8()?,
+        };
+        let flags_ns = state.timestamp(now_ns)?;
+        let irq_raised = state.bool()?;
+        if set && !set_time.is_valid() {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(Self {
+            ram: ram.try_into().map_err(|_| HyperError::InvalidParam)?,
+            reg_a: reg_a & !(1 << A_UIP),
+            reg_b,
+            reg_c,
+            offset_secs,
+            set_time: set.then_some(set_time),
+            flags_ns: flags_ns.min(now_ns),
+            irq_raised,
+            deadline: self.deadline.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2000-01-01 00:00:00 UTC, a Saturday.
+    const Y2K_SECS: u64 = 946_684_800;
+
+    /// The time of [`current_time_nanos`] at which the wall clock reads `hour:minute:second`
+    /// and `subsec_ns` on 2000-01-01.
+    fn at(hour: u64, minute: u64, second: u64, subsec_ns: u64) -> u64 {
+        crate::wall_clock::set_wall_clock(Y2K_SECS * NANOS_PER_SEC);
+        let secs = Y2K_SECS + hour * 3600 + minute * 60 + second;
+        secs * NANOS_PER_SEC + subsec_ns - boot_epoch_ns()
+    }
+
+    #[test]
+    fn test_bcd_and_binary() {
+        let now = at(13, 45, 30, 500_000_000);
+        let mut cmos = Cmos::new();
+        assert_eq!(cmos.read_at(REG_B, now), B_RESET);
+        let bcd = [
+            (REG_SECONDS, 0x30),
+            (REG_MINUTES, 0x45),
+            (REG_HOURS, 0x13),
+            (REG_WEEKDAY, 0x07),
+            (REG_DAY, 0x01),
+            (REG_MONTH, 0x01),
+            (REG_YEAR, 0x00),
+            (REG_CENTURY, 0x20),
+        ];
+        for (reg, value) in bcd {
+            assert_eq!(cmos.read_at(reg, now), value, "register {reg:#x}");
+        }
+
+        cmos.write_at(REG_B, 1 << B_BINARY | 1 << B_24_HOURS, now);
+        let binary = [
+            (REG_SECONDS, 30),
+            (REG_MINUTES, 45),
+            (REG_HOURS, 13),
+            (REG_WEEKDAY, 7),
+            (REG_CENTURY, 20),
+        ];
+        for (reg, value) in binary {
+            assert_eq!(cmos.read_at(reg, now), value, "register {reg:#x}");
+        }
+
+        // Writes are decoded in the mode they are made in.
+        cmos.write_at(REG_MINUTES, 59, now);
+        assert_eq!(cmos.read_at(REG_MINUTES, now), 59);
+        cmos.write_at(REG_B, B_RESET, now);
+        assert_eq!(cmos.read_at(REG_MINUTES, now), 0x59);
+        cmos.write_at(REG_YEAR, 0x24, now);
+        assert_eq!(cmos.read_at(REG_YEAR, now), 0x24);
+        assert_eq!(cmos.read_at(REG_CENTURY, now), 0x20);
+    }
+
+    #[test]
+    fn test_12_hours() {
+        let mut cmos = Cmos::new();
+        let now = at(0, 0, 0, 0);
+        cmos.write_at(REG_B, 0, now);
+        // 12 AM, 11 AM, 12 PM and 1 PM in BCD, bit 7 being PM.
+        for (hour, value) in [(0, 0x12), (11, 0x11), (12, 0x92), (13, 0x81), (23, 0x91)] {
+            assert_eq!(cmos.read_at(REG_HOURS, at(hour, 0, 0, 0)), value, "{hour}h");
+        }
+
+        cmos.write_at(REG_B, 1 << B_BINARY, now);
+        assert_eq!(cmos.read_at(REG_HOURS, at(13, 0, 0, 0)), 0x81);
+        assert_eq!(cmos.read_at(REG_HOURS, at(23, 0, 0, 0)), 0x8b);
+
+        // Written in 12 hours BCD, read in 24 hours BCD.
+        let now = at(13, 45, 30, 0);
+        for (value, hour) in [(0x12, 0x00), (0x92, 0x12), (0x87, 0x19), (0x07, 0x07)] {
+            cmos.write_at(REG_B, 0, now);
+            cmos.write_at(REG_HOURS, value, now);
+            cmos.write_at(REG_B, B_RESET, now);
+            assert_eq!(cmos.read_at(REG_HOURS, now), hour, "{value:#x}");
+            assert_eq!(cmos.read_at(REG_MINUTES, now), 0x45);
+        }
+    }
+
+    #[test]
+    fn test_update_in_progress() {
+        let mut cmos = Cmos::new();
+        let uip = 1 << A_UIP;
+        assert_eq!(cmos.read_at(REG_A, at(13, 45, 30, 500_000_000)), A_RESET);
+        let end = NANOS_PER_SEC - UIP_NS;
+        assert_eq!(cmos.read_at(REG_A, at(13, 45, 30, end - 1)), A_RESET);
+        assert_eq!(cmos.read_at(REG_A, at(13, 45, 30, end)), A_RESET | uip);
+        assert_eq!(
+            cmos.read_at(REG_A, at(13, 45, 30, end + 100_000)),
+            A_RESET | uip
+        );
+        // The registers switch at once to the next second.
+        assert_eq!(
+            cmos.read_at(REG_SECONDS, at(13, 45, 30, end + 100_000)),
+            0x30
+        );
+        assert_eq!(cmos.read_at(REG_SECONDS, at(13, 45, 31, 0)), 0x31);
+
+        // The bit is read-only.
+        let now = at(13, 45, 30, 0);
+        cmos.write_at(REG_A, A_RESET | uip, now);
+        assert_eq!(cmos.read_at(REG_A, now), A_RESET);
+
+        // Clear while the updates are stopped, or the divider is.
+        let now = at(13, 45, 30, end + 100_000);
+        cmos.write_at(REG_B, 1 << B_SET | B_RESET, now);
+        assert_eq!(cmos.read_at(REG_A, now), A_RESET);
+        cmos.write_at(REG_B, B_RESET, now);
+        assert_eq!(cmos.read_at(REG_A, now), A_RESET | uip);
+        cmos.write_at(REG_A, 0x06, now);
+        assert_eq!(cmos.read_at(REG_A, now), 0x06);
+    }
+}
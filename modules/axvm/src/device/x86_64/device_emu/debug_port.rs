@@ -1,4 +1,5 @@
-use crate::Result as HyperResult;
+use crate::device::PioBatchOps;
+use crate::{Error as HyperError, Result as HyperResult};
 use hypercraft::PioOps;
 
 pub struct DebugPort {
@@ -26,3 +27,38 @@ impl PioOps for DebugPort {
         Ok(())
     }
 }
+
+impl PioBatchOps for DebugPort {
+    fn write_batch(&mut self, _port: u16, _access_size: u8, _data: &[u8]) -> HyperResult {
+        Ok(())
+    }
+}
+
+/// The `isa-debug-exit` device of QEMU, with which a guest ends its run with an exit code.
+pub struct DebugExit {
+    port: u16,
+}
+
+impl DebugExit {
+    pub fn new(port: u16) -> Self {
+        Self { port }
+    }
+}
+
+impl PioOps for DebugExit {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        self.port..self.port + 1
+    }
+
+    fn read(&mut self, _port: u16, _access_size: u8) -> HyperResult<u32> {
+        Ok(0)
+    }
+
+    fn write(&mut self, _port: u16, _access_size: u8, value: u32) -> HyperResult {
+        let vm_id = crate::vm::current_vm_id().ok_or(HyperError::BadState)?;
+        // As QEMU, which never exits with an even code this way.
+        let code = ((value as u64) << 1) | 1;
+        info!("VM [{}] debug exit with code {:#x}", vm_id, code);
+        crate::vm::shutdown_vm(vm_id, code)
+    }
+}
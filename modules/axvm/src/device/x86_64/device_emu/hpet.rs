@@ -0,0 +1,457 @@
+//! Emulated High Precision Event Timer. (ref: IA-PC HPET Specification 1.0a)
+//!
+//! The main counter is not ticked: it is computed from the host time elapsed since the HPET was
+//! enabled, at [`HPET_FREQ_HZ`]. The time is subtracted modulo 2^64, and a read never returns less
+//! than the previous one, so the counter stays monotonic even if the host time wraps. The
+//! comparators of the [`HPET_TIMERS`] timers are compared with the counter in the same way: the
+//! time of the next interrupt is published in a [`TimerDeadline`] for `check_events`, which takes
+//! the IRQs raised with [`Hpet::pending_irqs`].
+//!
+//! The timers support the one-shot and periodic modes, in 64 or 32 bits. Their interrupts go to
+//! the IO APIC pin of their route, to IRQ 0 and 8 for timers 0 and 1 in the legacy replacement
+//! mode. A level-triggered interrupt sets its bit in the interrupt status register, but is raised
+//! once, as the IO APIC delivers it as an edge. FSB interrupts are not supported. As for the
+//! PIT, periods missed before `check_events` runs are coalesced into one interrupt.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axhal::time::current_time_nanos;
+use bit_field::BitField;
+use hypercraft::{HyperError, HyperResult, MmioOps};
+
+use super::TimerDeadline;
+use crate::device::{DeviceState, StateReader, StateWriter};
+
+const HPET_STATE_VERSION: u16 = 1;
+
+/// Guest physical address of the HPET of a PC.
+pub const HPET_BASE: u64 = 0xfed0_0000;
+const HPET_SIZE: u64 = 0x400;
+/// Timers of the HPET, 3 as on most chipsets.
+pub const HPET_TIMERS: usize = 3;
+/// Frequency of the main counter.
+pub const HPET_FREQ_HZ: u64 = 100_000_000;
+const NANOS_PER_TICK: u64 = 1_000_000_000 / HPET_FREQ_HZ;
+/// Period of the main counter in femtoseconds, as the capabilities register has it.
+const PERIOD_FS: u64 = NANOS_PER_TICK * 1_000_000;
+
+/// Registers, at these offsets of the page.
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIG: u64 = 0x010;
+const INTERRUPT_STATUS: u64 = 0x020;
+const MAIN_COUNTER: u64 = 0x0f0;
+/// The registers of timer `n` are at `TIMER_BASE + n * TIMER_STRIDE`.
+const TIMER_BASE: u64 = 0x100;
+const TIMER_STRIDE: u64 = 0x20;
+const TIMER_CONFIG: u64 = 0x00;
+const TIMER_COMPARATOR: u64 = 0x08;
+
+/// Fields of the capabilities register: 64-bit counter, legacy replacement route, Intel.
+const CAP_COUNT_SIZE: u64 = 1 << 13;
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+const CAP_VENDOR_INTEL: u64 = 0x8086 << 16;
+const CAP_REVISION: u64 = 0x01;
+/// The low half of the capabilities register, the event timer block id of the HPET table of
+/// ACPI.
+pub const HPET_BLOCK_ID: u32 = (CAP_VENDOR_INTEL
+    | CAP_LEGACY_ROUTE
+    | CAP_COUNT_SIZE
+    | ((HPET_TIMERS as u64 - 1) << 8)
+    | CAP_REVISION) as u32;
+
+/// Fields of the general configuration register.
+const CONFIG_ENABLE: usize = 0;
+const CONFIG_LEGACY_ROUTE: usize = 1;
+
+/// Fields of the configuration register of a timer.
+const TIMER_LEVEL: usize = 1;
+const TIMER_INT_ENABLE: usize = 2;
+const TIMER_PERIODIC: usize = 3;
+const TIMER_VAL_SET: usize = 6;
+const TIMER_32BIT: usize = 8;
+const TIMER_ROUTE: Range<usize> = 9..14;
+/// Read-only: periodic mode and 64 bits supported, and the IO APIC pins a timer can be routed to.
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_SIZE_CAP: u64 = 1 << 5;
+const TIMER_ROUTE_CAP: u64 = 0x00ff_ffff << 32;
+/// Level, interrupt enable, periodic, 32 bits and route. The value set bit is taken on writes.
+const TIMER_WRITABLE: u64 = 0x3f0e;
+
+/// IRQs of timers 0 and 1 in the legacy replacement mode, of the PIT and of the RTC.
+const LEGACY_IRQS: [u8; 2] = [0, 8];
+
+struct HpetTimer {
+    config: u64,
+    comparator: u64,
+    /// The period of a periodic timer, in ticks.
+    period: u64,
+    /// The counter value of the next interrupt, if the HPET is enabled.
+    next_fire: Option<u64>,
+}
+
+impl HpetTimer {
+    const fn new() -> Self {
+        Self {
+            config: 0,
+            comparator: u64::MAX,
+            period: 0,
+            next_fire: None,
+        }
+    }
+
+    fn periodic(&self) -> bool {
+        self.config.get_bit(TIMER_PERIODIC)
+    }
+
+    /// Mask of the comparator, 32 bits in the 32-bit mode.
+    fn width_mask(&self) -> u64 {
+        if self.config.get_bit(TIMER_32BIT) {
+            u32::MAX as u64
+        } else {
+            u64::MAX
+        }
+    }
+
+    fn read_config(&self) -> u64 {
+        self.config | TIMER_PERIODIC_CAP | TIMER_SIZE_CAP | TIMER_ROUTE_CAP
+    }
+
+    /// Compute the next interrupt from the comparator, the counter being at `counter`. A 64-bit
+    /// comparator behind the counter only fires once it wraps around, never.
+    fn arm(&mut self, counter: u64) {
+        let mask = self.width_mask();
+        self.comparator &= mask;
+        self.next_fire = counter.checked_add(self.comparator.wrapping_sub(counter) & mask);
+    }
+
+    /// Whether the timer reached its comparator when the counter reached `counter`. A periodic
+    /// timer moves its comparator past the counter, a one-shot timer fires again once the counter
+    /// wraps around in the 32-bit mode.
+    fn advance(&mut self, counter: u64) -> bool {
+        let Some(next_fire) = self.next_fire.filter(|&next_fire| counter >= next_fire) else {
+            return false;
+        };
+        let mask = self.width_mask();
+        if self.periodic() && self.period != 0 {
+            let step = ((counter - next_fire) / self.period + 1) * self.period;
+            self.comparator = self.comparator.wrapping_add(step) & mask;
+            self.next_fire = Some(next_fire + step);
+        } else if mask != u64::MAX {
+            self.next_fire = Some(next_fire + mask + 1);
+        } else {
+            self.next_fire = None;
+        }
+        true
+    }
+
+    fn write_config(&mut self, value: u64) {
+        self.config = value & TIMER_WRITABLE;
+        // Taken by the next comparator write.
+        self.config
+            .set_bit(TIMER_VAL_SET, value.get_bit(TIMER_VAL_SET));
+    }
+
+    /// A write of the comparator: of a periodic timer, it sets the period, and the comparator too
+    /// right after the value set bit was written.
+    fn write_comparator(&mut self, value: u64) {
+        let value = value & self.width_mask();
+        if !self.periodic() || self.config.get_bit(TIMER_VAL_SET) {
+            self.comparator = value;
+        }
+        if self.periodic() {
+            self.period = value;
+        }
+        self.config.set_bit(TIMER_VAL_SET, false);
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state
+            .u64(self.config)
+            .u64(self.comparator)
+            .u64(self.period)
+            .bool(self.next_fire.is_some())
+            .u64(self.next_fire.unwrap_or(0));
+    }
+
+    fn load(state: &mut StateReader) -> HyperResult<Self> {
+        let (config, comparator, period) = (state.u64()?, state.u64()?, state.u64()?);
+        let next_fire = (state.bool()?, state.u64()?);
+        if config & !(TIMER_WRITABLE | 1 << TIMER_VAL_SET) != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(Self {
+            config,
+            comparator,
+            period,
+            next_fire: next_fire.0.then_some(next_fire.1),
+        })
+    }
+}
+
+pub struct Hpet {
+    config: u64,
+    interrupt_status: u64,
+    /// The counter when the HPET was last enabled or written, at `start_ns`.
+    counter_base: u64,
+    start_ns: u64,
+    /// The last counter read, which later reads cannot go below.
+    last_counter: u64,
+    timers: [HpetTimer; HPET_TIMERS],
+    /// The IRQs raised since the last [`Hpet::pending_irqs`], a bit each.
+    pending: u32,
+    /// The next interrupt, for `check_events`.
+    deadline: Arc<TimerDeadline>,
+}
+
+impl Hpet {
+    /// Disabled, the counter at 0 and the timers masked, as after a reset.
+    pub fn new() -> Self {
+        Self {
+            config: 0,
+            interrupt_status: 0,
+            counter_base: 0,
+            start_ns: 0,
+            last_counter: 0,
+            timers: [HpetTimer::new(), HpetTimer::new(), HpetTimer::new()],
+            pending: 0,
+            deadline: Arc::new(TimerDeadline::new()),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.config.get_bit(CONFIG_ENABLE)
+    }
+
+    /// The main counter at `now_ns`.
+    fn counter(&mut self, now_ns: u64) -> u64 {
+        if !self.enabled() {
+            return self.counter_base;
+        }
+        let ticks = now_ns.wrapping_sub(self.start_ns) / NANOS_PER_TICK;
+        self.last_counter = self.last_counter.max(self.counter_base.wrapping_add(ticks));
+        self.last_counter
+    }
+
+    /// The IRQ of timer `index`.
+    fn irq(&self, index: usize) -> u8 {
+        match LEGACY_IRQS.get(index) {
+            Some(&irq) if self.config.get_bit(CONFIG_LEGACY_ROUTE) => irq,
+            _ => self.timers[index].config.get_bits(TIMER_ROUTE) as u8,
+        }
+    }
+
+    /// Fire the timers which reached their comparator by `now_ns`.
+    fn advance(&mut self, now_ns: u64) {
+        if !self.enabled() {
+            return;
+        }
+        let counter = self.counter(now_ns);
+        for index in 0..HPET_TIMERS {
+            let timer = &mut self.timers[index];
+            if !timer.advance(counter) || !timer.config.get_bit(TIMER_INT_ENABLE) {
+                continue;
+            }
+            if timer.config.get_bit(TIMER_LEVEL) {
+                self.interrupt_status.set_bit(index, true);
+            }
+            self.pending |= 1 << self.irq(index);
+        }
+    }
+
+    /// Compute the next interrupt of all the timers, after the counter or the configuration
+    /// changed.
+    fn rearm(&mut self, now_ns: u64) {
+        let enabled = self.enabled();
+        let counter = self.counter(now_ns);
+        for timer in self.timers.iter_mut() {
+            if enabled {
+                timer.arm(counter);
+            } else {
+                timer.next_fire = None;
+            }
+        }
+    }
+
+    fn read_register(&mut self, offset: u64, now_ns: u64) -> u64 {
+        match offset {
+            GENERAL_CAPABILITIES => PERIOD_FS << 32 | HPET_BLOCK_ID as u64,
+            GENERAL_CONFIG => self.config,
+            INTERRUPT_STATUS => self.interrupt_status,
+            MAIN_COUNTER => self.counter(now_ns),
+            offset => match self.timer_register(offset) {
+                Some((index, TIMER_CONFIG)) => self.timers[index].read_config(),
+                Some((index, TIMER_COMPARATOR)) => self.timers[index].comparator,
+                _ => 0,
+            },
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u64, now_ns: u64) {
+        match offset {
+            GENERAL_CONFIG => {
+                let counter = self.counter(now_ns);
+                self.config = value & (1 << CONFIG_ENABLE | 1 << CONFIG_LEGACY_ROUTE);
+                // The counter continues from where it stopped.
+                self.counter_base = counter;
+                self.start_ns = now_ns;
+            }
+            // Write 1 to clear.
+            INTERRUPT_STATUS => self.interrupt_status &= !value,
+            MAIN_COUNTER => {
+                self.counter_base = value;
+                self.last_counter = value;
+                self.start_ns = now_ns;
+            }
+            offset => match self.timer_register(offset) {
+                Some((index, TIMER_CONFIG)) => self.timers[index].write_config(value),
+                Some((index, TIMER_COMPARATOR)) => self.timers[index].write_comparator(value),
+                _ => return,
+            },
+        }
+        self.rearm(now_ns);
+    }
+
+    /// The timer and the offset in its registers of the register at `offset`.
+    fn timer_register(&self, offset: u64) -> Option<(usize, u64)> {
+        let index = (offset.checked_sub(TIMER_BASE)? / TIMER_STRIDE) as usize;
+        (index < HPET_TIMERS).then_some((index, (offset - TIMER_BASE) % TIMER_STRIDE))
+    }
+
+    /// The offset of the 64-bit register holding `addr`, and the shift and the mask of the
+    /// `access_size` bytes accessed in it.
+    fn split(addr: u64, access_size: u8) -> (u64, u32, u64) {
+        let offset = addr - HPET_BASE;
+        let shift = (offset % 8) * 8;
+        let mask = match access_size {
+            8 => u64::MAX,
+            size => (1 << (size as u64 * 8)) - 1,
+        };
+        (offset & !7, shift as u32, mask)
+    }
+
+    pub(super) fn publish_deadline(&self) {
+        // Raised by an access, the IRQs are due now.
+        if self.pending != 0 {
+            return self.deadline.set(Some(self.start_ns.max(1)));
+        }
+        let deadline = self
+            .timers
+            .iter()
+            .filter(|timer| timer.config.get_bit(TIMER_INT_ENABLE))
+            .filter_map(|timer| timer.next_fire)
+            .map(|next_fire| {
+                let ticks = next_fire.wrapping_sub(self.counter_base);
+                self.start_ns
+                    .saturating_add(ticks.saturating_mul(NANOS_PER_TICK))
+            })
+            .min();
+        self.deadline.set(deadline.filter(|_| self.enabled()));
+    }
+}
+
+impl Hpet {
+    /// The lock-free view of the time of the next interrupt.
+    pub fn deadline(&self) -> Arc<TimerDeadline> {
+        self.deadline.clone()
+    }
+
+    /// The IRQs raised since the last call, bit `n` for IRQ `n`.
+    pub fn pending_irqs(&mut self) -> u32 {
+        self.advance(current_time_nanos());
+        self.publish_deadline();
+        core::mem::take(&mut self.pending)
+    }
+
+    /// Move the counter `delta_ns` later, so that it resumes where it was when the VM was paused.
+    pub fn shift_time(&mut self, delta_ns: u64) {
+        if self.enabled() {
+            self.start_ns += delta_ns;
+        }
+        self.publish_deadline();
+    }
+}
+
+impl Default for Hpet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MmioOps for Hpet {
+    fn mmio_range(&self) -> Range<u64> {
+        HPET_BASE..HPET_BASE + HPET_SIZE
+    }
+
+    fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+        let now_ns = current_time_nanos();
+        self.advance(now_ns);
+        let (offset, shift, mask) = Self::split(addr, access_size);
+        let value = self.read_register(offset, now_ns);
+        self.publish_deadline();
+        Ok((value >> shift) & mask)
+    }
+
+    fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+        let now_ns = current_time_nanos();
+        self.advance(now_ns);
+        let (offset, shift, mask) = Self::split(addr, access_size);
+        let value = (value & mask) << shift;
+        let value = match offset {
+            // Only the bits written are cleared.
+            INTERRUPT_STATUS => value,
+            // The other bits of the register are kept.
+            offset => (self.read_register(offset, now_ns) & !(mask << shift)) | value,
+        };
+        self.write_register(offset, value, now_ns);
+        self.publish_deadline();
+        Ok(())
+    }
+}
+
+impl DeviceState for Hpet {
+    fn save(&self) -> Vec<u8> {
+        let now_ns = current_time_nanos();
+        let mut state = StateWriter::new(HPET_STATE_VERSION);
+        state
+            .u64(self.config)
+            .u64(self.interrupt_status)
+            .u64(self.counter_base)
+            .timestamp(self.start_ns, now_ns)
+            .u64(self.last_counter)
+            .u32(self.pending);
+        for timer in self.timers.iter() {
+            timer.save(&mut state);
+        }
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let now_ns = current_time_nanos();
+        let mut state = StateReader::new(state, HPET_STATE_VERSION)?;
+        let restored = Self {
+            config: state.u64()?,
+            interrupt_status: state.u64()?,
+            counter_base: state.u64()?,
+            start_ns: state.timestamp(now_ns)?,
+            last_counter: state.u64()?,
+            pending: state.u32()?,
+            timers: [
+                HpetTimer::load(&mut state)?,
+                HpetTimer::load(&mut state)?,
+                HpetTimer::load(&mut state)?,
+            ],
+            deadline: self.deadline.clone(),
+        };
+        state.finish()?;
+        if restored.config & !(1 << CONFIG_ENABLE | 1 << CONFIG_LEGACY_ROUTE) != 0
+            || restored.start_ns > now_ns
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        *self = restored;
+        self.publish_deadline();
+        Ok(())
+    }
+}
@@ -0,0 +1,491 @@
+//! Emulated Intel 8042 PS/2 controller, with a keyboard on its first port and no second port.
+//! (ref: https://wiki.osdev.org/%228042%22_PS/2_Controller)
+//!
+//! The controller answers its commands and the keyboard its own at once, so the input buffer is
+//! always empty, and the bytes for the guest wait in the output queue. IRQ 1 is raised through
+//! the master PIC by the vCPU devices each time a byte reaches the output buffer, see
+//! [`I8042::poll_interrupt`].
+//!
+//! Host keystrokes are typed into the guest with [`I8042::inject_scancodes`] and
+//! [`I8042::type_ascii`], as scancodes of set 1: what the guest reads with the translation of the
+//! command byte, which it enables by default.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use bit_field::BitField;
+use hypercraft::{HyperError, HyperResult, PioOps};
+use log::Level;
+
+use super::{pmio_proxy_factory, pmio_proxy_struct};
+use crate::device::{DeviceState, StateReader, StateWriter};
+use crate::ratelimit::RateLimiter;
+
+pub const PORT_I8042_DATA: u16 = 0x60;
+pub const PORT_I8042_COMMAND: u16 = 0x64;
+
+const I8042_STATE_VERSION: u16 = 1;
+
+static I8042_LOG: RateLimiter = RateLimiter::new("i8042", 10);
+
+/// Output buffer full.
+const STATUS_OBF: u8 = 1 << 0;
+/// System flag, set once the self-test passed.
+const STATUS_SYS: u8 = 1 << 2;
+/// The last write was to the command port.
+const STATUS_COMMAND: u8 = 1 << 3;
+/// Keyboard not inhibited.
+const STATUS_UNLOCKED: u8 = 1 << 4;
+
+/// Bits of the command byte.
+const CMD_BYTE_KBD_INT: usize = 0;
+const CMD_BYTE_SYS: usize = 2;
+const CMD_BYTE_KBD_DISABLED: usize = 4;
+const CMD_BYTE_AUX_DISABLED: usize = 5;
+/// Keyboard interrupt, system flag, no second port and set 2 to set 1 translation.
+const CMD_BYTE_RESET: u8 = 0x65;
+
+/// Bits of the output port.
+const OUTPUT_PORT_RESET: usize = 0;
+const OUTPUT_PORT_A20: usize = 1;
+/// System not in reset, A20 enabled.
+const OUTPUT_PORT_RESET_VALUE: u8 = 0x03;
+
+const KBD_ACK: u8 = 0xfa;
+const KBD_RESEND: u8 = 0xfe;
+const KBD_BAT_PASSED: u8 = 0xaa;
+const KBD_ECHO: u8 = 0xee;
+/// Identity of a MF2 keyboard with translation.
+const KBD_ID: [u8; 2] = [0xab, 0x41];
+
+/// Bytes queued for the guest beyond which host input is dropped.
+const OUTPUT_QUEUE_CAPACITY: usize = 64;
+
+/// Controller commands whose parameter is the next byte written to the data port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingWrite {
+    /// Data for the keyboard.
+    Keyboard,
+    CommandByte,
+    OutputPort,
+    /// A byte to put in the output buffer as if the keyboard sent it.
+    KeyboardOutput,
+    /// A byte for the second port, which does not exist.
+    Aux,
+    /// The parameter of a keyboard command, e.g. the LED state after 0xed.
+    KeyboardParam,
+}
+
+impl PendingWrite {
+    fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    fn from_u8(value: u8) -> HyperResult<Self> {
+        Ok(match value {
+            0 => Self::Keyboard,
+            1 => Self::CommandByte,
+            2 => Self::OutputPort,
+            3 => Self::KeyboardOutput,
+            4 => Self::Aux,
+            5 => Self::KeyboardParam,
+            _ => return Err(HyperError::InvalidParam),
+        })
+    }
+}
+
+pub struct I8042 {
+    command_byte: u8,
+    output_port: u8,
+    /// What the next byte written to the data port is for.
+    pending_write: PendingWrite,
+    /// Whether the last write was to the command port.
+    last_write_command: bool,
+    self_tested: bool,
+    /// The keyboard sends the keys pressed.
+    scanning: bool,
+    /// Bytes for the guest, the first one being in the output buffer.
+    output: VecDeque<u8>,
+    /// The last byte read, read again while the output buffer is empty.
+    last_output: u8,
+    /// A byte reached the output buffer since `poll_interrupt` was last called.
+    irq_raised: bool,
+}
+
+pmio_proxy_struct!(
+    PORT_I8042_COMMAND,
+    PORT_I8042_COMMAND,
+    I8042CommandPort,
+    I8042,
+    read_status,
+    write_command
+);
+
+impl PioOps for I8042 {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        PORT_I8042_DATA..PORT_I8042_DATA + 1
+    }
+
+    fn read(&mut self, _port: u16, _access_size: u8) -> HyperResult<u32> {
+        if let Some(value) = self.output.pop_front() {
+            self.last_output = value;
+            // The next byte reaches the output buffer.
+            self.irq_raised |= !self.output.is_empty();
+        }
+        Ok(self.last_output as u32)
+    }
+
+    fn write(&mut self, _port: u16, _access_size: u8, value: u32) -> HyperResult {
+        let value = value as u8;
+        self.last_write_command = false;
+        match core::mem::replace(&mut self.pending_write, PendingWrite::Keyboard) {
+            PendingWrite::Keyboard => self.keyboard_command(value),
+            PendingWrite::CommandByte => {
+                self.command_byte = value;
+                self.self_tested |= value.get_bit(CMD_BYTE_SYS);
+            }
+            PendingWrite::OutputPort => self.write_output_port(value),
+            PendingWrite::KeyboardOutput => self.push_output(value),
+            PendingWrite::Aux => {}
+            PendingWrite::KeyboardParam => self.push_output(KBD_ACK),
+        }
+        Ok(())
+    }
+}
+
+impl I8042 {
+    pub fn new() -> Self {
+        Self {
+            command_byte: CMD_BYTE_RESET,
+            output_port: OUTPUT_PORT_RESET_VALUE,
+            pending_write: PendingWrite::Keyboard,
+            last_write_command: false,
+            self_tested: false,
+            scanning: true,
+            output: VecDeque::new(),
+            last_output: 0,
+            irq_raised: false,
+        }
+    }
+
+    pmio_proxy_factory!(proxy_command_port, I8042CommandPort);
+
+    fn read_status(&mut self, _port: u16, _access_size: u8) -> HyperResult<u32> {
+        let mut status = STATUS_UNLOCKED;
+        if !self.output.is_empty() {
+            status |= STATUS_OBF;
+        }
+        if self.self_tested {
+            status |= STATUS_SYS;
+        }
+        if self.last_write_command {
+            status |= STATUS_COMMAND;
+        }
+        Ok(status as u32)
+    }
+
+    fn write_command(&mut self, _port: u16, _access_size: u8, value: u32) -> HyperResult {
+        self.last_write_command = true;
+        self.pending_write = PendingWrite::Keyboard;
+        match value as u8 {
+            // Read byte 0 of the internal RAM, the command byte.
+            0x20 => self.push_output(self.command_byte),
+            0x60 => self.pending_write = PendingWrite::CommandByte,
+            0xa7 => {
+                self.command_byte.set_bit(CMD_BYTE_AUX_DISABLED, true);
+            }
+            0xa8 => {
+                // There is no second port to enable.
+            }
+            // The clock line of the missing second port is stuck low.
+            0xa9 => self.push_output(0x01),
+            0xaa => {
+                self.self_tested = true;
+                self.command_byte.set_bit(CMD_BYTE_SYS, true);
+                self.push_output(0x55);
+            }
+            0xab => self.push_output(0x00),
+            0xad => {
+                self.command_byte.set_bit(CMD_BYTE_KBD_DISABLED, true);
+            }
+            0xae => {
+                self.command_byte.set_bit(CMD_BYTE_KBD_DISABLED, false);
+            }
+            0xc0 => self.push_output(0x00),
+            0xd0 => self.push_output(self.output_port),
+            0xd1 => self.pending_write = PendingWrite::OutputPort,
+            0xd2 => self.pending_write = PendingWrite::KeyboardOutput,
+            0xd3 | 0xd4 => self.pending_write = PendingWrite::Aux,
+            // The A20 commands of some controllers.
+            0xdd => self.write_output_port(self.output_port & !(1 << OUTPUT_PORT_A20)),
+            0xdf => self.write_output_port(self.output_port | (1 << OUTPUT_PORT_A20)),
+            // Pulse output lines, bit 0 being the reset line, active low.
+            command @ 0xf0..=0xff => {
+                if !command.get_bit(OUTPUT_PORT_RESET) {
+                    self.reset_requested();
+                }
+            }
+            command => ratelimited!(
+                I8042_LOG,
+                Level::Warn,
+                "i8042: unsupported controller command {:#x}",
+                command
+            ),
+        }
+        Ok(())
+    }
+
+    fn write_output_port(&mut self, value: u8) {
+        if !value.get_bit(OUTPUT_PORT_A20) {
+            // Guest memory is never wrapped at 1 MB.
+            ratelimited!(I8042_LOG, Level::Warn, "i8042: A20 disabled, not emulated");
+        }
+        if !value.get_bit(OUTPUT_PORT_RESET) {
+            self.reset_requested();
+        }
+        self.output_port = value | (1 << OUTPUT_PORT_RESET);
+    }
+
+    fn reset_requested(&self) {
+        if crate::vm::guest_reset("i8042").is_err() {
+            ratelimited!(
+                I8042_LOG,
+                Level::Warn,
+                "i8042: system reset requested, not supported"
+            );
+        }
+    }
+
+    /// A byte written to the data port, for the keyboard.
+    fn keyboard_command(&mut self, command: u8) {
+        match command {
+            // Reset and self-test.
+            0xff => {
+                self.scanning = true;
+                self.push_output(KBD_ACK);
+                self.push_output(KBD_BAT_PASSED);
+            }
+            0xfe => {}
+            // Set defaults, disable and enable scanning.
+            0xf6 => self.push_output(KBD_ACK),
+            0xf5 => {
+                self.scanning = false;
+                self.push_output(KBD_ACK);
+            }
+            0xf4 => {
+                self.scanning = true;
+                self.push_output(KBD_ACK);
+            }
+            // Set the LEDs, the typematic rate or the scancode set, whose parameter follows.
+            0xed | 0xf3 | 0xf0 => {
+                self.pending_write = PendingWrite::KeyboardParam;
+                self.push_output(KBD_ACK);
+            }
+            0xf2 => {
+                self.push_output(KBD_ACK);
+                KBD_ID.into_iter().for_each(|byte| self.push_output(byte));
+            }
+            0xee => self.push_output(KBD_ECHO),
+            command => {
+                ratelimited!(
+                    I8042_LOG,
+                    Level::Debug,
+                    "i8042: unsupported keyboard command {:#x}",
+                    command
+                );
+                self.push_output(KBD_RESEND);
+            }
+        }
+    }
+
+    fn push_output(&mut self, value: u8) {
+        if self.output.len() >= OUTPUT_QUEUE_CAPACITY {
+            return;
+        }
+        self.output.push_back(value);
+        // Raised only when the byte reaches the output buffer.
+        if self.output.len() == 1 {
+            self.irq_raised = true;
+        }
+    }
+
+    /// Send scancodes of set 1 from the keyboard, e.g. `[0x1e, 0x9e]` to press and release A.
+    /// Dropped while the guest disabled the keyboard or its scanning, or if the guest is too far
+    /// behind reading them.
+    pub fn inject_scancodes(&mut self, scancodes: &[u8]) {
+        if !self.scanning || self.command_byte.get_bit(CMD_BYTE_KBD_DISABLED) {
+            return;
+        }
+        if self.output.len() + scancodes.len() > OUTPUT_QUEUE_CAPACITY {
+            ratelimited!(
+                I8042_LOG,
+                Level::Warn,
+                "i8042: {} scancodes dropped, the guest does not read them",
+                scancodes.len()
+            );
+            return;
+        }
+        scancodes.iter().for_each(|&code| self.push_output(code));
+    }
+
+    /// Type `text` on the keyboard, pressing and releasing a key, with shift if needed, for each
+    /// printable US ASCII character, Enter for `\n`, Tab and Backspace. The other characters
+    /// are skipped.
+    pub fn type_ascii(&mut self, text: &str) {
+        const LEFT_SHIFT: u8 = 0x2a;
+        const RELEASE: u8 = 0x80;
+        for c in text.bytes() {
+            let Some((code, shift)) = scancode_of(c) else {
+                continue;
+            };
+            let mut codes = Vec::with_capacity(4);
+            if shift {
+                codes.push(LEFT_SHIFT);
+            }
+            codes.extend([code, code | RELEASE]);
+            if shift {
+                codes.push(LEFT_SHIFT | RELEASE);
+            }
+            self.inject_scancodes(&codes);
+        }
+    }
+
+    /// Whether a byte reached the output buffer since the last call, with the keyboard interrupt
+    /// enabled.
+    pub(crate) fn poll_interrupt(&mut self) -> bool {
+        core::mem::take(&mut self.irq_raised) && self.command_byte.get_bit(CMD_BYTE_KBD_INT)
+    }
+}
+
+/// The rows of the main block of a US keyboard, from their first scancode of set 1, unshifted
+/// and shifted.
+const KEY_ROWS: [(u8, &[u8], &[u8]); 4] = [
+    (0x02, b"1234567890-=", b"!@#$%^&*()_+"),
+    (0x10, b"qwertyuiop[]", b"QWERTYUIOP{}"),
+    (0x1e, b"asdfghjkl;'`", b"ASDFGHJKL:\"~"),
+    (0x2b, b"\\zxcvbnm,./", b"|ZXCVBNM<>?"),
+];
+
+/// The set 1 scancode of the key typing `c`, and whether it needs shift.
+fn scancode_of(c: u8) -> Option<(u8, bool)> {
+    match c {
+        b' ' => return Some((0x39, false)),
+        b'\n' => return Some((0x1c, false)),
+        b'\t' => return Some((0x0f, false)),
+        0x08 => return Some((0x0e, false)),
+        _ => {}
+    }
+    KEY_ROWS.iter().find_map(|&(first, plain, shifted)| {
+        let key = |row: &[u8]| row.iter().position(|&k| k == c);
+        match (key(plain), key(shifted)) {
+            (Some(i), _) => Some((first + i as u8, false)),
+            (_, Some(i)) => Some((first + i as u8, true)),
+            _ => None,
+        }
+    })
+}
+
+impl DeviceState for I8042 {
+    fn save(&self) -> Vec<u8> {
+        let output: Vec<u8> = self.output.iter().copied().collect();
+        let mut state = StateWriter::new(I8042_STATE_VERSION);
+        state
+            .u8(self.command_byte)
+            .u8(self.output_port)
+            .u8(self.pending_write.to_u8())
+            .bool(self.last_write_command)
+            .bool(self.self_tested)
+            .bool(self.scanning)
+            .bytes(&output)
+            .u8(self.last_output)
+            .bool(self.irq_raised);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let mut state = StateReader::new(state, I8042_STATE_VERSION)?;
+        let command_byte = state.u8()?;
+        let output_port = state.u8()?;
+        let pending_write = PendingWrite::from_u8(state.u8()?)?;
+        let last_write_command = state.bool()?;
+        let self_tested = state.bool()?;
+        let scanning = state.bool()?;
+        let output = state.bytes()?;
+        let last_output = state.u8()?;
+        let irq_raised = state.bool()?;
+        state.finish()?;
+        if output.len() > OUTPUT_QUEUE_CAPACITY {
+            return Err(HyperError::InvalidParam);
+        }
+        *self = Self {
+            command_byte,
+            output_port,
+            pending_write,
+            last_write_command,
+            self_tested,
+            scanning,
+            output: output.iter().copied().collect(),
+            last_output,
+            irq_raised,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_data(i8042: &mut I8042) -> u8 {
+        PioOps::read(i8042, PORT_I8042_DATA, 1).unwrap() as u8
+    }
+
+    fn status(i8042: &mut I8042) -> u8 {
+        i8042.read_status(PORT_I8042_COMMAND, 1).unwrap() as u8
+    }
+
+    #[test]
+    fn test_self_test() {
+        let mut i8042 = I8042::new();
+        assert_eq!(status(&mut i8042), STATUS_UNLOCKED);
+        i8042.write_command(PORT_I8042_COMMAND, 1, 0xaa).unwrap();
+        assert_eq!(
+            status(&mut i8042),
+            STATUS_UNLOCKED | STATUS_COMMAND | STATUS_SYS | STATUS_OBF
+        );
+        assert!(i8042.poll_interrupt());
+        assert_eq!(read_data(&mut i8042), 0x55);
+        assert_eq!(
+            status(&mut i8042),
+            STATUS_UNLOCKED | STATUS_COMMAND | STATUS_SYS
+        );
+        // The last byte is read again from an empty output buffer.
+        assert_eq!(read_data(&mut i8042), 0x55);
+        assert!(!i8042.poll_interrupt());
+
+        // The command byte has the system flag.
+        i8042.write_command(PORT_I8042_COMMAND, 1, 0x20).unwrap();
+        assert_eq!(read_data(&mut i8042), CMD_BYTE_RESET);
+    }
+
+    #[test]
+    fn test_keyboard_reset() {
+        let mut i8042 = I8042::new();
+        PioOps::write(&mut i8042, PORT_I8042_DATA, 1, 0xf5).unwrap();
+        assert_eq!(read_data(&mut i8042), KBD_ACK);
+        i8042.inject_scancodes(&[0x1e, 0x9e]);
+        assert_eq!(status(&mut i8042) & STATUS_OBF, 0);
+
+        // Acknowledged, then the self-test passed, and the keyboard scans again.
+        PioOps::write(&mut i8042, PORT_I8042_DATA, 1, 0xff).unwrap();
+        assert!(i8042.poll_interrupt());
+        assert_eq!(read_data(&mut i8042), KBD_ACK);
+        // The second byte reaches the output buffer, with its own interrupt.
+        assert!(i8042.poll_interrupt());
+        assert_eq!(read_data(&mut i8042), KBD_BAT_PASSED);
+        assert_eq!(status(&mut i8042) & STATUS_OBF, 0);
+        i8042.inject_scancodes(&[0x1e, 0x9e]);
+        assert_eq!(read_data(&mut i8042), 0x1e);
+        assert_eq!(read_data(&mut i8042), 0x9e);
+    }
+}
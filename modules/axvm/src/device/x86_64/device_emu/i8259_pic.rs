@@ -3,6 +3,12 @@
 use bit_field::BitField;
 use hypercraft::{HyperError, HyperResult, PioOps};
 
+use alloc::vec::Vec;
+
+use crate::device::{DeviceState, StateReader, StateWriter};
+
+const PIC_STATE_VERSION: u16 = 1;
+
 pub struct I8259Pic {
     port_base: u16,
     icw1: u8,
@@ -100,4 +106,110 @@ impl I8259Pic {
     pub const fn mask(&self) -> u8 {
         self.mask
     }
+
+    /// Vector of IRQ 0, as programmed by ICW2.
+    pub const fn offset(&self) -> u8 {
+        self.offset
+    }
+}
+
+impl DeviceState for I8259Pic {
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(PIC_STATE_VERSION);
+        state
+            .u16(self.port_base)
+            .u8(self.icw1)
+            .u8(self.offset)
+            .u8(self.icw3)
+            .u8(self.icw4)
+            .u8(self.icw_written)
+            .bool(self.icw_left)
+            .u8(self.mask);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let mut state = StateReader::new(state, PIC_STATE_VERSION)?;
+        let restored = Self {
+            port_base: state.u16()?,
+            icw1: state.u8()?,
+            offset: state.u8()?,
+            icw3: state.u8()?,
+            icw4: state.u8()?,
+            icw_written: state.u8()?,
+            icw_left: state.bool()?,
+            mask: state.u8()?,
+        };
+        state.finish()?;
+        if restored.port_base != self.port_base || restored.icw_written > 3 {
+            return Err(HyperError::InvalidParam);
+        }
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start the initialization of a PIC at 0x20, with ICW4 expected.
+    fn start_init(pic: &mut I8259Pic) {
+        pic.write(0x20, 1, 0x11).unwrap();
+        pic.write(0x21, 1, 0x20).unwrap();
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut pic = I8259Pic::new(0x20);
+        start_init(&mut pic);
+        pic.write(0x21, 1, 0x04).unwrap();
+        pic.write(0x21, 1, 0x01).unwrap();
+        pic.write(0x21, 1, 0xfb).unwrap();
+
+        let mut restored = I8259Pic::new(0x20);
+        restored.restore(&pic.save()).unwrap();
+        assert_eq!(restored.save(), pic.save());
+        assert_eq!(restored.offset(), 0x20);
+        assert_eq!(restored.read(0x21, 1).unwrap(), 0xfb);
+    }
+
+    #[test]
+    fn test_round_trip_mid_init() {
+        let mut pic = I8259Pic::new(0x20);
+        start_init(&mut pic);
+
+        // The restored PIC expects ICW3 and ICW4 before the mask, as the saved one did.
+        let mut restored = I8259Pic::new(0x20);
+        restored.restore(&pic.save()).unwrap();
+        for pic in [&mut pic, &mut restored] {
+            pic.write(0x21, 1, 0x04).unwrap();
+            pic.write(0x21, 1, 0x01).unwrap();
+            pic.write(0x21, 1, 0xff).unwrap();
+        }
+        assert_eq!(restored.save(), pic.save());
+        assert_eq!(restored.read(0x21, 1).unwrap(), 0xff);
+    }
+
+    #[test]
+    fn test_rejected_states() {
+        let mut pic = I8259Pic::new(0x20);
+        pic.write(0x21, 1, 0x12).unwrap();
+        let saved = pic.save();
+
+        let mut secondary = I8259Pic::new(0xa0);
+        assert!(matches!(
+            secondary.restore(&saved),
+            Err(HyperError::InvalidParam)
+        ));
+        assert_eq!(secondary.save(), I8259Pic::new(0xa0).save());
+
+        // The ICW counter, the byte before `icw_left` and the mask.
+        let mut bad = saved.clone();
+        let icw_written = bad.len() - 3;
+        bad[icw_written] = 4;
+        assert!(matches!(pic.restore(&bad), Err(HyperError::InvalidParam)));
+        assert!(pic.restore(&saved[..saved.len() - 1]).is_err());
+        assert_eq!(pic.save(), saved);
+    }
 }
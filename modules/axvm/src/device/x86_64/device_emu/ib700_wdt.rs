@@ -0,0 +1,160 @@
+//! Emulated IB700 ISA watchdog, as QEMU's `ib700` and the Linux `ib700wdt` driver have it.
+//!
+//! Writing port 0x443 starts the watchdog, or reloads it, with the timeout its low nibble
+//! selects: 30 seconds minus 2 per step, down to 0 for 15. Writing port 0x441 stops it. The
+//! ports read as 0. Once a timeout runs out, `check_events` resets the VM, with the
+//! [`ResetPolicy`](crate::ResetPolicy) of its config, and the watchdog is stopped.
+//!
+//! The time of the expiry is published in a [`TimerDeadline`], the watchdog being one of the
+//! timer sources of the vCPU.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use axhal::time::current_time_nanos;
+use hypercraft::{HyperError, HyperResult, PioOps};
+
+use super::TimerDeadline;
+use crate::device::{DeviceState, StateReader, StateWriter};
+
+const WDT_STATE_VERSION: u16 = 1;
+
+/// Stops the watchdog.
+pub const PORT_WDT_STOP: u16 = 0x441;
+/// Starts or reloads the watchdog.
+pub const PORT_WDT_START: u16 = 0x443;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The timeout selected by the low nibble of a write of [`PORT_WDT_START`], in seconds.
+fn timeout_secs(value: u8) -> u64 {
+    30 - 2 * (value & 0xf) as u64
+}
+
+pub struct Ib700Watchdog {
+    /// Time of the expiry, 0 if stopped.
+    expiry_ns: u64,
+    deadline: Arc<TimerDeadline>,
+}
+
+impl Ib700Watchdog {
+    pub fn new() -> Self {
+        Self {
+            expiry_ns: 0,
+            deadline: Arc::new(TimerDeadline::new()),
+        }
+    }
+
+    /// Time of the expiry, for `check_events`.
+    pub fn deadline(&self) -> Arc<TimerDeadline> {
+        self.deadline.clone()
+    }
+
+    fn set_expiry(&mut self, expiry_ns: u64) {
+        self.expiry_ns = expiry_ns;
+        self.deadline.set((expiry_ns != 0).then_some(expiry_ns));
+    }
+
+    /// Start or reload the watchdog at `now_ns` with the timeout selected by `value`. A timeout
+    /// of 0 expires at the next check.
+    fn start(&mut self, value: u8, now_ns: u64) {
+        self.set_expiry((now_ns + timeout_secs(value) * NANOS_PER_SEC).max(1));
+    }
+
+    /// Whether the watchdog expired by `now_ns`, and the VM must be reset. It is stopped then,
+    /// until the guest starts it again.
+    pub fn check_expired(&mut self, now_ns: u64) -> bool {
+        if self.expiry_ns == 0 || now_ns < self.expiry_ns {
+            return false;
+        }
+        self.set_expiry(0);
+        true
+    }
+}
+
+impl Default for Ib700Watchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PioOps for Ib700Watchdog {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        PORT_WDT_STOP..PORT_WDT_START + 1
+    }
+
+    fn read(&mut self, _port: u16, _access_size: u8) -> HyperResult<u32> {
+        Ok(0)
+    }
+
+    fn write(&mut self, port: u16, _access_size: u8, value: u32) -> HyperResult {
+        match port {
+            PORT_WDT_START => self.start(value as u8, current_time_nanos()),
+            PORT_WDT_STOP => self.set_expiry(0),
+            // Port 0x442 does nothing.
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl DeviceState for Ib700Watchdog {
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(WDT_STATE_VERSION);
+        state.timestamp(self.expiry_ns, current_time_nanos());
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let now_ns = current_time_nanos();
+        let mut state = StateReader::new(state, WDT_STATE_VERSION)?;
+        let expiry_ns = state.timestamp(now_ns)?;
+        state.finish()?;
+        if expiry_ns > now_ns + timeout_secs(0) * NANOS_PER_SEC {
+            return Err(HyperError::InvalidParam);
+        }
+        self.set_expiry(expiry_ns);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: u64 = NANOS_PER_SEC;
+
+    #[test]
+    fn test_timeouts() {
+        let mut wdt = Ib700Watchdog::new();
+        assert!(!wdt.check_expired(u64::MAX));
+        assert_eq!(wdt.deadline().get(), None);
+
+        wdt.start(0, 100);
+        assert_eq!(wdt.deadline().get(), Some(100 + 30 * SEC));
+        wdt.start(0x1e, 100);
+        assert_eq!(wdt.deadline().get(), Some(100 + 2 * SEC));
+        // A timeout of 0 expires at once.
+        wdt.start(0xf, 100);
+        assert!(wdt.check_expired(100));
+    }
+
+    #[test]
+    fn test_reload_and_stop() {
+        let mut wdt = Ib700Watchdog::new();
+        wdt.start(0xe, 0);
+        // Reloaded before it runs out, it runs on from the reload.
+        assert!(!wdt.check_expired(SEC));
+        wdt.start(0xe, SEC);
+        assert!(!wdt.check_expired(2 * SEC));
+        assert!(wdt.check_expired(3 * SEC));
+        // Expired, it stays stopped.
+        assert!(!wdt.check_expired(4 * SEC));
+        assert_eq!(wdt.deadline().get(), None);
+
+        wdt.start(0xe, 0);
+        wdt.write(PORT_WDT_STOP, 1, 0).unwrap();
+        assert!(!wdt.check_expired(10 * SEC));
+        assert_eq!(wdt.deadline().get(), None);
+    }
+}
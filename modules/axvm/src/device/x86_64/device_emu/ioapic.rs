@@ -0,0 +1,269 @@
+//! Emulated IO APIC, an 82093AA with 24 pins. (ref: 82093AA I/O Advanced Programmable Interrupt
+//! Controller datasheet)
+//!
+//! The redirection table is consulted when an emulated device raises its IRQ, see
+//! [`IoApic::assert_irq`]. The vCPUs EOI their interrupts in the local APIC of their CPU, which the
+//! IO APIC never sees: the remote IRR bit is never set, and level-triggered pins are delivered
+//! like edge-triggered ones. The ISA devices emulated are edge-triggered anyway.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use bit_field::BitField;
+use hypercraft::{HyperError, HyperResult, MmioOps};
+
+use crate::device::{DeviceState, StateReader, StateWriter};
+
+const IOAPIC_STATE_VERSION: u16 = 1;
+
+/// Guest physical address of the IO APIC of a PC.
+pub const IOAPIC_BASE: u64 = 0xfec0_0000;
+const IOAPIC_SIZE: u64 = 0x1000;
+pub const IOAPIC_PINS: usize = 24;
+
+/// The index and data registers, at these offsets of the page.
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+/// The registers selected by IOREGSEL. Redirection entry `n` is at `IOREDTBL + 2 * n`, its high
+/// dword at the next index.
+const IOAPICID: u8 = 0x00;
+const IOAPICVER: u8 = 0x01;
+const IOAPICARB: u8 = 0x02;
+const IOREDTBL: u8 = 0x10;
+
+const IOAPIC_VERSION: u32 = 0x11;
+
+/// Fields of a redirection entry.
+const RTE_DELIVERY_MODE: Range<usize> = 8..11;
+const RTE_LOGICAL: usize = 11;
+const RTE_MASKED: usize = 16;
+const RTE_DEST: Range<usize> = 56..64;
+/// Vector, delivery and destination modes, polarity, trigger mode, mask and destination. The
+/// delivery status and the remote IRR are read-only.
+const RTE_WRITABLE: u64 = 0xff00_0000_0001_afff;
+
+/// Delivery modes of a redirection entry.
+pub const DELIVERY_FIXED: u8 = 0;
+pub const DELIVERY_LOWEST_PRIORITY: u8 = 1;
+/// The pin is handed to the PICs, which raise the interrupt.
+pub const DELIVERY_EXT_INT: u8 = 7;
+
+/// Where an unmasked pin sends its interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicRoute {
+    pub vector: u8,
+    pub delivery_mode: u8,
+    /// An APIC id, or a set of logical ids if `logical`.
+    pub dest: u8,
+    pub logical: bool,
+}
+
+pub struct IoApic {
+    id: u8,
+    select: u8,
+    redirection: [u64; IOAPIC_PINS],
+}
+
+impl IoApic {
+    /// Every pin masked, as after a reset.
+    pub const fn new() -> Self {
+        Self {
+            id: 0,
+            select: 0,
+            redirection: [1 << RTE_MASKED; IOAPIC_PINS],
+        }
+    }
+
+    /// The route of `gsi`, `None` if its pin is masked or there is no such pin.
+    pub fn route(&self, gsi: u8) -> Option<IoApicRoute> {
+        let entry = *self.redirection.get(gsi as usize)?;
+        if entry.get_bit(RTE_MASKED) {
+            return None;
+        }
+        Some(IoApicRoute {
+            vector: entry as u8,
+            delivery_mode: entry.get_bits(RTE_DELIVERY_MODE) as u8,
+            dest: entry.get_bits(RTE_DEST) as u8,
+            logical: entry.get_bit(RTE_LOGICAL),
+        })
+    }
+
+    /// Consult the redirection table for an interrupt on `gsi`, returns where it is sent, `None`
+    /// if the pin is masked.
+    pub fn assert_irq(&self, gsi: u8) -> Option<IoApicRoute> {
+        let route = self.route(gsi);
+        trace!("IO APIC pin {} asserted -> {:x?}", gsi, route);
+        route
+    }
+
+    fn read_register(&self, index: u8) -> u32 {
+        match index {
+            IOAPICID | IOAPICARB => (self.id as u32) << 24,
+            IOAPICVER => IOAPIC_VERSION | ((IOAPIC_PINS as u32 - 1) << 16),
+            index if index >= IOREDTBL => {
+                let pin = ((index - IOREDTBL) / 2) as usize;
+                match self.redirection.get(pin) {
+                    Some(&entry) if index % 2 == 0 => entry as u32,
+                    Some(&entry) => (entry >> 32) as u32,
+                    None => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, index: u8, value: u32) {
+        match index {
+            IOAPICID => self.id = value.get_bits(24..28) as u8,
+            index if index >= IOREDTBL => {
+                let pin = ((index - IOREDTBL) / 2) as usize;
+                let Some(entry) = self.redirection.get_mut(pin) else {
+                    return;
+                };
+                let written = match index % 2 {
+                    0 => (*entry & !0xffff_ffff) | value as u64,
+                    _ => (*entry & 0xffff_ffff) | (value as u64) << 32,
+                };
+                *entry = (*entry & !RTE_WRITABLE) | (written & RTE_WRITABLE);
+            }
+            // The version and arbitration registers are read-only.
+            _ => {}
+        }
+    }
+}
+
+impl Default for IoApic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MmioOps for IoApic {
+    fn mmio_range(&self) -> Range<u64> {
+        IOAPIC_BASE..IOAPIC_BASE + IOAPIC_SIZE
+    }
+
+    fn read(&mut self, addr: u64, _access_size: u8) -> HyperResult<u64> {
+        Ok(match addr - IOAPIC_BASE {
+            IOREGSEL => self.select as u64,
+            IOWIN => self.read_register(self.select) as u64,
+            _ => 0,
+        })
+    }
+
+    fn write(&mut self, addr: u64, _access_size: u8, value: u64) -> HyperResult {
+        match addr - IOAPIC_BASE {
+            IOREGSEL => self.select = value as u8,
+            IOWIN => self.write_register(self.select, value as u32),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl DeviceState for IoApic {
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(IOAPIC_STATE_VERSION);
+        state.u8(self.id).u8(self.select);
+        for &entry in &self.redirection {
+            state.u64(entry);
+        }
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let mut state = StateReader::new(state, IOAPIC_STATE_VERSION)?;
+        let mut restored = Self::new();
+        restored.id = state.u8()?;
+        restored.select = state.u8()?;
+        for entry in restored.redirection.iter_mut() {
+            *entry = state.u64()?;
+        }
+        state.finish()?;
+        if restored.id > 0xf {
+            return Err(HyperError::InvalidParam);
+        }
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_register(ioapic: &mut IoApic, index: u8, value: u32) {
+        ioapic
+            .write(IOAPIC_BASE + IOREGSEL, 4, index as u64)
+            .unwrap();
+        ioapic.write(IOAPIC_BASE + IOWIN, 4, value as u64).unwrap();
+    }
+
+    fn read_register(ioapic: &mut IoApic, index: u8) -> u32 {
+        ioapic
+            .write(IOAPIC_BASE + IOREGSEL, 4, index as u64)
+            .unwrap();
+        ioapic.read(IOAPIC_BASE + IOWIN, 4).unwrap() as u32
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut ioapic = IoApic::new();
+        write_register(&mut ioapic, IOAPICID, 3 << 24);
+        // Pin 4 to vector 0x24 of APIC 1, pin 9 to logical destination 0x3, lowest priority.
+        write_register(&mut ioapic, IOREDTBL + 8, 0x24);
+        write_register(&mut ioapic, IOREDTBL + 9, 1 << 24);
+        write_register(&mut ioapic, IOREDTBL + 18, 0x929);
+        write_register(&mut ioapic, IOREDTBL + 19, 3 << 24);
+        ioapic
+            .write(IOAPIC_BASE + IOREGSEL, 4, IOREDTBL as u64 + 18)
+            .unwrap();
+
+        let mut restored = IoApic::new();
+        restored.restore(&ioapic.save()).unwrap();
+        assert_eq!(restored.save(), ioapic.save());
+        // The selected register is kept.
+        assert_eq!(restored.read(IOAPIC_BASE + IOWIN, 4).unwrap(), 0x929);
+        for gsi in 0..IOAPIC_PINS as u8 {
+            assert_eq!(restored.route(gsi), ioapic.route(gsi));
+        }
+        assert_eq!(
+            restored.route(4),
+            Some(IoApicRoute {
+                vector: 0x24,
+                delivery_mode: DELIVERY_FIXED,
+                dest: 1,
+                logical: false,
+            })
+        );
+        assert_eq!(
+            restored.route(9),
+            Some(IoApicRoute {
+                vector: 0x29,
+                delivery_mode: DELIVERY_LOWEST_PRIORITY,
+                dest: 3,
+                logical: true,
+            })
+        );
+        assert_eq!(restored.route(0), None);
+        assert_eq!(read_register(&mut restored, IOAPICID), 3 << 24);
+    }
+
+    #[test]
+    fn test_rejected_states() {
+        let mut ioapic = IoApic::new();
+        write_register(&mut ioapic, IOREDTBL, 0x30);
+        let saved = ioapic.save();
+
+        // An id wider than the 4 bits of the register.
+        let mut bad = saved.clone();
+        bad[6] = 0x10;
+        assert!(matches!(
+            ioapic.restore(&bad),
+            Err(HyperError::InvalidParam)
+        ));
+        assert!(ioapic.restore(&saved[..saved.len() - 8]).is_err());
+        assert_eq!(ioapic.save(), saved);
+    }
+}
@@ -1,10 +1,32 @@
 mod apic_timer;
+#[cfg(feature = "legacy-pc-devices")]
 mod bundle;
+#[cfg(feature = "legacy-pc-devices")]
+mod cmos;
+#[cfg(feature = "legacy-pc-devices")]
 mod debug_port;
+#[cfg(any(feature = "legacy-pc-devices", feature = "vga"))]
 mod dummy;
+#[cfg(feature = "legacy-pc-devices")]
+mod hpet;
+#[cfg(feature = "legacy-pc-devices")]
+mod i8042;
+#[cfg(feature = "legacy-pc-devices")]
 mod i8259_pic;
+#[cfg(feature = "legacy-pc-devices")]
+mod ib700_wdt;
+#[cfg(feature = "legacy-pc-devices")]
+mod ioapic;
 // mod pcip;
+#[cfg(feature = "legacy-pc-devices")]
 mod pit;
+mod pmu;
+#[cfg(feature = "legacy-pc-devices")]
+mod reset_control;
+#[cfg(feature = "virtio-pci")]
+mod pci_config_pio;
+#[cfg(feature = "virtio-pci")]
+mod pci_ecam_mmio;
 mod port_passthrough;
 mod uart16550;
 mod pci_dummy;
@@ -13,20 +35,50 @@ extern crate alloc;
 
 use crate::Result as HyperResult;
 
-pub use apic_timer::{ApicBaseMsrHandler, VirtLocalApic, ProxyLocalApic};
+pub use apic_timer::{
+    ApicBaseMsrHandler, ApicTimerStats, PendingVectors, ProxyLocalApic, TimerDeadline,
+    VirtLocalApic, DEFAULT_APIC_BUS_FREQ_HZ,
+};
+#[cfg(feature = "legacy-pc-devices")]
 pub use bundle::Bundle;
-pub use debug_port::DebugPort;
+#[cfg(feature = "legacy-pc-devices")]
+pub use debug_port::{DebugExit, DebugPort};
+#[cfg(any(feature = "legacy-pc-devices", feature = "vga"))]
 pub use dummy::Dummy;
 use hypercraft::VirtMsrOps;
+#[cfg(feature = "legacy-pc-devices")]
+pub use hpet::{Hpet, HPET_BASE, HPET_BLOCK_ID, HPET_FREQ_HZ, HPET_TIMERS};
+#[cfg(feature = "legacy-pc-devices")]
+pub use i8042::I8042;
+#[cfg(feature = "legacy-pc-devices")]
 pub use i8259_pic::I8259Pic;
+#[cfg(feature = "legacy-pc-devices")]
+pub use ib700_wdt::Ib700Watchdog;
+#[cfg(feature = "legacy-pc-devices")]
+pub use ioapic::{
+    IoApic, IoApicRoute, DELIVERY_EXT_INT, DELIVERY_FIXED, DELIVERY_LOWEST_PRIORITY, IOAPIC_BASE,
+};
+#[cfg(feature = "virtio-pci")]
+pub use pci_config_pio::PciConfigPio;
+#[cfg(feature = "legacy-pc-devices")]
+pub use reset_control::ResetControl;
+#[cfg(feature = "virtio-pci")]
+pub use pci_ecam_mmio::PciEcamMmio;
+pub use pmu::{
+    enable_rdpmc_exiting, VirtPmu, IA32_FIXED_CTR0, IA32_FIXED_CTR_CTRL, IA32_PERFEVTSEL0,
+    IA32_PERF_CAPABILITIES, IA32_PERF_GLOBAL_CTRL, IA32_PERF_GLOBAL_OVF_CTRL,
+    IA32_PERF_GLOBAL_STATUS, PMU_COUNTER_WIDTH, PMU_FIXED_COUNTERS, PMU_GLOBAL_CTRL_VALID,
+    PMU_GP_COUNTERS,
+};
 pub use port_passthrough::PortPassthrough;
 pub use uart16550::{MultiplexConsoleBackend, Uart16550};
 pub use pci_dummy::PCIConfigurationSpace;
 
+#[cfg(feature = "legacy-pc-devices")]
 macro_rules! pmio_proxy_struct {
     ($port_begin:expr, $port_end:expr, $name:ident, $parent:ident, $reader:ident, $writer:ident) => {
         pub struct $name {
-            parent: alloc::sync::Arc<spin::Mutex<$parent>>,
+            parent: alloc::sync::Arc<lock_stat::Mutex<$parent>>,
         }
 
         impl $crate::device::PioOps for $name {
@@ -45,9 +97,10 @@ macro_rules! pmio_proxy_struct {
     };
 }
 
+#[cfg(feature = "legacy-pc-devices")]
 macro_rules! pmio_proxy_factory {
     ($fn:ident, $type:ident) => {
-        pub fn $fn(some: &alloc::sync::Arc<spin::Mutex<Self>>) -> $type {
+        pub fn $fn(some: &alloc::sync::Arc<lock_stat::Mutex<Self>>) -> $type {
             $type {
                 parent: some.clone(),
             }
@@ -58,7 +111,7 @@ macro_rules! pmio_proxy_factory {
 macro_rules! msr_proxy_struct {
     ($msr_begin:expr, $msr_end:expr, $name:ident, $parent:ident, $reader:ident, $writer:ident) => {
         pub struct $name {
-            parent: alloc::sync::Arc<spin::Mutex<$parent>>,
+            parent: alloc::sync::Arc<lock_stat::Mutex<$parent>>,
         }
 
         impl $crate::device::VirtMsrOps for $name {
@@ -79,7 +132,7 @@ macro_rules! msr_proxy_struct {
 
 macro_rules! msr_proxy_factory {
     ($fn:ident, $type:ident) => {
-        pub fn $fn(some: &alloc::sync::Arc<spin::Mutex<Self>>) -> $type {
+        pub fn $fn(some: &alloc::sync::Arc<lock_stat::Mutex<Self>>) -> $type {
             $type {
                 parent: some.clone(),
             }
@@ -89,7 +142,9 @@ macro_rules! msr_proxy_factory {
 
 pub(crate) use msr_proxy_factory;
 pub(crate) use msr_proxy_struct;
+#[cfg(feature = "legacy-pc-devices")]
 pub(crate) use pmio_proxy_factory;
+#[cfg(feature = "legacy-pc-devices")]
 pub(crate) use pmio_proxy_struct;
 
 pub struct MsrDummy {
@@ -0,0 +1,255 @@
+//! PCI configuration mechanism #1: CONFIG_ADDRESS at port 0xcf8 selects a register of a device,
+//! CONFIG_DATA at ports 0xcfc to 0xcff accesses it (PCI Local Bus Specification 3.0, Section
+//! 3.2.2.3.2).
+//!
+//! Guests without ECAM, nimbos or older kernels, enumerate the virtio devices of the [`PciHost`]
+//! of their VM through it.
+
+use alloc::sync::Arc;
+use core::ops::Range;
+
+use hypercraft::PioOps;
+use lock_stat::Mutex;
+use pci::{BarAllocTrait, PciHost};
+
+use crate::Result as HyperResult;
+
+const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+/// The enable bit, the bus, device and function numbers and the dword of the register. Bits 24
+/// to 30 are reserved and bits 0 and 1 read as 0.
+const CONFIG_ADDRESS_MASK: u32 = CONFIG_ADDRESS_ENABLE | 0x00ff_fffc;
+
+/// The configuration ports of a VM, in front of its PCI host.
+pub struct PciConfigPio<B: BarAllocTrait> {
+    host: Arc<Mutex<PciHost<B>>>,
+}
+
+impl<B: BarAllocTrait> PciConfigPio<B> {
+    pub fn new(host: Arc<Mutex<PciHost<B>>>) -> Self {
+        Self { host }
+    }
+
+    /// The bus, the device and function, and the offset in its configuration space of an access
+    /// of `access_size` bytes to the data port `port`, with `config_addr` in CONFIG_ADDRESS.
+    /// `None` when the enable bit is clear, or the access crosses the end of the dword.
+    fn target(config_addr: u32, port: u16, access_size: u8) -> Option<(u8, u8, usize)> {
+        let byte = (port - CONFIG_DATA_PORT) as usize;
+        if config_addr & CONFIG_ADDRESS_ENABLE == 0 || byte + access_size as usize > 4 {
+            return None;
+        }
+        let bus = (config_addr >> 16) as u8;
+        let devfn = (config_addr >> 8) as u8;
+        Some((bus, devfn, (config_addr & 0xfc) as usize + byte))
+    }
+}
+
+/// Only a dword access to 0xcf8 reaches CONFIG_ADDRESS, the others are not decoded: Linux probes
+/// configuration mechanism #1 with an `outb` to 0xcfb, which must not change it.
+fn is_config_address(port: u16, access_size: u8) -> bool {
+    port == CONFIG_ADDRESS_PORT && access_size == 4
+}
+
+impl<B: BarAllocTrait> PioOps for PciConfigPio<B> {
+    fn port_range(&self) -> Range<u16> {
+        CONFIG_ADDRESS_PORT..CONFIG_DATA_PORT + 4
+    }
+
+    fn read(&mut self, port: u16, access_size: u8) -> HyperResult<u32> {
+        let host = self.host.lock();
+        if port < CONFIG_DATA_PORT {
+            return Ok(if is_config_address(port, access_size) {
+                host.config_addr()
+            } else {
+                u32::MAX
+            });
+        }
+        // A missing device, or a disabled or bad access, reads as all ones.
+        let mut data = [0xff; 4];
+        if let Some((bus, devfn, offset)) = Self::target(host.config_addr(), port, access_size) {
+            if let Some(dev) = host.find_device(bus, devfn) {
+                dev.lock()
+                    .read_config(offset, &mut data[..access_size as usize]);
+            }
+        }
+        Ok(u32::from_le_bytes(data) & (u32::MAX >> (32 - 8 * access_size as u32)))
+    }
+
+    fn write(&mut self, port: u16, access_size: u8, value: u32) -> HyperResult {
+        let mut host = self.host.lock();
+        if port < CONFIG_DATA_PORT {
+            if is_config_address(port, access_size) {
+                host.set_config_addr(value & CONFIG_ADDRESS_MASK);
+            }
+            return Ok(());
+        }
+        if let Some((bus, devfn, offset)) = Self::target(host.config_addr(), port, access_size) {
+            if let Some(dev) = host.find_device(bus, devfn) {
+                dev.lock()
+                    .write_config(offset, &value.to_le_bytes()[..access_size as usize]);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+    use alloc::sync::Weak;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::any::Any;
+
+    use hypercraft::HyperError;
+    use pci::config::{RegionType, PCI_CONFIG_SPACE_SIZE};
+    use pci::{AsAny, PciConfig, PciDevBase, PciDevOps};
+
+    #[derive(Clone)]
+    struct TestBarAlloc;
+
+    impl BarAllocTrait for TestBarAlloc {
+        fn alloc(_region_type: RegionType, _size: u64) -> HyperResult<u64> {
+            Err(HyperError::NotSupported)
+        }
+
+        fn dealloc(_region_type: RegionType, _addr: u64, _size: u64) -> HyperResult<()> {
+            Ok(())
+        }
+    }
+
+    /// A function whose configuration space counts up from 0, recording the writes.
+    struct TestDevice {
+        base: PciDevBase<TestBarAlloc>,
+        writes: Vec<(usize, Vec<u8>)>,
+    }
+
+    impl AsAny for TestDevice {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    impl PciDevOps<TestBarAlloc> for TestDevice {
+        fn name(&self) -> String {
+            self.base.id.clone()
+        }
+
+        fn pci_base(&self) -> &PciDevBase<TestBarAlloc> {
+            &self.base
+        }
+
+        fn pci_base_mut(&mut self) -> &mut PciDevBase<TestBarAlloc> {
+            &mut self.base
+        }
+
+        fn realize(self) -> HyperResult<()> {
+            Ok(())
+        }
+
+        fn write_config(&mut self, offset: usize, data: &[u8]) {
+            self.writes.push((offset, data.to_vec()));
+        }
+    }
+
+    type Ports = PciConfigPio<TestBarAlloc>;
+
+    const DEVFN: u8 = 0x08;
+
+    /// The configuration ports of a host with a `TestDevice` at `DEVFN` of bus 0.
+    fn ports() -> (Ports, Arc<Mutex<TestDevice>>) {
+        let host = PciHost::<TestBarAlloc>::new(None);
+        let mut config = PciConfig::new(PCI_CONFIG_SPACE_SIZE, 6);
+        for (i, byte) in config.config.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let device = Arc::new(Mutex::new(TestDevice {
+            base: PciDevBase {
+                id: String::from("test"),
+                config,
+                devfn: DEVFN,
+                parent_bus: Weak::new(),
+            },
+            writes: Vec::new(),
+        }));
+        host.root_bus.lock().devices.insert(DEVFN, device.clone());
+        (Ports::new(Arc::new(Mutex::new(host))), device)
+    }
+
+    fn address(bus: u8, devfn: u8, reg: u8) -> u32 {
+        CONFIG_ADDRESS_ENABLE | (bus as u32) << 16 | (devfn as u32) << 8 | reg as u32
+    }
+
+    #[test]
+    fn test_target() {
+        let addr = address(1, 0x0a, 0x10);
+        assert_eq!(Ports::target(addr, 0xcfc, 4), Some((1, 0x0a, 0x10)));
+        // The bytes of the data port select the bytes of the dword.
+        assert_eq!(Ports::target(addr, 0xcfd, 1), Some((1, 0x0a, 0x11)));
+        assert_eq!(Ports::target(addr, 0xcfe, 2), Some((1, 0x0a, 0x12)));
+        assert_eq!(Ports::target(addr, 0xcff, 1), Some((1, 0x0a, 0x13)));
+        // Bits 0 and 1 of CONFIG_ADDRESS do not select a byte.
+        assert_eq!(Ports::target(addr | 0x3, 0xcfc, 4), Some((1, 0x0a, 0x10)));
+
+        assert_eq!(Ports::target(addr & !CONFIG_ADDRESS_ENABLE, 0xcfc, 4), None);
+        // Past the end of the dword.
+        assert_eq!(Ports::target(addr, 0xcfe, 4), None);
+        assert_eq!(Ports::target(addr, 0xcff, 2), None);
+    }
+
+    #[test]
+    fn test_config_address() {
+        let (mut ports, _) = ports();
+        ports.write(0xcf8, 4, 0xffff_ffff).unwrap();
+        assert_eq!(ports.read(0xcf8, 4).unwrap(), CONFIG_ADDRESS_MASK);
+        // The probe of Linux does not change it, and only a dword reads it back.
+        ports.write(0xcfb, 1, 0x01).unwrap();
+        ports.write(0xcf8, 2, 0).unwrap();
+        assert_eq!(ports.read(0xcf8, 4).unwrap(), CONFIG_ADDRESS_MASK);
+        assert_eq!(ports.read(0xcf8, 2).unwrap(), u32::MAX);
+    }
+
+    #[test]
+    fn test_data() {
+        let (mut ports, device) = ports();
+        ports.write(0xcf8, 4, address(0, DEVFN, 0x04)).unwrap();
+        assert_eq!(ports.read(0xcfc, 4).unwrap(), 0x0706_0504);
+        assert_eq!(ports.read(0xcfe, 2).unwrap(), 0x0706);
+        assert_eq!(ports.read(0xcfd, 1).unwrap(), 0x05);
+        ports.write(0xcff, 1, 0xab).unwrap();
+        ports.write(0xcfc, 2, 0x1234).unwrap();
+        assert_eq!(
+            device.lock().writes,
+            [(0x07, vec![0xab]), (0x04, vec![0x34, 0x12])]
+        );
+
+        // Crossing the end of the dword, or with the enable bit clear, nothing is accessed.
+        assert_eq!(ports.read(0xcfe, 4).unwrap(), u32::MAX);
+        ports
+            .write(0xcf8, 4, address(0, DEVFN, 0x04) & !CONFIG_ADDRESS_ENABLE)
+            .unwrap();
+        assert_eq!(ports.read(0xcfc, 4).unwrap(), u32::MAX);
+        ports.write(0xcfc, 4, 0).unwrap();
+        assert_eq!(device.lock().writes.len(), 2);
+    }
+
+    #[test]
+    fn test_missing_device() {
+        let (mut ports, device) = ports();
+        for addr in [address(0, DEVFN + 1, 0), address(1, DEVFN, 0)] {
+            ports.write(0xcf8, 4, addr).unwrap();
+            assert_eq!(ports.read(0xcfc, 4).unwrap(), 0xffff_ffff);
+            assert_eq!(ports.read(0xcfe, 2).unwrap(), 0xffff);
+            assert_eq!(ports.read(0xcff, 1).unwrap(), 0xff);
+            ports.write(0xcfc, 4, 0).unwrap();
+        }
+        assert!(device.lock().writes.is_empty());
+    }
+}
@@ -31,20 +31,11 @@ impl PioOps for PCIConfigurationSpace {
             _offset @ CONFIGURATION_SPACE_ADDRESS_PORT_OFFSET
                 ..=CONFIGURATION_SPACE_ADDRESS_PORT_LAST_OFFSET => {
                 // we return non-sense to tell linux pci is not present.
-                match access_size {
-                    1 => Ok(0xfe),
-                    2 => Ok(0xfffe),
-                    4 => Ok(0xffff_fffe),
-                    _ => Err(HyperError::InvalidParam),
-                }
+                // The dispatcher truncates it to the access size.
+                Ok(0xffff_fffe)
             }
             CONFIGURATION_SPACE_DATA_PORT_OFFSET..=CONFIGURATION_SPACE_DATA_PORT_LAST_OFFSET => {
-                match access_size {
-                    1 => Ok(0xff),
-                    2 => Ok(0xffff),
-                    4 => Ok(0xffff_ffff),
-                    _ => Err(HyperError::InvalidParam),
-                }
+                Ok(0xffff_ffff)
             }
             _ => Err(HyperError::InvalidParam),
         }
@@ -53,21 +44,12 @@ impl PioOps for PCIConfigurationSpace {
     fn write(&mut self, port: u16, access_size: u8, value: u32) -> HyperResult {
         match (port - self.port_base) as usize {
             offset @ CONFIGURATION_SPACE_ADDRESS_PORT_OFFSET
-                ..=CONFIGURATION_SPACE_ADDRESS_PORT_LAST_OFFSET => match access_size {
-                1 => Ok({
-                    self.current_address
-                        .set_bits(offset * 8..offset * 8 + 8, value as u8 as u64);
-                }),
-                2 => Ok({
-                    self.current_address
-                        .set_bits(offset * 8..offset * 8 + 16, value as u16 as u64);
-                }),
-                4 => Ok({
-                    self.current_address
-                        .set_bits(offset * 8..offset * 8 + 32, value as u64);
-                }),
-                _ => Err(HyperError::InvalidParam),
-            },
+                ..=CONFIGURATION_SPACE_ADDRESS_PORT_LAST_OFFSET => {
+                // The dispatcher keeps the access within the address port.
+                let bits = offset * 8..(offset + access_size as usize) * 8;
+                self.current_address.set_bits(bits, value as u64);
+                Ok(())
+            }
             _ => Err(HyperError::NotSupported),
         }
     }
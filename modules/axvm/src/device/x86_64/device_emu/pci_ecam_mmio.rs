@@ -0,0 +1,239 @@
+//! The ECAM (MMCONFIG) window of the PCI host of a VM (PCI Express Base Specification 4.0,
+//! Section 7.2.2): the configuration space of each function is a 4 KiB page of the window, which
+//! modern Linux prefers to the configuration ports.
+//!
+//! The window is described by the [`PciEcamCfg`] of the VM. No MCFG table advertises it, the
+//! guest learns it from its own configuration.
+//!
+//! [`PciEcamCfg`]: crate::config::entry::PciEcamCfg
+
+use alloc::sync::Arc;
+use core::ops::Range;
+
+use hypercraft::MmioOps;
+use lock_stat::Mutex;
+use pci::{BarAllocTrait, PciHost};
+
+use crate::config::entry::PciEcamCfg;
+use crate::Result as HyperResult;
+
+/// The configuration space of a function in the window.
+const FUNCTION_CONFIG_SIZE: u64 = 0x1000;
+
+/// The ECAM window of a VM, in front of its PCI host.
+pub struct PciEcamMmio<B: BarAllocTrait> {
+    host: Arc<Mutex<PciHost<B>>>,
+    range: Range<u64>,
+}
+
+impl<B: BarAllocTrait> PciEcamMmio<B> {
+    pub fn new(host: Arc<Mutex<PciHost<B>>>, ecam: PciEcamCfg) -> Self {
+        let range = ecam.range();
+        Self {
+            host,
+            range: range.start as u64..range.end as u64,
+        }
+    }
+
+    /// The bus, the device and function, and the offset in its configuration space of an access
+    /// of `access_size` bytes at `addr`. `None` if the access is larger than a dword, which the
+    /// configuration space does not support, or crosses into the next function.
+    fn target(&self, addr: u64, access_size: u8) -> Option<(u8, u8, usize)> {
+        let offset = addr - self.range.start;
+        let reg = offset % FUNCTION_CONFIG_SIZE;
+        if access_size > 4 || reg + access_size as u64 > FUNCTION_CONFIG_SIZE {
+            return None;
+        }
+        Some(((offset >> 20) as u8, (offset >> 12) as u8, reg as usize))
+    }
+}
+
+impl<B: BarAllocTrait> MmioOps for PciEcamMmio<B> {
+    fn mmio_range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+
+    fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+        // A missing device, or a bad access, reads as all ones.
+        let mut data = [0xff; 8];
+        if let Some((bus, devfn, offset)) = self.target(addr, access_size) {
+            if let Some(dev) = self.host.lock().find_device(bus, devfn) {
+                dev.lock()
+                    .read_config(offset, &mut data[..access_size as usize]);
+            }
+        }
+        Ok(u64::from_le_bytes(data) & (u64::MAX >> (64 - 8 * access_size as u32)))
+    }
+
+    fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+        if let Some((bus, devfn, offset)) = self.target(addr, access_size) {
+            if let Some(dev) = self.host.lock().find_device(bus, devfn) {
+                dev.lock()
+                    .write_config(offset, &value.to_le_bytes()[..access_size as usize]);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+    use alloc::sync::Weak;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::any::Any;
+
+    use hypercraft::HyperError;
+    use pci::config::{RegionType, PCI_CONFIG_SPACE_SIZE};
+    use pci::{AsAny, PciConfig, PciDevBase, PciDevOps};
+
+    #[derive(Clone)]
+    struct TestBarAlloc;
+
+    impl BarAllocTrait for TestBarAlloc {
+        fn alloc(_region_type: RegionType, _size: u64) -> HyperResult<u64> {
+            Err(HyperError::NotSupported)
+        }
+
+        fn dealloc(_region_type: RegionType, _addr: u64, _size: u64) -> HyperResult<()> {
+            Ok(())
+        }
+    }
+
+    /// A function whose configuration space counts up from 0, recording the writes.
+    struct TestDevice {
+        base: PciDevBase<TestBarAlloc>,
+        writes: Vec<(usize, Vec<u8>)>,
+    }
+
+    impl AsAny for TestDevice {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    impl PciDevOps<TestBarAlloc> for TestDevice {
+        fn name(&self) -> String {
+            self.base.id.clone()
+        }
+
+        fn pci_base(&self) -> &PciDevBase<TestBarAlloc> {
+            &self.base
+        }
+
+        fn pci_base_mut(&mut self) -> &mut PciDevBase<TestBarAlloc> {
+            &mut self.base
+        }
+
+        fn realize(self) -> HyperResult<()> {
+            Ok(())
+        }
+
+        fn write_config(&mut self, offset: usize, data: &[u8]) {
+            self.writes.push((offset, data.to_vec()));
+        }
+    }
+
+    type Ecam = PciEcamMmio<TestBarAlloc>;
+
+    const BASE: u64 = 0xb000_0000;
+    const DEVFN: u8 = 0x08;
+
+    /// The window of two buses of a host with a `TestDevice` at `DEVFN` of bus 0.
+    fn ecam() -> (Ecam, Arc<Mutex<TestDevice>>) {
+        let host = PciHost::<TestBarAlloc>::new(None);
+        let mut config = PciConfig::new(PCI_CONFIG_SPACE_SIZE, 6);
+        for (i, byte) in config.config.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let device = Arc::new(Mutex::new(TestDevice {
+            base: PciDevBase {
+                id: String::from("test"),
+                config,
+                devfn: DEVFN,
+                parent_bus: Weak::new(),
+            },
+            writes: Vec::new(),
+        }));
+        host.root_bus.lock().devices.insert(DEVFN, device.clone());
+        let ecam = Ecam::new(
+            Arc::new(Mutex::new(host)),
+            PciEcamCfg::new(BASE as usize, 2),
+        );
+        (ecam, device)
+    }
+
+    /// The address of register `reg` of function `devfn` of bus `bus`.
+    fn address(bus: u8, devfn: u8, reg: u64) -> u64 {
+        BASE + ((bus as u64) << 20 | (devfn as u64) << 12 | reg)
+    }
+
+    #[test]
+    fn test_target() {
+        let (ecam, _) = ecam();
+        assert_eq!(ecam.mmio_range(), BASE..BASE + (2 << 20));
+        // Bus 1, device 3, function 2.
+        let devfn = 3 << 3 | 2;
+        assert_eq!(
+            ecam.target(address(1, devfn, 0x10), 4),
+            Some((1, devfn, 0x10))
+        );
+        assert_eq!(
+            ecam.target(address(1, devfn, 0x12), 2),
+            Some((1, devfn, 0x12))
+        );
+        assert_eq!(
+            ecam.target(address(1, devfn, 0x13), 1),
+            Some((1, devfn, 0x13))
+        );
+        // The extended configuration space, up to its last dword.
+        assert_eq!(
+            ecam.target(address(0, devfn, 0xffc), 4),
+            Some((0, devfn, 0xffc))
+        );
+
+        // Larger than a dword, or into the next function.
+        assert_eq!(ecam.target(address(1, devfn, 0x10), 8), None);
+        assert_eq!(ecam.target(address(1, devfn, 0xffe), 4), None);
+        assert_eq!(ecam.target(address(1, devfn, 0xfff), 2), None);
+    }
+
+    #[test]
+    fn test_access() {
+        let (mut ecam, device) = ecam();
+        assert_eq!(ecam.read(address(0, DEVFN, 0x04), 4).unwrap(), 0x0706_0504);
+        assert_eq!(ecam.read(address(0, DEVFN, 0x06), 2).unwrap(), 0x0706);
+        assert_eq!(ecam.read(address(0, DEVFN, 0x05), 1).unwrap(), 0x05);
+        ecam.write(address(0, DEVFN, 0x07), 1, 0xab).unwrap();
+        ecam.write(address(0, DEVFN, 0x04), 2, 0x1234).unwrap();
+        assert_eq!(
+            device.lock().writes,
+            [(0x07, vec![0xab]), (0x04, vec![0x34, 0x12])]
+        );
+
+        // A qword, or an access crossing into the next function, reaches no function.
+        assert_eq!(ecam.read(address(0, DEVFN, 0), 8).unwrap(), u64::MAX);
+        assert_eq!(ecam.read(address(0, DEVFN, 0xffe), 4).unwrap(), 0xffff_ffff);
+        ecam.write(address(0, DEVFN, 0), 8, 0).unwrap();
+        ecam.write(address(0, DEVFN, 0xffe), 4, 0).unwrap();
+        assert_eq!(device.lock().writes.len(), 2);
+    }
+
+    #[test]
+    fn test_missing_device() {
+        let (mut ecam, device) = ecam();
+        for addr in [address(0, DEVFN + 1, 0), address(1, DEVFN, 0)] {
+            assert_eq!(ecam.read(addr, 4).unwrap(), 0xffff_ffff);
+            assert_eq!(ecam.read(addr + 2, 2).unwrap(), 0xffff);
+            assert_eq!(ecam.read(addr + 3, 1).unwrap(), 0xff);
+            ecam.write(addr, 4, 0).unwrap();
+        }
+        assert!(device.lock().writes.is_empty());
+    }
+}
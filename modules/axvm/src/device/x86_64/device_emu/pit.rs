@@ -1,11 +1,39 @@
+//! Emulated Intel 8253/8254 Programmable Interval Timer.
+//! (ref: https://wiki.osdev.org/Programmable_Interval_Timer)
+//!
+//! The counters are not ticked: a counting channel remembers when its count was loaded, and its
+//! counter and output are computed from the ticks of the 1.193182 MHz clock elapsed since. The
+//! rising edges of the output of channel 0 are IRQ 0, whose next time is published in a
+//! [`TimerDeadline`] for `check_events`, which takes them with [`PIT::pending_irq`].
+//!
+//! The gates of channels 0 and 1 are tied high, the gate of channel 2 is bit 0 of the system
+//! control port B. BCD counting is not supported.
+
 use crate::{Error as HyperError, Result as HyperResult};
+use alloc::sync::Arc;
 use axhal::time::current_time_nanos;
 use bit_field::BitField;
 
+use super::TimerDeadline;
+use crate::device::{StateReader, StateWriter};
+
 pub const PIT_FREQ: u32 = 1_193182;
 pub const PIT_CHANNEL_COUNT: usize = 3;
 pub const NANOS_PER_SEC: u64 = 1_000_000_000;
 
+/// The channel whose output is IRQ 0.
+const IRQ_CHANNEL: usize = 0;
+
+/// Ticks of the PIT clock in `ns` nanoseconds, rounded down.
+fn nanos_to_ticks(ns: u64) -> u64 {
+    ((ns as u128 * PIT_FREQ as u128) / NANOS_PER_SEC as u128) as u64
+}
+
+/// Nanoseconds until `ticks` ticks of the PIT clock elapsed, rounded up.
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    ((ticks as u128 * NANOS_PER_SEC as u128).div_ceil(PIT_FREQ as u128)) as u64
+}
+
 enum PITChannelAccessMode {
     LowOnly,
     HighOnly,
@@ -18,6 +46,7 @@ impl TryFrom<u8> for PITChannelAccessMode {
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
+            // The counter latch command.
             0 => Err(HyperError::NotSupported),
             1 => Ok(Self::LowOnly),
             2 => Ok(Self::HighOnly),
@@ -27,8 +56,38 @@ impl TryFrom<u8> for PITChannelAccessMode {
     }
 }
 
+impl PITChannelAccessMode {
+    fn to_state(&self) -> u8 {
+        match self {
+            Self::Invalid => 0,
+            Self::LowOnly => 1,
+            Self::HighOnly => 2,
+            Self::LowThenHigh => 3,
+        }
+    }
+
+    fn from_state(value: u8) -> HyperResult<Self> {
+        match value {
+            0 => Ok(Self::Invalid),
+            value => value.try_into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum PITChannelOpMode {
+    /// Mode 0: the output rises at the terminal count.
+    InterruptOnTerminalCount,
+    /// Mode 1: as mode 0, triggered by a rising edge of the gate.
     OneShot,
+    /// Mode 2: the output is low for one tick at the end of each period.
+    RateGenerator,
+    /// Mode 3: the output is high for the first half of each period.
+    SquareWave,
+    /// Mode 4: the output is low for one tick at the terminal count.
+    SoftwareStrobe,
+    /// Mode 5: as mode 4, triggered by a rising edge of the gate.
+    HardwareStrobe,
     Invalid,
 }
 
@@ -37,29 +96,93 @@ impl TryFrom<u8> for PITChannelOpMode {
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => Ok(Self::OneShot),
-            _ => Err(HyperError::NotSupported),
+            0 => Ok(Self::InterruptOnTerminalCount),
+            1 => Ok(Self::OneShot),
+            // Modes 6 and 7 are aliases of modes 2 and 3.
+            2 | 6 => Ok(Self::RateGenerator),
+            3 | 7 => Ok(Self::SquareWave),
+            4 => Ok(Self::SoftwareStrobe),
+            5 => Ok(Self::HardwareStrobe),
+            _ => Err(HyperError::InvalidParam),
         }
     }
 }
 
+impl PITChannelOpMode {
+    fn to_state(&self) -> u8 {
+        match self {
+            Self::InterruptOnTerminalCount => 0,
+            Self::OneShot => 1,
+            Self::RateGenerator => 2,
+            Self::SquareWave => 3,
+            Self::SoftwareStrobe => 4,
+            Self::HardwareStrobe => 5,
+            Self::Invalid => 0xff,
+        }
+    }
+
+    fn from_state(value: u8) -> HyperResult<Self> {
+        match value {
+            0xff => Ok(Self::Invalid),
+            0..=5 => value.try_into(),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    /// Whether the counter reloads itself at the end of each period.
+    fn is_periodic(&self) -> bool {
+        matches!(self, Self::RateGenerator | Self::SquareWave)
+    }
+
+    /// Whether counting waits for a rising edge of the gate.
+    fn is_gate_triggered(&self) -> bool {
+        matches!(self, Self::OneShot | Self::HardwareStrobe)
+    }
+}
+
 struct PITChannel {
+    /// Level of the gate input, counting stops or waits while it is low.
+    gate: bool,
+    /// The count written, 0 standing for 0x10000.
     reload: u32, // 16-bit is enough for counter and reload but ...
-    reload_low_written: bool,
-    start_nanos: u64,
+    /// The low byte of a count written low then high, waiting for the high byte.
+    reload_low: Option<u8>,
+    /// A count was written since the last command.
+    loaded: bool,
+    /// Counting from `start_nanos`.
     started: bool,
+    start_nanos: u64,
+    /// Ticks counted when the gate of a mode 0 or 4 counter went low, which paused it.
+    paused_ticks: Option<u64>,
+    /// A count written to a running mode 2 or 3 counter, loaded at the end of the current period
+    /// at the given time.
+    next_reload: Option<(u32, u64)>,
+    /// Rising edges of the output since `start_nanos` already reported by `pending_irq`.
+    edges_raised: u64,
+    /// The output rose at the last reload of `next_reload`, not yet reported.
+    edge_pending: bool,
+    latched_count: Option<u16>,
+    latched_status: Option<u8>,
     access_mode: PITChannelAccessMode,
     op_mode: PITChannelOpMode,
     low_read: bool,
 }
 
 impl PITChannel {
-    fn new() -> Self {
+    fn new(gate: bool) -> Self {
         Self {
+            gate,
             reload: 0,
-            reload_low_written: false,
-            start_nanos: 0,
+            reload_low: None,
+            loaded: false,
             started: false,
+            start_nanos: 0,
+            paused_ticks: None,
+            next_reload: None,
+            edges_raised: 0,
+            edge_pending: false,
+            latched_count: None,
+            latched_status: None,
             access_mode: PITChannelAccessMode::Invalid,
             low_read: false,
             op_mode: PITChannelOpMode::Invalid,
@@ -74,128 +197,428 @@ impl PITChannel {
             return Err(HyperError::NotSupported);
         }
 
-        match op_mode {
-            PITChannelOpMode::OneShot => {
-                self.access_mode = access_mode;
-                self.op_mode = op_mode;
+        // The counter stops until a new count is written.
+        *self = Self {
+            access_mode,
+            op_mode,
+            ..Self::new(self.gate)
+        };
+        Ok(())
+    }
 
-                self.reload_low_written = false;
-                self.started = false;
+    /// Ticks of the current count, frozen while paused. `None` if not counting.
+    fn ticks(&self, now_ns: u64) -> Option<u64> {
+        self.started.then(|| {
+            self.paused_ticks
+                .unwrap_or_else(|| nanos_to_ticks(now_ns.saturating_sub(self.start_nanos)))
+        })
+    }
 
-                Ok(())
+    /// Length of a count in ticks.
+    fn period(&self) -> u64 {
+        let period = match self.reload {
+            0 => 0x1_0000,
+            reload => reload as u64,
+        };
+        // A count of 1 is illegal in modes 2 and 3.
+        if self.op_mode.is_periodic() {
+            period.max(2)
+        } else {
+            period
+        }
+    }
+
+    /// Start counting the count written, from `now_ns`.
+    fn restart(&mut self, now_ns: u64) {
+        self.started = true;
+        self.start_nanos = now_ns;
+        self.edges_raised = 0;
+        self.next_reload = None;
+        // A mode 0 or 4 count loaded while the gate is low waits for it.
+        self.paused_ticks = (!self.gate && !self.op_mode.is_periodic()).then_some(0);
+    }
+
+    /// Load the count of a running mode 2 or 3 counter written during its last period.
+    fn advance(&mut self, now_ns: u64) {
+        let Some((reload, reload_ns)) = self.next_reload else {
+            return;
+        };
+        if now_ns < reload_ns {
+            return;
+        }
+        // The last period ended with a rising edge.
+        let edges = self.edges(nanos_to_ticks(reload_ns - self.start_nanos));
+        self.edge_pending |= edges > self.edges_raised;
+        self.reload = reload;
+        self.restart(reload_ns);
+    }
+
+    fn load_count(&mut self, count: u32, now_ns: u64) {
+        self.advance(now_ns);
+        self.loaded = true;
+        match self.op_mode {
+            PITChannelOpMode::RateGenerator | PITChannelOpMode::SquareWave if self.started => {
+                // The new count is loaded at the end of the current period.
+                let ticks = self.ticks(now_ns).unwrap_or(0);
+                let period = self.period();
+                let reload_ns = self.start_nanos + ticks_to_nanos((ticks / period + 1) * period);
+                self.next_reload = Some((count, reload_ns));
+            }
+            PITChannelOpMode::OneShot | PITChannelOpMode::HardwareStrobe => {
+                // Loaded on the next trigger.
+                self.reload = count;
+            }
+            PITChannelOpMode::RateGenerator | PITChannelOpMode::SquareWave => {
+                self.reload = count;
+                if self.gate {
+                    self.restart(now_ns);
+                }
+            }
+            _ => {
+                self.reload = count;
+                self.restart(now_ns);
+            }
+        }
+    }
+
+    fn counter(&self, now_ns: u64) -> u16 {
+        let Some(ticks) = self.ticks(now_ns) else {
+            return self.reload as u16;
+        };
+        let period = self.period();
+        let count = match self.op_mode {
+            // Counts down past the terminal count, wrapping around.
+            PITChannelOpMode::InterruptOnTerminalCount
+            | PITChannelOpMode::OneShot
+            | PITChannelOpMode::SoftwareStrobe
+            | PITChannelOpMode::HardwareStrobe => period.wrapping_sub(ticks),
+            PITChannelOpMode::RateGenerator => period - ticks % period,
+            // Decremented by 2, twice each period.
+            PITChannelOpMode::SquareWave => period - (2 * (ticks % period)) % period,
+            PITChannelOpMode::Invalid => 0,
+        };
+        (count & 0xffff) as u16
+    }
+
+    /// Rising edges of the output once `ticks` elapsed from the start of the count.
+    fn edges(&self, ticks: u64) -> u64 {
+        let period = self.period();
+        match self.op_mode {
+            PITChannelOpMode::InterruptOnTerminalCount | PITChannelOpMode::OneShot => {
+                (ticks >= period) as u64
+            }
+            PITChannelOpMode::RateGenerator | PITChannelOpMode::SquareWave => ticks / period,
+            PITChannelOpMode::SoftwareStrobe | PITChannelOpMode::HardwareStrobe => {
+                (ticks > period) as u64
+            }
+            PITChannelOpMode::Invalid => 0,
+        }
+    }
+
+    fn read_output(&self, now_ns: u64) -> bool {
+        let Some(ticks) = self.ticks(now_ns) else {
+            // Set low by the command in mode 0, high in the other modes.
+            return !matches!(
+                self.op_mode,
+                PITChannelOpMode::InterruptOnTerminalCount | PITChannelOpMode::Invalid
+            );
+        };
+        let period = self.period();
+        match self.op_mode {
+            PITChannelOpMode::InterruptOnTerminalCount | PITChannelOpMode::OneShot => {
+                ticks >= period
             }
-            _ => Err(HyperError::NotSupported),
+            PITChannelOpMode::RateGenerator => ticks % period != period - 1,
+            PITChannelOpMode::SquareWave => ticks % period < period.div_ceil(2),
+            PITChannelOpMode::SoftwareStrobe | PITChannelOpMode::HardwareStrobe => ticks != period,
+            PITChannelOpMode::Invalid => false,
         }
     }
 
-    fn read_low_byte(&self) -> u8 {
-        self.read_counter().get_bits(0..8) as u8
+    fn latch_count(&mut self, now_ns: u64) {
+        // Latched until read, later latch commands are ignored.
+        if self.latched_count.is_none() {
+            self.latched_count = Some(self.counter(now_ns));
+        }
     }
 
-    fn read_high_byte(&self) -> u8 {
-        self.read_counter().get_bits(8..16) as u8
+    fn latch_status(&mut self, now_ns: u64) {
+        if self.latched_status.is_none() {
+            let mut status = 0u8;
+            status.set_bit(7, self.read_output(now_ns));
+            // Null count: the count written is not loaded in the counter yet.
+            status.set_bit(6, !self.loaded || self.next_reload.is_some());
+            status.set_bits(4..6, self.access_mode.to_state());
+            status.set_bits(1..4, self.op_mode.to_state() & 0x7);
+            self.latched_status = Some(status);
+        }
     }
 
-    fn read(&mut self) -> HyperResult<u8> {
+    fn read(&mut self, now_ns: u64) -> HyperResult<u8> {
+        self.advance(now_ns);
+        if let Some(status) = self.latched_status.take() {
+            return Ok(status);
+        }
+        let counter = self.latched_count.unwrap_or_else(|| self.counter(now_ns));
+        let low = counter.get_bits(0..8) as u8;
+        let high = counter.get_bits(8..16) as u8;
         match self.access_mode {
-            PITChannelAccessMode::LowOnly => Ok(self.read_low_byte()),
-            PITChannelAccessMode::HighOnly => Ok(self.read_high_byte()),
+            PITChannelAccessMode::LowOnly => {
+                self.latched_count = None;
+                Ok(low)
+            }
+            PITChannelAccessMode::HighOnly => {
+                self.latched_count = None;
+                Ok(high)
+            }
             PITChannelAccessMode::LowThenHigh => {
                 self.low_read = !self.low_read;
-                Ok(if self.low_read {
-                    self.read_low_byte()
+                if self.low_read {
+                    Ok(low)
                 } else {
-                    self.read_high_byte()
-                })
+                    self.latched_count = None;
+                    Ok(high)
+                }
             }
             _ => Err(HyperError::BadState),
         }
     }
 
-    fn restart(&mut self) {
-        self.started = true;
-        self.start_nanos = current_time_nanos();
+    fn write(&mut self, value: u8, now_ns: u64) -> HyperResult {
+        if self.op_mode == PITChannelOpMode::Invalid {
+            return Err(HyperError::BadState);
+        }
+        match self.access_mode {
+            PITChannelAccessMode::LowOnly => self.load_count(value as u32, now_ns),
+            PITChannelAccessMode::HighOnly => self.load_count((value as u32) << 8, now_ns),
+            PITChannelAccessMode::LowThenHigh => match self.reload_low.take() {
+                None => {
+                    self.reload_low = Some(value);
+                    // Writing the first byte stops a mode 0 counter.
+                    if self.op_mode == PITChannelOpMode::InterruptOnTerminalCount {
+                        self.started = false;
+                    }
+                }
+                Some(low) => self.load_count(low as u32 | (value as u32) << 8, now_ns),
+            },
+            _ => return Err(HyperError::BadState),
+        }
+        Ok(())
     }
 
-    fn write(&mut self, value: u8) -> HyperResult {
+    fn set_gate(&mut self, gate: bool, now_ns: u64) {
+        self.advance(now_ns);
+        if gate == self.gate {
+            return;
+        }
+        self.gate = gate;
         match self.op_mode {
-            PITChannelOpMode::OneShot => match self.access_mode {
-                PITChannelAccessMode::LowOnly => {
-                    self.reload.set_bits(0..8, value as u32);
-                    self.restart();
-                    Ok(())
-                }
-                PITChannelAccessMode::HighOnly => {
-                    self.reload.set_bits(8..16, value as u32);
-                    self.restart();
-                    Ok(())
+            PITChannelOpMode::InterruptOnTerminalCount | PITChannelOpMode::SoftwareStrobe
+                if self.started =>
+            {
+                if gate {
+                    if let Some(ticks) = self.paused_ticks.take() {
+                        self.start_nanos = now_ns.saturating_sub(ticks_to_nanos(ticks));
+                    }
+                } else {
+                    self.paused_ticks = self.ticks(now_ns);
                 }
-                PITChannelAccessMode::LowThenHigh => {
-                    if self.reload_low_written {
-                        self.reload.set_bits(0..8, value as u32);
-                    } else {
-                        self.reload.set_bits(8..16, value as u32);
-                        self.restart();
+            }
+            PITChannelOpMode::RateGenerator | PITChannelOpMode::SquareWave => {
+                if gate {
+                    // Counting restarts from the count on the rising edge.
+                    if self.loaded {
+                        self.restart(now_ns);
                     }
-
-                    self.reload_low_written = !self.reload_low_written;
-                    Ok(())
+                } else {
+                    if let Some((reload, _)) = self.next_reload.take() {
+                        self.reload = reload;
+                    }
+                    self.started = false;
                 }
-                _ => Err(HyperError::BadState),
-            },
-            _ => Err(HyperError::BadState),
+            }
+            mode if mode.is_gate_triggered() => {
+                if gate && self.loaded {
+                    self.restart(now_ns);
+                }
+            }
+            _ => {}
         }
     }
 
-    fn eclipsed_periods(&self) -> u64 {
-        if self.started {
-            let eclipsed_nanos = current_time_nanos() - self.start_nanos;
-            ((eclipsed_nanos as u128 * PIT_FREQ as u128) / (NANOS_PER_SEC as u128)) as u64
-        } else {
-            0
-        }
+    /// Whether the output rose since the last call.
+    fn pending_irq(&mut self, now_ns: u64) -> bool {
+        self.advance(now_ns);
+        let edge_pending = core::mem::take(&mut self.edge_pending);
+        let Some(ticks) = self.ticks(now_ns) else {
+            return edge_pending;
+        };
+        // Edges missed since the last call are coalesced into one.
+        let edges = self.edges(ticks);
+        let raised = edges > self.edges_raised;
+        self.edges_raised = self.edges_raised.max(edges);
+        edge_pending || raised
     }
 
-    fn read_counter(&self) -> u16 {
-        let eclipsed_periods = self.eclipsed_periods();
-        let reload = self.reload as u64;
-
-        ((reload - eclipsed_periods) & 0xffff) as u16
+    /// Time of the next rising edge of the output, if counting.
+    fn next_irq_ns(&self) -> Option<u64> {
+        if self.edge_pending {
+            return Some(self.start_nanos);
+        }
+        if !self.started || self.paused_ticks.is_some() {
+            return None;
+        }
+        let period = self.period();
+        let ticks = match self.op_mode {
+            PITChannelOpMode::InterruptOnTerminalCount | PITChannelOpMode::OneShot => {
+                (self.edges_raised == 0).then_some(period)?
+            }
+            PITChannelOpMode::RateGenerator | PITChannelOpMode::SquareWave => {
+                (self.edges_raised + 1) * period
+            }
+            PITChannelOpMode::SoftwareStrobe | PITChannelOpMode::HardwareStrobe => {
+                (self.edges_raised == 0).then_some(period + 1)?
+            }
+            PITChannelOpMode::Invalid => return None,
+        };
+        Some(self.start_nanos + ticks_to_nanos(ticks))
     }
 
-    fn read_output(&self) -> bool {
+    fn shift_time(&mut self, delta_ns: u64) {
         if self.started {
-            self.eclipsed_periods() > self.reload as u64
-        } else {
-            false
+            self.start_nanos += delta_ns;
+        }
+        if let Some((_, reload_ns)) = &mut self.next_reload {
+            *reload_ns += delta_ns;
         }
     }
 
-    fn set_enabled(&self, _enabled: bool) {}
+    fn save(&self, state: &mut StateWriter, now_ns: u64) {
+        let (next_reload, next_reload_ns) = self.next_reload.unwrap_or((0, 0));
+        state
+            .bool(self.gate)
+            .u32(self.reload)
+            .bool(self.reload_low.is_some())
+            .u8(self.reload_low.unwrap_or(0))
+            .bool(self.loaded)
+            .bool(self.started)
+            .timestamp(self.start_nanos, now_ns)
+            .bool(self.paused_ticks.is_some())
+            .u64(self.paused_ticks.unwrap_or(0))
+            .bool(self.next_reload.is_some())
+            .u32(next_reload)
+            .timestamp(next_reload_ns, now_ns)
+            .u64(self.edges_raised)
+            .bool(self.edge_pending)
+            .bool(self.latched_count.is_some())
+            .u16(self.latched_count.unwrap_or(0))
+            .bool(self.latched_status.is_some())
+            .u8(self.latched_status.unwrap_or(0))
+            .u8(self.access_mode.to_state())
+            .u8(self.op_mode.to_state())
+            .bool(self.low_read);
+    }
+
+    fn load(state: &mut StateReader, now_ns: u64) -> HyperResult<Self> {
+        let gate = state.bool()?;
+        let reload = state.u32()?;
+        let reload_low = (state.bool()?, state.u8()?);
+        let loaded = state.bool()?;
+        let started = state.bool()?;
+        let start_nanos = state.timestamp(now_ns)?;
+        let paused_ticks = (state.bool()?, state.u64()?);
+        let next_reload = (state.bool()?, state.u32()?, state.timestamp(now_ns)?);
+        let edges_raised = state.u64()?;
+        let edge_pending = state.bool()?;
+        let latched_count = (state.bool()?, state.u16()?);
+        let latched_status = (state.bool()?, state.u8()?);
+        let channel = Self {
+            gate,
+            reload,
+            reload_low: reload_low.0.then_some(reload_low.1),
+            loaded,
+            started,
+            start_nanos,
+            paused_ticks: paused_ticks.0.then_some(paused_ticks.1),
+            next_reload: next_reload.0.then_some((next_reload.1, next_reload.2)),
+            edges_raised,
+            edge_pending,
+            latched_count: latched_count.0.then_some(latched_count.1),
+            latched_status: latched_status.0.then_some(latched_status.1),
+            access_mode: PITChannelAccessMode::from_state(state.u8()?)?,
+            op_mode: PITChannelOpMode::from_state(state.u8()?)?,
+            low_read: state.bool()?,
+        };
+        // The counter runs down from the start, which cannot be ahead.
+        if channel.reload > 0xffff
+            || next_reload.1 > 0xffff
+            || (channel.started && channel.start_nanos > now_ns)
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(channel)
+    }
 }
 
 /// Intel 8253/8254 Programmable Interval Timer (PIT) emulation
 pub struct PIT {
     channels: [PITChannel; PIT_CHANNEL_COUNT],
+    /// The next IRQ 0, for `check_events`.
+    deadline: Arc<TimerDeadline>,
 }
 
 impl PIT {
     pub fn new() -> Self {
         Self {
-            channels: [PITChannel::new(), PITChannel::new(), PITChannel::new()],
+            // The gate of channel 2 is low until the guest sets it in port 0x61.
+            channels: [
+                PITChannel::new(true),
+                PITChannel::new(true),
+                PITChannel::new(false),
+            ],
+            deadline: Arc::new(TimerDeadline::new()),
         }
     }
 
-    pub fn command(&mut self, channel: u8, access_mode: u8, op_mode: u8, bcd: bool) -> HyperResult {
-        let channel = channel as usize;
-        if channel >= PIT_CHANNEL_COUNT {
-            Err(HyperError::InvalidParam)
-        } else {
-            self.channels[channel].command(access_mode, op_mode, bcd).or_else(|err| {
-                warn!("PIT command (channel: {channel}, access_mode: {access_mode:#x}, op_mode: {op_mode:#x}, bcd: {bcd}) error: {err:?}, skipped");
-                Ok(())
-            })
+    /// A write of `command` to the mode/command register.
+    pub fn command(&mut self, command: u8) -> HyperResult {
+        self.command_at(command, current_time_nanos())
+    }
+
+    fn command_at(&mut self, command: u8, now_ns: u64) -> HyperResult {
+        let channel = command.get_bits(6..8) as usize;
+        if channel == PIT_CHANNEL_COUNT {
+            // Read-back: bits 1 to 3 select the channels, bits 5 and 4 latch their counters and
+            // their status when clear.
+            for (index, channel) in self.channels.iter_mut().enumerate() {
+                if command.get_bit(index + 1) {
+                    if !command.get_bit(5) {
+                        channel.latch_count(now_ns);
+                    }
+                    if !command.get_bit(4) {
+                        channel.latch_status(now_ns);
+                    }
+                }
+            }
+            return Ok(());
         }
+
+        let (access_mode, op_mode, bcd) = (
+            command.get_bits(4..6),
+            command.get_bits(1..4),
+            command.get_bit(0),
+        );
+        let result = if access_mode == 0 {
+            self.channels[channel].latch_count(now_ns);
+            Ok(())
+        } else {
+            self.channels[channel].command(access_mode, op_mode, bcd)
+        };
+        self.publish_deadline();
+        result.or_else(|err| {
+            warn!("PIT command (channel: {channel}, access_mode: {access_mode:#x}, op_mode: {op_mode:#x}, bcd: {bcd}) error: {err:?}, skipped");
+            Ok(())
+        })
     }
 
     pub fn read(&mut self, channel: u8) -> HyperResult<u8> {
@@ -203,7 +626,9 @@ impl PIT {
         if channel >= PIT_CHANNEL_COUNT {
             Err(HyperError::InvalidParam)
         } else {
-            self.channels[channel].read().or_else(|_err| {
+            let result = self.channels[channel].read(current_time_nanos());
+            self.publish_deadline();
+            result.or_else(|_err| {
                 // warn!("PIT read (channel: {channel}) error: {err:?}, skipped");
                 Ok(0)
             })
@@ -215,7 +640,9 @@ impl PIT {
         if channel >= PIT_CHANNEL_COUNT {
             Err(HyperError::InvalidParam)
         } else {
-            self.channels[channel].write(value).or_else(|_err| {
+            let result = self.channels[channel].write(value, current_time_nanos());
+            self.publish_deadline();
+            result.or_else(|_err| {
                 // warn!("PIT write (channel: {channel}, value: {value}) error: {err:?}, skipped");
                 Ok(())
             })
@@ -227,16 +654,175 @@ impl PIT {
         if channel >= PIT_CHANNEL_COUNT {
             Err(HyperError::InvalidParam)
         } else {
-            Ok(self.channels[channel].read_output())
+            Ok(self.channels[channel].read_output(current_time_nanos()))
         }
     }
 
-    pub fn set_enabled(&mut self, channel: u8, enabled: bool) -> HyperResult {
+    pub fn set_gate(&mut self, channel: u8, gate: bool) -> HyperResult {
         let channel = channel as usize;
         if channel >= PIT_CHANNEL_COUNT {
             Err(HyperError::InvalidParam)
         } else {
-            Ok(self.channels[channel].set_enabled(enabled))
+            self.channels[channel].set_gate(gate, current_time_nanos());
+            self.publish_deadline();
+            Ok(())
+        }
+    }
+}
+
+impl PIT {
+    /// The lock-free view of the time of the next IRQ 0.
+    pub fn deadline(&self) -> Arc<TimerDeadline> {
+        self.deadline.clone()
+    }
+
+    /// Whether the output of channel 0 rose since the last call, i.e. IRQ 0 is to be raised.
+    pub fn pending_irq(&mut self) -> bool {
+        let raised = self.channels[IRQ_CHANNEL].pending_irq(current_time_nanos());
+        self.publish_deadline();
+        raised
+    }
+
+    pub(super) fn publish_deadline(&self) {
+        self.deadline.set(self.channels[IRQ_CHANNEL].next_irq_ns());
+    }
+
+    /// Move the start of the running channels `delta_ns` later, so that the counters resume
+    /// where they were when the VM was paused.
+    pub fn shift_time(&mut self, delta_ns: u64) {
+        for channel in self.channels.iter_mut() {
+            channel.shift_time(delta_ns);
+        }
+        self.publish_deadline();
+    }
+
+    /// Append the state of the channels to `state`, see [`crate::device::DeviceState`].
+    pub fn save(&self, state: &mut StateWriter) {
+        let now_ns = current_time_nanos();
+        for channel in self.channels.iter() {
+            channel.save(state, now_ns);
         }
     }
+
+    /// Read a state appended by [`PIT::save`], keeping the deadline of this PIT. The deadline
+    /// is published once the result replaces this PIT, see [`PIT::publish_deadline`].
+    pub fn load(&self, state: &mut StateReader) -> HyperResult<Self> {
+        let now_ns = current_time_nanos();
+        Ok(Self {
+            channels: [
+                PITChannel::load(state, now_ns)?,
+                PITChannel::load(state, now_ns)?,
+                PITChannel::load(state, now_ns)?,
+            ],
+            deadline: self.deadline.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A channel counting `count` ticks low then high in `op_mode` from 0.
+    fn channel(op_mode: u8, count: u16) -> PITChannel {
+        let mut channel = PITChannel::new(true);
+        channel.command(3, op_mode, false).unwrap();
+        channel.write(count as u8, 0).unwrap();
+        channel.write((count >> 8) as u8, 0).unwrap();
+        channel
+    }
+
+    fn read_count(channel: &mut PITChannel, now_ns: u64) -> u16 {
+        let low = channel.read(now_ns).unwrap() as u16;
+        low | (channel.read(now_ns).unwrap() as u16) << 8
+    }
+
+    #[test]
+    fn test_counter_latch() {
+        let mut ch = channel(2, 1000);
+        assert_eq!(read_count(&mut ch, ticks_to_nanos(100)), 900);
+        ch.latch_count(ticks_to_nanos(300));
+        // Latched until read, the second latch is ignored.
+        ch.latch_count(ticks_to_nanos(400));
+        assert_eq!(read_count(&mut ch, ticks_to_nanos(500)), 700);
+        assert_eq!(read_count(&mut ch, ticks_to_nanos(500)), 500);
+
+        // A byte access reads the latch once.
+        ch.command(1, 2, false).unwrap();
+        ch.write(200, 0).unwrap();
+        ch.latch_count(ticks_to_nanos(50));
+        assert_eq!(ch.read(ticks_to_nanos(60)).unwrap(), 150);
+        assert_eq!(ch.read(ticks_to_nanos(60)).unwrap(), 140);
+    }
+
+    #[test]
+    fn test_read_back() {
+        let mut pit = PIT::new();
+        // Channel 0 in mode 2 from 1000, channel 2 in mode 3 without a count.
+        pit.command_at(0x34, 0).unwrap();
+        pit.channels[0].write(0xe8, 0).unwrap();
+        pit.channels[0].write(0x03, 0).unwrap();
+        pit.command_at(0xb6, 0).unwrap();
+
+        // Count and status of channels 0 and 2.
+        pit.command_at(0xca, ticks_to_nanos(300)).unwrap();
+        let now = ticks_to_nanos(400);
+        // Output high, count loaded, low then high, mode 2.
+        assert_eq!(pit.channels[0].read(now).unwrap(), 0xb4);
+        assert_eq!(read_count(&mut pit.channels[0], now), 700);
+        // Output high, null count, low then high, mode 3.
+        assert_eq!(pit.channels[2].read(now).unwrap(), 0xf6);
+        assert_eq!(read_count(&mut pit.channels[2], now), 0);
+        assert!(pit.channels[1].latched_count.is_none());
+        assert!(pit.channels[1].latched_status.is_none());
+
+        // The status alone, the count is read as it runs.
+        pit.command_at(0xe2, ticks_to_nanos(500)).unwrap();
+        assert_eq!(pit.channels[0].read(now).unwrap(), 0xb4);
+        assert_eq!(read_count(&mut pit.channels[0], ticks_to_nanos(600)), 400);
+
+        // The count alone, and the counter latch command.
+        pit.command_at(0xd2, ticks_to_nanos(700)).unwrap();
+        assert_eq!(read_count(&mut pit.channels[0], ticks_to_nanos(800)), 300);
+        pit.command_at(0x00, ticks_to_nanos(900)).unwrap();
+        assert_eq!(read_count(&mut pit.channels[0], ticks_to_nanos(950)), 100);
+    }
+
+    #[test]
+    fn test_reload_mid_count() {
+        let mut ch = channel(2, 1000);
+        assert!(!ch.pending_irq(ticks_to_nanos(300)));
+        ch.write(0xf4, ticks_to_nanos(300)).unwrap();
+        ch.write(0x01, ticks_to_nanos(300)).unwrap();
+        // 500 is loaded at the end of the current period.
+        ch.latch_status(ticks_to_nanos(400));
+        assert!(ch.read(ticks_to_nanos(400)).unwrap().get_bit(6));
+        assert_eq!(read_count(&mut ch, ticks_to_nanos(600)), 400);
+        assert!(!ch.pending_irq(ticks_to_nanos(999)));
+        let reload_ns = ticks_to_nanos(1000);
+        assert!(ch.pending_irq(reload_ns));
+        let after = |ticks| reload_ns + ticks_to_nanos(ticks);
+        assert_eq!(read_count(&mut ch, after(100)), 400);
+        ch.latch_status(after(100));
+        assert!(!ch.read(after(100)).unwrap().get_bit(6));
+        assert!(!ch.pending_irq(after(499)));
+        assert!(ch.pending_irq(after(500)));
+
+        // Mode 3 counts down by 2, and reloads the same way.
+        let mut ch = channel(3, 1000);
+        assert_eq!(read_count(&mut ch, ticks_to_nanos(100)), 800);
+        ch.write(0x00, ticks_to_nanos(300)).unwrap();
+        ch.write(0x01, ticks_to_nanos(300)).unwrap();
+        assert_eq!(read_count(&mut ch, ticks_to_nanos(600)), 800);
+        assert!(ch.pending_irq(reload_ns));
+        assert_eq!(read_count(&mut ch, after(64)), 128);
+
+        // A mode 0 count is stopped by its first byte and restarted by the second one.
+        let mut ch = channel(0, 1000);
+        ch.write(0x00, ticks_to_nanos(300)).unwrap();
+        assert_eq!(read_count(&mut ch, ticks_to_nanos(400)), 1000);
+        let load_ns = ticks_to_nanos(500);
+        ch.write(0x02, load_ns).unwrap();
+        assert_eq!(read_count(&mut ch, load_ns + ticks_to_nanos(100)), 412);
+    }
 }
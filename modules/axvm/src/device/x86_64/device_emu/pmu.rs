@@ -0,0 +1,315 @@
+//! Emulated architectural performance monitoring, version 2. (SDM Vol. 3B, Chapter 20)
+//!
+//! [`VirtPmu`] gives the guest the 3 fixed counters and [`PMU_GP_COUNTERS`] general-purpose
+//! counters without touching the PMU of the CPU, which the host keeps. A counter enabled in
+//! IA32_PERF_GLOBAL_CTRL and in its own control, for any privilege level, advances with the
+//! guest TSC whatever its event, so the counts `perf stat` reads are approximate but monotonic,
+//! and stop while the VM is paused. The counters are [`PMU_COUNTER_WIDTH`] bits wide, never
+//! overflow in practice and raise no PMI: IA32_PERF_GLOBAL_STATUS reads as 0.
+//!
+//! CPUID leaf 0xa advertises these counters exactly, see [`VirtPmu::cpuid_leaf`]. RDPMC exits,
+//! see [`enable_rdpmc_exiting`], for the guest to read these counters rather than those of the
+//! CPU.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use hypercraft::VirtMsrOps;
+use lock_stat::Mutex;
+use x86::bits64::vmx::{vmread, vmwrite};
+use x86::vmx::vmcs;
+use x86::vmx::vmcs::control::PrimaryControls;
+
+use super::super::guest_tsc;
+use crate::device::{DeviceState, StateReader, StateWriter};
+use crate::{Error as HyperError, Result as HyperResult};
+
+const PMU_VERSION: u32 = 2;
+/// Number of general-purpose counters, IA32_PMC0 and IA32_PERFEVTSEL0 onwards.
+pub const PMU_GP_COUNTERS: usize = 4;
+/// Number of fixed counters: instructions retired, core cycles and reference cycles.
+pub const PMU_FIXED_COUNTERS: usize = 3;
+/// Width of the counters, in bits.
+pub const PMU_COUNTER_WIDTH: u32 = 48;
+const COUNTER_MASK: u64 = (1 << PMU_COUNTER_WIDTH) - 1;
+/// Number of architectural events of the EBX bit vector of leaf 0xa, all available.
+const ARCH_EVENTS: u32 = 7;
+
+pub const IA32_PMC0: u32 = 0xc1;
+pub const IA32_PERFEVTSEL0: u32 = 0x186;
+pub const IA32_FIXED_CTR0: u32 = 0x309;
+pub const IA32_PERF_CAPABILITIES: u32 = 0x345;
+pub const IA32_FIXED_CTR_CTRL: u32 = 0x38d;
+pub const IA32_PERF_GLOBAL_STATUS: u32 = 0x38e;
+pub const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+pub const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_EN: u64 = 1 << 22;
+/// The OS and USR enables of a fixed counter in IA32_FIXED_CTR_CTRL.
+const FIXED_CTRL_ENABLE: u64 = 0b11;
+const FIXED_CTRL_BITS: u32 = 4;
+/// The RDPMC index of the fixed counters.
+const RDPMC_FIXED: u32 = 1 << 30;
+
+/// The bits of IA32_PERF_GLOBAL_CTRL for the counters.
+pub const PMU_GLOBAL_CTRL_VALID: u64 =
+    ((1 << PMU_GP_COUNTERS) - 1) | ((1 << PMU_FIXED_COUNTERS) - 1) << 32;
+
+const PMU_STATE_VERSION: u16 = 1;
+
+/// A counter, its value as of `since` when it counts.
+#[derive(Debug, Clone, Copy, Default)]
+struct PmuCounter {
+    value: u64,
+    /// The guest TSC the counter counts from, `None` if it is stopped.
+    since: Option<u64>,
+}
+
+impl PmuCounter {
+    fn value(&self, now: u64) -> u64 {
+        let elapsed = self.since.map_or(0, |since| now.wrapping_sub(since));
+        self.value.wrapping_add(elapsed) & COUNTER_MASK
+    }
+}
+
+/// The performance monitoring counters of a vCPU, see the [module documentation](self).
+#[derive(Debug)]
+pub struct VirtPmu {
+    global_ctrl: u64,
+    fixed_ctrl: u64,
+    evtsel: [u64; PMU_GP_COUNTERS],
+    /// The general-purpose counters, then the fixed ones.
+    counters: [PmuCounter; PMU_GP_COUNTERS + PMU_FIXED_COUNTERS],
+}
+
+impl VirtPmu {
+    /// The MSRs of the PMU, by device: the general-purpose counters and their event selects, the
+    /// fixed counters, IA32_PERF_CAPABILITIES, and the control and status MSRs.
+    pub const MSRS: [Range<u32>; 5] = [
+        IA32_PMC0..IA32_PMC0 + PMU_GP_COUNTERS as u32,
+        IA32_PERFEVTSEL0..IA32_PERFEVTSEL0 + PMU_GP_COUNTERS as u32,
+        IA32_FIXED_CTR0..IA32_FIXED_CTR0 + PMU_FIXED_COUNTERS as u32,
+        IA32_PERF_CAPABILITIES..IA32_PERF_CAPABILITIES + 1,
+        IA32_FIXED_CTR_CTRL..IA32_PERF_GLOBAL_OVF_CTRL + 1,
+    ];
+
+    /// A PMU in its reset state: the counters stopped at 0, the general-purpose ones enabled in
+    /// IA32_PERF_GLOBAL_CTRL.
+    pub fn new() -> Self {
+        Self {
+            global_ctrl: (1 << PMU_GP_COUNTERS) - 1,
+            fixed_ctrl: 0,
+            evtsel: [0; PMU_GP_COUNTERS],
+            counters: [PmuCounter::default(); PMU_GP_COUNTERS + PMU_FIXED_COUNTERS],
+        }
+    }
+
+    /// CPUID leaf 0xa, as EAX, EBX, ECX and EDX.
+    pub const fn cpuid_leaf() -> [u32; 4] {
+        [
+            PMU_VERSION
+                | (PMU_GP_COUNTERS as u32) << 8
+                | PMU_COUNTER_WIDTH << 16
+                | ARCH_EVENTS << 24,
+            0,
+            0,
+            PMU_FIXED_COUNTERS as u32 | PMU_COUNTER_WIDTH << 5,
+        ]
+    }
+
+    /// Devices for the [`Self::MSRS`] of `pmu`.
+    pub fn msr_proxies(pmu: &Arc<Mutex<Self>>) -> Vec<VirtPmuMsrs> {
+        Self::MSRS
+            .into_iter()
+            .map(|msrs| VirtPmuMsrs {
+                parent: pmu.clone(),
+                msrs,
+            })
+            .collect()
+    }
+
+    /// Whether counter `index`, in `counters`, counts.
+    fn counting(&self, index: usize) -> bool {
+        let global = match index.checked_sub(PMU_GP_COUNTERS) {
+            None => 1 << index,
+            Some(fixed) => 1 << (32 + fixed),
+        };
+        if self.global_ctrl & global == 0 {
+            return false;
+        }
+        match index.checked_sub(PMU_GP_COUNTERS) {
+            None => {
+                let evtsel = self.evtsel[index];
+                evtsel & EVTSEL_EN != 0 && evtsel & (EVTSEL_USR | EVTSEL_OS) != 0
+            }
+            Some(fixed) => {
+                (self.fixed_ctrl >> (fixed as u32 * FIXED_CTRL_BITS)) & FIXED_CTRL_ENABLE != 0
+            }
+        }
+    }
+
+    /// Apply `f` to the PMU, the counters being stopped at the current guest TSC around it and
+    /// started again if they count afterwards.
+    fn update(&mut self, f: impl FnOnce(&mut Self)) {
+        let now = guest_tsc();
+        for counter in self.counters.iter_mut() {
+            *counter = PmuCounter {
+                value: counter.value(now),
+                since: None,
+            };
+        }
+        f(self);
+        for index in 0..self.counters.len() {
+            if self.counting(index) {
+                self.counters[index].since = Some(now);
+            }
+        }
+    }
+
+    /// What RDPMC reads for counter `index`, as given in ECX. `None` if there is no such
+    /// counter, the instruction failing with #GP.
+    pub fn rdpmc(&self, index: u32) -> Option<u64> {
+        let (first, count) = match index & RDPMC_FIXED {
+            0 => (0, PMU_GP_COUNTERS),
+            _ => (PMU_GP_COUNTERS, PMU_FIXED_COUNTERS),
+        };
+        let index = (index & !RDPMC_FIXED) as usize;
+        (index < count).then(|| self.counters[first + index].value(guest_tsc()))
+    }
+
+    /// Read `msr`. Fails for an MSR which is not the PMU's, and the guest RDMSR then takes #GP.
+    fn read_msr(&self, msr: u32) -> HyperResult<u64> {
+        let [pmcs, evtsels, fixed, ..] = Self::MSRS;
+        let value = match msr {
+            _ if pmcs.contains(&msr) => {
+                self.counters[(msr - IA32_PMC0) as usize].value(guest_tsc())
+            }
+            _ if evtsels.contains(&msr) => self.evtsel[(msr - IA32_PERFEVTSEL0) as usize],
+            _ if fixed.contains(&msr) => {
+                let index = PMU_GP_COUNTERS + (msr - IA32_FIXED_CTR0) as usize;
+                self.counters[index].value(guest_tsc())
+            }
+            // No full-width writes, LBR or PEBS.
+            IA32_PERF_CAPABILITIES => 0,
+            IA32_FIXED_CTR_CTRL => self.fixed_ctrl,
+            // Nothing overflows.
+            IA32_PERF_GLOBAL_STATUS | IA32_PERF_GLOBAL_OVF_CTRL => 0,
+            IA32_PERF_GLOBAL_CTRL => self.global_ctrl,
+            _ => return Err(HyperError::InvalidParam),
+        };
+        Ok(value)
+    }
+
+    /// Write `value` to `msr`, its reserved bits having been checked by `msr_spec`, which injects
+    /// #GP. Fails like [`Self::read_msr`], the exit handler then injecting #GP too.
+    fn write_msr(&mut self, msr: u32, value: u64) -> HyperResult {
+        let [pmcs, evtsels, fixed, ..] = Self::MSRS;
+        match msr {
+            // The legacy writes set the low 32 bits, sign-extended.
+            _ if pmcs.contains(&msr) => self.update(|pmu| {
+                pmu.counters[(msr - IA32_PMC0) as usize].value =
+                    value as u32 as i32 as i64 as u64 & COUNTER_MASK;
+            }),
+            _ if evtsels.contains(&msr) => self.update(|pmu| {
+                pmu.evtsel[(msr - IA32_PERFEVTSEL0) as usize] = value;
+            }),
+            _ if fixed.contains(&msr) => self.update(|pmu| {
+                let index = PMU_GP_COUNTERS + (msr - IA32_FIXED_CTR0) as usize;
+                pmu.counters[index].value = value & COUNTER_MASK;
+            }),
+            IA32_FIXED_CTR_CTRL => self.update(|pmu| pmu.fixed_ctrl = value),
+            IA32_PERF_GLOBAL_CTRL => self.update(|pmu| pmu.global_ctrl = value),
+            IA32_PERF_GLOBAL_OVF_CTRL => {}
+            _ => return Err(HyperError::InvalidParam),
+        }
+        Ok(())
+    }
+}
+
+impl Default for VirtPmu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Have RDPMC exit on the VMCS loaded on the current CPU.
+pub fn enable_rdpmc_exiting() {
+    let rdpmc_exiting = PrimaryControls::RDPMC_EXITING.bits() as u64;
+    let result = unsafe {
+        vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS).and_then(|controls| {
+            if controls & rdpmc_exiting != 0 {
+                return Ok(());
+            }
+            vmwrite(
+                vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+                controls | rdpmc_exiting,
+            )
+        })
+    };
+    if let Err(err) = result {
+        warn!("failed to set RDPMC exiting: {:?}", err);
+    }
+}
+
+/// Some of the MSRs of a [`VirtPmu`], see [`VirtPmu::msr_proxies`].
+pub struct VirtPmuMsrs {
+    parent: Arc<Mutex<VirtPmu>>,
+    msrs: Range<u32>,
+}
+
+impl VirtMsrOps for VirtPmuMsrs {
+    fn msr_range(&self) -> Range<u32> {
+        self.msrs.clone()
+    }
+
+    fn read(&mut self, msr: u32) -> HyperResult<u64> {
+        self.parent.lock().read_msr(msr)
+    }
+
+    fn write(&mut self, msr: u32, value: u64) -> HyperResult {
+        self.parent.lock().write_msr(msr, value)
+    }
+}
+
+impl DeviceState for VirtPmu {
+    // The counters are saved as of the guest TSC they count from, which the restored VM keeps.
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(PMU_STATE_VERSION);
+        state.u64(self.global_ctrl).u64(self.fixed_ctrl);
+        for evtsel in self.evtsel.iter() {
+            state.u64(*evtsel);
+        }
+        for counter in self.counters.iter() {
+            state
+                .u64(counter.value)
+                .bool(counter.since.is_some())
+                .u64(counter.since.unwrap_or(0));
+        }
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let mut state = StateReader::new(state, PMU_STATE_VERSION)?;
+        let mut pmu = Self::new();
+        pmu.global_ctrl = state.u64()?;
+        pmu.fixed_ctrl = state.u64()?;
+        for evtsel in pmu.evtsel.iter_mut() {
+            *evtsel = state.u64()?;
+        }
+        for counter in pmu.counters.iter_mut() {
+            let value = state.u64()?;
+            let counting = state.bool()?;
+            let since = state.u64()?;
+            *counter = PmuCounter {
+                value,
+                since: counting.then_some(since),
+            };
+        }
+        state.finish()?;
+        *self = pmu;
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+//! Emulated reset control register of the PIIX/ICH chipsets at port 0xcf9, through which a guest
+//! resets the machine, as Linux does with `reboot=pci` and as most firmwares do.
+//!
+//! Setting RST_CPU resets the VM, with the [`ResetPolicy`](crate::ResetPolicy) of its config,
+//! whatever SYS_RST and FULL_RST ask for: a reboot always starts the VM from scratch.
+
+use crate::Result as HyperResult;
+use hypercraft::PioOps;
+
+pub const PORT_RESET_CONTROL: u16 = 0xcf9;
+
+/// System reset, rather than a reset of the CPU alone.
+const SYS_RST: u8 = 1 << 1;
+/// Reset, as the bit is set.
+const RST_CPU: u8 = 1 << 2;
+/// Full reset, the power cycled.
+const FULL_RST: u8 = 1 << 3;
+
+#[derive(Default)]
+pub struct ResetControl {
+    /// The reset type last written, RST_CPU reading as 0.
+    value: u8,
+}
+
+impl ResetControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PioOps for ResetControl {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        PORT_RESET_CONTROL..PORT_RESET_CONTROL + 1
+    }
+
+    fn read(&mut self, _port: u16, _access_size: u8) -> HyperResult<u32> {
+        Ok(self.value as u32)
+    }
+
+    fn write(&mut self, _port: u16, _access_size: u8, value: u32) -> HyperResult {
+        let value = value as u8;
+        self.value = value & (SYS_RST | FULL_RST);
+        // The host VM cannot be reset, the write is dropped.
+        if value & RST_CPU != 0 && crate::vm::guest_reset("port 0xcf9").is_err() {
+            warn!("reset control: system reset requested, not supported");
+        }
+        Ok(())
+    }
+}
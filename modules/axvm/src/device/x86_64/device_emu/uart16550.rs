@@ -1,10 +1,26 @@
 //! Emulated UART 16550. (ref: https://wiki.osdev.org/Serial_Ports)
 //!
+//! The model covers what the Linux 8250 driver checks when it probes and starts a port: the IER,
+//! scratch and divisor latch registers read back, the FIFO control is reported through the IIR
+//! as a 16550A (no EFR, no 64-byte FIFO), the loopback mode wires the MCR outputs to the MSR
+//! inputs, the THRE interrupt is re-raised whenever it is enabled with the THR empty, and the
+//! interrupt causes are reported in priority order. Bytes are transmitted at once, so the THR is
+//! always empty, and the only receive error is the overrun of the RX FIFO by host input.
+//!
+//! The received bytes come from the host console through the backend, for the UART connected to
+//! it, see [`Uart16550::set_host_input`], and from [`Uart16550::push_byte`]. The interrupt output,
+//! gated by OUT2 as on a PC, is turned into IRQs by the vCPU devices, see
+//! [`Uart16550::poll_interrupt`].
 use hypercraft::{HyperError, HyperResult, PioOps};
 
-use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use spin::Mutex;
 
+use crate::console_mux::MultiplexConsole;
+use crate::device::console_backend::{DefaultConsoleBackend, Fifo, VirtualConsoleBackend};
+use crate::device::{write_each, DeviceState, PioBatchOps, StateReader, StateWriter};
+
 const DATA_REG: u16 = 0;
 const INT_EN_REG: u16 = 1;
 const FIFO_CTRL_REG: u16 = 2;
@@ -16,155 +32,117 @@ const SCRATCH_REG: u16 = 7;
 
 const UART_FIFO_CAPACITY: usize = 16;
 
+const UART_STATE_VERSION: u16 = 2;
+
+/// Divisor latch access bit of the LCR, registers 0 and 1 being the divisor while it is set.
+const LINE_CTRL_DLAB: u8 = 1 << 7;
+
+/// FCR bits, the trigger level and DMA mode bits are ignored.
+const FIFO_CTRL_ENABLE: u8 = 1 << 0;
+const FIFO_CTRL_CLEAR_RX: u8 = 1 << 1;
+
+/// Interrupt identification, by decreasing priority.
+const INT_ID_LINE_STATUS: u8 = 0x06;
+const INT_ID_RECEIVED_DATA: u8 = 0x04;
+const INT_ID_TRANSMIT_EMPTY: u8 = 0x02;
+const INT_ID_MODEM_STATUS: u8 = 0x00;
+const INT_ID_NONE: u8 = 0x01;
+/// Bits 6 and 7 of the IIR, set while the FIFOs are enabled.
+const INT_ID_FIFO_ENABLED: u8 = 0xc0;
+
 bitflags::bitflags! {
     /// Line status flags
+    #[derive(Clone, Copy)]
     struct LineStsFlags: u8 {
         const INPUT_FULL = 1 << 0;
-        // 1 to 3 is error flag
+        const OVERRUN_ERROR = 1 << 1;
+        const PARITY_ERROR = 1 << 2;
+        const FRAMING_ERROR = 1 << 3;
         const BREAK_INTERRUPT = 1 << 4;
         const OUTPUT_EMPTY = 1 << 5;
         const OUTPUT_EMPTY2 = 1 << 6;
-        // 7 is error flag
+        // 7 is error in RX FIFO, never set since only overruns are emulated
+        /// Bits cleared by reading the LSR.
+        const ERRORS = Self::OVERRUN_ERROR.bits()
+            | Self::PARITY_ERROR.bits()
+            | Self::FRAMING_ERROR.bits()
+            | Self::BREAK_INTERRUPT.bits();
     }
-}
-
-/// FIFO queue for caching bytes read.
-pub struct Fifo<const CAP: usize> {
-    buf: [u8; CAP],
-    head: usize,
-    num: usize,
-}
 
-impl<const CAP: usize> Fifo<CAP> {
-    const fn new() -> Self {
-        Self {
-            buf: [0; CAP],
-            head: 0,
-            num: 0,
-        }
+    /// Interrupt enable flags
+    #[derive(Clone, Copy)]
+    struct IntEnFlags: u8 {
+        const RECEIVED_DATA = 1 << 0;
+        const TRANSMIT_EMPTY = 1 << 1;
+        const LINE_STATUS = 1 << 2;
+        const MODEM_STATUS = 1 << 3;
     }
 
-    fn is_empty(&self) -> bool {
-        self.num == 0
+    /// Modem control flags
+    #[derive(Clone, Copy)]
+    struct ModemCtrlFlags: u8 {
+        const DTR = 1 << 0;
+        const RTS = 1 << 1;
+        const OUT1 = 1 << 2;
+        const OUT2 = 1 << 3;
+        const LOOPBACK = 1 << 4;
     }
 
-    fn is_full(&self) -> bool {
-        self.num == CAP
+    /// Modem status flags
+    #[derive(Clone, Copy)]
+    struct ModemStsFlags: u8 {
+        const DELTA_CTS = 1 << 0;
+        const DELTA_DSR = 1 << 1;
+        const TRAILING_EDGE_RI = 1 << 2;
+        const DELTA_DCD = 1 << 3;
+        const CTS = 1 << 4;
+        const DSR = 1 << 5;
+        const RI = 1 << 6;
+        const DCD = 1 << 7;
+        /// Bits cleared by reading the MSR.
+        const DELTAS = Self::DELTA_CTS.bits()
+            | Self::DELTA_DSR.bits()
+            | Self::TRAILING_EDGE_RI.bits()
+            | Self::DELTA_DCD.bits();
     }
-
-    fn push(&mut self, value: u8) {
-        assert!(self.num < CAP);
-        self.buf[(self.head + self.num) % CAP] = value;
-        self.num += 1;
-    }
-
-    fn pop(&mut self) -> u8 {
-        assert!(self.num > 0);
-        let ret = self.buf[self.head];
-        self.head += 1;
-        self.head %= CAP;
-        self.num -= 1;
-        ret
-    }
-}
-
-pub trait VirtualConsoleBackend: Send + Sync + Sized {
-    fn new() -> Self;
-    fn putchar(&mut self, c: u8);
-    fn getchar(&mut self) -> Option<u8>;
 }
 
-pub struct DefaultConsoleBackend;
+/// Modem inputs outside of loopback: the host console is always connected and ready.
+const CONNECTED_MODEM_INPUTS: ModemStsFlags = ModemStsFlags::CTS
+    .union(ModemStsFlags::DSR)
+    .union(ModemStsFlags::DCD);
 
-impl VirtualConsoleBackend for DefaultConsoleBackend {
-    fn new() -> Self {
-        Self
-    }
-
-    fn putchar(&mut self, c: u8) {
-        use axhal::console as uart;
-        uart::putchar(c)
-    }
-
-    fn getchar(&mut self) -> Option<u8> {
-        use axhal::console as uart;
-        uart::getchar()
-    }
-}
-
-const MULTIPLEX_BUFFER_LENGTH: usize = 80;
-pub enum MultiplexConsoleBackend {
-    Primary,
-    Secondary {
-        id: isize,
-        buffer: Fifo<MULTIPLEX_BUFFER_LENGTH>,
-        input: String,
-        input_ptr: usize,
-    },
+/// A backend on the [`MultiplexConsole`] shared by the VMs, or on the host console as
+/// [`DefaultConsoleBackend`] if there is none.
+#[derive(Default)]
+pub struct MultiplexConsoleBackend {
+    console: Option<Arc<MultiplexConsole>>,
 }
 
 impl MultiplexConsoleBackend {
-    pub fn new_secondary(id: isize, input: impl Into<String>) -> Self {
-        Self::Secondary {
-            id: id,
-            buffer: Fifo::new(),
-            input: input.into(),
-            input_ptr: 0,
+    pub fn shared(console: Arc<MultiplexConsole>) -> Self {
+        Self {
+            console: Some(console),
         }
     }
 }
 
 impl VirtualConsoleBackend for MultiplexConsoleBackend {
     fn new() -> Self {
-        Self::Primary
+        Self::default()
     }
 
     fn putchar(&mut self, c: u8) {
-        match self {
-            MultiplexConsoleBackend::Primary => {
-                use axhal::console as uart;
-                uart::putchar(c)
-            }
-            MultiplexConsoleBackend::Secondary { id, buffer, .. } => {
-                if c == ('\n' as u8) {
-                    let mut result = [0u8; MULTIPLEX_BUFFER_LENGTH + 1];
-                    let mut ptr = 0;
-
-                    while !buffer.is_empty() {
-                        result[ptr] = buffer.pop();
-                        ptr += 1;
-                    }
-
-                    info!(
-                        "multiplex console output {}: {}",
-                        id,
-                        core::str::from_utf8(&result[0..ptr]).unwrap()
-                    )
-                } else {
-                    buffer.push(c);
-                }
-            }
+        match &self.console {
+            Some(console) => console.putchar(c),
+            None => crate::console_mux::guest_putchar(None, c),
         }
     }
 
     fn getchar(&mut self) -> Option<u8> {
-        match self {
-            MultiplexConsoleBackend::Primary => {
-                use axhal::console as uart;
-                uart::getchar()
-            }
-            MultiplexConsoleBackend::Secondary {
-                input, input_ptr, ..
-            } => {
-                let result = input.as_bytes()[*input_ptr];
-
-                *input_ptr += 1;
-                if *input_ptr >= input.len() {
-                    *input_ptr = 0;
-                }
-
-                Some(result)
-            }
+        match &self.console {
+            Some(console) => console.getchar(),
+            None => crate::console_mux::guest_getchar(),
         }
     }
 }
@@ -172,7 +150,24 @@ impl VirtualConsoleBackend for MultiplexConsoleBackend {
 pub struct Uart16550<B: VirtualConsoleBackend = DefaultConsoleBackend> {
     port_base: u16,
     fifo: Mutex<Fifo<UART_FIFO_CAPACITY>>,
+    int_en_reg: IntEnFlags,
+    /// Only the enable bit, the FCR being write-only.
+    fifo_ctrl_reg: u8,
     line_control_reg: u8,
+    modem_ctrl_reg: ModemCtrlFlags,
+    /// The error bits of the LSR, the other ones are computed when it is read.
+    line_errors: LineStsFlags,
+    modem_status_reg: ModemStsFlags,
+    scratch_reg: u8,
+    divisor: u16,
+    /// The THR emptied, or the THRE interrupt was enabled, since the IIR last reported it.
+    transmit_empty_pending: bool,
+    /// Level of the interrupt output after the last access, and whether it rose since
+    /// `poll_interrupt` was last called.
+    irq_level: bool,
+    irq_raised: bool,
+    /// Whether the host console input is received, through `backend`.
+    host_input: bool,
     backend: B,
 }
 
@@ -187,7 +182,10 @@ impl<B: VirtualConsoleBackend> PioOps for Uart16550<B> {
             error!("Invalid serial port I/O read size: {} != 1", access_size);
             return Err(HyperError::InvalidParam);
         }
+        let dlab = self.line_control_reg & LINE_CTRL_DLAB != 0;
         let ret = match port - self.port_base {
+            DATA_REG if dlab => self.divisor as u8,
+            INT_EN_REG if dlab => (self.divisor >> 8) as u8,
             DATA_REG => {
                 // read a byte from FIFO
                 let mut fifo = self.fifo.lock();
@@ -197,27 +195,41 @@ impl<B: VirtualConsoleBackend> PioOps for Uart16550<B> {
                     fifo.pop()
                 }
             }
+            INT_EN_REG => self.int_en_reg.bits(),
+            FIFO_CTRL_REG => {
+                let id = self.interrupt_id();
+                // Reading the IIR acknowledges the THRE interrupt, the other causes are
+                // acknowledged by reading the register they report.
+                if id == INT_ID_TRANSMIT_EMPTY {
+                    self.transmit_empty_pending = false;
+                }
+                if self.fifo_enabled() {
+                    id | INT_ID_FIFO_ENABLED
+                } else {
+                    id
+                }
+            }
             LINE_STATUS_REG => {
                 // check if the physical serial port has an available byte, and push it to FIFO.
-                let mut fifo = self.fifo.lock();
-                if !fifo.is_full() {
-                    if let Some(c) = self.backend.getchar() {
-                        fifo.push(c);
-                    }
-                }
+                self.poll_input();
                 let mut lsr = LineStsFlags::OUTPUT_EMPTY | LineStsFlags::OUTPUT_EMPTY2;
-                if !fifo.is_empty() {
+                if !self.fifo.lock().is_empty() {
                     lsr |= LineStsFlags::INPUT_FULL;
                 }
+                lsr |= core::mem::replace(&mut self.line_errors, LineStsFlags::empty());
                 lsr.bits()
             }
             LINE_CTRL_REG => self.line_control_reg,
-            INT_EN_REG | FIFO_CTRL_REG | MODEM_CTRL_REG | MODEM_STATUS_REG | SCRATCH_REG => {
-                trace!("Unimplemented serial port I/O read: {:#x}", port); // unimplemented
-                0
+            MODEM_CTRL_REG => self.modem_ctrl_reg.bits(),
+            MODEM_STATUS_REG => {
+                let msr = self.modem_status_reg;
+                self.modem_status_reg.remove(ModemStsFlags::DELTAS);
+                msr.bits()
             }
+            SCRATCH_REG => self.scratch_reg,
             _ => unreachable!(),
         };
+        self.update_irq();
         Ok(ret as u32)
     }
 
@@ -227,15 +239,66 @@ impl<B: VirtualConsoleBackend> PioOps for Uart16550<B> {
             error!("Invalid serial port I/O write size: {} != 1", access_size);
             return Err(HyperError::InvalidParam);
         }
+        let value = value as u8;
+        let dlab = self.line_control_reg & LINE_CTRL_DLAB != 0;
         match port - self.port_base {
-            DATA_REG => self.backend.putchar(value as u8),
-            LINE_CTRL_REG => self.line_control_reg = value as u8,
-            INT_EN_REG | FIFO_CTRL_REG | MODEM_CTRL_REG | SCRATCH_REG => {
-                trace!("Unimplemented serial port I/O write: {:#x}", port); // unimplemented
+            DATA_REG if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            INT_EN_REG if dlab => self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8,
+            DATA_REG => {
+                if self.modem_ctrl_reg.contains(ModemCtrlFlags::LOOPBACK) {
+                    self.receive(value);
+                } else {
+                    self.backend.putchar(value);
+                }
+                self.transmit_empty_pending = true;
+            }
+            INT_EN_REG => {
+                let int_en = IntEnFlags::from_bits_truncate(value);
+                if int_en.contains(IntEnFlags::TRANSMIT_EMPTY)
+                    && !self.int_en_reg.contains(IntEnFlags::TRANSMIT_EMPTY)
+                {
+                    // The THR is always empty.
+                    self.transmit_empty_pending = true;
+                }
+                self.int_en_reg = int_en;
+            }
+            FIFO_CTRL_REG => {
+                let enable = value & FIFO_CTRL_ENABLE;
+                // Enabling or disabling the FIFOs clears them.
+                if enable != self.fifo_ctrl_reg || value & FIFO_CTRL_CLEAR_RX != 0 {
+                    *self.fifo.lock() = Fifo::new();
+                }
+                self.fifo_ctrl_reg = enable;
             }
-            LINE_STATUS_REG => {} // ignore
+            LINE_CTRL_REG => self.line_control_reg = value,
+            MODEM_CTRL_REG => {
+                self.modem_ctrl_reg = ModemCtrlFlags::from_bits_truncate(value);
+                self.update_modem_inputs();
+            }
+            SCRATCH_REG => self.scratch_reg = value,
+            LINE_STATUS_REG | MODEM_STATUS_REG => {} // ignore
             _ => unreachable!(),
         }
+        self.update_irq();
+        Ok(())
+    }
+}
+
+impl<B: VirtualConsoleBackend> PioBatchOps for Uart16550<B> {
+    fn write_batch(&mut self, port: u16, access_size: u8, data: &[u8]) -> HyperResult {
+        let transmit = port - self.port_base == DATA_REG
+            && access_size == 1
+            && self.line_control_reg & LINE_CTRL_DLAB == 0
+            && !self.modem_ctrl_reg.contains(ModemCtrlFlags::LOOPBACK);
+        if !transmit {
+            return write_each(self, port, access_size, data);
+        }
+        // The whole string goes out at once, with a single THRE interrupt once it is sent.
+        for &byte in data {
+            self.backend.putchar(byte);
+        }
+        self.transmit_empty_pending = true;
+        self.update_irq();
         Ok(())
     }
 }
@@ -245,12 +308,573 @@ impl<B: VirtualConsoleBackend> Uart16550<B> {
         Self {
             port_base,
             fifo: Mutex::new(Fifo::new()),
+            int_en_reg: IntEnFlags::empty(),
+            fifo_ctrl_reg: 0,
             line_control_reg: 0,
+            modem_ctrl_reg: ModemCtrlFlags::empty(),
+            line_errors: LineStsFlags::empty(),
+            modem_status_reg: CONNECTED_MODEM_INPUTS,
+            scratch_reg: 0,
+            divisor: 0,
+            transmit_empty_pending: false,
+            irq_level: false,
+            irq_raised: false,
+            host_input: true,
             backend: B::new(),
         }
     }
 
+    /// A UART writing to and reading from `backend`.
+    pub fn with_backend(port_base: u16, backend: B) -> Self {
+        Self {
+            backend,
+            ..Self::new(port_base)
+        }
+    }
+
     pub fn backend(&mut self) -> &mut B {
         &mut self.backend
     }
+
+    /// Connect the UART to the host console input, the default, or disconnect it: the UARTs
+    /// sharing a console would otherwise take its bytes in turn.
+    pub fn set_host_input(&mut self, connected: bool) {
+        self.host_input = connected;
+    }
+
+    /// Receive `b` on the serial line, as if the other end sent it. Lost with an overrun if the
+    /// guest does not read the RX FIFO fast enough, and in loopback mode, where the serial input
+    /// is disconnected. The interrupt is raised at the next `poll_interrupt`.
+    pub fn push_byte(&mut self, b: u8) {
+        if self.modem_ctrl_reg.contains(ModemCtrlFlags::LOOPBACK) {
+            return;
+        }
+        self.receive(b);
+        self.update_irq();
+    }
+
+    /// Whether the interrupt output rose since the last call, polling the host input first if
+    /// the guest enabled the receive interrupt.
+    pub(crate) fn poll_interrupt(&mut self) -> bool {
+        if self.int_en_reg.contains(IntEnFlags::RECEIVED_DATA) {
+            self.poll_input();
+            self.update_irq();
+        }
+        core::mem::take(&mut self.irq_raised)
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fifo_ctrl_reg & FIFO_CTRL_ENABLE != 0
+    }
+
+    /// Take a byte of host input, the serial input being disconnected in loopback mode.
+    fn poll_input(&mut self) {
+        if !self.host_input || self.modem_ctrl_reg.contains(ModemCtrlFlags::LOOPBACK) {
+            return;
+        }
+        if let Some(c) = self.backend.getchar() {
+            self.receive(c);
+        }
+    }
+
+    /// A byte arrived, lost with an overrun if the RX FIFO (or the RBR alone while the FIFOs
+    /// are disabled) is full.
+    fn receive(&mut self, c: u8) {
+        let capacity = if self.fifo_enabled() {
+            UART_FIFO_CAPACITY
+        } else {
+            1
+        };
+        let mut fifo = self.fifo.lock();
+        if fifo.len() < capacity {
+            fifo.push(c);
+        } else {
+            self.line_errors |= LineStsFlags::OVERRUN_ERROR;
+        }
+    }
+
+    /// Set the MSR inputs, and their delta bits, after a change of the MCR.
+    fn update_modem_inputs(&mut self) {
+        let inputs = if self.modem_ctrl_reg.contains(ModemCtrlFlags::LOOPBACK) {
+            let mcr = self.modem_ctrl_reg;
+            let mut inputs = ModemStsFlags::empty();
+            inputs.set(ModemStsFlags::CTS, mcr.contains(ModemCtrlFlags::RTS));
+            inputs.set(ModemStsFlags::DSR, mcr.contains(ModemCtrlFlags::DTR));
+            inputs.set(ModemStsFlags::RI, mcr.contains(ModemCtrlFlags::OUT1));
+            inputs.set(ModemStsFlags::DCD, mcr.contains(ModemCtrlFlags::OUT2));
+            inputs
+        } else {
+            CONNECTED_MODEM_INPUTS
+        };
+        let old = self.modem_status_reg;
+        let changed = old ^ inputs;
+        let mut deltas = ModemStsFlags::empty();
+        deltas.set(
+            ModemStsFlags::DELTA_CTS,
+            changed.contains(ModemStsFlags::CTS),
+        );
+        deltas.set(
+            ModemStsFlags::DELTA_DSR,
+            changed.contains(ModemStsFlags::DSR),
+        );
+        deltas.set(
+            ModemStsFlags::DELTA_DCD,
+            changed.contains(ModemStsFlags::DCD),
+        );
+        // Only the trailing edge of RI is reported.
+        deltas.set(
+            ModemStsFlags::TRAILING_EDGE_RI,
+            old.contains(ModemStsFlags::RI) && !inputs.contains(ModemStsFlags::RI),
+        );
+        self.modem_status_reg = (old & ModemStsFlags::DELTAS) | deltas | inputs;
+    }
+
+    /// The pending interrupt of highest priority, as reported by the IIR.
+    fn interrupt_id(&self) -> u8 {
+        let int_en = self.int_en_reg;
+        if int_en.contains(IntEnFlags::LINE_STATUS) && !self.line_errors.is_empty() {
+            INT_ID_LINE_STATUS
+        } else if int_en.contains(IntEnFlags::RECEIVED_DATA) && !self.fifo.lock().is_empty() {
+            INT_ID_RECEIVED_DATA
+        } else if int_en.contains(IntEnFlags::TRANSMIT_EMPTY) && self.transmit_empty_pending {
+            INT_ID_TRANSMIT_EMPTY
+        } else if int_en.contains(IntEnFlags::MODEM_STATUS)
+            && self.modem_status_reg.intersects(ModemStsFlags::DELTAS)
+        {
+            INT_ID_MODEM_STATUS
+        } else {
+            INT_ID_NONE
+        }
+    }
+
+    /// Recompute the interrupt output. On a PC it only reaches the PIC while OUT2 is set, and
+    /// OUT2 is looped back instead of driving the output in loopback mode.
+    fn update_irq(&mut self) {
+        let mcr = self.modem_ctrl_reg;
+        let level = mcr.contains(ModemCtrlFlags::OUT2)
+            && !mcr.contains(ModemCtrlFlags::LOOPBACK)
+            && self.interrupt_id() != INT_ID_NONE;
+        if level && !self.irq_level {
+            self.irq_raised = true;
+        }
+        self.irq_level = level;
+    }
+}
+
+impl<B: VirtualConsoleBackend> DeviceState for Uart16550<B> {
+    fn save(&self) -> Vec<u8> {
+        let fifo = self.fifo.lock();
+        let received: Vec<u8> = fifo.iter().collect();
+        let mut state = StateWriter::new(UART_STATE_VERSION);
+        state
+            .u16(self.port_base)
+            .u8(self.line_control_reg)
+            .bytes(&received)
+            .u8(self.int_en_reg.bits())
+            .u8(self.fifo_ctrl_reg)
+            .u8(self.modem_ctrl_reg.bits())
+            .u8(self.line_errors.bits())
+            .u8(self.modem_status_reg.bits())
+            .u8(self.scratch_reg)
+            .u16(self.divisor)
+            .bool(self.transmit_empty_pending);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let mut state = StateReader::new(state, UART_STATE_VERSION)?;
+        let port_base = state.u16()?;
+        let line_control_reg = state.u8()?;
+        let received = state.bytes()?;
+        let int_en_reg = state.u8()?;
+        let fifo_ctrl_reg = state.u8()?;
+        let modem_ctrl_reg = state.u8()?;
+        let line_errors = state.u8()?;
+        let modem_status_reg = state.u8()?;
+        let scratch_reg = state.u8()?;
+        let divisor = state.u16()?;
+        let transmit_empty_pending = state.bool()?;
+        state.finish()?;
+        if port_base != self.port_base || received.len() > UART_FIFO_CAPACITY {
+            return Err(HyperError::InvalidParam);
+        }
+
+        self.line_control_reg = line_control_reg;
+        let mut fifo = Fifo::new();
+        for &c in received {
+            fifo.push(c);
+        }
+        *self.fifo.lock() = fifo;
+        self.int_en_reg = IntEnFlags::from_bits_truncate(int_en_reg);
+        self.fifo_ctrl_reg = fifo_ctrl_reg & FIFO_CTRL_ENABLE;
+        self.modem_ctrl_reg = ModemCtrlFlags::from_bits_truncate(modem_ctrl_reg);
+        self.line_errors = LineStsFlags::from_bits_truncate(line_errors) & LineStsFlags::ERRORS;
+        self.modem_status_reg = ModemStsFlags::from_bits_truncate(modem_status_reg);
+        self.scratch_reg = scratch_reg;
+        self.divisor = divisor;
+        self.transmit_empty_pending = transmit_empty_pending;
+        // The IRQ of a pending interrupt is raised again.
+        self.irq_level = false;
+        self.update_irq();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+
+    const COM1: u16 = 0x3f8;
+
+    /// The other end of the serial line: records what the UART sends, and gives it `input`.
+    #[derive(Default)]
+    struct MockBackend {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl VirtualConsoleBackend for MockBackend {
+        fn new() -> Self {
+            Self::default()
+        }
+
+        fn putchar(&mut self, c: u8) {
+            self.output.push(c);
+        }
+
+        fn getchar(&mut self) -> Option<u8> {
+            self.input.pop_front()
+        }
+    }
+
+    type Uart = Uart16550<MockBackend>;
+
+    fn write(uart: &mut Uart, reg: u16, value: u8) {
+        uart.write(COM1 + reg, 1, value as u32).unwrap();
+    }
+
+    fn read(uart: &mut Uart, reg: u16) -> u8 {
+        uart.read(COM1 + reg, 1).unwrap() as u8
+    }
+
+    /// A UART programmed by a driver, with received bytes and interrupts pending.
+    fn programmed() -> Uart {
+        let mut uart = Uart::new(COM1);
+        write(&mut uart, LINE_CTRL_REG, LINE_CTRL_DLAB | 0x03);
+        write(&mut uart, DATA_REG, 0x01);
+        write(&mut uart, INT_EN_REG, 0x00);
+        write(&mut uart, LINE_CTRL_REG, 0x03);
+        write(&mut uart, FIFO_CTRL_REG, FIFO_CTRL_ENABLE);
+        write(&mut uart, SCRATCH_REG, 0x5a);
+        write(&mut uart, MODEM_CTRL_REG, 0x0b);
+        write(&mut uart, INT_EN_REG, 0x07);
+        for &b in b"hello" {
+            uart.push_byte(b);
+        }
+        uart
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut uart = programmed();
+        // Overrun the RX FIFO, for the LSR to report an error.
+        for b in 0..UART_FIFO_CAPACITY as u8 {
+            uart.push_byte(b);
+        }
+        assert!(uart.poll_interrupt());
+
+        let mut restored = Uart::new(COM1);
+        restored.restore(&uart.save()).unwrap();
+        assert_eq!(restored.save(), uart.save());
+        // The pending interrupt is raised again once restored.
+        assert!(restored.poll_interrupt());
+
+        // Both UARTs read the same, side effects included: the IIR reporting the causes in
+        // priority order, the LSR clearing its errors and the RBR draining the FIFO.
+        for uart in [&mut uart, &mut restored] {
+            assert_eq!(read(uart, FIFO_CTRL_REG), 0xc0 | INT_ID_LINE_STATUS);
+            assert_eq!(read(uart, LINE_STATUS_REG), 0x63);
+            assert_eq!(read(uart, FIFO_CTRL_REG), 0xc0 | INT_ID_RECEIVED_DATA);
+            let received: Vec<u8> = (0..UART_FIFO_CAPACITY)
+                .map(|_| read(uart, DATA_REG))
+                .collect();
+            assert_eq!(&received[..5], b"hello");
+            assert_eq!(read(uart, FIFO_CTRL_REG), 0xc0 | INT_ID_TRANSMIT_EMPTY);
+            assert_eq!(read(uart, FIFO_CTRL_REG), 0xc0 | INT_ID_NONE);
+            assert_eq!(read(uart, LINE_CTRL_REG), 0x03);
+            assert_eq!(read(uart, SCRATCH_REG), 0x5a);
+            assert_eq!(read(uart, MODEM_CTRL_REG), 0x0b);
+            write(uart, LINE_CTRL_REG, LINE_CTRL_DLAB | 0x03);
+            assert_eq!(read(uart, DATA_REG), 0x01);
+            assert_eq!(read(uart, INT_EN_REG), 0x00);
+        }
+        assert_eq!(restored.save(), uart.save());
+    }
+
+    #[test]
+    fn test_round_trip_keeps_backend() {
+        let mut uart = programmed();
+        write(&mut uart, DATA_REG, b'a');
+
+        // The backend is not part of the state: the restored UART keeps its own, with its
+        // pending host input received after the restored bytes.
+        let mut backend = MockBackend::new();
+        backend.input.push_back(b'!');
+        let mut restored = Uart::with_backend(COM1, backend);
+        restored.restore(&uart.save()).unwrap();
+        write(&mut restored, DATA_REG, b'b');
+        assert_eq!(uart.backend().output, b"a");
+        assert_eq!(restored.backend().output, b"b");
+        assert_eq!(read(&mut restored, LINE_STATUS_REG) & 0x01, 0x01);
+        let received: Vec<u8> = (0..6).map(|_| read(&mut restored, DATA_REG)).collect();
+        assert_eq!(received, b"hello!");
+    }
+
+    #[test]
+    fn test_rejected_states() {
+        let mut uart = programmed();
+        let saved = uart.save();
+
+        let mut com2 = Uart::new(0x2f8);
+        assert!(matches!(
+            com2.restore(&saved),
+            Err(HyperError::InvalidParam)
+        ));
+        assert_eq!(com2.save(), Uart::new(0x2f8).save());
+
+        // More received bytes than the FIFO holds.
+        let mut state = StateWriter::new(UART_STATE_VERSION);
+        state
+            .u16(COM1)
+            .u8(0)
+            .bytes(&[0; UART_FIFO_CAPACITY + 1])
+            .u8(0)
+            .u8(0)
+            .u8(0)
+            .u8(0)
+            .u8(0)
+            .u8(0)
+            .u16(0)
+            .bool(false);
+        assert!(matches!(
+            uart.restore(&state.finish()),
+            Err(HyperError::InvalidParam)
+        ));
+        assert!(uart.restore(&saved[..saved.len() - 1]).is_err());
+        assert_eq!(uart.save(), saved);
+    }
+
+    const OUT2: u8 = 0x08;
+    const LOOPBACK: u8 = 0x10;
+    const IER_ALL: u8 = 0x0f;
+
+    #[test]
+    fn test_interrupt_priority() {
+        let mut uart = Uart::new(COM1);
+        write(&mut uart, MODEM_CTRL_REG, OUT2);
+        write(&mut uart, INT_EN_REG, IER_ALL);
+        // The RBR alone holds a byte while the FIFOs are disabled, the second one overruns it.
+        uart.push_byte(b'a');
+        uart.push_byte(b'b');
+        assert!(uart.poll_interrupt());
+
+        // Each cause is reported until the register it reports is read, the THRE one until the
+        // IIR reports it.
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_LINE_STATUS);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_LINE_STATUS);
+        assert_eq!(read(&mut uart, LINE_STATUS_REG), 0x63);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_RECEIVED_DATA);
+        assert_eq!(read(&mut uart, DATA_REG), b'a');
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_TRANSMIT_EMPTY);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_NONE);
+
+        // A change of the modem inputs comes last.
+        write(&mut uart, INT_EN_REG, 0x00);
+        write(&mut uart, MODEM_CTRL_REG, OUT2 | LOOPBACK);
+        write(&mut uart, MODEM_CTRL_REG, OUT2);
+        write(&mut uart, INT_EN_REG, IER_ALL);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_TRANSMIT_EMPTY);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_MODEM_STATUS);
+        assert_eq!(read(&mut uart, MODEM_STATUS_REG) & 0x0f, 0x03);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_NONE);
+    }
+
+    #[test]
+    fn test_loopback() {
+        let mut uart = Uart::new(COM1);
+        assert_eq!(read(&mut uart, MODEM_STATUS_REG), 0xb0);
+
+        // DTR, RTS, OUT1 and OUT2 drive DSR, CTS, RI and DCD. Only the trailing edge of RI is
+        // reported.
+        write(&mut uart, MODEM_CTRL_REG, LOOPBACK | 0x0f);
+        assert_eq!(read(&mut uart, MODEM_STATUS_REG), 0xf0);
+        write(&mut uart, MODEM_CTRL_REG, LOOPBACK | 0x01);
+        assert_eq!(read(&mut uart, MODEM_STATUS_REG), 0x2d);
+        assert_eq!(read(&mut uart, MODEM_STATUS_REG), 0x20);
+
+        // The transmitted bytes are received, the serial input is disconnected, and so is the
+        // interrupt output.
+        write(&mut uart, INT_EN_REG, IER_ALL);
+        write(&mut uart, MODEM_CTRL_REG, LOOPBACK | OUT2);
+        uart.backend().input.push_back(b'!');
+        uart.push_byte(b'?');
+        write(&mut uart, DATA_REG, b'x');
+        assert!(uart.backend().output.is_empty());
+        assert!(!uart.poll_interrupt());
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_RECEIVED_DATA);
+        assert_eq!(read(&mut uart, DATA_REG), b'x');
+        assert_eq!(read(&mut uart, LINE_STATUS_REG) & 0x01, 0x00);
+
+        // Out of loopback, the modem inputs are connected again.
+        write(&mut uart, MODEM_CTRL_REG, OUT2);
+        assert_eq!(read(&mut uart, MODEM_STATUS_REG) & 0xf0, 0xb0);
+        assert_eq!(read(&mut uart, LINE_STATUS_REG) & 0x01, 0x01);
+        assert_eq!(read(&mut uart, DATA_REG), b'!');
+    }
+
+    #[test]
+    fn test_transmit_empty() {
+        let mut uart = Uart::new(COM1);
+        write(&mut uart, MODEM_CTRL_REG, OUT2);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_NONE);
+
+        // Enabling the THRE interrupt raises it, the THR being empty.
+        write(&mut uart, INT_EN_REG, 0x02);
+        assert!(uart.poll_interrupt());
+        assert!(!uart.poll_interrupt());
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_TRANSMIT_EMPTY);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_NONE);
+        // Not when it is already enabled.
+        write(&mut uart, INT_EN_REG, 0x02);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_NONE);
+        write(&mut uart, INT_EN_REG, 0x00);
+        write(&mut uart, INT_EN_REG, 0x02);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_TRANSMIT_EMPTY);
+
+        // Each transmitted byte raises it again.
+        write(&mut uart, DATA_REG, b'a');
+        assert!(uart.poll_interrupt());
+        write(&mut uart, DATA_REG, b'b');
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_TRANSMIT_EMPTY);
+        assert_eq!(uart.backend().output, b"ab");
+    }
+
+    #[test]
+    fn test_scratch_and_divisor() {
+        let mut uart = Uart::new(COM1);
+        write(&mut uart, INT_EN_REG, 0x05);
+        write(&mut uart, SCRATCH_REG, 0xa5);
+        assert_eq!(read(&mut uart, SCRATCH_REG), 0xa5);
+
+        // While the DLAB is set, registers 0 and 1 are the divisor, and nothing is sent.
+        write(&mut uart, LINE_CTRL_REG, LINE_CTRL_DLAB | 0x03);
+        write(&mut uart, DATA_REG, 0x0c);
+        write(&mut uart, INT_EN_REG, 0x01);
+        assert_eq!(read(&mut uart, DATA_REG), 0x0c);
+        assert_eq!(read(&mut uart, INT_EN_REG), 0x01);
+        uart.write_batch(COM1 + DATA_REG, 1, &[0x30, 0x60]).unwrap();
+        assert_eq!(read(&mut uart, DATA_REG), 0x60);
+        assert_eq!(read(&mut uart, LINE_CTRL_REG), LINE_CTRL_DLAB | 0x03);
+        assert!(uart.backend().output.is_empty());
+
+        write(&mut uart, LINE_CTRL_REG, 0x03);
+        assert_eq!(read(&mut uart, INT_EN_REG), 0x05);
+        assert_eq!(read(&mut uart, SCRATCH_REG), 0xa5);
+        write(&mut uart, LINE_CTRL_REG, LINE_CTRL_DLAB);
+        assert_eq!(read(&mut uart, INT_EN_REG), 0x01);
+    }
+
+    #[test]
+    fn test_fifo_and_overrun() {
+        let mut uart = Uart::new(COM1);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_NONE);
+        write(&mut uart, FIFO_CTRL_REG, FIFO_CTRL_ENABLE);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), 0xc0 | INT_ID_NONE);
+
+        // The 17th byte overruns the FIFO, the error being cleared by reading the LSR.
+        for b in 0..=UART_FIFO_CAPACITY as u8 {
+            uart.push_byte(b);
+        }
+        assert_eq!(read(&mut uart, LINE_STATUS_REG), 0x63);
+        assert_eq!(read(&mut uart, LINE_STATUS_REG), 0x61);
+        let received: Vec<u8> = (0..UART_FIFO_CAPACITY)
+            .map(|_| read(&mut uart, DATA_REG))
+            .collect();
+        assert_eq!(received, (0..UART_FIFO_CAPACITY as u8).collect::<Vec<_>>());
+        assert_eq!(read(&mut uart, LINE_STATUS_REG), 0x60);
+        assert_eq!(read(&mut uart, DATA_REG), 0);
+
+        // Clearing the RX FIFO, or disabling the FIFOs, drops the received bytes.
+        uart.push_byte(b'a');
+        write(
+            &mut uart,
+            FIFO_CTRL_REG,
+            FIFO_CTRL_ENABLE | FIFO_CTRL_CLEAR_RX,
+        );
+        assert_eq!(read(&mut uart, LINE_STATUS_REG), 0x60);
+        uart.push_byte(b'b');
+        write(&mut uart, FIFO_CTRL_REG, FIFO_CTRL_ENABLE);
+        assert_eq!(read(&mut uart, LINE_STATUS_REG), 0x61);
+        write(&mut uart, FIFO_CTRL_REG, 0);
+        assert_eq!(read(&mut uart, LINE_STATUS_REG), 0x60);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_NONE);
+    }
+
+    #[test]
+    fn test_interrupt_output() {
+        let mut uart = Uart::new(COM1);
+        write(&mut uart, INT_EN_REG, 0x01);
+
+        // Without OUT2 the interrupt does not reach the PIC.
+        uart.push_byte(b'a');
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_RECEIVED_DATA);
+        assert!(!uart.poll_interrupt());
+        write(&mut uart, MODEM_CTRL_REG, OUT2);
+        assert!(uart.poll_interrupt());
+        // Raised once per rising edge.
+        assert!(!uart.poll_interrupt());
+        read(&mut uart, DATA_REG);
+
+        // The host input is polled for the receive interrupt, unless it is disconnected.
+        uart.backend().input.push_back(b'b');
+        uart.set_host_input(false);
+        assert!(!uart.poll_interrupt());
+        uart.set_host_input(true);
+        assert!(uart.poll_interrupt());
+        assert_eq!(read(&mut uart, DATA_REG), b'b');
+        write(&mut uart, INT_EN_REG, 0x00);
+        uart.backend().input.push_back(b'c');
+        assert!(!uart.poll_interrupt());
+        assert_eq!(uart.backend().input.len(), 1);
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let mut uart = Uart::new(COM1);
+        write(&mut uart, MODEM_CTRL_REG, OUT2);
+        write(&mut uart, INT_EN_REG, 0x02);
+        assert!(uart.poll_interrupt());
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_TRANSMIT_EMPTY);
+
+        // A string is sent at once, with a single THRE interrupt.
+        uart.write_batch(COM1 + DATA_REG, 1, b"hello").unwrap();
+        assert_eq!(uart.backend().output, b"hello");
+        assert!(uart.poll_interrupt());
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_TRANSMIT_EMPTY);
+        assert_eq!(read(&mut uart, FIFO_CTRL_REG), INT_ID_NONE);
+
+        // In loopback mode it is received byte by byte.
+        write(&mut uart, FIFO_CTRL_REG, FIFO_CTRL_ENABLE);
+        write(&mut uart, MODEM_CTRL_REG, LOOPBACK);
+        uart.write_batch(COM1 + DATA_REG, 1, b"abc").unwrap();
+        assert_eq!(uart.backend().output, b"hello");
+        let received: Vec<u8> = (0..3).map(|_| read(&mut uart, DATA_REG)).collect();
+        assert_eq!(received, b"abc");
+
+        // To another register, each byte is a write of its own.
+        uart.write_batch(COM1 + SCRATCH_REG, 1, &[1, 2, 3]).unwrap();
+        assert_eq!(read(&mut uart, SCRATCH_REG), 3);
+        assert!(uart.write_batch(COM1 + DATA_REG, 2, b"ab").is_err());
+    }
 }
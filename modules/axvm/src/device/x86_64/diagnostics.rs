@@ -0,0 +1,148 @@
+//! State dumps of a vCPU stopped by a VM exit nobody handles, or by an NMI nobody sent.
+//!
+//! The `{:#x?}` of the vCPU gave the registers but not what the guest was executing, nor how it
+//! got there. [`dump_vcpu_state`] adds the code at the guest RIP, read through the guest page
+//! tables and disassembled, and the last exits of the vCPU.
+
+use alloc::string::String;
+
+use iced_x86::{Decoder, DecoderOptions, Formatter, MasmFormatter};
+use log::Level;
+use x86::vmx::vmcs;
+
+use super::cr_access::GuestMode;
+use super::string_io::{GuestLinearMemory, VmxGuestMemory};
+use super::vmexit::{exit_history, vmcs_read, ExitContext};
+use crate::{HyperCraftHal, VCpu};
+
+/// Bytes of guest code dumped from the guest RIP, enough for the longest instruction.
+const CODE_DUMP_LEN: usize = 16;
+
+/// Log, at `level`, the registers of `vcpu` at the exit of `ctx`, the code at its RIP and its
+/// last exits.
+pub(crate) fn dump_vcpu_state<H: HyperCraftHal>(level: Level, vcpu: &VCpu<H>, ctx: &ExitContext) {
+    let regs = vcpu.regs();
+    log!(
+        level,
+        "  exit {:?} ({:#x}), qualification {:#x}",
+        ctx.exit_reason,
+        ctx.exit_reason as u32,
+        ctx.qualification
+    );
+    log!(
+        level,
+        "  RIP {:#018x} RSP {:#018x} RFLAGS {:#x}",
+        ctx.guest_rip,
+        vmcs_read(vmcs::guest::RSP),
+        vmcs_read(vmcs::guest::RFLAGS)
+    );
+    log!(
+        level,
+        "  RAX {:#018x} RBX {:#018x} RCX {:#018x} RDX {:#018x}",
+        regs.rax,
+        regs.rbx,
+        regs.rcx,
+        regs.rdx
+    );
+    log!(
+        level,
+        "  RSI {:#018x} RDI {:#018x} RBP {:#018x} R8  {:#018x}",
+        regs.rsi,
+        regs.rdi,
+        regs.rbp,
+        regs.r8
+    );
+    log!(
+        level,
+        "  R9  {:#018x} R10 {:#018x} R11 {:#018x} R12 {:#018x}",
+        regs.r9,
+        regs.r10,
+        regs.r11,
+        regs.r12
+    );
+    log!(
+        level,
+        "  R13 {:#018x} R14 {:#018x} R15 {:#018x}",
+        regs.r13,
+        regs.r14,
+        regs.r15
+    );
+    log!(
+        level,
+        "  CR0 {:#x} CR3 {:#x} CR4 {:#x} EFER {:#x}",
+        vmcs_read(vmcs::guest::CR0),
+        vmcs_read(vmcs::guest::CR3),
+        vmcs_read(vmcs::guest::CR4),
+        vmcs_read(vmcs::guest::IA32_EFER_FULL)
+    );
+    dump_code(level, ctx.guest_rip as u64);
+    dump_exit_history(level, vcpu.vcpu_id());
+}
+
+/// Log the code at guest RIP `rip` and its disassembly, as far as it can be read.
+fn dump_code(level: Level, rip: u64) {
+    let bitness = GuestMode::current().code_bitness(vmcs_read(vmcs::guest::CS_ACCESS_RIGHTS));
+    let addr = vmcs_read(vmcs::guest::CS_BASE).wrapping_add(rip);
+    let mut buf = [0; CODE_DUMP_LEN];
+    let len = crate::vm::current_vm_id()
+        .and_then(VmxGuestMemory::current)
+        .map_or(0, |mut mem| read_guest(&mut mem, addr, &mut buf));
+    if len == 0 {
+        log!(level, "  code at {:#x}: not mapped", addr);
+        return;
+    }
+    log!(
+        level,
+        "  code at {:#x} ({}-bit): {:02x?}",
+        addr,
+        bitness,
+        &buf[..len]
+    );
+
+    let mut decoder = Decoder::with_ip(bitness, &buf[..len], rip, DecoderOptions::NONE);
+    let mut formatter = MasmFormatter::new();
+    let mut text = String::new();
+    for instr in &mut decoder {
+        if instr.is_invalid() {
+            // Also the last instruction when it is cut by the end of the dump or an unmapped
+            // page.
+            log!(level, "    {:#x}: (bad)", instr.ip());
+            break;
+        }
+        text.clear();
+        formatter.format(&instr, &mut text);
+        log!(level, "    {:#x}: {}", instr.ip(), text);
+    }
+}
+
+/// Read guest linear memory at `addr` into `buf`, returns the number of bytes read before the
+/// first byte which is not mapped to RAM.
+fn read_guest(mem: &mut VmxGuestMemory, addr: u64, buf: &mut [u8]) -> usize {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        match mem.translate(addr.wrapping_add(i as u64), false) {
+            // The guest may rewrite its code concurrently, as with the decoder.
+            Ok(ptr) => *byte = unsafe { ptr.read_volatile() },
+            Err(_) => return i,
+        }
+    }
+    buf.len()
+}
+
+/// Log the exit history of vCPU `vcpu_id` of the VM running on the current CPU.
+fn dump_exit_history(level: Level, vcpu_id: usize) {
+    let vm_id = crate::vm::current_vm_id().unwrap_or(crate::vm::HOST_VM_ID);
+    let Some(history) = exit_history(vm_id, vcpu_id) else {
+        return;
+    };
+    log!(level, "  last exits of vCPU {} (oldest first):", vcpu_id);
+    for r in history.iter() {
+        log!(
+            level,
+            "    [{:>14} ns] {:?} @ {:#x}, qualification {:#x}",
+            r.timestamp_ns,
+            r.exit_reason,
+            r.guest_rip,
+            r.qualification
+        );
+    }
+}
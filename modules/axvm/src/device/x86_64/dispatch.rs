@@ -0,0 +1,339 @@
+//! Precedence of the device lists answering a VM exit.
+//!
+//! hypercraft hands every exit to the per-vCPU devices, then, if they decline it, to the per-VM
+//! devices. Both levels dispatch through [`DeviceList::dispatch_exit`](super::DeviceList), so an
+//! exit is handled by the first of:
+//!
+//! 1. the per-vCPU device list ([`X64VcpuDevices`](super::X64VcpuDevices)), for port I/O and
+//!    MSRs, after the exits it owns (HLT, preemption timer);
+//! 2. the per-VM device list, for port I/O, MSRs and MMIO, after external interrupts;
+//! 3. the final fallback: a fatal VM exit, or for an unhandled MSR the
+//!    [`UnhandledMsrPolicy`](crate::UnhandledMsrPolicy) of the VM, and for an unclaimed port its
+//!    [`UnhandledPortPolicy`](crate::UnhandledPortPolicy).
+//!
+//! A per-vCPU device list given its own policy applies it to the MSRs the per-VM devices do not
+//! implement either, before the exit reaches the per-VM list.
+//!
+//! The [`DispatchChain`] of a VM records what each level claims, in that order. A port, MSR or
+//! MMIO range registered at both levels is therefore always answered by the per-vCPU device.
+//! Such a registration is almost certainly a mistake, so the claims of both levels are compared
+//! when a vCPU first enters its VM and whenever a device is attached to the VM, and each overlap
+//! is reported once.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// The ranges claimed by the devices of one device list.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClaimedRanges {
+    pub port: Vec<Range<u64>>,
+    pub mmio: Vec<Range<u64>>,
+    pub msr: Vec<Range<u64>>,
+}
+
+/// The address spaces of the exits the device lists dispatch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Space {
+    Port,
+    Mmio,
+    Msr,
+}
+
+impl Space {
+    const ALL: [Space; 3] = [Space::Port, Space::Mmio, Space::Msr];
+
+    fn name(self) -> &'static str {
+        match self {
+            Space::Port => "port",
+            Space::Mmio => "MMIO",
+            Space::Msr => "MSR",
+        }
+    }
+}
+
+impl ClaimedRanges {
+    fn get(&self, space: Space) -> &[Range<u64>] {
+        match space {
+            Space::Port => &self.port,
+            Space::Mmio => &self.mmio,
+            Space::Msr => &self.msr,
+        }
+    }
+
+    fn claims(&self, space: Space, range: &Range<u64>) -> bool {
+        self.get(space)
+            .iter()
+            .any(|claimed| claimed.start < range.end && range.start < claimed.end)
+    }
+}
+
+/// The step of a [`DispatchChain`] answering an exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DispatchLevel {
+    Vcpu,
+    Vm,
+    Fallback,
+}
+
+/// A range claimed at both levels, the per-vCPU device winning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Conflict {
+    pub space: Space,
+    pub vcpu_id: usize,
+    pub vcpu_range: Range<u64>,
+    pub vm_range: Range<u64>,
+}
+
+/// What the device lists of a VM claim, in the order they answer its exits, see the
+/// [module documentation](self).
+#[derive(Debug, Default)]
+pub(crate) struct DispatchChain {
+    /// The ranges of the per-vCPU device lists, by vCPU id.
+    vcpus: BTreeMap<usize, ClaimedRanges>,
+    vm: ClaimedRanges,
+}
+
+impl DispatchChain {
+    fn new(vm: ClaimedRanges) -> Self {
+        Self {
+            vcpus: BTreeMap::new(),
+            vm,
+        }
+    }
+
+    /// The level answering an exit of vCPU `vcpu_id` at `addr` of `space`.
+    pub fn level(&self, vcpu_id: usize, space: Space, addr: u64) -> DispatchLevel {
+        let range = addr..addr + 1;
+        if let Some(vcpu) = self.vcpus.get(&vcpu_id) {
+            if vcpu.claims(space, &range) {
+                return DispatchLevel::Vcpu;
+            }
+        }
+        if self.vm.claims(space, &range) {
+            DispatchLevel::Vm
+        } else {
+            DispatchLevel::Fallback
+        }
+    }
+
+    /// The overlaps between the ranges of vCPU `vcpu_id` and the per-VM ranges `vm` accepts.
+    fn conflicts(&self, vcpu_id: usize, vm: impl Fn(Space, &Range<u64>) -> bool) -> Vec<Conflict> {
+        let mut conflicts = Vec::new();
+        let Some(vcpu) = self.vcpus.get(&vcpu_id) else {
+            return conflicts;
+        };
+        for space in Space::ALL {
+            for (vcpu_range, vm_range) in overlaps(vcpu.get(space), self.vm.get(space)) {
+                if vm(space, vm_range) {
+                    conflicts.push(Conflict {
+                        space,
+                        vcpu_id,
+                        vcpu_range: vcpu_range.clone(),
+                        vm_range: vm_range.clone(),
+                    });
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Record the ranges of the per-vCPU devices of `vcpu_id`, and return their overlaps with the
+    /// per-VM ranges.
+    pub fn set_vcpu_ranges(&mut self, vcpu_id: usize, ranges: ClaimedRanges) -> Vec<Conflict> {
+        self.vcpus.insert(vcpu_id, ranges);
+        self.conflicts(vcpu_id, |_, _| true)
+    }
+
+    /// Replace the ranges of the per-VM devices, e.g. once a device was attached, and return the
+    /// overlaps of the ranges not claimed before with those of the vCPUs.
+    pub fn set_vm_ranges(&mut self, ranges: ClaimedRanges) -> Vec<Conflict> {
+        let old = core::mem::replace(&mut self.vm, ranges);
+        let added = |space: Space, range: &Range<u64>| !old.get(space).contains(range);
+        let vcpu_ids: Vec<usize> = self.vcpus.keys().copied().collect();
+        vcpu_ids
+            .into_iter()
+            .flat_map(|vcpu_id| self.conflicts(vcpu_id, added))
+            .collect()
+    }
+}
+
+lazy_static! {
+    /// The dispatch chain of each VM.
+    static ref DISPATCH_CHAINS: Mutex<HashMap<u32, DispatchChain>> = Mutex::new(HashMap::new());
+}
+
+fn report(vm_id: u32, conflicts: &[Conflict]) -> usize {
+    for conflict in conflicts {
+        warn!(
+            "VM [{}] {} range {:#x?} of vCPU {} overlaps per-VM device {:#x?}, \
+             the per-vCPU device wins",
+            vm_id,
+            conflict.space.name(),
+            conflict.vcpu_range,
+            conflict.vcpu_id,
+            conflict.vm_range
+        );
+    }
+    conflicts.len()
+}
+
+/// Start the dispatch chain of `vm_id`, whose per-VM devices were just created and claim
+/// `ranges`. Its vCPUs add theirs when they first enter the VM.
+pub(crate) fn register_vm_ranges(vm_id: u32, ranges: ClaimedRanges) {
+    DISPATCH_CHAINS
+        .lock()
+        .insert(vm_id, DispatchChain::new(ranges));
+}
+
+/// Update the ranges of the per-VM devices of `vm_id` once devices were attached or detached,
+/// warn about the new overlaps with the per-vCPU devices and return their number.
+pub(crate) fn update_vm_ranges(vm_id: u32, ranges: ClaimedRanges) -> usize {
+    let conflicts = DISPATCH_CHAINS
+        .lock()
+        .entry(vm_id)
+        .or_default()
+        .set_vm_ranges(ranges);
+    report(vm_id, &conflicts)
+}
+
+/// Add the ranges of the per-vCPU devices of `vcpu_id` to the dispatch chain of `vm_id`, warn
+/// about their overlaps with the per-VM devices and return their number.
+pub(crate) fn register_vcpu_ranges(vm_id: u32, vcpu_id: usize, ranges: ClaimedRanges) -> usize {
+    let conflicts = DISPATCH_CHAINS
+        .lock()
+        .entry(vm_id)
+        .or_default()
+        .set_vcpu_ranges(vcpu_id, ranges);
+    report(vm_id, &conflicts)
+}
+
+/// The level of the dispatch chain of `vm_id` answering an exit of vCPU `vcpu_id` at `addr` of
+/// `space`, see [`DispatchChain::level`].
+pub(crate) fn dispatch_level(vm_id: u32, vcpu_id: usize, space: Space, addr: u64) -> DispatchLevel {
+    DISPATCH_CHAINS
+        .lock()
+        .get(&vm_id)
+        .map_or(DispatchLevel::Fallback, |chain| {
+            chain.level(vcpu_id, space, addr)
+        })
+}
+
+pub(crate) fn unregister_vm_ranges(vm_id: u32) {
+    DISPATCH_CHAINS.lock().remove(&vm_id);
+}
+
+/// Whether a per-VM device of `vm_id` implements `msr`.
+pub(crate) fn vm_claims_msr(vm_id: u32, msr: u32) -> bool {
+    let msr = msr as u64;
+    DISPATCH_CHAINS
+        .lock()
+        .get(&vm_id)
+        .map_or(false, |chain| chain.vm.claims(Space::Msr, &(msr..msr + 1)))
+}
+
+/// Whether a per-VM device of `vm_id` claims MMIO addresses in `range`.
+pub(crate) fn vm_claims_mmio(vm_id: u32, range: Range<u64>) -> bool {
+    DISPATCH_CHAINS
+        .lock()
+        .get(&vm_id)
+        .map_or(false, |chain| chain.vm.claims(Space::Mmio, &range))
+}
+
+fn overlaps<'a>(
+    vcpu_ranges: &'a [Range<u64>],
+    vm_ranges: &'a [Range<u64>],
+) -> impl Iterator<Item = (&'a Range<u64>, &'a Range<u64>)> {
+    vcpu_ranges.iter().flat_map(move |a| {
+        vm_ranges
+            .iter()
+            .filter(move |b| a.start < b.end && b.start < a.end)
+            .map(move |b| (a, b))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const COM1: Range<u64> = 0x3f8..0x400;
+    const COM2: Range<u64> = 0x2f8..0x300;
+    const IA32_TSC_DEADLINE: u64 = 0x6e0;
+
+    fn ports(ranges: &[Range<u64>]) -> ClaimedRanges {
+        ClaimedRanges {
+            port: ranges.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        let mut chain = DispatchChain::new(ClaimedRanges {
+            port: vec![COM2],
+            mmio: vec![0xfec0_0000..0xfec0_1000],
+            msr: vec![IA32_TSC_DEADLINE..IA32_TSC_DEADLINE + 1],
+        });
+        assert!(chain.set_vcpu_ranges(0, ports(&[COM1])).is_empty());
+        assert_eq!(chain.level(0, Space::Port, 0x3f8), DispatchLevel::Vcpu);
+        assert_eq!(chain.level(0, Space::Port, 0x2fd), DispatchLevel::Vm);
+        assert_eq!(chain.level(0, Space::Port, 0x60), DispatchLevel::Fallback);
+        assert_eq!(chain.level(0, Space::Mmio, 0xfec0_0010), DispatchLevel::Vm);
+        assert_eq!(
+            chain.level(0, Space::Msr, IA32_TSC_DEADLINE),
+            DispatchLevel::Vm
+        );
+        // Another vCPU, whose devices are not known yet.
+        assert_eq!(chain.level(1, Space::Port, 0x3f8), DispatchLevel::Fallback);
+    }
+
+    #[test]
+    fn test_conflicting_registration() {
+        let mut chain = DispatchChain::new(ports(&[0x3fc..0x3fd]));
+        let conflicts = chain.set_vcpu_ranges(0, ports(&[COM1]));
+        assert_eq!(
+            conflicts,
+            [Conflict {
+                space: Space::Port,
+                vcpu_id: 0,
+                vcpu_range: COM1,
+                vm_range: 0x3fc..0x3fd,
+            }]
+        );
+        // The per-vCPU device wins.
+        assert_eq!(chain.level(0, Space::Port, 0x3fc), DispatchLevel::Vcpu);
+        // The spaces are compared separately, MSR 0x3fc is no port.
+        let msrs = ClaimedRanges {
+            msr: vec![0x3fc..0x3fd],
+            ..Default::default()
+        };
+        assert!(chain.set_vcpu_ranges(1, msrs).is_empty());
+    }
+
+    #[test]
+    fn test_attach_conflict() {
+        let mut chain = DispatchChain::new(ClaimedRanges::default());
+        assert!(chain.set_vcpu_ranges(0, ports(&[COM1])).is_empty());
+        assert!(chain.set_vcpu_ranges(1, ports(&[COM1])).is_empty());
+        // A device attached over COM1, reported for both vCPUs.
+        let conflicts = chain.set_vm_ranges(ports(&[0x3f8..0x3f9]));
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(conflicts[0].vcpu_id, 0);
+        assert_eq!(conflicts[1].vcpu_id, 1);
+        assert_eq!(chain.level(1, Space::Port, 0x3f8), DispatchLevel::Vcpu);
+        // Attaching another device reports only its own overlaps.
+        let conflicts = chain.set_vm_ranges(ports(&[0x3f8..0x3f9, COM2]));
+        assert!(conflicts.is_empty());
+        assert_eq!(chain.level(0, Space::Port, 0x2f8), DispatchLevel::Vm);
+        // Detaching one neither.
+        assert!(chain.set_vm_ranges(ports(&[COM2])).is_empty());
+        assert_eq!(chain.level(0, Space::Port, 0x3f8), DispatchLevel::Vcpu);
+        assert_eq!(chain.level(1, Space::Port, 0x3ff), DispatchLevel::Vcpu);
+        assert_eq!(chain.level(1, Space::Port, 0x400), DispatchLevel::Fallback);
+    }
+}
@@ -0,0 +1,443 @@
+//! Observers of the VM exits, for host-side tooling such as profilers and trace collectors.
+//!
+//! An observer is called on the CPU of the vCPU, either before the exit is handled (right after
+//! it is recorded and classified) or after, with the outcome. It runs in the exit path, so it
+//! must be short. The observers of an exit share [`OBSERVER_BUDGET_NS`]: once they used it up,
+//! the ones left are skipped for this exit, so that the guest is re-entered after at most the
+//! budget and one more observer. An observer taking more than the budget by itself is reported,
+//! and disabled after [`MAX_OVERRUNS`] overruns in a row.
+//!
+//! The observer list is replaced, never modified in place, so an observer can be removed while
+//! the vCPUs are running: an exit already notifying it finishes with the old list. When no
+//! observer is registered, the exit path only pays for one atomic load.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use axconfig::SMP;
+use axhal::current_cpu_id;
+use spin::RwLock;
+
+use crate::{Result as HyperResult, VmExitInfo};
+
+/// Time the observers of an exit, in one phase, may take together.
+pub const OBSERVER_BUDGET_NS: u64 = 20_000;
+/// Overruns of the budget in a row after which an observer is disabled.
+pub const MAX_OVERRUNS: u32 = 16;
+
+static OBSERVER_LOG: crate::ratelimit::RateLimiter =
+    crate::ratelimit::RateLimiter::new("exit observer", 10);
+
+/// When an observer is called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObserverPhase {
+    /// After the exit is classified, before any device handles it.
+    BeforeHandling,
+    /// After the exit is handled, with its outcome.
+    AfterHandling,
+}
+
+/// How the exit was handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitOutcome {
+    Handled,
+    Failed,
+}
+
+/// What an observer is told about an exit, besides its [`VmExitInfo`].
+#[derive(Debug, Clone, Copy)]
+pub struct ObserverCtx {
+    pub vm_id: u32,
+    pub vcpu_id: usize,
+    pub phase: ObserverPhase,
+    /// When the exit was recorded, in nanoseconds.
+    pub exit_ns: u64,
+    /// When the observer is called, in nanoseconds.
+    pub now_ns: u64,
+    /// `None` before the exit is handled.
+    pub outcome: Option<ExitOutcome>,
+}
+
+pub type ExitObserverFn = Box<dyn Fn(&VmExitInfo, &ObserverCtx) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObserverId(u64);
+
+struct Observer {
+    id: ObserverId,
+    vm_id: u32,
+    phase: ObserverPhase,
+    f: ExitObserverFn,
+    overruns: AtomicU32,
+    disabled: AtomicBool,
+}
+
+/// Number of registered observers, so that the exit path skips them with a single load.
+static ACTIVE_OBSERVERS: AtomicUsize = AtomicUsize::new(0);
+static OBSERVERS: RwLock<Option<Arc<Vec<Arc<Observer>>>>> = RwLock::new(None);
+static NEXT_OBSERVER_ID: AtomicU64 = AtomicU64::new(0);
+
+const NO_EXIT: AtomicU64 = AtomicU64::new(0);
+/// When the exit being handled on each physical CPU was recorded.
+static EXIT_START_NS: [AtomicU64; SMP] = [NO_EXIT; SMP];
+
+/// Call `f` on the exits of VM `vm_id`, in `phase`.
+pub fn add_exit_observer(vm_id: u32, phase: ObserverPhase, f: ExitObserverFn) -> ObserverId {
+    let id = ObserverId(NEXT_OBSERVER_ID.fetch_add(1, Ordering::Relaxed));
+    let observer = Arc::new(Observer {
+        id,
+        vm_id,
+        phase,
+        f,
+        overruns: AtomicU32::new(0),
+        disabled: AtomicBool::new(false),
+    });
+    let mut observers = OBSERVERS.write();
+    let mut list = observers.as_deref().cloned().unwrap_or_default();
+    list.push(observer);
+    ACTIVE_OBSERVERS.store(list.len(), Ordering::Release);
+    *observers = Some(Arc::new(list));
+    id
+}
+
+/// Remove the observers matching `filter`, and return how many were removed.
+fn remove_observers(filter: impl Fn(&Observer) -> bool) -> usize {
+    let mut observers = OBSERVERS.write();
+    let mut list = observers.as_deref().cloned().unwrap_or_default();
+    list.retain(|observer| !filter(observer));
+    let remaining = list.len();
+    let before = ACTIVE_OBSERVERS.swap(remaining, Ordering::Release);
+    *observers = Some(Arc::new(list));
+    before - remaining
+}
+
+/// Remove an observer. It may still run once on the exits being handled.
+pub fn remove_exit_observer(id: ObserverId) -> bool {
+    remove_observers(|observer| observer.id == id) != 0
+}
+
+/// Remove the observers of a VM which exited.
+pub(crate) fn remove_vm_exit_observers(vm_id: u32) {
+    remove_observers(|observer| observer.vm_id == vm_id);
+}
+
+/// Record the start of an exit, at `start_ns`, and notify the
+/// [`ObserverPhase::BeforeHandling`] observers.
+///
+/// The start is recorded even without observers, so that the first exit after one is added
+/// has its timestamp.
+#[inline]
+pub(crate) fn observe_exit_start(vcpu_id: usize, exit_info: &VmExitInfo, start_ns: u64) {
+    EXIT_START_NS[current_cpu_id()].store(start_ns, Ordering::Relaxed);
+    if ACTIVE_OBSERVERS.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    notify(vcpu_id, exit_info, ObserverPhase::BeforeHandling, None);
+}
+
+/// Notify the [`ObserverPhase::AfterHandling`] observers of the outcome of an exit.
+#[inline]
+pub(crate) fn observe_exit_end(vcpu_id: usize, exit_info: &VmExitInfo, result: &HyperResult) {
+    if ACTIVE_OBSERVERS.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let outcome = match result {
+        Ok(()) => ExitOutcome::Handled,
+        Err(_) => ExitOutcome::Failed,
+    };
+    notify(
+        vcpu_id,
+        exit_info,
+        ObserverPhase::AfterHandling,
+        Some(outcome),
+    );
+}
+
+#[cold]
+fn notify(
+    vcpu_id: usize,
+    exit_info: &VmExitInfo,
+    phase: ObserverPhase,
+    outcome: Option<ExitOutcome>,
+) {
+    let Some(vm_id) = crate::vm::current_vm_id() else {
+        return;
+    };
+    let Some(observers) = OBSERVERS.read().clone() else {
+        return;
+    };
+    let ctx = ObserverCtx {
+        vm_id,
+        vcpu_id,
+        phase,
+        exit_ns: EXIT_START_NS[current_cpu_id()].load(Ordering::Relaxed),
+        now_ns: 0,
+        outcome,
+    };
+    let skipped = run_observers(
+        &observers,
+        ctx,
+        axhal::time::current_time_nanos,
+        |f, ctx| f(exit_info, ctx),
+    );
+    if skipped > 0 {
+        ratelimited!(
+            OBSERVER_LOG,
+            log::Level::Warn,
+            "{} exit observers of VM [{}] skipped, budget {} ns used up",
+            skipped,
+            vm_id,
+            OBSERVER_BUDGET_NS
+        );
+    }
+}
+
+/// Call the observers of `observers` matching `ctx`, `clock` telling the time, through `call`,
+/// within the budget of the exit. Returns the number of observers skipped once the budget was
+/// used up.
+fn run_observers(
+    observers: &[Arc<Observer>],
+    mut ctx: ObserverCtx,
+    clock: impl Fn() -> u64,
+    call: impl Fn(&ExitObserverFn, &ObserverCtx),
+) -> usize {
+    let mut observers = observers.iter().filter(|observer| {
+        observer.vm_id == ctx.vm_id
+            && observer.phase == ctx.phase
+            && !observer.disabled.load(Ordering::Relaxed)
+    });
+    let first = clock();
+    let mut start = first;
+    for observer in observers.by_ref() {
+        ctx.now_ns = start;
+        call(&observer.f, &ctx);
+        let end = clock();
+        let spent = end.saturating_sub(start);
+        start = end;
+        if spent <= OBSERVER_BUDGET_NS {
+            observer.overruns.store(0, Ordering::Relaxed);
+        } else {
+            let overruns = observer.overruns.fetch_add(1, Ordering::Relaxed) + 1;
+            ratelimited!(
+                OBSERVER_LOG,
+                log::Level::Warn,
+                "exit observer {:?} of VM [{}] took {} ns, budget {} ns",
+                observer.id,
+                ctx.vm_id,
+                spent,
+                OBSERVER_BUDGET_NS
+            );
+            if overruns >= MAX_OVERRUNS {
+                observer.disabled.store(true, Ordering::Relaxed);
+                warn!(
+                    "exit observer {:?} of VM [{}] disabled after {} overruns in a row",
+                    observer.id, ctx.vm_id, overruns
+                );
+            }
+        }
+        if end.saturating_sub(first) >= OBSERVER_BUDGET_NS {
+            break;
+        }
+    }
+    observers.count()
+}
+
+/// Buckets of [`ExitLatencyHistogram`], bucket `i` counting latencies in `[2^i, 2^(i+1))` ns.
+pub const LATENCY_BUCKETS: usize = 32;
+/// Exit reasons tracked by [`ExitLatencyHistogram`], the basic exit reasons being below 80.
+const HISTOGRAM_REASONS: usize = 80;
+
+/// A sample observer: the histogram of the handling latency of the exits, per exit reason,
+/// registered with `vm.add_exit_observer(ObserverPhase::AfterHandling, histogram.observer())`.
+pub struct ExitLatencyHistogram {
+    buckets: [[AtomicU64; LATENCY_BUCKETS]; HISTOGRAM_REASONS],
+}
+
+impl ExitLatencyHistogram {
+    pub fn new() -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        const ZEROS: [AtomicU64; LATENCY_BUCKETS] = [ZERO; LATENCY_BUCKETS];
+        Self {
+            buckets: [ZEROS; HISTOGRAM_REASONS],
+        }
+    }
+
+    pub fn record(&self, exit_reason: usize, latency_ns: u64) {
+        let Some(buckets) = self.buckets.get(exit_reason) else {
+            return;
+        };
+        let bucket = (63 - latency_ns.max(1).leading_zeros() as usize).min(LATENCY_BUCKETS - 1);
+        buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The observer feeding this histogram, to register for [`ObserverPhase::AfterHandling`].
+    pub fn observer(self: &Arc<Self>) -> ExitObserverFn {
+        let histogram = self.clone();
+        Box::new(move |exit_info, ctx| histogram.observe(exit_info.exit_reason as usize, ctx))
+    }
+
+    /// Record the latency of an exit of `exit_reason`, from its start to `ctx`.
+    fn observe(&self, exit_reason: usize, ctx: &ObserverCtx) {
+        self.record(exit_reason, ctx.now_ns.saturating_sub(ctx.exit_ns));
+    }
+
+    /// The counts of the buckets of `exit_reason`.
+    pub fn snapshot(&self, exit_reason: usize) -> [u64; LATENCY_BUCKETS] {
+        let mut counts = [0; LATENCY_BUCKETS];
+        if let Some(buckets) = self.buckets.get(exit_reason) {
+            for (count, bucket) in counts.iter_mut().zip(buckets.iter()) {
+                *count = bucket.load(Ordering::Relaxed);
+            }
+        }
+        counts
+    }
+
+    /// Log the non-empty buckets of every exit reason.
+    pub fn dump(&self) {
+        for reason in 0..HISTOGRAM_REASONS {
+            let counts = self.snapshot(reason);
+            if counts.iter().all(|&count| count == 0) {
+                continue;
+            }
+            info!("exit reason {}:", reason);
+            for (bucket, &count) in counts.iter().enumerate().filter(|(_, &count)| count != 0) {
+                info!("  [{:>10} ns, ...): {}", 1u64 << bucket, count);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    fn observer(vm_id: u32, phase: ObserverPhase) -> Arc<Observer> {
+        Arc::new(Observer {
+            id: ObserverId(0),
+            vm_id,
+            phase,
+            f: Box::new(|_, _| {}),
+            overruns: AtomicU32::new(0),
+            disabled: AtomicBool::new(false),
+        })
+    }
+
+    fn ctx(vm_id: u32, phase: ObserverPhase) -> ObserverCtx {
+        ObserverCtx {
+            vm_id,
+            vcpu_id: 0,
+            phase,
+            exit_ns: 0,
+            now_ns: 0,
+            outcome: None,
+        }
+    }
+
+    /// Run `observers` for VM 1 before handling, the calls taking `costs` in turn. Returns the
+    /// number of observers skipped and the times at which the calls were made.
+    fn run(observers: &[Arc<Observer>], costs: &[u64]) -> (usize, Vec<u64>) {
+        let now = Cell::new(1_000);
+        let calls = core::cell::RefCell::new(Vec::new());
+        let skipped = run_observers(
+            observers,
+            ctx(1, ObserverPhase::BeforeHandling),
+            || now.get(),
+            |_, ctx| {
+                let mut calls = calls.borrow_mut();
+                now.set(now.get() + costs[calls.len()]);
+                calls.push(ctx.now_ns);
+            },
+        );
+        (skipped, calls.into_inner())
+    }
+
+    #[test]
+    fn test_observer_filter() {
+        let disabled = observer(1, ObserverPhase::BeforeHandling);
+        disabled.disabled.store(true, Ordering::Relaxed);
+        let observers = [
+            observer(2, ObserverPhase::BeforeHandling),
+            observer(1, ObserverPhase::AfterHandling),
+            disabled,
+            observer(1, ObserverPhase::BeforeHandling),
+        ];
+        assert_eq!(run(&observers, &[100]), (0, vec![1_000]));
+    }
+
+    #[test]
+    fn test_observer_budget() {
+        let observers: Vec<_> = (0..4)
+            .map(|_| observer(1, ObserverPhase::BeforeHandling))
+            .collect();
+        // The second observer uses the budget up, the last two are skipped for this exit.
+        let (skipped, calls) = run(&observers, &[5_000, 30_000]);
+        assert_eq!((skipped, calls), (2, vec![1_000, 6_000]));
+        assert_eq!(observers[0].overruns.load(Ordering::Relaxed), 0);
+        assert_eq!(observers[1].overruns.load(Ordering::Relaxed), 1);
+        // Within the budget, all of them run.
+        assert_eq!(run(&observers, &[1_000; 4]).0, 0);
+        assert_eq!(observers[1].overruns.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_observer_disabled() {
+        let slow = [observer(1, ObserverPhase::BeforeHandling)];
+        for _ in 0..MAX_OVERRUNS - 1 {
+            run(&slow, &[OBSERVER_BUDGET_NS + 1]);
+        }
+        // A call within the budget resets the overruns.
+        run(&slow, &[1]);
+        for _ in 0..MAX_OVERRUNS - 1 {
+            run(&slow, &[OBSERVER_BUDGET_NS + 1]);
+        }
+        assert!(!slow[0].disabled.load(Ordering::Relaxed));
+        run(&slow, &[OBSERVER_BUDGET_NS + 1]);
+        assert!(slow[0].disabled.load(Ordering::Relaxed));
+        assert_eq!(run(&slow, &[]), (0, vec![]));
+    }
+
+    #[test]
+    fn test_remove_observers() {
+        // A VM of its own, the list being global.
+        const VM: u32 = 7;
+        let before = ACTIVE_OBSERVERS.load(Ordering::Relaxed);
+        let first = add_exit_observer(VM, ObserverPhase::BeforeHandling, Box::new(|_, _| {}));
+        add_exit_observer(VM, ObserverPhase::AfterHandling, Box::new(|_, _| {}));
+        add_exit_observer(VM, ObserverPhase::AfterHandling, Box::new(|_, _| {}));
+        assert!(remove_exit_observer(first));
+        assert!(!remove_exit_observer(first));
+        assert_eq!(remove_observers(|observer| observer.vm_id == VM), 2);
+        assert_eq!(ACTIVE_OBSERVERS.load(Ordering::Relaxed), before);
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = ExitLatencyHistogram::new();
+        for latency in [0, 1, 2, 3, 1024, 2047, u64::MAX] {
+            histogram.record(10, latency);
+        }
+        let mut expected = [0; LATENCY_BUCKETS];
+        expected[0] = 2;
+        expected[1] = 2;
+        expected[10] = 2;
+        expected[LATENCY_BUCKETS - 1] = 1;
+        assert_eq!(histogram.snapshot(10), expected);
+
+        // From the start of the exit to the call of the observer.
+        let mut ctx = ctx(1, ObserverPhase::AfterHandling);
+        ctx.exit_ns = 1_000;
+        ctx.now_ns = 1_000 + 1_500;
+        histogram.observe(48, &ctx);
+        let mut expected = [0; LATENCY_BUCKETS];
+        expected[10] = 1;
+        assert_eq!(histogram.snapshot(48), expected);
+
+        // The reasons out of the histogram are ignored.
+        histogram.record(HISTOGRAM_REASONS, 1);
+        assert_eq!(histogram.snapshot(HISTOGRAM_REASONS), [0; LATENCY_BUCKETS]);
+    }
+}
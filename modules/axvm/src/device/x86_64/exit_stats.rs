@@ -0,0 +1,317 @@
+//! Where the VM exits of each vCPU go, for performance tuning.
+//!
+//! Every vCPU counts its exits and the time spent handling them in its [`ExitStats`], by exit
+//! reason, and keeps histograms of the I/O ports and the MSRs accessed, and the hits and misses of
+//! the MMIO decode cache, see [`decode_cache`](super::decode_cache), and of the last-hit device
+//! caches of the vCPU, see [`DispatchCache`](super::DispatchCache). An exit is counted once
+//! it is handled: by the per-vCPU devices, or by the per-VM devices when the per-vCPU ones left
+//! it to them, see [`defer_exit`] and [`finish_deferred_exit`].
+//!
+//! The counters are fixed-size tables, so the exit path never allocates. The statistics of the
+//! running vCPUs are dumped with [`dump_exit_stats`], from the host shell or by the guest with
+//! `HVC_EXIT_STATS_DUMP`.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use axconfig::SMP;
+use axhal::current_cpu_id;
+use bit_field::BitField;
+use spin::{Mutex, MutexGuard, RwLock};
+
+use super::vmexit::ExitContext;
+use crate::{HyperCraftHal, VCpu, VmExitInfo, VmxExitReason};
+
+/// Exit reasons counted, the basic exit reasons being below 80.
+const EXIT_REASONS: usize = 80;
+/// Distinct I/O ports and MSRs counted by each histogram, the others are counted together.
+const HISTOGRAM_KEYS: usize = 64;
+
+/// Exits of one exit reason.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExitReasonStats {
+    pub count: u64,
+    pub total_ns: u64,
+}
+
+/// Lookups of the decoded MMIO instructions in the decode cache.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Lookups of the devices of the exits in a last-hit device cache.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DispatchCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Counts of the I/O ports or MSRs accessed by the exits, in an open-addressing table.
+struct KeyHistogram {
+    slots: [Option<(u32, u64)>; HISTOGRAM_KEYS],
+    /// Accesses to the keys which did not fit in the table.
+    others: u64,
+}
+
+impl KeyHistogram {
+    const fn new() -> Self {
+        Self {
+            slots: [None; HISTOGRAM_KEYS],
+            others: 0,
+        }
+    }
+
+    fn record(&mut self, key: u32) {
+        let start = (key.wrapping_mul(0x9e37_79b9) >> 26) as usize;
+        for i in 0..HISTOGRAM_KEYS {
+            match &mut self.slots[(start + i) % HISTOGRAM_KEYS] {
+                Some((k, count)) if *k == key => {
+                    *count += 1;
+                    return;
+                }
+                slot @ None => {
+                    *slot = Some((key, 1));
+                    return;
+                }
+                Some(_) => {}
+            }
+        }
+        self.others += 1;
+    }
+
+    /// The keys and their counts, most accessed first.
+    fn sorted(&self) -> Vec<(u32, u64)> {
+        let mut keys: Vec<_> = self.slots.iter().flatten().copied().collect();
+        keys.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        keys
+    }
+}
+
+struct Counters {
+    reasons: [(Option<VmxExitReason>, ExitReasonStats); EXIT_REASONS],
+    io_ports: KeyHistogram,
+    msrs: KeyHistogram,
+    mmio_decodes: DecodeCacheStats,
+    /// As of the last exit handled by the per-vCPU and by the per-VM devices.
+    vcpu_dispatches: DispatchCacheStats,
+    vm_dispatches: DispatchCacheStats,
+}
+
+/// The VM exits of a vCPU, see the [module documentation](self).
+pub struct ExitStats {
+    vcpu_id: usize,
+    counters: Mutex<Counters>,
+}
+
+impl ExitStats {
+    pub(super) fn new(vcpu_id: usize) -> Self {
+        Self {
+            vcpu_id,
+            counters: Mutex::new(Counters {
+                reasons: [(None, ExitReasonStats::default()); EXIT_REASONS],
+                io_ports: KeyHistogram::new(),
+                msrs: KeyHistogram::new(),
+                mmio_decodes: DecodeCacheStats::default(),
+                vcpu_dispatches: DispatchCacheStats::default(),
+                vm_dispatches: DispatchCacheStats::default(),
+            }),
+        }
+    }
+
+    /// Count the current exit of `vcpu`, whose handling started at `start_ns`, handled by the
+    /// per-vCPU devices, whose device cache counted `dispatches` so far.
+    pub(super) fn record<H: HyperCraftHal>(
+        &self,
+        vcpu: &VCpu<H>,
+        exit_info: &VmExitInfo,
+        start_ns: u64,
+        dispatches: DispatchCacheStats,
+    ) {
+        self.count(vcpu, exit_info, start_ns).vcpu_dispatches = dispatches;
+    }
+
+    /// Count the current exit of `vcpu` in its reason and histogram, returning the counters.
+    fn count<H: HyperCraftHal>(
+        &self,
+        vcpu: &VCpu<H>,
+        exit_info: &VmExitInfo,
+        start_ns: u64,
+    ) -> MutexGuard<'_, Counters> {
+        let elapsed = axhal::time::current_time_nanos().saturating_sub(start_ns);
+        let reason = exit_info.exit_reason;
+        let mut counters = self.counters.lock();
+        if let Some((seen, stats)) = counters.reasons.get_mut(reason as usize) {
+            *seen = Some(reason);
+            stats.count += 1;
+            stats.total_ns += elapsed;
+        }
+        match reason {
+            VmxExitReason::IO_INSTRUCTION => {
+                // Captured when the exit was recorded, no VMCS read here.
+                let port = ExitContext::current(exit_info)
+                    .qualification
+                    .get_bits(16..32);
+                counters.io_ports.record(port as u32);
+            }
+            VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE => {
+                counters.msrs.record(vcpu.regs().rcx as u32);
+            }
+            _ => {}
+        }
+        counters
+    }
+
+    /// The exits of `reason` so far.
+    pub fn reason(&self, reason: VmxExitReason) -> ExitReasonStats {
+        let counters = self.counters.lock();
+        counters
+            .reasons
+            .get(reason as usize)
+            .map_or(ExitReasonStats::default(), |(_, stats)| *stats)
+    }
+
+    /// The lookups of the MMIO instructions of the exits in the decode cache so far.
+    pub fn mmio_decode_cache(&self) -> DecodeCacheStats {
+        self.counters.lock().mmio_decodes
+    }
+
+    /// The lookups of the devices of the exits in the device caches of the per-vCPU and of the
+    /// per-VM device lists so far.
+    pub fn dispatch_cache(&self) -> (DispatchCacheStats, DispatchCacheStats) {
+        let counters = self.counters.lock();
+        (counters.vcpu_dispatches, counters.vm_dispatches)
+    }
+
+    /// Log the exit reasons, slowest in total first, the most accessed ports and MSRs, and the
+    /// MMIO decode and device cache hits.
+    pub fn dump(&self) {
+        let (mut reasons, io_ports, msrs, mmio_decodes, dispatches) = {
+            let counters = self.counters.lock();
+            let reasons: Vec<_> = counters
+                .reasons
+                .iter()
+                .filter_map(|&(reason, stats)| Some((reason?, stats)))
+                .collect();
+            (
+                reasons,
+                (counters.io_ports.sorted(), counters.io_ports.others),
+                (counters.msrs.sorted(), counters.msrs.others),
+                counters.mmio_decodes,
+                [
+                    ("vCPU", counters.vcpu_dispatches),
+                    ("VM", counters.vm_dispatches),
+                ],
+            )
+        };
+        reasons.sort_unstable_by(|a, b| b.1.total_ns.cmp(&a.1.total_ns));
+        info!("vCPU {} VM exits:", self.vcpu_id);
+        info!(
+            "  {:<24} {:>10} {:>14} {:>10}",
+            "reason", "count", "total ns", "avg ns"
+        );
+        for (reason, stats) in reasons {
+            info!(
+                "  {:<24} {:>10} {:>14} {:>10}",
+                alloc::format!("{:?}", reason),
+                stats.count,
+                stats.total_ns,
+                stats.total_ns / stats.count.max(1)
+            );
+        }
+        for (name, (keys, others)) in [("I/O port", io_ports), ("MSR", msrs)] {
+            if keys.is_empty() {
+                continue;
+            }
+            info!("  {} exits:", name);
+            for (key, count) in keys {
+                info!("    {:#10x} {:>10}", key, count);
+            }
+            if others != 0 {
+                info!("    {:>10} {:>10}", "others", others);
+            }
+        }
+        if mmio_decodes.hits + mmio_decodes.misses != 0 {
+            info!(
+                "  MMIO decode cache: {} hits, {} misses",
+                mmio_decodes.hits, mmio_decodes.misses
+            );
+        }
+        for (level, stats) in dispatches {
+            if stats.hits + stats.misses != 0 {
+                info!(
+                    "  {} device cache: {} hits, {} misses",
+                    level, stats.hits, stats.misses
+                );
+            }
+        }
+    }
+}
+
+const NO_DEFERRED_EXIT: Mutex<Option<(Arc<ExitStats>, u64)>> = Mutex::new(None);
+/// The statistics and start time of the exit left to the per-VM devices on each physical CPU.
+static DEFERRED_EXIT: [Mutex<Option<(Arc<ExitStats>, u64)>>; SMP] = [NO_DEFERRED_EXIT; SMP];
+
+/// Count the current exit, declined by the per-vCPU devices, in `stats` once the per-VM devices
+/// handled it.
+pub(super) fn defer_exit(stats: &Arc<ExitStats>, start_ns: u64) {
+    *DEFERRED_EXIT[current_cpu_id()].lock() = Some((stats.clone(), start_ns));
+}
+
+/// Count the exit left to the per-VM devices, which handled it, their device cache having counted
+/// `dispatches` so far.
+pub(super) fn finish_deferred_exit<H: HyperCraftHal>(
+    vcpu: &VCpu<H>,
+    exit_info: &VmExitInfo,
+    dispatches: DispatchCacheStats,
+) {
+    let deferred = DEFERRED_EXIT[current_cpu_id()].lock().take();
+    if let Some((stats, start_ns)) = deferred {
+        stats.count(vcpu, exit_info, start_ns).vm_dispatches = dispatches;
+    }
+}
+
+/// Count a lookup of the MMIO instruction of the exit left to the per-VM devices in the decode
+/// cache, a hit if `hit`.
+pub(super) fn count_mmio_decode(hit: bool) {
+    let deferred = DEFERRED_EXIT[current_cpu_id()].lock();
+    if let Some((stats, _)) = deferred.as_ref() {
+        let mut counters = stats.counters.lock();
+        if hit {
+            counters.mmio_decodes.hits += 1;
+        } else {
+            counters.mmio_decodes.misses += 1;
+        }
+    }
+}
+
+/// The statistics of the vCPUs which entered their guest, by VM and vCPU id.
+static REGISTERED: RwLock<BTreeMap<(u32, usize), Arc<ExitStats>>> = RwLock::new(BTreeMap::new());
+
+/// Make the statistics of vCPU `vcpu_id` of `vm_id` available to [`dump_exit_stats`].
+pub(super) fn register_exit_stats(vm_id: u32, stats: &Arc<ExitStats>) {
+    REGISTERED
+        .write()
+        .insert((vm_id, stats.vcpu_id), stats.clone());
+}
+
+/// Forget the statistics of the vCPUs of a VM which stopped.
+pub(crate) fn remove_vm_exit_stats(vm_id: u32) {
+    REGISTERED.write().retain(|&(vm, _), _| vm != vm_id);
+}
+
+/// Log the exit statistics of every vCPU of VM `vm_id`, returns whether it has any.
+pub fn dump_exit_stats(vm_id: u32) -> bool {
+    let vcpus: Vec<Arc<ExitStats>> = REGISTERED
+        .read()
+        .range((vm_id, 0)..=(vm_id, usize::MAX))
+        .map(|(_, stats)| stats.clone())
+        .collect();
+    info!("VM [{}] exit statistics:", vm_id);
+    for stats in vcpus.iter() {
+        stats.dump();
+    }
+    !vcpus.is_empty()
+}
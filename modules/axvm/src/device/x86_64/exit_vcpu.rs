@@ -0,0 +1,66 @@
+//! The operations of a vCPU used by the exit handlers of the device lists.
+//!
+//! The port I/O and MSR handlers only touch the general-purpose registers, the RIP and the event
+//! queue of the vCPU, so they are written against [`ExitVcpu`] rather than [`VCpu`]: they can
+//! run on any model of these, without VMX.
+
+use hypercraft::GeneralRegisters;
+
+use crate::{HyperCraftHal, Result as HyperResult, VCpu};
+
+pub trait ExitVcpu {
+    fn regs(&self) -> &GeneralRegisters;
+    fn regs_mut(&mut self) -> &mut GeneralRegisters;
+    /// Skip the `instr_len` bytes of the instruction which exited.
+    fn advance_rip(&mut self, instr_len: u8) -> HyperResult;
+    /// Inject exception or interrupt `vector` at the next VM entry.
+    fn queue_event(&mut self, vector: u8, err_code: Option<u32>);
+}
+
+impl<H: HyperCraftHal> ExitVcpu for VCpu<H> {
+    fn regs(&self) -> &GeneralRegisters {
+        VCpu::regs(self)
+    }
+
+    fn regs_mut(&mut self) -> &mut GeneralRegisters {
+        VCpu::regs_mut(self)
+    }
+
+    fn advance_rip(&mut self, instr_len: u8) -> HyperResult {
+        VCpu::advance_rip(self, instr_len)
+    }
+
+    fn queue_event(&mut self, vector: u8, err_code: Option<u32>) {
+        VCpu::queue_event(self, vector, err_code)
+    }
+}
+
+/// A vCPU of plain state, for the handlers run without VMX: its RIP is advanced and its events
+/// queued in order, none of them being injected.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MockVcpu {
+    pub regs: GeneralRegisters,
+    pub rip: u64,
+    pub events: alloc::vec::Vec<(u8, Option<u32>)>,
+}
+
+#[cfg(test)]
+impl ExitVcpu for MockVcpu {
+    fn regs(&self) -> &GeneralRegisters {
+        &self.regs
+    }
+
+    fn regs_mut(&mut self) -> &mut GeneralRegisters {
+        &mut self.regs
+    }
+
+    fn advance_rip(&mut self, instr_len: u8) -> HyperResult {
+        self.rip += instr_len as u64;
+        Ok(())
+    }
+
+    fn queue_event(&mut self, vector: u8, err_code: Option<u32>) {
+        self.events.push((vector, err_code));
+    }
+}
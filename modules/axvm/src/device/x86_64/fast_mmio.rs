@@ -0,0 +1,374 @@
+//! MMIO emulation without the instruction decoder, for the hottest MMIO pages.
+//!
+//! Guests touch the local APIC and the HPET with plain `mov`s, an EOI is a single store, yet every
+//! MMIO exit went through the full decode of [`Instruction`] and the emulation of every supported
+//! mnemonic. The handlers added by [`DeviceList::add_fast_mmio_device`] take the decoded access
+//! instead: the few bytes at guest RIP are peeked, and the common `mov` forms are recognised from
+//! their opcode and ModRM bytes alone. Any other instruction, or bytes which cannot be peeked, go
+//! the full decode path, on the same handler. Either way the result goes to the
+//! [`decode_cache`](super::decode_cache), which the next exit at the same RIP is emulated from.
+//!
+//! [`Instruction`]: iced_x86::Instruction
+//! [`DeviceList::add_fast_mmio_device`]: super::DeviceList::add_fast_mmio_device
+
+use alloc::sync::Arc;
+use core::ops::Range;
+
+use bit_field::BitField;
+use hypercraft::MmioOps;
+use iced_x86::Register;
+
+use super::Operand;
+use crate::device::MmioAccess;
+use crate::Result as HyperResult;
+
+/// Performs a decoded MMIO access and returns the value read, 0 for a write.
+pub type FastMmioHandler = Arc<dyn Fn(MmioAccess) -> HyperResult<u64> + Send + Sync>;
+
+/// The general-purpose registers by ModRM number, for 16, 32 and 64-bit operands. RSP is `None`,
+/// it lives in the VMCS.
+const GPRS_16: [Option<Register>; 16] = gprs([
+    Register::AX,
+    Register::CX,
+    Register::DX,
+    Register::BX,
+    Register::SP,
+    Register::BP,
+    Register::SI,
+    Register::DI,
+    Register::R8W,
+    Register::R9W,
+    Register::R10W,
+    Register::R11W,
+    Register::R12W,
+    Register::R13W,
+    Register::R14W,
+    Register::R15W,
+]);
+const GPRS_32: [Option<Register>; 16] = gprs([
+    Register::EAX,
+    Register::ECX,
+    Register::EDX,
+    Register::EBX,
+    Register::ESP,
+    Register::EBP,
+    Register::ESI,
+    Register::EDI,
+    Register::R8D,
+    Register::R9D,
+    Register::R10D,
+    Register::R11D,
+    Register::R12D,
+    Register::R13D,
+    Register::R14D,
+    Register::R15D,
+]);
+const GPRS_64: [Option<Register>; 16] = gprs([
+    Register::RAX,
+    Register::RCX,
+    Register::RDX,
+    Register::RBX,
+    Register::RSP,
+    Register::RBP,
+    Register::RSI,
+    Register::RDI,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+]);
+
+const fn gprs(regs: [Register; 16]) -> [Option<Register>; 16] {
+    let mut gprs = [None; 16];
+    let mut i = 0;
+    while i < 16 {
+        if i != 4 {
+            gprs[i] = Some(regs[i]);
+        }
+        i += 1;
+    }
+    gprs
+}
+
+/// A `mov` between a register or an immediate and memory.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct FastMov {
+    pub len: u8,
+    pub access_size: u8,
+    /// The register loaded, or the register or immediate stored.
+    pub operand: Operand,
+    pub is_write: bool,
+}
+
+/// Decode `bytes` if they start with one of the `mov` forms taken by the fast path, for code of
+/// `bitness` 32 or 64:
+///
+/// * `mov r/m, r` (89) and `mov r, r/m` (8b), with 16, 32 or 64-bit operands;
+/// * `mov r/m, imm` (c7 /0), the immediate being sign-extended to 64-bit operands.
+///
+/// The operand size prefix, the segment overrides and REX are accepted, the memory operand itself
+/// is only skipped over as the address comes from the EPT violation. 16-bit code, whose operands
+/// and ModR/M differ, is left to the decoder.
+pub(super) fn decode_mov(bytes: &[u8], bitness: u32) -> Option<FastMov> {
+    if bitness == 16 {
+        return None;
+    }
+    let long_mode = bitness == 64;
+    let mut pos = 0;
+    let mut operand_16 = false;
+    loop {
+        match *bytes.get(pos)? {
+            0x66 => operand_16 = true,
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 => {}
+            _ => break,
+        }
+        pos += 1;
+    }
+    // In 32-bit code, 40 to 4f are `inc` and `dec`.
+    let rex = match *bytes.get(pos)? {
+        rex @ 0x40..=0x4f if long_mode => {
+            pos += 1;
+            rex
+        }
+        _ => 0,
+    };
+    let opcode = *bytes.get(pos)?;
+    let modrm = *bytes.get(pos + 1)?;
+    pos += 2;
+    let (mode, reg_field, rm) = (modrm >> 6, (modrm >> 3) & 7, modrm & 7);
+    if mode == 3 {
+        // A register operand, this is not the access which faulted.
+        return None;
+    }
+    if rm == 4 {
+        let sib = *bytes.get(pos)?;
+        pos += 1;
+        if mode == 0 && sib & 7 == 5 {
+            pos += 4;
+        }
+    }
+    pos += match (mode, rm) {
+        // disp32, RIP-relative in 64-bit code.
+        (0, 5) => 4,
+        (1, _) => 1,
+        (2, _) => 4,
+        _ => 0,
+    };
+    let (access_size, gprs) = if rex.get_bit(3) {
+        (8, &GPRS_64)
+    } else if operand_16 {
+        (2, &GPRS_16)
+    } else {
+        (4, &GPRS_32)
+    };
+    let reg = gprs[(reg_field | (rex & 0x4) << 1) as usize];
+    let (operand, is_write) = match opcode {
+        0x89 => (Operand::Register(reg?), true),
+        0x8b => (Operand::Register(reg?), false),
+        0xc7 if reg_field == 0 => {
+            let imm = match access_size {
+                2 => u16::from_le_bytes(bytes.get(pos..pos + 2)?.try_into().ok()?) as u64,
+                _ => i32::from_le_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?) as i64 as u64,
+            };
+            pos += if access_size == 2 { 2 } else { 4 };
+            (Operand::Immediate(imm), true)
+        }
+        _ => return None,
+    };
+    // The displacement must have been peeked too.
+    (pos <= bytes.len()).then_some(FastMov {
+        len: pos as u8,
+        access_size,
+        operand,
+        is_write,
+    })
+}
+
+/// A fast MMIO handler as a [`MmioOps`] device, for the accesses which need the full decode.
+pub(super) struct FastMmioDevice {
+    range: Range<u64>,
+    handler: FastMmioHandler,
+}
+
+impl FastMmioDevice {
+    pub fn new(range: Range<u64>, handler: FastMmioHandler) -> Self {
+        Self { range, handler }
+    }
+}
+
+impl MmioOps for FastMmioDevice {
+    fn mmio_range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+
+    fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+        (self.handler)(MmioAccess {
+            addr,
+            access_size,
+            write: None,
+        })
+    }
+
+    fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+        (self.handler)(MmioAccess {
+            addr,
+            access_size,
+            write: Some(value),
+        })
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use iced_x86::{Decoder, DecoderOptions, Instruction, OpKind};
+
+    /// Displacements and immediates, the immediate having its sign bit set.
+    const TAIL: [u8; 12] = [
+        0x78, 0x56, 0x34, 0x12, 0x00, 0x00, 0x00, 0x80, 0x90, 0x90, 0x90, 0x90,
+    ];
+
+    fn decode(bitness: u32, bytes: &[u8]) -> Instruction {
+        Decoder::new(bitness, bytes, DecoderOptions::NONE).decode()
+    }
+
+    /// Every `mov` form of [`decode_mov`] with a memory operand, with and without prefixes and
+    /// REX, and the forms it leaves to the decoder with the same bytes: 88, and c7 /1 to /7.
+    fn movs(bitness: u32) -> Vec<Vec<u8>> {
+        let prefixes: [&[u8]; 4] = [&[], &[0x66], &[0x3e], &[0x66, 0x64]];
+        let rexes: &[&[u8]] = match bitness {
+            64 => &[&[], &[0x40], &[0x48], &[0x44], &[0x4c], &[0x41], &[0x49]],
+            _ => &[&[]],
+        };
+        let mut movs = Vec::new();
+        for prefix in prefixes {
+            for rex in rexes {
+                for opcode in [0x89, 0x8b, 0xc7, 0x88] {
+                    for modrm in 0..0xc0u8 {
+                        let sibs: &[&[u8]] = match modrm & 7 {
+                            4 => &[&[0x24], &[0x25], &[0x88]],
+                            _ => &[&[]],
+                        };
+                        for sib in sibs {
+                            let mut bytes = [prefix, rex].concat();
+                            bytes.extend([opcode, modrm]);
+                            bytes.extend_from_slice(sib);
+                            bytes.extend_from_slice(&TAIL);
+                            movs.push(bytes);
+                        }
+                    }
+                }
+            }
+        }
+        movs
+    }
+
+    /// Whether `instr` is one of the forms of [`decode_mov`], with a register other than RSP.
+    fn is_fast_mov(instr: &Instruction) -> bool {
+        use iced_x86::Code::*;
+        let sp = (0..instr.op_count()).any(|i| {
+            instr.op_kind(i) == OpKind::Register
+                && [Register::SP, Register::ESP, Register::RSP].contains(&instr.op_register(i))
+        });
+        let code = matches!(
+            instr.code(),
+            Mov_rm16_r16
+                | Mov_rm32_r32
+                | Mov_rm64_r64
+                | Mov_r16_rm16
+                | Mov_r32_rm32
+                | Mov_r64_rm64
+                | Mov_rm16_imm16
+                | Mov_rm32_imm32
+                | Mov_rm64_imm32
+        );
+        code && !sp
+    }
+
+    /// Check `decode_mov` against iced on `bytes`.
+    fn check(bitness: u32, bytes: &[u8]) {
+        let instr = decode(bitness, bytes);
+        let mov = decode_mov(bytes, bitness);
+        assert_eq!(mov.is_some(), is_fast_mov(&instr), "{bytes:02x?}: {instr}");
+        let Some(mov) = mov else {
+            return;
+        };
+        assert_eq!(mov.len as usize, instr.len(), "{bytes:02x?}: {instr}");
+        assert_eq!(
+            mov.access_size as usize,
+            instr.memory_size().size(),
+            "{bytes:02x?}: {instr}"
+        );
+        assert_eq!(
+            mov.is_write,
+            instr.op0_kind() == OpKind::Memory,
+            "{bytes:02x?}: {instr}"
+        );
+        let other = if mov.is_write { 1 } else { 0 };
+        match mov.operand {
+            Operand::Register(reg) => assert_eq!(reg, instr.op_register(other), "{instr}"),
+            Operand::Immediate(imm) => {
+                let mask = u64::MAX >> (64 - 8 * mov.access_size as u32);
+                assert_eq!(imm & mask, instr.immediate(other) & mask, "{instr}")
+            }
+        }
+        // The instruction must have been peeked whole.
+        assert!(decode_mov(&bytes[..mov.len as usize - 1], bitness).is_none());
+    }
+
+    #[test]
+    fn test_decode_mov() {
+        for bitness in [32, 64] {
+            for bytes in movs(bitness) {
+                check(bitness, &bytes);
+            }
+        }
+        // 16-bit code, and register operands.
+        assert!(decode_mov(&[0x89, 0x07], 16).is_none());
+        assert!(decode_mov(&[0x89, 0xc0], 64).is_none());
+        // In 32-bit code, 48 is `dec eax`.
+        assert!(decode_mov(&[0x48, 0x89, 0x07], 32).is_none());
+    }
+
+    /// The decode of an EOI, a store to the local APIC, by [`decode_mov`] and by iced,
+    /// `cargo test -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_eoi_decode() {
+        extern crate std;
+        use core::hint::black_box;
+        use std::time::Instant;
+
+        const ITERATIONS: u32 = 1_000_000;
+        // mov [rdx + 0xb0], eax and mov dword ptr [rax + 0xb0], 0
+        let eois: [&[u8]; 2] = [
+            &[0x89, 0x82, 0xb0, 0x00, 0x00, 0x00, 0x90, 0x90],
+            &[0xc7, 0x80, 0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        ];
+        for eoi in eois {
+            let start = Instant::now();
+            for _ in 0..ITERATIONS {
+                black_box(decode_mov(black_box(eoi), 64).unwrap());
+            }
+            let fast = start.elapsed();
+            let start = Instant::now();
+            for _ in 0..ITERATIONS {
+                let instr = decode(64, black_box(eoi));
+                black_box(super::super::mmio_instr(&instr, true).unwrap());
+            }
+            let full = start.elapsed();
+            std::println!(
+                "{}: {} ns per decode, {} ns with iced",
+                decode(64, eoi),
+                fast.as_nanos() / ITERATIONS as u128,
+                full.as_nanos() / ITERATIONS as u128
+            );
+        }
+    }
+}
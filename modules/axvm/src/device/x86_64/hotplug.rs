@@ -0,0 +1,100 @@
+//! Port I/O and MMIO devices attached to, and detached from, a running VM.
+//!
+//! A device attached from the host is added to the tables of the per-VM device list of the VM,
+//! the one its vCPUs share, see [`vm_devices`], as at creation, and every vCPU finds it at its
+//! next lookup, the exits in progress completing on the previous tables. The per-vCPU lists are
+//! never changed: a range they claim stays answered by them, and attaching a device there is
+//! warned about, see [`dispatch`](super::dispatch).
+//!
+//! This is not PCI hotplug, the guest is not told about the device and reaches it through the
+//! exits of its range only.
+
+use alloc::sync::Arc;
+
+use hypercraft::{MmioOps, PioOps};
+use lock_stat::Mutex;
+
+use super::{dispatch, vm_devices, SharedTables};
+use crate::config::entry::vm_cfg_entry;
+use crate::{Error as HyperError, Result as HyperResult};
+
+/// A device attached to, or detached from, a running VM.
+pub enum HotplugDevice {
+    PortIo(Arc<Mutex<dyn PioOps>>),
+    MemoryIo(Arc<Mutex<dyn MmioOps>>),
+}
+
+/// A port or an address claimed by the device to detach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugAddress {
+    Port(u16),
+    Memory(u64),
+}
+
+fn vm_tables(vm_id: u32) -> HyperResult<Arc<SharedTables>> {
+    vm_devices::tables(vm_id).ok_or_else(|| {
+        warn!("VM [{}] has no per-VM devices", vm_id);
+        HyperError::BadState
+    })
+}
+
+/// Add `device` to the per-VM devices of `vm_id`. Fails with `BadState` if the VM has none, and
+/// with `InvalidParam` if the range of the device is empty, overlaps another device or a mapped
+/// BAR, or for a MMIO device a memory region of the VM, whose accesses do not exit.
+pub(crate) fn attach_device(vm_id: u32, device: HotplugDevice) -> HyperResult {
+    let tables = vm_tables(vm_id)?;
+    let cfg = vm_cfg_entry(vm_id as usize);
+    tables.try_update(|tables| match device {
+        HotplugDevice::PortIo(device) => {
+            let range = device.lock().port_range();
+            if let Some(bar) = tables
+                .pio_bars
+                .overlapping(&(range.start as u64..range.end as u64))
+            {
+                warn!("port I/O device {:#x?} overlaps BAR {:#x?}", range, bar);
+                return Err(HyperError::InvalidParam);
+            }
+            tables.add_port_io_device(device)
+        }
+        HotplugDevice::MemoryIo(device) => {
+            let range = device.lock().mmio_range();
+            let region = cfg
+                .as_ref()
+                .and_then(|cfg| cfg.overlapping_region(range.start as usize..range.end as usize));
+            if let Some(region) = region {
+                warn!(
+                    "MMIO device {:#x?} overlaps memory region {}",
+                    range, region
+                );
+                return Err(HyperError::InvalidParam);
+            }
+            if let Some(bar) = tables.mmio_bars.overlapping(&range) {
+                warn!("MMIO device {:#x?} overlaps BAR {:#x?}", range, bar);
+                return Err(HyperError::InvalidParam);
+            }
+            tables.add_memory_io_device(device)
+        }
+    })?;
+    dispatch::update_vm_ranges(vm_id, tables.snapshot().claimed_ranges());
+    Ok(())
+}
+
+/// Remove and return the per-VM device of `vm_id` claiming `address`. Fails with `BadState` if
+/// the VM has no per-VM devices, or as [`DeviceList::remove_port_io_device`](super::DeviceList).
+pub(crate) fn detach_device(vm_id: u32, address: HotplugAddress) -> HyperResult<HotplugDevice> {
+    let tables = vm_tables(vm_id)?;
+    let mut removed = None;
+    tables.try_update(|tables| {
+        removed = Some(match address {
+            HotplugAddress::Port(port) => {
+                HotplugDevice::PortIo(tables.remove_port_io_device(port)?)
+            }
+            HotplugAddress::Memory(addr) => {
+                HotplugDevice::MemoryIo(tables.remove_memory_io_device(addr)?)
+            }
+        });
+        Ok(())
+    })?;
+    dispatch::update_vm_ranges(vm_id, tables.snapshot().claimed_ranges());
+    removed.ok_or(HyperError::BadState)
+}
@@ -0,0 +1,372 @@
+//! Interprocessor interrupts between the vCPUs of a guest VM, sent through the x2APIC ICR.
+//!
+//! The local APIC of a guest vCPU is the one of its physical CPU, see `ProxyLocalApic`, but the
+//! guest addresses its vCPUs by their ids: the APIC id of a vCPU is its vCPU id and its logical
+//! id is derived from it as in x2APIC cluster mode. An ICR write is decoded and each target is
+//! translated to the physical CPU running it:
+//!
+//! - fixed and lowest priority IPIs are sent with the same vector to the target CPUs, lowest
+//!   priority being delivered to every target. A vCPU with a virtual local APIC gets the vector
+//!   posted to it instead, see [`register_virtual_apic`], and its CPU is kicked out of the guest
+//!   to take it;
+//! - INIT and STARTUP implement the INIT-SIPI-SIPI sequence for the APs, which boot in the
+//!   wait-for-SIPI state, see [`register_aps`] and [`wait_for_sipi`];
+//! - NMIs, which carry the messages of the hypervisor, SMIs and the reserved modes are dropped.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axconfig::SMP;
+use axhal::current_cpu_id;
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use log::Level;
+use spin::Mutex;
+use x86::bits64::vmx::vmwrite;
+use x86::msr::{wrmsr, IA32_X2APIC_ICR};
+use x86::vmx::vmcs;
+
+use super::device_emu::PendingVectors;
+use crate::nmi::{NmiMessage, NmiRequest};
+use crate::ratelimit::RateLimiter;
+
+static IPI_LOG: RateLimiter = RateLimiter::new("guest ipi", 10);
+
+/// Fields of the x2APIC ICR, SDM Vol. 3A, Section 11.12.9.
+const ICR_VECTOR_MASK: u64 = 0xff;
+const ICR_DELIVERY_MODE_SHIFT: u64 = 8;
+const ICR_LOGICAL: u64 = 1 << 11;
+const ICR_LEVEL_ASSERT: u64 = 1 << 14;
+const ICR_TRIGGER_LEVEL: u64 = 1 << 15;
+const ICR_SHORTHAND_SHIFT: u64 = 18;
+const ICR_DEST_SHIFT: u64 = 32;
+
+const DELIVERY_FIXED: u64 = 0;
+const DELIVERY_LOWEST_PRIORITY: u64 = 1;
+const DELIVERY_INIT: u64 = 5;
+const DELIVERY_STARTUP: u64 = 6;
+
+const SHORTHAND_NONE: u64 = 0;
+const SHORTHAND_SELF: u64 = 1;
+const SHORTHAND_ALL: u64 = 2;
+const SHORTHAND_ALL_BUT_SELF: u64 = 3;
+
+/// Destination addressing every vCPU.
+const BROADCAST: u32 = u32::MAX;
+
+/// The x2APIC logical id of a vCPU: cluster `id / 16`, bit `id % 16` of the cluster.
+pub(crate) fn logical_apic_id(vcpu_id: u32) -> u32 {
+    ((vcpu_id >> 4) << 16) | (1 << (vcpu_id & 0xf))
+}
+
+/// Where an AP of a guest is in its startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApState {
+    /// Created, waiting for a STARTUP IPI.
+    WaitForSipi,
+    /// Released by a STARTUP IPI with this vector, not yet running.
+    Started(u8),
+}
+
+lazy_static! {
+    /// The APs of the guests which have not started running yet, by VM and vCPU id.
+    static ref AP_STATES: Mutex<HashMap<(u32, u32), ApState>> = Mutex::new(HashMap::new());
+}
+/// Size of [`AP_STATES`], lets `check_events` skip the lookup.
+static WAITING_APS: AtomicUsize = AtomicUsize::new(0);
+
+/// Have vCPUs `vcpu_ids` of `vm_id` wait for their STARTUP IPI before their first VM entry.
+pub(crate) fn register_aps(vm_id: u32, vcpu_ids: impl Iterator<Item = u32>) {
+    let mut states = AP_STATES.lock();
+    for vcpu_id in vcpu_ids {
+        states.insert((vm_id, vcpu_id), ApState::WaitForSipi);
+    }
+    WAITING_APS.store(states.len(), Ordering::Release);
+}
+
+/// Forget the APs of `vm_id` which never started, once the VM stopped.
+pub(crate) fn unregister_vm_aps(vm_id: u32) {
+    let mut states = AP_STATES.lock();
+    states.retain(|&(vm, _), _| vm != vm_id);
+    WAITING_APS.store(states.len(), Ordering::Release);
+}
+
+/// Whether vCPU `vcpu_id` of `vm_id` is an AP which has not started running yet.
+pub(super) fn waits_for_sipi(vm_id: u32, vcpu_id: u32) -> bool {
+    WAITING_APS.load(Ordering::Acquire) != 0 && AP_STATES.lock().contains_key(&(vm_id, vcpu_id))
+}
+
+/// Block vCPU `vcpu_id` of `vm_id`, on the current CPU, until a STARTUP IPI releases it, and
+/// return the vector of the IPI. `None` at once if the vCPU is not an AP waiting to start, or
+/// once its VM has to stop.
+pub(super) fn wait_for_sipi(vm_id: u32, vcpu_id: u32) -> Option<u8> {
+    if WAITING_APS.load(Ordering::Acquire) == 0 {
+        return None;
+    }
+    loop {
+        {
+            let mut states = AP_STATES.lock();
+            match states.get(&(vm_id, vcpu_id)) {
+                None => return None,
+                Some(&ApState::Started(vector)) => {
+                    states.remove(&(vm_id, vcpu_id));
+                    WAITING_APS.store(states.len(), Ordering::Release);
+                    return Some(vector);
+                }
+                Some(ApState::WaitForSipi) => {}
+            }
+        }
+        if crate::vm::stop_requested() {
+            return None;
+        }
+        // The memory set of the VM may change meanwhile, e.g. for a fork.
+        crate::mm::handle_pending_invalidation();
+        // Woken by `startup` and by the stop requests.
+        crate::park::park(None);
+    }
+}
+
+/// The vCPUs with a virtual local APIC, by VM and vCPU id, and the vectors posted to it.
+static VIRTUAL_APICS: Mutex<BTreeMap<(u32, u32), Arc<PendingVectors>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Have the interrupts sent to vCPU `vcpu_id` of `vm_id` posted to `pending`, the vectors of
+/// its virtual local APIC, rather than sent to its CPU.
+pub(super) fn register_virtual_apic(vm_id: u32, vcpu_id: u32, pending: Arc<PendingVectors>) {
+    VIRTUAL_APICS.lock().insert((vm_id, vcpu_id), pending);
+}
+
+/// Forget the virtual local APICs of `vm_id`, once the VM stopped.
+pub(crate) fn unregister_vm_virtual_apics(vm_id: u32) {
+    VIRTUAL_APICS.lock().retain(|&(vm, _), _| vm != vm_id);
+}
+
+/// Post `vector` to the virtual local APIC of vCPU `vcpu_id` of `vm_id`, and have its CPU take
+/// it. `false` if the vCPU has no virtual local APIC.
+fn post_interrupt(vm_id: u32, vcpu_id: u32, vector: u8) -> bool {
+    let Some(pending) = VIRTUAL_APICS.lock().get(&(vm_id, vcpu_id)).cloned() else {
+        return false;
+    };
+    // Posted before the kick, the vCPU goes through `check_events` before entering the guest.
+    pending.post(vector);
+    let cpu = crate::vm::vcpu2pcpu(vm_id, vcpu_id).map(|cpu| cpu as usize);
+    if let Some(cpu) = cpu.filter(|&cpu| cpu < SMP && cpu != current_cpu_id()) {
+        if crate::vm::cpu_running_vm(cpu) == Some(vm_id) {
+            let msg = NmiMessage {
+                vm_id,
+                vcpu_id,
+                request: NmiRequest::KickVcpu,
+            };
+            crate::nmi::send_to_cpu(cpu, msg);
+        }
+        // A halted vCPU is parked in the hypervisor and does not see the NMI.
+        crate::park::wake_cpu(cpu);
+    }
+    true
+}
+
+/// Release an AP waiting for its STARTUP IPI.
+fn startup(vm_id: u32, vcpu_id: u32, vector: u8) {
+    {
+        let mut states = AP_STATES.lock();
+        match states.get_mut(&(vm_id, vcpu_id)) {
+            Some(state @ ApState::WaitForSipi) => *state = ApState::Started(vector),
+            // The second SIPI of the sequence, or a vCPU already running.
+            _ => return,
+        }
+    }
+    debug!(
+        "VM [{}] vCPU {} started at {:#x}",
+        vm_id,
+        vcpu_id,
+        (vector as u32) << 12
+    );
+    crate::park::wake_vcpu(vm_id, vcpu_id);
+}
+
+/// Send a fixed IPI with `vector` to the physical CPU of the vCPU, or post it to its virtual
+/// local APIC.
+fn deliver_fixed(vm_id: u32, vcpu_id: u32, vector: u8) {
+    if post_interrupt(vm_id, vcpu_id, vector) {
+        return;
+    }
+    match crate::vm::vcpu2pcpu(vm_id, vcpu_id) {
+        // Physical destination mode, fixed delivery, edge triggered.
+        Some(cpu) => {
+            unsafe {
+                wrmsr(
+                    IA32_X2APIC_ICR,
+                    ((cpu as u64) << ICR_DEST_SHIFT) | vector as u64,
+                )
+            };
+            // A halted vCPU is parked in the hypervisor, it has to be back in the guest to take
+            // the interrupt.
+            if (cpu as usize) < SMP {
+                crate::park::wake_cpu(cpu as usize);
+            }
+        }
+        None => ratelimited!(
+            IPI_LOG,
+            Level::Warn,
+            "VM [{}] IPI {:#x} to vCPU {} not running yet, dropped",
+            vm_id,
+            vector,
+            vcpu_id
+        ),
+    }
+}
+
+/// Deliver `vector`, raised by a device of vCPU `vcpu_id` of `vm_id`, to the vCPUs addressed by
+/// the 8-bit destination `dest` of the IO APIC, 0xff broadcasting: to every target for a fixed
+/// interrupt, to one of them for a lowest priority one. Returns whether `vcpu_id` is a target
+/// without a virtual local APIC, the caller asserting the vector on it.
+pub(super) fn deliver_device_irq(
+    vm_id: u32,
+    vcpu_id: u32,
+    dest: u8,
+    logical: bool,
+    lowest_priority: bool,
+    vector: u8,
+) -> bool {
+    let vcpus = crate::vm::find_vm(vm_id).map_or(vcpu_id + 1, |vm| vm.vcpus as u32);
+    let dest = if dest == 0xff { BROADCAST } else { dest as u32 };
+    let mut targets = destinations(vcpus, dest, logical);
+    if lowest_priority {
+        // The raising vCPU if it is a target, it takes the interrupt without an IPI.
+        if targets.contains(&vcpu_id) {
+            targets = alloc::vec![vcpu_id];
+        } else {
+            targets.truncate(1);
+        }
+    }
+    let mut local = false;
+    for target in targets {
+        if target == vcpu_id {
+            local = !post_interrupt(vm_id, vcpu_id, vector);
+        } else {
+            deliver_fixed(vm_id, target, vector);
+        }
+    }
+    local
+}
+
+/// Deliver `vector` of an MSI of a device of `vm_id`, raised outside of its vCPUs, to the vCPUs
+/// addressed by the 8-bit destination `dest` of its message address, 0xff broadcasting: to every
+/// target for a fixed interrupt, to the first one for a lowest priority one.
+pub(crate) fn deliver_msi(vm_id: u32, dest: u8, logical: bool, lowest_priority: bool, vector: u8) {
+    let Some(vm) = crate::vm::find_vm(vm_id) else {
+        return;
+    };
+    let dest = if dest == 0xff { BROADCAST } else { dest as u32 };
+    let mut targets = destinations(vm.vcpus as u32, dest, logical);
+    if lowest_priority {
+        targets.truncate(1);
+    }
+    if targets.is_empty() {
+        ratelimited!(
+            IPI_LOG,
+            Level::Warn,
+            "VM [{}] MSI {:#x} to {:#x} addresses no vCPU, dropped",
+            vm_id,
+            vector,
+            dest
+        );
+    }
+    for target in targets {
+        deliver_fixed(vm_id, target, vector);
+    }
+}
+
+/// The vCPUs of the VM among `vcpus` addressed by `dest`.
+fn destinations(vcpus: u32, dest: u32, logical: bool) -> Vec<u32> {
+    (0..vcpus)
+        .filter(|&vcpu_id| match (dest, logical) {
+            (BROADCAST, _) => true,
+            (dest, false) => dest == vcpu_id,
+            (dest, true) => {
+                let id = logical_apic_id(vcpu_id);
+                id >> 16 == dest >> 16 && id & dest & 0xffff != 0
+            }
+        })
+        .collect()
+}
+
+/// Emulate the write of `icr` to the ICR of vCPU `vcpu_id` of `vm_id`.
+pub(crate) fn write_icr(vm_id: u32, vcpu_id: u32, icr: u64) {
+    let vector = (icr & ICR_VECTOR_MASK) as u8;
+    let mode = (icr >> ICR_DELIVERY_MODE_SHIFT) & 0x7;
+    let vcpus = crate::vm::find_vm(vm_id).map_or(vcpu_id + 1, |vm| vm.vcpus as u32);
+    let targets = match (icr >> ICR_SHORTHAND_SHIFT) & 0x3 {
+        SHORTHAND_NONE => destinations(
+            vcpus,
+            (icr >> ICR_DEST_SHIFT) as u32,
+            icr & ICR_LOGICAL != 0,
+        ),
+        SHORTHAND_SELF => alloc::vec![vcpu_id],
+        SHORTHAND_ALL => (0..vcpus).collect(),
+        SHORTHAND_ALL_BUT_SELF => (0..vcpus).filter(|&id| id != vcpu_id).collect(),
+        _ => unreachable!(),
+    };
+    trace!(
+        "VM [{}] vCPU {} ICR {:#x} -> {:?}",
+        vm_id,
+        vcpu_id,
+        icr,
+        targets
+    );
+    match mode {
+        DELIVERY_FIXED | DELIVERY_LOWEST_PRIORITY => {
+            for target in targets {
+                deliver_fixed(vm_id, target, vector);
+            }
+        }
+        // A level-triggered de-assert, which only the 82489DX needs.
+        DELIVERY_INIT if icr & ICR_TRIGGER_LEVEL != 0 && icr & ICR_LEVEL_ASSERT == 0 => {}
+        DELIVERY_INIT => {
+            let states = AP_STATES.lock();
+            for target in targets {
+                if target != vcpu_id && !states.contains_key(&(vm_id, target)) {
+                    ratelimited!(
+                        IPI_LOG,
+                        Level::Warn,
+                        "VM [{}] INIT of running vCPU {} not supported, ignored",
+                        vm_id,
+                        target
+                    );
+                }
+            }
+        }
+        DELIVERY_STARTUP => {
+            for target in targets.into_iter().filter(|&target| target != vcpu_id) {
+                startup(vm_id, target, vector);
+            }
+        }
+        _ => ratelimited!(
+            IPI_LOG,
+            Level::Warn,
+            "VM [{}] vCPU {} IPI of delivery mode {} not supported, dropped",
+            vm_id,
+            vcpu_id,
+            mode
+        ),
+    }
+}
+
+/// Have the vCPU whose VMCS is loaded on the current CPU enter the guest at the start of page
+/// `vector` as a STARTUP IPI does, CS:IP being `vector << 8 : 0`. The rest of its state is the
+/// reset state set up by `VCpu::new`.
+pub(super) fn enter_at_startup_vector(vector: u8) {
+    let result = unsafe {
+        vmwrite(vmcs::guest::CS_SELECTOR, (vector as u64) << 8)
+            .and_then(|_| vmwrite(vmcs::guest::CS_BASE, (vector as u64) << 12))
+            .and_then(|_| vmwrite(vmcs::guest::RIP, 0))
+    };
+    if let Err(err) = result {
+        warn!(
+            "failed to set the STARTUP IPI vector {:#x}: {:?}",
+            vector, err
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Routing of the IRQs of the emulated ISA devices, through the IO APIC or the PICs.
+//!
+//! An IRQ goes through the IO APIC if the guest unmasked its pin there, to the vCPUs of its
+//! redirection entry, and through the PICs otherwise, to the vCPU raising it. A pin in ExtINT
+//! mode hands its IRQ to the PICs, as in virtual wire mode. ISA IRQ `n` is GSI `n`, as the MADT of
+//! the guests with ACPI tables says of IRQ 0, see [`crate::acpi`].
+//!
+//! The vectors for the raising vCPU are asserted in its [`PendingInterrupts`], or in its
+//! virtual local APIC. The virtio devices raise MSI-X interrupts, which go to the local APICs
+//! directly, or INTx interrupts without MSI-X, which are kept for the VM by [`raise_vm_irq`] and
+//! routed by the first of its vCPUs to check its events.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bit_field::BitField;
+use lock_stat::Mutex;
+use log::Level;
+
+use super::device_emu::{
+    I8259Pic, IoApic, IoApicRoute, DELIVERY_EXT_INT, DELIVERY_FIXED, DELIVERY_LOWEST_PRIORITY,
+};
+use super::ipi;
+use super::pending_irq::PendingInterrupts;
+use crate::ratelimit::RateLimiter;
+
+static IRQ_ROUTER_LOG: RateLimiter = RateLimiter::new("irq router", 10);
+
+/// IRQ of the master PIC the slave is cascaded on.
+const SLAVE_PIC_CASCADE_IRQ: u8 = 2;
+/// The pins of the IO APIC, the IRQs a device of the VM can raise.
+const NUM_IRQS: u8 = 24;
+
+/// The IRQs raised by the PCI devices of each VM on their INTx pins, a bit per IRQ.
+static VM_IRQS: Mutex<BTreeMap<u32, u32>> = Mutex::new(BTreeMap::new());
+/// Whether `VM_IRQS` is not empty, checked on every exit without taking its lock.
+static HAS_VM_IRQS: AtomicBool = AtomicBool::new(false);
+
+/// Raise `irq` for the VM `vm_id`, from a device not bound to a vCPU, and wake its vCPUs to route
+/// it.
+pub(crate) fn raise_vm_irq(vm_id: u32, irq: u8) {
+    if irq >= NUM_IRQS {
+        ratelimited!(IRQ_ROUTER_LOG, Level::Warn, "VM [{}] IRQ {} has no pin", vm_id, irq);
+        return;
+    }
+    let mut irqs = VM_IRQS.lock();
+    irqs.entry(vm_id).or_default().set_bit(irq as usize, true);
+    HAS_VM_IRQS.store(true, Ordering::Release);
+    drop(irqs);
+    crate::vm::wake_vcpus(vm_id);
+}
+
+/// Take the IRQs raised for `vm_id` by [`raise_vm_irq`].
+pub(super) fn take_vm_irqs(vm_id: u32) -> u32 {
+    if !HAS_VM_IRQS.load(Ordering::Acquire) {
+        return 0;
+    }
+    let mut irqs = VM_IRQS.lock();
+    let taken = irqs.remove(&vm_id).unwrap_or(0);
+    HAS_VM_IRQS.store(!irqs.is_empty(), Ordering::Release);
+    taken
+}
+
+/// Forget the IRQs raised for `vm_id`, when it is torn down.
+pub(crate) fn unregister_vm_irqs(vm_id: u32) {
+    take_vm_irqs(vm_id);
+}
+
+/// The interrupt controllers of a vCPU, any of which may be missing.
+pub(super) struct IrqRouter {
+    /// The master and the slave PIC.
+    pics: [Option<Arc<Mutex<I8259Pic>>>; 2],
+    ioapic: Option<Arc<Mutex<IoApic>>>,
+}
+
+impl IrqRouter {
+    pub fn new(
+        pics: [Option<Arc<Mutex<I8259Pic>>>; 2],
+        ioapic: Option<Arc<Mutex<IoApic>>>,
+    ) -> Self {
+        Self { pics, ioapic }
+    }
+
+    /// The route of `irq` through the IO APIC, `None` if its pin is masked or hands it to the
+    /// PICs. `asserted` if the IRQ is raised, not only looked up.
+    fn ioapic_route(&self, irq: u8, asserted: bool) -> Option<IoApicRoute> {
+        let ioapic = self.ioapic.as_ref()?.lock();
+        let route = if asserted {
+            ioapic.assert_irq(irq)
+        } else {
+            ioapic.route(irq)
+        };
+        route.filter(|route| route.delivery_mode != DELIVERY_EXT_INT)
+    }
+
+    /// Whether `irq` of the PICs, 8 to 15 being the ones of the slave, is masked, or there is
+    /// no PIC. The IRQs of the slave are also masked by IRQ 2 of the master.
+    fn pic_masked(&self, irq: u8) -> bool {
+        let masked = |index: usize, irq: u8| {
+            self.pics[index]
+                .as_ref()
+                .map_or(true, |pic| pic.lock().mask().get_bit(irq as usize))
+        };
+        match irq {
+            0..=7 => masked(0, irq),
+            _ => masked(0, SLAVE_PIC_CASCADE_IRQ) || masked(1, irq - 8),
+        }
+    }
+
+    /// Whether `irq` is masked both in the IO APIC and in the PICs, raising it injects nothing.
+    pub fn masked(&self, irq: u8) -> bool {
+        self.ioapic_route(irq, false).is_none() && self.pic_masked(irq)
+    }
+
+    /// Raise `irq` from a device of vCPU `vcpu_id`, whose interrupts are `pending`, through the
+    /// IO APIC if its pin is unmasked, through the PICs otherwise.
+    pub fn assert_irq(&self, vcpu_id: u32, pending: &mut PendingInterrupts, irq: u8) {
+        match self.ioapic_route(irq, true) {
+            Some(route) => deliver(vcpu_id, pending, irq, route),
+            None => self.assert_pic_irq(pending, irq),
+        }
+    }
+
+    /// Assert the vector of `irq` of the PICs, unless it is masked. Nothing is asserted before
+    /// the guest programs the PIC, its vectors overlapping the exceptions.
+    fn assert_pic_irq(&self, pending: &mut PendingInterrupts, irq: u8) {
+        if self.pic_masked(irq) {
+            return;
+        }
+        if let Some(pic) = &self.pics[(irq / 8) as usize] {
+            let pic = pic.lock();
+            if pic.offset() >= 0x20 {
+                pending.assert(pic.offset() + irq % 8);
+            }
+        }
+    }
+}
+
+/// Send `irq`, raised on vCPU `vcpu_id`, where its redirection entry `route` says. The vector is
+/// asserted in `pending` if the vCPU is a destination, and sent to the other destinations.
+fn deliver(vcpu_id: u32, pending: &mut PendingInterrupts, irq: u8, route: IoApicRoute) {
+    let lowest_priority = match route.delivery_mode {
+        DELIVERY_FIXED => false,
+        DELIVERY_LOWEST_PRIORITY => true,
+        mode => {
+            ratelimited!(
+                IRQ_ROUTER_LOG,
+                Level::Warn,
+                "IO APIC pin {} has delivery mode {}, not supported, dropped",
+                irq,
+                mode
+            );
+            return;
+        }
+    };
+    // Vectors below 0x20 are for exceptions, as for the PICs.
+    if route.vector < 0x20 {
+        return;
+    }
+    let local = match crate::vm::current_vm_id() {
+        Some(vm_id) => ipi::deliver_device_irq(
+            vm_id,
+            vcpu_id,
+            route.dest,
+            route.logical,
+            lowest_priority,
+            route.vector,
+        ),
+        None => true,
+    };
+    if local {
+        pending.assert(route.vector);
+    }
+}
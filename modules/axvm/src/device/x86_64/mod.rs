@@ -1,36 +1,177 @@
+mod cpuid;
+mod cr_access;
+mod decode_cache;
 pub mod device_emu;
-
+mod diagnostics;
+mod dispatch;
+mod exit_observer;
+mod exit_stats;
+mod exit_vcpu;
+mod fast_mmio;
+mod hotplug;
+mod ipi;
+#[cfg(feature = "legacy-pc-devices")]
+mod irq_router;
+mod msr_bitmap;
+mod msr_spec;
+mod pending_irq;
+mod pvclock;
+mod string_io;
+mod timer_queue;
+mod tsc_offset;
+mod unclaimed_port;
+mod vcpu_config;
+mod vcpu_snapshot;
+mod vm_devices;
+mod vmexit;
+mod xstate;
 extern crate alloc;
+#[cfg(feature = "virtio-pci")]
 use super::dummy_pci::DummyPciDevice;
+use super::range_index::{LastHit, RangeIndex};
+#[cfg(feature = "virtio-pci")]
 use super::virtio::{
-    DummyVirtioDevice, VirtioDevice, VirtioMsiIrqManager, VirtioPciDevice,
-    GLOBAL_VIRTIO_PCI_CFG_REQ, VIRTIO_TYPE_BLOCK,
+    take_virtio_pci_cfg_req, DummyVirtioDevice, VirtioBlk, VirtioConsole, VirtioDevice,
+    VirtioMsiIrqManager, VirtioNet, VirtioPciDevice, VirtioPciLayout, VIRTIO_TYPE_BLOCK,
+    VIRTIO_TYPE_CONSOLE,
 };
-use crate::device::BarAllocImpl;
+use crate::config::entry::{vm_cfg_entry, UnhandledMsrPolicy, UnhandledPortPolicy, VMCfgEntry};
+#[cfg(feature = "virtio-pci")]
+use crate::config::entry::{PciEcamCfg, VirtioDeviceCfg};
+use crate::device::{BarAllocImpl, DeviceState, MmioAccess, PioBatchOps, StateReader, StateWriter};
+use crate::ratelimit::RateLimiter;
 use crate::{
-    nmi::NmiMessage, nmi::CORE_NMI_LIST, HyperCraftHal, PerCpuDevices, PerVmDevices,
+    nmi::NmiMessage, nmi::NmiRequest, HyperCraftHal, PerCpuDevices, PerVmDevices,
     Result as HyperResult, VCpu, VmExitInfo, VmxExitReason,
 };
 use crate::{Error as HyperError, GuestPageTable, VmExitInfo as VmxExitInfo};
-use alloc::format;
+use alloc::collections::BTreeMap;
+#[cfg(feature = "virtio-pci")]
 use alloc::string::String;
 use alloc::{sync::Arc, vec, vec::Vec};
+use axconfig::SMP;
 use axhal::{current_cpu_id, mem::phys_to_virt};
+#[cfg(feature = "legacy-pc-devices")]
 use bit_field::BitField;
 use core::any::Any;
 use core::marker::PhantomData;
-use core::sync::atomic::{AtomicU16, Ordering};
-use device_emu::{ApicBaseMsrHandler, Bundle, VirtLocalApic};
+use core::ops::Range;
+#[cfg(feature = "virtio-pci")]
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::{AtomicU64, Ordering};
+use cpuid::CpuidHandler;
+pub use cpuid::{
+    CpuidPolicy, CpuidReg, CpuidRule, HV_FEATURE_CONSOLE, HV_FEATURE_LOG, HV_FEATURE_MEMORY_MAP,
+    HV_FEATURE_PVCLOCK, HYPERVISOR_SIGNATURE,
+};
+use cr_access::CrAccess;
+pub use cr_access::CrPinning;
+use decode_cache::{DecodeCache, MmioInstr, MmioOp};
+pub use device_emu::DEFAULT_APIC_BUS_FREQ_HZ;
+use device_emu::{
+    ApicBaseMsrHandler, ApicTimerStats, MultiplexConsoleBackend, TimerDeadline, Uart16550,
+    VirtLocalApic, VirtPmu,
+};
+#[cfg(feature = "legacy-pc-devices")]
+use device_emu::{Bundle, Hpet, Ib700Watchdog};
+pub(crate) use dispatch::{unregister_vm_ranges, vm_claims_mmio};
+use dispatch::{ClaimedRanges, DispatchLevel, Space};
+pub(crate) use exit_observer::remove_vm_exit_observers;
+pub use exit_observer::{
+    add_exit_observer, remove_exit_observer, ExitLatencyHistogram, ExitObserverFn, ExitOutcome,
+    ObserverCtx, ObserverId, ObserverPhase, LATENCY_BUCKETS,
+};
+use exit_observer::{observe_exit_end, observe_exit_start};
+pub(crate) use exit_stats::remove_vm_exit_stats;
+pub use exit_stats::{
+    dump_exit_stats, DecodeCacheStats, DispatchCacheStats, ExitReasonStats, ExitStats,
+};
+use exit_vcpu::ExitVcpu;
+use fast_mmio::FastMmioDevice;
+pub use fast_mmio::FastMmioHandler;
+pub(crate) use hotplug::{attach_device, detach_device};
+pub use hotplug::{HotplugAddress, HotplugDevice};
 use hypercraft::{GuestPageTableTrait, MmioOps, PioOps, VirtMsrOps, VmxInterruptionType};
-use iced_x86::{Code, Instruction, OpKind, Register};
+use iced_x86::{Code, Instruction, Mnemonic, OpKind, Register};
+pub(crate) use ipi::{deliver_msi, register_aps, unregister_vm_aps, unregister_vm_virtual_apics};
+#[cfg(feature = "legacy-pc-devices")]
+use irq_router::IrqRouter;
+#[cfg(feature = "legacy-pc-devices")]
+pub(crate) use irq_router::{raise_vm_irq, unregister_vm_irqs};
+use lock_stat::Mutex;
+use log::Level;
+pub(crate) use msr_bitmap::dump_msr_bitmap;
+use msr_bitmap::{AppliedPassthrough, MsrPassthrough};
 use page_table_entry::MappingFlags;
-use pci::{AsAny, BarAllocTrait, PciDevOps, PciHost};
-use spin::Mutex;
+use pci::config::{BarLayout, RegionType};
+#[cfg(feature = "virtio-pci")]
+use pci::PciBdf;
+use pci::{AsAny, BarAllocTrait, PciBus, PciDevOps, PciHost};
+use pending_irq::PendingInterrupts;
+use pvclock::PvClock;
+pub(crate) use pvclock::{register as register_pvclock, unregister_vm_pvclocks};
+use spin::RwLock;
+pub use timer_queue::TimerQueueStats;
+use timer_queue::{TimerQueue, TimerSource, NUM_SOURCES};
+use tsc_offset::TscOffset;
+pub use unclaimed_port::ignored_ports;
+pub(crate) use unclaimed_port::reset as reset_ignored_ports;
+use unclaimed_port::{SeenPorts, UnclaimedPort};
+pub(crate) use vcpu_config::set_vcpu_device_config;
+pub use vcpu_config::VcpuDeviceConfig;
+pub(crate) use vcpu_snapshot::{
+    discard_paused_snapshots, has_pending_snapshot, paused_snapshots, set_pending_snapshots,
+    unregister_vm_snapshots, vcpu_state_saved, VcpuSnapshot,
+};
+pub(crate) use vm_devices::unregister_vm_devices;
+pub(crate) use vmexit::remove_vm_exit_logs;
+use vmexit::{record_exit, vm_fatal, vmcs_read, watchdog_fire, ExitContext, IoExitInfo, LazyInstr};
+pub use vmexit::{
+    set_exit_watchdog, vmcs_read_stats, VmcsReadStats, WatchdogConfig, WatchdogPolicy,
+};
+use x86::vmx::vmcs;
+use x86::vmx::vmcs::control::PrimaryControls;
 use x86_64::registers::rflags::RFlags;
+use xstate::GuestXState;
+
+/// Architectural upper bound of the length of an x86 instruction.
+const MAX_INSTR_LEN: usize = 15;
 
-const VM_EXIT_INSTR_LEN_RDMSR: u8 = 2;
-const VM_EXIT_INSTR_LEN_WRMSR: u8 = 2;
-const VM_EXIT_INSTR_LEN_VMCALL: u8 = 3;
+/// Cross-check the VM-exit instruction length reported by the VMCS.
+///
+/// The length is always bounded by the architectural limit, and must match the length of the
+/// instruction at guest RIP exactly: `instr` if the caller decoded it, else it is decoded here.
+/// Prefixes make the real length of e.g. `rdmsr` differ from its 2-byte encoding, so a mismatch
+/// here means RIP would be advanced to the wrong place. Only checked in debug builds.
+#[cfg_attr(not(debug_assertions), allow(unused_variables))]
+pub(crate) fn debug_check_exit_instr_len(exit_info: &VmxExitInfo, instr: Option<&Instruction>) {
+    let len = exit_info.exit_instruction_length as usize;
+    debug_assert!(
+        len > 0 && len <= MAX_INSTR_LEN,
+        "invalid VM-exit instruction length {} @ {:#x}",
+        len,
+        exit_info.guest_rip
+    );
+    #[cfg(debug_assertions)]
+    if let Some(instr) = instr.copied().or_else(|| decode_exit_instr(exit_info)) {
+        debug_assert_eq!(
+            instr.len(),
+            len,
+            "VM-exit instruction length mismatch @ {:#x}, decoded: {:?}",
+            exit_info.guest_rip,
+            instr.code()
+        );
+    }
+}
+
+/// Decode the instruction at guest RIP of the current exit, `None` if it cannot be read, e.g.
+/// across the end of its page, or does not decode.
+#[cfg(debug_assertions)]
+fn decode_exit_instr(exit_info: &VmxExitInfo) -> Option<Instruction> {
+    LazyInstr::new(&ExitContext::current(exit_info), None)
+        .get()
+        .copied()
+}
 
 macro_rules! build_getcc {
     ($name:ident, $type:ty) => {
@@ -83,560 +224,2405 @@ fn getcc(access_size: u8, x: u64, y: u64) -> u64 {
     }
 }
 
+/// Whether the guest TSC of the vCPU running on the current CPU is offset from the TSC of the
+/// machine.
+fn guest_tsc_offset() -> bool {
+    let offsetting = PrimaryControls::USE_TSC_OFFSETTING.bits() as u64;
+    vmcs_read(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) & offsetting != 0
+        && vmcs_read(vmcs::control::TSC_OFFSET_FULL) != 0
+}
+
+/// The guest TSC of the vCPU running on the current CPU, the TSC of the machine plus its offset.
+fn guest_tsc() -> u64 {
+    let tsc = axhal::time::current_ticks();
+    if guest_tsc_offset() {
+        tsc.wrapping_add(vmcs_read(vmcs::control::TSC_OFFSET_FULL))
+    } else {
+        tsc
+    }
+}
+
+/// Exception vectors of the NMI and of #GP.
+const NMI_VECTOR: u8 = 2;
+const GP_VECTOR: u8 = 13;
+
+/// Inject #GP(0) into the guest. RIP is left at the faulting instruction.
+fn inject_gp<V: ExitVcpu>(vcpu: &mut V) {
+    vcpu.queue_event(GP_VECTOR, Some(0));
+}
+
+// Limiters of the per-exit logs, see `crate::ratelimit`.
+pub(crate) static IO_EXIT_LOG: RateLimiter = RateLimiter::new("io exit", 10);
+pub(crate) static MMIO_EXIT_LOG: RateLimiter = RateLimiter::new("mmio exit", 40);
+pub(crate) static MSR_EXIT_LOG: RateLimiter = RateLimiter::new("msr exit", 10);
+pub(crate) static EXT_INTR_LOG: RateLimiter = RateLimiter::new("external interrupt", 10);
+static UNEXPECTED_NMI_LOG: RateLimiter = RateLimiter::new("unexpected NMI", 10);
+
+/// Access sizes accepted for port I/O.
+const PIO_ACCESS_SIZES: &[u8] = &[1, 2, 4];
+/// Access sizes accepted for MMIO.
+const MMIO_ACCESS_SIZES: &[u8] = &[1, 2, 4, 8];
+
+/// Mask of the low `access_size` bytes of a value.
+const fn access_size_mask(access_size: u8) -> u64 {
+    match access_size {
+        8 => u64::MAX,
+        size => (1 << (size as u32 * 8)) - 1,
+    }
+}
+
+/// Write the `access_size` bytes of `value` read by an `in` to AL, AX or EAX, the destination of
+/// the instruction whatever the mode of the guest (SDM Vol. 1, Section 3.4.1.1):
+/// * 32-bit operands generate a 32-bit result, zero-extended to a 64-bit result in the
+///   destination general-purpose register.
+/// * 8-bit and 16-bit operands generate an 8-bit or 16-bit result. The upper 56 bits or 48 bits
+///   (respectively) of the destination general-purpose register are not modified by the
+///   operation.
+fn write_in_result(rax: &mut u64, access_size: u8, value: u64) {
+    let mask = access_size_mask(access_size);
+    *rax = match access_size {
+        1 | 2 => (*rax & !mask) | (value & mask),
+        _ => value & mask,
+    };
+}
+
+/// Check an access of `access_size` bytes at `addr` to a device covering `range` against the
+/// dispatch contract described on [`DeviceList`], and return the bytes of the access the device
+/// covers: all of them, unless the access straddles the end of the device.
+fn check_access(
+    range: Range<u64>,
+    addr: u64,
+    access_size: u8,
+    sizes: &[u8],
+) -> HyperResult<Range<u64>> {
+    if !sizes.contains(&access_size) {
+        warn!("Invalid device access size {} @ {:#x}", access_size, addr);
+        return Err(HyperError::InvalidParam);
+    }
+    if !range.contains(&addr) {
+        warn!(
+            "Device access {:#x} size {} out of device range {:#x?}",
+            addr, access_size, range
+        );
+        return Err(HyperError::InvalidParam);
+    }
+    // The bytes past the end of the address space are out of any device.
+    let end = addr.saturating_add(access_size as u64);
+    if end > range.end {
+        ratelimited!(
+            IO_EXIT_LOG,
+            Level::Debug,
+            "Device access {:#x} size {} straddles the end of device range {:#x?}",
+            addr,
+            access_size,
+            range
+        );
+    }
+    Ok(addr..end.min(range.end))
+}
+
+/// Whether `inside`, returned by [`check_access`], is the whole access.
+fn whole_access(inside: &Range<u64>, access_size: u8) -> bool {
+    inside.end - inside.start == access_size as u64
+}
+
+/// Read the `access_size` bytes at `addr` with `read`. The bytes out of `inside`, which starts at
+/// `addr`, read as all ones as from a bus no device decodes, and the ones in it are read one at a
+/// time.
+fn split_read(
+    inside: Range<u64>,
+    addr: u64,
+    access_size: u8,
+    mut read: impl FnMut(u64, u8) -> HyperResult<u64>,
+) -> HyperResult<u64> {
+    if whole_access(&inside, access_size) {
+        return read(addr, access_size);
+    }
+    let mut value = access_size_mask(access_size);
+    for byte in inside {
+        let shift = (byte - addr) * 8;
+        value &= !(0xff << shift);
+        value |= (read(byte, 1)? & 0xff) << shift;
+    }
+    Ok(value)
+}
+
+/// Write the `access_size` bytes of `value` to `addr` with `write`, as [`split_read`]: the bytes
+/// out of `inside` are dropped.
+fn split_write(
+    inside: Range<u64>,
+    addr: u64,
+    access_size: u8,
+    value: u64,
+    mut write: impl FnMut(u64, u8, u64) -> HyperResult,
+) -> HyperResult {
+    if whole_access(&inside, access_size) {
+        return write(addr, access_size, value);
+    }
+    for byte in inside {
+        write(byte, 1, (value >> ((byte - addr) * 8)) & 0xff)?;
+    }
+    Ok(())
+}
+
+/// A port I/O device, whose accesses straddling its end are split by [`split_read`] and
+/// [`split_write`] and checked against the dispatch contract.
+struct ClippedPio<'a> {
+    device: &'a mut dyn PioOps,
+}
+
+impl ClippedPio<'_> {
+    fn inside(&self, port: u16, access_size: u8) -> HyperResult<Range<u64>> {
+        let range = self.device.port_range();
+        check_access(
+            range.start as u64..range.end as u64,
+            port as u64,
+            access_size,
+            PIO_ACCESS_SIZES,
+        )
+    }
+}
+
+impl PioOps for ClippedPio<'_> {
+    fn port_range(&self) -> Range<u16> {
+        self.device.port_range()
+    }
+
+    fn read(&mut self, port: u16, access_size: u8) -> HyperResult<u32> {
+        let inside = self.inside(port, access_size)?;
+        let value = split_read(inside, port as u64, access_size, |port, size| {
+            self.device.read(port as u16, size).map(u64::from)
+        })?;
+        Ok((value & access_size_mask(access_size)) as u32)
+    }
+
+    fn write(&mut self, port: u16, access_size: u8, value: u32) -> HyperResult {
+        let inside = self.inside(port, access_size)?;
+        let value = value as u64 & access_size_mask(access_size);
+        split_write(
+            inside,
+            port as u64,
+            access_size,
+            value,
+            |port, size, value| self.device.write(port as u16, size, value as u32),
+        )
+    }
+}
+
+/// Registration tables of a [`DeviceList`].
+///
+/// Lookups search an immutable snapshot of the tables; registration builds a new copy and swaps
+/// it in, so that it never blocks the vCPUs dispatching exits.
+#[derive(Clone)]
+struct DeviceTables {
+    port_io_devices: RangeIndex<Arc<Mutex<dyn PioOps>>>,
+    memory_io_devices: RangeIndex<Arc<Mutex<dyn MmioOps>>>,
+    msr_devices: RangeIndex<Arc<Mutex<dyn VirtMsrOps>>>,
+    /// MSRs accessed without exits, see [`DeviceList::set_msr_passthrough`].
+    msr_passthrough: BTreeMap<u32, MsrPassthrough>,
+    /// Handlers tried before the MMIO devices, see [`DeviceList::add_fast_mmio_device`].
+    fast_mmio_devices: RangeIndex<FastMmioHandler>,
+    /// Mapped BARs of the PCI devices, searched after the devices above.
+    pio_bars: RangeIndex<Arc<Mutex<dyn PioOps>>>,
+    mmio_bars: RangeIndex<Arc<Mutex<dyn MmioOps>>>,
+    /// Devices added with their concrete type, see [`DeviceList::add_typed_port_io_device`].
+    typed_devices: Vec<Arc<dyn Any + Send + Sync>>,
+    /// Port I/O devices taking `rep outs` at once, see [`DeviceList::add_batch_writer`].
+    batch_writers: Vec<Arc<Mutex<dyn PioBatchOps>>>,
+    /// Devices saved and restored by [`DeviceList::save_all`], in registration order.
+    stateful_devices: Vec<(&'static str, Arc<Mutex<dyn DeviceState + Send>>)>,
+    /// BAR layout generation the BAR indexes were built from.
+    bar_generation: Option<u64>,
+    /// Bumped by every update of the tables, invalidates the [`DispatchCache`]s.
+    generation: u64,
+}
+
+impl DeviceTables {
+    const fn new() -> Self {
+        Self {
+            port_io_devices: RangeIndex::new(),
+            memory_io_devices: RangeIndex::new(),
+            msr_devices: RangeIndex::new(),
+            msr_passthrough: BTreeMap::new(),
+            fast_mmio_devices: RangeIndex::new(),
+            pio_bars: RangeIndex::new(),
+            mmio_bars: RangeIndex::new(),
+            typed_devices: Vec::new(),
+            batch_writers: Vec::new(),
+            stateful_devices: Vec::new(),
+            bar_generation: None,
+            generation: 0,
+        }
+    }
+
+    /// Index `bars`, the BARs mapped by the guest in the layout `generation`, replacing the BARs
+    /// of the previous layout. `cfg` is the configuration of the VM.
+    ///
+    /// A BAR is only reached through its exits: the MMIO BARs trap because the guest places them
+    /// outside of the memory regions mapped in its nested page table. A BAR placed over another
+    /// BAR, a device of this list, or for a MMIO BAR over a memory region, cannot be emulated:
+    /// it is left out, with an error, until the guest moves it away.
+    fn set_bars(&mut self, bars: Vec<pci::config::Bar>, generation: u64, cfg: Option<&VMCfgEntry>) {
+        self.pio_bars.clear();
+        self.mmio_bars.clear();
+        for bar in bars {
+            let result = match bar.region_type() {
+                RegionType::Io => {
+                    let range = PioOps::port_range(&bar);
+                    let range = range.start as u64..range.end as u64;
+                    match self.port_io_devices.overlapping(&range) {
+                        Some(claimed) => {
+                            Err((range, alloc::format!("port I/O device {:#x?}", claimed)))
+                        }
+                        None => self
+                            .pio_bars
+                            .insert(range, Arc::new(Mutex::new(bar)))
+                            .map_err(|(range, _)| (range, String::from("another BAR"))),
+                    }
+                }
+                RegionType::Mem32Bit | RegionType::Mem64Bit => {
+                    let range = MmioOps::mmio_range(&bar);
+                    let region = cfg.and_then(|cfg| {
+                        cfg.overlapping_region(range.start as usize..range.end as usize)
+                    });
+                    if let Some(region) = region {
+                        Err((range, alloc::format!("memory region {}", region)))
+                    } else if let Some(claimed) = self.memory_io_devices.overlapping(&range) {
+                        Err((range, alloc::format!("MMIO device {:#x?}", claimed)))
+                    } else {
+                        self.mmio_bars
+                            .insert(range, Arc::new(Mutex::new(bar)))
+                            .map_err(|(range, _)| (range, String::from("another BAR")))
+                    }
+                }
+            };
+            if let Err((range, claimed)) = result {
+                error!(
+                    "PCI BAR {:#x?} placed over {}, not decoded until it moves",
+                    range, claimed
+                );
+            }
+        }
+        self.bar_generation = Some(generation);
+    }
+
+    /// Insert `device` at its current range, see [`DeviceList::add_port_io_device`].
+    fn add_port_io_device(&mut self, device: Arc<Mutex<dyn PioOps>>) -> HyperResult {
+        let range = device.lock().port_range();
+        let range = range.start as u64..range.end as u64;
+        insert_device(&mut self.port_io_devices, "port I/O", range, device)
+    }
+
+    /// Insert `device` at its current range, see [`DeviceList::add_memory_io_device`].
+    fn add_memory_io_device(&mut self, device: Arc<Mutex<dyn MmioOps>>) -> HyperResult {
+        let range = device.lock().mmio_range();
+        insert_device(&mut self.memory_io_devices, "MMIO", range, device)
+    }
+
+    /// Remove the port I/O device claiming `port`, see [`DeviceList::remove_port_io_device`].
+    fn remove_port_io_device(&mut self, port: u16) -> HyperResult<Arc<Mutex<dyn PioOps>>> {
+        let device = self
+            .port_io_devices
+            .find(port as u64)
+            .cloned()
+            .ok_or(HyperError::InvalidParam)?;
+        check_device_idle(&device)?;
+        let ptr = Arc::as_ptr(&device) as *const ();
+        self.port_io_devices
+            .remove_by(|dev| Arc::as_ptr(dev) as *const () == ptr);
+        self.batch_writers
+            .retain(|writer| Arc::as_ptr(writer) as *const () != ptr);
+        self.forget_device(ptr);
+        Ok(device)
+    }
+
+    /// Remove the MMIO device claiming `address`, see [`DeviceList::remove_memory_io_device`].
+    fn remove_memory_io_device(&mut self, address: u64) -> HyperResult<Arc<Mutex<dyn MmioOps>>> {
+        let (range, device) = self
+            .memory_io_devices
+            .find_entry(address)
+            .cloned()
+            .ok_or(HyperError::InvalidParam)?;
+        check_device_idle(&device)?;
+        let ptr = Arc::as_ptr(&device) as *const ();
+        self.memory_io_devices
+            .remove_by(|dev| Arc::as_ptr(dev) as *const () == ptr);
+        // The handler of a fast MMIO device has the same range.
+        if let Some(handler) = self.fast_mmio_devices.find(range.start).cloned() {
+            self.fast_mmio_devices
+                .remove_by(|fast| Arc::ptr_eq(fast, &handler));
+        }
+        self.forget_device(ptr);
+        Ok(device)
+    }
+
+    /// Drop the typed and stateful handles of the device at `ptr`, which is being removed.
+    fn forget_device(&mut self, ptr: *const ()) {
+        self.typed_devices
+            .retain(|typed| Arc::as_ptr(typed) as *const () != ptr);
+        self.stateful_devices
+            .retain(|(_, device)| Arc::as_ptr(device) as *const () != ptr);
+    }
+
+    /// The ranges claimed by the devices of these tables, mapped BARs included.
+    fn claimed_ranges(&self) -> ClaimedRanges {
+        ClaimedRanges {
+            port: self
+                .port_io_devices
+                .ranges()
+                .chain(self.pio_bars.ranges())
+                .cloned()
+                .collect(),
+            mmio: self
+                .memory_io_devices
+                .ranges()
+                .chain(self.mmio_bars.ranges())
+                .cloned()
+                .collect(),
+            msr: self.msr_devices.ranges().cloned().collect(),
+        }
+    }
+}
+
+/// The tables of a [`DeviceList`], shared with [`hotplug`] for a per-VM list, whose port I/O and
+/// MMIO devices can be attached and detached while the VM runs.
+struct SharedTables {
+    tables: RwLock<Arc<DeviceTables>>,
+    /// Serializes the updates of `tables`.
+    update_lock: Mutex<()>,
+    /// The `generation` and `bar_generation` of `tables`, for the [`DispatchCache`] hits to be
+    /// checked without taking a snapshot. A `bar_generation` of `u64::MAX` is none.
+    generation: AtomicU64,
+    bar_generation: AtomicU64,
+}
+
+impl SharedTables {
+    fn new() -> Self {
+        Self {
+            tables: RwLock::new(Arc::new(DeviceTables::new())),
+            update_lock: Mutex::new(()),
+            generation: AtomicU64::new(0),
+            bar_generation: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    fn snapshot(&self) -> Arc<DeviceTables> {
+        self.tables.read().clone()
+    }
+
+    /// Apply `f` to a copy of the tables and publish the copy if `f` succeeds. Lookups in
+    /// progress keep using the snapshot they started with.
+    fn try_update(&self, f: impl FnOnce(&mut DeviceTables) -> HyperResult) -> HyperResult {
+        let _guard = self.update_lock.lock();
+        let mut tables = DeviceTables::clone(&self.tables.read());
+        f(&mut tables)?;
+        tables.generation += 1;
+        let (generation, bar_generation) = (tables.generation, tables.bar_generation);
+        *self.tables.write() = Arc::new(tables);
+        self.generation.store(generation, Ordering::Release);
+        self.bar_generation
+            .store(bar_generation.unwrap_or(u64::MAX), Ordering::Release);
+        Ok(())
+    }
+}
+
+const DEVICE_LIST_STATE_VERSION: u16 = 1;
+
+/// The devices last hit by the port I/O, MMIO and MSR dispatch of one vCPU in one
+/// [`DeviceList`], owned by the vCPU, so checking it takes no lock.
+///
+/// Guests keep touching the same few devices (the console UART, the APIC MSRs), so checking the
+/// last hit first saves most of the lookups. A hit is only taken while the tables it was found in
+/// are current: any update of the tables, a device being added or removed or a BAR moving,
+/// invalidates it.
+pub(crate) struct DispatchCache {
+    pio: LastHit<Arc<Mutex<dyn PioOps>>>,
+    mmio: LastHit<Arc<Mutex<dyn MmioOps>>>,
+    msr: LastHit<Arc<Mutex<dyn VirtMsrOps>>>,
+    stats: DispatchCacheStats,
+}
+
+impl DispatchCache {
+    pub const fn new() -> Self {
+        Self {
+            pio: LastHit::new(),
+            mmio: LastHit::new(),
+            msr: LastHit::new(),
+            stats: DispatchCacheStats { hits: 0, misses: 0 },
+        }
+    }
+
+    /// The hits and misses so far.
+    pub fn stats(&self) -> DispatchCacheStats {
+        self.stats
+    }
+}
+
+const PER_CPU_DECODE_CACHE: Mutex<DecodeCache> = Mutex::new(DecodeCache::new());
+
+/// The device of `last_hit` if it claims `addr` in the tables of `generation`, counting the hit.
+fn cached_device<T: Clone>(
+    last_hit: &LastHit<T>,
+    stats: &mut DispatchCacheStats,
+    generation: u64,
+    addr: u64,
+) -> Option<T> {
+    let device = last_hit.get(generation, addr)?;
+    stats.hits += 1;
+    Some(device)
+}
+
+/// Look `addr` up in `indexes` in order, counting the miss of the cache and remembering the entry
+/// found in `last_hit`.
+fn lookup_device<T: Clone>(
+    last_hit: &mut LastHit<T>,
+    stats: &mut DispatchCacheStats,
+    generation: u64,
+    addr: u64,
+    indexes: &[&RangeIndex<T>],
+) -> Option<T> {
+    stats.misses += 1;
+    let entry = indexes.iter().find_map(|index| index.find_entry(addr))?;
+    last_hit.set(generation, entry);
+    Some(entry.1.clone())
+}
+
+/// Insert a `kind` device claiming `range` in `index`, failing with `InvalidParam` if the range
+/// is empty or overlaps another device.
+fn insert_device<T>(
+    index: &mut RangeIndex<T>,
+    kind: &str,
+    range: Range<u64>,
+    device: T,
+) -> HyperResult {
+    index.insert(range, device).map_err(|(range, _)| {
+        warn!("{} device {:#x?} overlaps another device", kind, range);
+        HyperError::InvalidParam
+    })
+}
+
+/// Fail with `BadState` if a handler of `device` is executing, holding its lock.
+fn check_device_idle<T: ?Sized>(device: &Mutex<T>) -> HyperResult {
+    if device.is_locked() {
+        warn!("device busy, not removed");
+        return Err(HyperError::BadState);
+    }
+    Ok(())
+}
+
+/// Fail with `InvalidParam` if the MSRs of `range` include one passed through, which a device
+/// cannot implement.
+fn check_msr_passthrough(tables: &DeviceTables, range: &Range<u32>) -> HyperResult {
+    match tables.msr_passthrough.range(range.clone()).next() {
+        Some((msr, _)) => {
+            warn!(
+                "MSR device {:#x?} implements MSR {:#x}, which is passed through",
+                range, msr
+            );
+            Err(HyperError::InvalidParam)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Devices of a vCPU or a VM, and the dispatch of VM exits to them.
+///
+/// The dispatcher enforces the access-size contract, so devices do not have to check it again:
+/// - [`PioOps`] devices only see accesses of 1, 2 or 4 bytes, and [`MmioOps`] devices accesses
+///   of 1, 2, 4 or 8 bytes; anything else fails with `InvalidParam` before reaching the device.
+/// - The whole access lies within the `port_range()`/`mmio_range()` of the device. An access
+///   straddling its end reaches it one byte at a time, the bytes beyond the end being ignored,
+///   reading as all ones.
+/// - Written values have no bits set above `access_size`, and read results are truncated to
+///   `access_size` by the dispatcher, so devices may return unmasked values.
+///
+/// Devices are looked up in sorted [`RangeIndex`]es, without locking any device: only the mutex
+/// of the device being accessed is taken, so vCPUs touching different devices do not serialize.
+/// The range of a device is captured when it is added, and read again only by
+/// [`DeviceList::refresh_device_range`]; the ranges of the PCI BARs are re-read whenever the guest
+/// moves a BAR. Devices can be added and removed while the VM runs, the vCPUs dispatching exits
+/// on the snapshot of the tables they started with, see [`hotplug`] for the per-VM lists.
 pub struct DeviceList<H: HyperCraftHal, B: BarAllocTrait> {
-    port_io_devices: Vec<Arc<Mutex<dyn PioOps>>>,
-    memory_io_devices: Vec<Arc<Mutex<dyn MmioOps>>>,
-    msr_devices: Vec<Arc<Mutex<dyn VirtMsrOps>>>,
+    tables: Arc<SharedTables>,
+    /// The MMIO instructions decoded recently, per physical CPU, and thus per vCPU since vCPUs
+    /// are pinned, so it is never contended.
+    decode_cache: [Mutex<DecodeCache>; SMP],
     pci_devices: Option<Arc<Mutex<PciHost<B>>>>,
+    /// Root bus of `pci_devices`, cached so that reaching it does not lock the host.
+    pci_root_bus: Option<Arc<Mutex<PciBus<B>>>>,
+    /// BAR layout of `pci_root_bus`, cached so that checking it does not lock the bus.
+    bar_layout: Option<Arc<BarLayout>>,
     vm_id: Option<u32>,
     vcpu_id: Option<u32>,
+    /// Applied to the MSRs no device implements, see [`dispatch`]. A per-vCPU list without one
+    /// leaves them to the per-VM list.
+    msr_policy: Option<UnhandledMsrPolicy>,
+    /// The ports this list ignored, if it applies [`UnhandledPortPolicy::IgnoreRdOnesWrDrop`] to
+    /// the ports no device claims, see [`dispatch`]. The per-vCPU lists leave them to the per-VM
+    /// list.
+    seen_ports: Option<SeenPorts>,
     marker: core::marker::PhantomData<H>,
 }
 
 impl<H: HyperCraftHal, B: BarAllocTrait + 'static> DeviceList<H, B> {
     pub fn new(vcpu_id: Option<u32>, vm_id: Option<u32>) -> Self {
         Self {
-            port_io_devices: vec![],
-            memory_io_devices: vec![],
-            msr_devices: vec![],
+            tables: Arc::new(SharedTables::new()),
+            decode_cache: [PER_CPU_DECODE_CACHE; SMP],
             pci_devices: None,
+            pci_root_bus: None,
+            bar_layout: None,
             vm_id,
             vcpu_id,
+            msr_policy: None,
+            seen_ports: None,
             marker: core::marker::PhantomData,
         }
     }
 
+    /// Set the policy for the MSRs no device implements, see [`UnhandledMsrPolicy`].
+    pub fn set_unhandled_msr_policy(&mut self, policy: Option<UnhandledMsrPolicy>) {
+        self.msr_policy = policy;
+    }
+
+    /// Set the policy for the ports no device claims, see [`UnhandledPortPolicy`]. Only the
+    /// per-VM lists apply it.
+    pub fn set_unhandled_port_policy(&mut self, policy: UnhandledPortPolicy) {
+        self.seen_ports = (policy == UnhandledPortPolicy::IgnoreRdOnesWrDrop)
+            .then(|| self.seen_ports.take().unwrap_or_else(SeenPorts::new));
+    }
+
+    /// Id of the VM owning this device list, for per-VM device lists only.
+    fn vm_id(&self) -> u32 {
+        self.vm_id
+            .expect("this is not vm devicelist. vm_id is None")
+    }
+
+    #[cfg(feature = "virtio-pci")]
     fn init_pci_host(&mut self) {
         if let Some(vm_id) = self.vm_id {
             let pci_host = PciHost::new(Some(Arc::new(super::virtio::VirtioMsiIrqManager {
                 vm_id: self.vm_id.expect("None vm for pci host"),
             })));
+            self.bar_layout = Some(pci_host.root_bus.lock().bar_layout.clone());
+            self.pci_root_bus = Some(pci_host.root_bus.clone());
             self.pci_devices = Some(Arc::new(Mutex::new(pci_host)));
         } else {
             panic!("this is not vm devicelist. vm_id is None");
         }
     }
 
-    fn add_pci_device(
-        &mut self,
-        name: String,
-        dev_id: Arc<AtomicU16>,
-        devfn: u8,
-    ) -> HyperResult<()> {
-        let mut pci_host = self.pci_devices.clone().unwrap();
-        let pci_bus = pci_host.lock().root_bus.clone();
-        let parent_bus = Arc::downgrade(&pci_bus);
+    #[cfg(feature = "virtio-pci")]
+    fn add_pci_device(&self, name: String, dev_id: Arc<AtomicU16>, devfn: u8) -> HyperResult<()> {
+        let parent_bus = Arc::downgrade(self.pci_root_bus.as_ref().unwrap());
         let mut pcidev = DummyPciDevice::<B>::new(name, devfn, parent_bus, 0x1010);
         pcidev.realize()
     }
 
-    // virtio pci devfn: 0x18 bus: 0x0.
+    /// Add `device` at `devfn` of the root bus, which must be free.
+    #[cfg(feature = "virtio-pci")]
     fn add_virtio_pci_device(
-        &mut self,
+        &self,
         name: String,
         devfn: u8,
         device: Arc<Mutex<dyn VirtioDevice>>,
         multi_func: bool,
-    ) -> HyperResult<()> {
-        let mut pci_host = self.pci_devices.clone().unwrap();
-        let pci_bus = pci_host.lock().root_bus.clone();
-        let parent_bus = Arc::downgrade(&pci_bus);
-        let mut pcidev = VirtioPciDevice::<B>::new(name, devfn, device, parent_bus, multi_func);
-        pcidev.realize()
+    ) -> HyperResult<PciBdf> {
+        let parent_bus = Arc::downgrade(self.pci_root_bus.as_ref().unwrap());
+        let pcidev = VirtioPciDevice::<B>::new(
+            name,
+            devfn,
+            device,
+            parent_bus,
+            multi_func,
+            VirtioPciLayout::default(),
+        );
+        pcidev.realize()?;
+        Ok(PciBdf { bus: 0, devfn })
     }
 
-    pub fn add_port_io_device(&mut self, device: Arc<Mutex<dyn PioOps>>) {
-        self.port_io_devices.push(device)
+    /// Add `device` at the first free slot of the root bus, returning where it was placed.
+    #[cfg(feature = "virtio-pci")]
+    fn add_virtio_device(
+        &self,
+        name: String,
+        device: Arc<Mutex<dyn VirtioDevice>>,
+    ) -> HyperResult<PciBdf> {
+        let devfn = self.pci_root_bus.as_ref().unwrap().lock().alloc_devfn();
+        match devfn {
+            Some(devfn) => self.add_virtio_pci_device(name, devfn, device, false),
+            None => Err(HyperError::PciError(hypercraft::PciError::Other(
+                alloc::format!("no free PCI slot for virtio device {}", name),
+            ))),
+        }
     }
 
-    pub fn add_port_io_devices(&mut self, devices: &mut Vec<Arc<Mutex<dyn PioOps>>>) {
-        self.port_io_devices.append(devices)
+    /// Apply `f` to a copy of the tables and publish the copy, see [`SharedTables::try_update`].
+    fn update_tables(&self, f: impl FnOnce(&mut DeviceTables)) {
+        let _ = self.try_update_tables(|tables| {
+            f(tables);
+            Ok(())
+        });
     }
 
-    pub fn find_port_io_device(&self, port: u16) -> Option<Arc<Mutex<dyn PioOps>>> {
-        self.port_io_devices
-            .iter()
-            .find(|dev| dev.lock().port_range().contains(&port))
-            .cloned()
-            .or_else(|| {
-                if let Some(pci_host) = &self.pci_devices {
-                    let root_bus = &pci_host.lock().root_bus;
-                    root_bus.clone().lock().find_pio_bar(port)
-                } else {
-                    None
-                }
-            })
+    /// Like [`DeviceList::update_tables`], the new tables being published only if `f` succeeds.
+    fn try_update_tables(&self, f: impl FnOnce(&mut DeviceTables) -> HyperResult) -> HyperResult {
+        self.tables.try_update(f)
     }
 
-    pub fn add_memory_io_device(&mut self, device: Arc<Mutex<dyn MmioOps>>) {
-        self.memory_io_devices.push(device)
+    /// Snapshot of the tables, with the BAR indexes rebuilt first if a BAR was registered,
+    /// unregistered or moved since they were last built.
+    fn tables(&self) -> Arc<DeviceTables> {
+        let tables = self.tables.snapshot();
+        let (root_bus, bar_layout) = match (&self.pci_root_bus, &self.bar_layout) {
+            (Some(root_bus), Some(bar_layout)) => (root_bus, bar_layout),
+            _ => return tables,
+        };
+        let generation = bar_layout.generation();
+        if tables.bar_generation == Some(generation) {
+            return tables;
+        }
+        let mut bars = vec![];
+        root_bus.lock().collect_mapped_bars(&mut bars);
+        let cfg = self.vm_id.and_then(|vm_id| vm_cfg_entry(vm_id as usize));
+        self.update_tables(|tables| tables.set_bars(bars, generation, cfg.as_deref()));
+        self.tables.snapshot()
     }
 
-    pub fn add_memory_io_devices(&mut self, devices: &mut Vec<Arc<Mutex<dyn MmioOps>>>) {
-        self.memory_io_devices.append(devices)
+    /// Generation of the current tables, with the BAR indexes rebuilt first like
+    /// [`DeviceList::tables`], but only reading two atomics while no BAR moved.
+    fn generation(&self) -> u64 {
+        let bars_moved = self.bar_layout.as_ref().is_some_and(|bar_layout| {
+            self.tables.bar_generation.load(Ordering::Acquire) != bar_layout.generation()
+        });
+        if bars_moved {
+            return self.tables().generation;
+        }
+        self.tables.generation.load(Ordering::Acquire)
     }
 
-    pub fn find_memory_io_device(&self, address: u64) -> Option<Arc<Mutex<dyn MmioOps>>> {
-        self.memory_io_devices
-            .iter()
-            .find(|dev| dev.lock().mmio_range().contains(&address))
-            .cloned()
-            .or_else(|| {
-                if let Some(pci_host) = &self.pci_devices {
-                    let root_bus = &pci_host.lock().root_bus;
-                    root_bus.clone().lock().find_mmio_bar(address)
-                } else {
-                    None
-                }
-            })
+    /// Add a port I/O device, its range being read once, here. Fails with `InvalidParam` if the
+    /// range is empty or overlaps another device of this list.
+    pub fn add_port_io_device(&self, device: Arc<Mutex<dyn PioOps>>) -> HyperResult {
+        self.add_port_io_devices(&mut vec![device])
     }
 
-    pub fn add_msr_device(&mut self, device: Arc<Mutex<dyn VirtMsrOps>>) {
-        self.msr_devices.push(device)
+    /// Add the port I/O devices drained from `devices`, none of them if one fails as in
+    /// [`DeviceList::add_port_io_device`].
+    pub fn add_port_io_devices(&self, devices: &mut Vec<Arc<Mutex<dyn PioOps>>>) -> HyperResult {
+        self.try_update_tables(|tables| {
+            for device in devices.drain(..) {
+                tables.add_port_io_device(device)?;
+            }
+            Ok(())
+        })
     }
 
-    pub fn add_msr_devices(&mut self, devices: &mut Vec<Arc<Mutex<dyn VirtMsrOps>>>) {
-        self.msr_devices.append(devices)
+    /// The port I/O device or BAR claiming `port`, from `cache` if it was the last one hit.
+    pub(crate) fn find_port_io_device(
+        &self,
+        cache: &mut DispatchCache,
+        port: u16,
+    ) -> Option<Arc<Mutex<dyn PioOps>>> {
+        let generation = self.generation();
+        if let Some(device) = cached_device(&cache.pio, &mut cache.stats, generation, port as u64) {
+            return Some(device);
+        }
+        let tables = self.tables();
+        lookup_device(
+            &mut cache.pio,
+            &mut cache.stats,
+            tables.generation,
+            port as u64,
+            &[&tables.port_io_devices, &tables.pio_bars],
+        )
     }
 
-    pub fn find_msr_device(&self, msr: u32) -> Option<Arc<Mutex<dyn VirtMsrOps>>> {
-        self.msr_devices
-            .iter()
-            .find(|dev| dev.lock().msr_range().contains(&msr))
-            .cloned()
+    /// Remove and return the port I/O device claiming `port`, e.g. one attached while the VM
+    /// runs. Fails with `InvalidParam` if no device claims it, and with `BadState` while a
+    /// handler of the device is executing. An access which looked the device up before its
+    /// removal may still reach it once.
+    pub fn remove_port_io_device(&self, port: u16) -> HyperResult<Arc<Mutex<dyn PioOps>>> {
+        let mut removed = None;
+        self.try_update_tables(|tables| {
+            removed = Some(tables.remove_port_io_device(port)?);
+            Ok(())
+        })?;
+        removed.ok_or(HyperError::BadState)
     }
 
-    fn handle_io_instruction_to_device(
-        vcpu: &mut VCpu<H>,
-        exit_info: &VmxExitInfo,
-        device: Arc<Mutex<dyn PioOps>>,
-    ) -> HyperResult {
-        let io_info = vcpu.io_exit_info().unwrap();
-        trace!(
-            "VM exit: I/O instruction @ {:#x}: {:#x?}",
-            exit_info.guest_rip,
-            io_info,
-        );
+    /// Add a MMIO device, its range being read once, here. Fails with `InvalidParam` if the range
+    /// is empty or overlaps another device of this list.
+    pub fn add_memory_io_device(&self, device: Arc<Mutex<dyn MmioOps>>) -> HyperResult {
+        self.add_memory_io_devices(&mut vec![device])
+    }
 
-        if io_info.is_string {
-            error!("INS/OUTS instructions are not supported!");
-            return Err(HyperError::NotSupported);
-        }
-        if io_info.is_repeat {
-            error!("REP prefixed I/O instructions are not supported!");
-            return Err(HyperError::NotSupported);
-        }
-        if io_info.is_in {
-            let value = device.lock().read(io_info.port, io_info.access_size)?;
-            let rax = &mut vcpu.regs_mut().rax;
-            // SDM Vol. 1, Section 3.4.1.1:
-            // * 32-bit operands generate a 32-bit result, zero-extended to a 64-bit result in the
-            //   destination general-purpose register.
-            // * 8-bit and 16-bit operands generate an 8-bit or 16-bit result. The upper 56 bits or
-            //   48 bits (respectively) of the destination general-purpose register are not modified
-            //   by the operation.
-            match io_info.access_size {
-                1 => *rax = (*rax & !0xff) | (value & 0xff) as u64,
-                2 => *rax = (*rax & !0xffff) | (value & 0xffff) as u64,
-                4 => *rax = value as u64,
-                _ => unreachable!(),
+    /// Add the MMIO devices drained from `devices`, none of them if one fails as in
+    /// [`DeviceList::add_memory_io_device`].
+    pub fn add_memory_io_devices(&self, devices: &mut Vec<Arc<Mutex<dyn MmioOps>>>) -> HyperResult {
+        self.try_update_tables(|tables| {
+            for device in devices.drain(..) {
+                tables.add_memory_io_device(device)?;
             }
-        } else {
-            let rax = vcpu.regs().rax;
-            let value = match io_info.access_size {
-                1 => rax & 0xff,
-                2 => rax & 0xffff,
-                4 => rax,
-                _ => unreachable!(),
-            } as u32;
-            device
-                .lock()
-                .write(io_info.port, io_info.access_size, value)?;
-        }
-        vcpu.advance_rip(exit_info.exit_instruction_length as _)?;
-        Ok(())
+            Ok(())
+        })
     }
 
-    pub fn handle_io_instruction(
-        &mut self,
-        vcpu: &mut VCpu<H>,
-        exit_info: &VmxExitInfo,
-    ) -> Option<HyperResult> {
-        let io_info = vcpu.io_exit_info().unwrap();
-        if let Some(dev) = self.find_port_io_device(io_info.port) {
-            let mut ret = Some(Self::handle_io_instruction_to_device(vcpu, exit_info, dev));
-            // deal with virtio pci cfg access cap
-            let mmio_req = GLOBAL_VIRTIO_PCI_CFG_REQ.read().clone();
-            if let Some(req) = mmio_req.as_ref() {
-                // this mmio req can only be generated from pci config read(virtio pci cfg access cap), so do not check mmio_ops in the devicelist
-                *GLOBAL_VIRTIO_PCI_CFG_REQ.write() = None;
-                if self.pci_devices.is_some() {
-                    let addr = req.addr;
-                    let pci_host = self.pci_devices.clone().unwrap();
-                    let mut root_bus = &pci_host.lock().root_bus;
-                    if let Some(mmio_ops) = root_bus.clone().lock().find_mmio_bar(addr) {
-                        let access_size = req.len;
-                        if req.is_write {
-                            let mut bytes = [0u8; 8];
-                            bytes.copy_from_slice(&(req.data)[..8]);
-                            let value = u64::from_le_bytes(bytes);
-                            let ret = Some(mmio_ops.lock().write(addr, access_size, value));
-                        } else {
-                            let value = mmio_ops.lock().read(addr, access_size).ok()?;
-                            let rax = &mut vcpu.regs_mut().rax;
-                            match access_size {
-                                1 => *rax = (*rax & !0xff) | (value & 0xff) as u64,
-                                2 => *rax = (*rax & !0xffff) | (value & 0xffff) as u64,
-                                4 => *rax = (*rax & !0xffff_ffff) | (value & 0xffff_ffff) as u64,
-                                8 => *rax = value,
-                                _ => unreachable!(),
-                            }
-                            ret = Some(Ok(()))
-                        }
-                    }
-                }
-            }
-            return ret;
-        } else {
-            return None;
+    /// The MMIO device or BAR claiming `address`, from `cache` if it was the last one hit.
+    pub(crate) fn find_memory_io_device(
+        &self,
+        cache: &mut DispatchCache,
+        address: u64,
+    ) -> Option<Arc<Mutex<dyn MmioOps>>> {
+        let generation = self.generation();
+        if let Some(device) = cached_device(&cache.mmio, &mut cache.stats, generation, address) {
+            return Some(device);
         }
+        let tables = self.tables();
+        lookup_device(
+            &mut cache.mmio,
+            &mut cache.stats,
+            tables.generation,
+            address,
+            &[&tables.memory_io_devices, &tables.mmio_bars],
+        )
     }
 
-    fn handle_mmio_instruction_to_device(
-        vcpu: &mut VCpu<H>,
-        exit_info: &VmxExitInfo,
-        device: Arc<Mutex<dyn MmioOps>>,
-        instr: Option<Instruction>,
-    ) -> HyperResult {
-        if let Some(instr) = instr {
-            if let ept_info = vcpu
-                .nested_page_fault_info()
-                .expect("Failed to get nested page fault info")
+    /// Remove and return the MMIO device claiming `address`, failing like
+    /// [`DeviceList::remove_port_io_device`].
+    pub fn remove_memory_io_device(&self, address: u64) -> HyperResult<Arc<Mutex<dyn MmioOps>>> {
+        let mut removed = None;
+        self.try_update_tables(|tables| {
+            removed = Some(tables.remove_memory_io_device(address)?);
+            Ok(())
+        })?;
+        removed.ok_or(HyperError::BadState)
+    }
+
+    /// Add `handler` for the MMIO accesses to `range`, emulated without the instruction decoder
+    /// when they are plain `mov`s, see [`fast_mmio`]. The other accesses go through the full
+    /// decode to the same handler, like to a MMIO device, and fail like
+    /// [`DeviceList::add_memory_io_device`].
+    pub fn add_fast_mmio_device(&self, range: Range<u64>, handler: FastMmioHandler) -> HyperResult {
+        self.try_update_tables(|tables| {
+            let device = Arc::new(Mutex::new(FastMmioDevice::new(
+                range.clone(),
+                handler.clone(),
+            )));
+            insert_device(&mut tables.memory_io_devices, "MMIO", range.clone(), device)?;
+            // Cannot overlap, the ranges are a subset of those of the MMIO devices.
+            let _ = tables.fast_mmio_devices.insert(range, handler);
+            Ok(())
+        })
+    }
+
+    /// The fast MMIO handler of `address` and its range.
+    fn find_fast_mmio_device(&self, address: u64) -> Option<(Range<u64>, FastMmioHandler)> {
+        self.tables().fast_mmio_devices.find_entry(address).cloned()
+    }
+
+    /// Find the mapped MMIO BAR containing `address`, ignoring the other MMIO devices.
+    #[cfg(feature = "virtio-pci")]
+    fn find_mmio_bar(&self, address: u64) -> Option<Arc<Mutex<dyn MmioOps>>> {
+        self.tables().mmio_bars.find(address).cloned()
+    }
+
+    /// Add a MSR device, its range being read once, here. Fails with `InvalidParam` if the range
+    /// is empty, overlaps another device of this list, or contains an MSR passed through.
+    pub fn add_msr_device(&self, device: Arc<Mutex<dyn VirtMsrOps>>) -> HyperResult {
+        self.add_msr_devices(&mut vec![device])
+    }
+
+    /// Add the MSR devices drained from `devices`, none of them if one fails as in
+    /// [`DeviceList::add_msr_device`].
+    pub fn add_msr_devices(&self, devices: &mut Vec<Arc<Mutex<dyn VirtMsrOps>>>) -> HyperResult {
+        self.try_update_tables(|tables| {
+            for device in devices.drain(..) {
+                let range = device.lock().msr_range();
+                check_msr_passthrough(tables, &range)?;
+                let range = range.start as u64..range.end as u64;
+                insert_device(&mut tables.msr_devices, "MSR", range, device)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Read again the range of `device`, a port I/O, MMIO or MSR device of this list whose range
+    /// changed since it was added, e.g. moved by the guest. The PCI BARs are refreshed without
+    /// it, when the guest moves them.
+    ///
+    /// Fails with `BadState` if `device` is not in this list, and as the `add_*` method of its
+    /// kind, keeping its previous range, if the new one is rejected.
+    pub fn refresh_device_range<T: ?Sized>(&self, device: &Arc<Mutex<T>>) -> HyperResult {
+        let ptr = Arc::as_ptr(device) as *const ();
+        self.try_update_tables(|tables| {
+            if let Some((_, device)) = tables
+                .port_io_devices
+                .remove_by(|dev| Arc::as_ptr(dev) as *const () == ptr)
             {
-                let fault_addr = ept_info.fault_guest_paddr as u64;
-                let is_write = ept_info.access_flags.contains(MappingFlags::WRITE);
-                let access_size =
-                    get_access_size(instr.clone()).expect("Failed to get access size");
-                let (op_kind, op) = get_instr_data(instr.clone(), is_write)
-                    .expect("Failed to get instruction data");
-                if let Some(operand) = op {
-                    if is_write {
-                        let value = match op_kind {
-                            OpKind::Immediate8
-                            | OpKind::Immediate16
-                            | OpKind::Immediate32
-                            | OpKind::Immediate64 => operand.parse::<u64>().unwrap(),
-                            OpKind::Register => match operand {
-                                _ if operand.contains("a") => vcpu.regs().rax,
-                                _ if operand.contains("b") => vcpu.regs().rbx,
-                                _ if operand.contains("c") => vcpu.regs().rcx,
-                                _ if operand.contains("d") => vcpu.regs().rdx,
-                                _ if operand.contains("si") => vcpu.regs().rsi,
-                                _ if operand.contains("di") => vcpu.regs().rdi,
-                                _ if operand.contains("bp") => vcpu.regs().rbp,
-                                _ if operand.contains("r8") => vcpu.regs().r8,
-                                _ if operand.contains("r9") => vcpu.regs().r9,
-                                _ if operand.contains("r10") => vcpu.regs().r10,
-                                _ if operand.contains("r11") => vcpu.regs().r11,
-                                _ if operand.contains("r12") => vcpu.regs().r12,
-                                _ if operand.contains("r13") => vcpu.regs().r13,
-                                _ if operand.contains("r14") => vcpu.regs().r14,
-                                _ if operand.contains("r15") => vcpu.regs().r15,
-                                _ => return Err(HyperError::InvalidParam),
-                            },
-                            _ => return Err(HyperError::InvalidParam),
-                        };
-                        debug!("[handle_mmio_instruction_to_device] write value:{:#x} to fault addr:{:#x} access_size:{:#x}", value, fault_addr, access_size);
-                        let op_code = instr.op_code();
-                        match op_code.instruction_string().to_lowercase() {
-                            s if s.contains("mov") => {
-                                debug!("this is write mmio and instr: {}", s);
-                                device.lock().write(fault_addr, access_size, value)?;
-                            }
-                            _ => {
-                                error!("unrealized instruction:{:?}", op_code.instruction_string());
-                                return Err(HyperError::InstructionNotSupported);
-                            }
-                        };
-                    } else {
-                        debug!("[handle_mmio_instruction_to_device] read begin");
-                        let value = device.lock().read(fault_addr, access_size)?;
-                        debug!("[handle_mmio_instruction_to_device] read from fault addr:{:#x} value:{:#x} access_size:{:#x}", fault_addr, value, access_size);
-                        let (op_kind, op) = get_instr_data(instr.clone(), is_write)
-                            .expect("Failed to get instruction data");
-                        let op_code = instr.op_code();
-                        match op_code.instruction_string().to_lowercase() {
-                            s if s.contains("mov") => {
-                                debug!("this is read mmio and instr: {}", s);
-                                // mov instruction can only be used to write to register
-                                if op_kind != OpKind::Register {
-                                    debug!("opkind:{:?}", op_kind);
-                                    return Err(HyperError::InvalidParam);
-                                }
-                                // not consider segment register
-                                let reg = match operand {
-                                    _ if operand.contains("a") => &mut vcpu.regs_mut().rax,
-                                    _ if operand.contains("b") => &mut vcpu.regs_mut().rbx,
-                                    _ if operand.contains("c") => &mut vcpu.regs_mut().rcx,
-                                    _ if operand.contains("d") => &mut vcpu.regs_mut().rdx,
-                                    _ if operand.contains("si") => &mut vcpu.regs_mut().rsi,
-                                    _ if operand.contains("di") => &mut vcpu.regs_mut().rdi,
-                                    _ if operand.contains("bp") => &mut vcpu.regs_mut().rbp,
-                                    _ if operand.contains("r8") => &mut vcpu.regs_mut().r8,
-                                    _ if operand.contains("r9") => &mut vcpu.regs_mut().r9,
-                                    _ if operand.contains("r10") => &mut vcpu.regs_mut().r10,
-                                    _ if operand.contains("r11") => &mut vcpu.regs_mut().r11,
-                                    _ if operand.contains("r12") => &mut vcpu.regs_mut().r12,
-                                    _ if operand.contains("r13") => &mut vcpu.regs_mut().r13,
-                                    _ if operand.contains("r14") => &mut vcpu.regs_mut().r14,
-                                    _ if operand.contains("r15") => &mut vcpu.regs_mut().r15,
-                                    _ => return Err(HyperError::InvalidParam),
-                                };
-                                match access_size {
-                                    1 => *reg = (*reg & !0xff) | (value & 0xff) as u64,
-                                    2 => *reg = (*reg & !0xffff) | (value & 0xffff) as u64,
-                                    4 => {
-                                        *reg = (*reg & !0xffff_ffff) | (value & 0xffff_ffff) as u64
-                                    }
-                                    8 => *reg = value,
-                                    _ => unreachable!(),
-                                }
-                            }
-                            s if s.contains("test") => {
-                                debug!("this is read mmio and instr: {}", s);
-                                // test instruction use value from the other operand
-                                let value2 = match op_kind {
-                                    OpKind::Immediate8
-                                    | OpKind::Immediate16
-                                    | OpKind::Immediate32
-                                    | OpKind::Immediate64 => operand.parse::<u64>().unwrap(),
-                                    OpKind::Register => match operand {
-                                        _ if operand.contains("a") => vcpu.regs().rax,
-                                        _ if operand.contains("b") => vcpu.regs().rbx,
-                                        _ if operand.contains("c") => vcpu.regs().rcx,
-                                        _ if operand.contains("d") => vcpu.regs().rdx,
-                                        _ if operand.contains("si") => vcpu.regs().rsi,
-                                        _ if operand.contains("di") => vcpu.regs().rdi,
-                                        _ if operand.contains("bp") => vcpu.regs().rbp,
-                                        _ if operand.contains("r8") => vcpu.regs().r8,
-                                        _ if operand.contains("r9") => vcpu.regs().r9,
-                                        _ if operand.contains("r10") => vcpu.regs().r10,
-                                        _ if operand.contains("r11") => vcpu.regs().r11,
-                                        _ if operand.contains("r12") => vcpu.regs().r12,
-                                        _ if operand.contains("r13") => vcpu.regs().r13,
-                                        _ if operand.contains("r14") => vcpu.regs().r14,
-                                        _ if operand.contains("r15") => vcpu.regs().r15,
-                                        _ => return Err(HyperError::InvalidParam),
-                                    },
-                                    _ => return Err(HyperError::InvalidParam),
-                                };
-                                let result = match access_size {
-                                    1 => (value2 & value) & 0xff,
-                                    2 => (value2 & value) & 0xffff,
-                                    4 => (value2 & value) & 0xffff_ffff,
-                                    8 => value2 & value,
-                                    _ => unreachable!(),
-                                };
-                                /*
-                                 * OF and CF are cleared; the SF, ZF and PF flags are set
-                                 * according to the result; AF is undefined.
-                                 *
-                                 * The updated status flags are obtained by subtracting 0 from
-                                 * 'result'.
-                                 */
-                                let mut rflags = getcc(access_size, result, 0);
-                                debug!(
-                                    "value1:{:#x} value2:{:#x} rflags:{:#x}",
-                                    value, value2, rflags
-                                );
-                                // clear OF and CF
-                                rflags = rflags
-                                    & !(RFlags::OVERFLOW_FLAG.bits())
-                                    & !(RFlags::CARRY_FLAG.bits());
-                                // set mask for ZF, PF, SF, OF, CF
-                                let mask = RFlags::ZERO_FLAG.bits()
-                                    | RFlags::PARITY_FLAG.bits()
-                                    | RFlags::SIGN_FLAG.bits()
-                                    | RFlags::OVERFLOW_FLAG.bits()
-                                    | RFlags::CARRY_FLAG.bits();
-                                vcpu.set_guest_rflags(rflags as usize, mask as usize)?;
-                            }
-                            _ => {
-                                error!("unrealized instruction:{:?}", op_code.instruction_string());
-                                return Err(HyperError::InstructionNotSupported);
-                            }
-                        };
-                    }
-                }
-                vcpu.advance_rip(exit_info.exit_instruction_length as _)?;
-                debug!("===============");
-                return Ok(());
+                let range = device.lock().port_range();
+                let range = range.start as u64..range.end as u64;
+                insert_device(&mut tables.port_io_devices, "port I/O", range, device)
+            } else if let Some((_, device)) = tables
+                .memory_io_devices
+                .remove_by(|dev| Arc::as_ptr(dev) as *const () == ptr)
+            {
+                let range = device.lock().mmio_range();
+                insert_device(&mut tables.memory_io_devices, "MMIO", range, device)
+            } else if let Some((_, device)) = tables
+                .msr_devices
+                .remove_by(|dev| Arc::as_ptr(dev) as *const () == ptr)
+            {
+                let range = device.lock().msr_range();
+                check_msr_passthrough(tables, &range)?;
+                let range = range.start as u64..range.end as u64;
+                insert_device(&mut tables.msr_devices, "MSR", range, device)
             } else {
-                panic!(
-                    "VM exit: EPT violation with unknown fault info @ {:#x}, vcpu: {:#x?}",
-                    exit_info.guest_rip, vcpu
-                );
+                Err(HyperError::BadState)
             }
-        }
-        Err(HyperError::InvalidInstruction)
+        })
     }
 
-    pub fn handle_mmio_instruction(
-        &mut self,
-        vcpu: &mut VCpu<H>,
-        exit_info: &VmxExitInfo,
-        instr: Option<Instruction>,
-    ) -> Option<HyperResult> {
-        match vcpu.nested_page_fault_info() {
-            Ok(fault_info) => {
-                // debug!(
-                //     "VM exit: EPT violation @ {:#x}, fault_paddr={:#x}, access_flags=({:?}), vcpu: {:#x?}",
-                //     exit_info.guest_rip, fault_info.fault_guest_paddr, fault_info.access_flags, vcpu
-                // );
-                if let Some(dev) = self.find_memory_io_device(fault_info.fault_guest_paddr as u64) {
-                    return Some(Self::handle_mmio_instruction_to_device(
-                        vcpu, exit_info, dev, instr,
-                    ));
-                }
+    /// Let the guest read `msr`, if `read`, and write it, if `write`, without exits, on the
+    /// vCPU of this per-vCPU list, from its next VM entry. Both `false` restore the exits.
+    ///
+    /// Fails with `InvalidParam` if the MSR bitmap does not cover `msr`, or if a device of this
+    /// list or of the per-VM list implements it: an MSR is either emulated or passed through.
+    pub fn set_msr_passthrough(&self, msr: u32, read: bool, write: bool) -> HyperResult {
+        if self.vcpu_id.is_none() {
+            warn!("MSR {:#x}: the MSR bitmap belongs to a vCPU", msr);
+            return Err(HyperError::NotSupported);
+        }
+        if !msr_bitmap::covers(msr) {
+            warn!("MSR {:#x} is not covered by the MSR bitmap", msr);
+            return Err(HyperError::InvalidParam);
+        }
+        let vm_claims =
+            crate::vm::current_vm_id().map_or(false, |vm_id| dispatch::vm_claims_msr(vm_id, msr));
+        let mut result = Ok(());
+        self.update_tables(|tables| {
+            if vm_claims || tables.msr_devices.find(msr as u64).is_some() {
                 warn!(
-                    "VM exit Error: EPT violation @ {:#x}\nFault_paddr={:#x} access_flags=({:?}), vcpu: {:#x?}",
-                    exit_info.guest_rip, fault_info.fault_guest_paddr, fault_info.access_flags, vcpu
+                    "MSR {:#x} is implemented by a device, cannot pass it through",
+                    msr
                 );
-                return Some(Err(HyperError::InValidMmio));
+                result = Err(HyperError::InvalidParam);
+            } else if read || write {
+                tables
+                    .msr_passthrough
+                    .insert(msr, MsrPassthrough { read, write });
+            } else {
+                tables.msr_passthrough.remove(&msr);
             }
-            Err(_err) => panic!(
-                "VM exit: EPT violation with unknown fault info @ {:#x}, vcpu: {:#x?}",
-                exit_info.guest_rip, vcpu
-            ),
-        }
-        None
+        });
+        result
     }
 
-    pub fn handle_msr_read(&mut self, vcpu: &mut VCpu<H>) -> HyperResult {
-        let msr = vcpu.regs().rcx as u32;
-
-        if let Some(dev) = self.find_msr_device(msr) {
-            match dev.lock().read(msr) {
-                Ok(value) => {
-                    trace!("VM exit: RDMSR({:#x}) -> {:#x}", msr, value);
-
-                    vcpu.regs_mut().rax = value & 0xffff_ffff;
-                    vcpu.regs_mut().rdx = value >> 32;
+    /// Write the MSRs passed through to the MSR bitmap of the vCPU running on the current CPU, if
+    /// they changed since `applied` was synced.
+    fn sync_msr_bitmap(&self, applied: &mut AppliedPassthrough) {
+        let tables = self.tables.snapshot();
+        applied.sync(tables.generation, &tables.msr_passthrough);
+    }
 
-                    vcpu.advance_rip(VM_EXIT_INSTR_LEN_RDMSR)?;
-                    Ok(())
-                }
-                Err(e) => {
-                    panic!("Failed to handle RDMSR({:#x}): {:?}", msr, e);
-                }
-            }
-        } else {
-            panic!("Unsupported RDMSR {:#x}, vcpu: {:#x?}", msr, vcpu);
+    /// The MSR device implementing `msr`, from `cache` if it was the last one hit.
+    pub(crate) fn find_msr_device(
+        &self,
+        cache: &mut DispatchCache,
+        msr: u32,
+    ) -> Option<Arc<Mutex<dyn VirtMsrOps>>> {
+        let generation = self.tables.generation.load(Ordering::Acquire);
+        if let Some(device) = cached_device(&cache.msr, &mut cache.stats, generation, msr as u64) {
+            return Some(device);
         }
+        let tables = self.tables.snapshot();
+        lookup_device(
+            &mut cache.msr,
+            &mut cache.stats,
+            tables.generation,
+            msr as u64,
+            &[&tables.msr_devices],
+        )
     }
 
-    pub fn handle_msr_write(&mut self, vcpu: &mut VCpu<H>) -> HyperResult {
-        let msr = vcpu.regs().rcx as u32;
-        let value = (vcpu.regs().rax & 0xffff_ffff) | (vcpu.regs().rdx << 32);
+    /// The typed handle of `device`, if it was added by a typed `add_*` method as a `T`.
+    fn typed_device<T: Send + 'static, D: ?Sized>(
+        tables: &DeviceTables,
+        device: &Arc<Mutex<D>>,
+    ) -> Option<Arc<Mutex<T>>> {
+        let ptr = Arc::as_ptr(device) as *const ();
+        tables
+            .typed_devices
+            .iter()
+            .filter(|typed| Arc::as_ptr(typed) as *const () == ptr)
+            .find_map(|typed| typed.clone().downcast::<Mutex<T>>().ok())
+    }
 
-        if let Some(dev) = self.find_msr_device(msr) {
-            match dev.lock().write(msr, value) {
-                Ok(_) => {
-                    trace!("VM exit: WRMSR({:#x}) <- {:#x}", msr, value);
+    /// Add a port I/O device which can be retrieved by [`DeviceList::get_port_io_device_as`].
+    pub fn add_typed_port_io_device<T: PioOps + Send + 'static>(
+        &self,
+        device: Arc<Mutex<T>>,
+    ) -> HyperResult {
+        self.add_port_io_device(device.clone())?;
+        self.update_tables(|tables| tables.typed_devices.push(device));
+        Ok(())
+    }
 
-                    vcpu.advance_rip(VM_EXIT_INSTR_LEN_WRMSR)?;
-                    Ok(())
-                }
-                Err(e) => {
-                    panic!("Failed to handle WRMSR({:#x}): {:?}", msr, e);
+    /// Let the `rep outs` to `device`, a port I/O device of this list, go to
+    /// [`PioBatchOps::write_batch`] instead of one write per element.
+    pub fn add_batch_writer<T: PioBatchOps + Send + 'static>(&self, device: Arc<Mutex<T>>) {
+        self.update_tables(|tables| tables.batch_writers.push(device.clone()));
+    }
+
+    /// The batch handle of `device`, if it was added by [`DeviceList::add_batch_writer`].
+    fn batch_writer(&self, device: &Arc<Mutex<dyn PioOps>>) -> Option<Arc<Mutex<dyn PioBatchOps>>> {
+        let ptr = Arc::as_ptr(device) as *const ();
+        self.tables()
+            .batch_writers
+            .iter()
+            .find(|writer| Arc::as_ptr(writer) as *const () == ptr)
+            .cloned()
+    }
+
+    /// The port I/O device handling `port`, if it is a `T` added by
+    /// [`DeviceList::add_typed_port_io_device`].
+    pub fn get_port_io_device_as<T: PioOps + Send + 'static>(
+        &self,
+        port: u16,
+    ) -> Option<Arc<Mutex<T>>> {
+        let tables = self.tables();
+        let device = tables.port_io_devices.find(port as u64)?;
+        Self::typed_device(&tables, device)
+    }
+
+    /// Add a MMIO device which can be retrieved by [`DeviceList::get_memory_io_device_as`].
+    pub fn add_typed_memory_io_device<T: MmioOps + Send + 'static>(
+        &self,
+        device: Arc<Mutex<T>>,
+    ) -> HyperResult {
+        self.add_memory_io_device(device.clone())?;
+        self.update_tables(|tables| tables.typed_devices.push(device));
+        Ok(())
+    }
+
+    /// The MMIO device handling `address`, if it is a `T` added by
+    /// [`DeviceList::add_typed_memory_io_device`].
+    pub fn get_memory_io_device_as<T: MmioOps + Send + 'static>(
+        &self,
+        address: u64,
+    ) -> Option<Arc<Mutex<T>>> {
+        let tables = self.tables();
+        let device = tables.memory_io_devices.find(address)?;
+        Self::typed_device(&tables, device)
+    }
+
+    /// Add a MSR device which can be retrieved by [`DeviceList::get_msr_device_as`].
+    pub fn add_typed_msr_device<T: VirtMsrOps + Send + 'static>(
+        &self,
+        device: Arc<Mutex<T>>,
+    ) -> HyperResult {
+        self.add_msr_device(device.clone())?;
+        self.update_tables(|tables| tables.typed_devices.push(device));
+        Ok(())
+    }
+
+    /// The MSR device handling `msr`, if it is a `T` added by
+    /// [`DeviceList::add_typed_msr_device`].
+    pub fn get_msr_device_as<T: VirtMsrOps + Send + 'static>(
+        &self,
+        msr: u32,
+    ) -> Option<Arc<Mutex<T>>> {
+        let tables = self.tables();
+        let device = tables.msr_devices.find(msr as u64)?;
+        Self::typed_device(&tables, device)
+    }
+
+    /// Register `device` for [`DeviceList::save_all`] and [`DeviceList::restore_all`], under
+    /// `name`. The device is not added to the dispatch tables.
+    pub fn add_stateful_device<T: DeviceState + Send + 'static>(
+        &self,
+        name: &'static str,
+        device: Arc<Mutex<T>>,
+    ) {
+        self.update_tables(|tables| tables.stateful_devices.push((name, device)));
+    }
+
+    /// The state of the registered devices, in registration order.
+    pub fn save_all(&self) -> Vec<u8> {
+        let tables = self.tables();
+        let mut state = StateWriter::new(DEVICE_LIST_STATE_VERSION);
+        state.u32(tables.stateful_devices.len() as u32);
+        for (name, device) in tables.stateful_devices.iter() {
+            state.bytes(name.as_bytes()).bytes(&device.lock().save());
+        }
+        state.finish()
+    }
+
+    /// Restore a state returned by [`DeviceList::save_all`] for the same devices. A device
+    /// rejecting its state stops the restore, the devices before it being already restored.
+    pub fn restore_all(&self, state: &[u8]) -> HyperResult {
+        let tables = self.tables();
+        let mut state = StateReader::new(state, DEVICE_LIST_STATE_VERSION)?;
+        if state.u32()? as usize != tables.stateful_devices.len() {
+            warn!("device list state for another set of devices");
+            return Err(HyperError::InvalidParam);
+        }
+        let mut device_states = Vec::with_capacity(tables.stateful_devices.len());
+        for (name, _) in tables.stateful_devices.iter() {
+            let saved_name = state.bytes()?;
+            if saved_name != name.as_bytes() {
+                warn!("device list state does not match device {}", name);
+                return Err(HyperError::InvalidParam);
+            }
+            device_states.push(state.bytes()?);
+        }
+        state.finish()?;
+
+        for ((name, device), device_state) in tables.stateful_devices.iter().zip(device_states) {
+            device.lock().restore(device_state).map_err(|err| {
+                warn!("failed to restore device {}: {:?}", name, err);
+                err
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The ranges claimed by the devices of this list, mapped BARs included.
+    fn claimed_ranges(&self) -> ClaimedRanges {
+        self.tables().claimed_ranges()
+    }
+
+    /// Dispatch a port I/O, MSR or, given the instruction, MMIO exit to the devices of this list.
+    ///
+    /// Returns `None` if no device of this list claims the access, see [`dispatch`] for the
+    /// order in which the lists are tried.
+    fn dispatch_exit(
+        &self,
+        cache: &mut DispatchCache,
+        vcpu: &mut VCpu<H>,
+        ctx: &mut ExitContext,
+        instr: Option<&mut LazyInstr>,
+    ) -> Option<HyperResult> {
+        match ctx.exit_reason {
+            VmxExitReason::IO_INSTRUCTION => self.handle_io_instruction(cache, vcpu, ctx),
+            VmxExitReason::MSR_READ => self.handle_msr_read(cache, vcpu, ctx),
+            VmxExitReason::MSR_WRITE => self.handle_msr_write(cache, vcpu, ctx),
+            VmxExitReason::EPT_VIOLATION => self.handle_mmio_instruction(cache, vcpu, ctx, instr?),
+            _ => None,
+        }
+    }
+
+    /// The exit handler of the per-VM device lists, `ext_intr_level` being the level at which
+    /// host interrupts are logged.
+    fn handle_vm_exit(
+        &self,
+        cache: &mut DispatchCache,
+        vcpu: &mut VCpu<H>,
+        exit_info: &VmExitInfo,
+        instr: Option<Instruction>,
+        ext_intr_level: Level,
+    ) -> Option<HyperResult> {
+        // Captured by the per-vCPU devices, which have already seen this exit.
+        let mut ctx = ExitContext::current(exit_info);
+        // Only read and decoded for the exits which need it, hypercraft's decode is a fallback.
+        let mut instr = LazyInstr::new(&ctx, instr);
+        match exit_info.exit_reason {
+            VmxExitReason::IO_INSTRUCTION | VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE => {
+                // Only decoded for the check, in debug builds.
+                let decoded = if cfg!(debug_assertions) {
+                    instr.get()
+                } else {
+                    None
+                };
+                debug_check_exit_instr_len(exit_info, decoded)
+            }
+            _ => {}
+        }
+        let result = match exit_info.exit_reason {
+            VmxExitReason::EXTERNAL_INTERRUPT => {
+                Some(handle_external_interrupt(&mut ctx, ext_intr_level))
+            }
+            // RAM populated on demand or shared with a fork is mapped and the access retried,
+            // before the MMIO devices.
+            VmxExitReason::EPT_VIOLATION => self
+                .handle_ram_fault(&mut ctx)
+                .or_else(|| self.dispatch_exit(cache, vcpu, &mut ctx, Some(&mut instr))),
+            // A guest resets the machine this way too, the host cannot.
+            VmxExitReason::TRIPLE_FAULT
+                if self
+                    .vm_id
+                    .map_or(false, |vm_id| vm_id != crate::vm::HOST_VM_ID) =>
+            {
+                warn!("VM [{}] triple fault", self.vm_id());
+                diagnostics::dump_vcpu_state(Level::Warn, vcpu, &ctx);
+                Some(crate::vm::guest_reset("triple fault"))
+            }
+            _ => self.dispatch_exit(cache, vcpu, &mut ctx, Some(&mut instr)),
+        };
+        // The per-vCPU devices have already declined this exit, nobody else will handle it.
+        let result = result.or_else(|| self.unclaimed_port(vcpu, &ctx));
+        let result = result.unwrap_or_else(|| {
+            unhandled_exit(
+                self.vm_id(),
+                vcpu,
+                &ctx,
+                self.msr_policy.unwrap_or_default(),
+            )
+        });
+        observe_exit_end(vcpu.vcpu_id(), exit_info, &result);
+        exit_stats::finish_deferred_exit(vcpu, exit_info, cache.stats());
+        Some(result)
+    }
+
+    /// Map the page of guest RAM populated on demand or shared with a fork the EPT violation of
+    /// `ctx` faulted on, see [`crate::mm::handle_ram_fault`]. `None` if the fault is not on such a
+    /// page.
+    fn handle_ram_fault(&self, ctx: &mut ExitContext) -> Option<HyperResult> {
+        let vm_id = self.vm_id?;
+        let fault_info = ctx.ept_violation_info().ok()?;
+        crate::mm::handle_ram_fault(vm_id, fault_info.fault_guest_paddr, fault_info.access_flags)
+    }
+
+    /// Ignore the port I/O of `ctx`, which no device claims, if the policy of this list says so,
+    /// see [`unclaimed_port`]. RIP is advanced, and string I/O emulated, as for a device.
+    fn unclaimed_port(&self, vcpu: &mut VCpu<H>, ctx: &ExitContext) -> Option<HyperResult> {
+        let seen_ports = self.seen_ports.as_ref()?;
+        let io_info = ctx.io_exit_info().ok()?;
+        seen_ports.record(self.vm_id(), io_info.port, io_info.is_in, ctx.guest_rip);
+        Some(Self::ignore_io_instruction(
+            vcpu,
+            ctx,
+            &io_info,
+            self.vm_id(),
+        ))
+    }
+
+    /// Answer the port I/O of `io_info`, of the guest of `vm_id`, as from a bus no device
+    /// decodes: all ones for every byte, even past port 0xffff, the writes dropped.
+    fn ignore_io_instruction<V: ExitVcpu>(
+        vcpu: &mut V,
+        ctx: &ExitContext,
+        io_info: &IoExitInfo,
+        vm_id: u32,
+    ) -> HyperResult {
+        if io_info.is_string {
+            let mut mem = string_io::VmxGuestMemory::current(vm_id).ok_or(HyperError::BadState)?;
+            let operands = string_io::StringIoOperands::current(io_info.is_in);
+            return string_io::emulate(vcpu, ctx, io_info, operands, &mut UnclaimedPort, &mut mem);
+        }
+        if io_info.is_in {
+            write_in_result(&mut vcpu.regs_mut().rax, io_info.access_size, u64::MAX);
+        }
+        vcpu.advance_rip(ctx.exit_instruction_length as _)
+    }
+
+    /// Handle a port I/O exit on `device`, a `rep outs` going to `batch_writer` if there is one.
+    fn handle_io_instruction_to_device<V: ExitVcpu>(
+        vcpu: &mut V,
+        ctx: &ExitContext,
+        device: Arc<Mutex<dyn PioOps>>,
+        batch_writer: Option<Arc<Mutex<dyn PioBatchOps>>>,
+    ) -> HyperResult {
+        let io_info = ctx.io_exit_info()?;
+        ratelimited!(
+            IO_EXIT_LOG,
+            Level::Trace,
+            "VM exit: I/O instruction @ {:#x}: {:#x?}",
+            ctx.guest_rip,
+            io_info,
+        );
+
+        let mut locked_device = device.lock();
+        let mut device = ClippedPio {
+            device: &mut *locked_device,
+        };
+        let inside = device.inside(io_info.port, io_info.access_size)?;
+        if io_info.is_string {
+            let vm_id = crate::vm::current_vm_id().ok_or(HyperError::BadState)?;
+            let mut mem = string_io::VmxGuestMemory::current(vm_id).ok_or(HyperError::BadState)?;
+            let operands = string_io::StringIoOperands::current(io_info.is_in);
+            // The writer takes the elements whole.
+            let batch_writer = batch_writer.filter(|_| whole_access(&inside, io_info.access_size));
+            if let Some(writer) = batch_writer {
+                // The same device, behind the same lock.
+                drop(locked_device);
+                let mut writer = writer.lock();
+                return string_io::emulate_batch(
+                    vcpu,
+                    ctx,
+                    &io_info,
+                    operands,
+                    &mut *writer,
+                    &mut mem,
+                );
+            }
+            return string_io::emulate(vcpu, ctx, &io_info, operands, &mut device, &mut mem);
+        }
+        if io_info.is_in {
+            let value = device.read(io_info.port, io_info.access_size)?;
+            write_in_result(&mut vcpu.regs_mut().rax, io_info.access_size, value as u64);
+        } else {
+            // The operand size of the instruction, from the exit qualification, not the mode.
+            let value = vcpu.regs().rax & access_size_mask(io_info.access_size);
+            device.write(io_info.port, io_info.access_size, value as u32)?;
+        }
+        vcpu.advance_rip(ctx.exit_instruction_length as _)?;
+        Ok(())
+    }
+
+    pub(crate) fn handle_io_instruction(
+        &self,
+        cache: &mut DispatchCache,
+        vcpu: &mut VCpu<H>,
+        ctx: &ExitContext,
+    ) -> Option<HyperResult> {
+        let io_info = ctx.io_exit_info().unwrap();
+        let dev = self.find_port_io_device(cache, io_info.port)?;
+        let batch_writer = if io_info.is_string && io_info.is_repeat && !io_info.is_in {
+            self.batch_writer(&dev)
+        } else {
+            None
+        };
+        let ret = Some(Self::handle_io_instruction_to_device(
+            vcpu,
+            ctx,
+            dev,
+            batch_writer,
+        ));
+        self.complete_virtio_pci_cfg_req(vcpu, ret)
+    }
+
+    /// Deal with the virtio pci cfg access cap, whose accesses are recorded by the PCI host while
+    /// handling the port I/O. Returns the result of the exit, `ret` if there was no such access.
+    #[cfg(feature = "virtio-pci")]
+    fn complete_virtio_pci_cfg_req(
+        &self,
+        vcpu: &mut VCpu<H>,
+        mut ret: Option<HyperResult>,
+    ) -> Option<HyperResult> {
+        let mmio_req = take_virtio_pci_cfg_req();
+        if let Some(req) = mmio_req.as_ref() {
+            // this mmio req can only be generated from pci config read(virtio pci cfg access cap), so do not check mmio_ops in the devicelist
+            if self.pci_devices.is_some() {
+                let addr = req.addr;
+                if let Some(mmio_ops) = self.find_mmio_bar(addr) {
+                    let access_size = req.len;
+                    let mut mmio_ops = mmio_ops.lock();
+                    let inside = match check_access(
+                        mmio_ops.mmio_range(),
+                        addr,
+                        access_size,
+                        MMIO_ACCESS_SIZES,
+                    ) {
+                        Ok(inside) => inside,
+                        Err(err) => return Some(Err(err)),
+                    };
+                    if req.is_write {
+                        let mut bytes = [0u8; 8];
+                        bytes.copy_from_slice(&(req.data)[..8]);
+                        let value = u64::from_le_bytes(bytes) & access_size_mask(access_size);
+                        let ret = Some(split_write(
+                            inside,
+                            addr,
+                            access_size,
+                            value,
+                            |addr, size, value| mmio_ops.write(addr, size, value),
+                        ));
+                    } else {
+                        let value = split_read(inside, addr, access_size, |addr, size| {
+                            mmio_ops.read(addr, size)
+                        })
+                        .ok()?;
+                        // The result of the `in` of the config data port.
+                        write_in_result(&mut vcpu.regs_mut().rax, access_size, value);
+                        ret = Some(Ok(()))
+                    }
                 }
             }
+        }
+        ret
+    }
+
+    /// Without the virtio PCI devices, there is no pci cfg access cap.
+    #[cfg(not(feature = "virtio-pci"))]
+    fn complete_virtio_pci_cfg_req(
+        &self,
+        _vcpu: &mut VCpu<H>,
+        ret: Option<HyperResult>,
+    ) -> Option<HyperResult> {
+        ret
+    }
+
+    /// Emulate the MMIO access of `mmio` to `fault_addr`, in `range`, with `access`.
+    ///
+    /// The direction comes from the violation. Loads (`mov`, `movzx`, `movsx`, `movsxd`), `test`
+    /// and stores of a register or an immediate (`mov`) are supported.
+    fn emulate_mmio_instr<A: FnMut(MmioAccess) -> HyperResult<u64>>(
+        vcpu: &mut VCpu<H>,
+        range: Range<u64>,
+        fault_addr: u64,
+        mmio: MmioInstr,
+        mut access: A,
+    ) -> HyperResult {
+        let access_size = mmio.access_size;
+        let inside = check_access(range, fault_addr, access_size, MMIO_ACCESS_SIZES)?;
+        let operand = mmio.operand;
+        if mmio.is_write {
+            let value = operand_value(vcpu, operand)?;
+            ratelimited!(
+                MMIO_EXIT_LOG,
+                Level::Debug,
+                "[emulate_mmio_instr] write value:{:#x} to fault addr:{:#x} access_size:{:#x}",
+                value,
+                fault_addr,
+                access_size
+            );
+            let value = value & access_size_mask(access_size);
+            split_write(
+                inside,
+                fault_addr,
+                access_size,
+                value,
+                |addr, size, value| {
+                    access(MmioAccess {
+                        addr,
+                        access_size: size,
+                        write: Some(value),
+                    })
+                    .map(|_| ())
+                },
+            )?;
         } else {
-            panic!("Unsupported WRMSR {:#x}, vcpu: {:#x?}", msr, vcpu);
+            let value = split_read(inside, fault_addr, access_size, |addr, size| {
+                access(MmioAccess {
+                    addr,
+                    access_size: size,
+                    write: None,
+                })
+            })? & access_size_mask(access_size);
+            ratelimited!(
+                MMIO_EXIT_LOG,
+                Level::Debug,
+                "[emulate_mmio_instr] read from fault addr:{:#x} value:{:#x} access_size:{:#x}",
+                fault_addr,
+                value,
+                access_size
+            );
+            match mmio.op {
+                // The value is already zero-extended.
+                MmioOp::Mov => match operand {
+                    Operand::Register(reg) => write_gpr(vcpu, reg, value)?,
+                    Operand::Immediate(_) => return Err(HyperError::DecodeError),
+                },
+                MmioOp::Movsx => match operand {
+                    Operand::Register(reg) => {
+                        let shift = 64 - access_size as u32 * 8;
+                        let value = (((value << shift) as i64) >> shift) as u64;
+                        write_gpr(vcpu, reg, value)?
+                    }
+                    Operand::Immediate(_) => return Err(HyperError::DecodeError),
+                },
+                MmioOp::Test => {
+                    // test instruction use value from the other operand
+                    let value2 = operand_value(vcpu, operand)?;
+                    let result = (value2 & value) & access_size_mask(access_size);
+                    /*
+                     * OF and CF are cleared; the SF, ZF and PF flags are set
+                     * according to the result; AF is undefined.
+                     *
+                     * The updated status flags are obtained by subtracting 0 from
+                     * 'result'.
+                     */
+                    let mut rflags = getcc(access_size, result, 0);
+                    ratelimited!(
+                        MMIO_EXIT_LOG,
+                        Level::Debug,
+                        "value1:{:#x} value2:{:#x} rflags:{:#x}",
+                        value,
+                        value2,
+                        rflags
+                    );
+                    // clear OF and CF
+                    rflags =
+                        rflags & !(RFlags::OVERFLOW_FLAG.bits()) & !(RFlags::CARRY_FLAG.bits());
+                    // set mask for ZF, PF, SF, OF, CF
+                    let mask = RFlags::ZERO_FLAG.bits()
+                        | RFlags::PARITY_FLAG.bits()
+                        | RFlags::SIGN_FLAG.bits()
+                        | RFlags::OVERFLOW_FLAG.bits()
+                        | RFlags::CARRY_FLAG.bits();
+                    vcpu.set_guest_rflags(rflags as usize, mask as usize)?;
+                }
+            };
+        }
+        // The exit instruction length is not defined for EPT violations, use the decoded one.
+        vcpu.advance_rip(mmio.len as _)?;
+        Ok(())
+    }
+
+    /// The MMIO instruction of the current EPT violation, from the [`decode_cache`] of this CPU
+    /// or decoded from the bytes at guest RIP, or, for a `fast` handler, recognised by
+    /// [`fast_mmio::decode_mov`].
+    ///
+    /// The bytes are decoded with the operand and address sizes of the code, from the mode of the
+    /// guest and CS. The instruction decoded by hypercraft is only used when these bytes cannot
+    /// be read or decoded, for an instruction crossing a page, and is not cached: it may not have
+    /// been decoded from the same bytes.
+    fn decode_mmio_instr(
+        &self,
+        fast: bool,
+        is_write: bool,
+        instr: &mut LazyInstr,
+    ) -> HyperResult<MmioInstr> {
+        let Some(code) = instr.code().cloned() else {
+            let instr = instr.get().ok_or(HyperError::InvalidInstruction)?;
+            return mmio_instr(instr, is_write);
+        };
+        let cache = &self.decode_cache[current_cpu_id()];
+        let cached = cache
+            .lock()
+            .get(&code)
+            .filter(|mmio| mmio.is_write == is_write);
+        exit_stats::count_mmio_decode(cached.is_some());
+        if let Some(mmio) = cached {
+            return Ok(mmio);
+        }
+        let mov = fast
+            .then(|| fast_mmio::decode_mov(code.bytes(), code.bitness))
+            .flatten()
+            .filter(|mov| mov.is_write == is_write);
+        let mmio = match mov {
+            Some(mov) => MmioInstr {
+                len: mov.len,
+                access_size: mov.access_size,
+                operand: mov.operand,
+                op: MmioOp::Mov,
+                is_write,
+            },
+            None => match instr.decoded() {
+                Some(decoded) => mmio_instr(decoded, is_write)?,
+                None => {
+                    let instr = instr.get().ok_or(HyperError::InvalidInstruction)?;
+                    return mmio_instr(instr, is_write);
+                }
+            },
+        };
+        cache.lock().insert(&code, mmio);
+        Ok(mmio)
+    }
+
+    pub(crate) fn handle_mmio_instruction(
+        &self,
+        cache: &mut DispatchCache,
+        vcpu: &mut VCpu<H>,
+        ctx: &mut ExitContext,
+        instr: &mut LazyInstr,
+    ) -> Option<HyperResult> {
+        match ctx.ept_violation_info() {
+            Ok(fault_info) => {
+                // debug!(
+                //     "VM exit: EPT violation @ {:#x}, fault_paddr={:#x}, access_flags=({:?}), vcpu: {:#x?}",
+                //     exit_info.guest_rip, fault_info.fault_guest_paddr, fault_info.access_flags, vcpu
+                // );
+                let fault_addr = fault_info.fault_guest_paddr;
+                let is_write = fault_info.access_flags.contains(MappingFlags::WRITE);
+                if let Some((range, handler)) = self.find_fast_mmio_device(fault_addr) {
+                    let mmio = self.decode_mmio_instr(true, is_write, instr);
+                    let result = mmio.and_then(|mmio| {
+                        Self::emulate_mmio_instr(vcpu, range, fault_addr, mmio, &*handler)
+                    });
+                    return Some(result);
+                }
+                if let Some(dev) = self.find_memory_io_device(cache, fault_addr) {
+                    let range = dev.lock().mmio_range();
+                    let mmio = self.decode_mmio_instr(false, is_write, instr);
+                    let result = mmio.and_then(|mmio| {
+                        Self::emulate_mmio_instr(vcpu, range, fault_addr, mmio, |access| {
+                            mmio_device_access(&dev, access)
+                        })
+                    });
+                    return Some(result);
+                }
+                warn!(
+                    "VM exit Error: EPT violation @ {:#x}\nFault_paddr={:#x} linear_addr={:#x?} access_flags=({:?}), vcpu: {:#x?}",
+                    ctx.guest_rip, fault_info.fault_guest_paddr, ctx.guest_linear_addr(), fault_info.access_flags, vcpu
+                );
+                return Some(Err(HyperError::InValidMmio));
+            }
+            Err(err) => {
+                warn!(
+                    "VM exit: EPT violation with unknown fault info @ {:#x}: {:?}",
+                    ctx.guest_rip, err
+                );
+                Some(Err(err))
+            }
+        }
+    }
+
+    /// Handle a RDMSR exit, `None` if no device of this list implements the MSR and the exit is
+    /// left to the next list.
+    pub(crate) fn handle_msr_read(
+        &self,
+        cache: &mut DispatchCache,
+        vcpu: &mut VCpu<H>,
+        ctx: &ExitContext,
+    ) -> Option<HyperResult> {
+        let msr = vcpu.regs().rcx as u32;
+        match self.find_msr_device(cache, msr) {
+            Some(dev) => Some(Self::handle_msr_read_to_device(vcpu, ctx, msr, dev)),
+            None => self.unclaimed_msr(vcpu, ctx, msr),
+        }
+    }
+
+    fn handle_msr_read_to_device<V: ExitVcpu>(
+        vcpu: &mut V,
+        ctx: &ExitContext,
+        msr: u32,
+        dev: Arc<Mutex<dyn VirtMsrOps>>,
+    ) -> HyperResult {
+        match dev.lock().read(msr) {
+            Ok(value) => {
+                ratelimited!(
+                    MSR_EXIT_LOG,
+                    Level::Trace,
+                    "VM exit: RDMSR({:#x}) -> {:#x}",
+                    msr,
+                    value
+                );
+
+                vcpu.regs_mut().rax = value & 0xffff_ffff;
+                vcpu.regs_mut().rdx = value >> 32;
+
+                vcpu.advance_rip(ctx.exit_instruction_length as _)?;
+                Ok(())
+            }
+            // What the guest did was invalid (a write-only or reserved MSR), as with the device
+            // errors of WRMSR.
+            Err(err) => {
+                ratelimited!(
+                    MSR_EXIT_LOG,
+                    Level::Warn,
+                    "VM exit: RDMSR({:#x}) failed: {:?}, inject #GP @ {:#x}",
+                    msr,
+                    err,
+                    ctx.guest_rip
+                );
+                inject_gp(vcpu);
+                Ok(())
+            }
+        }
+    }
+
+    /// Handle a WRMSR exit, `None` if no device of this list implements the MSR and the exit is
+    /// left to the next list.
+    pub(crate) fn handle_msr_write(
+        &self,
+        cache: &mut DispatchCache,
+        vcpu: &mut VCpu<H>,
+        ctx: &ExitContext,
+    ) -> Option<HyperResult> {
+        let msr = vcpu.regs().rcx as u32;
+        match self.find_msr_device(cache, msr) {
+            Some(dev) => Some(Self::handle_msr_write_to_device(vcpu, ctx, msr, dev)),
+            None => self.unclaimed_msr(vcpu, ctx, msr),
+        }
+    }
+
+    /// Apply the policy of a per-vCPU list to `msr`, implemented by none of its devices, unless a
+    /// per-VM device implements it. The per-VM list applies its policy in [`unhandled_exit`].
+    fn unclaimed_msr(
+        &self,
+        vcpu: &mut VCpu<H>,
+        ctx: &ExitContext,
+        msr: u32,
+    ) -> Option<HyperResult> {
+        let vcpu_id = self.vcpu_id? as usize;
+        let policy = self.msr_policy?;
+        let vm_id = crate::vm::current_vm_id()?;
+        // Not claimed by this list, the MSR goes on down the chain.
+        if dispatch::dispatch_level(vm_id, vcpu_id, Space::Msr, msr as u64) == DispatchLevel::Vm {
+            return None;
+        }
+        Some(unhandled_msr(vcpu, ctx, msr, policy))
+    }
+
+    fn handle_msr_write_to_device<V: ExitVcpu>(
+        vcpu: &mut V,
+        ctx: &ExitContext,
+        msr: u32,
+        dev: Arc<Mutex<dyn VirtMsrOps>>,
+    ) -> HyperResult {
+        let value = (vcpu.regs().rax & 0xffff_ffff) | (vcpu.regs().rdx << 32);
+
+        let mut dev = dev.lock();
+        if !msr_spec::check_msr_write(msr, value, || dev.read(msr).ok()) {
+            warn!(
+                "VM exit: WRMSR({:#x}) <- {:#x} rejected, inject #GP @ {:#x}",
+                msr, value, ctx.guest_rip
+            );
+            inject_gp(vcpu);
+            return Ok(());
+        }
+        match dev.write(msr, value) {
+            Ok(_) => {
+                ratelimited!(
+                    MSR_EXIT_LOG,
+                    Level::Trace,
+                    "VM exit: WRMSR({:#x}) <- {:#x}",
+                    msr,
+                    value
+                );
+
+                vcpu.advance_rip(ctx.exit_instruction_length as _)?;
+                Ok(())
+            }
+            // The device rejected the value, as the MSR of the CPU would with #GP.
+            Err(err) => {
+                ratelimited!(
+                    MSR_EXIT_LOG,
+                    Level::Warn,
+                    "VM exit: WRMSR({:#x}) <- {:#x} failed: {:?}, inject #GP @ {:#x}",
+                    msr,
+                    value,
+                    err,
+                    ctx.guest_rip
+                );
+                inject_gp(vcpu);
+                Ok(())
+            }
         }
     }
 }
 
+/// A UART of [`X64VcpuDevices`], on the host console.
+type GuestUart = Arc<Mutex<Uart16550<MultiplexConsoleBackend>>>;
+
 pub struct X64VcpuDevices<H: HyperCraftHal, B: BarAllocTrait> {
     pub(crate) apic_timer: Arc<Mutex<VirtLocalApic>>,
-    pub(crate) bundle: Arc<Mutex<Bundle>>,
+    apic_deadline: Arc<TimerDeadline>,
+    /// Whether the guest programs `apic_timer`, rather than the timer of the local APIC of its
+    /// CPU.
+    emulated_apic_timer: bool,
+    /// Whether `apic_timer` is the whole local APIC of the vCPU, whose interrupts go through it.
+    virtual_apic: bool,
+    /// The interrupts asserted by the devices and not injected yet, but the ones in the IRR of
+    /// the virtual local APIC.
+    pending_irqs: PendingInterrupts,
+    /// Whether interrupt-window exiting is set in the VMCS.
+    interrupt_window: bool,
+    /// An NMI was sent to the vCPU and not injected yet. The NMIs sent meanwhile are merged.
+    nmi_pending: bool,
+    /// Whether NMI-window exiting is set in the VMCS.
+    nmi_window: bool,
+    /// The PIT, the CMOS and the system control ports, if configured.
+    #[cfg(feature = "legacy-pc-devices")]
+    pub(crate) bundle: Option<Arc<Mutex<Bundle>>>,
+    /// The next IRQ 0 of the PIT and IRQ 8 of the RTC of `bundle`.
+    #[cfg(feature = "legacy-pc-devices")]
+    pit_deadline: Option<Arc<TimerDeadline>>,
+    #[cfg(feature = "legacy-pc-devices")]
+    rtc_deadline: Option<Arc<TimerDeadline>>,
+    /// The HPET and its next interrupt, if configured.
+    #[cfg(feature = "legacy-pc-devices")]
+    hpet: Option<Arc<Mutex<Hpet>>>,
+    #[cfg(feature = "legacy-pc-devices")]
+    hpet_deadline: Option<Arc<TimerDeadline>>,
+    /// The watchdog and its expiry, if configured.
+    #[cfg(feature = "legacy-pc-devices")]
+    watchdog: Option<Arc<Mutex<Ib700Watchdog>>>,
+    #[cfg(feature = "legacy-pc-devices")]
+    watchdog_deadline: Option<Arc<TimerDeadline>>,
+    /// Where the IRQs of the PIT, the RTC, the HPET, the UARTs and the keyboard go.
+    #[cfg(feature = "legacy-pc-devices")]
+    irq_router: IrqRouter,
+    /// The UARTs of `devices` by base port, and the IRQ of those at a COM port.
+    uarts: Vec<(u16, Option<u8>, GuestUart)>,
+    /// The PS/2 controller of `devices`, if configured.
+    #[cfg(feature = "legacy-pc-devices")]
+    keyboard: Option<Arc<Mutex<device_emu::I8042>>>,
     pub(crate) devices: DeviceList<H, B>,
-    // pub(crate) console: Arc<Mutex<device_emu::Uart16550<device_emu::MultiplexConsoleBackend>>>,
-    pub(crate) pic: [Arc<Mutex<device_emu::I8259Pic>>; 2],
-    last: Option<u64>,
+    /// The devices of `devices` last hit by the exits of this vCPU.
+    dispatch_cache: DispatchCache,
+    timers: TimerQueue,
+    timers_started: bool,
+    exit_stats: Arc<ExitStats>,
+    /// The part of the [`crate::vm::frozen_ns`] of the VM already hidden from the guest.
+    frozen_ns: u64,
+    tsc_offset: Arc<Mutex<TscOffset>>,
+    /// The x87, SSE and AVX state of the guest, see [`xstate`].
+    xstate: Arc<Mutex<GuestXState>>,
+    /// The paravirtual clock registered by the guest, see [`pvclock`].
+    pvclock: Option<PvClock>,
+    /// CPUID as the policy of the VM has it, CPUID of the CPU without one.
+    cpuid: CpuidHandler,
+    /// The emulated PMU, if configured, which RDPMC reads.
+    pmu: Option<Arc<Mutex<VirtPmu>>>,
+    /// The control register accesses and the mode of the guest, see [`cr_access`].
+    cr_access: CrAccess,
+    /// The MSRs of `devices` passed through, as written to the MSR bitmap.
+    msr_passthrough: AppliedPassthrough,
     marker: PhantomData<H>,
 }
 
+/// The TSC deadline MSR, passed through while the guest TSC is the TSC of the machine, unless the
+/// APIC timer is emulated.
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+/// Base ports of the emulated master and slave PICs.
+#[cfg(feature = "legacy-pc-devices")]
+const MASTER_PIC_PORT: u16 = 0x20;
+#[cfg(feature = "legacy-pc-devices")]
+const SLAVE_PIC_PORT: u16 = 0xa0;
+
+/// IRQs of the PIT and of the RTC.
+const PIT_IRQ: u8 = 0;
+const RTC_IRQ: u8 = 8;
+
+/// Base ports of the emulated COM1 to COM4 UARTs.
+const UART_PORTS: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+/// Master PIC IRQs of the emulated COM1 to COM4 UARTs.
+const UART_IRQS: [u8; 4] = [4, 3, 4, 3];
+
+/// Data port of the emulated PS/2 controller, and its master PIC IRQ.
+#[cfg(feature = "legacy-pc-devices")]
+const I8042_DATA_PORT: u16 = 0x60;
+#[cfg(feature = "legacy-pc-devices")]
+const KEYBOARD_IRQ: u8 = 1;
+
+impl<H: HyperCraftHal, B: BarAllocTrait> X64VcpuDevices<H, B> {
+    /// The guest may have reprogrammed the APIC timer since the last VM entry.
+    fn sync_apic_timer(&mut self) {
+        self.timers
+            .set(TimerSource::ApicTimer, self.apic_deadline.get());
+    }
+
+    /// The guest may have reprogrammed channel 0 of the PIT, the RTC, the HPET or the watchdog
+    /// since the last VM entry.
+    fn sync_bundle_timers(&mut self) {
+        #[cfg(feature = "legacy-pc-devices")]
+        for (source, deadline) in [
+            (TimerSource::PitIrq, &self.pit_deadline),
+            (TimerSource::RtcIrq, &self.rtc_deadline),
+            (TimerSource::HpetIrq, &self.hpet_deadline),
+            (TimerSource::Watchdog, &self.watchdog_deadline),
+        ] {
+            let deadline_ns = deadline.as_ref().and_then(|deadline| deadline.get());
+            self.timers.set(source, deadline_ns);
+        }
+    }
+
+    /// Time of the next interrupt generated by the emulated timers, as checked by
+    /// `check_events`.
+    fn next_event_ns(&mut self) -> Option<u64> {
+        self.sync_apic_timer();
+        self.sync_bundle_timers();
+        let apic_timer = self.timers.deadline(TimerSource::ApicTimer);
+        // A masked IRQ does not inject anything, there is no need to wake up for it.
+        let pit_irq = self
+            .timers
+            .deadline(TimerSource::PitIrq)
+            .filter(|_| !self.irq_masked(PIT_IRQ));
+        let rtc_irq = self
+            .timers
+            .deadline(TimerSource::RtcIrq)
+            .filter(|_| !self.irq_masked(RTC_IRQ));
+        // The timers of the HPET have IRQs of their own.
+        let hpet_irq = self.timers.deadline(TimerSource::HpetIrq);
+        // A halted guest is reset as well once its watchdog runs out.
+        let watchdog = self.timers.deadline(TimerSource::Watchdog);
+        [apic_timer, pit_irq, rtc_irq, hpet_irq, watchdog]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Whether an interrupt waits to be injected, in `pending_irqs` or in the virtual local APIC.
+    fn has_pending_interrupt(&self) -> bool {
+        !self.pending_irqs.is_empty()
+            || (self.virtual_apic && self.apic_timer.lock().has_interrupt())
+    }
+
+    /// Take the interrupts the devices raised since the last exit into `pending_irqs`. Done by
+    /// `check_events`, and before a halted vCPU parks.
+    #[cfg_attr(not(feature = "legacy-pc-devices"), allow(unused_variables))]
+    fn poll_device_interrupts(&mut self, vcpu: &mut VCpu<H>) {
+        if let Some(vector) = crate::hvc_console::check_input() {
+            self.pending_irqs.assert(vector);
+        }
+        #[cfg(feature = "virtio-pci")]
+        crate::device::poll_virtio_consoles();
+        #[cfg(feature = "virtio-pci")]
+        crate::device::poll_virtio_nets();
+        #[cfg(feature = "legacy-pc-devices")]
+        {
+            self.check_uart_interrupts(vcpu);
+            self.check_keyboard_interrupt(vcpu);
+            self.check_vm_irqs(vcpu);
+        }
+    }
+
+    /// Whether a halted vCPU has to go through `check_events` at once rather than park: a message
+    /// or a stop request waits for it, or an interrupt or NMI is pending.
+    fn wakes_from_halt(&mut self, vcpu: &mut VCpu<H>) -> bool {
+        if crate::nmi::has_messages() || crate::vm::stop_requested() {
+            return true;
+        }
+        self.poll_device_interrupts(vcpu);
+        self.nmi_pending || self.has_pending_interrupt()
+    }
+
+    /// Handle `msg`, a message to be handled now, see [`crate::nmi`]: the messages addressed to a
+    /// vCPU are for this one.
+    fn handle_nmi_message(&mut self, msg: NmiMessage) {
+        match msg.request {
+            NmiRequest::BootVm => {
+                if let Err(err) = crate::vm::boot_vm(msg.vm_id as usize) {
+                    error!(
+                        "CPU [{}] failed to boot VM [{}]: {:?}",
+                        current_cpu_id(),
+                        msg.vm_id,
+                        err
+                    );
+                }
+            }
+            NmiRequest::StartVcpu => crate::vm::start_vcpu(msg.vm_id, msg.vcpu_id),
+            NmiRequest::InvalidateEpt { .. } => crate::mm::handle_pending_invalidation(),
+            // The vectors posted are taken by `check_events`, before the next VM entry.
+            NmiRequest::KickVcpu => {}
+            // Injected by `check_events`, before the next VM entry.
+            NmiRequest::InjectNmi => self.nmi_pending = true,
+        }
+    }
+
+    /// Inject the pending NMI if the guest can take it at this VM entry, otherwise have it exit
+    /// once it unblocks NMIs, which needs virtual NMIs. Returns whether the NMI was injected.
+    fn inject_pending_nmi(&mut self, vcpu: &mut VCpu<H>) -> bool {
+        let injected = self.nmi_pending && pending_irq::guest_nmi_unblocked();
+        if injected {
+            self.nmi_pending = false;
+            vcpu.queue_event(NMI_VECTOR, None);
+            crate::nmi::count_injected_nmi();
+        }
+        let window = self.nmi_pending && pending_irq::has_virtual_nmis();
+        if window != self.nmi_window {
+            self.nmi_window = window;
+            pending_irq::set_nmi_window_exiting(window);
+        }
+        injected
+    }
+
+    /// Inject the pending NMI, or else the highest pending interrupt, if the guest can take it at
+    /// this VM entry, otherwise have it exit as soon as it can. The interrupt-window exit is also
+    /// requested while other interrupts remain.
+    fn inject_pending_interrupt(&mut self, vcpu: &mut VCpu<H>) {
+        let window = if self.inject_pending_nmi(vcpu) {
+            // The NMI takes this VM entry.
+            self.has_pending_interrupt()
+        } else {
+            let apic_vector = if self.virtual_apic {
+                self.apic_timer.lock().next_interrupt()
+            } else {
+                None
+            };
+            let vector = [self.pending_irqs.highest(), apic_vector]
+                .into_iter()
+                .flatten()
+                .max();
+            match vector {
+                Some(_) if !pending_irq::guest_interruptible() => true,
+                Some(vector) => {
+                    if apic_vector == Some(vector) {
+                        self.apic_timer.lock().take_interrupt();
+                    } else {
+                        self.pending_irqs.take(vector);
+                    }
+                    vcpu.queue_event(vector, None);
+                    self.has_pending_interrupt()
+                }
+                None => false,
+            }
+        };
+        if window != self.interrupt_window {
+            self.interrupt_window = window;
+            pending_irq::set_interrupt_window_exiting(window);
+        }
+    }
+
+    /// Hide from the guest the time its VM spent paused since the last call, `frozen_ns` being
+    /// the [`crate::vm::frozen_ns`] of the VM: the TSC and the emulated timers continue from
+    /// where they stopped.
+    fn freeze_time(&mut self, frozen_ns: u64) {
+        let delta_ns = frozen_ns - self.frozen_ns;
+        if delta_ns == 0 {
+            return;
+        }
+        self.frozen_ns = frozen_ns;
+        self.tsc_offset.lock().shift_ns(delta_ns);
+        self.sync_tsc_offset();
+        self.apic_timer.lock().inner.shift_time(delta_ns);
+        self.sync_apic_timer();
+        #[cfg(feature = "legacy-pc-devices")]
+        {
+            if let Some(bundle) = &self.bundle {
+                bundle.lock().shift_time(delta_ns);
+            }
+            if let Some(hpet) = &self.hpet {
+                hpet.lock().shift_time(delta_ns);
+            }
+        }
+        self.sync_bundle_timers();
+    }
+
+    /// Write the TSC offset to the VMCS if it changed.
+    fn sync_tsc_offset(&mut self) {
+        let (changed, offset) = {
+            let mut tsc_offset = self.tsc_offset.lock();
+            (tsc_offset.apply(), tsc_offset.get())
+        };
+        // The guest TSC is now apart from the TSC of the machine which would compare its
+        // deadlines.
+        if changed && offset != 0 && !self.emulated_apic_timer {
+            let _ = self
+                .devices
+                .set_msr_passthrough(IA32_TSC_DEADLINE, false, false);
+        }
+    }
+
+    /// The TSC offset of the vCPU: its guest TSC is the TSC of the machine plus the offset, modulo
+    /// 2^64.
+    pub fn tsc_offset(&self) -> u64 {
+        self.tsc_offset.lock().get()
+    }
+
+    /// Set the TSC offset of the vCPU, written to its VMCS before its next VM entry. The pauses
+    /// of the VM still move it back by the time the VM was paused.
+    pub fn set_tsc_offset(&mut self, offset: u64) {
+        self.tsc_offset.lock().set(offset);
+    }
+
+    /// Take the paravirtual clock the guest registered since the last VM entry, and rewrite it
+    /// if it is new, due, or the guest TSC moved.
+    fn sync_pvclock(&mut self, vcpu_id: u32) {
+        if let Some(vm_id) = crate::vm::current_vm_id() {
+            if let Some(clock) = pvclock::take_registration(vm_id, vcpu_id) {
+                self.pvclock = clock;
+            }
+        }
+        let frozen_ns = self.frozen_ns;
+        let tsc_offset = self.tsc_offset();
+        if let Some(clock) = self.pvclock.as_mut() {
+            if clock.due(axhal::time::current_time_nanos(), tsc_offset) {
+                clock.update(tsc_offset, frozen_ns);
+            }
+        }
+    }
+
+    /// Override the policy of the VM for the MSRs no device implements, on this vCPU only.
+    /// `None` goes back to the policy of the VM.
+    pub fn set_unhandled_msr_policy(&mut self, policy: Option<UnhandledMsrPolicy>) {
+        self.devices.set_unhandled_msr_policy(policy);
+    }
+
+    /// The emulated UART at `port`, the base port it was configured at, to type into the guest
+    /// with [`Uart16550::push_byte`].
+    pub fn uart(&self, port: u16) -> Option<GuestUart> {
+        self.uarts
+            .iter()
+            .find(|(base, _, _)| *base == port)
+            .map(|(_, _, uart)| uart.clone())
+    }
+
+    /// The emulated PS/2 controller, to type into the guest with
+    /// [`I8042::inject_scancodes`](device_emu::I8042::inject_scancodes), if configured.
+    #[cfg(feature = "legacy-pc-devices")]
+    pub fn keyboard(&self) -> Option<Arc<Mutex<device_emu::I8042>>> {
+        self.keyboard.clone()
+    }
+
+    /// Whether `irq` is masked in the IO APIC and in the PICs, see [`IrqRouter::masked`].
+    #[cfg(feature = "legacy-pc-devices")]
+    fn irq_masked(&self, irq: u8) -> bool {
+        self.irq_router.masked(irq)
+    }
+
+    /// Without the PICs, there is no IRQ to inject.
+    #[cfg(not(feature = "legacy-pc-devices"))]
+    fn irq_masked(&self, _irq: u8) -> bool {
+        true
+    }
+
+    /// Raise the IRQs of the UARTs whose interrupt output rose.
+    #[cfg(feature = "legacy-pc-devices")]
+    fn check_uart_interrupts(&mut self, vcpu: &mut VCpu<H>) {
+        for (_, irq, uart) in self.uarts.iter() {
+            let Some(irq) = *irq else {
+                continue;
+            };
+            if uart.lock().poll_interrupt() {
+                self.irq_router
+                    .assert_irq(vcpu.vcpu_id() as u32, &mut self.pending_irqs, irq);
+            }
+        }
+    }
+
+    /// Raise IRQ 1 if a byte reached the output buffer of the PS/2 controller.
+    #[cfg(feature = "legacy-pc-devices")]
+    fn check_keyboard_interrupt(&mut self, vcpu: &mut VCpu<H>) {
+        let Some(keyboard) = &self.keyboard else {
+            return;
+        };
+        if keyboard.lock().poll_interrupt() {
+            self.irq_router
+                .assert_irq(vcpu.vcpu_id() as u32, &mut self.pending_irqs, KEYBOARD_IRQ);
+        }
+    }
+
+    /// Route the IRQs the PCI devices of the VM raised on their INTx pins.
+    #[cfg(feature = "legacy-pc-devices")]
+    fn check_vm_irqs(&mut self, vcpu: &mut VCpu<H>) {
+        let Some(vm_id) = crate::vm::current_vm_id() else {
+            return;
+        };
+        let irqs = irq_router::take_vm_irqs(vm_id);
+        for irq in (0..32).filter(|irq| irqs.get_bit(*irq)) {
+            self.irq_router
+                .assert_irq(vcpu.vcpu_id() as u32, &mut self.pending_irqs, irq as u8);
+        }
+    }
+
+    /// Raise IRQ 0 if the output of channel 0 of the PIT rose.
+    #[cfg(feature = "legacy-pc-devices")]
+    fn check_pit_interrupt(&mut self, vcpu: &mut VCpu<H>) {
+        let raised = match &self.bundle {
+            Some(bundle) => bundle.lock().pit_pending_irq(),
+            None => false,
+        };
+        if raised {
+            self.irq_router
+                .assert_irq(vcpu.vcpu_id() as u32, &mut self.pending_irqs, PIT_IRQ);
+        }
+        // A periodic channel re-armed itself.
+        self.sync_bundle_timers();
+    }
+
+    /// Raise IRQ 8 if the interrupt output of the RTC rose.
+    #[cfg(feature = "legacy-pc-devices")]
+    fn check_rtc_interrupt(&mut self, vcpu: &mut VCpu<H>) {
+        let raised = match &self.bundle {
+            Some(bundle) => bundle.lock().rtc_pending_irq(),
+            None => false,
+        };
+        if raised {
+            self.irq_router
+                .assert_irq(vcpu.vcpu_id() as u32, &mut self.pending_irqs, RTC_IRQ);
+        }
+        self.sync_bundle_timers();
+    }
+
+    /// Raise the IRQs of the timers of the HPET which reached their comparator.
+    #[cfg(feature = "legacy-pc-devices")]
+    fn check_hpet_interrupts(&mut self, vcpu: &mut VCpu<H>) {
+        let raised = match &self.hpet {
+            Some(hpet) => hpet.lock().pending_irqs(),
+            None => 0,
+        };
+        for irq in (0..u32::BITS as u8).filter(|&irq| raised.get_bit(irq as usize)) {
+            self.irq_router
+                .assert_irq(vcpu.vcpu_id() as u32, &mut self.pending_irqs, irq);
+        }
+        self.sync_bundle_timers();
+    }
+
+    /// Reset the VM if its watchdog ran out.
+    #[cfg(feature = "legacy-pc-devices")]
+    fn check_watchdog(&mut self, now_ns: u64) {
+        let expired = match &self.watchdog {
+            Some(watchdog) => watchdog.lock().check_expired(now_ns),
+            None => false,
+        };
+        // The host VM cannot be reset, the expiry is dropped.
+        if expired && crate::vm::guest_reset("the IB700 watchdog").is_err() {
+            warn!("watchdog: expired, the VM cannot be reset");
+        }
+        self.sync_bundle_timers();
+    }
+
+    /// How often `check_events` looked at the APIC timer, and how often it had to lock it.
+    pub fn apic_timer_stats(&self) -> ApicTimerStats {
+        self.apic_deadline.stats()
+    }
+
+    /// Injection latency of the emulated timers.
+    pub fn timer_stats(&self) -> TimerQueueStats {
+        self.timers.stats()
+    }
+
+    /// The lookups of the per-vCPU devices in their device cache, see [`DispatchCache`].
+    pub fn dispatch_cache_stats(&self) -> DispatchCacheStats {
+        self.dispatch_cache.stats()
+    }
+
+    /// Where the VM exits of this vCPU went.
+    pub fn exit_stats(&self) -> &ExitStats {
+        &self.exit_stats
+    }
+
+    /// Log the table of the VM exits of this vCPU, see [`ExitStats::dump`].
+    pub fn dump_stats(&self) {
+        self.exit_stats.dump();
+    }
+}
+
+impl<H: HyperCraftHal, B: BarAllocTrait + 'static> X64VcpuDevices<H, B> {
+    /// RDPMC, reading the counter of `pmu` ECX selects, #GP if there is none.
+    fn handle_rdpmc(vcpu: &mut VCpu<H>, ctx: &ExitContext, pmu: &Mutex<VirtPmu>) -> HyperResult {
+        let index = vcpu.regs().rcx as u32;
+        match pmu.lock().rdpmc(index) {
+            Some(value) => {
+                vcpu.regs_mut().rax = value & 0xffff_ffff;
+                vcpu.regs_mut().rdx = value >> 32;
+                vcpu.advance_rip(ctx.exit_instruction_length as _)
+            }
+            None => {
+                inject_gp(vcpu);
+                Ok(())
+            }
+        }
+    }
+
+    /// XSETBV, setting the XCR0 of the guest, #GP if the value is not one of `xstate`.
+    fn handle_xsetbv(&mut self, vcpu: &mut VCpu<H>, ctx: &ExitContext) -> HyperResult {
+        let regs = vcpu.regs();
+        let (index, value) = (regs.rcx as u32, (regs.rdx << 32) | (regs.rax & 0xffff_ffff));
+        if let Err(err) = self.xstate.lock().set_xcr(index, value) {
+            warn!("XSETBV({:#x}, {:#x}): {:?}", index, value, err);
+            inject_gp(vcpu);
+            return Ok(());
+        }
+        vcpu.advance_rip(ctx.exit_instruction_length as _)
+    }
+
+    /// The exit handler of the per-vCPU devices, `None` leaving the exit to the per-VM devices.
+    fn handle_vcpu_exit(
+        &mut self,
+        vcpu: &mut VCpu<H>,
+        exit_info: &VmExitInfo,
+    ) -> Option<HyperResult> {
+        // Before any host code clobbers it, or another vCPU runs on this CPU.
+        self.xstate.lock().unload();
+        let mut ctx = ExitContext::new(exit_info).with_guest_mode(self.cr_access.mode());
+        if let Some(count) = record_exit(vcpu.vcpu_id(), &ctx) {
+            if let Some(result) = watchdog_fire(vcpu, &ctx, count) {
+                return Some(result);
+            }
+        }
+        match exit_info.exit_reason {
+            VmxExitReason::IO_INSTRUCTION
+            | VmxExitReason::MSR_READ
+            | VmxExitReason::MSR_WRITE
+            | VmxExitReason::HLT
+            | VmxExitReason::CPUID => debug_check_exit_instr_len(exit_info, None),
+            _ => {}
+        }
+        match exit_info.exit_reason {
+            VmxExitReason::HLT => {
+                if let Err(err) = vcpu.advance_rip(ctx.exit_instruction_length as _) {
+                    return Some(Err(err));
+                }
+                // Events are injected by `check_events`, which runs before the next VM entry.
+                // The ones raised since it ran are looked for first: nothing wakes the vCPU for
+                // them once it is parked.
+                if !self.wakes_from_halt(vcpu) {
+                    let now = axhal::time::current_time_nanos();
+                    let input_poll = crate::vm::current_vm_id()
+                        .and_then(|vm_id| crate::console_mux::input_poll_deadline(vm_id, now));
+                    let deadline = [self.next_event_ns(), input_poll]
+                        .into_iter()
+                        .flatten()
+                        .min();
+                    crate::park::park(deadline);
+                }
+                Some(Ok(()))
+            }
+            // Armed by `check_events`, which runs the due timers before the next VM entry.
+            VmxExitReason::PREEMPTION_TIMER => Some(Ok(())),
+            // Requested by `check_events`, which injects the pending interrupt or NMI.
+            VmxExitReason::INTERRUPT_WINDOW | VmxExitReason::NMI_WINDOW => Some(Ok(())),
+            VmxExitReason::CPUID => {
+                let xcr0 = self.xstate.lock().xcr0();
+                Some(
+                    self.cpuid
+                        .handle(vcpu, ctx.exit_instruction_length as _, xcr0),
+                )
+            }
+            VmxExitReason::XSETBV => Some(self.handle_xsetbv(vcpu, &ctx)),
+            VmxExitReason::CR_ACCESS => self.cr_access.handle(vcpu, &ctx),
+            VmxExitReason::RDPMC => {
+                let pmu = self.pmu.as_ref()?;
+                Some(Self::handle_rdpmc(vcpu, &ctx, pmu))
+            }
+            VmxExitReason::IO_INSTRUCTION => {
+                // Console ring output written before this (possibly UART) access goes first.
+                if let Some(vm_id) = crate::vm::current_vm_id() {
+                    crate::console_ring::drain(vm_id);
+                }
+                self.devices
+                    .dispatch_exit(&mut self.dispatch_cache, vcpu, &mut ctx, None)
+            }
+            // No instruction here, MMIO is left to the per-VM devices.
+            _ => self
+                .devices
+                .dispatch_exit(&mut self.dispatch_cache, vcpu, &mut ctx, None),
+        }
+    }
+}
+
 impl<H: HyperCraftHal, B: BarAllocTrait + 'static> PerCpuDevices<H> for X64VcpuDevices<H, B> {
     fn new(vcpu: &VCpu<H>) -> HyperResult<Self> {
-        let apic_timer = Arc::new(Mutex::new(VirtLocalApic::new()));
-        let bundle = Arc::new(Mutex::new(Bundle::new()));
-        let pic: [Arc<Mutex<device_emu::I8259Pic>>; 2] = [
-            Arc::new(Mutex::new(device_emu::I8259Pic::new(0x20))),
-            Arc::new(Mutex::new(device_emu::I8259Pic::new(0xA0))),
-        ];
+        let config = vcpu_config::vcpu_device_config();
+        let bus_freq_hz = config
+            .apic_timer
+            .unwrap_or(device_emu::DEFAULT_APIC_BUS_FREQ_HZ);
+        let apic_timer = Arc::new(Mutex::new(VirtLocalApic::new(
+            vcpu.vcpu_id() as u32,
+            bus_freq_hz,
+        )));
+        let apic_deadline = apic_timer.lock().inner.next_deadline();
 
-        let mut devices = DeviceList::new(Some(vcpu.vcpu_id() as u32), None);
-
-        let mut pmio_devices: Vec<Arc<Mutex<dyn PioOps>>> = vec![
-            // These are all fully emulated consoles!!!
-            // 0x3f8, 0x3f8 + 8
-            Arc::new(Mutex::new(<device_emu::Uart16550>::new(0x3f8))), // COM1
-            // 0x2f8, 0x2f8 + 8
-            Arc::new(Mutex::new(<device_emu::Uart16550>::new(0x2f8))), // COM2
-            // 0x3e8, 0x3e8 + 8
-            Arc::new(Mutex::new(<device_emu::Uart16550>::new(0x3e8))), // COM3
-            // 0x2e8, 0x2e8 + 8
-            Arc::new(Mutex::new(<device_emu::Uart16550>::new(0x2e8))), // COM4
-            // 0x20, 0x20 + 2
-            pic[0].clone(), // PIC1
-            // 0xa0, 0xa0 + 2
-            pic[1].clone(), // PIC2
-            // 0x80, 0x80 + 1
-            Arc::new(Mutex::new(device_emu::DebugPort::new(0x80))), // Debug Port
-            /*
-               the complexity:
-               - port 0x70 and 0x71 is for CMOS, but bit 7 of 0x70 is for NMI
-               - port 0x40 ~ 0x43 is for PIT, but port 0x61 is also related
-            */
-            // 0x92, 0x92 + 1
-            Arc::new(Mutex::new(Bundle::proxy_system_control_a(&bundle))),
-            // 0x61, 0x61 + 1
-            Arc::new(Mutex::new(Bundle::proxy_system_control_b(&bundle))),
-            // 0x70, 0x70 + 2
-            Arc::new(Mutex::new(Bundle::proxy_cmos(&bundle))),
-            // 0x40, 0x40 + 4
-            Arc::new(Mutex::new(Bundle::proxy_pit(&bundle))),
-            // 0xf0, 0xf0 + 2
-            Arc::new(Mutex::new(device_emu::Dummy::new(0xf0, 2))), // 0xf0 and 0xf1 are ports about fpu
-            // 0x3d4, 0x3d4 + 2
-            Arc::new(Mutex::new(device_emu::Dummy::new(0x3d4, 2))), // 0x3d4 and 0x3d5 are ports about vga
-            // 0x87, 0x87 + 1
-            Arc::new(Mutex::new(device_emu::Dummy::new(0x87, 1))), // 0x87 is a port about dma
-            // 0x60, 0x60 + 1
-            Arc::new(Mutex::new(device_emu::Dummy::new(0x60, 1))), // 0x60 and 0x64 are ports about ps/2 controller
-            // 0x64, 0x64 + 1
-            Arc::new(Mutex::new(device_emu::Dummy::new(0x64, 1))), //
-                                                                   // Arc::new(Mutex::new(device_emu::PCIConfigurationSpace::new(0xcf8))),
-                                                                   // Arc::new(Mutex::new(device_emu::PCIPassthrough::new(0xcf8))),
-        ];
-        devices.add_port_io_devices(&mut pmio_devices);
+        let devices = DeviceList::new(Some(vcpu.vcpu_id() as u32), None);
+        let mut uarts = Vec::new();
+        for &port in &config.uarts {
+            // each 8 ports, e.g. 0x3f8, 0x2f8, 0x3e8, 0x2e8: COM1 to COM4
+            let backend = config.console.clone().map_or_else(
+                MultiplexConsoleBackend::default,
+                MultiplexConsoleBackend::shared,
+            );
+            let mut uart = Uart16550::with_backend(port, backend);
+            // The host console input goes to COM1 only.
+            uart.set_host_input(port == UART_PORTS[0]);
+            let uart = Arc::new(Mutex::new(uart));
+            devices.add_port_io_device(uart.clone())?;
+            devices.add_batch_writer(uart.clone());
+            devices.add_stateful_device("uart16550", uart.clone());
+            let irq = UART_PORTS
+                .iter()
+                .position(|&com| com == port)
+                .map(|com| UART_IRQS[com]);
+            uarts.push((port, irq, uart));
+        }
+        #[cfg(feature = "legacy-pc-devices")]
+        let LegacyPcDevices {
+            bundle,
+            hpet,
+            watchdog,
+            irq_router,
+        } = add_legacy_pc_devices(&devices, &config)?;
+        #[cfg(not(feature = "legacy-pc-devices"))]
+        if config.pic
+            || config.ioapic
+            || config.cmos
+            || config.hpet
+            || config.i8042
+            || config.reset_control
+            || config.watchdog
+            || config.debug_port.is_some()
+            || config.debug_exit.is_some()
+            || !config.dummy_ports.is_empty()
+        {
+            warn!(
+                "legacy PC devices configured for vCPU {}, but compiled out",
+                vcpu.vcpu_id()
+            );
+        }
+        #[cfg(feature = "vga")]
+        if config.vga {
+            add_vga_devices(&devices)?;
+        }
 
-        devices.add_msr_device(Arc::new(Mutex::new(device_emu::ProxyLocalApic::new())));
-        devices.add_msr_device(Arc::new(Mutex::new(ApicBaseMsrHandler {})));
+        if config.virtual_apic {
+            devices.add_msr_device(Arc::new(Mutex::new(VirtLocalApic::msr_proxy(&apic_timer))))?;
+            devices.add_msr_device(Arc::new(Mutex::new(VirtLocalApic::tsc_deadline_msr_proxy(
+                &apic_timer,
+            ))))?;
+        } else if config.apic_timer.is_some() {
+            for timer_msrs in VirtLocalApic::timer_msr_proxies(&apic_timer) {
+                devices.add_msr_device(Arc::new(Mutex::new(timer_msrs)))?;
+            }
+            add_local_apic_proxy(&devices, vcpu.vcpu_id() as u32, &VirtLocalApic::TIMER_MSRS)?;
+        } else {
+            add_local_apic_proxy(&devices, vcpu.vcpu_id() as u32, &[])?;
+        }
+        let apic_base = Arc::new(Mutex::new(ApicBaseMsrHandler::new()));
+        devices.add_msr_device(apic_base.clone())?;
+        devices.add_stateful_device("apic base", apic_base);
+        devices.add_stateful_device("local apic", apic_timer.clone());
+        let tsc_offset = Arc::new(Mutex::new(TscOffset::new()));
+        devices.add_stateful_device("tsc offset", tsc_offset.clone());
+        let xstate = Arc::new(Mutex::new(GuestXState::new()));
+        devices.add_stateful_device("xstate", xstate.clone());
+        let pmu = config.pmu.then(|| Arc::new(Mutex::new(VirtPmu::new())));
+        if let Some(pmu) = &pmu {
+            for pmu_msrs in VirtPmu::msr_proxies(pmu) {
+                devices.add_msr_device(Arc::new(Mutex::new(pmu_msrs)))?;
+            }
+            devices.add_stateful_device("pmu", pmu.clone());
+        }
+        // Even without a policy, for the XSAVE features to be those of `xstate`.
+        let cpuid = CpuidHandler::new(
+            config
+                .cpuid
+                .unwrap_or_else(|| Arc::new(CpuidPolicy::passthrough())),
+            config.pmu.then(VirtPmu::cpuid_leaf),
+            vcpu.vcpu_id() as u32,
+        );
         // linux read this amd-related msr on my intel cpu for some unknown reason... make it happy
-        devices.add_msr_device(Arc::new(Mutex::new(device_emu::MsrDummy::new(0xc0011029))));
+        devices.add_msr_device(Arc::new(Mutex::new(device_emu::MsrDummy::new(0xc0011029))))?;
         const IA32_UMWAIT_CONTROL: u32 = 0xe1;
         devices.add_msr_device(Arc::new(Mutex::new(device_emu::MsrDummy::new(
             IA32_UMWAIT_CONTROL,
-        ))));
+        ))))?;
 
         Ok(Self {
             apic_timer,
+            apic_deadline,
+            emulated_apic_timer: config.apic_timer.is_some() || config.virtual_apic,
+            virtual_apic: config.virtual_apic,
+            pending_irqs: PendingInterrupts::new(),
+            interrupt_window: false,
+            nmi_pending: false,
+            nmi_window: false,
+            #[cfg(feature = "legacy-pc-devices")]
+            pit_deadline: bundle.as_ref().map(|bundle| bundle.lock().pit_deadline()),
+            #[cfg(feature = "legacy-pc-devices")]
+            rtc_deadline: bundle.as_ref().map(|bundle| bundle.lock().rtc_deadline()),
+            #[cfg(feature = "legacy-pc-devices")]
             bundle,
+            #[cfg(feature = "legacy-pc-devices")]
+            hpet_deadline: hpet.as_ref().map(|hpet| hpet.lock().deadline()),
+            #[cfg(feature = "legacy-pc-devices")]
+            hpet,
+            #[cfg(feature = "legacy-pc-devices")]
+            watchdog_deadline: watchdog.as_ref().map(|watchdog| watchdog.lock().deadline()),
+            #[cfg(feature = "legacy-pc-devices")]
+            watchdog,
+            #[cfg(feature = "legacy-pc-devices")]
+            irq_router,
+            uarts,
+            #[cfg(feature = "legacy-pc-devices")]
+            keyboard: devices.get_port_io_device_as(I8042_DATA_PORT),
             devices,
-            pic,
-            last: None,
+            dispatch_cache: DispatchCache::new(),
+            timers: TimerQueue::new(),
+            timers_started: false,
+            exit_stats: Arc::new(ExitStats::new(vcpu.vcpu_id())),
+            frozen_ns: 0,
+            tsc_offset,
+            xstate,
+            pvclock: None,
+            cpuid,
+            pmu,
+            cr_access: CrAccess::new(config.cr_pinning),
+            msr_passthrough: AppliedPassthrough::default(),
             marker: PhantomData,
         })
     }
@@ -646,12 +2632,21 @@ impl<H: HyperCraftHal, B: BarAllocTrait + 'static> PerCpuDevices<H> for X64VcpuD
         vcpu: &mut VCpu<H>,
         exit_info: &VmExitInfo,
     ) -> Option<HyperResult> {
-        match exit_info.exit_reason {
-            VmxExitReason::IO_INSTRUCTION => self.devices.handle_io_instruction(vcpu, exit_info),
-            VmxExitReason::MSR_READ => Some(self.devices.handle_msr_read(vcpu)),
-            VmxExitReason::MSR_WRITE => Some(self.devices.handle_msr_write(vcpu)),
-            _ => None,
+        let start_ns = axhal::time::current_time_nanos();
+        observe_exit_start(vcpu.vcpu_id(), exit_info, start_ns);
+        let result = self.handle_vcpu_exit(vcpu, exit_info);
+        // The exits left to the per-VM devices are observed and counted once they are handled
+        // there.
+        match &result {
+            Some(result) => {
+                observe_exit_end(vcpu.vcpu_id(), exit_info, result);
+                let dispatches = self.dispatch_cache.stats();
+                self.exit_stats
+                    .record(vcpu, exit_info, start_ns, dispatches);
+            }
+            None => exit_stats::defer_exit(&self.exit_stats, start_ns),
         }
+        result
     }
 
     fn hypercall_handler(
@@ -661,34 +2656,58 @@ impl<H: HyperCraftHal, B: BarAllocTrait + 'static> PerCpuDevices<H> for X64VcpuD
         args: (usize, usize, usize),
     ) -> HyperResult<u32> {
         // debug!("hypercall #{id:#x?}, args: {args:#x?}");
-        crate::hvc::handle_hvc(vcpu, id as usize, args)
+        let start_ns = axhal::time::current_time_nanos();
+        let exit_info = vcpu.exit_info()?;
+        let ctx = ExitContext::new(&exit_info);
+        if let Some(count) = record_exit(vcpu.vcpu_id(), &ctx) {
+            if let Some(result) = watchdog_fire(vcpu, &ctx, count) {
+                return result.map(|_| 0);
+            }
+        }
+        let result = crate::hvc::handle_hvc(vcpu, &exit_info, id as usize, args);
+        let dispatches = self.dispatch_cache.stats();
+        self.exit_stats
+            .record(vcpu, &exit_info, start_ns, dispatches);
+        result
     }
 
     fn nmi_handler(&mut self, vcpu: &mut VCpu<H>) -> HyperResult<u32> {
         let current_cpu_id = current_cpu_id();
         let current_core_id = axhal::cpu_id_to_core_id(current_cpu_id);
-        let msg = CORE_NMI_LIST[current_core_id].lock().pop();
-        match msg {
-            Some(NmiMessage::BootVm(vm_id)) => {
-                crate::vm::boot_vm(vm_id);
+        match crate::nmi::take_messages() {
+            Some(messages) => {
+                for msg in messages {
+                    self.handle_nmi_message(msg);
+                }
                 Ok(0)
             }
             None => {
-                warn!(
-                    "CPU [{}] (Processor [{}])NMI VM-Exit",
-                    current_cpu_id, current_core_id
-                );
                 let int_info = vcpu.interrupt_exit_info()?;
-                warn!(
-                    "interrupt_exit_info:{:#x}\n{:#x?}\n{:#x?}",
-                    vcpu.raw_interrupt_exit_info()?,
-                    int_info,
-                    vcpu
-                );
-
                 if int_info.int_type == VmxInterruptionType::NMI {
-                    unsafe { core::arch::asm!("int 2") }
+                    // Not sent by the hypervisor: forwarded to the guest rather than lost in the
+                    // empty NMI handler of the hypervisor.
+                    crate::nmi::count_unexpected_nmi();
+                    ratelimited!(
+                        UNEXPECTED_NMI_LOG,
+                        Level::Warn,
+                        "CPU [{}] (Processor [{}]) unexpected NMI, forwarded to vCPU {}",
+                        current_cpu_id,
+                        current_core_id,
+                        vcpu.vcpu_id()
+                    );
+                    self.nmi_pending = true;
                 } else {
+                    warn!(
+                        "CPU [{}] (Processor [{}])NMI VM-Exit",
+                        current_cpu_id, current_core_id
+                    );
+                    warn!(
+                        "interrupt_exit_info:{:#x}\n{:#x?}",
+                        vcpu.raw_interrupt_exit_info()?,
+                        int_info
+                    );
+                    let ctx = ExitContext::current(&vcpu.exit_info()?);
+                    diagnostics::dump_vcpu_state(Level::Warn, vcpu, &ctx);
                     // Reinject the event straight away.
                     debug!(
                         "reinject to VM on CPU {} Processor {}",
@@ -717,72 +2736,346 @@ impl<H: HyperCraftHal, B: BarAllocTrait + 'static> PerCpuDevices<H> for X64VcpuD
     }
 
     fn check_events(&mut self, vcpu: &mut VCpu<H>) -> HyperResult {
-        if self.apic_timer.lock().inner.check_interrupt() {
-            vcpu.queue_event(self.apic_timer.lock().inner.vector(), None);
-        }
-
-        // it's naive but it works.
-        // inject 0x30(irq 0) every 1 ms after 5 seconds after booting.
-        match self.last {
-            Some(last) => {
-                let now = axhal::time::current_time_nanos();
-                if now > 1_000_000 + last {
-                    // debug!(
-                    //     "vcpu [{}] check events current {} last {} tick {} ns",
-                    //     vcpu.vcpu_id(),
-                    //     now,
-                    //     last,
-                    //     now - last,
-                    // );
-                    if !self.pic[0].lock().mask().get_bit(0) {
-                        vcpu.queue_event(0x30, None);
-                        let _mask = self.pic[0].lock().mask();
-                        // debug!("0x30 queued, mask {_mask:#x}");
-                    }
-                    self.last = Some(now);
+        // Not saved on the exits hypercraft handled itself, and this may park.
+        self.xstate.lock().unload();
+        // Messages kept for this vCPU while another one ran on this CPU, or not taken yet.
+        if crate::nmi::has_messages() {
+            if let Some(messages) = crate::nmi::take_messages() {
+                for msg in messages {
+                    self.handle_nmi_message(msg);
                 }
             }
-            None => {
-                self.last = Some(axhal::time::current_time_nanos() + 5_000_000_000);
-                // debug!(
-                //     "vcpu [{}] check events last set to {} ns",
-                //     vcpu.vcpu_id(),
-                //     self.last.unwrap()
-                // );
+        }
+        // A vCPU of a fork or of a restored VM continues from where the vCPU it copies was
+        // paused.
+        if !self.timers_started {
+            if let Some(vm_id) = crate::vm::current_vm_id() {
+                vcpu_snapshot::restore_pending(vm_id, vcpu, &self.devices)?;
+            }
+        }
+        let devices = &self.devices;
+        let paused = crate::vm::wait_while_paused(|vm_id| {
+            if vm_id != crate::vm::HOST_VM_ID {
+                vcpu_snapshot::save_paused(vm_id, vcpu, devices);
+            }
+        });
+        if let Some(frozen_ns) = paused {
+            self.freeze_time(frozen_ns);
+        }
+        self.sync_tsc_offset();
+        self.sync_pvclock(vcpu.vcpu_id() as u32);
+        // An AP of a guest waits for its STARTUP IPI before its first VM entry.
+        if let Some(vm_id) = crate::vm::current_vm_id() {
+            if let Some(vector) = ipi::wait_for_sipi(vm_id, vcpu.vcpu_id() as u32) {
+                ipi::enter_at_startup_vector(vector);
+            }
+        }
+        // Shut down or killed, the vCPU leaves its run loop instead of entering the guest again.
+        if crate::vm::stop_requested() {
+            return Err(HyperError::BadState);
+        }
+        crate::console_ring::poll();
+        crate::mm::handle_pending_invalidation();
+        self.poll_device_interrupts(vcpu);
+
+        let now = axhal::time::current_time_nanos();
+        if !self.timers_started {
+            self.timers_started = true;
+
+            if let Some(vm_id) = crate::vm::current_vm_id() {
+                let vcpu_ranges = self.devices.claimed_ranges();
+                dispatch::register_vcpu_ranges(vm_id, vcpu.vcpu_id(), vcpu_ranges);
+                exit_stats::register_exit_stats(vm_id, &self.exit_stats);
+                if self.virtual_apic {
+                    let pending = self.apic_timer.lock().pending();
+                    ipi::register_virtual_apic(vm_id, vcpu.vcpu_id() as u32, pending);
+                }
+            }
+            if self.pmu.is_some() {
+                device_emu::enable_rdpmc_exiting();
+            }
+            xstate::enable_host_xstate();
+            self.cr_access.start();
+            if !self.emulated_apic_timer && !guest_tsc_offset() {
+                // Not if a device implements it, which is logged.
+                let _ = self
+                    .devices
+                    .set_msr_passthrough(IA32_TSC_DEADLINE, true, true);
+            }
+        }
+        self.devices.sync_msr_bitmap(&mut self.msr_passthrough);
+        self.sync_apic_timer();
+        self.sync_bundle_timers();
+
+        // Every due source runs once, even if it fell behind by several periods.
+        let mut due = [None; NUM_SOURCES];
+        for slot in due.iter_mut() {
+            *slot = self.timers.pop_due(now);
+        }
+        for (source, _) in due.into_iter().flatten() {
+            match source {
+                TimerSource::ApicTimer => {
+                    if self.apic_deadline.expired() {
+                        let mut apic_timer = self.apic_timer.lock();
+                        if apic_timer.inner.check_interrupt() {
+                            let vector = apic_timer.inner.vector();
+                            if self.virtual_apic {
+                                apic_timer.accept_interrupt(vector);
+                            } else {
+                                self.pending_irqs.assert(vector);
+                            }
+                        }
+                    }
+                    // A periodic timer re-armed itself.
+                    self.sync_apic_timer();
+                }
+                TimerSource::PitIrq => {
+                    #[cfg(feature = "legacy-pc-devices")]
+                    self.check_pit_interrupt(vcpu);
+                }
+                TimerSource::RtcIrq => {
+                    #[cfg(feature = "legacy-pc-devices")]
+                    self.check_rtc_interrupt(vcpu);
+                }
+                TimerSource::HpetIrq => {
+                    #[cfg(feature = "legacy-pc-devices")]
+                    self.check_hpet_interrupts(vcpu);
+                }
+                TimerSource::Watchdog => {
+                    #[cfg(feature = "legacy-pc-devices")]
+                    self.check_watchdog(now);
+                }
             }
         }
+        self.timers.arm(now);
+        self.inject_pending_interrupt(vcpu);
+        self.xstate.lock().load();
 
         Ok(())
     }
 }
 
-pub struct X64VmDevices<H: HyperCraftHal, B: BarAllocTrait> {
-    devices: DeviceList<H, B>,
-    marker: PhantomData<H>,
+/// The devices of [`add_legacy_pc_devices`] which the vCPU keeps: the bundle of the PIT, the CMOS
+/// and the system control ports, the HPET and the watchdog, if configured, and the router of the
+/// IRQs to the interrupt controllers.
+#[cfg(feature = "legacy-pc-devices")]
+struct LegacyPcDevices {
+    bundle: Option<Arc<Mutex<Bundle>>>,
+    hpet: Option<Arc<Mutex<Hpet>>>,
+    watchdog: Option<Arc<Mutex<Ib700Watchdog>>>,
+    irq_router: IrqRouter,
 }
 
-impl<H: HyperCraftHal, B: BarAllocTrait + 'static> X64VmDevices<H, B> {
-    fn handle_external_interrupt(vcpu: &VCpu<H>) -> HyperResult {
-        let int_info = vcpu.interrupt_exit_info()?;
-        debug!("VM-exit: external interrupt: {:#x?}", int_info);
-
-        if int_info.vector != 0xf0 {
-            panic!("VM-exit: external interrupt: {:#x?}", int_info);
+/// The PICs, the IO APIC, the PIT, the CMOS, the HPET and the other ports of the PC platform
+/// `config` asks for.
+///
+/// A VM which maps the IO APIC of the machine at [`device_emu::IOAPIC_BASE`], as the host does,
+/// keeps accessing it natively, the emulated one only sees the IRQs of the emulated devices. The
+/// same goes for the HPET at [`device_emu::HPET_BASE`].
+#[cfg(feature = "legacy-pc-devices")]
+fn add_legacy_pc_devices<H: HyperCraftHal, B: BarAllocTrait + 'static>(
+    devices: &DeviceList<H, B>,
+    config: &VcpuDeviceConfig,
+) -> HyperResult<LegacyPcDevices> {
+    let mut pics = [None, None];
+    if config.pic {
+        for (port, slot) in [MASTER_PIC_PORT, SLAVE_PIC_PORT].into_iter().zip(&mut pics) {
+            // 0x20, 0x20 + 2: PIC1; 0xa0, 0xa0 + 2: PIC2
+            let pic = Arc::new(Mutex::new(device_emu::I8259Pic::new(port)));
+            devices.add_port_io_device(pic.clone())?;
+            devices.add_stateful_device("i8259 pic", pic.clone());
+            *slot = Some(pic);
         }
+    }
+    let mut ioapic = None;
+    if config.ioapic {
+        // 0xfec0_0000, 0xfec0_0000 + 0x1000
+        let device = Arc::new(Mutex::new(device_emu::IoApic::new()));
+        devices.add_memory_io_device(device.clone())?;
+        devices.add_stateful_device("ioapic", device.clone());
+        ioapic = Some(device);
+    }
+    let irq_router = IrqRouter::new(pics, ioapic);
+    let bundle = config.cmos.then(|| Arc::new(Mutex::new(Bundle::new())));
+    if let Some(bundle) = &bundle {
+        devices.add_stateful_device("bundle", bundle.clone());
+    }
+    let hpet = config.hpet.then(|| Arc::new(Mutex::new(Hpet::new())));
+    if let Some(hpet) = &hpet {
+        // 0xfed0_0000, 0xfed0_0000 + 0x400: its counter is read with plain `mov`s, often.
+        let range = hpet.lock().mmio_range();
+        let device = hpet.clone();
+        devices.add_fast_mmio_device(
+            range,
+            Arc::new(move |access: crate::device::MmioAccess| {
+                let mut hpet = device.lock();
+                match access.write {
+                    Some(value) => hpet
+                        .write(access.addr, access.access_size, value)
+                        .map(|_| 0),
+                    None => hpet.read(access.addr, access.access_size),
+                }
+            }),
+        )?;
+        devices.add_stateful_device("hpet", hpet.clone());
+    }
+
+    // e.g. 0x80, 0x80 + 1
+    if let Some(port) = config.debug_port {
+        let debug_port = Arc::new(Mutex::new(device_emu::DebugPort::new(port)));
+        devices.add_port_io_device(debug_port.clone())?;
+        devices.add_batch_writer(debug_port);
+    }
+    // e.g. 0x501, 0x501 + 1
+    if let Some(port) = config.debug_exit {
+        devices.add_port_io_device(Arc::new(Mutex::new(device_emu::DebugExit::new(port))))?;
+    }
+    // 0xcf9, within the PCI configuration ports of the VM: its byte accesses come here first.
+    if config.reset_control {
+        devices.add_port_io_device(Arc::new(Mutex::new(device_emu::ResetControl::new())))?;
+    }
+    // 0x441, 0x441 + 3
+    let watchdog = config
+        .watchdog
+        .then(|| Arc::new(Mutex::new(Ib700Watchdog::new())));
+    if let Some(watchdog) = &watchdog {
+        devices.add_port_io_device(watchdog.clone())?;
+        devices.add_stateful_device("ib700 watchdog", watchdog.clone());
+    }
+
+    let mut pmio_devices: Vec<Arc<Mutex<dyn PioOps>>> = Vec::new();
+    if config.i8042 {
+        // Typed so that it can be retrieved with `keyboard`.
+        // 0x60, 0x60 + 1: data; 0x64, 0x64 + 1: status and command
+        let i8042 = Arc::new(Mutex::new(device_emu::I8042::new()));
+        devices.add_typed_port_io_device(i8042.clone())?;
+        devices.add_stateful_device("i8042", i8042.clone());
+        pmio_devices.push(Arc::new(Mutex::new(device_emu::I8042::proxy_command_port(
+            &i8042,
+        ))));
+    }
+    if let Some(bundle) = &bundle {
+        pmio_devices.extend([
+            /*
+               the complexity:
+               - port 0x70 and 0x71 is for CMOS, but bit 7 of 0x70 is for NMI
+               - port 0x40 ~ 0x43 is for PIT, but port 0x61 is also related
+            */
+            // 0x92, 0x92 + 1
+            Arc::new(Mutex::new(Bundle::proxy_system_control_a(bundle))) as Arc<Mutex<dyn PioOps>>,
+            // 0x61, 0x61 + 1
+            Arc::new(Mutex::new(Bundle::proxy_system_control_b(bundle))),
+            // 0x70, 0x70 + 2
+            Arc::new(Mutex::new(Bundle::proxy_cmos(bundle))),
+            // 0x40, 0x40 + 4
+            Arc::new(Mutex::new(Bundle::proxy_pit(bundle))),
+        ]);
+    }
+    // By default 0xf0 and 0xf1 (fpu), 0x87 (dma).
+    for &(port, len) in &config.dummy_ports {
+        pmio_devices.push(Arc::new(Mutex::new(device_emu::Dummy::new(port, len))));
+    }
+    // Arc::new(Mutex::new(device_emu::PCIConfigurationSpace::new(0xcf8))),
+    // Arc::new(Mutex::new(device_emu::PCIPassthrough::new(0xcf8))),
+    devices.add_port_io_devices(&mut pmio_devices)?;
+    Ok(LegacyPcDevices {
+        bundle,
+        hpet,
+        watchdog,
+        irq_router,
+    })
+}
+
+/// The ports of the VGA CRT controller.
+#[cfg(feature = "vga")]
+fn add_vga_devices<H: HyperCraftHal, B: BarAllocTrait + 'static>(
+    devices: &DeviceList<H, B>,
+) -> HyperResult {
+    // 0x3d4, 0x3d4 + 2: 0x3d4 and 0x3d5 are ports about vga
+    devices.add_port_io_device(Arc::new(Mutex::new(device_emu::Dummy::new(0x3d4, 2))))
+}
 
-        assert!(int_info.valid);
+/// The x2APIC MSRs of vCPU `vcpu_id`, proxied to the x2APIC of the machine, but the ones of
+/// `emulated` which other devices implement.
+///
+/// The proxy forwards the EOI register as is, so once the x2APIC of the machine is enabled the
+/// guest writes it natively instead.
+fn add_local_apic_proxy<H: HyperCraftHal, B: BarAllocTrait + 'static>(
+    devices: &DeviceList<H, B>,
+    vcpu_id: u32,
+    emulated: &[Range<u32>],
+) -> HyperResult {
+    use device_emu::ProxyLocalApic;
 
-        crate::irq::dispatch_host_irq(int_info.vector as usize)
+    let x2apic = unsafe { x86::msr::rdmsr(x86::msr::IA32_APIC_BASE) }.get_bit(10);
+    let eoi = ProxyLocalApic::EOI_MSR;
+    let msrs = ProxyLocalApic::MSRS;
+    // The EOI register is proxied with the others if it cannot be passed through.
+    let mut skipped = emulated.to_vec();
+    if x2apic && devices.set_msr_passthrough(eoi, true, true).is_ok() {
+        skipped.push(eoi..eoi + 1);
     }
+    skipped.sort_by_key(|range| range.start);
+    let mut start = msrs.start;
+    for range in skipped.into_iter().chain([msrs.end..msrs.end]) {
+        if start < range.start {
+            devices.add_msr_device(Arc::new(Mutex::new(ProxyLocalApic::with_msrs(
+                vcpu_id,
+                start..range.start,
+            ))))?;
+        }
+        start = range.end;
+    }
+    Ok(())
+}
+
+/// The policy configured for `vm_id`, for the MSRs no device implements.
+fn vm_msr_policy(vm_id: u32) -> UnhandledMsrPolicy {
+    vm_cfg_entry(vm_id as usize)
+        .map(|cfg| cfg.unhandled_msr_policy())
+        .unwrap_or_default()
+}
+
+/// The policy configured for `vm_id`, for the ports no device claims.
+fn vm_port_policy(vm_id: u32) -> UnhandledPortPolicy {
+    vm_cfg_entry(vm_id as usize)
+        .map(|cfg| cfg.unhandled_port_policy())
+        .unwrap_or_default()
+}
+
+pub struct X64VmDevices<H: HyperCraftHal, B: BarAllocTrait> {
+    /// Shared by the vCPUs of the VM, see [`vm_devices`].
+    devices: Arc<DeviceList<H, B>>,
+    /// The devices of `devices` last hit, by the exits of the one vCPU these devices are created
+    /// for.
+    dispatch_cache: DispatchCache,
+    marker: PhantomData<H>,
 }
 
-impl<H: HyperCraftHal, B: BarAllocTrait + 'static> PerVmDevices<H> for X64VmDevices<H, B> {
+impl<H: HyperCraftHal, B: BarAllocTrait + 'static> PerVmDevices<H> for X64VmDevices<H, B>
+where
+    DeviceList<H, B>: Send + Sync + 'static,
+{
     fn new(vm_id: u32) -> HyperResult<Self> {
-        let mut devices = DeviceList::new(None, Some(vm_id));
+        let devices = vm_devices::shared(vm_id, || {
+            let mut devices = DeviceList::new(None, Some(vm_id));
+            devices.set_unhandled_msr_policy(Some(vm_msr_policy(vm_id)));
+            devices.set_unhandled_port_policy(vm_port_policy(vm_id));
+            // Unlike NimbOS, the host VM only gets a PCI host for the virtio devices it lists.
+            #[cfg(feature = "virtio-pci")]
+            let devices = match vm_cfg_entry(vm_id as usize) {
+                Some(cfg) if !cfg.virtio_devices().is_empty() => {
+                    add_virtio_pci_devices(devices, cfg.virtio_devices(), cfg.pci_ecam())?
+                }
+                _ => devices,
+            };
+            Ok(devices)
+        })?;
 
         Ok(Self {
             marker: PhantomData,
             devices,
+            dispatch_cache: DispatchCache::new(),
         })
     }
 
@@ -792,68 +3085,171 @@ impl<H: HyperCraftHal, B: BarAllocTrait + 'static> PerVmDevices<H> for X64VmDevi
         exit_info: &VmExitInfo,
         instr: Option<Instruction>,
     ) -> Option<HyperResult> {
-        match exit_info.exit_reason {
-            VmxExitReason::EXTERNAL_INTERRUPT => Some(Self::handle_external_interrupt(vcpu)),
-            VmxExitReason::EPT_VIOLATION => {
-                self.devices.handle_mmio_instruction(vcpu, exit_info, instr)
-            }
-            VmxExitReason::IO_INSTRUCTION => self.devices.handle_io_instruction(vcpu, exit_info),
-            VmxExitReason::MSR_READ => Some(self.devices.handle_msr_read(vcpu)),
-            VmxExitReason::MSR_WRITE => Some(self.devices.handle_msr_write(vcpu)),
-            _ => None,
-        }
+        self.devices.handle_vm_exit(
+            &mut self.dispatch_cache,
+            vcpu,
+            exit_info,
+            instr,
+            Level::Debug,
+        )
     }
 }
 
-fn get_access_size(instruction: Instruction) -> HyperResult<u8> {
-    // only consider
-    match instruction.code() {
-        Code::INVALID => Err(HyperError::DecodeError),
-        _ => {
-            // debug!("op0:{:?} op1:{:?}", instruction.op0_kind(), instruction.op1_kind());
-            let size = match (instruction.op0_kind(), instruction.op1_kind()) {
-                (OpKind::Register, _) => instruction.op_register(0).size(),
-                (_, OpKind::Register) => instruction.op_register(1).size(),
-                (OpKind::Immediate8, _) | (_, OpKind::Immediate8) => 1,
-                (OpKind::Immediate16, _) | (_, OpKind::Immediate16) => 2,
-                (OpKind::Immediate32, _) | (_, OpKind::Immediate32) => 4,
-                (OpKind::Immediate64, _) | (_, OpKind::Immediate64) => 8,
-                _ => 0,
-            };
-            Ok(size as u8)
-        }
+fn handle_external_interrupt(ctx: &mut ExitContext, level: Level) -> HyperResult {
+    let int_info = ctx.interruption_info();
+    ratelimited!(
+        EXT_INTR_LOG,
+        level,
+        "VM-exit: external interrupt: {:#x?}",
+        int_info
+    );
+
+    if int_info.vector != 0xf0 {
+        panic!("VM-exit: external interrupt: {:#x?}", int_info);
     }
+
+    assert!(int_info.valid);
+
+    crate::irq::dispatch_host_irq(int_info.vector as usize)
 }
 
-pub struct NimbosVmDevices<H: HyperCraftHal, B: BarAllocTrait> {
-    devices: DeviceList<H, B>,
-    marker: PhantomData<H>,
+/// The last step of the [`dispatch`] chain, for exits no device list handled. `msr_policy` is
+/// the policy of the VM.
+fn unhandled_exit<H: HyperCraftHal>(
+    vm_id: u32,
+    vcpu: &mut VCpu<H>,
+    ctx: &ExitContext,
+    msr_policy: UnhandledMsrPolicy,
+) -> HyperResult {
+    match ctx.exit_reason {
+        VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE => {
+            let msr = vcpu.regs().rcx as u32;
+            unhandled_msr(vcpu, ctx, msr, msr_policy)
+        }
+        _ => vm_fatal(vm_id, vcpu, ctx),
+    }
 }
 
-impl<H: HyperCraftHal, B: BarAllocTrait + 'static> NimbosVmDevices<H, B> {
-    fn handle_external_interrupt(vcpu: &VCpu<H>) -> HyperResult {
-        let int_info = vcpu.interrupt_exit_info()?;
-        trace!("VM-exit: external interrupt: {:#x?}", int_info);
+/// Answer a RDMSR or WRMSR of `msr`, which no device implements, according to `policy`.
+fn unhandled_msr<H: HyperCraftHal>(
+    vcpu: &mut VCpu<H>,
+    ctx: &ExitContext,
+    msr: u32,
+    policy: UnhandledMsrPolicy,
+) -> HyperResult {
+    let is_read = ctx.exit_reason == VmxExitReason::MSR_READ;
+    match policy {
+        UnhandledMsrPolicy::Panic if is_read => {
+            panic!("Unsupported RDMSR {:#x}, vcpu: {:#x?}", msr, vcpu)
+        }
+        UnhandledMsrPolicy::Panic => panic!("Unsupported WRMSR {:#x}, vcpu: {:#x?}", msr, vcpu),
+        UnhandledMsrPolicy::InjectGp => {
+            ratelimited!(
+                MSR_EXIT_LOG,
+                Level::Warn,
+                "VM exit: unsupported {}({:#x}), inject #GP @ {:#x}",
+                if is_read { "RDMSR" } else { "WRMSR" },
+                msr,
+                ctx.guest_rip
+            );
+            inject_gp(vcpu);
+            Ok(())
+        }
+        UnhandledMsrPolicy::IgnoreRdZeroWrDrop => {
+            if is_read {
+                ratelimited!(
+                    MSR_EXIT_LOG,
+                    Level::Warn,
+                    "VM exit: unsupported RDMSR({:#x}) @ {:#x}, read as 0",
+                    msr,
+                    ctx.guest_rip
+                );
+                vcpu.regs_mut().rax = 0;
+                vcpu.regs_mut().rdx = 0;
+            } else {
+                let value = (vcpu.regs().rax & 0xffff_ffff) | (vcpu.regs().rdx << 32);
+                ratelimited!(
+                    MSR_EXIT_LOG,
+                    Level::Warn,
+                    "VM exit: unsupported WRMSR({:#x}) <- {:#x} @ {:#x}, ignored",
+                    msr,
+                    value,
+                    ctx.guest_rip
+                );
+            }
+            vcpu.advance_rip(ctx.exit_instruction_length as _)?;
+            Ok(())
+        }
+    }
+}
 
-        if int_info.vector != 0xf0 {
-            panic!("VM-exit: external interrupt: {:#x?}", int_info);
+/// What the MMIO emulation needs of `instr`, accessing memory in the direction `is_write`.
+fn mmio_instr(instr: &Instruction, is_write: bool) -> HyperResult<MmioInstr> {
+    let op = match (instr.mnemonic(), is_write) {
+        (Mnemonic::Mov, _) | (Mnemonic::Movzx, false) => MmioOp::Mov,
+        (Mnemonic::Movsx | Mnemonic::Movsxd, false) => MmioOp::Movsx,
+        (Mnemonic::Test, false) => MmioOp::Test,
+        (mnemonic, _) => {
+            error!("unrealized instruction:{:?}", mnemonic);
+            return Err(HyperError::InstructionNotSupported);
         }
+    };
+    Ok(MmioInstr {
+        len: instr.len() as u8,
+        access_size: get_access_size(instr)?,
+        operand: get_instr_data(instr, is_write)?,
+        op,
+        is_write,
+    })
+}
 
-        assert!(int_info.valid);
+/// Perform `access` on the MMIO device `device`, returning the value read, 0 for a write.
+fn mmio_device_access(device: &Mutex<dyn MmioOps>, access: MmioAccess) -> HyperResult<u64> {
+    match access.write {
+        Some(value) => device
+            .lock()
+            .write(access.addr, access.access_size, value)
+            .map(|_| 0),
+        None => device.lock().read(access.addr, access.access_size),
+    }
+}
 
-        crate::irq::dispatch_host_irq(int_info.vector as usize)
+/// Size of the memory operand of an MMIO instruction.
+fn get_access_size(instruction: &Instruction) -> HyperResult<u8> {
+    match instruction.code() {
+        Code::INVALID => Err(HyperError::DecodeError),
+        // Also the size read by `movzx` and `movsx`, unlike their register operand.
+        _ => match instruction.memory_size().size() {
+            0 => Err(HyperError::DecodeError),
+            size => Ok(size as u8),
+        },
     }
 }
 
-impl<H: HyperCraftHal, B: BarAllocTrait + 'static> PerVmDevices<H> for NimbosVmDevices<H, B> {
-    fn new(vm_id: u32) -> HyperResult<Self> {
-        let mut devices = DeviceList::new(None, Some(vm_id));
-        // init pci device
-        devices.init_pci_host();
-        devices.add_port_io_device(devices.pci_devices.clone().unwrap());
-        // This is just for test.
-        // devices.add_pci_device(String::from("pcitest"), Arc::new(AtomicU16::new(0)), 0x18)?;
+/// The PCI host of a VM, with its ECAM window `ecam` if any, and the virtio devices `configured`
+/// behind it.
+#[cfg(feature = "virtio-pci")]
+fn add_virtio_pci_devices<H: HyperCraftHal, B: BarAllocTrait + 'static>(
+    mut devices: DeviceList<H, B>,
+    configured: &[VirtioDeviceCfg],
+    ecam: Option<PciEcamCfg>,
+) -> HyperResult<DeviceList<H, B>> {
+    // init pci device
+    devices.init_pci_host();
+    devices.add_port_io_device(Arc::new(Mutex::new(device_emu::PciConfigPio::new(
+        devices.pci_devices.clone().unwrap(),
+    ))))?;
+    if let Some(ecam) = ecam {
+        devices.add_memory_io_device(Arc::new(Mutex::new(device_emu::PciEcamMmio::new(
+            devices.pci_devices.clone().unwrap(),
+            ecam,
+        ))))?;
+    }
+    devices.add_stateful_device("pci host", devices.pci_devices.clone().unwrap());
+    // This is just for test.
+    // devices.add_pci_device(String::from("pcitest"), Arc::new(AtomicU16::new(0)), 0x18)?;
 
+    if configured.is_empty() {
         // Create a virtio dummy device
         let virtio_device_dummy = DummyVirtioDevice::new(VIRTIO_TYPE_BLOCK, 1, 4);
         devices.add_virtio_pci_device(
@@ -862,10 +3258,91 @@ impl<H: HyperCraftHal, B: BarAllocTrait + 'static> PerVmDevices<H> for NimbosVmD
             Arc::new(Mutex::new(virtio_device_dummy)),
             false,
         )?;
+    }
+    for cfg in configured {
+        let device: Arc<Mutex<dyn VirtioDevice>> = match (&cfg.block_backend, &cfg.net_backend) {
+            (Some(backend), _) => Arc::new(Mutex::new(VirtioBlk::new(
+                devices.vm_id(),
+                &cfg.name,
+                backend.clone(),
+                cfg.queue_size,
+            ))),
+            (None, Some((backend, mac))) => Arc::new(Mutex::new(VirtioNet::new(
+                devices.vm_id(),
+                &cfg.name,
+                backend.clone(),
+                *mac,
+                cfg.queue_size,
+            ))),
+            (None, None) if cfg.device_type == VIRTIO_TYPE_CONSOLE => Arc::new(Mutex::new(
+                VirtioConsole::new(devices.vm_id(), &cfg.name, cfg.queue_size),
+            )),
+            (None, None) => Arc::new(Mutex::new(DummyVirtioDevice::new(
+                cfg.device_type,
+                cfg.queue_num,
+                cfg.queue_size,
+            ))),
+        };
+        let bdf = match cfg.devfn {
+            Some(devfn) => devices.add_virtio_pci_device(cfg.name.clone(), devfn, device, false),
+            None => devices.add_virtio_device(cfg.name.clone(), device),
+        }
+        .map_err(|err| {
+            warn!(
+                "VM {}: failed to add virtio device {}: {:?}",
+                devices.vm_id(),
+                cfg.name,
+                err
+            );
+            err
+        })?;
+        info!(
+            "VM {}: virtio device {} (type {}) at {}",
+            devices.vm_id(),
+            cfg.name,
+            cfg.device_type,
+            bdf
+        );
+    }
+    Ok(devices)
+}
+
+pub struct NimbosVmDevices<H: HyperCraftHal, B: BarAllocTrait> {
+    /// Shared by the vCPUs of the VM, as in [`X64VmDevices`].
+    devices: Arc<DeviceList<H, B>>,
+    /// The devices of `devices` last hit, as in [`X64VmDevices`].
+    dispatch_cache: DispatchCache,
+    marker: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal, B: BarAllocTrait + 'static> PerVmDevices<H> for NimbosVmDevices<H, B>
+where
+    DeviceList<H, B>: Send + Sync + 'static,
+{
+    fn new(vm_id: u32) -> HyperResult<Self> {
+        let devices = vm_devices::shared(vm_id, || {
+            let mut devices = DeviceList::new(None, Some(vm_id));
+            devices.set_unhandled_msr_policy(Some(vm_msr_policy(vm_id)));
+            devices.set_unhandled_port_policy(vm_port_policy(vm_id));
+            #[cfg(feature = "virtio-pci")]
+            let devices = {
+                let cfg = crate::config::entry::vm_cfg_entry(vm_id as usize);
+                let configured = cfg
+                    .as_ref()
+                    .map(|cfg| cfg.virtio_devices().to_vec())
+                    .unwrap_or_default();
+                let ecam = cfg.and_then(|cfg| cfg.pci_ecam());
+                let devices = add_virtio_pci_devices(devices, &configured, ecam)?;
+                crate::device::add_shmem_devices(vm_id, devices.pci_root_bus.as_ref().unwrap())?;
+                devices
+            };
+            Ok(devices)
+        })?;
 
         Ok(Self {
             marker: PhantomData,
             devices,
+            dispatch_cache: DispatchCache::new(),
         })
     }
 
@@ -875,91 +3352,823 @@ impl<H: HyperCraftHal, B: BarAllocTrait + 'static> PerVmDevices<H> for NimbosVmD
         exit_info: &VmExitInfo,
         instr: Option<Instruction>,
     ) -> Option<HyperResult> {
-        match exit_info.exit_reason {
-            VmxExitReason::EXTERNAL_INTERRUPT => Some(Self::handle_external_interrupt(vcpu)),
-            VmxExitReason::EPT_VIOLATION => {
-                self.devices.handle_mmio_instruction(vcpu, exit_info, instr)
-            }
-            VmxExitReason::IO_INSTRUCTION => self.devices.handle_io_instruction(vcpu, exit_info),
-            VmxExitReason::MSR_READ => Some(self.devices.handle_msr_read(vcpu)),
-            VmxExitReason::MSR_WRITE => Some(self.devices.handle_msr_write(vcpu)),
-            _ => None,
+        self.devices.handle_vm_exit(
+            &mut self.dispatch_cache,
+            vcpu,
+            exit_info,
+            instr,
+            Level::Trace,
+        )
+    }
+}
+
+/// The non-memory operand of an emulated MMIO instruction.
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Register(Register),
+    Immediate(u64),
+}
+
+/// The 64-bit general-purpose register containing `reg`.
+fn gpr_mut<H: HyperCraftHal>(vcpu: &mut VCpu<H>, reg: Register) -> HyperResult<&mut u64> {
+    let regs = vcpu.regs_mut();
+    // not consider segment register
+    let gpr = match reg.full_register() {
+        Register::RAX => &mut regs.rax,
+        Register::RBX => &mut regs.rbx,
+        Register::RCX => &mut regs.rcx,
+        Register::RDX => &mut regs.rdx,
+        Register::RSI => &mut regs.rsi,
+        Register::RDI => &mut regs.rdi,
+        Register::RBP => &mut regs.rbp,
+        Register::R8 => &mut regs.r8,
+        Register::R9 => &mut regs.r9,
+        Register::R10 => &mut regs.r10,
+        Register::R11 => &mut regs.r11,
+        Register::R12 => &mut regs.r12,
+        Register::R13 => &mut regs.r13,
+        Register::R14 => &mut regs.r14,
+        Register::R15 => &mut regs.r15,
+        _ => return Err(HyperError::InvalidParam),
+    };
+    Ok(gpr)
+}
+
+/// Whether `reg` is one of the legacy high byte registers, bits 8 to 15 of its full register.
+fn is_high_byte_register(reg: Register) -> bool {
+    matches!(
+        reg,
+        Register::AH | Register::BH | Register::CH | Register::DH
+    )
+}
+
+/// Write `value` to `reg` like an instruction with `reg` as destination does (SDM Vol. 1,
+/// Section 3.4.1.1): a 32-bit result is zero-extended to the full register, an 8-bit or
+/// 16-bit result leaves the other bits of the register unchanged.
+fn write_gpr<H: HyperCraftHal>(vcpu: &mut VCpu<H>, reg: Register, value: u64) -> HyperResult {
+    let (size, shift) = if is_high_byte_register(reg) {
+        (1, 8)
+    } else {
+        (reg.size(), 0)
+    };
+    let gpr = gpr_mut(vcpu, reg)?;
+    match size {
+        1 | 2 => {
+            let mask = access_size_mask(size as u8) << shift;
+            *gpr = (*gpr & !mask) | ((value << shift) & mask);
+        }
+        4 => *gpr = value & 0xffff_ffff,
+        8 => *gpr = value,
+        _ => return Err(HyperError::DecodeError),
+    }
+    Ok(())
+}
+
+fn operand_value<H: HyperCraftHal>(vcpu: &mut VCpu<H>, operand: Operand) -> HyperResult<u64> {
+    match operand {
+        Operand::Register(reg) if is_high_byte_register(reg) => {
+            gpr_mut(vcpu, reg).map(|gpr| (*gpr >> 8) & 0xff)
         }
+        Operand::Register(reg) => gpr_mut(vcpu, reg).map(|gpr| *gpr),
+        Operand::Immediate(imm) => Ok(imm),
     }
 }
 
-fn get_instr_data(
-    instruction: Instruction,
-    is_write: bool,
-) -> HyperResult<(OpKind, Option<String>)> {
+/// The operand of `instruction` other than its memory operand: the source of a write, the
+/// memory operand being the destination, or the destination of a read, as in `mov r, m` and
+/// `movzx r, m`. Both operands of `test` are sources, its memory operand being the first.
+fn get_instr_data(instruction: &Instruction, is_write: bool) -> HyperResult<Operand> {
     // only support 2 operands instruction
-    let (kind, op_str) = match (instruction.op0_kind(), instruction.op1_kind()) {
-        (OpKind::Register, _) => {
-            let reg = instruction.op0_register();
-            (OpKind::Register, Some(format!("{:?}", reg).to_lowercase()))
-        }
-        (_, OpKind::Register) => {
-            let reg = instruction.op1_register();
-            (OpKind::Register, Some(format!("{:?}", reg).to_lowercase()))
-        }
-        (OpKind::Immediate8, _) | (_, OpKind::Immediate8) => (
-            OpKind::Immediate8,
-            Some(format!("{:?}", instruction.immediate64())),
-        ),
-        (OpKind::Immediate16, _) | (_, OpKind::Immediate16) => (
-            OpKind::Immediate16,
-            Some(format!("{:?}", instruction.immediate64())),
-        ),
-        (OpKind::Immediate32, _) | (_, OpKind::Immediate32) => (
-            OpKind::Immediate32,
-            Some(format!("{:?}", instruction.immediate64())),
-        ),
-        (OpKind::Immediate64, _) | (_, OpKind::Immediate64) => (
-            OpKind::Immediate64,
-            Some(format!("{:?}", instruction.immediate64())),
-        ),
+    if instruction.op_count() != 2 {
+        return Err(HyperError::DecodeError);
+    }
+    let memory = (0..2)
+        .find(|&i| instruction.op_kind(i) == OpKind::Memory)
+        .ok_or(HyperError::DecodeError)?;
+    let destination = memory == 0 && instruction.mnemonic() != Mnemonic::Test;
+    if destination != is_write {
+        return Err(HyperError::DecodeError);
+    }
+    let other = 1 - memory;
+    let operand = match instruction.op_kind(other) {
+        OpKind::Register => Operand::Register(instruction.op_register(other)),
+        // Sign-extended to 64 bits by `immediate` for the `Immediate*to*` kinds, the value is
+        // masked to the access size anyway.
+        OpKind::Immediate8
+        | OpKind::Immediate16
+        | OpKind::Immediate32
+        | OpKind::Immediate64
+        | OpKind::Immediate8to16
+        | OpKind::Immediate8to32
+        | OpKind::Immediate8to64
+        | OpKind::Immediate32to64 => Operand::Immediate(instruction.immediate(other)),
         _ => return Err(HyperError::OperandNotSupported),
     };
-    Ok((kind, op_str))
-    // let op_code = instruction.op_code();
-    // match op_code.instruction_string().to_lowercase() {
-    //     s if s.contains("mov") => {
-    //         debug!("this is instr: {}", s);
-    //         return get_mov_data(instruction, is_write);
-    //     }
-    //     s if s.contains("test") => {
-    //         debug!("this is instr: {}", s);
-    //     }
-    //     _ => {
-    //         error!("unrealized instruction:{:?}", op_code.instruction_string());
-    //         return Err(HyperError::InstructionNotSupported);
-    //     }
-    // };
-    // Err(HyperError::InstructionNotSupported)
-}
-
-fn get_mov_data(instruction: Instruction, is_write: bool) -> HyperResult<(OpKind, Option<String>)> {
-    // mov dest, src
-    let op_kind = if is_write {
-        instruction.op1_kind()
-    } else {
-        instruction.op0_kind()
-    };
-    match op_kind {
-        OpKind::Immediate8 | OpKind::Immediate16 | OpKind::Immediate32 | OpKind::Immediate64 => {
-            return Ok((op_kind, Some(format!("{:?}", instruction.immediate64()))));
+    Ok(operand)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::exit_vcpu::MockVcpu;
+    use super::*;
+
+    /// A device of `len` ports or bytes at `base` holding the dispatcher to its contract, byte
+    /// `addr` reading as `addr as u8`.
+    struct Recorder {
+        base: u64,
+        len: u64,
+        accesses: Vec<(u64, u8, Option<u64>)>,
+    }
+
+    impl Recorder {
+        fn new(base: u64, len: u64) -> Self {
+            Self {
+                base,
+                len,
+                accesses: Vec::new(),
+            }
         }
-        OpKind::Register => {
-            let reg = if is_write {
-                instruction.op1_register()
-            } else {
-                instruction.op0_register()
+
+        fn check(&self, addr: u64, access_size: u8) {
+            assert!([1, 2, 4, 8].contains(&access_size));
+            assert!(addr >= self.base && addr + access_size as u64 <= self.base + self.len);
+        }
+
+        fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+            self.check(addr, access_size);
+            self.accesses.push((addr, access_size, None));
+            Ok((0..access_size as u64).fold(0, |value, i| value | ((addr + i) & 0xff) << (i * 8)))
+        }
+
+        fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+            self.check(addr, access_size);
+            assert_eq!(value & !access_size_mask(access_size), 0);
+            self.accesses.push((addr, access_size, Some(value)));
+            Ok(())
+        }
+    }
+
+    impl PioOps for Recorder {
+        fn port_range(&self) -> Range<u16> {
+            self.base as u16..(self.base + self.len) as u16
+        }
+
+        fn read(&mut self, port: u16, access_size: u8) -> HyperResult<u32> {
+            Recorder::read(self, port as u64, access_size).map(|value| value as u32)
+        }
+
+        fn write(&mut self, port: u16, access_size: u8, value: u32) -> HyperResult {
+            Recorder::write(self, port as u64, access_size, value as u64)
+        }
+    }
+
+    impl MmioOps for Recorder {
+        fn mmio_range(&self) -> Range<u64> {
+            self.base..self.base + self.len
+        }
+
+        fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+            Recorder::read(self, addr, access_size)
+        }
+
+        fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+            Recorder::write(self, addr, access_size, value)
+        }
+    }
+
+    /// MSRs `msrs` holding `values`, rejecting the writes if `read_only`.
+    struct Msrs {
+        msrs: Range<u32>,
+        values: Vec<u64>,
+        read_only: bool,
+    }
+
+    impl Msrs {
+        fn new(msrs: Range<u32>, read_only: bool) -> Self {
+            let values = vec![0; msrs.len()];
+            Self {
+                msrs,
+                values,
+                read_only,
+            }
+        }
+    }
+
+    impl VirtMsrOps for Msrs {
+        fn msr_range(&self) -> Range<u32> {
+            self.msrs.clone()
+        }
+
+        fn read(&mut self, msr: u32) -> HyperResult<u64> {
+            Ok(self.values[(msr - self.msrs.start) as usize])
+        }
+
+        fn write(&mut self, msr: u32, value: u64) -> HyperResult {
+            if self.read_only {
+                return Err(HyperError::NotSupported);
+            }
+            self.values[(msr - self.msrs.start) as usize] = value;
+            Ok(())
+        }
+    }
+
+    type Devices = DeviceList<axhal::hv::HyperCraftHalImpl, BarAllocImpl>;
+
+    /// The system allocator, counting the allocations of each thread, for the allocation audit
+    /// of the exit handlers.
+    struct CountingAlloc;
+
+    std::thread_local! {
+        static ALLOCATIONS: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+    }
+
+    unsafe impl core::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+            // Not counted while the thread is torn down.
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    /// The heap allocations made by `f` on this thread. A reallocation counts as one.
+    fn allocations(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(|count| count.get());
+        f();
+        ALLOCATIONS.with(|count| count.get()) - before
+    }
+
+    /// The instruction of 64-bit code `bytes`.
+    fn decode(bytes: &[u8]) -> Instruction {
+        decode_code(64, bytes)
+    }
+
+    /// The instruction of code `bytes` of `bitness` 16, 32 or 64.
+    fn decode_code(bitness: u32, bytes: &[u8]) -> Instruction {
+        iced_x86::Decoder::new(bitness, bytes, iced_x86::DecoderOptions::NONE).decode()
+    }
+
+    /// The context of an `in` or `out` of `access_size` bytes at `port`, 1 byte long.
+    fn io_exit(port: u16, access_size: u8, is_in: bool) -> ExitContext {
+        let mut qualification = (access_size as u64 - 1) | (port as u64) << 16;
+        qualification.set_bit(3, is_in);
+        ExitContext::synthetic(VmxExitReason::IO_INSTRUCTION, 1, qualification)
+    }
+
+    fn msr_exit(exit_reason: VmxExitReason) -> ExitContext {
+        ExitContext::synthetic(exit_reason, 2, 0)
+    }
+
+    /// Drive `device` through every access size at every port of its range and around it, as
+    /// the dispatcher does: the accesses are rejected or reach the device within its contract.
+    fn conform_pio(device: &mut dyn PioOps) {
+        let range = device.port_range();
+        let mut device = ClippedPio { device };
+        for port in range.start.saturating_sub(1)..range.end.saturating_add(1) {
+            for access_size in [0, 1, 2, 3, 4, 8] {
+                let valid = PIO_ACCESS_SIZES.contains(&access_size) && range.contains(&port);
+                let read = device.read(port, access_size);
+                if !valid {
+                    assert!(matches!(read, Err(HyperError::InvalidParam)));
+                } else if let Ok(value) = read {
+                    assert_eq!(value as u64 & !access_size_mask(access_size), 0);
+                }
+                for value in [0, u32::MAX] {
+                    let write = device.write(port, access_size, value);
+                    if !valid {
+                        assert!(matches!(write, Err(HyperError::InvalidParam)));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_access() {
+        let range = 0x10..0x14;
+        assert_eq!(
+            check_access(range.clone(), 0x10, 4, PIO_ACCESS_SIZES).unwrap(),
+            0x10..0x14
+        );
+        assert_eq!(
+            check_access(range.clone(), 0x12, 4, PIO_ACCESS_SIZES).unwrap(),
+            0x12..0x14
+        );
+        assert_eq!(
+            check_access(range.clone(), 0x13, 2, PIO_ACCESS_SIZES).unwrap(),
+            0x13..0x14
+        );
+        assert!(check_access(range.clone(), 0x10, 8, PIO_ACCESS_SIZES).is_err());
+        assert!(check_access(range.clone(), 0x10, 3, MMIO_ACCESS_SIZES).is_err());
+        assert!(check_access(range.clone(), 0x14, 1, PIO_ACCESS_SIZES).is_err());
+        assert!(check_access(range, 0xf, 1, PIO_ACCESS_SIZES).is_err());
+
+        // A device at the end of the address space.
+        let range = u64::MAX - 4..u64::MAX;
+        assert_eq!(
+            check_access(range.clone(), u64::MAX - 2, 8, MMIO_ACCESS_SIZES).unwrap(),
+            u64::MAX - 2..u64::MAX
+        );
+        assert_eq!(
+            check_access(range.clone(), u64::MAX - 1, 1, MMIO_ACCESS_SIZES).unwrap(),
+            u64::MAX - 1..u64::MAX
+        );
+        assert!(check_access(range, u64::MAX, 1, MMIO_ACCESS_SIZES).is_err());
+    }
+
+    #[test]
+    fn test_straddling_read() {
+        let mut device = Recorder::new(0x60, 2);
+        let value = ClippedPio {
+            device: &mut device,
+        }
+        .read(0x61, 4)
+        .unwrap();
+        // The byte of the device, then the open bus.
+        assert_eq!(value, 0xffff_ff61);
+        assert_eq!(device.accesses, [(0x61, 1, None)]);
+    }
+
+    #[test]
+    fn test_straddling_write() {
+        let mut device = Recorder::new(0x60, 3);
+        ClippedPio {
+            device: &mut device,
+        }
+        .write(0x61, 4, 0x4433_2211)
+        .unwrap();
+        assert_eq!(
+            device.accesses,
+            [(0x61, 1, Some(0x11)), (0x62, 1, Some(0x22))]
+        );
+    }
+
+    #[test]
+    fn test_whole_access() {
+        let mut device = Recorder::new(0x60, 4);
+        let mut clipped = ClippedPio {
+            device: &mut device,
+        };
+        assert_eq!(clipped.read(0x60, 4).unwrap(), 0x6362_6160);
+        // The bits above the access size are not written.
+        clipped.write(0x62, 2, 0xdead_beef).unwrap();
+        assert_eq!(device.accesses, [(0x60, 4, None), (0x62, 2, Some(0xbeef))]);
+    }
+
+    #[test]
+    fn test_split_mmio() {
+        let mut device = Recorder::new(0x1000, 6);
+        let inside = check_access(0x1000..0x1006, 0x1004, 8, MMIO_ACCESS_SIZES).unwrap();
+        let value = split_read(inside.clone(), 0x1004, 8, |addr, size| {
+            device.read(addr, size)
+        });
+        assert_eq!(value.unwrap(), 0xffff_ffff_ffff_0504);
+        split_write(inside, 0x1004, 8, u64::MAX, |addr, size, value| {
+            device.write(addr, size, value)
+        })
+        .unwrap();
+        assert_eq!(
+            device.accesses,
+            [
+                (0x1004, 1, None),
+                (0x1005, 1, None),
+                (0x1004, 1, Some(0xff)),
+                (0x1005, 1, Some(0xff))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dispatch_cache_invalidation() {
+        let devices = DeviceList::<axhal::hv::HyperCraftHalImpl, BarAllocImpl>::new(Some(0), None);
+        let mut cache = DispatchCache::new();
+        let same = |a: &Arc<Mutex<dyn PioOps>>, b: &Arc<Mutex<dyn PioOps>>| {
+            Arc::as_ptr(a) as *const () == Arc::as_ptr(b) as *const ()
+        };
+        let first: Arc<Mutex<dyn PioOps>> = Arc::new(Mutex::new(Recorder::new(0x60, 2)));
+        devices.add_port_io_device(first.clone()).unwrap();
+        for port in [0x60, 0x61, 0x61] {
+            let found = devices.find_port_io_device(&mut cache, port).unwrap();
+            assert!(same(&found, &first));
+        }
+        assert_eq!((cache.stats().hits, cache.stats().misses), (2, 1));
+        // Only a hit within the range of the last device.
+        assert!(devices.find_port_io_device(&mut cache, 0x62).is_none());
+
+        // Removing the device invalidates the hit, the port is no one's.
+        devices.remove_port_io_device(0x60).unwrap();
+        assert!(devices.find_port_io_device(&mut cache, 0x61).is_none());
+        let misses = cache.stats().misses;
+        // A device added at the same port is found, not the removed one.
+        let second: Arc<Mutex<dyn PioOps>> = Arc::new(Mutex::new(Recorder::new(0x60, 4)));
+        devices.add_port_io_device(second.clone()).unwrap();
+        let found = devices.find_port_io_device(&mut cache, 0x61).unwrap();
+        assert!(same(&found, &second));
+        assert_eq!(cache.stats().misses, misses + 1);
+
+        // Any other update of the tables invalidates it too.
+        let other: Arc<Mutex<dyn PioOps>> = Arc::new(Mutex::new(Recorder::new(0x70, 2)));
+        devices.add_port_io_device(other).unwrap();
+        devices.find_port_io_device(&mut cache, 0x61).unwrap();
+        assert_eq!(cache.stats().misses, misses + 2);
+    }
+
+    #[test]
+    fn test_pio_conformance() {
+        conform_pio(&mut Recorder::new(0x70, 2));
+        conform_pio(&mut Recorder::new(0x3f8, 8));
+        conform_pio(&mut device_emu::PCIConfigurationSpace::new(0xcf8));
+        #[cfg(any(feature = "legacy-pc-devices", feature = "vga"))]
+        conform_pio(&mut device_emu::Dummy::new(0x3c0, 0x20));
+        #[cfg(feature = "legacy-pc-devices")]
+        {
+            conform_pio(&mut device_emu::DebugPort::new(0x80));
+            conform_pio(&mut device_emu::I8042::new());
+        }
+    }
+
+    #[test]
+    fn test_io_instruction() {
+        const RAX: u64 = 0x1122_3344_5566_7788;
+        // An `in` merges the bytes and words into RAX, a doubleword is zero-extended.
+        for (access_size, rax) in [
+            (1, 0x1122_3344_5566_7760),
+            (2, 0x1122_3344_5566_6160),
+            (4, 0x6362_6160),
+        ] {
+            let device = Arc::new(Mutex::new(Recorder::new(0x60, 4)));
+            let mut vcpu = MockVcpu::default();
+            vcpu.regs.rax = RAX;
+            let ctx = io_exit(0x60, access_size, true);
+            Devices::handle_io_instruction_to_device(&mut vcpu, &ctx, device.clone(), None)
+                .unwrap();
+            assert_eq!(vcpu.regs.rax, rax);
+            assert_eq!(vcpu.rip, 1);
+            assert_eq!(device.lock().accesses, [(0x60, access_size, None)]);
+        }
+        // An `out` writes the low bytes of RAX, leaving it unchanged.
+        for (access_size, value) in [(1, 0x88), (2, 0x7788), (4, 0x5566_7788)] {
+            let device = Arc::new(Mutex::new(Recorder::new(0x60, 4)));
+            let mut vcpu = MockVcpu::default();
+            vcpu.regs.rax = RAX;
+            let ctx = io_exit(0x60, access_size, false);
+            Devices::handle_io_instruction_to_device(&mut vcpu, &ctx, device.clone(), None)
+                .unwrap();
+            assert_eq!(vcpu.regs.rax, RAX);
+            assert_eq!(vcpu.rip, 1);
+            assert_eq!(device.lock().accesses, [(0x60, access_size, Some(value))]);
+        }
+        // A port outside of the device fails, the instruction is not skipped.
+        let device = Arc::new(Mutex::new(Recorder::new(0x60, 4)));
+        let mut vcpu = MockVcpu::default();
+        let ctx = io_exit(0x64, 1, true);
+        assert!(Devices::handle_io_instruction_to_device(&mut vcpu, &ctx, device, None).is_err());
+        assert_eq!(vcpu.rip, 0);
+        // Not an I/O instruction exit.
+        let device = Arc::new(Mutex::new(Recorder::new(0x60, 4)));
+        let ctx = msr_exit(VmxExitReason::MSR_READ);
+        assert!(Devices::handle_io_instruction_to_device(&mut vcpu, &ctx, device, None).is_err());
+    }
+
+    #[test]
+    fn test_msr_instructions() {
+        // IA32_PAT, whose bits 3 to 7 of each entry are reserved.
+        const PAT: u32 = 0x277;
+        let device = Arc::new(Mutex::new(Msrs::new(PAT..PAT + 1, false)));
+        device.lock().values[0] = 0x0007_0406_0007_0406;
+        let mut vcpu = MockVcpu::default();
+        vcpu.regs.rcx = PAT as u64;
+        let read = msr_exit(VmxExitReason::MSR_READ);
+        Devices::handle_msr_read_to_device(&mut vcpu, &read, PAT, device.clone()).unwrap();
+        assert_eq!((vcpu.regs.rax, vcpu.regs.rdx), (0x0007_0406, 0x0007_0406));
+        assert_eq!(vcpu.rip, 2);
+
+        // EDX:EAX, the bits above EAX being ignored.
+        let write = msr_exit(VmxExitReason::MSR_WRITE);
+        vcpu.regs.rax = 0xffff_ffff_0001_0203;
+        vcpu.regs.rdx = 0x0405_0607;
+        Devices::handle_msr_write_to_device(&mut vcpu, &write, PAT, device.clone()).unwrap();
+        assert_eq!(device.lock().values[0], 0x0405_0607_0001_0203);
+        assert_eq!(vcpu.rip, 4);
+        assert!(vcpu.events.is_empty());
+
+        // A reserved bit set: #GP, the MSR and RIP are left unchanged.
+        vcpu.regs.rax = 0x08;
+        Devices::handle_msr_write_to_device(&mut vcpu, &write, PAT, device.clone()).unwrap();
+        assert_eq!(device.lock().values[0], 0x0405_0607_0001_0203);
+        assert_eq!(vcpu.rip, 4);
+        assert_eq!(vcpu.events, [(GP_VECTOR, Some(0))]);
+
+        // The device rejecting the write: #GP too.
+        let device = Arc::new(Mutex::new(Msrs::new(0x4000_0000..0x4000_0001, true)));
+        let mut vcpu = MockVcpu::default();
+        Devices::handle_msr_write_to_device(&mut vcpu, &write, 0x4000_0000, device).unwrap();
+        assert_eq!(vcpu.rip, 0);
+        assert_eq!(vcpu.events, [(GP_VECTOR, Some(0))]);
+    }
+
+    #[test]
+    fn test_find_devices() {
+        let devices = Devices::new(Some(0), None);
+        let mut cache = DispatchCache::new();
+        let pio: Arc<Mutex<dyn PioOps>> = Arc::new(Mutex::new(Recorder::new(0x60, 2)));
+        let mmio: Arc<Mutex<dyn MmioOps>> =
+            Arc::new(Mutex::new(Recorder::new(0xd000_0000, 0x1000)));
+        let msrs: Arc<Mutex<dyn VirtMsrOps>> =
+            Arc::new(Mutex::new(Msrs::new(0x4000_0000..0x4000_0100, false)));
+        devices.add_port_io_device(pio).unwrap();
+        devices.add_memory_io_device(mmio).unwrap();
+        devices.add_msr_device(msrs).unwrap();
+
+        // The first and last of each range are found, not their neighbours.
+        for (port, found) in [(0x5f, false), (0x60, true), (0x61, true), (0x62, false)] {
+            assert_eq!(
+                devices.find_port_io_device(&mut cache, port).is_some(),
+                found
+            );
+        }
+        for (addr, found) in [
+            (0xcfff_ffff, false),
+            (0xd000_0000, true),
+            (0xd000_0fff, true),
+            (0xd000_1000, false),
+        ] {
+            assert_eq!(
+                devices.find_memory_io_device(&mut cache, addr).is_some(),
+                found
+            );
+        }
+        for (msr, found) in [
+            (0x3fff_ffff, false),
+            (0x4000_0000, true),
+            (0x4000_00ff, true),
+            (0x4000_0100, false),
+        ] {
+            assert_eq!(devices.find_msr_device(&mut cache, msr).is_some(), found);
+        }
+
+        // An overlapping device is rejected, the other one still found.
+        let overlapping: Arc<Mutex<dyn PioOps>> = Arc::new(Mutex::new(Recorder::new(0x61, 2)));
+        assert!(matches!(
+            devices.add_port_io_device(overlapping),
+            Err(HyperError::InvalidParam)
+        ));
+        assert!(devices.find_port_io_device(&mut cache, 0x62).is_none());
+    }
+
+    #[test]
+    fn test_mmio_instr() {
+        let read = |bytes: &[u8]| mmio_instr(&decode(bytes), false).unwrap();
+        // mov eax, [rbx]
+        let mmio = read(&[0x8b, 0x03]);
+        assert_eq!((mmio.op, mmio.access_size, mmio.len), (MmioOp::Mov, 4, 2));
+        assert!(matches!(mmio.operand, Operand::Register(Register::EAX)));
+        // movzx eax, byte ptr [rbx]: the access is the size of the memory operand.
+        let mmio = read(&[0x0f, 0xb6, 0x03]);
+        assert_eq!((mmio.op, mmio.access_size, mmio.len), (MmioOp::Mov, 1, 3));
+        assert!(matches!(mmio.operand, Operand::Register(Register::EAX)));
+        // movsx ecx, word ptr [rbx]
+        let mmio = read(&[0x0f, 0xbf, 0x0b]);
+        assert_eq!((mmio.op, mmio.access_size), (MmioOp::Movsx, 2));
+        assert!(matches!(mmio.operand, Operand::Register(Register::ECX)));
+        // movsxd rax, dword ptr [rbx]
+        let mmio = read(&[0x48, 0x63, 0x03]);
+        assert_eq!((mmio.op, mmio.access_size), (MmioOp::Movsx, 4));
+        assert!(matches!(mmio.operand, Operand::Register(Register::RAX)));
+        // test [rbx], edx
+        let mmio = read(&[0x85, 0x13]);
+        assert_eq!((mmio.op, mmio.access_size), (MmioOp::Test, 4));
+        assert!(matches!(mmio.operand, Operand::Register(Register::EDX)));
+
+        // mov dword ptr [rbx], 0x12345678
+        let mmio = mmio_instr(&decode(&[0xc7, 0x03, 0x78, 0x56, 0x34, 0x12]), true).unwrap();
+        assert_eq!((mmio.op, mmio.access_size, mmio.len), (MmioOp::Mov, 4, 6));
+        assert!(matches!(mmio.operand, Operand::Immediate(0x1234_5678)));
+        // mov byte ptr [rbx], ah
+        let mmio = mmio_instr(&decode(&[0x88, 0x23]), true).unwrap();
+        assert!(matches!(mmio.operand, Operand::Register(Register::AH)));
+
+        // The direction of the violation must be the one of the instruction.
+        assert!(mmio_instr(&decode(&[0x0f, 0xb6, 0x03]), true).is_err());
+        assert!(mmio_instr(&decode(&[0x89, 0x03]), false).is_err());
+        // add [rbx], eax
+        assert!(matches!(
+            mmio_instr(&decode(&[0x01, 0x03]), true),
+            Err(HyperError::InstructionNotSupported)
+        ));
+    }
+
+    #[test]
+    fn test_mmio_instr_bitness() {
+        let mmio = |bitness, bytes: &[u8], is_write| {
+            mmio_instr(&decode_code(bitness, bytes), is_write).unwrap()
+        };
+        // The operand size prefix switches between 16 and 32 bits, the address size prefix only
+        // changes the length of the instruction.
+        for (bitness, bytes, access_size, register) in [
+            // mov [bx], ax
+            (16, &[0x89, 0x07][..], 2, Register::AX),
+            // mov [bx], eax
+            (16, &[0x66, 0x89, 0x07], 4, Register::EAX),
+            // mov [edi], eax
+            (32, &[0x89, 0x07], 4, Register::EAX),
+            // mov [edi], ax
+            (32, &[0x66, 0x89, 0x07], 2, Register::AX),
+            // mov [bx], ah
+            (16, &[0x88, 0x27], 1, Register::AH),
+        ] {
+            let mmio = mmio(bitness, bytes, true);
+            assert_eq!(
+                (mmio.op, mmio.access_size, mmio.len),
+                (MmioOp::Mov, access_size, bytes.len() as u8),
+                "{bytes:02x?}"
+            );
+            assert!(matches!(mmio.operand, Operand::Register(r) if r == register));
+        }
+        for (bitness, bytes, access_size, register) in [
+            // mov ax, [di]
+            (16, &[0x8b, 0x05][..], 2, Register::AX),
+            // mov ax, ds:[0x1000]
+            (16, &[0x67, 0x8b, 0x05, 0, 0x10, 0, 0], 2, Register::AX),
+            // mov eax, ds:[0x1000]
+            (32, &[0x8b, 0x05, 0, 0x10, 0, 0], 4, Register::EAX),
+            // mov eax, [di]
+            (32, &[0x67, 0x8b, 0x05], 4, Register::EAX),
+            // mov ax, [di]
+            (32, &[0x66, 0x67, 0x8b, 0x05], 2, Register::AX),
+            // movzx eax, byte ptr [edi]
+            (32, &[0x0f, 0xb6, 0x07], 1, Register::EAX),
+        ] {
+            let mmio = mmio(bitness, bytes, false);
+            assert_eq!(
+                (mmio.op, mmio.access_size, mmio.len),
+                (MmioOp::Mov, access_size, bytes.len() as u8),
+                "{bytes:02x?}"
+            );
+            assert!(matches!(mmio.operand, Operand::Register(r) if r == register));
+        }
+
+        // mov word ptr [bx], 0x1234 and mov word ptr [edi], 0x1234
+        for (bitness, bytes) in [
+            (16, &[0xc7, 0x07, 0x34, 0x12][..]),
+            (32, &[0x66, 0xc7, 0x07, 0x34, 0x12]),
+        ] {
+            let mmio = mmio(bitness, bytes, true);
+            assert_eq!((mmio.access_size, mmio.len), (2, bytes.len() as u8));
+            assert!(matches!(mmio.operand, Operand::Immediate(0x1234)));
+        }
+
+        // The memory operand is the destination of a write and the source of a read, except for
+        // test [bx], ax whose operands are both sources.
+        assert!(mmio_instr(&decode_code(16, &[0x89, 0x07]), false).is_err());
+        assert!(mmio_instr(&decode_code(32, &[0x8b, 0x05, 0, 0, 0, 0]), true).is_err());
+        let mmio = mmio(16, &[0x85, 0x07], false);
+        assert_eq!((mmio.op, mmio.access_size), (MmioOp::Test, 2));
+        assert!(matches!(mmio.operand, Operand::Register(Register::AX)));
+        assert!(mmio_instr(&decode_code(16, &[0x85, 0x07]), true).is_err());
+    }
+
+    #[test]
+    fn test_write_in_result() {
+        const RAX: u64 = 0x1122_3344_5566_7788;
+        // AL and AX are merged, EAX is zero-extended, whatever the mode of the guest.
+        for (access_size, rax) in [
+            (1, 0x1122_3344_5566_77dd),
+            (2, 0x1122_3344_5566_ccdd),
+            (4, 0xaabb_ccdd),
+            (8, 0x9988_7766_aabb_ccdd),
+        ] {
+            let mut value = RAX;
+            write_in_result(&mut value, access_size, 0x9988_7766_aabb_ccdd);
+            assert_eq!(value, rax, "access size {access_size}");
+        }
+    }
+
+    /// The port I/O, MMIO and MSR handling of an exit, once the devices were looked up, the
+    /// instruction decoded and the caches filled, makes no heap allocation.
+    #[test]
+    fn test_exit_allocations() {
+        let devices = Devices::new(Some(0), None);
+        let mut cache = DispatchCache::new();
+        let mut decode_cache = DecodeCache::new();
+        let pio = Arc::new(Mutex::new(Recorder::new(0x60, 4)));
+        let mmio = Arc::new(Mutex::new(Recorder::new(0xd000_0000, 0x1000)));
+        // The records of the accesses are pushed within their capacity.
+        pio.lock().accesses.reserve(64);
+        mmio.lock().accesses.reserve(64);
+        devices.add_port_io_device(pio.clone()).unwrap();
+        devices.add_memory_io_device(mmio.clone()).unwrap();
+        const MSR: u32 = 0x4000_0000;
+        let msrs = Arc::new(Mutex::new(Msrs::new(MSR..MSR + 1, false)));
+        devices.add_msr_device(msrs).unwrap();
+        // mov eax, [rbx]
+        let code = GuestCode::from_bytes(&[0x8b, 0x03], 64, 0x1000);
+        let instr = decode(code.bytes());
+        let mut vcpu = MockVcpu::default();
+        vcpu.events.reserve(1);
+
+        let mut exit = |vcpu: &mut MockVcpu, cache: &mut DispatchCache| {
+            for is_in in [true, false] {
+                let ctx = io_exit(0x60, 2, is_in);
+                let device = devices.find_port_io_device(cache, 0x60).unwrap();
+                Devices::handle_io_instruction_to_device(vcpu, &ctx, device, None).unwrap();
+            }
+
+            let mmio = match decode_cache.get(&code) {
+                Some(mmio) => mmio,
+                None => {
+                    let mmio = mmio_instr(&instr, false).unwrap();
+                    decode_cache.insert(&code, mmio);
+                    mmio
+                }
             };
-            return Ok((op_kind, Some(format!("{:?}", reg).to_lowercase())));
+            let addr = 0xd000_0010;
+            let device = devices.find_memory_io_device(cache, addr).unwrap();
+            let range = device.lock().mmio_range();
+            let inside = check_access(range, addr, mmio.access_size, MMIO_ACCESS_SIZES).unwrap();
+            split_read(inside, addr, mmio.access_size, |addr, access_size| {
+                let access = MmioAccess {
+                    addr,
+                    access_size,
+                    write: None,
+                };
+                mmio_device_access(&device, access)
+            })
+            .unwrap();
+
+            vcpu.regs.rcx = MSR as u64;
+            let device = devices.find_msr_device(cache, MSR).unwrap();
+            let ctx = msr_exit(VmxExitReason::MSR_WRITE);
+            Devices::handle_msr_write_to_device(vcpu, &ctx, MSR, device).unwrap();
+            let device = devices.find_msr_device(cache, MSR).unwrap();
+            let ctx = msr_exit(VmxExitReason::MSR_READ);
+            Devices::handle_msr_read_to_device(vcpu, &ctx, MSR, device).unwrap();
+        };
+        // Filling the caches.
+        exit(&mut vcpu, &mut cache);
+        let misses = cache.stats().misses;
+        for _ in 0..16 {
+            assert_eq!(allocations(|| exit(&mut vcpu, &mut cache)), 0);
         }
-        _ => {
-            return Err(HyperError::OperandNotSupported);
+        assert_eq!(cache.stats().misses, misses);
+        assert!(vcpu.events.is_empty());
+    }
+
+    /// The dword at `offset` of the configuration space of `devfn` on bus 0, read as the guest
+    /// does through CONFIG_ADDRESS and CONFIG_DATA.
+    #[cfg(feature = "virtio-pci")]
+    fn read_pci_config(devices: &Devices, cache: &mut DispatchCache, devfn: u8, offset: u8) -> u32 {
+        let config_addr = 0x8000_0000 | (devfn as u32) << 8 | offset as u32;
+        let host = devices.find_port_io_device(cache, 0xcf8).unwrap();
+        host.lock().write(0xcf8, 4, config_addr).unwrap();
+        let value = host.lock().read(0xcfc, 4).unwrap();
+        value
+    }
+
+    #[cfg(feature = "virtio-pci")]
+    #[test]
+    fn test_virtio_placement() {
+        use crate::device::virtio::VIRTIO_TYPE_RNG;
+
+        let configured = [
+            VirtioDeviceCfg::new("blk", VIRTIO_TYPE_BLOCK, 1, 256),
+            VirtioDeviceCfg::new("console", VIRTIO_TYPE_CONSOLE, 2, 64),
+            VirtioDeviceCfg::new("rng", VIRTIO_TYPE_RNG, 1, 64),
+        ];
+        let devices =
+            add_virtio_pci_devices(Devices::new(None, Some(0)), &configured, None).unwrap();
+        let mut cache = DispatchCache::new();
+
+        // Slot 0 is left to the host bridge, the devices get the next slots in order, each a
+        // single-function virtio 1.0 device of its type and class.
+        for (devfn, device_type, class) in [
+            (0x08, VIRTIO_TYPE_BLOCK, 0x0100),
+            (0x10, VIRTIO_TYPE_CONSOLE, 0x0780),
+            (0x18, VIRTIO_TYPE_RNG, 0x00ff),
+        ] {
+            let id = read_pci_config(&devices, &mut cache, devfn, 0x00);
+            assert_eq!(id, 0x1af4 | (0x1040 + device_type) << 16);
+            let class_rev = read_pci_config(&devices, &mut cache, devfn, 0x08);
+            assert_eq!(class_rev, class << 16 | 1);
+            let header = read_pci_config(&devices, &mut cache, devfn, 0x0c);
+            assert_eq!(header >> 16 & 0xff, 0);
+            let subsystem = read_pci_config(&devices, &mut cache, devfn, 0x2c);
+            assert_eq!(subsystem, 0x1af4 | (0x40 + device_type) << 16);
         }
-    };
-    Err(HyperError::OperandNotSupported)
+        // Nothing answers in the next slot.
+        let missing = read_pci_config(&devices, &mut cache, 0x20, 0x00);
+        assert_eq!(missing, 0xffff_ffff);
+
+        // An explicit devfn colliding with an allocated device is rejected.
+        let mut colliding = VirtioDeviceCfg::new("rng", VIRTIO_TYPE_RNG, 1, 64);
+        colliding.devfn = Some(0x08);
+        let configured = [configured[0].clone(), colliding];
+        assert!(add_virtio_pci_devices(Devices::new(None, Some(1)), &configured, None).is_err());
+    }
 }
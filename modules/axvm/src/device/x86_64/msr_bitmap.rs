@@ -0,0 +1,192 @@
+//! The VMX MSR bitmap of the vCPU running on the current CPU (SDM Vol. 3C, Section 25.6.9).
+//!
+//! hypercraft allocates the bitmap and points the VMCS at it, but has no interface to change it,
+//! so it is reached through the VMCS loaded on the current CPU. A set bit makes the access exit,
+//! a clear one lets the guest access the MSR natively; the MSRs outside of the two ranges the
+//! bitmap covers always exit.
+//!
+//! The MSRs passed through are registered with [`DeviceList::set_msr_passthrough`] and written
+//! to the bitmap before the next VM entry of the vCPU.
+//!
+//! [`DeviceList::set_msr_passthrough`]: super::DeviceList::set_msr_passthrough
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use bit_field::BitField;
+use x86::vmx::vmcs;
+
+use super::vmexit::vmcs_read;
+use crate::{phys_to_virt, PhysAddr};
+
+/// The MSRs the bitmap covers, and the offset of their read bits in the bitmap. The write bits
+/// are [`WRITE_BITS_OFFSET`] bytes further.
+const MSR_RANGES: [(Range<u32>, usize); 2] = [(0..0x2000, 0), (0xc000_0000..0xc000_2000, 0x400)];
+const WRITE_BITS_OFFSET: usize = 0x800;
+
+/// The "use MSR bitmaps" primary processor-based VM-execution control.
+const USE_MSR_BITMAPS: usize = 28;
+
+/// Which accesses to an MSR skip the exit, see [`DeviceList::set_msr_passthrough`].
+///
+/// [`DeviceList::set_msr_passthrough`]: super::DeviceList::set_msr_passthrough
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct MsrPassthrough {
+    pub read: bool,
+    pub write: bool,
+}
+
+/// Whether the MSR bitmap covers `msr`, i.e. it can be passed through.
+pub(super) fn covers(msr: u32) -> bool {
+    MsrBitmap::position(msr).is_some()
+}
+
+/// The MSR bitmap of the VMCS loaded on the current CPU.
+pub(super) struct MsrBitmap {
+    bitmap: *mut u8,
+}
+
+impl MsrBitmap {
+    /// The bitmap of the vCPU running on the current CPU, `None` if it does not use one and
+    /// every MSR access exits.
+    pub fn current() -> Option<Self> {
+        if !vmcs_read(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS).get_bit(USE_MSR_BITMAPS) {
+            return None;
+        }
+        let paddr = vmcs_read(vmcs::control::MSR_BITMAPS_ADDR_FULL) as usize;
+        Some(Self {
+            bitmap: phys_to_virt(PhysAddr::from(paddr)).as_mut_ptr(),
+        })
+    }
+
+    /// Byte offset and bit of the read bit of `msr`, `None` if the bitmap does not cover it.
+    fn position(msr: u32) -> Option<(usize, usize)> {
+        let (range, offset) = MSR_RANGES.iter().find(|(range, _)| range.contains(&msr))?;
+        let index = (msr - range.start) as usize;
+        Some((offset + index / 8, index % 8))
+    }
+
+    /// Whether reads and writes of `msr` exit.
+    pub fn intercepts(&self, msr: u32) -> (bool, bool) {
+        match Self::position(msr) {
+            // SAFETY: the bitmap is a 4 KiB page, the offsets are below it.
+            Some((offset, bit)) => unsafe {
+                (
+                    self.bitmap.add(offset).read().get_bit(bit),
+                    self.bitmap
+                        .add(offset + WRITE_BITS_OFFSET)
+                        .read()
+                        .get_bit(bit),
+                )
+            },
+            None => (true, true),
+        }
+    }
+
+    /// Make reads and writes of `msr` exit or not. Returns `false` if the bitmap does not cover
+    /// `msr`, its accesses then always exit.
+    pub fn set_intercepts(&mut self, msr: u32, read_exits: bool, write_exits: bool) -> bool {
+        let (offset, bit) = match Self::position(msr) {
+            Some(position) => position,
+            None => return false,
+        };
+        for (offset, exits) in [
+            (offset, read_exits),
+            (offset + WRITE_BITS_OFFSET, write_exits),
+        ] {
+            // SAFETY: as in `intercepts`.
+            let byte = unsafe { self.bitmap.add(offset) };
+            let mut value = unsafe { byte.read() };
+            value.set_bit(bit, exits);
+            unsafe { byte.write(value) };
+        }
+        true
+    }
+
+    /// The runs of MSRs whose reads, if `write` is false, or writes do not exit.
+    fn passthrough_ranges(&self, write: bool) -> Vec<Range<u32>> {
+        let mut ranges: Vec<Range<u32>> = Vec::new();
+        for msr in MSR_RANGES.iter().flat_map(|(range, _)| range.clone()) {
+            let (read_exits, write_exits) = self.intercepts(msr);
+            let exits = if write { write_exits } else { read_exits };
+            if exits {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.end == msr => last.end = msr + 1,
+                _ => ranges.push(msr..msr + 1),
+            }
+        }
+        ranges
+    }
+
+    /// Log the MSRs accessed without exits.
+    pub fn dump(&self, vcpu_id: usize) {
+        info!(
+            "MSR bitmap of vCPU {}, the MSRs accessed natively:",
+            vcpu_id
+        );
+        for (what, write) in [("read", false), ("write", true)] {
+            let ranges = self.passthrough_ranges(write);
+            if ranges.is_empty() {
+                info!("  {}: none", what);
+            }
+            for range in ranges {
+                info!("  {}: {:#x}..{:#x}", what, range.start, range.end);
+            }
+        }
+    }
+}
+
+/// The passthrough MSRs of a vCPU written to its bitmap, with the intercepts they had before, so
+/// that they are restored when the passthrough is removed.
+#[derive(Default)]
+pub(super) struct AppliedPassthrough {
+    generation: Option<u64>,
+    original: BTreeMap<u32, (bool, bool)>,
+}
+
+impl AppliedPassthrough {
+    /// Write `passthrough`, the MSRs passed through as of `generation` of the device tables, to
+    /// the bitmap of the vCPU running on the current CPU, if the tables changed since the last
+    /// time.
+    pub fn sync(&mut self, generation: u64, passthrough: &BTreeMap<u32, MsrPassthrough>) {
+        if self.generation == Some(generation) {
+            return;
+        }
+        self.generation = Some(generation);
+        let Some(mut bitmap) = MsrBitmap::current() else {
+            if !passthrough.is_empty() {
+                warn!("the vCPU has no MSR bitmap, the passthrough MSRs still exit");
+            }
+            return;
+        };
+        self.original.retain(|msr, &mut (read_exits, write_exits)| {
+            let kept = passthrough.contains_key(msr);
+            if !kept {
+                bitmap.set_intercepts(*msr, read_exits, write_exits);
+            }
+            kept
+        });
+        for (&msr, passthrough) in passthrough {
+            let (read_exits, write_exits) = *self
+                .original
+                .entry(msr)
+                .or_insert_with(|| bitmap.intercepts(msr));
+            bitmap.set_intercepts(
+                msr,
+                read_exits && !passthrough.read,
+                write_exits && !passthrough.write,
+            );
+        }
+    }
+}
+
+/// Log the MSR bitmap of the vCPU running on the current CPU, `vcpu_id`.
+pub(crate) fn dump_msr_bitmap(vcpu_id: usize) {
+    match MsrBitmap::current() {
+        Some(bitmap) => bitmap.dump(vcpu_id),
+        None => info!("vCPU {} has no MSR bitmap, every MSR access exits", vcpu_id),
+    }
+}
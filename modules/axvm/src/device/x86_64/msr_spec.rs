@@ -0,0 +1,357 @@
+//! Architectural constraints on the values written to MSRs by the guest.
+//!
+//! `handle_msr_write` consults [`check_msr_write`] before calling the emulated device, and
+//! injects #GP instead of forwarding a write which real hardware would reject, so the devices
+//! only ever store architecturally valid values.
+
+use core::ops::Range;
+
+use lazy_static::lazy_static;
+use x86::msr::{IA32_APIC_BASE, IA32_EFER, IA32_MISC_ENABLE, IA32_PAT};
+
+use super::device_emu::{
+    IA32_FIXED_CTR0, IA32_FIXED_CTR_CTRL, IA32_PERFEVTSEL0, IA32_PERF_CAPABILITIES,
+    IA32_PERF_GLOBAL_CTRL, IA32_PERF_GLOBAL_OVF_CTRL, IA32_PERF_GLOBAL_STATUS, PMU_COUNTER_WIDTH,
+    PMU_FIXED_COUNTERS, PMU_GLOBAL_CTRL_VALID, PMU_GP_COUNTERS,
+};
+
+const IA32_MTRRCAP: u32 = 0xfe;
+const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+/// 10 variable-range MTRR pairs, enough for the `VCNT` reported by current processors.
+const IA32_MTRR_PHYSMASK9: u32 = 0x213;
+const IA32_MTRR_FIX64K_00000: u32 = 0x250;
+const IA32_MTRR_FIX16K_80000: u32 = 0x258;
+const IA32_MTRR_FIX16K_A0000: u32 = 0x259;
+const IA32_MTRR_FIX4K_C0000: u32 = 0x268;
+const IA32_MTRR_FIX4K_F8000: u32 = 0x26f;
+const IA32_MTRR_DEF_TYPE: u32 = 0x2ff;
+
+const APIC_BASE_BSP: u64 = 1 << 8;
+const APIC_BASE_EXTD: u64 = 1 << 10;
+const APIC_BASE_EN: u64 = 1 << 11;
+
+/// SCE, LME, LMA, NXE.
+const EFER_VALID: u64 = 1 << 0 | 1 << 8 | 1 << 10 | 1 << 11;
+/// Fast strings, TCC, EIST, MONITOR/MWAIT, limit CPUID maxval, xTPR disable, XD disable; the
+/// read-only bits 7, 11 and 12 may be written back unchanged.
+const MISC_ENABLE_VALID: u64 =
+    1 << 0 | 1 << 3 | 1 << 7 | 1 << 11 | 1 << 12 | 1 << 16 | 1 << 18 | 1 << 22 | 1 << 23 | 1 << 34;
+/// Type, FE (fixed range enable), E (enable).
+const MTRR_DEF_TYPE_VALID: u64 = 0xff | 1 << 10 | 1 << 11;
+/// The event select of version 2 of the architectural PMU, without AnyThread.
+const PERFEVTSEL_VALID: u64 = 0xffff_ffff & !(1 << 21);
+/// The OS, USR and PMI bits of each fixed counter.
+const FIXED_CTR_CTRL_VALID: u64 = ((1 << (4 * PMU_FIXED_COUNTERS)) - 1) & 0xbbbb_bbbb;
+/// The overflow bits of the counters, CondChgd and OvfBuf.
+const PERF_GLOBAL_OVF_CTRL_VALID: u64 = PMU_GLOBAL_CTRL_VALID | 1 << 62 | 1 << 63;
+
+struct MsrWriteSpec {
+    msrs: Range<u32>,
+    /// Bits which must be written as zero.
+    reserved: u64,
+    /// Whether the value holds a physical address, so that the bits above MAXPHYADDR are
+    /// reserved as well.
+    phys_addr: bool,
+    /// Further constraints.
+    check: Option<Check>,
+}
+
+/// A constraint on a written value beyond its reserved bits.
+#[derive(Clone, Copy)]
+enum Check {
+    /// On the MSR and the new value.
+    Value(fn(u32, u64) -> bool),
+    /// On the transition from the current value to the new one, the only check for which the
+    /// current value is read.
+    Transition(fn(u64, u64) -> bool),
+}
+
+static MSR_WRITE_SPECS: &[MsrWriteSpec] = &[
+    MsrWriteSpec {
+        msrs: IA32_APIC_BASE..IA32_APIC_BASE + 1,
+        // The base is page-aligned, bits 0..8 and 9 are reserved.
+        reserved: 0xfff & !(APIC_BASE_BSP | APIC_BASE_EXTD | APIC_BASE_EN),
+        phys_addr: true,
+        check: Some(Check::Transition(apic_base_transition)),
+    },
+    MsrWriteSpec {
+        msrs: IA32_EFER..IA32_EFER + 1,
+        reserved: !EFER_VALID,
+        phys_addr: false,
+        check: None,
+    },
+    MsrWriteSpec {
+        msrs: IA32_PAT..IA32_PAT + 1,
+        reserved: 0xf8f8_f8f8_f8f8_f8f8,
+        phys_addr: false,
+        check: Some(Check::Value(pat_types)),
+    },
+    MsrWriteSpec {
+        msrs: IA32_MISC_ENABLE..IA32_MISC_ENABLE + 1,
+        reserved: !MISC_ENABLE_VALID,
+        phys_addr: false,
+        check: None,
+    },
+    MsrWriteSpec {
+        msrs: IA32_MTRRCAP..IA32_MTRRCAP + 1,
+        // Read-only.
+        reserved: 0,
+        phys_addr: false,
+        check: Some(Check::Value(|_, _| false)),
+    },
+    MsrWriteSpec {
+        msrs: IA32_MTRR_DEF_TYPE..IA32_MTRR_DEF_TYPE + 1,
+        reserved: !MTRR_DEF_TYPE_VALID,
+        phys_addr: false,
+        check: Some(Check::Value(|_, new| is_mtrr_type(new as u8))),
+    },
+    MsrWriteSpec {
+        msrs: IA32_MTRR_PHYSBASE0..IA32_MTRR_PHYSMASK9 + 1,
+        reserved: 0,
+        phys_addr: true,
+        check: Some(Check::Value(mtrr_var)),
+    },
+    MsrWriteSpec {
+        msrs: IA32_MTRR_FIX64K_00000..IA32_MTRR_FIX64K_00000 + 1,
+        reserved: 0,
+        phys_addr: false,
+        check: Some(Check::Value(mtrr_fixed_types)),
+    },
+    MsrWriteSpec {
+        msrs: IA32_MTRR_FIX16K_80000..IA32_MTRR_FIX16K_A0000 + 1,
+        reserved: 0,
+        phys_addr: false,
+        check: Some(Check::Value(mtrr_fixed_types)),
+    },
+    MsrWriteSpec {
+        msrs: IA32_MTRR_FIX4K_C0000..IA32_MTRR_FIX4K_F8000 + 1,
+        reserved: 0,
+        phys_addr: false,
+        check: Some(Check::Value(mtrr_fixed_types)),
+    },
+    // The MSRs of the emulated PMU, see `VirtPmu`. The writes to IA32_PMCx only take the low
+    // 32 bits.
+    MsrWriteSpec {
+        msrs: IA32_PERFEVTSEL0..IA32_PERFEVTSEL0 + PMU_GP_COUNTERS as u32,
+        reserved: !PERFEVTSEL_VALID,
+        phys_addr: false,
+        check: None,
+    },
+    MsrWriteSpec {
+        msrs: IA32_FIXED_CTR0..IA32_FIXED_CTR0 + PMU_FIXED_COUNTERS as u32,
+        reserved: !((1 << PMU_COUNTER_WIDTH) - 1),
+        phys_addr: false,
+        check: None,
+    },
+    MsrWriteSpec {
+        msrs: IA32_PERF_CAPABILITIES..IA32_PERF_CAPABILITIES + 1,
+        // Read-only.
+        reserved: 0,
+        phys_addr: false,
+        check: Some(Check::Value(|_, _| false)),
+    },
+    MsrWriteSpec {
+        msrs: IA32_FIXED_CTR_CTRL..IA32_FIXED_CTR_CTRL + 1,
+        reserved: !FIXED_CTR_CTRL_VALID,
+        phys_addr: false,
+        check: None,
+    },
+    MsrWriteSpec {
+        msrs: IA32_PERF_GLOBAL_STATUS..IA32_PERF_GLOBAL_STATUS + 1,
+        // Read-only.
+        reserved: 0,
+        phys_addr: false,
+        check: Some(Check::Value(|_, _| false)),
+    },
+    MsrWriteSpec {
+        msrs: IA32_PERF_GLOBAL_CTRL..IA32_PERF_GLOBAL_CTRL + 1,
+        reserved: !PMU_GLOBAL_CTRL_VALID,
+        phys_addr: false,
+        check: None,
+    },
+    MsrWriteSpec {
+        msrs: IA32_PERF_GLOBAL_OVF_CTRL..IA32_PERF_GLOBAL_OVF_CTRL + 1,
+        reserved: !PERF_GLOBAL_OVF_CTRL_VALID,
+        phys_addr: false,
+        check: None,
+    },
+];
+
+lazy_static! {
+    /// Mask of the bits above MAXPHYADDR.
+    static ref PHYS_ADDR_RESERVED: u64 = {
+        let bits = raw_cpuid::CpuId::new()
+            .get_processor_capacity_feature_info()
+            .map_or(36, |info| info.physical_address_bits());
+        !((1u64 << bits) - 1)
+    };
+}
+
+/// Memory types which may be programmed into the PAT and the MTRRs.
+fn is_pat_type(ty: u8) -> bool {
+    matches!(ty, 0 | 1 | 4 | 5 | 6 | 7)
+}
+
+fn is_mtrr_type(ty: u8) -> bool {
+    matches!(ty, 0 | 1 | 4 | 5 | 6)
+}
+
+fn pat_types(_msr: u32, new: u64) -> bool {
+    new.to_le_bytes().iter().all(|ty| is_pat_type(*ty))
+}
+
+fn mtrr_fixed_types(_msr: u32, new: u64) -> bool {
+    new.to_le_bytes().iter().all(|ty| is_mtrr_type(*ty))
+}
+
+fn mtrr_var(msr: u32, new: u64) -> bool {
+    if (msr - IA32_MTRR_PHYSBASE0) % 2 == 0 {
+        // PHYSBASEn: type in bits 0..8, bits 8..12 reserved.
+        new & 0xf00 == 0 && is_mtrr_type(new as u8)
+    } else {
+        // PHYSMASKn: valid in bit 11, bits 0..11 reserved.
+        new & 0x7ff == 0
+    }
+}
+
+/// SDM Vol. 3A, Section 11.12.5, x2APIC state transitions.
+fn apic_base_transition(old: u64, new: u64) -> bool {
+    let mode = |v: u64| v & (APIC_BASE_EN | APIC_BASE_EXTD);
+    match (mode(old), mode(new)) {
+        // EXTD without EN is an invalid state.
+        (_, APIC_BASE_EXTD) => false,
+        // x2APIC can only be left by disabling the APIC.
+        (m, APIC_BASE_EN) if m == APIC_BASE_EN | APIC_BASE_EXTD => false,
+        // x2APIC can only be entered from xAPIC.
+        (0, m) if m == APIC_BASE_EN | APIC_BASE_EXTD => false,
+        _ => true,
+    }
+}
+
+/// Check a guest WRMSR against the architectural constraints of the MSR.
+///
+/// `old` reads the current value of the MSR, `None` if the device cannot provide it. It is only
+/// called for the MSRs whose transitions are constrained: reading an MSR may have side effects,
+/// or fault for a write-only one. Returns `false` if the write must fail with #GP. MSRs without a
+/// spec are not constrained here.
+pub(crate) fn check_msr_write(msr: u32, new: u64, old: impl FnOnce() -> Option<u64>) -> bool {
+    let spec = match MSR_WRITE_SPECS.iter().find(|spec| spec.msrs.contains(&msr)) {
+        Some(spec) => spec,
+        None => return true,
+    };
+    let mut reserved = spec.reserved;
+    if spec.phys_addr {
+        reserved |= *PHYS_ADDR_RESERVED;
+    }
+    if new & reserved != 0 {
+        return false;
+    }
+    match spec.check {
+        Some(Check::Value(check)) => check(msr, new),
+        Some(Check::Transition(check)) => check(old().unwrap_or(new), new),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `check_msr_write` for a value check, the current value not being expected to be read.
+    fn check(msr: u32, new: u64) -> bool {
+        check_msr_write(msr, new, || panic!("MSR {:#x} read before its write", msr))
+    }
+
+    #[test]
+    fn test_unconstrained_msr() {
+        // IA32_TSC_AUX, which has no spec.
+        assert!(check(0xc000_0103, u64::MAX));
+    }
+
+    #[test]
+    fn test_efer_reserved_bits() {
+        assert!(check(IA32_EFER, EFER_VALID));
+        assert!(check(IA32_EFER, 0));
+        assert!(!check(IA32_EFER, 1 << 1));
+        assert!(!check(IA32_EFER, 1 << 63));
+    }
+
+    #[test]
+    fn test_pat_memory_types() {
+        // The power-on value: WB, WT, UC-, UC, twice.
+        assert!(check(IA32_PAT, 0x0007_0406_0007_0406));
+        // Type 2 and 3 are reserved.
+        assert!(!check(IA32_PAT, 0x0007_0406_0007_0402));
+        assert!(!check(IA32_PAT, 0x0307_0406_0007_0406));
+        assert!(!check(IA32_PAT, 0x0007_0406_0007_0408));
+    }
+
+    #[test]
+    fn test_mtrrs() {
+        assert!(!check(IA32_MTRRCAP, 0));
+        assert!(check(IA32_MTRR_DEF_TYPE, 1 << 11 | 1 << 10 | 6));
+        assert!(!check(IA32_MTRR_DEF_TYPE, 1 << 11 | 7));
+        assert!(!check(IA32_MTRR_DEF_TYPE, 1 << 12));
+        // PHYSBASE0: a WB range at 1G, PHYSMASK0: valid.
+        assert!(check(IA32_MTRR_PHYSBASE0, 0x4000_0006));
+        assert!(!check(IA32_MTRR_PHYSBASE0, 0x4000_0106));
+        assert!(!check(IA32_MTRR_PHYSBASE0, 0x4000_0002));
+        assert!(check(IA32_MTRR_PHYSBASE0 + 1, 0xc000_0800));
+        assert!(!check(IA32_MTRR_PHYSBASE0 + 1, 0xc000_0801));
+        // Above MAXPHYADDR.
+        assert!(!check(IA32_MTRR_PHYSBASE0, 1 << 63 | 6));
+        assert!(check(IA32_MTRR_FIX64K_00000, 0x0606_0606_0606_0606));
+        assert!(!check(IA32_MTRR_FIX4K_F8000, 0x0606_0606_0606_0602));
+    }
+
+    #[test]
+    fn test_pmu_msrs() {
+        assert!(check(IA32_PERFEVTSEL0, 1 << 22 | 1 << 16 | 0x3c));
+        // AnyThread, and the upper half.
+        assert!(!check(IA32_PERFEVTSEL0 + 3, 1 << 21));
+        assert!(!check(IA32_PERFEVTSEL0, 1 << 32));
+        assert!(check(IA32_FIXED_CTR0, (1 << PMU_COUNTER_WIDTH) - 1));
+        assert!(!check(IA32_FIXED_CTR0, 1 << PMU_COUNTER_WIDTH));
+        assert!(check(IA32_FIXED_CTR_CTRL, FIXED_CTR_CTRL_VALID));
+        // AnyThread of fixed counter 0.
+        assert!(!check(IA32_FIXED_CTR_CTRL, 1 << 2));
+        assert!(check(IA32_PERF_GLOBAL_CTRL, PMU_GLOBAL_CTRL_VALID));
+        assert!(!check(IA32_PERF_GLOBAL_CTRL, !PMU_GLOBAL_CTRL_VALID));
+        assert!(!check(IA32_PERF_GLOBAL_STATUS, 0));
+        assert!(!check(IA32_PERF_CAPABILITIES, 0));
+        assert!(check(IA32_PERF_GLOBAL_OVF_CTRL, PERF_GLOBAL_OVF_CTRL_VALID));
+    }
+
+    #[test]
+    fn test_apic_base_transitions() {
+        const BASE: u64 = 0xfee0_0000 | APIC_BASE_BSP;
+        const XAPIC: u64 = BASE | APIC_BASE_EN;
+        const X2APIC: u64 = XAPIC | APIC_BASE_EXTD;
+        let transition = |old: u64, new: u64| check_msr_write(IA32_APIC_BASE, new, || Some(old));
+        assert!(transition(XAPIC, X2APIC));
+        assert!(transition(X2APIC, BASE));
+        assert!(transition(BASE, XAPIC));
+        assert!(transition(X2APIC, X2APIC));
+        // x2APIC to xAPIC, disabled to x2APIC, EXTD alone.
+        assert!(!transition(X2APIC, XAPIC));
+        assert!(!transition(BASE, X2APIC));
+        assert!(!transition(XAPIC, BASE | APIC_BASE_EXTD));
+        // Reserved bit 9, checked before the current value is read.
+        assert!(!check(IA32_APIC_BASE, XAPIC | 1 << 9));
+        // Without the current value, the new one is only checked on its own.
+        assert!(check_msr_write(IA32_APIC_BASE, X2APIC, || None));
+    }
+
+    #[test]
+    fn test_old_value_read_only_for_transitions() {
+        let mut reads = 0;
+        assert!(check_msr_write(IA32_APIC_BASE, 0xfee0_0800, || {
+            reads += 1;
+            None
+        }));
+        assert_eq!(reads, 1);
+        // The x2APIC EOI and SELF IPI, write-only.
+        assert!(check(0x80b, 0));
+        assert!(check(0x83f, 0x30));
+    }
+}
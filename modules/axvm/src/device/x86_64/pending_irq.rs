@@ -0,0 +1,129 @@
+//! The interrupts asserted to a vCPU and not injected yet.
+//!
+//! The emulated devices assert their vectors in the [`PendingInterrupts`] of their vCPU rather
+//! than queueing them on it. Before each VM entry, `check_events` injects the highest one if the
+//! guest can take an interrupt, and otherwise requests an interrupt-window exit, which happens as
+//! soon as it can: the vectors are neither dropped nor injected into a guest with interrupts
+//! disabled. One vector is injected per VM entry, the others wait for the next window.
+//!
+//! An NMI sent to the vCPU, see [`crate::send_nmi`], takes the VM entry before the interrupts, once
+//! the guest does not block NMIs. With virtual NMIs, NMI-window exiting has the guest exit as soon
+//! as it unblocks them; without, the NMI waits for a later VM entry.
+//!
+//! Exceptions are still queued on the vCPU directly, they do not depend on RFLAGS.IF. With a
+//! virtual local APIC, its IRR holds the vectors which go through it and its processor priority
+//! applies, see [`VirtLocalApic`](super::device_emu::VirtLocalApic). The TPR of the local APIC of
+//! the CPU is not consulted, the vectors injected bypass it.
+
+use bit_field::BitField;
+use x86::bits64::vmx::{vmread, vmwrite};
+use x86::vmx::vmcs;
+use x86::vmx::vmcs::control::{PinbasedControls, PrimaryControls};
+
+use super::vmexit::vmcs_read;
+
+/// RFLAGS.IF.
+const RFLAGS_IF: usize = 9;
+/// Blocking by STI and by MOV SS in the interruptibility state of the guest.
+const BLOCKING_BY_STI_OR_MOV_SS: u64 = 0b11;
+/// Blocking by NMI in the interruptibility state of the guest.
+const BLOCKING_BY_NMI: u64 = 1 << 3;
+/// The valid bit of the IDT-vectoring information.
+const IDT_VECTORING_VALID: usize = 31;
+
+/// The vectors asserted to a vCPU, as an IRR.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct PendingInterrupts {
+    irr: [u64; 4],
+}
+
+impl PendingInterrupts {
+    pub const fn new() -> Self {
+        Self { irr: [0; 4] }
+    }
+
+    /// Assert `vector`. A vector asserted again before it is injected is injected once.
+    pub fn assert(&mut self, vector: u8) {
+        self.irr[vector as usize / 64].set_bit(vector as usize % 64, true);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.irr.iter().all(|&bits| bits == 0)
+    }
+
+    /// The highest vector asserted, which has the highest priority.
+    pub fn highest(&self) -> Option<u8> {
+        let (index, &bits) = self
+            .irr
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|&(_, &bits)| bits != 0)?;
+        Some((index as u32 * 64 + 63 - bits.leading_zeros()) as u8)
+    }
+
+    /// Clear `vector`, which is being injected.
+    pub fn take(&mut self, vector: u8) {
+        self.irr[vector as usize / 64].set_bit(vector as usize % 64, false);
+    }
+}
+
+/// Whether the vCPU whose VMCS is loaded on the current CPU can take an external interrupt at its
+/// next VM entry: RFLAGS.IF is set, it is not in the shadow of STI or MOV SS, and the exit did not
+/// interrupt the delivery of another event, which is delivered first.
+pub(super) fn guest_interruptible() -> bool {
+    vmcs_read(vmcs::guest::RFLAGS).get_bit(RFLAGS_IF)
+        && vmcs_read(vmcs::guest::INTERRUPTIBILITY_STATE) & BLOCKING_BY_STI_OR_MOV_SS == 0
+        && !vmcs_read(vmcs::ro::IDT_VECTORING_INFO).get_bit(IDT_VECTORING_VALID)
+}
+
+/// Whether the vCPU whose VMCS is loaded on the current CPU can take an NMI at its next VM entry:
+/// it does not block NMIs, it is not in the shadow of STI or MOV SS, and the exit did not
+/// interrupt the delivery of another event.
+pub(super) fn guest_nmi_unblocked() -> bool {
+    vmcs_read(vmcs::guest::INTERRUPTIBILITY_STATE) & (BLOCKING_BY_STI_OR_MOV_SS | BLOCKING_BY_NMI)
+        == 0
+        && !vmcs_read(vmcs::ro::IDT_VECTORING_INFO).get_bit(IDT_VECTORING_VALID)
+}
+
+/// Whether the VMCS loaded on the current CPU has virtual NMIs, which NMI-window exiting needs.
+pub(super) fn has_virtual_nmis() -> bool {
+    let virtual_nmis = PinbasedControls::VIRTUAL_NMIS.bits() as u64;
+    vmcs_read(vmcs::control::PINBASED_EXEC_CONTROLS) & virtual_nmis != 0
+}
+
+/// Set or clear the interrupt-window exiting control of the VMCS loaded on the current CPU: the
+/// guest exits as soon as it can take an interrupt.
+pub(super) fn set_interrupt_window_exiting(enabled: bool) {
+    set_window_exiting(
+        PrimaryControls::INTERRUPT_WINDOW_EXITING,
+        enabled,
+        "interrupt",
+    );
+}
+
+/// Set or clear the NMI-window exiting control of the VMCS loaded on the current CPU, which has
+/// virtual NMIs: the guest exits as soon as it unblocks NMIs.
+pub(super) fn set_nmi_window_exiting(enabled: bool) {
+    set_window_exiting(PrimaryControls::NMI_WINDOW_EXITING, enabled, "NMI");
+}
+
+fn set_window_exiting(control: PrimaryControls, enabled: bool, name: &str) {
+    let window = control.bits() as u64;
+    let result = unsafe {
+        vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS).and_then(|controls| {
+            let updated = if enabled {
+                controls | window
+            } else {
+                controls & !window
+            };
+            if updated == controls {
+                return Ok(());
+            }
+            vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, updated)
+        })
+    };
+    if let Err(err) = result {
+        warn!("failed to set {}-window exiting: {:?}", name, err);
+    }
+}
@@ -0,0 +1,164 @@
+//! The paravirtual clock of a vCPU, the `pvclock_vcpu_time_info` of KVM.
+//!
+//! A guest registers 32 bytes of its RAM, 32-byte aligned, for the calling vCPU with
+//! `HVC_PVCLOCK_SETUP`, GPA 0 unregistering them. The vCPU writes there, before its next VM
+//! entry, its guest TSC and its system time in nanoseconds at a same instant, and the scale from
+//! TSC ticks to nanoseconds:
+//!
+//! | offset | size | field                                                  |
+//! |--------|------|--------------------------------------------------------|
+//! | 0      | 4    | version, odd while the vCPU writes                     |
+//! | 8      | 8    | `tsc_timestamp`, the guest TSC                         |
+//! | 16     | 8    | `system_time`, ns                                      |
+//! | 24     | 4    | `tsc_to_system_mul`                                    |
+//! | 28     | 1    | `tsc_shift`, signed                                    |
+//! | 29     | 1    | flags, 0                                               |
+//!
+//! The guest reads the time as `system_time + (((tsc - tsc_timestamp) << tsc_shift) *
+//! tsc_to_system_mul >> 32)`, a negative shift shifting right, and retries if the version is odd
+//! or changed meanwhile. The system time stops while the VM is paused, as its TSC does. The
+//! page is rewritten every second against drift, and whenever the TSC offset changes.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::config::entry::{vm_cfg_entry, VMCfgEntry};
+use crate::{Error as HyperError, GuestPhysAddr, Result as HyperResult};
+
+const PVCLOCK_SIZE: usize = 32;
+const UPDATE_PERIOD_NS: u64 = 1_000_000_000;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The registrations not taken by their vCPU yet, `None` unregistering its clock.
+static REGISTRATIONS: Mutex<BTreeMap<(u32, u32), Option<PvClock>>> = Mutex::new(BTreeMap::new());
+/// Number of `REGISTRATIONS`, lets `check_events` skip the lookup when there is none.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// The clock of a vCPU.
+pub(super) struct PvClock {
+    /// Hypervisor address of the clock.
+    clock: *mut u8,
+    version: u32,
+    mul: u32,
+    shift: i8,
+    /// When the clock is next rewritten, in host ns.
+    next_update_ns: u64,
+    /// The TSC offset the clock was written with.
+    tsc_offset: u64,
+    /// Owns the RAM of a guest VM, the host RAM is never released.
+    _cfg: Option<Arc<VMCfgEntry>>,
+}
+
+// The page stays mapped as long as the clock is registered, see `_cfg`.
+unsafe impl Send for PvClock {}
+
+/// Register the clock at `gpa` for vCPU `vcpu_id` of `vm_id`, or unregister its clock if `gpa`
+/// is 0. Fails with `InvalidParam` if `gpa` is not 32-byte aligned guest RAM.
+pub(crate) fn register(vm_id: u32, vcpu_id: u32, gpa: GuestPhysAddr) -> HyperResult {
+    let clock = if gpa == 0 {
+        None
+    } else {
+        if gpa % PVCLOCK_SIZE != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let offset = gpa % memory_addr::PAGE_SIZE_4K;
+        let page = crate::hvc::guest_ram_page_hva(vm_id, gpa - offset)?;
+        let (mul, shift) = time_scale(axhal::time::nanos_to_ticks(NANOS_PER_SEC));
+        Some(PvClock {
+            clock: unsafe { page.add(offset) },
+            version: 0,
+            mul,
+            shift,
+            next_update_ns: 0,
+            tsc_offset: 0,
+            _cfg: vm_cfg_entry(vm_id as usize).filter(|_| vm_id != crate::vm::HOST_VM_ID),
+        })
+    };
+    debug!("VM [{}] vCPU [{}] pvclock at {:#x}", vm_id, vcpu_id, gpa);
+    if REGISTRATIONS
+        .lock()
+        .insert((vm_id, vcpu_id), clock)
+        .is_none()
+    {
+        PENDING.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// The registration of vCPU `vcpu_id` of `vm_id` since the last call, `Some(None)` if it
+/// unregistered its clock.
+pub(super) fn take_registration(vm_id: u32, vcpu_id: u32) -> Option<Option<PvClock>> {
+    if PENDING.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    let clock = REGISTRATIONS.lock().remove(&(vm_id, vcpu_id))?;
+    PENDING.fetch_sub(1, Ordering::Relaxed);
+    Some(clock)
+}
+
+/// Drop the registrations of `vm_id` its vCPUs did not take.
+pub(crate) fn unregister_vm_pvclocks(vm_id: u32) {
+    let mut registrations = REGISTRATIONS.lock();
+    let before = registrations.len();
+    registrations.retain(|&(vm, _), _| vm != vm_id);
+    PENDING.fetch_sub(before - registrations.len(), Ordering::Relaxed);
+}
+
+impl PvClock {
+    /// Whether the clock is to be rewritten at `now_ns`, in host ns, the TSC offset of the vCPU
+    /// being `tsc_offset`.
+    pub fn due(&self, now_ns: u64, tsc_offset: u64) -> bool {
+        now_ns >= self.next_update_ns || tsc_offset != self.tsc_offset
+    }
+
+    /// Rewrite the clock, the guest TSC being the TSC of the machine plus `tsc_offset` and its
+    /// system time the host time less `frozen_ns`.
+    pub fn update(&mut self, tsc_offset: u64, frozen_ns: u64) {
+        let now_ns = axhal::time::current_time_nanos();
+        let tsc = axhal::time::current_ticks().wrapping_add(tsc_offset);
+        self.next_update_ns = now_ns + UPDATE_PERIOD_NS;
+        self.tsc_offset = tsc_offset;
+        let clock = self.clock;
+        let write = |offset: usize, bytes: &[u8]| {
+            for (i, &byte) in bytes.iter().enumerate() {
+                unsafe { clock.add(offset + i).write_volatile(byte) };
+            }
+        };
+        // Seqlock: the guest discards what it read while the version is odd or changed.
+        self.version = self.version.wrapping_add(1) | 1;
+        write(0, &self.version.to_le_bytes());
+        fence(Ordering::SeqCst);
+        write(8, &tsc.to_le_bytes());
+        write(16, &(now_ns - frozen_ns).to_le_bytes());
+        write(24, &self.mul.to_le_bytes());
+        write(28, &[self.shift as u8, 0]);
+        fence(Ordering::SeqCst);
+        self.version = self.version.wrapping_add(1);
+        write(0, &self.version.to_le_bytes());
+    }
+}
+
+/// The multiplier and the shift converting ticks of a `tsc_hz` TSC to nanoseconds, as
+/// `kvm_get_time_scale` computes them.
+fn time_scale(tsc_hz: u64) -> (u32, i8) {
+    let mut shift = 0i8;
+    let mut scaled = NANOS_PER_SEC;
+    let mut tps64 = tsc_hz.max(1);
+    while tps64 > scaled * 2 || tps64 >> 32 != 0 {
+        tps64 >>= 1;
+        shift -= 1;
+    }
+    let mut tps32 = tps64 as u32;
+    while tps32 as u64 <= scaled || scaled >> 32 != 0 {
+        if scaled >> 32 != 0 || tps32 & 0x8000_0000 != 0 {
+            scaled >>= 1;
+        } else {
+            tps32 <<= 1;
+        }
+        shift += 1;
+    }
+    (((scaled << 32) / tps32 as u64) as u32, shift)
+}
@@ -0,0 +1,940 @@
+//! Emulation of the string port I/O instructions, INS and OUTS, with or without REP.
+//!
+//! The I/O exit only tells the port, the element size and the direction. The elements are moved
+//! one at a time between the port and the memory operand, `seg:RSI` for OUTS and `ES:RDI` for
+//! INS, through the guest page tables. After each element the index register moves by the
+//! element size, backwards if RFLAGS.DF is set, and RCX counts down for REP, all of them wrapping
+//! at the address size of the instruction, as the CPU would update them.
+//!
+//! The registers are written back after every element, so the instruction can stop anywhere:
+//!
+//! - on a fault of the memory operand, the elements before it are done, #PF is injected and RIP
+//!   is left at the instruction, which resumes at the faulting element once the guest handled the
+//!   fault;
+//! - after [`MAX_ELEMENTS_PER_EXIT`] elements, likewise without a fault, so that a long REP does
+//!   not hold the vCPU away from its interrupts. The instruction exits again and continues.
+//!
+//! Either way the next exit repeats this one, which the exit watchdog is told not to count when
+//! the instruction made progress, see [`mark_intentional_repeat`].
+//!
+//! A `rep outs` to a device with a batch handle, see [`PioBatchOps`], is gathered and written in
+//! one call, which spares a console the device lock and the backend call per byte.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use hypercraft::PioOps;
+use memory_addr::PAGE_SIZE_4K;
+use x86::vmx::vmcs;
+
+use super::exit_vcpu::ExitVcpu;
+use super::vmexit::{mark_intentional_repeat, vmcs_read, ExitContext, IoExitInfo};
+use crate::config::entry::{vm_cfg_entry, VMCfgEntry};
+use crate::device::PioBatchOps;
+use crate::{phys_to_virt, Error as HyperError, PhysAddr, Result as HyperResult};
+
+/// Elements moved before the vCPU goes back to the guest, see the module documentation.
+const MAX_ELEMENTS_PER_EXIT: u64 = 4096;
+
+/// Exception vector of #PF.
+const PF_VECTOR: u8 = 14;
+
+/// A memory operand which cannot be accessed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum GuestAccessFault {
+    /// The guest page tables do not allow the access, #PF with `error_code` at `addr`.
+    Page { addr: u64, error_code: u32 },
+    /// The page is mapped to guest physical memory which is not RAM, e.g. MMIO.
+    NotRam { addr: u64, gpa: u64 },
+}
+
+/// The guest linear address space of the vCPU which exited.
+pub(crate) trait GuestLinearMemory {
+    /// Host pointer to the byte at `addr`, valid up to the end of its 4K page, for a read or,
+    /// if `write` is set, a write by the guest.
+    fn translate(&mut self, addr: u64, write: bool) -> Result<*mut u8, GuestAccessFault>;
+
+    /// Make the guest take #PF at `addr` with `error_code`.
+    fn inject_page_fault<V: ExitVcpu>(&mut self, vcpu: &mut V, addr: u64, error_code: u32);
+}
+
+/// The operands of a string I/O instruction which are not in the exit qualification.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StringIoOperands {
+    /// Base of the segment of the memory operand.
+    pub segment_base: u64,
+    /// Mask of the address size, which also applies to RCX.
+    pub address_mask: u64,
+    /// RFLAGS.DF: the index register decreases.
+    pub backward: bool,
+}
+
+impl StringIoOperands {
+    /// The operands of the string I/O instruction which exited on the current CPU.
+    pub fn current(is_in: bool) -> Self {
+        // SDM Vol. 3C, Table 28-8: VM-exit instruction information for INS and OUTS.
+        let info = vmcs_read(vmcs::ro::VMEXIT_INSTRUCTION_INFO);
+        let address_mask = match info.get_bits(7..10) {
+            0 => 0xffff,
+            1 => 0xffff_ffff,
+            _ => u64::MAX,
+        };
+        // INS always writes to ES, OUTS reads from DS unless overridden.
+        let segment = if is_in { 0 } else { info.get_bits(15..18) };
+        let long_mode = vmcs_read(vmcs::guest::CS_ACCESS_RIGHTS).get_bit(13)
+            && vmcs_read(vmcs::guest::IA32_EFER_FULL).get_bit(10);
+        let segment_base = match segment {
+            4 => vmcs_read(vmcs::guest::FS_BASE),
+            5 => vmcs_read(vmcs::guest::GS_BASE),
+            // The other segments are flat in 64-bit mode.
+            _ if long_mode => 0,
+            0 => vmcs_read(vmcs::guest::ES_BASE),
+            1 => vmcs_read(vmcs::guest::CS_BASE),
+            2 => vmcs_read(vmcs::guest::SS_BASE),
+            _ => vmcs_read(vmcs::guest::DS_BASE),
+        };
+        Self {
+            segment_base,
+            address_mask,
+            backward: vmcs_read(vmcs::guest::RFLAGS).get_bit(10),
+        }
+    }
+}
+
+/// Update `reg` to `value` at the address size of `mask`: the CPU zero-extends a 32-bit result
+/// and keeps the upper bits of a 16-bit one.
+fn set_masked(reg: &mut u64, value: u64, mask: u64) {
+    *reg = match mask {
+        0xffff => (*reg & !0xffff) | (value & 0xffff),
+        mask => value & mask,
+    };
+}
+
+/// Elements left to move: RCX for REP, at the address size of the instruction.
+fn element_count<V: ExitVcpu>(vcpu: &V, io_info: &IoExitInfo, operands: &StringIoOperands) -> u64 {
+    if io_info.is_repeat {
+        vcpu.regs().rcx & operands.address_mask
+    } else {
+        1
+    }
+}
+
+/// Linear address of the memory operand of the element `ahead` elements after the next one.
+fn element_addr<V: ExitVcpu>(
+    vcpu: &V,
+    io_info: &IoExitInfo,
+    operands: &StringIoOperands,
+    ahead: u64,
+) -> u64 {
+    let index = if io_info.is_in {
+        vcpu.regs().rdi
+    } else {
+        vcpu.regs().rsi
+    };
+    let offset = io_info.access_size as u64 * ahead;
+    let index = if operands.backward {
+        index.wrapping_sub(offset)
+    } else {
+        index.wrapping_add(offset)
+    };
+    operands
+        .segment_base
+        .wrapping_add(index & operands.address_mask)
+}
+
+/// Move the index register past `count` elements and count them down from RCX for REP.
+fn advance<V: ExitVcpu>(
+    vcpu: &mut V,
+    io_info: &IoExitInfo,
+    operands: &StringIoOperands,
+    count: u64,
+) {
+    let mask = operands.address_mask;
+    let size = io_info.access_size as u64 * count;
+    let step = if operands.backward {
+        size.wrapping_neg()
+    } else {
+        size
+    };
+    let regs = vcpu.regs_mut();
+    let reg = if io_info.is_in {
+        &mut regs.rdi
+    } else {
+        &mut regs.rsi
+    };
+    let index = *reg;
+    set_masked(reg, index.wrapping_add(step), mask);
+    if io_info.is_repeat {
+        let rcx = regs.rcx;
+        set_masked(&mut regs.rcx, rcx.wrapping_sub(count), mask);
+    }
+}
+
+/// Host pointers to the element at `addr`, `None` once a fault stopped the instruction.
+fn translate_or_stop<V: ExitVcpu, M: GuestLinearMemory>(
+    vcpu: &mut V,
+    ctx: &ExitContext,
+    io_info: &IoExitInfo,
+    mem: &mut M,
+    addr: u64,
+    first_len: usize,
+) -> HyperResult<Option<[*mut u8; 2]>> {
+    let size = io_info.access_size as usize;
+    match translate_element(mem, addr, size, first_len, io_info.is_in) {
+        Ok(parts) => Ok(Some(parts)),
+        Err(GuestAccessFault::Page { addr, error_code }) => {
+            mem.inject_page_fault(vcpu, addr, error_code);
+            Ok(None)
+        }
+        Err(GuestAccessFault::NotRam { addr, gpa }) => {
+            warn!(
+                "string I/O on port {:#x} @ {:#x}: operand {:#x} is not RAM (GPA {:#x})",
+                io_info.port, ctx.guest_rip, addr, gpa
+            );
+            Err(HyperError::NotSupported)
+        }
+    }
+}
+
+/// Emulate the string I/O instruction described by `io_info` and `operands`, moving its elements
+/// between `device` and `mem`.
+pub(crate) fn emulate<V: ExitVcpu, M: GuestLinearMemory>(
+    vcpu: &mut V,
+    ctx: &ExitContext,
+    io_info: &IoExitInfo,
+    operands: StringIoOperands,
+    device: &mut dyn PioOps,
+    mem: &mut M,
+) -> HyperResult {
+    let size = io_info.access_size as usize;
+    let count = element_count(vcpu, io_info, &operands);
+    for done in 0..count.min(MAX_ELEMENTS_PER_EXIT) {
+        let addr = element_addr(vcpu, io_info, &operands, 0);
+        // The element may cross a page, translate both parts before touching the device.
+        let first_len = size.min(PAGE_SIZE_4K - (addr as usize % PAGE_SIZE_4K));
+        let parts = match translate_or_stop(vcpu, ctx, io_info, mem, addr, first_len)? {
+            Some(parts) => parts,
+            None => {
+                if done != 0 {
+                    mark_intentional_repeat();
+                }
+                return Ok(());
+            }
+        };
+        let mut bytes = [0u8; 4];
+        if io_info.is_in {
+            let value = device.read(io_info.port, io_info.access_size)?;
+            bytes = value.to_le_bytes();
+            write_element(&parts, first_len, &bytes[..size]);
+        } else {
+            read_element(&parts, first_len, &mut bytes[..size]);
+            device.write(io_info.port, io_info.access_size, u32::from_le_bytes(bytes))?;
+        }
+        advance(vcpu, io_info, &operands, 1);
+    }
+    finish(vcpu, ctx, count)
+}
+
+/// Emulate a `rep outs`: gather the elements, up to a fault or [`MAX_ELEMENTS_PER_EXIT`], and
+/// hand them to `device` in one [`PioBatchOps::write_batch`].
+pub(crate) fn emulate_batch<V: ExitVcpu, M: GuestLinearMemory>(
+    vcpu: &mut V,
+    ctx: &ExitContext,
+    io_info: &IoExitInfo,
+    operands: StringIoOperands,
+    device: &mut dyn PioBatchOps,
+    mem: &mut M,
+) -> HyperResult {
+    debug_assert!(io_info.is_repeat && !io_info.is_in);
+    let size = io_info.access_size as usize;
+    let count = element_count(vcpu, io_info, &operands);
+    let batch = count.min(MAX_ELEMENTS_PER_EXIT);
+    let mut data = Vec::with_capacity(batch as usize * size);
+    for i in 0..batch {
+        let addr = element_addr(vcpu, io_info, &operands, i);
+        let first_len = size.min(PAGE_SIZE_4K - (addr as usize % PAGE_SIZE_4K));
+        let parts = match translate_element(mem, addr, size, first_len, false) {
+            Ok(parts) => parts,
+            // Reported once the elements before it are written, below.
+            Err(_) => break,
+        };
+        let start = data.len();
+        data.resize(start + size, 0);
+        read_element(&parts, first_len, &mut data[start..]);
+    }
+    let gathered = (data.len() / size) as u64;
+    if gathered != 0 {
+        device.write_batch(io_info.port, io_info.access_size, &data)?;
+        advance(vcpu, io_info, &operands, gathered);
+    }
+    if gathered < batch {
+        // Translate the element which failed again, to inject its #PF or report it.
+        let addr = element_addr(vcpu, io_info, &operands, 0);
+        let first_len = size.min(PAGE_SIZE_4K - (addr as usize % PAGE_SIZE_4K));
+        translate_or_stop(vcpu, ctx, io_info, mem, addr, first_len)?;
+        if gathered != 0 {
+            mark_intentional_repeat();
+        }
+        return Ok(());
+    }
+    finish(vcpu, ctx, count)
+}
+
+/// Skip the instruction if its `count` elements are done, or leave it to exit again.
+fn finish<V: ExitVcpu>(vcpu: &mut V, ctx: &ExitContext, count: u64) -> HyperResult {
+    if count > MAX_ELEMENTS_PER_EXIT {
+        // RIP stays, the instruction exits again for the rest.
+        mark_intentional_repeat();
+        return Ok(());
+    }
+    vcpu.advance_rip(ctx.exit_instruction_length as _)
+}
+
+/// Host pointers to the part of the element at `addr` in its first page and, if it crosses a
+/// page, to the rest.
+fn translate_element<M: GuestLinearMemory>(
+    mem: &mut M,
+    addr: u64,
+    size: usize,
+    first_len: usize,
+    write: bool,
+) -> Result<[*mut u8; 2], GuestAccessFault> {
+    let first = mem.translate(addr, write)?;
+    let second = if first_len < size {
+        mem.translate(addr.wrapping_add(first_len as u64), write)?
+    } else {
+        core::ptr::null_mut()
+    };
+    Ok([first, second])
+}
+
+fn read_element(parts: &[*mut u8; 2], first_len: usize, bytes: &mut [u8]) {
+    // SAFETY: `translate` returned pointers valid up to the end of their pages.
+    unsafe {
+        core::ptr::copy_nonoverlapping(parts[0], bytes.as_mut_ptr(), first_len);
+        if first_len < bytes.len() {
+            core::ptr::copy_nonoverlapping(
+                parts[1],
+                bytes[first_len..].as_mut_ptr(),
+                bytes.len() - first_len,
+            );
+        }
+    }
+}
+
+fn write_element(parts: &[*mut u8; 2], first_len: usize, bytes: &[u8]) {
+    // SAFETY: as in `read_element`.
+    unsafe {
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), parts[0], first_len);
+        if first_len < bytes.len() {
+            core::ptr::copy_nonoverlapping(
+                bytes[first_len..].as_ptr(),
+                parts[1],
+                bytes.len() - first_len,
+            );
+        }
+    }
+}
+
+/// Bits of the guest paging entries.
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_ACCESSED: u64 = 1 << 5;
+const PTE_DIRTY: u64 = 1 << 6;
+const PTE_HUGE: u64 = 1 << 7;
+/// Frame address of a 64-bit entry, PAE or 4/5-level.
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Bits of the #PF error code.
+const PF_PROTECTION: u32 = 1 << 0;
+const PF_WRITE: u32 = 1 << 1;
+const PF_USER: u32 = 1 << 2;
+
+/// The paging mode of the guest, from its control registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PagingMode {
+    None,
+    /// 32-bit paging, with 4M pages if CR4.PSE is set.
+    Legacy {
+        pse: bool,
+    },
+    Pae,
+    /// 4-level or 5-level paging.
+    Long {
+        levels: u32,
+    },
+}
+
+/// The guest linear address space of the vCPU which exited on the current CPU, walked like the
+/// CPU would, setting the accessed and dirty bits.
+pub(crate) struct VmxGuestMemory {
+    cfg: Arc<VMCfgEntry>,
+    mode: PagingMode,
+    cr3: u64,
+    /// CR0.WP: supervisor writes honor the read-only pages.
+    write_protect: bool,
+    user: bool,
+    /// The last page translated, its host pointer and whether it was for a write, as most
+    /// string I/O stays within a page.
+    last_page: Option<(u64, *mut u8, bool)>,
+}
+
+impl VmxGuestMemory {
+    /// The address space of the current guest, `None` if the VM has no configuration.
+    pub fn current(vm_id: u32) -> Option<Self> {
+        let cr0 = vmcs_read(vmcs::guest::CR0);
+        let cr4 = vmcs_read(vmcs::guest::CR4);
+        let efer = vmcs_read(vmcs::guest::IA32_EFER_FULL);
+        let mode = if !cr0.get_bit(31) {
+            PagingMode::None
+        } else if efer.get_bit(10) {
+            PagingMode::Long {
+                levels: if cr4.get_bit(12) { 5 } else { 4 },
+            }
+        } else if cr4.get_bit(5) {
+            PagingMode::Pae
+        } else {
+            PagingMode::Legacy {
+                pse: cr4.get_bit(4),
+            }
+        };
+        Some(Self {
+            cfg: vm_cfg_entry(vm_id as usize)?,
+            mode,
+            cr3: vmcs_read(vmcs::guest::CR3),
+            write_protect: cr0.get_bit(16),
+            // The CPL is the DPL of SS.
+            user: vmcs_read(vmcs::guest::SS_ACCESS_RIGHTS).get_bits(5..7) == 3,
+            last_page: None,
+        })
+    }
+
+    /// Host pointer to the guest physical byte at `gpa`, if it is RAM.
+    fn gpa_ptr(&self, gpa: u64) -> Option<*mut u8> {
+        let page = gpa as usize & !(PAGE_SIZE_4K - 1);
+        let hpa = self.cfg.guest_ram_page_hpa(page)?;
+        Some(phys_to_virt(PhysAddr::from(hpa + gpa as usize % PAGE_SIZE_4K)).as_mut_ptr())
+    }
+
+    /// Read the entry at `gpa`, 4 or 8 bytes wide, and set `bits` in it if it is present.
+    fn entry(&self, gpa: u64, wide: bool, bits: u64) -> Result<u64, GuestAccessFault> {
+        let ptr = self
+            .gpa_ptr(gpa)
+            .ok_or(GuestAccessFault::NotRam { addr: 0, gpa })?;
+        // The guest may update its page tables on another vCPU, as with the CPU the bits are set
+        // atomically.
+        let entry = if wide {
+            // SAFETY: entries are naturally aligned in guest RAM.
+            let entry = unsafe { &*(ptr as *const AtomicU64) };
+            let value = entry.load(Ordering::Acquire);
+            if value & PTE_PRESENT != 0 && value & bits != bits {
+                entry.fetch_or(bits, Ordering::AcqRel);
+            }
+            value
+        } else {
+            // SAFETY: as above.
+            let entry = unsafe { &*(ptr as *const AtomicU32) };
+            let value = entry.load(Ordering::Acquire) as u64;
+            if value & PTE_PRESENT != 0 && value & bits != bits {
+                entry.fetch_or(bits as u32, Ordering::AcqRel);
+            }
+            value
+        };
+        Ok(entry)
+    }
+
+    /// Guest physical address of `addr`.
+    fn walk(&self, addr: u64, write: bool) -> Result<u64, GuestAccessFault> {
+        let (levels, wide, index_bits, mut table) = match self.mode {
+            PagingMode::None => return Ok(addr & 0xffff_ffff),
+            PagingMode::Legacy { .. } => (2, false, 10, self.cr3 & !0xfff),
+            PagingMode::Pae => (3, true, 9, self.cr3 & 0xffff_ffe0),
+            PagingMode::Long { levels } => (levels, true, 9, self.cr3 & PTE_ADDR_MASK),
+        };
+        let fault = |present: bool| {
+            let mut error_code = 0;
+            if present {
+                error_code |= PF_PROTECTION;
+            }
+            if write {
+                error_code |= PF_WRITE;
+            }
+            if self.user {
+                error_code |= PF_USER;
+            }
+            GuestAccessFault::Page { addr, error_code }
+        };
+        let (mut writable, mut user) = (true, true);
+        for level in (1..=levels).rev() {
+            let shift = 12 + index_bits * (level - 1);
+            let index = if self.mode == PagingMode::Pae && level == 3 {
+                // The 4 PDPTEs, indexed by bits 31:30.
+                addr.get_bits(30..32)
+            } else {
+                addr.get_bits(shift as usize..(shift + index_bits) as usize)
+            };
+            let entry_size = if wide { 8 } else { 4 };
+            let is_pdpte = self.mode == PagingMode::Pae && level == 3;
+            let huge_allowed = match self.mode {
+                PagingMode::Legacy { pse } => pse && level == 2,
+                PagingMode::Pae => level == 2,
+                PagingMode::Long { .. } => level == 2 || level == 3,
+                PagingMode::None => false,
+            };
+            let entry_gpa = table + index * entry_size;
+            // PDPTEs have no accessed bit.
+            let accessed = if is_pdpte { 0 } else { PTE_ACCESSED };
+            let entry = self.entry(entry_gpa, wide, accessed)?;
+            let leaf = level == 1 || (huge_allowed && entry & PTE_HUGE != 0);
+            if entry & PTE_PRESENT == 0 {
+                return Err(fault(false));
+            }
+            if !is_pdpte {
+                writable &= entry & PTE_WRITABLE != 0;
+                user &= entry & PTE_USER != 0;
+            }
+            let frame = if wide {
+                entry & PTE_ADDR_MASK
+            } else {
+                entry & 0xffff_f000
+            };
+            if leaf {
+                if (self.user && !user) || (write && !writable && (self.user || self.write_protect))
+                {
+                    return Err(fault(true));
+                }
+                if write {
+                    self.entry(entry_gpa, wide, PTE_DIRTY)?;
+                }
+                let page_mask = (1u64 << shift) - 1;
+                return Ok((frame & !page_mask) | (addr & page_mask));
+            }
+            table = frame;
+        }
+        unreachable!()
+    }
+}
+
+impl GuestLinearMemory for VmxGuestMemory {
+    fn translate(&mut self, addr: u64, write: bool) -> Result<*mut u8, GuestAccessFault> {
+        let page = addr & !(PAGE_SIZE_4K as u64 - 1);
+        let offset = (addr - page) as usize;
+        if let Some((last, ptr, writable)) = self.last_page {
+            if last == page && (writable || !write) {
+                // SAFETY: within the page `ptr` points to.
+                return Ok(unsafe { ptr.add(offset) });
+            }
+        }
+        let gpa = self.walk(page, write).map_err(|fault| match fault {
+            GuestAccessFault::Page { error_code, .. } => {
+                GuestAccessFault::Page { addr, error_code }
+            }
+            GuestAccessFault::NotRam { gpa, .. } => GuestAccessFault::NotRam { addr, gpa },
+        })?;
+        let ptr = self
+            .gpa_ptr(gpa)
+            .ok_or(GuestAccessFault::NotRam { addr, gpa })?;
+        self.last_page = Some((page, ptr, write));
+        // SAFETY: as above.
+        Ok(unsafe { ptr.add(offset) })
+    }
+
+    fn inject_page_fault<V: ExitVcpu>(&mut self, vcpu: &mut V, addr: u64, error_code: u32) {
+        // VMX neither saves nor loads CR2: the value the host leaves in it is the one the guest
+        // sees after the VM entry, and the hypervisor takes no page fault in between.
+        unsafe { x86::controlregs::cr2_write(addr) };
+        vcpu.queue_event(PF_VECTOR, Some(error_code));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::device_emu::Uart16550;
+    use super::super::exit_vcpu::MockVcpu;
+    use super::*;
+    use crate::device::console_backend::VirtualConsoleBackend;
+    use crate::device::write_each;
+    use crate::VmxExitReason;
+
+    /// Linear address of the first page of [`MockMemory`].
+    const BASE: u64 = 0x10000;
+    const PAGES: usize = 3;
+    const PORT: u16 = 0x3f8;
+    /// Length of the `rep ins`/`rep outs` instructions.
+    const INSTR_LEN: u32 = 2;
+
+    /// `PAGES` pages of RAM at [`BASE`], with pages which fault or are not RAM. Any other address
+    /// is not mapped.
+    struct MockMemory {
+        ram: Vec<u8>,
+        faulting: Vec<u64>,
+        not_ram: Vec<u64>,
+        /// The addresses of the page faults injected.
+        injected: Vec<u64>,
+    }
+
+    impl MockMemory {
+        fn new() -> Self {
+            Self {
+                ram: (0..PAGES * PAGE_SIZE_4K).map(|i| i as u8).collect(),
+                faulting: Vec::new(),
+                not_ram: Vec::new(),
+                injected: Vec::new(),
+            }
+        }
+
+        fn bytes(&self, addr: u64, len: usize) -> &[u8] {
+            let start = (addr - BASE) as usize;
+            &self.ram[start..start + len]
+        }
+    }
+
+    impl GuestLinearMemory for MockMemory {
+        fn translate(&mut self, addr: u64, write: bool) -> Result<*mut u8, GuestAccessFault> {
+            let page = addr & !(PAGE_SIZE_4K as u64 - 1);
+            let error_code = if write { PF_WRITE } else { 0 };
+            if self.not_ram.contains(&page) {
+                return Err(GuestAccessFault::NotRam { addr, gpa: page });
+            }
+            let offset = addr.wrapping_sub(BASE) as usize;
+            if self.faulting.contains(&page) || offset >= self.ram.len() {
+                return Err(GuestAccessFault::Page { addr, error_code });
+            }
+            Ok(self.ram[offset..].as_mut_ptr())
+        }
+
+        fn inject_page_fault<V: ExitVcpu>(&mut self, vcpu: &mut V, addr: u64, error_code: u32) {
+            self.injected.push(addr);
+            vcpu.queue_event(PF_VECTOR, Some(error_code));
+        }
+    }
+
+    /// A port whose reads return 0x11, 0x22, ... in each byte, recording its writes.
+    #[derive(Default)]
+    struct MockPort {
+        reads: u32,
+        writes: Vec<(u8, u32)>,
+        batches: Vec<Vec<u8>>,
+    }
+
+    impl PioOps for MockPort {
+        fn port_range(&self) -> core::ops::Range<u16> {
+            PORT..PORT + 1
+        }
+
+        fn read(&mut self, port: u16, access_size: u8) -> HyperResult<u32> {
+            assert_eq!(port, PORT);
+            self.reads += 1;
+            let byte = self.reads * 0x11;
+            Ok((0..access_size).fold(0, |value, i| value | byte << (i * 8)))
+        }
+
+        fn write(&mut self, port: u16, access_size: u8, value: u32) -> HyperResult {
+            assert_eq!(port, PORT);
+            self.writes.push((access_size, value));
+            Ok(())
+        }
+    }
+
+    impl PioBatchOps for MockPort {
+        fn write_batch(&mut self, port: u16, access_size: u8, data: &[u8]) -> HyperResult {
+            self.batches.push(data.to_vec());
+            write_each(self, port, access_size, data)
+        }
+    }
+
+    fn io_info(is_in: bool, access_size: u8, is_repeat: bool) -> IoExitInfo {
+        IoExitInfo {
+            port: PORT,
+            access_size,
+            is_in,
+            is_string: true,
+            is_repeat,
+        }
+    }
+
+    fn operands(address_mask: u64, backward: bool) -> StringIoOperands {
+        StringIoOperands {
+            segment_base: 0,
+            address_mask,
+            backward,
+        }
+    }
+
+    fn ctx() -> ExitContext {
+        ExitContext::synthetic(VmxExitReason::IO_INSTRUCTION, INSTR_LEN, 0)
+    }
+
+    fn new_vcpu(rsi: u64, rdi: u64, rcx: u64) -> MockVcpu {
+        let mut vcpu = MockVcpu::default();
+        vcpu.regs.rsi = rsi;
+        vcpu.regs.rdi = rdi;
+        vcpu.regs.rcx = rcx;
+        vcpu
+    }
+
+    /// The host end of the serial line of a [`Uart16550`], recording what it sends.
+    #[derive(Default)]
+    struct Console {
+        output: Vec<u8>,
+    }
+
+    impl VirtualConsoleBackend for Console {
+        fn new() -> Self {
+            Self::default()
+        }
+
+        fn putchar(&mut self, c: u8) {
+            self.output.push(c);
+        }
+
+        fn getchar(&mut self) -> Option<u8> {
+            None
+        }
+    }
+
+    fn bytes_written(port: &MockPort) -> Vec<u8> {
+        port.writes
+            .iter()
+            .flat_map(|&(size, value)| value.to_le_bytes().into_iter().take(size as usize))
+            .collect()
+    }
+
+    #[test]
+    fn test_rep_outs() {
+        const TEXT: &[u8] = b"rep outsb to COM1\r\n";
+        const LINE_STATUS: u16 = PORT + 5;
+        // The string is sent on the serial line one byte at a time, then as a batch.
+        for batch in [false, true] {
+            let mut mem = MockMemory::new();
+            mem.ram[0x10..0x10 + TEXT.len()].copy_from_slice(TEXT);
+            let mut uart = Uart16550::with_backend(PORT, Console::default());
+            // The THRE interrupt, raised once enabled, then acknowledged by reading the IIR.
+            uart.write(PORT + 1, 1, 0x02).unwrap();
+            assert_eq!(uart.read(PORT + 2, 1).unwrap(), 0x02);
+            assert_eq!(uart.read(PORT + 2, 1).unwrap(), 0x01);
+
+            let mut vcpu = new_vcpu(BASE + 0x10, 0, TEXT.len() as u64);
+            let info = io_info(false, 1, true);
+            let ops = operands(u64::MAX, false);
+            if batch {
+                emulate_batch(&mut vcpu, &ctx(), &info, ops, &mut uart, &mut mem).unwrap();
+            } else {
+                emulate(&mut vcpu, &ctx(), &info, ops, &mut uart, &mut mem).unwrap();
+            }
+            assert_eq!(uart.backend().output, TEXT);
+            let end = BASE + 0x10 + TEXT.len() as u64;
+            assert_eq!((vcpu.regs.rsi, vcpu.regs.rcx), (end, 0));
+            assert_eq!(vcpu.rip, INSTR_LEN as u64);
+            assert!(vcpu.events.is_empty());
+            // The THR and the shift register are empty, with a THRE interrupt pending.
+            assert_eq!(uart.read(LINE_STATUS, 1).unwrap(), 0x60);
+            assert_eq!(uart.read(PORT + 2, 1).unwrap(), 0x02);
+        }
+
+        // With DLAB set, the bytes go to the divisor latch instead of the line.
+        let mut mem = MockMemory::new();
+        let mut uart = Uart16550::with_backend(PORT, Console::default());
+        uart.write(PORT + 3, 1, 0x83).unwrap();
+        let mut vcpu = new_vcpu(BASE + 0x10, 0, 2);
+        let info = io_info(false, 1, true);
+        let ops = operands(u64::MAX, false);
+        emulate_batch(&mut vcpu, &ctx(), &info, ops, &mut uart, &mut mem).unwrap();
+        assert!(uart.backend().output.is_empty());
+        assert_eq!(uart.read(PORT, 1).unwrap(), 0x11);
+    }
+
+    #[test]
+    fn test_rep_ins_backward() {
+        let (mut mem, mut port) = (MockMemory::new(), MockPort::default());
+        let mut vcpu = new_vcpu(0, BASE + 0x10, 3);
+        let info = io_info(true, 2, true);
+        emulate(
+            &mut vcpu,
+            &ctx(),
+            &info,
+            operands(u64::MAX, true),
+            &mut port,
+            &mut mem,
+        )
+        .unwrap();
+        assert_eq!(
+            mem.bytes(BASE + 0xc, 6),
+            [0x33, 0x33, 0x22, 0x22, 0x11, 0x11]
+        );
+        assert_eq!((vcpu.regs.rdi, vcpu.regs.rcx), (BASE + 0xa, 0));
+        assert_eq!(vcpu.rip, INSTR_LEN as u64);
+    }
+
+    #[test]
+    fn test_page_crossing() {
+        let (mut mem, mut port) = (MockMemory::new(), MockPort::default());
+        // `outsd` of the last 2 bytes of the first page and the first 2 of the second.
+        let addr = BASE + PAGE_SIZE_4K as u64 - 2;
+        let mut vcpu = new_vcpu(addr, 0, 7);
+        let info = io_info(false, 4, false);
+        emulate(
+            &mut vcpu,
+            &ctx(),
+            &info,
+            operands(u64::MAX, false),
+            &mut port,
+            &mut mem,
+        )
+        .unwrap();
+        assert_eq!(bytes_written(&port), mem.bytes(addr, 4));
+        // Without REP, RCX is left alone.
+        assert_eq!((vcpu.regs.rsi, vcpu.regs.rcx), (addr + 4, 7));
+
+        // `insd` into the same bytes, the second page faulting: the device is not read.
+        mem.faulting.push(BASE + PAGE_SIZE_4K as u64);
+        let mut vcpu = new_vcpu(0, addr, 0);
+        let info = io_info(true, 4, false);
+        emulate(
+            &mut vcpu,
+            &ctx(),
+            &info,
+            operands(u64::MAX, false),
+            &mut port,
+            &mut mem,
+        )
+        .unwrap();
+        assert_eq!(port.reads, 0);
+        assert_eq!(mem.injected, [BASE + PAGE_SIZE_4K as u64]);
+        assert_eq!(vcpu.events, [(PF_VECTOR, Some(PF_WRITE))]);
+        assert_eq!((vcpu.regs.rdi, vcpu.rip), (addr, 0));
+    }
+
+    #[test]
+    fn test_fault_mid_rep() {
+        let (mut mem, mut port) = (MockMemory::new(), MockPort::default());
+        let second_page = BASE + PAGE_SIZE_4K as u64;
+        mem.faulting.push(second_page);
+        let mut vcpu = new_vcpu(second_page - 2, 0, 4);
+        let info = io_info(false, 1, true);
+        emulate(
+            &mut vcpu,
+            &ctx(),
+            &info,
+            operands(u64::MAX, false),
+            &mut port,
+            &mut mem,
+        )
+        .unwrap();
+        // The elements before the fault are done, the instruction resumes at the faulting one.
+        assert_eq!(bytes_written(&port), mem.bytes(second_page - 2, 2));
+        assert_eq!((vcpu.regs.rsi, vcpu.regs.rcx), (second_page, 2));
+        assert_eq!(vcpu.events, [(PF_VECTOR, Some(0))]);
+        assert_eq!(vcpu.rip, 0);
+    }
+
+    #[test]
+    fn test_not_ram() {
+        let (mut mem, mut port) = (MockMemory::new(), MockPort::default());
+        mem.not_ram.push(BASE);
+        let mut vcpu = new_vcpu(BASE, 0, 4);
+        let info = io_info(false, 1, true);
+        assert!(matches!(
+            emulate(
+                &mut vcpu,
+                &ctx(),
+                &info,
+                operands(u64::MAX, false),
+                &mut port,
+                &mut mem
+            ),
+            Err(HyperError::NotSupported)
+        ));
+        assert!(port.writes.is_empty() && vcpu.events.is_empty());
+        assert_eq!((vcpu.regs.rsi, vcpu.regs.rcx, vcpu.rip), (BASE, 4, 0));
+    }
+
+    #[test]
+    fn test_address_size() {
+        // 16-bit: SI wraps within the segment and the upper bits of RSI and RCX are kept.
+        let (mut mem, mut port) = (MockMemory::new(), MockPort::default());
+        let mut vcpu = new_vcpu(0xdead_ffff, 0, 0x1_0002);
+        let mut ops = operands(0xffff, false);
+        ops.segment_base = BASE + PAGE_SIZE_4K as u64 - 0xffff;
+        let info = io_info(false, 1, true);
+        emulate(&mut vcpu, &ctx(), &info, ops, &mut port, &mut mem).unwrap();
+        assert_eq!(
+            bytes_written(&port),
+            mem.bytes(BASE + PAGE_SIZE_4K as u64, 1)
+        );
+        // The next element, at offset 0 of the segment, is not mapped.
+        assert_eq!(mem.injected, [ops.segment_base]);
+        assert_eq!((vcpu.regs.rsi, vcpu.regs.rcx), (0xdead_0000, 0x1_0001));
+
+        // 32-bit: ESI is zero-extended.
+        let (mut mem, mut port) = (MockMemory::new(), MockPort::default());
+        let mut vcpu = new_vcpu(0xffff_0000_0000_0000 | BASE, 0, 0);
+        let info = io_info(false, 2, false);
+        emulate(
+            &mut vcpu,
+            &ctx(),
+            &info,
+            operands(0xffff_ffff, false),
+            &mut port,
+            &mut mem,
+        )
+        .unwrap();
+        assert_eq!(bytes_written(&port), mem.bytes(BASE, 2));
+        assert_eq!(vcpu.regs.rsi, BASE + 2);
+    }
+
+    #[test]
+    fn test_max_elements() {
+        let (mut mem, mut port) = (MockMemory::new(), MockPort::default());
+        let mut vcpu = new_vcpu(BASE, 0, MAX_ELEMENTS_PER_EXIT + 10);
+        let info = io_info(false, 1, true);
+        emulate(
+            &mut vcpu,
+            &ctx(),
+            &info,
+            operands(u64::MAX, false),
+            &mut port,
+            &mut mem,
+        )
+        .unwrap();
+        assert_eq!(port.writes.len() as u64, MAX_ELEMENTS_PER_EXIT);
+        // RIP stays, the instruction exits again for the last 10.
+        assert_eq!(vcpu.regs.rcx, 10);
+        assert_eq!(vcpu.regs.rsi, BASE + MAX_ELEMENTS_PER_EXIT);
+        assert_eq!(vcpu.rip, 0);
+    }
+
+    #[test]
+    fn test_batch() {
+        let (mut mem, mut port) = (MockMemory::new(), MockPort::default());
+        let mut vcpu = new_vcpu(BASE + 0x20, 0, 3);
+        let info = io_info(false, 2, true);
+        let ops = operands(u64::MAX, false);
+        emulate_batch(&mut vcpu, &ctx(), &info, ops, &mut port, &mut mem).unwrap();
+        assert_eq!(port.batches, [mem.bytes(BASE + 0x20, 6)]);
+        assert_eq!(port.writes.len(), 3);
+        assert_eq!((vcpu.regs.rsi, vcpu.regs.rcx), (BASE + 0x26, 0));
+        assert_eq!(vcpu.rip, INSTR_LEN as u64);
+
+        // Up to a fault: the gathered words are written, then #PF for the one crossing the page.
+        let (mut mem, mut port) = (MockMemory::new(), MockPort::default());
+        let second_page = BASE + PAGE_SIZE_4K as u64;
+        mem.faulting.push(second_page);
+        let mut vcpu = new_vcpu(second_page - 5, 0, 4);
+        emulate_batch(&mut vcpu, &ctx(), &info, ops, &mut port, &mut mem).unwrap();
+        assert_eq!(port.batches, [mem.bytes(second_page - 5, 4)]);
+        assert_eq!(mem.injected, [second_page]);
+        assert_eq!((vcpu.regs.rsi, vcpu.regs.rcx), (second_page - 1, 2));
+        assert_eq!(vcpu.rip, 0);
+
+        // The first element faulting, nothing is written.
+        let mut vcpu = new_vcpu(second_page, 0, 2);
+        port.batches.clear();
+        emulate_batch(&mut vcpu, &ctx(), &info, ops, &mut port, &mut mem).unwrap();
+        assert!(port.batches.is_empty());
+        assert_eq!((vcpu.regs.rsi, vcpu.regs.rcx), (second_page, 2));
+    }
+}
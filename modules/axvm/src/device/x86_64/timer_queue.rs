@@ -0,0 +1,272 @@
+//! Deadline-driven scheduling of the emulated timer sources of a vCPU.
+//!
+//! Every timer source registers its next deadline in the [`TimerQueue`] of its vCPU. Before each
+//! VM entry, `check_events` runs the sources whose deadline passed and arms the VMX-preemption
+//! timer for the soonest remaining one, so the guest exits in time even if it would not exit on
+//! its own. If the CPU has no preemption timer, the exits forced by host interrupts remain the
+//! only bound on the injection latency.
+//!
+//! New timer devices get a [`TimerSource`] and are run from `check_events` when they are due.
+
+use alloc::collections::BinaryHeap;
+use core::cmp::Reverse;
+
+use axhal::time::nanos_to_ticks;
+use x86::bits64::vmx::{vmread, vmwrite};
+use x86::msr::{rdmsr, IA32_VMX_MISC, IA32_VMX_PINBASED_CTLS};
+use x86::vmx::vmcs;
+use x86::vmx::vmcs::control::PinbasedControls;
+
+/// Emulated timers of a vCPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum TimerSource {
+    /// The local APIC timer.
+    ApicTimer = 0,
+    /// IRQ 0 of the master PIC, raised by channel 0 of the PIT.
+    PitIrq = 1,
+    /// IRQ 8 of the slave PIC, raised by the RTC.
+    RtcIrq = 2,
+    /// The IRQs of the timers of the HPET.
+    HpetIrq = 3,
+    /// The expiry of the IB700 watchdog, which resets the VM.
+    Watchdog = 4,
+}
+
+pub(crate) const NUM_SOURCES: usize = 5;
+
+/// The heap is rebuilt from the registered deadlines when it holds more stale entries than this.
+const MAX_HEAP_LEN: usize = 4 * NUM_SOURCES;
+
+/// Injection latency of the timer sources of a vCPU.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimerQueueStats {
+    /// Deadlines run by `check_events`.
+    pub fired: u64,
+    /// Sum and maximum of the delays between a deadline and `check_events` running it.
+    pub total_latency_ns: u64,
+    pub max_latency_ns: u64,
+}
+
+/// Whether the VMX-preemption timer of the VMCS of a vCPU is set up.
+#[derive(Debug, Clone, Copy)]
+enum PreemptionTimer {
+    Unprobed,
+    Unsupported,
+    /// Enabled, counting down once every `1 << rate` TSC ticks.
+    Enabled {
+        rate: u8,
+    },
+}
+
+/// The deadlines of the timer sources of a vCPU, soonest first.
+pub(crate) struct TimerQueue {
+    /// Registered deadlines, with stale entries which are dropped when they reach the top.
+    heap: BinaryHeap<Reverse<(u64, TimerSource)>>,
+    deadlines: [Option<u64>; NUM_SOURCES],
+    preemption_timer: PreemptionTimer,
+    stats: TimerQueueStats,
+}
+
+impl TimerQueue {
+    pub fn new() -> Self {
+        Self {
+            heap: BinaryHeap::with_capacity(MAX_HEAP_LEN),
+            deadlines: [None; NUM_SOURCES],
+            preemption_timer: PreemptionTimer::Unprobed,
+            stats: TimerQueueStats::default(),
+        }
+    }
+
+    /// The registered deadline of `source`.
+    pub fn deadline(&self, source: TimerSource) -> Option<u64> {
+        self.deadlines[source as usize]
+    }
+
+    /// Register the next deadline of `source`, or cancel it.
+    pub fn set(&mut self, source: TimerSource, deadline_ns: Option<u64>) {
+        if self.deadlines[source as usize] == deadline_ns {
+            return;
+        }
+        self.deadlines[source as usize] = deadline_ns;
+        if let Some(deadline_ns) = deadline_ns {
+            if self.heap.len() >= MAX_HEAP_LEN {
+                self.rebuild();
+            }
+            self.heap.push(Reverse((deadline_ns, source)));
+        }
+    }
+
+    fn rebuild(&mut self) {
+        self.heap.clear();
+        for source in [
+            TimerSource::ApicTimer,
+            TimerSource::PitIrq,
+            TimerSource::RtcIrq,
+            TimerSource::HpetIrq,
+            TimerSource::Watchdog,
+        ] {
+            if let Some(deadline_ns) = self.deadline(source) {
+                self.heap.push(Reverse((deadline_ns, source)));
+            }
+        }
+    }
+
+    fn is_stale(&self, deadline_ns: u64, source: TimerSource) -> bool {
+        self.deadline(source) != Some(deadline_ns)
+    }
+
+    /// The soonest registered deadline.
+    pub fn next_deadline(&mut self) -> Option<u64> {
+        while let Some(&Reverse((deadline_ns, source))) = self.heap.peek() {
+            if !self.is_stale(deadline_ns, source) {
+                return Some(deadline_ns);
+            }
+            self.heap.pop();
+        }
+        None
+    }
+
+    /// Unregister and return a source whose deadline is at or before `now_ns`, with its deadline.
+    pub fn pop_due(&mut self, now_ns: u64) -> Option<(TimerSource, u64)> {
+        let deadline_ns = self.next_deadline()?;
+        if deadline_ns > now_ns {
+            return None;
+        }
+        let Reverse((_, source)) = self.heap.pop()?;
+        self.deadlines[source as usize] = None;
+
+        let latency = now_ns - deadline_ns;
+        self.stats.fired += 1;
+        self.stats.total_latency_ns += latency;
+        self.stats.max_latency_ns = self.stats.max_latency_ns.max(latency);
+        Some((source, deadline_ns))
+    }
+
+    pub fn stats(&self) -> TimerQueueStats {
+        self.stats
+    }
+
+    /// Arm the VMX-preemption timer of the current VMCS for the soonest deadline.
+    ///
+    /// Must be called on the CPU of the vCPU, with its VMCS loaded.
+    pub fn arm(&mut self, now_ns: u64) {
+        if let PreemptionTimer::Unprobed = self.preemption_timer {
+            self.preemption_timer = enable_preemption_timer();
+        }
+        let PreemptionTimer::Enabled { rate } = self.preemption_timer else {
+            return;
+        };
+        let value = match self.next_deadline() {
+            Some(deadline_ns) => {
+                let ticks = nanos_to_ticks(deadline_ns.saturating_sub(now_ns)) >> rate;
+                ticks.min(u32::MAX as u64)
+            }
+            // Nothing to wait for, expire as late as possible.
+            None => u32::MAX as u64,
+        };
+        if let Err(err) = unsafe { vmwrite(vmcs::guest::VMX_PREEMPTION_TIMER_VALUE, value) } {
+            warn!("failed to arm the VMX-preemption timer: {:?}", err);
+        }
+    }
+}
+
+/// Enable the VMX-preemption timer in the pin-based controls of the current VMCS.
+fn enable_preemption_timer() -> PreemptionTimer {
+    // IA32_VMX_PINBASED_CTLS: the high 32 bits are the controls allowed to be 1.
+    let allowed = (unsafe { rdmsr(IA32_VMX_PINBASED_CTLS) } >> 32) as u32;
+    let timer = PinbasedControls::VMX_PREEMPTION_TIMER.bits();
+    if allowed & timer == 0 {
+        info!("no VMX-preemption timer, timer injection relies on host interrupts");
+        return PreemptionTimer::Unsupported;
+    }
+    let enabled = unsafe {
+        vmread(vmcs::control::PINBASED_EXEC_CONTROLS).and_then(|controls| {
+            vmwrite(
+                vmcs::control::PINBASED_EXEC_CONTROLS,
+                controls | timer as u64,
+            )
+        })
+    };
+    if let Err(err) = enabled {
+        warn!("failed to enable the VMX-preemption timer: {:?}", err);
+        return PreemptionTimer::Unsupported;
+    }
+    // IA32_VMX_MISC bits 4:0: the timer counts down once every 2^rate TSC ticks.
+    let rate = (unsafe { rdmsr(IA32_VMX_MISC) } & 0x1f) as u8;
+    PreemptionTimer::Enabled { rate }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn test_order() {
+        let mut queue = TimerQueue::new();
+        assert_eq!(queue.next_deadline(), None);
+        queue.set(TimerSource::RtcIrq, Some(300));
+        queue.set(TimerSource::ApicTimer, Some(100));
+        queue.set(TimerSource::PitIrq, Some(200));
+        assert_eq!(queue.next_deadline(), Some(100));
+
+        assert_eq!(queue.pop_due(50), None);
+        assert_eq!(queue.pop_due(250), Some((TimerSource::ApicTimer, 100)));
+        assert_eq!(queue.pop_due(250), Some((TimerSource::PitIrq, 200)));
+        assert_eq!(queue.pop_due(250), None);
+        assert_eq!(queue.deadline(TimerSource::ApicTimer), None);
+        assert_eq!(queue.deadline(TimerSource::RtcIrq), Some(300));
+
+        let stats = queue.stats();
+        assert_eq!(stats.fired, 2);
+        assert_eq!(stats.total_latency_ns, 150 + 50);
+        assert_eq!(stats.max_latency_ns, 150);
+
+        // Sources due at the same time run in the order of their numbers.
+        queue.set(TimerSource::Watchdog, Some(300));
+        assert_eq!(queue.pop_due(300), Some((TimerSource::RtcIrq, 300)));
+        assert_eq!(queue.pop_due(300), Some((TimerSource::Watchdog, 300)));
+        assert_eq!(queue.pop_due(300), None);
+    }
+
+    #[test]
+    fn test_reprogram() {
+        let mut queue = TimerQueue::new();
+        queue.set(TimerSource::ApicTimer, Some(100));
+        queue.set(TimerSource::HpetIrq, Some(150));
+        // Moved later and cancelled, the stale entries are skipped.
+        queue.set(TimerSource::ApicTimer, Some(400));
+        queue.set(TimerSource::HpetIrq, None);
+        assert_eq!(queue.next_deadline(), Some(400));
+        assert_eq!(queue.pop_due(399), None);
+        assert_eq!(queue.pop_due(400), Some((TimerSource::ApicTimer, 400)));
+        assert_eq!(queue.next_deadline(), None);
+        assert_eq!(queue.stats().fired, 1);
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let mut queue = TimerQueue::new();
+        // A guest reprogramming its timer on every tick, the heap stays bounded.
+        for deadline in 0..10 * MAX_HEAP_LEN as u64 {
+            queue.set(TimerSource::ApicTimer, Some(1000 + deadline));
+            queue.set(TimerSource::PitIrq, Some(5000 - deadline));
+            assert!(queue.heap.len() <= MAX_HEAP_LEN);
+        }
+        queue.set(TimerSource::RtcIrq, Some(3000));
+        let mut fired = Vec::new();
+        while let Some(due) = queue.pop_due(10_000) {
+            fired.push(due);
+        }
+        let last = 10 * MAX_HEAP_LEN as u64 - 1;
+        assert_eq!(
+            fired,
+            [
+                (TimerSource::ApicTimer, 1000 + last),
+                (TimerSource::RtcIrq, 3000),
+                (TimerSource::PitIrq, 5000 - last),
+            ]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! The TSC offset of a vCPU: its guest TSC is the TSC of the machine plus the offset.
+//!
+//! The offset is kept here, with the devices of the vCPU, and written to its VMCS before the next
+//! VM entry, on the CPU of the vCPU: a restored state or an offset set from another CPU takes
+//! effect then. Pauses of the VM move it back by the time the VM was paused, so that the guest
+//! TSC continues from where it stopped.
+
+use alloc::vec::Vec;
+
+use x86::bits64::vmx::{vmread, vmwrite};
+use x86::vmx::vmcs;
+use x86::vmx::vmcs::control::PrimaryControls;
+
+use crate::device::{DeviceState, StateReader, StateWriter};
+use crate::Result as HyperResult;
+
+const TSC_OFFSET_STATE_VERSION: u16 = 1;
+
+pub(crate) struct TscOffset {
+    offset: u64,
+    /// Whether `offset` is the one in the VMCS.
+    applied: bool,
+}
+
+impl TscOffset {
+    /// No offset, as a VMCS starts.
+    pub fn new() -> Self {
+        Self {
+            offset: 0,
+            applied: true,
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.offset
+    }
+
+    /// Set the offset, added to the TSC of the machine modulo 2^64.
+    pub fn set(&mut self, offset: u64) {
+        self.offset = offset;
+        self.applied = false;
+    }
+
+    /// Move the guest TSC `delta_ns` back.
+    pub fn shift_ns(&mut self, delta_ns: u64) {
+        let ticks = axhal::time::nanos_to_ticks(delta_ns);
+        self.set(self.offset.wrapping_sub(ticks));
+    }
+
+    /// Write the offset to the current VMCS if it changed, enabling TSC offsetting. Returns
+    /// whether it was written.
+    ///
+    /// Must be called on the CPU of the vCPU, with its VMCS loaded.
+    pub fn apply(&mut self) -> bool {
+        if self.applied {
+            return false;
+        }
+        let offsetting = PrimaryControls::USE_TSC_OFFSETTING.bits() as u64;
+        let result = unsafe {
+            vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS).and_then(|controls| {
+                if controls & offsetting == 0 {
+                    vmwrite(
+                        vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+                        controls | offsetting,
+                    )?;
+                }
+                vmwrite(vmcs::control::TSC_OFFSET_FULL, self.offset)
+            })
+        };
+        if let Err(err) = result {
+            warn!(
+                "failed to set the guest TSC offset {:#x}: {:?}",
+                self.offset, err
+            );
+            return false;
+        }
+        self.applied = true;
+        true
+    }
+}
+
+/// The offset only, the TSC of the restoring machine being unrelated: the guest TSC jumps unless
+/// the offset is adjusted with [`crate::device::X64VcpuDevices::set_tsc_offset`].
+impl DeviceState for TscOffset {
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(TSC_OFFSET_STATE_VERSION);
+        state.u64(self.offset);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let mut state = StateReader::new(state, TSC_OFFSET_STATE_VERSION)?;
+        let offset = state.u64()?;
+        state.finish()?;
+        self.set(offset);
+        Ok(())
+    }
+}
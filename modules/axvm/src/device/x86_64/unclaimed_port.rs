@@ -0,0 +1,120 @@
+//! The ports no device claims, answered by the per-VM device list of a VM whose policy is
+//! [`UnhandledPortPolicy::IgnoreRdOnesWrDrop`](crate::UnhandledPortPolicy).
+//!
+//! They read as all ones, like a port with nothing on the bus of a PC, and their writes are
+//! dropped. Each port is logged the first time the guest touches it and recorded, so that the
+//! devices a guest pokes can be listed with [`ignored_ports`] and emulated later. A device list
+//! remembers the ports it recorded in its [`SeenPorts`], a port ignored again costs no lock.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use hypercraft::PioOps;
+use spin::Mutex;
+
+use crate::Result as HyperResult;
+
+/// The ports ignored for each VM since it booted.
+static IGNORED_PORTS: Mutex<BTreeMap<u32, BTreeSet<u16>>> = Mutex::new(BTreeMap::new());
+
+/// The ports nobody claims, for the elements of a string I/O. Never registered: the accesses
+/// come to it whole, even the ones going past port 0xffff.
+pub(super) struct UnclaimedPort;
+
+impl PioOps for UnclaimedPort {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        0..u16::MAX
+    }
+
+    fn read(&mut self, _port: u16, _access_size: u8) -> HyperResult<u32> {
+        Ok(u32::MAX)
+    }
+
+    fn write(&mut self, _port: u16, _access_size: u8, _value: u32) -> HyperResult {
+        Ok(())
+    }
+}
+
+/// The ports a device list recorded, a bit per port.
+pub(super) struct SeenPorts(Box<[AtomicU64]>);
+
+impl SeenPorts {
+    pub fn new() -> Self {
+        Self(
+            (0..(u16::MAX as usize + 1) / 64)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        )
+    }
+
+    /// Record that the guest of `vm_id` touched `port`, logged the first time one of its device
+    /// lists sees it.
+    pub fn record(&self, vm_id: u32, port: u16, is_in: bool, guest_rip: usize) {
+        let (word, bit) = (&self.0[port as usize / 64], 1 << (port % 64));
+        // Read first, the vCPUs of the VM hitting the same ports do not bounce the line.
+        if word.load(Ordering::Relaxed) & bit != 0
+            || word.fetch_or(bit, Ordering::Relaxed) & bit != 0
+        {
+            return;
+        }
+        if IGNORED_PORTS.lock().entry(vm_id).or_default().insert(port) {
+            warn!(
+                "VM [{}] {} port {:#x} @ {:#x}: no device, ignored from now on",
+                vm_id,
+                if is_in { "IN from" } else { "OUT to" },
+                port,
+                guest_rip
+            );
+        }
+    }
+}
+
+/// Forget the ports of `vm_id`, which is booting, before any of its device lists is created.
+pub(crate) fn reset(vm_id: u32) {
+    IGNORED_PORTS.lock().remove(&vm_id);
+}
+
+/// The ports the guest of `vm_id` touched with no device behind them, by increasing number.
+/// Kept once the VM stopped, until it boots again.
+pub fn ignored_ports(vm_id: u32) -> Vec<u16> {
+    IGNORED_PORTS
+        .lock()
+        .get(&vm_id)
+        .map_or_else(Vec::new, |ports| ports.iter().copied().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let vm_id = 0x279;
+        let (a, b) = (SeenPorts::new(), SeenPorts::new());
+        for port in [0xffff, 0x80, 0xffff, 0x3f, 0x40] {
+            a.record(vm_id, port, true, 0);
+        }
+        assert_eq!(ignored_ports(vm_id), [0x3f, 0x40, 0x80, 0xffff]);
+        // Another list of the VM, or another VM.
+        b.record(vm_id, 0x81, false, 0);
+        b.record(vm_id + 1, 0x3f, false, 0);
+        assert_eq!(ignored_ports(vm_id), [0x3f, 0x40, 0x80, 0x81, 0xffff]);
+        assert_eq!(ignored_ports(vm_id + 1), [0x3f]);
+
+        reset(vm_id);
+        assert_eq!(ignored_ports(vm_id), []);
+        assert_eq!(ignored_ports(vm_id + 1), [0x3f]);
+        SeenPorts::new().record(vm_id, 0x40, true, 0);
+        assert_eq!(ignored_ports(vm_id), [0x40]);
+        reset(vm_id + 1);
+    }
+
+    #[test]
+    fn test_unclaimed_port() {
+        let mut port = UnclaimedPort;
+        assert_eq!(port.read(0xffff, 4).unwrap(), u32::MAX);
+        assert!(port.write(0xfffe, 2, 0x1234).is_ok());
+    }
+}
@@ -0,0 +1,290 @@
+//! Which devices each vCPU of a VM emulates, see [`VcpuDeviceConfig`].
+//!
+//! hypercraft creates the per-vCPU devices with [`PerCpuDevices::new`](crate::PerCpuDevices)
+//! from the vCPU alone, so the VM being built hands its configuration over through
+//! [`set_vcpu_device_config`], for the CPU building it.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use axconfig::SMP;
+use axhal::current_cpu_id;
+use spin::Mutex;
+
+use super::cpuid::CpuidPolicy;
+use super::cr_access::CrPinning;
+#[cfg(feature = "legacy-pc-devices")]
+use super::device_emu::{HPET_BASE, HPET_BLOCK_ID, IOAPIC_BASE};
+use crate::acpi::AcpiPlatform;
+use crate::console_mux::MultiplexConsole;
+
+/// The devices emulated for each vCPU of a VM. The local APIC and its MSRs are always
+/// emulated, by the local APIC of the CPU unless [`VcpuDeviceConfig::with_virtual_apic`], its
+/// timer being the one of the CPU unless [`VcpuDeviceConfig::with_apic_timer`].
+///
+/// The default is the PC the guests have always been given, a minimal guest can start from
+/// [`VcpuDeviceConfig::empty`]:
+///
+/// ```ignore
+/// let devices = VcpuDeviceConfig::empty().with_uart(0x3f8);
+/// ```
+///
+/// The PICs and the IO APIC, the PIT and the CMOS, the HPET, the PS/2 controller, the reset
+/// control register, the watchdog, the debug ports and the dummy ports need the
+/// `legacy-pc-devices` feature, the VGA ports the `vga` feature. They are left out, with a
+/// warning, from a build without it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VcpuDeviceConfig {
+    pub(super) uarts: Vec<u16>,
+    pub(super) pic: bool,
+    pub(super) ioapic: bool,
+    pub(super) cmos: bool,
+    pub(super) hpet: bool,
+    pub(super) i8042: bool,
+    pub(super) reset_control: bool,
+    pub(super) watchdog: bool,
+    pub(super) debug_port: Option<u16>,
+    pub(super) debug_exit: Option<u16>,
+    pub(super) dummy_ports: Vec<(u16, u16)>,
+    pub(super) vga: bool,
+    /// The bus frequency of the emulated APIC timer, in Hz.
+    pub(super) apic_timer: Option<u64>,
+    pub(super) virtual_apic: bool,
+    pub(super) pmu: bool,
+    pub(super) console: Option<Arc<MultiplexConsole>>,
+    pub(super) cpuid: Option<Arc<CpuidPolicy>>,
+    pub(super) cr_pinning: CrPinning,
+}
+
+impl VcpuDeviceConfig {
+    /// No device but the local APIC.
+    pub const fn empty() -> Self {
+        Self {
+            uarts: Vec::new(),
+            pic: false,
+            ioapic: false,
+            cmos: false,
+            hpet: false,
+            i8042: false,
+            reset_control: false,
+            watchdog: false,
+            debug_port: None,
+            debug_exit: None,
+            dummy_ports: Vec::new(),
+            vga: false,
+            apic_timer: None,
+            virtual_apic: false,
+            pmu: false,
+            console: None,
+            cpuid: None,
+            cr_pinning: CrPinning { cr0: 0, cr4: 0 },
+        }
+    }
+
+    /// The devices of a PC: COM1 to COM4, the PICs and the IO APIC, the PIT and the CMOS, the PS/2
+    /// controller with a keyboard, the reset control register, the debug port at 0x80, the FPU and
+    /// DMA ports as dummies, and the VGA CRT controller.
+    pub fn pc() -> Self {
+        Self::empty()
+            .with_uart(0x3f8)
+            .with_uart(0x2f8)
+            .with_uart(0x3e8)
+            .with_uart(0x2e8)
+            .with_pic()
+            .with_ioapic()
+            .with_cmos()
+            .with_i8042()
+            .with_reset_control()
+            .with_debug_port(0x80)
+            .with_dummy_port(0xf0, 2)
+            .with_dummy_port(0x87, 1)
+            .with_vga()
+    }
+
+    /// A 16550 UART at ports `port..port + 8`. Only the COM1 to COM4 ports raise interrupts,
+    /// through the PIC.
+    pub fn with_uart(mut self, port: u16) -> Self {
+        self.uarts.push(port);
+        self
+    }
+
+    /// Whether a UART is at `port`, see [`VcpuDeviceConfig::with_uart`].
+    pub fn has_uart(&self, port: u16) -> bool {
+        self.uarts.contains(&port)
+    }
+
+    /// The master and slave 8259 PICs.
+    pub fn with_pic(mut self) -> Self {
+        self.pic = true;
+        self
+    }
+
+    /// An IO APIC at 0xfec0_0000, through which the IRQs of the emulated devices go once the guest
+    /// unmasks their pins, instead of the PICs.
+    pub fn with_ioapic(mut self) -> Self {
+        self.ioapic = true;
+        self
+    }
+
+    /// The PIT, the CMOS and the system control ports A and B, which share their state.
+    pub fn with_cmos(mut self) -> Self {
+        self.cmos = true;
+        self
+    }
+
+    /// An HPET at 0xfed0_0000, with 3 timers routed to the IO APIC, or to IRQs 0 and 8 in the
+    /// legacy replacement mode. A VM which maps the HPET of the machine there keeps accessing it
+    /// natively.
+    pub fn with_hpet(mut self) -> Self {
+        self.hpet = true;
+        self
+    }
+
+    /// The i8042 PS/2 controller at ports 0x60 and 0x64, with a keyboard raising IRQ 1 through
+    /// the PIC.
+    pub fn with_i8042(mut self) -> Self {
+        self.i8042 = true;
+        self
+    }
+
+    /// The reset control register at port 0xcf9, through which the guest resets the VM, see
+    /// [`ResetPolicy`](crate::ResetPolicy).
+    pub fn with_reset_control(mut self) -> Self {
+        self.reset_control = true;
+        self
+    }
+
+    /// An IB700 watchdog at ports 0x441 and 0x443, which resets the VM, as the reset control
+    /// register does, once the guest lets it run out.
+    pub fn with_watchdog(mut self) -> Self {
+        self.watchdog = true;
+        self
+    }
+
+    /// A POST debug port at `port`, whose writes are dropped.
+    pub fn with_debug_port(mut self, port: u16) -> Self {
+        self.debug_port = Some(port);
+        self
+    }
+
+    /// The QEMU `isa-debug-exit` port at `port`, 0x501 in QEMU: writing `value` shuts the VM
+    /// down with exit code `(value << 1) | 1`.
+    pub fn with_debug_exit(mut self, port: u16) -> Self {
+        self.debug_exit = Some(port);
+        self
+    }
+
+    /// Ports `port..port + len`, read as 0 and ignoring writes.
+    pub fn with_dummy_port(mut self, port: u16, len: u16) -> Self {
+        self.dummy_ports.push((port, len));
+        self
+    }
+
+    /// The ports of the VGA CRT controller, as dummies.
+    pub fn with_vga(mut self) -> Self {
+        self.vga = true;
+        self
+    }
+
+    /// An emulated local APIC timer, counting at `bus_freq_hz` before its divider, in place of
+    /// the timer of the CPU: its registers and IA32_TSC_DEADLINE exit, and its deadlines follow
+    /// the guest TSC across pauses of the VM. The guest calibrates the timer, or is told the
+    /// frequency, [`DEFAULT_APIC_BUS_FREQ_HZ`](crate::DEFAULT_APIC_BUS_FREQ_HZ) if it does not
+    /// matter.
+    pub fn with_apic_timer(mut self, bus_freq_hz: u64) -> Self {
+        self.apic_timer = Some(bus_freq_hz);
+        self
+    }
+
+    /// A virtual x2APIC, in place of the local APIC of the CPU, which the guest then cannot
+    /// touch: the interrupts of the vCPU are injected from its IRR as the guest EOIs them, and its
+    /// IPIs are posted to the virtual local APICs of their targets. Its timer is emulated, at the
+    /// frequency of [`Self::with_apic_timer`] if given.
+    pub fn with_virtual_apic(mut self) -> Self {
+        self.virtual_apic = true;
+        self
+    }
+
+    /// An emulated PMU, whose fixed counters and few general-purpose counters count the guest TSC
+    /// whatever their events, with CPUID leaf 0xa advertising exactly them whatever the CPUID
+    /// policy. Without it the PMU MSRs are unhandled, see
+    /// [`UnhandledMsrPolicy`](crate::UnhandledMsrPolicy).
+    pub fn with_pmu(mut self) -> Self {
+        self.pmu = true;
+        self
+    }
+
+    /// UARTs on `console`, shared with the other VMs given it, instead of writing to the host
+    /// console directly: the focus is switched with Ctrl-A then the id of a VM, and the output of
+    /// the VMs without it is kept for later. Only COM1 reads the input.
+    pub fn with_console(mut self, console: Arc<MultiplexConsole>) -> Self {
+        self.console = Some(console);
+        self
+    }
+
+    /// CPUID filtered through `policy`, instead of the CPUID of the CPU or the one `boot_vm`
+    /// picks for the type of the VM.
+    pub fn with_cpuid_policy(mut self, policy: CpuidPolicy) -> Self {
+        self.cpuid = Some(Arc::new(policy));
+        self
+    }
+
+    /// The bits of CR0 and CR4 the guest cannot clear once it set them, a write clearing one
+    /// taking #GP: [`CrPinning::hardened`] pins CR0.WP, CR4.SMEP, CR4.SMAP and CR4.UMIP. None by
+    /// default.
+    pub fn with_cr_pinning(mut self, pinning: CrPinning) -> Self {
+        self.cr_pinning = pinning;
+        self
+    }
+
+    /// [`Self::with_cpuid_policy`] unless a policy was given already.
+    pub(crate) fn or_cpuid_policy(mut self, policy: impl FnOnce() -> CpuidPolicy) -> Self {
+        self.cpuid.get_or_insert_with(|| Arc::new(policy()));
+        self
+    }
+
+    /// What the ACPI tables of a VM with `vcpus` vCPUs and these devices describe, the devices
+    /// left out of the build excluded.
+    pub(crate) fn acpi_platform(&self, vcpus: usize) -> AcpiPlatform {
+        let legacy = cfg!(feature = "legacy-pc-devices");
+        #[cfg(feature = "legacy-pc-devices")]
+        let (ioapic, hpet) = (
+            self.ioapic.then_some(IOAPIC_BASE),
+            self.hpet.then_some((HPET_BASE, HPET_BLOCK_ID)),
+        );
+        #[cfg(not(feature = "legacy-pc-devices"))]
+        let (ioapic, hpet) = (None, None);
+        AcpiPlatform {
+            vcpus,
+            pic: legacy && self.pic,
+            ioapic,
+            hpet,
+            cmos: legacy && self.cmos,
+            i8042: legacy && self.i8042,
+            vga: cfg!(feature = "vga") && self.vga,
+        }
+    }
+}
+
+impl Default for VcpuDeviceConfig {
+    fn default() -> Self {
+        Self::pc()
+    }
+}
+
+const NO_CONFIG: Mutex<Option<VcpuDeviceConfig>> = Mutex::new(None);
+/// The configuration of the vCPUs created on each CPU, indexed by cpu id.
+static BUILDING: [Mutex<Option<VcpuDeviceConfig>>; SMP] = [NO_CONFIG; SMP];
+
+/// Give the vCPUs created on the current CPU from now on the devices of `config`, the default
+/// ones if `None`.
+pub(crate) fn set_vcpu_device_config(config: Option<VcpuDeviceConfig>) {
+    *BUILDING[current_cpu_id()].lock() = config;
+}
+
+/// The configuration of a vCPU being created on the current CPU.
+pub(super) fn vcpu_device_config() -> VcpuDeviceConfig {
+    BUILDING[current_cpu_id()]
+        .lock()
+        .clone()
+        .unwrap_or_default()
+}
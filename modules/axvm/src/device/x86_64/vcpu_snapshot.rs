@@ -0,0 +1,290 @@
+//! The state of the vCPUs of a paused VM, taken over by the vCPUs of its forks and of the VMs
+//! restored from its snapshots.
+//!
+//! A vCPU parking for a pause of a guest saves its general registers, the guest state of its
+//! VMCS and the state of its devices, as [`DeviceList::save_all`] has it: the UARTs, the PICs, the
+//! local APIC, the x87, SSE and AVX state and the rest of the stateful devices. The vCPU of the
+//! same id of a fork, or of a restored VM, restores it before its first VM entry, see
+//! [`crate::fork_vm`] and [`crate::restore_vm`]; a state it rejects stops the VM. The MSRs the
+//! VMCS does not hold, e.g. `IA32_LSTAR`, the events pending in hypercraft and the per-VM devices
+//! are not part of it.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use hypercraft::{HyperCraftHal, VCpu};
+use spin::Mutex;
+use x86::bits64::vmx::{vmread, vmwrite};
+use x86::vmx::vmcs;
+
+use super::{BarAllocTrait, DeviceList};
+use crate::device::{StateReader, StateWriter};
+use crate::{Error as HyperError, Result as HyperResult};
+
+const VCPU_SNAPSHOT_VERSION: u16 = 1;
+
+/// The VMCS fields of a snapshot.
+const VMCS_FIELDS: [u32; 53] = [
+    vmcs::guest::RIP,
+    vmcs::guest::RSP,
+    vmcs::guest::RFLAGS,
+    vmcs::guest::CR0,
+    vmcs::guest::CR3,
+    vmcs::guest::CR4,
+    vmcs::guest::DR7,
+    vmcs::guest::ES_SELECTOR,
+    vmcs::guest::ES_BASE,
+    vmcs::guest::ES_LIMIT,
+    vmcs::guest::ES_ACCESS_RIGHTS,
+    vmcs::guest::CS_SELECTOR,
+    vmcs::guest::CS_BASE,
+    vmcs::guest::CS_LIMIT,
+    vmcs::guest::CS_ACCESS_RIGHTS,
+    vmcs::guest::SS_SELECTOR,
+    vmcs::guest::SS_BASE,
+    vmcs::guest::SS_LIMIT,
+    vmcs::guest::SS_ACCESS_RIGHTS,
+    vmcs::guest::DS_SELECTOR,
+    vmcs::guest::DS_BASE,
+    vmcs::guest::DS_LIMIT,
+    vmcs::guest::DS_ACCESS_RIGHTS,
+    vmcs::guest::FS_SELECTOR,
+    vmcs::guest::FS_BASE,
+    vmcs::guest::FS_LIMIT,
+    vmcs::guest::FS_ACCESS_RIGHTS,
+    vmcs::guest::GS_SELECTOR,
+    vmcs::guest::GS_BASE,
+    vmcs::guest::GS_LIMIT,
+    vmcs::guest::GS_ACCESS_RIGHTS,
+    vmcs::guest::LDTR_SELECTOR,
+    vmcs::guest::LDTR_BASE,
+    vmcs::guest::LDTR_LIMIT,
+    vmcs::guest::LDTR_ACCESS_RIGHTS,
+    vmcs::guest::TR_SELECTOR,
+    vmcs::guest::TR_BASE,
+    vmcs::guest::TR_LIMIT,
+    vmcs::guest::TR_ACCESS_RIGHTS,
+    vmcs::guest::GDTR_BASE,
+    vmcs::guest::GDTR_LIMIT,
+    vmcs::guest::IDTR_BASE,
+    vmcs::guest::IDTR_LIMIT,
+    vmcs::guest::IA32_EFER_FULL,
+    vmcs::guest::IA32_PAT_FULL,
+    vmcs::guest::IA32_SYSENTER_CS,
+    vmcs::guest::IA32_SYSENTER_ESP,
+    vmcs::guest::IA32_SYSENTER_EIP,
+    vmcs::guest::INTERRUPTIBILITY_STATE,
+    vmcs::guest::ACTIVITY_STATE,
+    vmcs::guest::PENDING_DBG_EXCEPTIONS,
+    vmcs::control::CR0_READ_SHADOW,
+    vmcs::control::CR4_READ_SHADOW,
+];
+
+/// The snapshots of the vCPUs parked for a pause, by VM and vCPU id.
+static PAUSED: Mutex<BTreeMap<(u32, u32), Arc<VcpuSnapshot>>> = Mutex::new(BTreeMap::new());
+/// The snapshots the vCPUs of the forks and of the restored VMs take over, by VM and vCPU id.
+static PENDING: Mutex<BTreeMap<(u32, u32), Arc<VcpuSnapshot>>> = Mutex::new(BTreeMap::new());
+/// Size of [`PENDING`], lets `check_events` skip the lookup.
+static PENDING_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The state of a vCPU, see the [module documentation](self).
+pub(crate) struct VcpuSnapshot {
+    /// RAX to R15 in encoding order, RSP aside.
+    gprs: [u64; 15],
+    vmcs: Vec<(u32, u64)>,
+    devices: Vec<u8>,
+}
+
+impl VcpuSnapshot {
+    /// Save `vcpu`, whose VMCS is loaded on the current CPU, and `devices`.
+    fn save<H: HyperCraftHal, B: BarAllocTrait + 'static>(
+        vcpu: &VCpu<H>,
+        devices: &DeviceList<H, B>,
+    ) -> Option<Self> {
+        let regs = vcpu.regs();
+        let gprs = [
+            regs.rax, regs.rcx, regs.rdx, regs.rbx, regs.rbp, regs.rsi, regs.rdi, regs.r8, regs.r9,
+            regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+        ];
+        let vmcs = VMCS_FIELDS
+            .iter()
+            .map(|&field| unsafe { vmread(field) }.map(|value| (field, value)))
+            .collect::<Result<Vec<_>, _>>();
+        let vmcs = match vmcs {
+            Ok(vmcs) => vmcs,
+            Err(err) => {
+                warn!(
+                    "vCPU [{}] failed to save its VMCS: {:?}",
+                    vcpu.vcpu_id(),
+                    err
+                );
+                return None;
+            }
+        };
+        Some(Self {
+            gprs,
+            vmcs,
+            devices: devices.save_all(),
+        })
+    }
+
+    /// Restore the snapshot to `vcpu`, whose VMCS is loaded on the current CPU, and `devices`.
+    fn restore<H: HyperCraftHal, B: BarAllocTrait + 'static>(
+        &self,
+        vcpu: &mut VCpu<H>,
+        devices: &DeviceList<H, B>,
+    ) -> HyperResult {
+        let regs = vcpu.regs_mut();
+        [
+            regs.rax, regs.rcx, regs.rdx, regs.rbx, regs.rbp, regs.rsi, regs.rdi, regs.r8, regs.r9,
+            regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+        ] = self.gprs;
+        for &(field, value) in self.vmcs.iter() {
+            if let Err(err) = unsafe { vmwrite(field, value) } {
+                warn!(
+                    "vCPU [{}] failed to restore VMCS field {:#x}: {:?}",
+                    vcpu.vcpu_id(),
+                    field,
+                    err
+                );
+                return Err(HyperError::BadState);
+            }
+        }
+        devices.restore_all(&self.devices)
+    }
+
+    /// The snapshot encoded as a device state, see [`crate::device::DeviceState`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(VCPU_SNAPSHOT_VERSION);
+        for &reg in self.gprs.iter() {
+            state.u64(reg);
+        }
+        state.u32(self.vmcs.len() as u32);
+        for &(field, value) in self.vmcs.iter() {
+            state.u32(field).u64(value);
+        }
+        state.bytes(&self.devices);
+        state.finish()
+    }
+
+    /// Decode a snapshot encoded by [`VcpuSnapshot::to_bytes`]. Fails with `NotSupported` for
+    /// another version, with `InvalidParam` for a field which is not one of a snapshot.
+    pub fn from_bytes(bytes: &[u8]) -> HyperResult<Self> {
+        let mut state = StateReader::new(bytes, VCPU_SNAPSHOT_VERSION)?;
+        let mut gprs = [0; 15];
+        for reg in gprs.iter_mut() {
+            *reg = state.u64()?;
+        }
+        let fields = state.u32()? as usize;
+        if fields > VMCS_FIELDS.len() {
+            return Err(HyperError::InvalidParam);
+        }
+        let mut vmcs = Vec::with_capacity(fields);
+        for _ in 0..fields {
+            let field = state.u32()?;
+            if !VMCS_FIELDS.contains(&field) {
+                warn!("vCPU snapshot with VMCS field {:#x}", field);
+                return Err(HyperError::InvalidParam);
+            }
+            vmcs.push((field, state.u64()?));
+        }
+        let devices = state.bytes()?.to_vec();
+        state.finish()?;
+        Ok(Self {
+            gprs,
+            vmcs,
+            devices,
+        })
+    }
+}
+
+/// Save the snapshot of `vcpu` of the guest `vm_id`, parking for a pause.
+pub(super) fn save_paused<H: HyperCraftHal, B: BarAllocTrait + 'static>(
+    vm_id: u32,
+    vcpu: &VCpu<H>,
+    devices: &DeviceList<H, B>,
+) {
+    if let Some(snapshot) = VcpuSnapshot::save(vcpu, devices) {
+        PAUSED
+            .lock()
+            .insert((vm_id, vcpu.vcpu_id() as u32), Arc::new(snapshot));
+    }
+}
+
+/// Restore to `vcpu` of `vm_id` the snapshot it takes over, if any.
+pub(super) fn restore_pending<H: HyperCraftHal, B: BarAllocTrait + 'static>(
+    vm_id: u32,
+    vcpu: &mut VCpu<H>,
+    devices: &DeviceList<H, B>,
+) -> HyperResult {
+    if PENDING_COUNT.load(Ordering::Acquire) == 0 {
+        return Ok(());
+    }
+    let snapshot = {
+        let mut pending = PENDING.lock();
+        let snapshot = pending.remove(&(vm_id, vcpu.vcpu_id() as u32));
+        PENDING_COUNT.store(pending.len(), Ordering::Release);
+        snapshot
+    };
+    let Some(snapshot) = snapshot else {
+        return Ok(());
+    };
+    snapshot.restore(vcpu, devices).map_err(|err| {
+        warn!(
+            "VM [{}] vCPU [{}] rejected its snapshot: {:?}",
+            vm_id,
+            vcpu.vcpu_id(),
+            err
+        );
+        err
+    })?;
+    debug!("VM [{}] vCPU [{}] restored", vm_id, vcpu.vcpu_id());
+    Ok(())
+}
+
+/// Whether vCPU `vcpu_id` of the paused VM `vm_id` saved its snapshot, or is an AP which never
+/// started: a fork or a snapshot of the VM may be taken.
+pub(crate) fn vcpu_state_saved(vm_id: u32, vcpu_id: u32) -> bool {
+    PAUSED.lock().contains_key(&(vm_id, vcpu_id)) || super::ipi::waits_for_sipi(vm_id, vcpu_id)
+}
+
+/// The snapshots saved by the vCPUs of the paused VM `vm_id`, by vCPU id.
+pub(crate) fn paused_snapshots(vm_id: u32) -> Vec<(u32, Arc<VcpuSnapshot>)> {
+    PAUSED
+        .lock()
+        .iter()
+        .filter(|(&(vm, _), _)| vm == vm_id)
+        .map(|(&(_, vcpu_id), snapshot)| (vcpu_id, snapshot.clone()))
+        .collect()
+}
+
+/// Have the vCPUs of `vm_id` take over `snapshots`, by vCPU id, before their first VM entry.
+pub(crate) fn set_pending_snapshots(vm_id: u32, snapshots: Vec<(u32, Arc<VcpuSnapshot>)>) {
+    let mut pending = PENDING.lock();
+    pending.extend(
+        snapshots
+            .into_iter()
+            .map(|(vcpu_id, snapshot)| ((vm_id, vcpu_id), snapshot)),
+    );
+    PENDING_COUNT.store(pending.len(), Ordering::Release);
+}
+
+/// Whether vCPU `vcpu_id` of `vm_id` takes over a snapshot, and does not start as an AP does.
+pub(crate) fn has_pending_snapshot(vm_id: u32, vcpu_id: u32) -> bool {
+    PENDING.lock().contains_key(&(vm_id, vcpu_id))
+}
+
+/// Drop the snapshots saved by the vCPUs of `vm_id`, once it is resumed.
+pub(crate) fn discard_paused_snapshots(vm_id: u32) {
+    PAUSED.lock().retain(|&(vm, _), _| vm != vm_id);
+}
+
+/// Drop the snapshots of `vm_id`, once the VM stopped.
+pub(crate) fn unregister_vm_snapshots(vm_id: u32) {
+    discard_paused_snapshots(vm_id);
+    let mut pending = PENDING.lock();
+    pending.retain(|&(vm, _), _| vm != vm_id);
+    PENDING_COUNT.store(pending.len(), Ordering::Release);
+}
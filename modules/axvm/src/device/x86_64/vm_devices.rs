@@ -0,0 +1,73 @@
+//! The per-VM device list of each VM, shared by its vCPUs.
+//!
+//! Every vCPU creates the per-VM devices of its VM, the BSP and each AP, in whatever order they
+//! get there. The first one builds the device list, with the PCI host, the virtio and the shmem
+//! devices of the VM, and registers its ranges with the [`dispatch`] chain, and the others take
+//! the same list. Each vCPU keeps its own [`DispatchCache`] of it. The devices attached with
+//! [`hotplug`] go to its tables, the only ones of the VM. The list is dropped once the VM exited
+//! and its last vCPU left, a reboot building a new one.
+//!
+//! [`DispatchCache`]: super::DispatchCache
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::any::Any;
+
+use pci::BarAllocTrait;
+
+use super::{dispatch, DeviceList, SharedTables};
+use crate::{Error as HyperError, HyperCraftHal, Result as HyperResult};
+
+/// The per-VM device list of a VM.
+struct VmDevices {
+    /// A `DeviceList` of the types of the [`PerVmDevices`] of the VM.
+    ///
+    /// [`PerVmDevices`]: crate::PerVmDevices
+    list: Arc<dyn Any + Send + Sync>,
+    /// The tables of `list`.
+    tables: Arc<SharedTables>,
+}
+
+static VM_DEVICES: spin::Mutex<BTreeMap<u32, VmDevices>> = spin::Mutex::new(BTreeMap::new());
+
+/// The per-VM device list of `vm_id`, built with `build` if none of its vCPUs did yet. Fails as
+/// `build` does, and with `BadState` if the VM has a list of other types.
+pub(super) fn shared<H: HyperCraftHal, B: BarAllocTrait + 'static>(
+    vm_id: u32,
+    build: impl FnOnce() -> HyperResult<DeviceList<H, B>>,
+) -> HyperResult<Arc<DeviceList<H, B>>>
+where
+    DeviceList<H, B>: Send + Sync + 'static,
+{
+    let mut lists = VM_DEVICES.lock();
+    if let Some(devices) = lists.get(&vm_id) {
+        return devices.list.clone().downcast().map_err(|_| {
+            warn!("VM [{}] has per-VM devices of another type", vm_id);
+            HyperError::BadState
+        });
+    }
+    // Under the lock, the other vCPUs of the VM wait for the list instead of building theirs.
+    let devices = Arc::new(build()?);
+    dispatch::register_vm_ranges(vm_id, devices.claimed_ranges());
+    let tables = devices.tables.clone();
+    lists.insert(
+        vm_id,
+        VmDevices {
+            list: devices.clone(),
+            tables,
+        },
+    );
+    Ok(devices)
+}
+
+/// The tables of the per-VM device list of `vm_id`, if one of its vCPUs built it.
+pub(super) fn tables(vm_id: u32) -> Option<Arc<SharedTables>> {
+    VM_DEVICES
+        .lock()
+        .get(&vm_id)
+        .map(|devices| devices.tables.clone())
+}
+
+pub(crate) fn unregister_vm_devices(vm_id: u32) {
+    VM_DEVICES.lock().remove(&vm_id);
+}
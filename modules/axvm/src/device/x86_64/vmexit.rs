@@ -0,0 +1,610 @@
+//! VM-exit bookkeeping shared by the per-vCPU and per-VM exit handlers.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axconfig::SMP;
+use axhal::current_cpu_id;
+use bit_field::BitField;
+use iced_x86::{Decoder, DecoderOptions, Instruction};
+use page_table_entry::MappingFlags;
+use spin::{Mutex, RwLock};
+use x86::bits64::vmx::vmread;
+use x86::vmx::vmcs;
+
+use super::cr_access::GuestMode;
+use super::decode_cache::GuestCode;
+use crate::{
+    Error as HyperError, HyperCraftHal, Result as HyperResult, VCpu, VmExitInfo, VmState,
+    VmxExitReason,
+};
+
+/// Number of exits kept in the exit history of each vCPU.
+pub const EXIT_HISTORY_LEN: usize = 32;
+
+/// One entry of the exit history.
+#[derive(Debug, Clone, Copy)]
+pub struct ExitRecord {
+    pub vcpu_id: usize,
+    pub exit_reason: VmxExitReason,
+    pub guest_rip: usize,
+    pub qualification: u64,
+    pub timestamp_ns: u64,
+}
+
+/// Ring buffer of the most recent VM exits.
+#[derive(Clone)]
+pub struct ExitHistory {
+    records: [Option<ExitRecord>; EXIT_HISTORY_LEN],
+    next: usize,
+}
+
+impl ExitHistory {
+    const fn new() -> Self {
+        Self {
+            records: [None; EXIT_HISTORY_LEN],
+            next: 0,
+        }
+    }
+
+    pub fn push(&mut self, record: ExitRecord) {
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % EXIT_HISTORY_LEN;
+    }
+
+    /// The most recently recorded exit.
+    pub fn last(&self) -> Option<&ExitRecord> {
+        self.records[(self.next + EXIT_HISTORY_LEN - 1) % EXIT_HISTORY_LEN].as_ref()
+    }
+
+    /// Iterate over the recorded exits, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &ExitRecord> {
+        (0..EXIT_HISTORY_LEN)
+            .filter_map(move |i| self.records[(self.next + i) % EXIT_HISTORY_LEN].as_ref())
+    }
+}
+
+/// What to do with a VM whose vCPU is caught by the exit watchdog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogPolicy {
+    /// Pause the VM until it is resumed (or stopped) from outside.
+    Pause,
+    /// Crash the VM, as for an unhandled VM exit.
+    Crash,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WatchdogConfig {
+    /// The watchdog fires when the same exit repeats more than `threshold` times in a row...
+    pub threshold: u32,
+    /// ... within `window_ns` nanoseconds.
+    pub window_ns: u64,
+    pub policy: WatchdogPolicy,
+}
+
+static WATCHDOG_CONFIG: RwLock<WatchdogConfig> = RwLock::new(WatchdogConfig {
+    threshold: 500_000,
+    window_ns: 1_000_000_000,
+    policy: WatchdogPolicy::Crash,
+});
+
+pub fn set_exit_watchdog(config: WatchdogConfig) {
+    info!("VM-exit watchdog: {:?}", config);
+    *WATCHDOG_CONFIG.write() = config;
+}
+
+/// Detector of a vCPU stuck on the same VM exit, e.g. because an emulated device failed to
+/// advance RIP.
+struct ExitWatchdog {
+    /// (exit reason, guest RIP, exit qualification) of the current streak.
+    key: Option<(VmxExitReason, usize, u64)>,
+    count: u32,
+    window_start_ns: u64,
+    /// Set by a handler which intentionally leaves RIP unchanged (e.g. REP emulation that
+    /// resumes the guest in the middle of the instruction), consumed by the next exit.
+    intentional_repeat: bool,
+}
+
+impl ExitWatchdog {
+    const fn new() -> Self {
+        Self {
+            key: None,
+            count: 0,
+            window_start_ns: 0,
+            intentional_repeat: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.key = None;
+        self.count = 0;
+    }
+
+    /// Feed an exit into the watchdog, returns the repeat count if it fires.
+    fn check(&mut self, record: &ExitRecord, config: &WatchdogConfig) -> Option<u32> {
+        let key = (record.exit_reason, record.guest_rip, record.qualification);
+        let intentional = core::mem::replace(&mut self.intentional_repeat, false);
+        if self.key != Some(key) || record.timestamp_ns - self.window_start_ns > config.window_ns {
+            self.key = Some(key);
+            self.count = 1;
+            self.window_start_ns = record.timestamp_ns;
+            return None;
+        }
+        if !intentional {
+            self.count += 1;
+        }
+        if self.count > config.threshold {
+            let count = self.count;
+            self.reset();
+            Some(count)
+        } else {
+            None
+        }
+    }
+}
+
+/// The exit history and the exit watchdog of a vCPU.
+struct VcpuExitLog {
+    history: ExitHistory,
+    watchdog: ExitWatchdog,
+}
+
+type ExitLogRef = Arc<Mutex<VcpuExitLog>>;
+
+/// The exit logs of the vCPUs, by VM and vCPU id. They follow their vCPU, whichever CPU runs it
+/// and whichever vCPUs share the CPU.
+static EXIT_LOGS: RwLock<BTreeMap<(u32, usize), ExitLogRef>> = RwLock::new(BTreeMap::new());
+
+const NO_LOG: Mutex<Option<((u32, usize), ExitLogRef)>> = Mutex::new(None);
+/// The exit log of the vCPU whose exit is handled on each physical CPU, with its VM and vCPU id.
+static CURRENT_LOG: [Mutex<Option<((u32, usize), ExitLogRef)>>; SMP] = [NO_LOG; SMP];
+
+/// The exit log of vCPU `vcpu_id` of `vm_id`, created on its first exit.
+fn exit_log(vm_id: u32, vcpu_id: usize) -> ExitLogRef {
+    if let Some(log) = EXIT_LOGS.read().get(&(vm_id, vcpu_id)) {
+        return log.clone();
+    }
+    EXIT_LOGS
+        .write()
+        .entry((vm_id, vcpu_id))
+        .or_insert_with(|| {
+            Arc::new(Mutex::new(VcpuExitLog {
+                history: ExitHistory::new(),
+                watchdog: ExitWatchdog::new(),
+            }))
+        })
+        .clone()
+}
+
+/// Make the exit log of vCPU `vcpu_id` of `vm_id` the one of the exit handled on the current CPU,
+/// and return it.
+fn current_log(vm_id: u32, vcpu_id: usize) -> ExitLogRef {
+    let mut current = CURRENT_LOG[current_cpu_id()].lock();
+    match current.as_ref() {
+        Some((key, log)) if *key == (vm_id, vcpu_id) => log.clone(),
+        _ => {
+            let log = exit_log(vm_id, vcpu_id);
+            *current = Some(((vm_id, vcpu_id), log.clone()));
+            log
+        }
+    }
+}
+
+/// The recent exits of vCPU `vcpu_id` of `vm_id`, `None` if it has not exited yet.
+pub(crate) fn exit_history(vm_id: u32, vcpu_id: usize) -> Option<ExitHistory> {
+    let log = EXIT_LOGS.read().get(&(vm_id, vcpu_id))?.clone();
+    let history = log.lock().history.clone();
+    Some(history)
+}
+
+/// Forget the exit logs of the vCPUs of a VM which stopped.
+pub(crate) fn remove_vm_exit_logs(vm_id: u32) {
+    EXIT_LOGS.write().retain(|&(vm, _), _| vm != vm_id);
+    for current in CURRENT_LOG.iter() {
+        let mut current = current.lock();
+        if matches!(current.as_ref(), Some(((vm, _), _)) if *vm == vm_id) {
+            *current = None;
+        }
+    }
+}
+
+/// Tell the exit watchdog that the exit being handled will legitimately repeat with the same
+/// guest RIP and qualification, so that it is not counted as a stuck vCPU.
+pub(crate) fn mark_intentional_repeat() {
+    if let Some((_, log)) = CURRENT_LOG[current_cpu_id()].lock().as_ref() {
+        log.lock().watchdog.intentional_repeat = true;
+    }
+}
+
+/// Exits which are not caused by the guest instruction at RIP, they neither count towards nor
+/// break a watchdog streak.
+fn is_async_exit(exit_reason: VmxExitReason) -> bool {
+    matches!(
+        exit_reason,
+        VmxExitReason::EXTERNAL_INTERRUPT
+            | VmxExitReason::EXCEPTION_NMI
+            | VmxExitReason::INTERRUPT_WINDOW
+            | VmxExitReason::NMI_WINDOW
+            | VmxExitReason::PREEMPTION_TIMER
+    )
+}
+
+/// The VMREADs of the exit handlers on a physical CPU, see [`vmcs_read_stats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VmcsReadStats {
+    /// Exits recorded by the exit handlers.
+    pub exits: u64,
+    /// VMREADs through [`vmcs_read`]. Hypercraft reads the exit reason, RIP and instruction
+    /// length of every exit on its own, and the VMCS setup paths use `vmread` directly.
+    pub vmcs_reads: u64,
+}
+
+const NO_COUNT: AtomicU64 = AtomicU64::new(0);
+static VMCS_READS: [AtomicU64; SMP] = [NO_COUNT; SMP];
+static RECORDED_EXITS: [AtomicU64; SMP] = [NO_COUNT; SMP];
+
+/// The VMREADs of the exit handlers on physical CPU `cpu_id`, and its exits, since boot.
+pub fn vmcs_read_stats(cpu_id: usize) -> VmcsReadStats {
+    VmcsReadStats {
+        exits: RECORDED_EXITS[cpu_id].load(Ordering::Relaxed),
+        vmcs_reads: VMCS_READS[cpu_id].load(Ordering::Relaxed),
+    }
+}
+
+/// Read a field of the VMCS loaded on the current CPU, or 0 if the read fails.
+pub(crate) fn vmcs_read(field: u32) -> u64 {
+    VMCS_READS[current_cpu_id()].fetch_add(1, Ordering::Relaxed);
+    unsafe { vmread(field) }.unwrap_or(0)
+}
+
+/// Decoded exit qualification of an I/O instruction exit (SDM Vol. 3C, Table 28-5).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IoExitInfo {
+    pub port: u16,
+    pub access_size: u8,
+    pub is_in: bool,
+    pub is_string: bool,
+    pub is_repeat: bool,
+}
+
+/// Decoded EPT violation (SDM Vol. 3C, Table 28-7).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct EptViolationInfo {
+    pub fault_guest_paddr: u64,
+    pub access_flags: MappingFlags,
+}
+
+/// Decoded VM-exit interruption information (SDM Vol. 3C, Section 25.9.2).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ExitInterruptionInfo {
+    pub vector: u8,
+    pub int_type: u8,
+    pub valid: bool,
+    pub raw: u32,
+}
+
+/// The VMCS exit information of the VM exit being handled on the current CPU.
+///
+/// Every VMREAD costs, and the handlers of an exit used to read the same fields over and over
+/// (the qualification of an I/O exit was read three times). The context is captured once when
+/// the exit is recorded: the fields needed by every exit are read eagerly, the rarely needed
+/// ones on first use, then kept for the rest of the exit.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ExitContext {
+    pub exit_reason: VmxExitReason,
+    pub guest_rip: usize,
+    pub exit_instruction_length: u32,
+    pub qualification: u64,
+    instruction_info: Option<u32>,
+    guest_linear_addr: Option<u64>,
+    guest_phys_addr: Option<u64>,
+    interruption_info: Option<u32>,
+    /// The mode tracked by the vCPU, see [`with_guest_mode`](Self::with_guest_mode).
+    guest_mode: Option<GuestMode>,
+}
+
+impl ExitContext {
+    /// Capture the context of the current VM exit, reading the exit qualification.
+    pub fn new(exit_info: &VmExitInfo) -> Self {
+        Self {
+            exit_reason: exit_info.exit_reason,
+            guest_rip: exit_info.guest_rip,
+            exit_instruction_length: exit_info.exit_instruction_length,
+            qualification: vmcs_read(vmcs::ro::EXIT_QUALIFICATION),
+            instruction_info: None,
+            guest_linear_addr: None,
+            guest_phys_addr: None,
+            interruption_info: None,
+            guest_mode: None,
+        }
+    }
+
+    /// The context of an exit of `exit_reason` with `qualification`, as if captured from the
+    /// VMCS, for the handlers run without VMX.
+    #[cfg(test)]
+    pub fn synthetic(exit_reason: VmxExitReason, instr_len: u32, qualification: u64) -> Self {
+        Self {
+            exit_reason,
+            guest_rip: 0,
+            exit_instruction_length: instr_len,
+            qualification,
+            instruction_info: None,
+            guest_linear_addr: None,
+            guest_phys_addr: None,
+            interruption_info: None,
+            guest_mode: None,
+        }
+    }
+
+    /// This context, the guest being in `mode`, as the control register accesses of the vCPU
+    /// tracked it.
+    pub fn with_guest_mode(mut self, mode: GuestMode) -> Self {
+        self.guest_mode = Some(mode);
+        self
+    }
+
+    /// The context of the current VM exit, as captured by [`record_exit`].
+    ///
+    /// Falls back to capturing a new one if the recorded context is not the one of `exit_info`.
+    pub fn current(exit_info: &VmExitInfo) -> Self {
+        match *CURRENT_EXIT[current_cpu_id()].lock() {
+            Some(ctx)
+                if ctx.exit_reason == exit_info.exit_reason
+                    && ctx.guest_rip == exit_info.guest_rip =>
+            {
+                ctx
+            }
+            _ => Self::new(exit_info),
+        }
+    }
+
+    fn cached(slot: &mut Option<u32>, field: u32) -> u32 {
+        *slot.get_or_insert_with(|| vmcs_read(field) as u32)
+    }
+
+    /// VM-exit instruction information, for the exits which provide it.
+    pub fn instruction_info(&mut self) -> u32 {
+        Self::cached(
+            &mut self.instruction_info,
+            vmcs::ro::VMEXIT_INSTRUCTION_INFO,
+        )
+    }
+
+    /// Guest linear address, if the exit provides a valid one.
+    pub fn guest_linear_addr(&mut self) -> Option<u64> {
+        // For EPT violations, bit 7 of the qualification tells whether the field is valid.
+        if self.exit_reason == VmxExitReason::EPT_VIOLATION && !self.qualification.get_bit(7) {
+            return None;
+        }
+        Some(
+            *self
+                .guest_linear_addr
+                .get_or_insert_with(|| vmcs_read(vmcs::ro::GUEST_LINEAR_ADDR)),
+        )
+    }
+
+    /// Guest physical address of an EPT violation or misconfiguration.
+    pub fn guest_phys_addr(&mut self) -> u64 {
+        *self
+            .guest_phys_addr
+            .get_or_insert_with(|| vmcs_read(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL))
+    }
+
+    /// VM-exit interruption information, for exits caused by an interrupt or an exception.
+    pub fn interruption_info(&mut self) -> ExitInterruptionInfo {
+        let raw = Self::cached(
+            &mut self.interruption_info,
+            vmcs::ro::VMEXIT_INTERRUPTION_INFO,
+        );
+        ExitInterruptionInfo {
+            vector: raw.get_bits(0..8) as u8,
+            int_type: raw.get_bits(8..11) as u8,
+            valid: raw.get_bit(31),
+            raw,
+        }
+    }
+
+    /// The mode of the guest, read from the VMCS if the context was not given it.
+    pub fn guest_mode(&self) -> GuestMode {
+        self.guest_mode.unwrap_or_else(GuestMode::current)
+    }
+
+    /// The decoded qualification of an I/O instruction exit.
+    pub fn io_exit_info(&self) -> HyperResult<IoExitInfo> {
+        if self.exit_reason != VmxExitReason::IO_INSTRUCTION {
+            return Err(HyperError::BadState);
+        }
+        let qual = self.qualification;
+        Ok(IoExitInfo {
+            port: qual.get_bits(16..32) as u16,
+            access_size: qual.get_bits(0..3) as u8 + 1,
+            is_in: qual.get_bit(3),
+            is_string: qual.get_bit(4),
+            is_repeat: qual.get_bit(5),
+        })
+    }
+
+    /// The faulting address and access of an EPT violation.
+    pub fn ept_violation_info(&mut self) -> HyperResult<EptViolationInfo> {
+        if self.exit_reason != VmxExitReason::EPT_VIOLATION {
+            return Err(HyperError::BadState);
+        }
+        let qual = self.qualification;
+        let mut access_flags = MappingFlags::empty();
+        if qual.get_bit(0) {
+            access_flags |= MappingFlags::READ;
+        }
+        if qual.get_bit(1) {
+            access_flags |= MappingFlags::WRITE;
+        }
+        if qual.get_bit(2) {
+            access_flags |= MappingFlags::EXECUTE;
+        }
+        Ok(EptViolationInfo {
+            fault_guest_paddr: self.guest_phys_addr(),
+            access_flags,
+        })
+    }
+}
+
+/// The instruction at guest RIP of the current exit, its bytes read and decoded at most once,
+/// and only if a handler asks for them.
+///
+/// Only the emulation of MMIO accesses needs the instruction, the other exits are described by
+/// the VMCS exit information alone.
+pub(crate) struct LazyInstr {
+    vm_id: Option<u32>,
+    ctx: ExitContext,
+    /// The instruction decoded by hypercraft, if any, used when the bytes at RIP cannot be read
+    /// or decoded, e.g. for an instruction crossing a page.
+    fallback: Option<Instruction>,
+    code: Option<Option<GuestCode>>,
+    decoded: Option<Option<Instruction>>,
+}
+
+impl LazyInstr {
+    /// The instruction at RIP of the exit of `ctx`, in the memory of the VM running on the
+    /// current CPU.
+    pub fn new(ctx: &ExitContext, fallback: Option<Instruction>) -> Self {
+        Self {
+            vm_id: crate::vm::current_vm_id(),
+            ctx: *ctx,
+            fallback,
+            code: None,
+            decoded: None,
+        }
+    }
+
+    /// The bytes at RIP, read on the first call, in the mode of the guest at the exit.
+    pub(super) fn code(&mut self) -> Option<&GuestCode> {
+        let (vm_id, ctx) = (self.vm_id, &self.ctx);
+        self.code
+            .get_or_insert_with(|| vm_id.and_then(|vm_id| GuestCode::peek(vm_id, ctx)))
+            .as_ref()
+    }
+
+    /// The instruction decoded from [`Self::code`], on the first call.
+    pub fn decoded(&mut self) -> Option<&Instruction> {
+        if self.decoded.is_none() {
+            let decoded = self
+                .code()
+                .map(|code| Decoder::new(code.bitness, code.bytes(), DecoderOptions::NONE).decode())
+                .filter(|instr| !instr.is_invalid());
+            self.decoded = Some(decoded);
+        }
+        self.decoded.as_ref().and_then(Option::as_ref)
+    }
+
+    /// The instruction, [`Self::decoded`] if the bytes at RIP decode, else the one decoded by
+    /// hypercraft.
+    pub fn get(&mut self) -> Option<&Instruction> {
+        self.decoded();
+        match &self.decoded {
+            Some(Some(instr)) => Some(instr),
+            _ => self.fallback.as_ref(),
+        }
+    }
+}
+
+const NO_EXIT: Mutex<Option<ExitContext>> = Mutex::new(None);
+/// Context of the exit being handled on each physical CPU, shared by the per-vCPU and per-VM
+/// handlers of the exit.
+static CURRENT_EXIT: [Mutex<Option<ExitContext>>; SMP] = [NO_EXIT; SMP];
+
+/// Record a VM exit of `vcpu_id`, of the VM running on the current CPU, into the exit history of
+/// the vCPU, and make `ctx` the context of the current exit.
+///
+/// Returns the repeat count if the exit watchdog fires on this exit, in which case the caller
+/// should hand the exit to [`watchdog_fire`].
+pub(crate) fn record_exit(vcpu_id: usize, ctx: &ExitContext) -> Option<u32> {
+    RECORDED_EXITS[current_cpu_id()].fetch_add(1, Ordering::Relaxed);
+    *CURRENT_EXIT[current_cpu_id()].lock() = Some(*ctx);
+    let record = ExitRecord {
+        vcpu_id,
+        exit_reason: ctx.exit_reason,
+        guest_rip: ctx.guest_rip,
+        qualification: ctx.qualification,
+        timestamp_ns: axhal::time::current_time_nanos(),
+    };
+    let vm_id = crate::vm::current_vm_id().unwrap_or(crate::vm::HOST_VM_ID);
+    let log = current_log(vm_id, vcpu_id);
+    let mut log = log.lock();
+    log.history.push(record);
+    if is_async_exit(record.exit_reason) {
+        return None;
+    }
+    let config = *WATCHDOG_CONFIG.read();
+    log.watchdog.check(&record, &config)
+}
+
+/// Apply the watchdog policy to the VM of `vcpu`, which repeated the current exit `count` times.
+///
+/// Returns `None` if the VM was paused and then resumed, so that the exit is handled as usual.
+pub(crate) fn watchdog_fire<H: HyperCraftHal>(
+    vcpu: &VCpu<H>,
+    ctx: &ExitContext,
+    count: u32,
+) -> Option<HyperResult> {
+    let config = *WATCHDOG_CONFIG.read();
+    let vm_id = crate::vm::current_vm_id();
+    error!("!!!!!!!!!!!!!!!! VM-exit watchdog !!!!!!!!!!!!!!!!");
+    error!(
+        "VM [{:?}] vCPU [{}] repeated VM exit {:?} @ {:#x}, qualification {:#x}, {} times within {} ns",
+        vm_id,
+        vcpu.vcpu_id(),
+        ctx.exit_reason,
+        ctx.guest_rip,
+        ctx.qualification,
+        count,
+        config.window_ns,
+    );
+    error!(
+        "the exit handler is probably not advancing RIP, policy: {:?}",
+        config.policy
+    );
+    let vm_id = match vm_id {
+        Some(vm_id) => vm_id,
+        None => return Some(Err(HyperError::BadState)),
+    };
+    match config.policy {
+        WatchdogPolicy::Crash => Some(vm_fatal(vm_id, vcpu, ctx)),
+        WatchdogPolicy::Pause => {
+            // As `pause_vm` from outside: the other vCPUs park before their next VM entry, and
+            // this one parks until the VM is resumed.
+            if let Err(err) = crate::vm::pause_vm(vm_id) {
+                warn!("VM [{}] not paused by the exit watchdog: {:?}", vm_id, err);
+                return Some(Err(HyperError::BadState));
+            }
+            crate::vm::park_while_paused(vm_id);
+            match crate::vm::vm_state(vm_id) {
+                Some(VmState::Running) => None,
+                _ => Some(Err(HyperError::BadState)),
+            }
+        }
+    }
+}
+
+/// Final handler of a VM exit that neither the per-vCPU nor the per-VM devices handled.
+///
+/// Dumps the state of the vCPU, see [`dump_vcpu_state`], marks the VM as crashed, and returns the
+/// error which stops the vCPU.
+///
+/// [`dump_vcpu_state`]: super::diagnostics::dump_vcpu_state
+pub(crate) fn vm_fatal<H: HyperCraftHal>(
+    vm_id: u32,
+    vcpu: &VCpu<H>,
+    ctx: &ExitContext,
+) -> HyperResult {
+    error!(
+        "VM [{}] vCPU [{}] fatal: unhandled VM exit {:?} ({:#x})",
+        vm_id,
+        vcpu.vcpu_id(),
+        ctx.exit_reason,
+        ctx.exit_reason as u32,
+    );
+    super::diagnostics::dump_vcpu_state(log::Level::Error, vcpu, ctx);
+
+    crate::vm::set_vm_state(vm_id, VmState::Crashed);
+    Err(HyperError::BadState)
+}
@@ -0,0 +1,254 @@
+//! The x87, SSE and AVX state of a vCPU, its extended state. (SDM Vol. 1, Chapter 13)
+//!
+//! VMX switches none of it: on a VM exit the registers still hold the state of the guest, which
+//! the host clobbers as soon as it uses them, or switches to another vCPU on the CPU, which runs
+//! on the state of this one. So each vCPU has its own XSAVE area: [`GuestXState::unload`] stores
+//! the state of the guest there at every VM exit and leaves the host the initial state, and
+//! [`GuestXState::load`] loads it back before the next VM entry, on the CPU of the vCPU.
+//!
+//! The area is saved with all the [`GUEST_XCR0`] components, whatever XCR0 the guest set, so that
+//! a guest which does not use XSAVE keeps its XMM registers too; XCR0 itself is switched to the
+//! value of the guest, set through the XSETBV exits, last. A CPU without XSAVE saves the x87 and
+//! SSE state with FXSAVE instead. The guests are told the components of [`GUEST_XCR0`] only, see
+//! [`xsave_leaf`]: AVX-512, AMX, MPX, PKRU and the supervisor components are not saved and stay
+//! off.
+//!
+//! The vCPU is assumed not to be preempted between the last of its checks before a VM entry and
+//! the VM entry itself.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::x86_64::{__cpuid, __cpuid_count, _fxrstor64, _fxsave64};
+use core::arch::x86_64::{_xgetbv, _xrstor64, _xsave64, _xsetbv};
+
+use lazy_static::lazy_static;
+use x86::bits64::vmx::vmwrite;
+use x86::controlregs::{cr4, cr4_write, Cr4};
+use x86::vmx::vmcs;
+
+use crate::device::{DeviceState, StateReader, StateWriter};
+use crate::{Error as HyperError, Result as HyperResult};
+
+const XSTATE_STATE_VERSION: u16 = 1;
+
+/// The x87 state, whose component XCR0 always has.
+const XCR0_X87: u64 = 1 << 0;
+/// The SSE state: the XMM registers and MXCSR.
+const XCR0_SSE: u64 = 1 << 1;
+/// The AVX state: the upper halves of the YMM registers.
+const XCR0_AVX: u64 = 1 << 2;
+
+/// The XSAVE area in the standard format: the legacy FXSAVE region, the XSAVE header, then the
+/// AVX state, the last component saved.
+const LEGACY_REGION_SIZE: usize = 512;
+const XSAVE_HEADER_SIZE: usize = 64;
+const AVX_STATE_SIZE: usize = 256;
+const XSAVE_AREA_SIZE: usize = LEGACY_REGION_SIZE + XSAVE_HEADER_SIZE + AVX_STATE_SIZE;
+/// Offsets of MXCSR and of XSTATE_BV and XCOMP_BV in the header.
+const MXCSR_OFFSET: usize = 24;
+const XSTATE_BV_OFFSET: usize = LEGACY_REGION_SIZE;
+const XCOMP_BV_OFFSET: usize = LEGACY_REGION_SIZE + 8;
+
+/// The initial x87 control word and MXCSR, as FNINIT and a reset leave them.
+const FCW_INIT: u16 = 0x37f;
+const MXCSR_INIT: u32 = 0x1f80;
+/// The bits of MXCSR which may be set.
+const MXCSR_VALID: u32 = 0xffff;
+
+// Leaf 1.
+const LEAF_FEATURES: u32 = 1;
+const ECX_XSAVE: u32 = 1 << 26;
+// Leaf 0xd, subleaf 1.
+const EAX_XSAVES: u32 = 1 << 3;
+/// Leaf 0xd, the XSAVE features and components.
+pub(super) const LEAF_XSAVE: u32 = 0xd;
+
+lazy_static! {
+    /// Whether the CPUs have XSAVE, FXSAVE being used if not.
+    static ref HOST_XSAVE: bool = unsafe { __cpuid(LEAF_FEATURES) }.ecx & ECX_XSAVE != 0;
+    /// The components of the extended state of the guests: x87, SSE and, if the CPUs have it,
+    /// AVX. 0 without XSAVE.
+    static ref GUEST_XCR0: u64 = if *HOST_XSAVE {
+        let supported = unsafe { __cpuid_count(LEAF_XSAVE, 0) }.eax as u64;
+        supported & (XCR0_X87 | XCR0_SSE | XCR0_AVX)
+    } else {
+        0
+    };
+    /// The state the host runs on between a VM exit and the next VM entry: every component in
+    /// its initial state, XSTATE_BV being 0, but MXCSR, which is loaded whatever XSTATE_BV is.
+    static ref HOST_INIT_AREA: XsaveArea = {
+        let mut area = XsaveArea([0; XSAVE_AREA_SIZE]);
+        area.0[..2].copy_from_slice(&FCW_INIT.to_le_bytes());
+        area.0[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&MXCSR_INIT.to_le_bytes());
+        area
+    };
+}
+
+/// An XSAVE area, 64-byte aligned as XSAVE and XRSTOR want it.
+#[repr(C, align(64))]
+struct XsaveArea([u8; XSAVE_AREA_SIZE]);
+
+/// The extended state of a vCPU, see the [module documentation](self).
+pub(crate) struct GuestXState {
+    area: Box<XsaveArea>,
+    /// XCR0 as the guest set it.
+    xcr0: u64,
+    /// Whether the state is in the registers of the CPU, rather than in `area`.
+    loaded: bool,
+}
+
+impl GuestXState {
+    /// The state of a vCPU out of reset: x87 and SSE in their initial state, XCR0 with x87 only.
+    pub fn new() -> Self {
+        Self {
+            area: Box::new(XsaveArea(HOST_INIT_AREA.0)),
+            xcr0: XCR0_X87,
+            loaded: false,
+        }
+    }
+
+    /// XCR0 as the guest set it.
+    pub fn xcr0(&self) -> u64 {
+        self.xcr0
+    }
+
+    /// XSETBV of the guest: set XCR `index` to `value`. Fails, the guest taking #GP, unless it is
+    /// XCR0 with x87 and components of [`GUEST_XCR0`] only, and SSE with AVX.
+    pub fn set_xcr(&mut self, index: u32, value: u64) -> HyperResult {
+        let valid = index == 0
+            && value & XCR0_X87 != 0
+            && value & !*GUEST_XCR0 == 0
+            && (value & XCR0_AVX == 0 || value & XCR0_SSE != 0);
+        if !valid {
+            return Err(HyperError::InvalidParam);
+        }
+        self.xcr0 = value;
+        Ok(())
+    }
+
+    /// Store the state of the guest in its area and leave the host the initial state, if the
+    /// state is in the registers. Called on every VM exit, on the CPU of the vCPU.
+    pub fn unload(&mut self) {
+        if !self.loaded {
+            return;
+        }
+        unsafe {
+            if *HOST_XSAVE {
+                set_xcr0(*GUEST_XCR0);
+                _xsave64(self.area.0.as_mut_ptr(), *GUEST_XCR0);
+                _xrstor64(HOST_INIT_AREA.0.as_ptr(), *GUEST_XCR0);
+            } else {
+                _fxsave64(self.area.0.as_mut_ptr());
+                _fxrstor64(HOST_INIT_AREA.0.as_ptr());
+            }
+        }
+        self.loaded = false;
+    }
+
+    /// Load the state of the guest and its XCR0 into the registers, if it is not there already.
+    /// Called before every VM entry, on the CPU of the vCPU.
+    pub fn load(&mut self) {
+        if self.loaded {
+            return;
+        }
+        unsafe {
+            if *HOST_XSAVE {
+                set_xcr0(*GUEST_XCR0);
+                _xrstor64(self.area.0.as_ptr(), *GUEST_XCR0);
+                set_xcr0(self.xcr0);
+            } else {
+                _fxrstor64(self.area.0.as_ptr());
+            }
+        }
+        self.loaded = true;
+    }
+}
+
+/// Set XCR0 of the current CPU to `xcr0`, if it is not already.
+unsafe fn set_xcr0(xcr0: u64) {
+    if _xgetbv(0) != xcr0 {
+        _xsetbv(0, xcr0);
+    }
+}
+
+/// Enable the x87, SSE and XSAVE instructions on the current CPU, for the host between a VM exit
+/// and the next VM entry, and in the host CR4 of the VMCS loaded: the CR4 of the host boot
+/// leaves FXSAVE, and XSAVE, off. Called before the first VM entry of a vCPU.
+pub(super) fn enable_host_xstate() {
+    let mut flags = Cr4::CR4_ENABLE_SSE | Cr4::CR4_UNMASKED_SSE;
+    if *HOST_XSAVE {
+        flags |= Cr4::CR4_ENABLE_OS_XSAVE;
+    }
+    let host_cr4 = unsafe { cr4() } | flags;
+    unsafe {
+        cr4_write(host_cr4);
+        if *HOST_XSAVE {
+            set_xcr0(*GUEST_XCR0);
+        }
+    }
+    if let Err(err) = unsafe { vmwrite(vmcs::host::CR4, host_cr4.bits() as u64) } {
+        warn!(
+            "failed to set the host CR4 {:#x}: {:?}",
+            host_cr4.bits(),
+            err
+        );
+    }
+}
+
+/// Leaf 0xd, subleaf `subleaf`, for a guest of XCR0 `xcr0`: the components of [`GUEST_XCR0`] and
+/// the sizes of their area, the XSAVE instructions of the CPU but XSAVES, whose supervisor
+/// components are not saved.
+pub(super) fn xsave_leaf(subleaf: u32, xcr0: u64) -> [u32; 4] {
+    if *GUEST_XCR0 == 0 {
+        return [0; 4];
+    }
+    let result = unsafe { __cpuid_count(LEAF_XSAVE, subleaf) };
+    let size = |xcr0: u64| match xcr0 & XCR0_AVX {
+        0 => (LEGACY_REGION_SIZE + XSAVE_HEADER_SIZE) as u32,
+        _ => XSAVE_AREA_SIZE as u32,
+    };
+    match subleaf {
+        0 => [*GUEST_XCR0 as u32, size(xcr0), size(*GUEST_XCR0), 0],
+        1 => [result.eax & !EAX_XSAVES, 0, 0, 0],
+        2 if *GUEST_XCR0 & XCR0_AVX != 0 => [result.eax, result.ebx, 0, 0],
+        _ => [0; 4],
+    }
+}
+
+/// XCR0 and the area. A restored area must be in the standard format, with components of
+/// [`GUEST_XCR0`] only, as XRSTOR takes it.
+impl DeviceState for GuestXState {
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(XSTATE_STATE_VERSION);
+        state.u64(self.xcr0).bytes(&self.area.0);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> HyperResult {
+        let mut state = StateReader::new(state, XSTATE_STATE_VERSION)?;
+        let xcr0 = state.u64()?;
+        let area = state.bytes()?;
+        state.finish()?;
+        if area.len() != XSAVE_AREA_SIZE {
+            return Err(HyperError::InvalidParam);
+        }
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(area[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(area[offset..offset + 8].try_into().unwrap());
+        let header_reserved = &area[XCOMP_BV_OFFSET + 8..LEGACY_REGION_SIZE + XSAVE_HEADER_SIZE];
+        if u32_at(MXCSR_OFFSET) & !MXCSR_VALID != 0
+            || u64_at(XSTATE_BV_OFFSET) & !*GUEST_XCR0 != 0
+            || u64_at(XCOMP_BV_OFFSET) != 0
+            || header_reserved.iter().any(|&byte| byte != 0)
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        if xcr0 != self.xcr0 {
+            self.set_xcr(0, xcr0)?;
+        }
+        self.area.0.copy_from_slice(area);
+        self.loaded = false;
+        Ok(())
+    }
+}
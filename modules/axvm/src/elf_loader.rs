@@ -0,0 +1,253 @@
+//! Loading of ELF guest kernels, as built by the usual tooling.
+//!
+//! Only ELF64 little-endian x86_64 images are taken. Their `PT_LOAD` segments are loaded at
+//! their physical address `p_paddr`, followed by `p_memsz - p_filesz` zero bytes for the BSS, and
+//! the guest is entered at `e_entry`. Where the segments land in guest RAM is checked by
+//! [`VmBuilder::build`](crate::VmBuilder::build), with the other images.
+
+use alloc::vec::Vec;
+
+use crate::{Error, GuestPhysAddr, Result};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+/// Size of an ELF64 program header, the least `e_phentsize` may be.
+const PHDR_SIZE: usize = 56;
+
+/// A `PT_LOAD` segment: `data` at `gpa`, followed by `zeroed` zero bytes.
+pub(crate) struct ElfSegment<'a> {
+    pub gpa: GuestPhysAddr,
+    pub data: &'a [u8],
+    pub zeroed: usize,
+}
+
+/// The entry point and the loadable segments of an ELF image.
+pub(crate) struct ElfImage<'a> {
+    pub entry: GuestPhysAddr,
+    pub segments: Vec<ElfSegment<'a>>,
+}
+
+/// Whether `image` starts with the ELF magic.
+pub(crate) fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(ELF_MAGIC)
+}
+
+/// Parse the ELF image `elf`. Fails with `NotSupported` for a 32-bit, big-endian or non-x86_64
+/// ELF, and with `InvalidParam` if it is not an ELF or its headers point outside of it.
+pub(crate) fn parse(elf: &[u8]) -> Result<ElfImage<'_>> {
+    let truncated = || {
+        warn!("ELF image truncated ({:#x} bytes)", elf.len());
+        Error::InvalidParam
+    };
+    // The offsets come from the image, they may be anything.
+    let bytes_at = |offset: usize, len: usize| {
+        offset
+            .checked_add(len)
+            .and_then(|end| elf.get(offset..end))
+            .ok_or_else(truncated)
+    };
+    let u16_at = |offset: usize| -> Result<u16> {
+        Ok(u16::from_le_bytes(bytes_at(offset, 2)?.try_into().unwrap()))
+    };
+    let u32_at = |offset: usize| -> Result<u32> {
+        Ok(u32::from_le_bytes(bytes_at(offset, 4)?.try_into().unwrap()))
+    };
+    let u64_at = |offset: usize| -> Result<usize> {
+        Ok(u64::from_le_bytes(bytes_at(offset, 8)?.try_into().unwrap()) as usize)
+    };
+
+    if !is_elf(elf) {
+        warn!("not an ELF image");
+        return Err(Error::InvalidParam);
+    }
+    // e_ident[EI_CLASS], e_ident[EI_DATA].
+    match (elf.get(4), elf.get(5)) {
+        (Some(&ELFCLASS64), Some(&ELFDATA2LSB)) => {}
+        (Some(&ELFCLASS64), _) => {
+            warn!("big-endian ELF image not supported");
+            return Err(Error::NotSupported);
+        }
+        (Some(_), _) => {
+            warn!("32-bit ELF image not supported, the guest starts in 64-bit mode");
+            return Err(Error::NotSupported);
+        }
+        _ => return Err(truncated()),
+    }
+    let machine = u16_at(18)?;
+    if machine != EM_X86_64 {
+        warn!("ELF image for machine {}, not x86_64", machine);
+        return Err(Error::NotSupported);
+    }
+    let entry = u64_at(24)?;
+    let phoff = u64_at(32)?;
+    let phentsize = u16_at(54)? as usize;
+    let phnum = u16_at(56)? as usize;
+    if phnum != 0 && phentsize < PHDR_SIZE {
+        warn!("ELF program headers of {} bytes", phentsize);
+        return Err(Error::InvalidParam);
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        // The whole header within the image.
+        let ph = i
+            .checked_mul(phentsize)
+            .and_then(|offset| phoff.checked_add(offset))
+            .filter(|&ph| ph <= elf.len().saturating_sub(PHDR_SIZE))
+            .ok_or_else(truncated)?;
+        if u32_at(ph)? != PT_LOAD {
+            continue;
+        }
+        let offset = u64_at(ph + 8)?;
+        let paddr = u64_at(ph + 24)?;
+        let filesz = u64_at(ph + 32)?;
+        let memsz = u64_at(ph + 40)?;
+        let data = offset
+            .checked_add(filesz)
+            .and_then(|end| elf.get(offset..end))
+            .ok_or_else(|| {
+                warn!(
+                    "ELF segment at {:#x} ({:#x} bytes) outside of the image",
+                    offset, filesz
+                );
+                Error::InvalidParam
+            })?;
+        if memsz < filesz {
+            warn!(
+                "ELF segment at {:#x}: memory size {:#x} below file size {:#x}",
+                paddr, memsz, filesz
+            );
+            return Err(Error::InvalidParam);
+        }
+        segments.push(ElfSegment {
+            gpa: paddr,
+            data,
+            zeroed: memsz - filesz,
+        });
+    }
+    if segments.is_empty() {
+        warn!("ELF image without a loadable segment");
+        return Err(Error::InvalidParam);
+    }
+    Ok(ElfImage { entry, segments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const EHDR_SIZE: usize = 64;
+
+    /// A program header: type, file offset, physical address, file and memory sizes.
+    type Phdr = (u32, usize, usize, usize, usize);
+
+    /// An x86_64 ELF64 image with `phdrs` right after its header, followed by `data`.
+    fn elf(entry: usize, phdrs: &[Phdr], data: &[u8]) -> Vec<u8> {
+        let mut image = vec![0u8; EHDR_SIZE + phdrs.len() * PHDR_SIZE];
+        image[..4].copy_from_slice(ELF_MAGIC);
+        image[4] = ELFCLASS64;
+        image[5] = ELFDATA2LSB;
+        image[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        image[24..32].copy_from_slice(&(entry as u64).to_le_bytes());
+        image[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        image[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        image[56..58].copy_from_slice(&(phdrs.len() as u16).to_le_bytes());
+        for (i, &(p_type, offset, paddr, filesz, memsz)) in phdrs.iter().enumerate() {
+            let ph = &mut image[EHDR_SIZE + i * PHDR_SIZE..];
+            ph[..4].copy_from_slice(&p_type.to_le_bytes());
+            ph[8..16].copy_from_slice(&(offset as u64).to_le_bytes());
+            ph[24..32].copy_from_slice(&(paddr as u64).to_le_bytes());
+            ph[32..40].copy_from_slice(&(filesz as u64).to_le_bytes());
+            ph[40..48].copy_from_slice(&(memsz as u64).to_le_bytes());
+        }
+        image.extend_from_slice(data);
+        image
+    }
+
+    /// Where `data` starts in an image of `phdrs` program headers.
+    fn data_offset(phdrs: usize) -> usize {
+        EHDR_SIZE + phdrs * PHDR_SIZE
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = [1, 2, 3, 4, 5, 6];
+        let image = elf(
+            0x20_0000,
+            &[
+                (PT_LOAD, data_offset(3), 0x20_0000, 4, 4),
+                (4, 0, 0, 0, 0),
+                (PT_LOAD, data_offset(3) + 4, 0x30_0000, 2, 0x1000),
+            ],
+            &data,
+        );
+        let parsed = parse(&image).unwrap();
+        assert_eq!(parsed.entry, 0x20_0000);
+        assert_eq!(parsed.segments.len(), 2);
+        assert_eq!(parsed.segments[0].gpa, 0x20_0000);
+        assert_eq!(parsed.segments[0].data, &data[..4]);
+        assert_eq!(parsed.segments[0].zeroed, 0);
+        assert_eq!(parsed.segments[1].gpa, 0x30_0000);
+        assert_eq!(parsed.segments[1].data, &data[4..]);
+        assert_eq!(parsed.segments[1].zeroed, 0x1000 - 2);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut image = elf(0, &[(PT_LOAD, data_offset(1), 0, 1, 1)], &[0]);
+        assert!(parse(&image).is_ok());
+        image[4] = 1;
+        assert!(matches!(parse(&image), Err(Error::NotSupported)));
+        image[4] = ELFCLASS64;
+        image[5] = 2;
+        assert!(matches!(parse(&image), Err(Error::NotSupported)));
+        image[5] = ELFDATA2LSB;
+        image[18] = 3;
+        assert!(matches!(parse(&image), Err(Error::NotSupported)));
+        assert!(matches!(parse(b"MZ\x90\0"), Err(Error::InvalidParam)));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let image = elf(0, &[(PT_LOAD, data_offset(1), 0, 4, 4)], &[0; 4]);
+        for len in [4, 20, EHDR_SIZE - 1, EHDR_SIZE + PHDR_SIZE - 1] {
+            assert!(
+                matches!(parse(&image[..len]), Err(Error::InvalidParam)),
+                "{}",
+                len
+            );
+        }
+        // The segment data past the end.
+        assert!(parse(&image[..image.len() - 1]).is_err());
+        assert!(matches!(parse(&elf(0, &[], &[])), Err(Error::InvalidParam)));
+    }
+
+    #[test]
+    fn test_hostile_offsets() {
+        let base = elf(0, &[(PT_LOAD, data_offset(1), 0, 1, 1)], &[0]);
+        // Program headers at the end of the address space, or past the image.
+        for phoff in [usize::MAX - 8, usize::MAX, base.len() - PHDR_SIZE + 1] {
+            let mut image = base.clone();
+            image[32..40].copy_from_slice(&(phoff as u64).to_le_bytes());
+            assert!(matches!(parse(&image), Err(Error::InvalidParam)));
+        }
+        // 0xffff program headers of 0xffff bytes, far past the image.
+        let mut image = base.clone();
+        image[54..56].copy_from_slice(&u16::MAX.to_le_bytes());
+        image[56..58].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(parse(&image), Err(Error::InvalidParam)));
+        // Program headers too short to hold their fields.
+        let mut image = base.clone();
+        image[54..56].copy_from_slice(&8u16.to_le_bytes());
+        assert!(matches!(parse(&image), Err(Error::InvalidParam)));
+        // A segment whose end overflows, or whose BSS is negative.
+        let image = elf(0, &[(PT_LOAD, usize::MAX, 0, 2, 2)], &[0]);
+        assert!(matches!(parse(&image), Err(Error::InvalidParam)));
+        let image = elf(0, &[(PT_LOAD, data_offset(1), 0, 1, 0)], &[0]);
+        assert!(matches!(parse(&image), Err(Error::InvalidParam)));
+    }
+}
@@ -1,21 +1,78 @@
-use alloc::string::String;
+use core::mem::size_of;
 
 use axhal::current_cpu_id;
 use axhal::mem::{phys_to_virt, PhysAddr};
 use hypercraft::{GuestPhysAddr, HostPhysAddr};
+use log::Level;
+use memory_addr::PAGE_SIZE_4K;
 
-use crate::config::entry::{vm_cfg_add_vm_entry, vm_cfg_entry, VMCfgEntry, VmType};
+use crate::config::entry::{vm_cfg_entry, VmType};
+use crate::hvc_console::HVC_CONSOLE_IO_MAX;
+use crate::ratelimit::RateLimiter;
+use crate::vm::VmState;
 use crate::Error;
-use crate::{nmi::nmi_send_msg_by_core_id, nmi::NmiMessage, HyperCraftHal, Result, VCpu};
+use crate::VmBuilder;
+use crate::{
+    nmi::nmi_send_msg_by_core_id, nmi::NmiMessage, nmi::NmiRequest, HyperCraftHal, Result, VCpu,
+    VmExitInfo,
+};
 // use axhal::hv::HyperCraftHalImpl;
 
 pub const HVC_SHADOW_PROCESS_INIT: usize = 0x53686477;
 pub const HVC_SHADOW_PROCESS_PRCS: usize = 0x70726373;
 pub const HVC_SHADOW_PROCESS_RDY: usize = 0x52647921;
 
+static HYPERCALL_LOG: RateLimiter = RateLimiter::new("hypercall", 10);
+
 pub const HVC_AXVM_CREATE_CFG: usize = 0x101;
 pub const HVC_AXVM_LOAD_IMG: usize = 0x102;
 pub const HVC_AXVM_BOOT: usize = 0x103;
+/// Shut the calling VM down with exit code `args.0`, see [`crate::VmJoinHandle::wait`].
+pub const HVC_VM_SHUTDOWN: usize = 0x104;
+/// Set the wall clock of the hypervisor to `args.0` nanoseconds since the Unix epoch, from the
+/// host only, see [`crate::set_wall_clock`].
+pub const HVC_WALL_CLOCK_SET: usize = 0x105;
+/// Pause the guest VM `args.0`, from the host only, see [`crate::pause_vm`].
+pub const HVC_VM_PAUSE: usize = 0x106;
+/// Resume the guest VM `args.0`, from the host only.
+pub const HVC_VM_RESUME: usize = 0x107;
+
+/// Register the exit-less console ring page at guest physical address `args.0`.
+pub const HVC_CONSOLE_RING_SETUP: usize = 0x110;
+/// The console ring is full, drain it now.
+pub const HVC_CONSOLE_RING_KICK: usize = 0x111;
+/// Write `args.1` bytes at guest physical address `args.0` to the console, see
+/// [`crate::hvc_console`].
+pub const HVC_CONSOLE_WRITE: usize = 0x112;
+/// Read up to `args.1` bytes of console input to guest physical address `args.0`.
+pub const HVC_CONSOLE_READ: usize = 0x113;
+/// Count the console input ready to be read, and arm an interrupt at vector `args.0` if none.
+pub const HVC_CONSOLE_POLL: usize = 0x114;
+
+/// Log the VM exit statistics of the calling VM, see [`crate::dump_exit_stats`].
+pub const HVC_EXIT_STATS_DUMP: usize = 0x120;
+/// Log the MSRs the calling vCPU accesses without exits, see
+/// [`crate::device::DeviceList::set_msr_passthrough`].
+pub const HVC_MSR_BITMAP_DUMP: usize = 0x121;
+
+/// Share the `args.1` bytes at guest physical address `args.0` with the hypervisor, `args.2`
+/// being the [`crate::ShareFlags`], and return the handle of the region, see
+/// [`crate::with_shared_region`].
+pub const HVC_MEM_SHARE: usize = 0x130;
+/// Stop sharing the region of handle `args.0`.
+pub const HVC_MEM_UNSHARE: usize = 0x131;
+
+/// Write the memory map of the calling VM to the `args.1` bytes at guest physical address
+/// `args.0`, see [`crate::memory_map`].
+pub const HVC_GET_MEMORY_MAP: usize = 0x140;
+
+/// Register the paravirtual clock of the calling vCPU at guest physical address `args.0`, 0
+/// unregistering it. The clock is the `pvclock_vcpu_time_info` of KVM.
+pub const HVC_PVCLOCK_SETUP: usize = 0x150;
+
+/// Log the `args.2` bytes at guest physical address `args.1` on the host at level `args.0`, see
+/// [`crate::hvc_log`].
+pub const HVC_LOG: usize = 0x160;
 
 // The struct used for parameter passing between the kernel module and ArceOS hypervisor.
 // This struct should have the same memory layout as the `AxVMCreateArg` structure in ArceOS.
@@ -47,14 +104,32 @@ pub struct AxVMCreateArg {
     ramdisk_load_hpa: HostPhysAddr,
 }
 
+/// Length of the encoding of `vmcall`, which hypercraft skips once the hypercall returns.
+const VMCALL_LEN: u32 = 3;
+
+/// Handle a hypercall from `vcpu`.
+///
+/// RIP ends past the `vmcall`, at the length reported in `exit_info`: the caller skips the
+/// [`VMCALL_LEN`] bytes of its encoding, the prefixes of a longer one are skipped here.
 pub fn handle_hvc<H: HyperCraftHal>(
     vcpu: &mut VCpu<H>,
+    exit_info: &VmExitInfo,
     id: usize,
     args: (usize, usize, usize),
 ) -> Result<u32> {
-    debug!(
-        "hypercall_handler vcpu: {}, id: {:#x?}, args: {:#x?}, {:#x?}, {:#x?}",
+    crate::device::debug_check_exit_instr_len(exit_info, None);
+    if let Some(prefixes) = exit_info.exit_instruction_length.checked_sub(VMCALL_LEN) {
+        if prefixes != 0 {
+            vcpu.advance_rip(prefixes as u8)?;
+        }
+    }
+    ratelimited!(
+        HYPERCALL_LOG,
+        Level::Debug,
+        "hypercall_handler vcpu: {} @ {:#x} (len {}), id: {:#x?}, args: {:#x?}, {:#x?}, {:#x?}",
         vcpu.vcpu_id(),
+        exit_info.guest_rip,
+        exit_info.exit_instruction_length,
         id,
         args.0,
         args.1,
@@ -66,23 +141,115 @@ pub fn handle_hvc<H: HyperCraftHal>(
             axtask::notify_all_process();
         }
         HVC_AXVM_CREATE_CFG => {
-            // Translate guest physical address of AxVMCreateArg into virtual address of hypervisor.
-            let arg_gpa = args.0;
-            let arg_hpa = crate::config::root_gpm().translate(arg_gpa)?;
-            let arg_hva = phys_to_virt(PhysAddr::from(arg_hpa)).as_mut_ptr();
-
-            let arg = unsafe { &mut *{ arg_hva as *mut AxVMCreateArg } };
+            // Only the host creates VMs, the argument is in its RAM.
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            if vm_id != crate::vm::HOST_VM_ID {
+                return Err(Error::NotSupported);
+            }
+            let arg_hva = guest_buffer(vm_id, args.0, size_of::<AxVMCreateArg>())?;
+            // Copied, the host may change it meanwhile.
+            let mut arg = unsafe { (arg_hva as *const AxVMCreateArg).read_unaligned() };
 
             debug!("HVC_AXVM_CREATE_CFG get\n{:#x?}", arg);
 
-            let _ = ax_hvc_create_vm(arg)?;
+            let _ = ax_hvc_create_vm(&mut arg)?;
+            unsafe { (arg_hva as *mut AxVMCreateArg).write_unaligned(arg) };
         }
         HVC_AXVM_LOAD_IMG => {
             warn!("HVC_AXVM_LOAD_IMG is combined with HVC_AXVM_CREATE_CFG currently");
             warn!("Just return");
         }
         HVC_AXVM_BOOT => {
-            ax_hvc_boot_vm(args.0);
+            ax_hvc_boot_vm(args.0)?;
+        }
+        HVC_VM_SHUTDOWN => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            // The vCPU stops before its next VM entry.
+            crate::vm::shutdown_vm(vm_id, args.0 as u64)?;
+        }
+        HVC_WALL_CLOCK_SET => {
+            // A guest sets the time of its own RTC instead.
+            if crate::vm::current_vm_id() != Some(crate::vm::HOST_VM_ID) {
+                return Err(Error::NotSupported);
+            }
+            crate::wall_clock::set_wall_clock(args.0 as u64);
+        }
+        HVC_VM_PAUSE | HVC_VM_RESUME => {
+            // Only the host manages the guests, and it cannot pause itself: nothing would be
+            // left to resume it but the shell.
+            let vm_id = args.0 as u32;
+            if crate::vm::current_vm_id() != Some(crate::vm::HOST_VM_ID)
+                || vm_id == crate::vm::HOST_VM_ID
+            {
+                return Err(Error::NotSupported);
+            }
+            if id == HVC_VM_PAUSE {
+                crate::vm::pause_vm(vm_id)?;
+            } else {
+                crate::vm::resume_vm(vm_id)?;
+            }
+        }
+        HVC_CONSOLE_RING_SETUP => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            let page = guest_ram_page_hva(vm_id, args.0)?;
+            crate::console_ring::register(vm_id, page)?;
+        }
+        HVC_CONSOLE_RING_KICK => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            crate::console_ring::drain(vm_id);
+        }
+        // These return the number of bytes, not the hypercall ID.
+        HVC_CONSOLE_WRITE => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            let guest = guest_console_buffer(vm_id, args.0, args.1)?;
+            let mut buf = [0; HVC_CONSOLE_IO_MAX];
+            for (i, c) in buf[..args.1].iter_mut().enumerate() {
+                // The guest may write its buffer concurrently.
+                *c = unsafe { guest.add(i).read_volatile() };
+            }
+            return Ok(crate::hvc_console::write(vm_id, &buf[..args.1]) as u32);
+        }
+        HVC_CONSOLE_READ => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            let guest = guest_console_buffer(vm_id, args.0, args.1)?;
+            let mut buf = [0; HVC_CONSOLE_IO_MAX];
+            let len = crate::hvc_console::read(vm_id, &mut buf[..args.1]);
+            for (i, &c) in buf[..len].iter().enumerate() {
+                unsafe { guest.add(i).write_volatile(c) };
+            }
+            return Ok(len as u32);
+        }
+        HVC_CONSOLE_POLL => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            return Ok(crate::hvc_console::poll(vm_id, args.0)? as u32);
+        }
+        HVC_EXIT_STATS_DUMP => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            crate::device::dump_exit_stats(vm_id);
+        }
+        HVC_MSR_BITMAP_DUMP => crate::device::dump_msr_bitmap(vcpu.vcpu_id()),
+        // Returns the handle, not the hypercall ID.
+        HVC_MEM_SHARE => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            return crate::shared_mem::share(vm_id, args.0, args.1, args.2);
+        }
+        HVC_MEM_UNSHARE => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            crate::shared_mem::unshare(vm_id, args.0 as u32)?;
+        }
+        // Returns the number of entries, or the length needed.
+        HVC_GET_MEMORY_MAP => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            return crate::memory_map::write_memory_map(vm_id, args.0, args.1);
+        }
+        HVC_PVCLOCK_SETUP => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            crate::device::register_pvclock(vm_id, vcpu.vcpu_id() as u32, args.0)?;
+        }
+        // Returns the number of bytes consumed.
+        HVC_LOG => {
+            let vm_id = crate::vm::current_vm_id().ok_or(Error::BadState)?;
+            return crate::hvc_log::log(vm_id, vcpu.vcpu_id(), args.0, args.1, args.2);
         }
         _ => {
             warn!("Unhandled hypercall {}. vcpu: {:#x?}", id, vcpu);
@@ -94,6 +261,50 @@ pub fn handle_hvc<H: HyperCraftHal>(
     // Err(HyperError::NotSupported)
 }
 
+/// Hypervisor address of the guest RAM page at `gpa` of `vm_id`, which must be page aligned.
+pub(crate) fn guest_ram_page_hva(vm_id: u32, gpa: GuestPhysAddr) -> Result<*mut u8> {
+    let hpa = if vm_id == crate::vm::HOST_VM_ID {
+        if gpa % PAGE_SIZE_4K != 0 {
+            return Err(Error::InvalidParam);
+        }
+        crate::config::root_gpm().translate(gpa)?
+    } else {
+        vm_cfg_entry(vm_id as usize)
+            .and_then(|entry| entry.guest_ram_page_hpa(gpa))
+            .ok_or(Error::InvalidParam)?
+    };
+    Ok(phys_to_virt(PhysAddr::from(hpa)).as_mut_ptr())
+}
+
+/// Hypervisor address of the console buffer of `len` bytes at `gpa` of `vm_id`, which must be at
+/// most [`HVC_CONSOLE_IO_MAX`] bytes and must not cross a page.
+fn guest_console_buffer(vm_id: u32, gpa: GuestPhysAddr, len: usize) -> Result<*mut u8> {
+    if len > HVC_CONSOLE_IO_MAX {
+        return Err(Error::InvalidParam);
+    }
+    guest_buffer(vm_id, gpa, len)
+}
+
+/// Hypervisor address of the `len` bytes at `gpa` of `vm_id`, checked as the regions shared
+/// with [`crate::shared_mem::share`]: they must not cross a page, and must be RAM not overlapping
+/// an emulated device.
+fn guest_buffer(vm_id: u32, gpa: GuestPhysAddr, len: usize) -> Result<*mut u8> {
+    let offset = gpa % PAGE_SIZE_4K;
+    let end = gpa.checked_add(len).ok_or(Error::InvalidParam)?;
+    if offset + len > PAGE_SIZE_4K {
+        return Err(Error::InvalidParam);
+    }
+    if crate::device::vm_claims_mmio(vm_id, gpa as u64..end as u64) {
+        warn!(
+            "VM [{}] hypercall buffer {:#x}..{:#x} overlaps an emulated device",
+            vm_id, gpa, end
+        );
+        return Err(Error::InvalidParam);
+    }
+    let page = guest_ram_page_hva(vm_id, gpa - offset)?;
+    Ok(unsafe { page.add(offset) })
+}
+
 fn ax_hvc_create_vm(cfg: &mut AxVMCreateArg) -> Result<u32> {
     // These fields should be set by user, but now this is provided by hypervisor.
     // Todo: refactor these.
@@ -117,17 +328,6 @@ fn ax_hvc_create_vm(cfg: &mut AxVMCreateArg) -> Result<u32> {
         }
     }
 
-    let mut vm_cfg_entry = VMCfgEntry::new(
-        String::from("Guest VM"),
-        VmType::from(cfg.vm_type),
-        String::from("guest cmdline"),
-        cfg.cpu_mask,
-        cfg.kernel_load_gpa,
-        cfg.vm_entry_point,
-        cfg.bios_load_gpa,
-        cfg.ramdisk_load_gpa,
-    );
-
     let mm_setup_fn = match VmType::from(cfg.vm_type) {
         VmType::VmTNimbOS => crate::config::nimbos_cfg_def::nimbos_memory_regions_setup,
         VmType::VmTLinux => crate::config::linux_cfg_def::linux_memory_regions_setup,
@@ -137,40 +337,68 @@ fn ax_hvc_create_vm(cfg: &mut AxVMCreateArg) -> Result<u32> {
         }
     };
 
-    vm_cfg_entry.memory_region_editor(mm_setup_fn);
-
-    vm_cfg_entry.set_up_memory_region()?;
+    // The guest gets the config entry it always had: the PC devices, no ACPI tables, and images
+    // the Linux kernel module copies itself.
+    let vm = VmBuilder::new("Guest VM")
+        .vm_type(VmType::from(cfg.vm_type))
+        .cmdline("guest cmdline")
+        .cpu_set(cfg.cpu_mask)
+        .memory_layout(mm_setup_fn)
+        .host_loaded_images(cfg.kernel_load_gpa, cfg.bios_load_gpa, cfg.ramdisk_load_gpa)
+        .entry(cfg.vm_entry_point)
+        .acpi(false)
+        .build()?;
 
     // These fields should be set by hypervisor and read by Linux kernel module.
     (cfg.bios_load_hpa, cfg.kernel_load_hpa, cfg.ramdisk_load_hpa) =
-        vm_cfg_entry.get_img_load_info();
-
-    let vm_id = vm_cfg_add_vm_entry(vm_cfg_entry)?;
+        vm.host_image_hpas().ok_or(Error::NotFound)?;
 
     // This field should be set by hypervisor and read by Linux kernel module.
-    cfg.vm_id = vm_id;
+    cfg.vm_id = vm.id() as usize;
 
-    Ok(vm_id as u32)
+    Ok(vm.id())
 }
 
-fn ax_hvc_boot_vm(vm_id: usize) {
+fn ax_hvc_boot_vm(vm_id: usize) -> Result {
     let vm_cfg_entry = match vm_cfg_entry(vm_id) {
         Some(entry) => entry,
         None => {
             warn!("VM {} not existed, boot vm failed", vm_id);
-            return;
+            return Err(Error::InvalidParam);
+        }
+    };
+    // Reject what can be checked here, the target CPU reports any later failure itself. A VM
+    // which stopped boots again.
+    match crate::vm::vm_state(vm_id as u32) {
+        None | Some(VmState::Stopped) => {}
+        Some(state) => {
+            warn!("VM {} is already {:?}, boot vm failed", vm_id, state);
+            return Err(Error::BadState);
         }
+    }
+    vm_cfg_entry.validate()?;
+
+    // vCPU 0 boots on the core it is pinned to, or on the first core of the cpuset, and starts
+    // the APs on the other cores itself, see `crate::vm::boot_vm`.
+    let core = match vm_cfg_entry.vcpu_affinity().cores.first() {
+        Some(&core) => core,
+        None => match vm_cfg_entry.get_cpu_set() {
+            0 => {
+                warn!("VM {} has an empty cpuset, boot vm failed", vm_id);
+                return Err(Error::InvalidParam);
+            }
+            cpuset => cpuset.trailing_zeros() as usize,
+        },
     };
-    let cpuset = vm_cfg_entry.get_cpu_set();
     let vm_type = vm_cfg_entry.get_vm_type();
 
-    info!("boot VM {} {:?} on cpuset {:#x}", vm_id, vm_type, cpuset);
+    info!("boot VM {} {:?} on core {}", vm_id, vm_type, core);
 
-    let num_bits = core::mem::size_of::<u32>() * 8;
-    let msg = NmiMessage::BootVm(vm_id);
-    for i in 0..num_bits {
-        if cpuset & (1 << i) != 0 {
-            nmi_send_msg_by_core_id(i, msg);
-        }
-    }
+    let msg = NmiMessage {
+        vm_id: vm_id as u32,
+        vcpu_id: 0,
+        request: NmiRequest::BootVm,
+    };
+    nmi_send_msg_by_core_id(core, msg);
+    Ok(())
 }
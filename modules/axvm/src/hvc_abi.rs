@@ -0,0 +1,19 @@
+//! The hypercall interface shared with the guests: the hypercall IDs, and the constants of their
+//! arguments and results.
+
+pub use crate::hvc::{
+    HVC_CONSOLE_POLL, HVC_CONSOLE_READ, HVC_CONSOLE_RING_KICK, HVC_CONSOLE_RING_SETUP,
+    HVC_CONSOLE_WRITE, HVC_EXIT_STATS_DUMP, HVC_GET_MEMORY_MAP, HVC_LOG, HVC_MEM_SHARE,
+    HVC_MEM_UNSHARE, HVC_MSR_BITMAP_DUMP, HVC_PVCLOCK_SETUP, HVC_VM_PAUSE, HVC_VM_RESUME,
+    HVC_VM_SHUTDOWN, HVC_WALL_CLOCK_SET,
+};
+pub use crate::hvc_console::HVC_CONSOLE_IO_MAX;
+
+/// Level of an `HVC_LOG` message, as the levels of the `log` crate.
+pub const HVC_LOG_ERROR: usize = 1;
+pub const HVC_LOG_WARN: usize = 2;
+pub const HVC_LOG_INFO: usize = 3;
+pub const HVC_LOG_DEBUG: usize = 4;
+pub const HVC_LOG_TRACE: usize = 5;
+/// Longest message of `HVC_LOG`, in bytes.
+pub const HVC_LOG_MAX: usize = 4096;
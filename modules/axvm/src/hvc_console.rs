@@ -0,0 +1,152 @@
+//! Console over hypercalls, for guests without any UART driver.
+//!
+//! | hypercall            | arguments             | returns                               |
+//! |----------------------|-----------------------|---------------------------------------|
+//! | `HVC_CONSOLE_WRITE`  | buffer GPA, length    | bytes written                         |
+//! | `HVC_CONSOLE_READ`   | buffer GPA, length    | bytes read, without blocking          |
+//! | `HVC_CONSOLE_POLL`   | interrupt vector      | bytes ready to be read                |
+//!
+//! Buffers are at most [`HVC_CONSOLE_IO_MAX`] bytes of guest RAM and do not cross a page.
+//! `HVC_CONSOLE_POLL` with a non-zero vector arms a one-shot interrupt when no input is ready: the
+//! vector is injected once input arrives, and the guest polls again to rearm it. Vector 0 disarms
+//! it.
+//!
+//! The bytes go through the [`VirtualConsoleBackend`] of the emulated UARTs, so that the operator
+//! sees both kinds of guests the same way. Output is ordered after what was written to the
+//! console ring of the VM, see [`crate::console_ring`].
+
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::device::{DefaultConsoleBackend, Fifo, VirtualConsoleBackend};
+use crate::{Error, Result};
+
+/// Longest buffer of `HVC_CONSOLE_WRITE` and `HVC_CONSOLE_READ`.
+pub const HVC_CONSOLE_IO_MAX: usize = 256;
+/// Lowest vector for input interrupts, below are the exceptions.
+const MIN_VECTOR: usize = 32;
+const INPUT_FIFO_CAPACITY: usize = 64;
+
+struct HvcConsole {
+    backend: DefaultConsoleBackend,
+    /// Input taken from the backend while checking for the armed interrupt, read first.
+    input: Fifo<INPUT_FIFO_CAPACITY>,
+    /// The vector of the armed input interrupt.
+    vector: Option<u8>,
+}
+
+impl HvcConsole {
+    fn new() -> Self {
+        Self {
+            backend: DefaultConsoleBackend::new(),
+            input: Fifo::new(),
+            vector: None,
+        }
+    }
+
+    fn fill_input(&mut self) {
+        while !self.input.is_full() {
+            match self.backend.getchar() {
+                Some(c) => self.input.push(c),
+                None => break,
+            }
+        }
+    }
+}
+
+static HVC_CONSOLES: Mutex<BTreeMap<u32, HvcConsole>> = Mutex::new(BTreeMap::new());
+/// Number of armed input interrupts, lets `check_events` skip the lookup when there is none.
+static ARMED_CONSOLES: AtomicUsize = AtomicUsize::new(0);
+
+fn with_console<T>(vm_id: u32, f: impl FnOnce(&mut HvcConsole) -> T) -> T {
+    f(HVC_CONSOLES
+        .lock()
+        .entry(vm_id)
+        .or_insert_with(HvcConsole::new))
+}
+
+/// Write `buf` from `vm_id` to the host console.
+pub fn write(vm_id: u32, buf: &[u8]) -> usize {
+    crate::console_ring::drain(vm_id);
+    with_console(vm_id, |console| {
+        for &c in buf {
+            console.backend.putchar(c);
+        }
+    });
+    buf.len()
+}
+
+/// Read the host input of `vm_id` into `buf`, returns the number of bytes read.
+pub fn read(vm_id: u32, buf: &mut [u8]) -> usize {
+    with_console(vm_id, |console| {
+        let mut len = 0;
+        while len < buf.len() && !console.input.is_empty() {
+            buf[len] = console.input.pop();
+            len += 1;
+        }
+        while len < buf.len() {
+            match console.backend.getchar() {
+                Some(c) => buf[len] = c,
+                None => break,
+            }
+            len += 1;
+        }
+        len
+    })
+}
+
+/// Returns the number of bytes ready to be read by `vm_id`, and arms its input interrupt at
+/// `vector` if there is none. Vector 0 disarms it.
+pub fn poll(vm_id: u32, vector: usize) -> Result<usize> {
+    if vector != 0 && !(MIN_VECTOR..256).contains(&vector) {
+        return Err(Error::InvalidParam);
+    }
+    Ok(with_console(vm_id, |console| {
+        console.fill_input();
+        let ready = console.input.len();
+        let was_armed = console.vector.take().is_some();
+        if vector != 0 && ready == 0 {
+            console.vector = Some(vector as u8);
+        }
+        match (was_armed, console.vector.is_some()) {
+            (false, true) => {
+                ARMED_CONSOLES.fetch_add(1, Ordering::Release);
+            }
+            (true, false) => {
+                ARMED_CONSOLES.fetch_sub(1, Ordering::Release);
+            }
+            _ => {}
+        }
+        ready
+    }))
+}
+
+/// The vector to inject to the VM running on the current CPU, if its input interrupt is armed
+/// and input arrived. Called from `check_events`, skips the check if another CPU holds the
+/// consoles.
+pub(crate) fn check_input() -> Option<u8> {
+    if ARMED_CONSOLES.load(Ordering::Acquire) == 0 {
+        return None;
+    }
+    let vm_id = crate::vm::current_vm_id()?;
+    let mut consoles = HVC_CONSOLES.try_lock()?;
+    let console = consoles.get_mut(&vm_id)?;
+    console.vector?;
+    console.fill_input();
+    if console.input.is_empty() {
+        return None;
+    }
+    ARMED_CONSOLES.fetch_sub(1, Ordering::Release);
+    console.vector.take()
+}
+
+/// Forget the console of `vm_id`, once the VM stopped.
+pub fn unregister(vm_id: u32) {
+    if let Some(console) = HVC_CONSOLES.lock().remove(&vm_id) {
+        if console.vector.is_some() {
+            ARMED_CONSOLES.fetch_sub(1, Ordering::Release);
+        }
+    }
+}
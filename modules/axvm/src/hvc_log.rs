@@ -0,0 +1,88 @@
+//! Debug log of guests over a hypercall, a message per exit instead of a byte per UART exit.
+//!
+//! | hypercall | arguments                      | returns        |
+//! |-----------|--------------------------------|----------------|
+//! | `HVC_LOG` | level, buffer GPA, length      | bytes consumed |
+//!
+//! The level is one of the [`HVC_LOG_ERROR`](crate::hvc_abi::HVC_LOG_ERROR) to
+//! [`HVC_LOG_TRACE`](crate::hvc_abi::HVC_LOG_TRACE) of [`crate::hvc_abi`]. At most
+//! [`HVC_LOG_MAX`] bytes of the buffer are consumed: a longer message is cut, and the guest logs
+//! the rest with another call. The buffer must be guest RAM, every page of it, and may cross
+//! pages. It is decoded as UTF-8, invalid sequences replaced, and logged on the host at the level
+//! of the guest, prefixed by the ids of the VM and the vCPU.
+//!
+//! Each VM logs at most [`GUEST_LOG_BURST`] messages per second, the others are consumed but
+//! folded into a "suppressed" summary, so that a guest cannot flood the log of the host.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use log::Level;
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::hvc_abi::{
+    HVC_LOG_DEBUG, HVC_LOG_ERROR, HVC_LOG_INFO, HVC_LOG_MAX, HVC_LOG_TRACE, HVC_LOG_WARN,
+};
+use crate::ratelimit::RateLimiter;
+use crate::{Error, GuestPhysAddr, Result};
+
+/// Messages a VM may log per second.
+pub const GUEST_LOG_BURST: u32 = 20;
+
+/// The rate limiters of the VMs which logged.
+static GUEST_LOGS: Mutex<BTreeMap<u32, RateLimiter>> = Mutex::new(BTreeMap::new());
+
+/// Handle `HVC_LOG` of vCPU `vcpu_id` of `vm_id`, see the [module documentation](self). Fails
+/// with `InvalidParam` for an unknown level or a buffer which is not RAM.
+pub(crate) fn log(
+    vm_id: u32,
+    vcpu_id: usize,
+    level: usize,
+    gpa: GuestPhysAddr,
+    len: usize,
+) -> Result<u32> {
+    let level = match level {
+        HVC_LOG_ERROR => Level::Error,
+        HVC_LOG_WARN => Level::Warn,
+        HVC_LOG_INFO => Level::Info,
+        HVC_LOG_DEBUG => Level::Debug,
+        HVC_LOG_TRACE => Level::Trace,
+        _ => return Err(Error::InvalidParam),
+    };
+    let len = len.min(HVC_LOG_MAX);
+    let end = gpa.checked_add(len).ok_or(Error::InvalidParam)?;
+    let mut buf = Vec::with_capacity(len);
+    let mut cur = gpa;
+    while cur < end {
+        let offset = cur % PAGE_SIZE_4K;
+        let chunk = (PAGE_SIZE_4K - offset).min(end - cur);
+        let page =
+            crate::hvc::guest_ram_page_hva(vm_id, cur - offset).map_err(|_| Error::InvalidParam)?;
+        for i in 0..chunk {
+            // The guest may write its buffer concurrently.
+            buf.push(unsafe { page.add(offset + i).read_volatile() });
+        }
+        cur += chunk;
+    }
+    let message = String::from_utf8_lossy(&buf);
+    let mut limiters = GUEST_LOGS.lock();
+    let limiter = limiters
+        .entry(vm_id)
+        .or_insert_with(|| RateLimiter::new("guest log", GUEST_LOG_BURST));
+    ratelimited!(
+        limiter,
+        level,
+        "[VM {}] vCPU {}: {}",
+        vm_id,
+        vcpu_id,
+        message.trim_end()
+    );
+    Ok(len as u32)
+}
+
+/// Forget the rate limiter of `vm_id`, once the VM stopped.
+pub(crate) fn unregister(vm_id: u32) {
+    GUEST_LOGS.lock().remove(&vm_id);
+}
@@ -0,0 +1,455 @@
+//! Guest image bundles: the images of a guest in one blob, with a header telling where each of
+//! them goes in guest memory.
+//!
+//! A bundle starts with a [`GuestImageHeader`], all fields little-endian:
+//!
+//! | offset | size | field                                          |
+//! |--------|------|------------------------------------------------|
+//! | 0      | 8    | magic, [`GUEST_IMAGE_MAGIC`]                   |
+//! | 8      | 4    | version, [`GUEST_IMAGE_VERSION`]               |
+//! | 12     | 4    | VM type, 1 for NimbOS and 2 for Linux          |
+//! | 16     | 8    | entry point, a guest physical address          |
+//! | 24     | 4    | offset of the command line in the bundle       |
+//! | 28     | 4    | length of the command line, 0 if there is none |
+//! | 32     | 4    | number of segments                             |
+//! | 36     | 4    | reserved, 0                                    |
+//!
+//! followed by the segment table, 24 bytes per segment: the offset of its data in the bundle, its
+//! size and the guest physical address it is loaded at, as `u64`s.
+//!
+//! A bundle without the magic is in the layout used before the header: the sizes of the BIOS,
+//! the kernel and the ramdisk as three `u64`s, then the images back to back, loaded at the
+//! addresses of the VM type, see [`crate::config`].
+//!
+//! The command line is copied to guest memory at [`CMDLINE_GPA`], NUL-terminated, and its
+//! address is passed to the guest in RSI, as the boot parameters of the Linux 64-bit boot
+//! protocol.
+
+use alloc::vec::Vec;
+
+use crate::config::entry::VmType;
+use crate::config::{
+    LINUX_BIOS_LOAD_GPA, LINUX_KERNEL_LOAD_GPA, LINUX_RAMDISK_LOAD_GPA, LINUX_VM_ENTRY,
+    NIMBOS_BIOS_LOAD_GPA, NIMBOS_KERNEL_LOAD_GPA, NIMBOS_VM_ENTRY,
+};
+use crate::{Error, GuestPhysAddr, Result};
+
+pub const GUEST_IMAGE_MAGIC: [u8; 8] = *b"AXVMIMG\0";
+pub const GUEST_IMAGE_VERSION: u32 = 1;
+
+/// Guest physical address the command line is copied to.
+pub const CMDLINE_GPA: GuestPhysAddr = 0x2_0000;
+/// Longest command line, NUL included.
+pub const CMDLINE_MAX: usize = 4096;
+
+const HEADER_SIZE: usize = 40;
+const SEGMENT_ENTRY_SIZE: usize = 24;
+const LEGACY_HEADER_SIZE: usize = 24;
+
+/// An image of a bundle: `size` bytes at `offset` in the bundle, loaded at `load_gpa`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageSegment {
+    pub offset: usize,
+    pub size: usize,
+    pub load_gpa: GuestPhysAddr,
+}
+
+/// The header of a guest image bundle, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestImageHeader {
+    pub version: u32,
+    pub vm_type: VmType,
+    pub entry: GuestPhysAddr,
+    pub segments: Vec<ImageSegment>,
+    /// Offset and length of the command line in the bundle.
+    pub cmdline: Option<(usize, usize)>,
+}
+
+impl GuestImageHeader {
+    /// Parse and check the header of `bundle`, in the legacy layout if it has no magic, its
+    /// images then being those of a `vm_type` guest. Fails with `InvalidParam` if the header
+    /// does not describe a guest, places an image or the command line outside of the bundle, or
+    /// two images over each other in guest memory, and with `NotSupported` for another version.
+    pub fn parse(bundle: &[u8], vm_type: VmType) -> Result<Self> {
+        let header = Self::parse_header(bundle, vm_type)?;
+        header.check(bundle)?;
+        Ok(header)
+    }
+
+    /// The length of the bundle starting with `prefix`, which holds at least its header and
+    /// segment table: up to the end of its last image or of its command line. Fails like
+    /// [`GuestImageHeader::parse`] if `prefix` does not hold the header.
+    pub fn bundle_len(prefix: &[u8], vm_type: VmType) -> Result<usize> {
+        let header = Self::parse_header(prefix, vm_type)?;
+        let table_end = match header.version {
+            0 => LEGACY_HEADER_SIZE,
+            _ => HEADER_SIZE + header.segments.len() * SEGMENT_ENTRY_SIZE,
+        };
+        let mut ends = header
+            .segments
+            .iter()
+            .map(|segment| segment.offset.checked_add(segment.size))
+            .chain(header.cmdline.map(|(offset, len)| offset.checked_add(len)));
+        ends.try_fold(table_end, |len, end| Some(len.max(end?)))
+            .ok_or_else(|| {
+                warn!("guest image segment past the end of the address space");
+                Error::InvalidParam
+            })
+    }
+
+    /// Parse the header at the start of `bundle`, without checking where it places the images.
+    fn parse_header(bundle: &[u8], vm_type: VmType) -> Result<Self> {
+        if bundle.get(..GUEST_IMAGE_MAGIC.len()) != Some(&GUEST_IMAGE_MAGIC[..]) {
+            return Self::parse_legacy(bundle, vm_type);
+        }
+        if bundle.len() < HEADER_SIZE {
+            warn!("guest image header truncated");
+            return Err(Error::InvalidParam);
+        }
+        let version = u32_at(bundle, 8);
+        if version != GUEST_IMAGE_VERSION {
+            warn!("guest image version {} not supported", version);
+            return Err(Error::NotSupported);
+        }
+        let vm_type = match u32_at(bundle, 12) {
+            1 => VmType::VmTNimbOS,
+            2 => VmType::VmTLinux,
+            vm_type => {
+                warn!("guest image of unknown VM type {}", vm_type);
+                return Err(Error::InvalidParam);
+            }
+        };
+        let entry = u64_at(bundle, 16);
+        let cmdline = match (u32_at(bundle, 24) as usize, u32_at(bundle, 28) as usize) {
+            (_, 0) => None,
+            (offset, len) => Some((offset, len)),
+        };
+        let count = u32_at(bundle, 32) as usize;
+        let table_end = count
+            .checked_mul(SEGMENT_ENTRY_SIZE)
+            .and_then(|size| size.checked_add(HEADER_SIZE))
+            .filter(|&end| end <= bundle.len())
+            .ok_or_else(|| {
+                warn!("guest image segment table truncated");
+                Error::InvalidParam
+            })?;
+        let segments = bundle[HEADER_SIZE..table_end]
+            .chunks_exact(SEGMENT_ENTRY_SIZE)
+            .map(|entry| ImageSegment {
+                offset: u64_at(entry, 0),
+                size: u64_at(entry, 8),
+                load_gpa: u64_at(entry, 16),
+            })
+            .collect();
+        Ok(Self {
+            version,
+            vm_type,
+            entry,
+            segments,
+            cmdline,
+        })
+    }
+
+    /// The header of a bundle in the legacy layout, for a `vm_type` guest.
+    fn parse_legacy(bundle: &[u8], vm_type: VmType) -> Result<Self> {
+        let (entry, load_gpas) = match vm_type {
+            VmType::VmTNimbOS => (
+                NIMBOS_VM_ENTRY,
+                [NIMBOS_BIOS_LOAD_GPA, NIMBOS_KERNEL_LOAD_GPA, 0],
+            ),
+            VmType::VmTLinux => (
+                LINUX_VM_ENTRY,
+                [
+                    LINUX_BIOS_LOAD_GPA,
+                    LINUX_KERNEL_LOAD_GPA,
+                    LINUX_RAMDISK_LOAD_GPA,
+                ],
+            ),
+            VmType::VmTUnknown => return Err(Error::InvalidParam),
+        };
+        if bundle.len() < LEGACY_HEADER_SIZE {
+            warn!("guest image without header, and too short for the legacy layout");
+            return Err(Error::InvalidParam);
+        }
+        let mut offset = LEGACY_HEADER_SIZE;
+        let mut segments = Vec::new();
+        for (i, load_gpa) in load_gpas.into_iter().enumerate() {
+            let size = u64_at(bundle, i * 8);
+            if size == 0 {
+                continue;
+            }
+            // NimbOS has no ramdisk.
+            if load_gpa == 0 {
+                warn!("legacy guest image with a ramdisk, for a guest without one");
+                return Err(Error::InvalidParam);
+            }
+            segments.push(ImageSegment {
+                offset,
+                size,
+                load_gpa,
+            });
+            offset = offset.checked_add(size).ok_or(Error::InvalidParam)?;
+        }
+        Ok(Self {
+            version: 0,
+            vm_type,
+            entry,
+            segments,
+            cmdline: None,
+        })
+    }
+
+    /// Check that the images and the command line are within `bundle`.
+    fn check(&self, bundle: &[u8]) -> Result {
+        let within = |offset: usize, size: usize| {
+            offset
+                .checked_add(size)
+                .map_or(false, |end| end <= bundle.len())
+        };
+        for segment in self.segments.iter() {
+            let fits_gpa = segment.load_gpa.checked_add(segment.size).is_some();
+            if !within(segment.offset, segment.size) || !fits_gpa {
+                warn!("guest image segment {:#x?} outside of the bundle", segment);
+                return Err(Error::InvalidParam);
+            }
+        }
+        // The image loaded last would win, whatever the order the tools meant.
+        let mut loads: Vec<_> = self
+            .segments
+            .iter()
+            .filter(|segment| segment.size != 0)
+            .map(|segment| segment.load_gpa..segment.load_gpa + segment.size)
+            .collect();
+        loads.sort_by_key(|load| load.start);
+        if let Some(pair) = loads.windows(2).find(|pair| pair[0].end > pair[1].start) {
+            warn!(
+                "guest image segments loaded at {:#x?} and {:#x?} overlap",
+                pair[0], pair[1]
+            );
+            return Err(Error::InvalidParam);
+        }
+        if let Some((offset, len)) = self.cmdline {
+            if !within(offset, len) || len >= CMDLINE_MAX {
+                warn!(
+                    "guest command line at {:#x} ({:#x} bytes) outside of the bundle or too long",
+                    offset, len
+                );
+                return Err(Error::InvalidParam);
+            }
+        }
+        Ok(())
+    }
+
+    /// The data of `segment`, one of the segments of this header, in `bundle`.
+    pub fn segment_data<'a>(&self, bundle: &'a [u8], segment: &ImageSegment) -> &'a [u8] {
+        &bundle[segment.offset..segment.offset + segment.size]
+    }
+
+    /// The command line in `bundle`, without a NUL.
+    pub fn cmdline<'a>(&self, bundle: &'a [u8]) -> Option<&'a [u8]> {
+        self.cmdline
+            .map(|(offset, len)| &bundle[offset..offset + len])
+    }
+}
+
+/// The `u32` at `offset` of `bytes`, which the caller checked is long enough.
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> usize {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// A bundle of a `vm_type` guest entered at `entry`, with `segments` of data loaded at their
+    /// address, then `cmdline`.
+    fn bundle(vm_type: u32, entry: u64, segments: &[(u64, &[u8])], cmdline: &[u8]) -> Vec<u8> {
+        let mut bundle = vec![0u8; HEADER_SIZE + segments.len() * SEGMENT_ENTRY_SIZE];
+        bundle[..8].copy_from_slice(&GUEST_IMAGE_MAGIC);
+        bundle[8..12].copy_from_slice(&GUEST_IMAGE_VERSION.to_le_bytes());
+        bundle[12..16].copy_from_slice(&vm_type.to_le_bytes());
+        bundle[16..24].copy_from_slice(&entry.to_le_bytes());
+        bundle[32..36].copy_from_slice(&(segments.len() as u32).to_le_bytes());
+        for (i, &(load_gpa, data)) in segments.iter().enumerate() {
+            let offset = bundle.len() as u64;
+            let entry = &mut bundle[HEADER_SIZE + i * SEGMENT_ENTRY_SIZE..];
+            entry[..8].copy_from_slice(&offset.to_le_bytes());
+            entry[8..16].copy_from_slice(&(data.len() as u64).to_le_bytes());
+            entry[16..24].copy_from_slice(&load_gpa.to_le_bytes());
+            bundle.extend_from_slice(data);
+        }
+        if !cmdline.is_empty() {
+            let offset = bundle.len() as u32;
+            bundle[24..28].copy_from_slice(&offset.to_le_bytes());
+            bundle[28..32].copy_from_slice(&(cmdline.len() as u32).to_le_bytes());
+            bundle.extend_from_slice(cmdline);
+        }
+        bundle
+    }
+
+    /// The legacy layout: the sizes of the three images, then the images.
+    fn legacy(images: [&[u8]; 3]) -> Vec<u8> {
+        let mut bundle = Vec::new();
+        for image in images {
+            bundle.extend_from_slice(&(image.len() as u64).to_le_bytes());
+        }
+        images
+            .iter()
+            .for_each(|image| bundle.extend_from_slice(image));
+        bundle
+    }
+
+    fn assert_invalid(bundle: &[u8]) {
+        assert!(matches!(
+            GuestImageHeader::parse(bundle, VmType::VmTLinux),
+            Err(Error::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn test_parse() {
+        let segments: [(u64, &[u8]); 2] = [(0x8000, &[1, 2, 3]), (0x20_0000, &[4, 5, 6, 7])];
+        let bundle = bundle(1, 0x8000, &segments, b"console=ttyS0");
+        // The VM type of the header wins over the one of the configuration.
+        let header = GuestImageHeader::parse(&bundle, VmType::VmTLinux).unwrap();
+        assert_eq!(header.version, GUEST_IMAGE_VERSION);
+        assert_eq!(header.vm_type, VmType::VmTNimbOS);
+        assert_eq!(header.entry, 0x8000);
+        assert_eq!(header.segments.len(), 2);
+        for (segment, (load_gpa, data)) in header.segments.iter().zip(segments) {
+            assert_eq!(segment.load_gpa, load_gpa as usize);
+            assert_eq!(header.segment_data(&bundle, segment), data);
+        }
+        assert_eq!(header.cmdline(&bundle), Some(&b"console=ttyS0"[..]));
+        assert_eq!(
+            GuestImageHeader::bundle_len(&bundle, VmType::VmTLinux).unwrap(),
+            bundle.len()
+        );
+        // The bundle may be followed by anything.
+        let mut padded = bundle.clone();
+        padded.extend_from_slice(&[0xff; 16]);
+        assert_eq!(
+            GuestImageHeader::parse(&padded, VmType::VmTLinux).unwrap(),
+            header
+        );
+    }
+
+    #[test]
+    fn test_bad_magic_and_version() {
+        let good = bundle(2, 0x7c00, &[(0x7c00, &[0x90; 16])], b"");
+        // Parsed in the legacy layout, whose sizes are then the magic and the version.
+        let mut bad_magic = good.clone();
+        bad_magic[7] = b'1';
+        assert_invalid(&bad_magic);
+
+        for version in [0, 2, u32::MAX] {
+            let mut bad_version = good.clone();
+            bad_version[8..12].copy_from_slice(&version.to_le_bytes());
+            assert!(matches!(
+                GuestImageHeader::parse(&bad_version, VmType::VmTLinux),
+                Err(Error::NotSupported)
+            ));
+        }
+        let mut bad_vm_type = good.clone();
+        bad_vm_type[12..16].copy_from_slice(&3u32.to_le_bytes());
+        assert_invalid(&bad_vm_type);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let good = bundle(2, 0x7c00, &[(0x7c00, &[1; 8]), (0x7000_0000, &[2; 8])], b"");
+        // The header, the segment table and the images.
+        assert_invalid(&good[..HEADER_SIZE - 1]);
+        let table_end = HEADER_SIZE + 2 * SEGMENT_ENTRY_SIZE;
+        assert_invalid(&good[..table_end - 1]);
+        assert!(matches!(
+            GuestImageHeader::bundle_len(&good[..table_end - 1], VmType::VmTLinux),
+            Err(Error::InvalidParam)
+        ));
+        assert_eq!(
+            GuestImageHeader::bundle_len(&good[..table_end], VmType::VmTLinux).unwrap(),
+            good.len()
+        );
+        assert_invalid(&good[..good.len() - 1]);
+
+        // A count whose table would not fit in the address space.
+        let mut huge_count = good.clone();
+        huge_count[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_invalid(&huge_count);
+        // A segment past the end of the bundle, or of the address space.
+        let mut past_end = good.clone();
+        past_end[HEADER_SIZE..HEADER_SIZE + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_invalid(&past_end);
+        let mut past_gpa = good.clone();
+        past_gpa[HEADER_SIZE + 16..HEADER_SIZE + 24].copy_from_slice(&(u64::MAX - 4).to_le_bytes());
+        assert_invalid(&past_gpa);
+
+        // A command line past the end of the bundle, or too long.
+        let with_cmdline = bundle(2, 0x7c00, &[], b"quiet");
+        assert_invalid(&with_cmdline[..with_cmdline.len() - 1]);
+        let long = vec![b'x'; CMDLINE_MAX];
+        assert_invalid(&bundle(2, 0x7c00, &[], &long));
+        assert!(
+            GuestImageHeader::parse(&bundle(2, 0x7c00, &[], &long[1..]), VmType::VmTLinux).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_overlapping_segments() {
+        let data = [0u8; 0x10];
+        // Overlapping in guest memory, in either order.
+        assert_invalid(&bundle(2, 0, &[(0x1000, &data), (0x1008, &data)], b""));
+        assert_invalid(&bundle(2, 0, &[(0x1008, &data), (0x1000, &data)], b""));
+        assert_invalid(&bundle(2, 0, &[(0x1000, &data), (0x1000, &data[..1])], b""));
+        // Back to back, or empty.
+        let adjacent = [(0x1010, &data[..]), (0x1000, &data), (0x1008, &[])];
+        let header = GuestImageHeader::parse(&bundle(2, 0, &adjacent, b""), VmType::VmTLinux);
+        assert_eq!(header.unwrap().segments.len(), 3);
+    }
+
+    #[test]
+    fn test_legacy() {
+        let (bios, kernel, ramdisk) = ([1u8; 4], [2u8; 6], [3u8; 2]);
+        let nimbos = legacy([&bios, &kernel, &[]]);
+        let header = GuestImageHeader::parse(&nimbos, VmType::VmTNimbOS).unwrap();
+        assert_eq!((header.version, header.vm_type), (0, VmType::VmTNimbOS));
+        assert_eq!(header.entry, NIMBOS_VM_ENTRY);
+        assert_eq!(header.cmdline, None);
+        let expected = [
+            (LEGACY_HEADER_SIZE, 4, NIMBOS_BIOS_LOAD_GPA),
+            (LEGACY_HEADER_SIZE + 4, 6, NIMBOS_KERNEL_LOAD_GPA),
+        ];
+        for (segment, (offset, size, load_gpa)) in header.segments.iter().zip(expected) {
+            assert_eq!(
+                *segment,
+                ImageSegment {
+                    offset,
+                    size,
+                    load_gpa
+                }
+            );
+        }
+        assert_eq!(header.segment_data(&nimbos, &header.segments[1]), kernel);
+        assert_eq!(
+            GuestImageHeader::bundle_len(&nimbos, VmType::VmTNimbOS).unwrap(),
+            nimbos.len()
+        );
+
+        let linux = legacy([&bios, &kernel, &ramdisk]);
+        let header = GuestImageHeader::parse(&linux, VmType::VmTLinux).unwrap();
+        assert_eq!(header.entry, LINUX_VM_ENTRY);
+        assert_eq!(header.segments[2].load_gpa, LINUX_RAMDISK_LOAD_GPA);
+        assert_eq!(header.segment_data(&linux, &header.segments[2]), ramdisk);
+
+        // NimbOS has no ramdisk, the VM type must be known, and the sizes must fit.
+        assert!(GuestImageHeader::parse(&linux, VmType::VmTNimbOS).is_err());
+        assert!(GuestImageHeader::parse(&nimbos, VmType::VmTUnknown).is_err());
+        assert_invalid(&linux[..LEGACY_HEADER_SIZE - 1]);
+        assert_invalid(&linux[..linux.len() - 1]);
+    }
+}
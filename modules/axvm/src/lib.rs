@@ -19,22 +19,119 @@ extern crate log;
 #[macro_use]
 extern crate pci;
 
+#[macro_use]
+mod ratelimit;
+
+mod completion;
 mod config;
+mod console_mux;
+mod console_ring;
 // #[cfg(target_arch = "x86_64")]
 mod device;
 mod mm;
 
+#[cfg(target_arch = "x86_64")]
+mod acpi;
 mod arch;
+#[cfg(target_arch = "x86_64")]
+mod builder;
+#[cfg(target_arch = "x86_64")]
+mod elf_loader;
+#[cfg(target_arch = "x86_64")]
+mod linux_loader;
 
 mod hvc;
+pub mod hvc_abi;
+mod hvc_console;
+mod hvc_log;
+mod image;
 mod irq;
+mod memory_map;
 mod nmi;
 mod page_table;
+mod park;
+mod shared_mem;
+#[cfg(target_arch = "x86_64")]
+mod shell;
+#[cfg(target_arch = "x86_64")]
+mod snapshot;
 
 // pub use nmi::cpu_nmi_list_init;
 
 mod vm;
 pub use vm::*;
+#[cfg(target_arch = "x86_64")]
+mod wall_clock;
+
+#[cfg(target_arch = "x86_64")]
+pub use acpi::{ACPI_TABLES_GPA, ACPI_TABLES_MAX};
+#[cfg(target_arch = "x86_64")]
+pub use builder::{VmBuilder, VmHandle};
+pub use config::entry::{
+    PciEcamCfg, ResetPolicy, UnhandledMsrPolicy, UnhandledPortPolicy, VcpuAffinity,
+    VirtioDeviceCfg, VmType, PCI_ECAM_DEFAULT_BASE,
+};
+#[cfg(target_arch = "x86_64")]
+pub use device::{
+    dump_exit_stats, ignored_ports, vmcs_read_stats, CpuidPolicy, CpuidReg, CpuidRule, CrPinning,
+    DecodeCacheStats, ExitLatencyHistogram, ExitObserverFn, ExitOutcome, ExitReasonStats,
+    ExitStats, HotplugAddress, HotplugDevice, ObserverCtx, ObserverId, ObserverPhase,
+    VcpuDeviceConfig, VmcsReadStats, DEFAULT_APIC_BUS_FREQ_HZ, HV_FEATURE_CONSOLE, HV_FEATURE_LOG,
+    HV_FEATURE_MEMORY_MAP, HV_FEATURE_PVCLOCK, HYPERVISOR_SIGNATURE,
+};
+#[cfg(target_arch = "riscv64")]
+pub use device::{
+    handle_mmio, handle_sbi, supervisor_context, MmioAccess, MmioVcpuDevices, MmioVmDevices, Plic,
+    Riscv64VcpuDevices, Riscv64VmDevices, SbiCall, SbiRet, VirtualInterrupts, PLIC_BASE,
+    PLIC_SOURCES,
+};
+#[cfg(target_arch = "aarch64")]
+pub use device::{
+    handle_mmio, Aarch64VcpuDevices, Aarch64VmDevices, GicCpuInterface, GicDistributor, Gicv2,
+    MmioAccess, MmioVcpuDevices, MmioVmDevices, Pl011, GICC_BASE, GICD_BASE, GIC_MAX_IRQS,
+    PL011_BASE, PL011_IRQ,
+};
+pub use device::{
+    BlockBackend, DeviceState, MemoryNet, NetBackend, PioBatchOps, RamDisk, StateReader,
+    StateWriter, MAX_FRAME_LEN,
+};
+#[cfg(feature = "virtio-blk-file")]
+pub use device::FileDisk;
+#[cfg(feature = "virtio-pci")]
+pub use device::{
+    set_virtio_workers, virtio_net_stats, virtio_worker_stats, IvshmemDevice, VirtioNetStats,
+    VirtioWorkerStats, IVSHMEM_MSIX_VECTORS, MAX_SHMEM_CHANNEL_SIZE,
+};
+
+pub use console_mux::{
+    console_focus, set_console_focus, ConsoleFocus, MultiplexConsole, CONSOLE_ESCAPE,
+    CONSOLE_HISTORY_LEN,
+};
+pub use image::{
+    GuestImageHeader, ImageSegment, CMDLINE_GPA, CMDLINE_MAX, GUEST_IMAGE_MAGIC,
+    GUEST_IMAGE_VERSION,
+};
+pub use memory_map::{
+    memory_map, MemoryMapEntry, MEMORY_MAP_ACPI, MEMORY_MAP_ENTRY_SIZE, MEMORY_MAP_MMIO,
+    MEMORY_MAP_RAM, MEMORY_MAP_RESERVED, MEMORY_MAP_TOO_SMALL,
+};
+#[cfg(target_arch = "x86_64")]
+pub use mm::{
+    copied_pages, dirty_stats, faulted_pages, fetch_and_reset_dirty, start_dirty_tracking,
+    stop_dirty_tracking, DirtyStats,
+};
+pub use nmi::{nmi_stats, NmiStats};
+pub use park::{park_stats, wake_vcpu, ParkStats, VcpuWaker};
+pub use shared_mem::{with_shared_region, ShareFlags, SharedRegion};
+#[cfg(target_arch = "x86_64")]
+pub use shell::spawn_shell;
+#[cfg(target_arch = "x86_64")]
+pub use snapshot::VM_SNAPSHOT_VERSION;
+#[cfg(target_arch = "x86_64")]
+pub use wall_clock::{set_wall_clock, wall_clock_ns, DateTime};
+
+/// Print the most contended device locks, with the `lock_stats` feature.
+pub use lock_stat::dump as dump_lock_stats;
 
 pub use arch::{PerCpu, VCpu};
 
@@ -0,0 +1,634 @@
+//! Booting Linux from a bzImage through the x86 boot protocol, see
+//! `Documentation/arch/x86/boot.rst` in the kernel tree.
+//!
+//! The loader does what a boot loader would before the 32-bit entry of the kernel:
+//!
+//! - the real-mode setup code is loaded at [`SETUP_GPA`], and the protected-mode kernel at
+//!   [`KERNEL_GPA`], 1 MiB, where it decompresses itself;
+//! - the zero page, `struct boot_params`, is built at [`BOOT_PARAMS_GPA`] from the setup header
+//!   of the image, with the command line at [`CMDLINE_GPA`], the initrd at the top of the RAM it
+//!   may use, an E820 map of the guest physical memory, and the address of the RSDP of the ACPI
+//!   tables, if any;
+//! - the guest starts in real mode at [`LINUX_VM_ENTRY`], where a stub switches to flat 32-bit
+//!   protected mode and jumps to the kernel with ESI pointing at the zero page.
+//!
+//! The vlbl guest in `apps/hv/guest` boots the images of the type 1.5 Linux through the 16-bit
+//! protocol instead, this loader is for the guests of [`VmBuilder`](crate::VmBuilder).
+
+use alloc::borrow::Cow;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::config::LINUX_VM_ENTRY;
+use crate::image::{CMDLINE_GPA, CMDLINE_MAX};
+use crate::{Error, GuestPhysAddr, Result};
+
+/// The zero page.
+pub(crate) const BOOT_PARAMS_GPA: GuestPhysAddr = 0x1_0000;
+/// The real-mode setup code, not run with the 32-bit entry but loaded as the protocol places it.
+const SETUP_GPA: GuestPhysAddr = 0x9_0000;
+/// The protected-mode kernel, `code32_start`.
+const KERNEL_GPA: GuestPhysAddr = 0x10_0000;
+/// Conventional memory ends with the EBDA, reserved up to 1 MiB with the legacy BIOS area.
+const LOW_RAM_END: GuestPhysAddr = 0x9_f000;
+
+const PAGE_SIZE: usize = 0x1000;
+const SECTOR_SIZE: usize = 512;
+
+// Offsets in the setup header, which is at the same offset in the image and in the zero page.
+const HDR_SETUP_SECTS: usize = 0x1f1;
+const HDR_BOOT_FLAG: usize = 0x1fe;
+const HDR_JUMP: usize = 0x200;
+const HDR_MAGIC: usize = 0x202;
+const HDR_VERSION: usize = 0x206;
+const HDR_TYPE_OF_LOADER: usize = 0x210;
+const HDR_LOADFLAGS: usize = 0x211;
+const HDR_CODE32_START: usize = 0x214;
+const HDR_RAMDISK_IMAGE: usize = 0x218;
+const HDR_RAMDISK_SIZE: usize = 0x21c;
+const HDR_CMD_LINE_PTR: usize = 0x228;
+const HDR_INITRD_ADDR_MAX: usize = 0x22c;
+const HDR_CMDLINE_SIZE: usize = 0x238;
+const HDR_INIT_SIZE: usize = 0x260;
+/// The end of the setup header, the zero page continuing with the EDD buffer.
+const HDR_MAX_END: usize = 0x290;
+
+// Offsets in the zero page.
+const BP_ACPI_RSDP_ADDR: usize = 0x070;
+const BP_E820_ENTRIES: usize = 0x1e8;
+const BP_E820_TABLE: usize = 0x2d0;
+const E820_MAX_ENTRIES: usize = 128;
+const E820_ENTRY_SIZE: usize = 20;
+const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
+
+const BOOT_FLAG: u16 = 0xaa55;
+const HEADER_MAGIC: &[u8; 4] = b"HdrS";
+/// 2.10, the first version with `init_size`.
+const MIN_VERSION: u16 = 0x020a;
+const LOADED_HIGH: u8 = 1 << 0;
+/// A boot loader without an assigned ID.
+const LOADER_UNDEFINED: u8 = 0xff;
+
+/// A bzImage, and the initrd to boot it with.
+pub(crate) struct LinuxKernel {
+    image: &'static [u8],
+    initrd: Option<&'static [u8]>,
+    /// Size of the real-mode setup code, the boot sector included.
+    setup_size: usize,
+    /// Memory the kernel needs at [`KERNEL_GPA`] to decompress and run.
+    init_size: usize,
+    initrd_addr_max: usize,
+    cmdline_size: usize,
+    header_end: usize,
+}
+
+/// Data to load into guest memory for the boot of the kernel.
+pub(crate) struct LinuxSegment {
+    pub gpa: GuestPhysAddr,
+    pub data: Cow<'static, [u8]>,
+}
+
+impl LinuxKernel {
+    /// Check the setup header of the bzImage `image`. Fails with `InvalidParam` if it is not a
+    /// bzImage, and with `NotSupported` for a boot protocol older than 2.10.
+    pub fn parse(image: &'static [u8], initrd: Option<&'static [u8]>) -> Result<Self> {
+        if image.len() < HDR_MAX_END
+            || u16_at(image, HDR_BOOT_FLAG) != BOOT_FLAG
+            || &image[HDR_MAGIC..HDR_MAGIC + 4] != HEADER_MAGIC
+        {
+            warn!("not a bzImage, no setup header");
+            return Err(Error::InvalidParam);
+        }
+        let version = u16_at(image, HDR_VERSION);
+        if version < MIN_VERSION {
+            warn!("boot protocol {:#x} of the kernel not supported", version);
+            return Err(Error::NotSupported);
+        }
+        if image[HDR_LOADFLAGS] & LOADED_HIGH == 0 {
+            warn!("zImage not supported, the kernel must load at 1 MiB");
+            return Err(Error::NotSupported);
+        }
+        let setup_sects = match image[HDR_SETUP_SECTS] as usize {
+            0 => 4,
+            sects => sects,
+        };
+        let setup_size = (setup_sects + 1) * SECTOR_SIZE;
+        if setup_size >= image.len() {
+            warn!(
+                "bzImage truncated, {:#x} bytes with {:#x} of setup code",
+                image.len(),
+                setup_size
+            );
+            return Err(Error::InvalidParam);
+        }
+        let header_end = (HDR_JUMP + 2 + image[HDR_JUMP + 1] as usize).min(HDR_MAX_END);
+        Ok(Self {
+            image,
+            initrd,
+            setup_size,
+            init_size: u32_at(image, HDR_INIT_SIZE) as usize,
+            initrd_addr_max: u32_at(image, HDR_INITRD_ADDR_MAX) as usize,
+            cmdline_size: u32_at(image, HDR_CMDLINE_SIZE) as usize,
+            header_end,
+        })
+    }
+
+    /// Where the guest starts, in real mode.
+    pub fn entry(&self) -> GuestPhysAddr {
+        LINUX_VM_ENTRY
+    }
+
+    fn kernel(&self) -> &'static [u8] {
+        &self.image[self.setup_size..]
+    }
+
+    /// The initrd, if any, and where it goes: as high as the kernel allows below the end of
+    /// `ram`, above the memory the kernel decompresses into.
+    fn place_initrd(&self, ram: &Range<GuestPhysAddr>) -> Result<Option<(GuestPhysAddr, usize)>> {
+        let initrd = match self.initrd {
+            Some(initrd) if !initrd.is_empty() => initrd,
+            _ => return Ok(None),
+        };
+        let kernel_end = KERNEL_GPA + self.init_size.max(self.kernel().len());
+        let top = ram.end.min(self.initrd_addr_max.saturating_add(1));
+        match top.checked_sub(initrd.len()) {
+            Some(start) if start & !(PAGE_SIZE - 1) >= kernel_end => {
+                Ok(Some((start & !(PAGE_SIZE - 1), initrd.len())))
+            }
+            _ => {
+                warn!(
+                    "initrd of {:#x} bytes does not fit between the kernel end {:#x} and {:#x}",
+                    initrd.len(),
+                    kernel_end,
+                    top
+                );
+                Err(Error::InvalidParam)
+            }
+        }
+    }
+
+    /// The segments to boot the kernel with `cmdline`, in a guest whose RAM is `ram`, whose
+    /// device regions are `reserved` and whose ACPI tables start with the RSDP at `acpi_rsdp`.
+    /// Fails with `InvalidParam` if the kernel, the initrd or the command line do not fit, or
+    /// the E820 map is too long.
+    pub fn segments(
+        &self,
+        cmdline: &str,
+        ram: Range<GuestPhysAddr>,
+        reserved: &[Range<GuestPhysAddr>],
+        acpi_rsdp: Option<GuestPhysAddr>,
+    ) -> Result<Vec<LinuxSegment>> {
+        let kernel = self.kernel();
+        let kernel_end = KERNEL_GPA + self.init_size.max(kernel.len());
+        if ram.start != 0 || ram.end < kernel_end {
+            warn!(
+                "kernel needs guest RAM {:#x?}, the guest has {:#x?}",
+                0..kernel_end,
+                ram
+            );
+            return Err(Error::InvalidParam);
+        }
+        if cmdline.len() >= self.cmdline_size.min(CMDLINE_MAX) {
+            warn!(
+                "command line of {} bytes, the kernel takes {}",
+                cmdline.len(),
+                self.cmdline_size.min(CMDLINE_MAX) - 1
+            );
+            return Err(Error::InvalidParam);
+        }
+        let initrd = self.place_initrd(&ram)?;
+
+        let mut e820 = vec![
+            (0, LOW_RAM_END, E820_RAM),
+            (LOW_RAM_END, KERNEL_GPA, E820_RESERVED),
+            (KERNEL_GPA, ram.end, E820_RAM),
+        ];
+        e820.extend(
+            reserved
+                .iter()
+                .map(|range| (range.start, range.end, E820_RESERVED)),
+        );
+        if e820.len() > E820_MAX_ENTRIES {
+            warn!("E820 map of {} entries, too long", e820.len());
+            return Err(Error::InvalidParam);
+        }
+
+        let mut boot_params = vec![0u8; PAGE_SIZE];
+        boot_params[HDR_SETUP_SECTS..self.header_end]
+            .copy_from_slice(&self.image[HDR_SETUP_SECTS..self.header_end]);
+        boot_params[HDR_TYPE_OF_LOADER] = LOADER_UNDEFINED;
+        put_u32(&mut boot_params, HDR_CODE32_START, KERNEL_GPA as u32);
+        put_u32(&mut boot_params, HDR_CMD_LINE_PTR, CMDLINE_GPA as u32);
+        if let Some((gpa, size)) = initrd {
+            put_u32(&mut boot_params, HDR_RAMDISK_IMAGE, gpa as u32);
+            put_u32(&mut boot_params, HDR_RAMDISK_SIZE, size as u32);
+        }
+        // The ACPI tables are in the BIOS area, reserved in the E820 map.
+        if let Some(rsdp) = acpi_rsdp {
+            put_u64(&mut boot_params, BP_ACPI_RSDP_ADDR, rsdp as u64);
+        }
+        boot_params[BP_E820_ENTRIES] = e820.len() as u8;
+        for (i, &(start, end, kind)) in e820.iter().enumerate() {
+            let entry = BP_E820_TABLE + i * E820_ENTRY_SIZE;
+            put_u64(&mut boot_params, entry, start as u64);
+            put_u64(&mut boot_params, entry + 8, (end - start) as u64);
+            put_u32(&mut boot_params, entry + 16, kind);
+        }
+
+        let mut cmdline = Vec::from(cmdline.as_bytes());
+        cmdline.push(0);
+
+        let mut segments = vec![
+            LinuxSegment {
+                gpa: LINUX_VM_ENTRY,
+                data: Cow::Owned(boot_stub(KERNEL_GPA as u32, BOOT_PARAMS_GPA as u32)),
+            },
+            LinuxSegment {
+                gpa: BOOT_PARAMS_GPA,
+                data: Cow::Owned(boot_params),
+            },
+            LinuxSegment {
+                gpa: CMDLINE_GPA,
+                data: Cow::Owned(cmdline),
+            },
+            LinuxSegment {
+                gpa: SETUP_GPA,
+                data: Cow::Borrowed(&self.image[..self.setup_size]),
+            },
+            LinuxSegment {
+                gpa: KERNEL_GPA,
+                data: Cow::Borrowed(kernel),
+            },
+        ];
+        if let (Some((gpa, _)), Some(data)) = (initrd, self.initrd) {
+            segments.push(LinuxSegment {
+                gpa,
+                data: Cow::Borrowed(data),
+            });
+        }
+        Ok(segments)
+    }
+}
+
+/// The real-mode code at [`LINUX_VM_ENTRY`] switching to flat 32-bit protected mode, as the 32-bit
+/// boot protocol wants: `__BOOT_CS` is 0x10 and `__BOOT_DS` 0x18, interrupts are disabled, ESI
+/// points at the zero page and EBP, EDI and EBX are zero. Its GDT follows the code.
+fn boot_stub(kernel_entry: u32, boot_params: u32) -> Vec<u8> {
+    const CODE32: usize = 26;
+    const GDT: usize = 64;
+    const GDTR: usize = GDT + 4 * 8;
+    let base = LINUX_VM_ENTRY as u32;
+
+    let mut stub = Vec::with_capacity(GDTR + 6);
+    // cli; xor ax, ax; mov ds, ax; lgdt [GDTR]
+    stub.extend_from_slice(&[0xfa, 0x31, 0xc0, 0x8e, 0xd8, 0x0f, 0x01, 0x16]);
+    stub.extend_from_slice(&((base as usize + GDTR) as u16).to_le_bytes());
+    // mov eax, cr0; or al, 1; mov cr0, eax
+    stub.extend_from_slice(&[0x0f, 0x20, 0xc0, 0x0c, 0x01, 0x0f, 0x22, 0xc0]);
+    // jmp dword 0x10:CODE32
+    stub.extend_from_slice(&[0x66, 0xea]);
+    stub.extend_from_slice(&(base + CODE32 as u32).to_le_bytes());
+    stub.extend_from_slice(&[0x10, 0x00]);
+    debug_assert_eq!(stub.len(), CODE32);
+    // mov eax, 0x18; mov ds, ax; mov es, ax; mov ss, ax; mov fs, ax; mov gs, ax
+    stub.extend_from_slice(&[0xb8, 0x18, 0x00, 0x00, 0x00]);
+    stub.extend_from_slice(&[0x8e, 0xd8, 0x8e, 0xc0, 0x8e, 0xd0, 0x8e, 0xe0, 0x8e, 0xe8]);
+    // mov esi, boot_params
+    stub.push(0xbe);
+    stub.extend_from_slice(&boot_params.to_le_bytes());
+    // xor ebp, ebp; xor edi, edi; xor ebx, ebx
+    stub.extend_from_slice(&[0x31, 0xed, 0x31, 0xff, 0x31, 0xdb]);
+    // mov eax, kernel_entry; jmp eax
+    stub.push(0xb8);
+    stub.extend_from_slice(&kernel_entry.to_le_bytes());
+    stub.extend_from_slice(&[0xff, 0xe0]);
+
+    stub.resize(GDT, 0);
+    // Null, unused, flat code, flat data.
+    for descriptor in [0, 0, 0x00cf_9a00_0000_ffffu64, 0x00cf_9200_0000_ffff] {
+        stub.extend_from_slice(&descriptor.to_le_bytes());
+    }
+    stub.extend_from_slice(&((4 * 8 - 1) as u16).to_le_bytes());
+    stub.extend_from_slice(&(base + GDT as u32).to_le_bytes());
+    stub
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iced_x86::{Code, Decoder, DecoderOptions, Instruction, Register};
+
+    const MIB: usize = 0x10_0000;
+    const SETUP_SECTS: u8 = 3;
+    const INIT_SIZE: usize = 4 * MIB;
+    const INITRD_ADDR_MAX: usize = 0x37ff_ffff;
+
+    /// A bzImage of boot protocol `version` with `SETUP_SECTS` sectors of setup code, then a
+    /// protected-mode kernel of `kernel_len` bytes.
+    fn bzimage(version: u16, loadflags: u8, kernel_len: usize) -> Vec<u8> {
+        let mut image = vec![0u8; (SETUP_SECTS as usize + 1) * SECTOR_SIZE + kernel_len];
+        image[HDR_SETUP_SECTS] = SETUP_SECTS;
+        image[HDR_BOOT_FLAG..HDR_BOOT_FLAG + 2].copy_from_slice(&BOOT_FLAG.to_le_bytes());
+        // jmp short to the end of the header, as the kernel has it.
+        image[HDR_JUMP..HDR_JUMP + 2].copy_from_slice(&[0xeb, 0x66]);
+        image[HDR_MAGIC..HDR_MAGIC + 4].copy_from_slice(HEADER_MAGIC);
+        image[HDR_VERSION..HDR_VERSION + 2].copy_from_slice(&version.to_le_bytes());
+        image[HDR_LOADFLAGS] = loadflags;
+        put_u32(&mut image, HDR_CODE32_START, KERNEL_GPA as u32);
+        put_u32(&mut image, HDR_INITRD_ADDR_MAX, INITRD_ADDR_MAX as u32);
+        put_u32(&mut image, HDR_CMDLINE_SIZE, 2047);
+        put_u32(&mut image, HDR_INIT_SIZE, INIT_SIZE as u32);
+        image
+    }
+
+    fn kernel(image: Vec<u8>, initrd: Option<Vec<u8>>) -> Result<LinuxKernel> {
+        LinuxKernel::parse(image.leak(), initrd.map(|initrd| &*initrd.leak()))
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        let linux = kernel(bzimage(0x020f, LOADED_HIGH, 0x1000), None).unwrap();
+        assert_eq!(linux.setup_size, 4 * SECTOR_SIZE);
+        assert_eq!(linux.kernel().len(), 0x1000);
+        assert_eq!(
+            (linux.init_size, linux.initrd_addr_max),
+            (INIT_SIZE, INITRD_ADDR_MAX)
+        );
+        assert_eq!(linux.header_end, 0x268);
+        assert_eq!(linux.entry(), LINUX_VM_ENTRY);
+        // No setup_sects means 4.
+        let mut image = bzimage(0x020a, LOADED_HIGH, 0x1000);
+        image[HDR_SETUP_SECTS] = 0;
+        assert_eq!(kernel(image, None).unwrap().setup_size, 5 * SECTOR_SIZE);
+
+        let invalid = |image: Vec<u8>| matches!(kernel(image, None), Err(Error::InvalidParam));
+        let mut image = bzimage(0x020f, LOADED_HIGH, 0x1000);
+        image[HDR_BOOT_FLAG] = 0;
+        assert!(invalid(image));
+        let mut image = bzimage(0x020f, LOADED_HIGH, 0x1000);
+        image[HDR_MAGIC + 3] = b's';
+        assert!(invalid(image));
+        assert!(invalid(bzimage(0x020f, LOADED_HIGH, 0)));
+        let mut image = bzimage(0x020f, LOADED_HIGH, 0x1000);
+        image.truncate(HDR_MAX_END - 1);
+        assert!(invalid(image));
+
+        let not_supported = |image| matches!(kernel(image, None), Err(Error::NotSupported));
+        assert!(not_supported(bzimage(0x0209, LOADED_HIGH, 0x1000)));
+        assert!(not_supported(bzimage(0x020f, 0, 0x1000)));
+    }
+
+    #[test]
+    fn test_zero_page() {
+        let image = bzimage(0x020f, LOADED_HIGH, 0x1000);
+        let linux = kernel(image.clone(), Some(vec![0x5a; 0x1800])).unwrap();
+        let ram = 0..256 * MIB;
+        let ioapic = 0xfec0_0000..0xfec0_1000;
+        let segments = linux
+            .segments(
+                "console=ttyS0",
+                ram.clone(),
+                &[ioapic.clone()],
+                Some(0xe_0000),
+            )
+            .unwrap();
+        let gpas: Vec<_> = segments.iter().map(|segment| segment.gpa).collect();
+        let initrd_gpa = 256 * MIB - 0x2000;
+        assert_eq!(
+            gpas,
+            [
+                LINUX_VM_ENTRY,
+                BOOT_PARAMS_GPA,
+                CMDLINE_GPA,
+                SETUP_GPA,
+                KERNEL_GPA,
+                initrd_gpa
+            ]
+        );
+        assert_eq!(&*segments[2].data, b"console=ttyS0\0");
+        assert_eq!(&*segments[3].data, &image[..4 * SECTOR_SIZE]);
+        assert_eq!(&*segments[4].data, &image[4 * SECTOR_SIZE..]);
+        assert_eq!(&*segments[5].data, &[0x5a; 0x1800][..]);
+
+        let boot_params = &*segments[1].data;
+        assert_eq!(boot_params.len(), PAGE_SIZE);
+        // The setup header as the image has it, but for what the loader fills in.
+        assert_eq!(&boot_params[HDR_MAGIC..HDR_MAGIC + 4], HEADER_MAGIC);
+        assert_eq!(u16_at(boot_params, HDR_VERSION), 0x020f);
+        assert_eq!(u32_at(boot_params, HDR_INIT_SIZE) as usize, INIT_SIZE);
+        assert_eq!(boot_params[HDR_TYPE_OF_LOADER], LOADER_UNDEFINED);
+        assert_eq!(boot_params[HDR_LOADFLAGS], LOADED_HIGH);
+        assert_eq!(u32_at(boot_params, HDR_CODE32_START) as usize, KERNEL_GPA);
+        assert_eq!(u32_at(boot_params, HDR_CMD_LINE_PTR) as usize, CMDLINE_GPA);
+        assert_eq!(u32_at(boot_params, HDR_RAMDISK_IMAGE) as usize, initrd_gpa);
+        assert_eq!(u32_at(boot_params, HDR_RAMDISK_SIZE), 0x1800);
+        assert_eq!(u64_at(boot_params, BP_ACPI_RSDP_ADDR), 0xe_0000);
+        // Nothing past the setup header but the E820 map and the RSDP.
+        assert!(boot_params[0x268..BP_E820_TABLE]
+            .iter()
+            .all(|&byte| byte == 0));
+
+        let e820: Vec<_> = (0..boot_params[BP_E820_ENTRIES] as usize)
+            .map(|i| {
+                let entry = BP_E820_TABLE + i * E820_ENTRY_SIZE;
+                let start = u64_at(boot_params, entry) as usize;
+                let end = start + u64_at(boot_params, entry + 8) as usize;
+                (start..end, u32_at(boot_params, entry + 16))
+            })
+            .collect();
+        assert_eq!(
+            e820,
+            [
+                (0..LOW_RAM_END, E820_RAM),
+                (LOW_RAM_END..KERNEL_GPA, E820_RESERVED),
+                (KERNEL_GPA..ram.end, E820_RAM),
+                (ioapic, E820_RESERVED),
+            ]
+        );
+
+        // Without an initrd or ACPI tables.
+        let linux = kernel(image, None).unwrap();
+        let segments = linux.segments("", ram, &[], None).unwrap();
+        assert_eq!(segments.len(), 5);
+        let boot_params = &*segments[1].data;
+        assert_eq!(u32_at(boot_params, HDR_RAMDISK_IMAGE), 0);
+        assert_eq!(u64_at(boot_params, BP_ACPI_RSDP_ADDR), 0);
+        assert_eq!(boot_params[BP_E820_ENTRIES], 3);
+    }
+
+    #[test]
+    fn test_initrd_placement() {
+        let image = || bzimage(0x020f, LOADED_HIGH, 0x1000);
+        let ram = 0..2048 * MIB;
+        // Below the highest address the kernel takes, page aligned.
+        let linux = kernel(image(), Some(vec![0; 0x1001])).unwrap();
+        let placed = linux.place_initrd(&ram).unwrap();
+        assert_eq!(placed, Some((INITRD_ADDR_MAX + 1 - 0x2000, 0x1001)));
+        // Empty, or none.
+        assert_eq!(
+            kernel(image(), Some(vec![]))
+                .unwrap()
+                .place_initrd(&ram)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            kernel(image(), None).unwrap().place_initrd(&ram).unwrap(),
+            None
+        );
+        // Over the memory the kernel decompresses into.
+        let small = 0..KERNEL_GPA + INIT_SIZE + 0x1000;
+        let linux = kernel(image(), Some(vec![0; 0x1000])).unwrap();
+        assert_eq!(
+            linux.place_initrd(&small).unwrap(),
+            Some((KERNEL_GPA + INIT_SIZE, 0x1000))
+        );
+        let linux = kernel(image(), Some(vec![0; 0x1001])).unwrap();
+        assert!(matches!(
+            linux.place_initrd(&small),
+            Err(Error::InvalidParam)
+        ));
+        assert!(linux.segments("", small, &[], None).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let linux = kernel(bzimage(0x020f, LOADED_HIGH, 0x1000), None).unwrap();
+        let ram = 0..64 * MIB;
+        let invalid =
+            |result: Result<Vec<LinuxSegment>>| matches!(result, Err(Error::InvalidParam));
+        // The RAM must start at 0 and hold the decompressed kernel.
+        assert!(invalid(linux.segments("", 0x1000..64 * MIB, &[], None)));
+        assert!(invalid(linux.segments(
+            "",
+            0..KERNEL_GPA + INIT_SIZE - 1,
+            &[],
+            None
+        )));
+        assert!(linux
+            .segments("", 0..KERNEL_GPA + INIT_SIZE, &[], None)
+            .is_ok());
+        // The command line and its NUL must fit in cmdline_size.
+        let cmdline = "x".repeat(2046);
+        assert!(linux.segments(&cmdline, ram.clone(), &[], None).is_ok());
+        assert!(invalid(linux.segments(
+            &(cmdline + "x"),
+            ram.clone(),
+            &[],
+            None
+        )));
+        // The E820 map must fit in the zero page.
+        let reserved: Vec<_> = (0..E820_MAX_ENTRIES)
+            .map(|i| 0xe000_0000 + i * PAGE_SIZE..0xe000_0000 + (i + 1) * PAGE_SIZE)
+            .collect();
+        assert!(linux
+            .segments("", ram.clone(), &reserved[3..], None)
+            .is_ok());
+        assert!(invalid(linux.segments("", ram, &reserved[2..], None)));
+    }
+
+    #[test]
+    fn test_boot_stub() {
+        let stub = boot_stub(KERNEL_GPA as u32, BOOT_PARAMS_GPA as u32);
+        let base = LINUX_VM_ENTRY as u64;
+        let decode = |bitness, ip: u64| {
+            let offset = (ip - base) as usize;
+            let mut decoder = Decoder::with_ip(bitness, &stub[offset..], ip, DecoderOptions::NONE);
+            let mut instructions = Vec::new();
+            let mut instruction = Instruction::default();
+            while decoder.can_decode() {
+                decoder.decode_out(&mut instruction);
+                instructions.push(instruction);
+                if matches!(instruction.code(), Code::Jmp_ptr1632 | Code::Jmp_rm32) {
+                    break;
+                }
+            }
+            instructions
+        };
+
+        // Real mode, to the far jump into the 32-bit code segment.
+        let real_mode = decode(16, base);
+        let codes: Vec<_> = real_mode
+            .iter()
+            .map(|instruction| instruction.code())
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                Code::Cli,
+                Code::Xor_rm16_r16,
+                Code::Mov_Sreg_rm16,
+                Code::Lgdt_m1632_16,
+                Code::Mov_r32_cr,
+                Code::Or_AL_imm8,
+                Code::Mov_cr_r32,
+                Code::Jmp_ptr1632,
+            ]
+        );
+        let lgdt = real_mode[3];
+        let gdtr = lgdt.memory_displacement64() as usize - LINUX_VM_ENTRY;
+        let jmp = real_mode[7];
+        assert_eq!(jmp.far_branch_selector(), 0x10);
+
+        // Protected mode, to the kernel.
+        let protected_mode = decode(32, jmp.far_branch32() as u64);
+        let last = protected_mode.len() - 1;
+        assert_eq!(protected_mode[last].code(), Code::Jmp_rm32);
+        assert_eq!(protected_mode[last].op0_register(), Register::EAX);
+        let mov = |register| {
+            protected_mode
+                .iter()
+                .filter(|instruction| instruction.code() == Code::Mov_r32_imm32)
+                .filter(|instruction| instruction.op0_register() == register)
+                .map(|instruction| instruction.immediate32())
+                .last()
+        };
+        assert_eq!(mov(Register::ESI), Some(BOOT_PARAMS_GPA as u32));
+        assert_eq!(mov(Register::EAX), Some(KERNEL_GPA as u32));
+        let segments: Vec<_> = protected_mode
+            .iter()
+            .filter(|instruction| instruction.code() == Code::Mov_Sreg_r32m16)
+            .map(|instruction| instruction.op0_register())
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                Register::DS,
+                Register::ES,
+                Register::SS,
+                Register::FS,
+                Register::GS
+            ]
+        );
+
+        // The GDT the selectors 0x10 and 0x18 index, flat 4 GiB code and data.
+        let limit = u16_at(&stub, gdtr) as usize;
+        let gdt = u32_at(&stub, gdtr + 2) as usize - LINUX_VM_ENTRY;
+        assert_eq!(limit, 4 * 8 - 1);
+        assert_eq!(u64_at(&stub, gdt + 0x10), 0x00cf_9a00_0000_ffff);
+        assert_eq!(u64_at(&stub, gdt + 0x18), 0x00cf_9200_0000_ffff);
+        assert_eq!(stub.len(), gdtr + 6);
+    }
+}
@@ -0,0 +1,141 @@
+//! The memory map of a guest, for the guests booted without firmware to describe their RAM.
+//!
+//! | hypercall            | arguments                   | returns                          |
+//! |----------------------|-----------------------------|----------------------------------|
+//! | `HVC_GET_MEMORY_MAP` | buffer GPA, length in bytes | number of entries written        |
+//!
+//! The buffer receives an array of [`MemoryMapEntry`], by increasing base: the memory regions
+//! of the VM, RAM or MMIO passed through, the RAM holding its ACPI tables, see [`crate::acpi`],
+//! and the ECAM window of its PCI host, reserved. A buffer
+//! too small is left untouched, and the hypercall returns the length it needs, in bytes, with
+//! [`MEMORY_MAP_TOO_SMALL`] set. The buffer must be guest RAM, every page of it.
+//!
+//! The host has the memory map of its firmware and gets `NotSupported`.
+
+use alloc::vec::Vec;
+
+use memory_addr::PAGE_SIZE_4K;
+use page_table_entry::MappingFlags;
+
+use crate::config::entry::vm_cfg_entry;
+use crate::hvc::guest_ram_page_hva;
+use crate::{Error, GuestPhysAddr, Result};
+
+/// RAM the guest may use.
+pub const MEMORY_MAP_RAM: u32 = 1;
+/// Neither RAM nor a device the guest may claim, e.g. the ECAM window.
+pub const MEMORY_MAP_RESERVED: u32 = 2;
+/// A device range passed through to the guest.
+pub const MEMORY_MAP_MMIO: u32 = 3;
+/// RAM holding the ACPI tables, the RSDP at its base, which the guest may use once it read them.
+pub const MEMORY_MAP_ACPI: u32 = 4;
+/// Set in the return value of `HVC_GET_MEMORY_MAP` if the buffer is too small.
+pub const MEMORY_MAP_TOO_SMALL: u32 = 1 << 31;
+
+/// An entry of the memory map, as written to the guest, all fields little-endian.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryMapEntry {
+    pub base: u64,
+    pub len: u64,
+    /// [`MEMORY_MAP_RAM`], [`MEMORY_MAP_RESERVED`], [`MEMORY_MAP_MMIO`] or [`MEMORY_MAP_ACPI`].
+    pub kind: u32,
+    /// Zero.
+    pub reserved: u32,
+}
+
+/// Size of a [`MemoryMapEntry`] in the guest buffer.
+pub const MEMORY_MAP_ENTRY_SIZE: usize = core::mem::size_of::<MemoryMapEntry>();
+
+impl MemoryMapEntry {
+    fn new(base: GuestPhysAddr, len: usize, kind: u32) -> Self {
+        Self {
+            base: base as u64,
+            len: len as u64,
+            kind,
+            reserved: 0,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; MEMORY_MAP_ENTRY_SIZE] {
+        let mut bytes = [0; MEMORY_MAP_ENTRY_SIZE];
+        bytes[0..8].copy_from_slice(&self.base.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.len.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.reserved.to_le_bytes());
+        bytes
+    }
+}
+
+/// The memory map of the guest VM `vm_id`, by increasing base. Fails with `NotSupported` for
+/// the host, and with `InvalidParam` if there is no such VM.
+pub fn memory_map(vm_id: u32) -> Result<Vec<MemoryMapEntry>> {
+    if vm_id == crate::vm::HOST_VM_ID {
+        return Err(Error::NotSupported);
+    }
+    let cfg = vm_cfg_entry(vm_id as usize).ok_or(Error::InvalidParam)?;
+    let mut map = Vec::new();
+    for region in cfg.memory_regions() {
+        let end = region.gpa + region.size;
+        if region.flags.contains(MappingFlags::DEVICE) {
+            map.push(MemoryMapEntry::new(
+                region.gpa,
+                region.size,
+                MEMORY_MAP_MMIO,
+            ));
+            continue;
+        }
+        match cfg.acpi_tables() {
+            // The RAM around the tables.
+            Some((gpa, size)) if region.gpa <= gpa && gpa + size <= end => {
+                map.push(MemoryMapEntry::new(
+                    region.gpa,
+                    gpa - region.gpa,
+                    MEMORY_MAP_RAM,
+                ));
+                map.push(MemoryMapEntry::new(gpa, size, MEMORY_MAP_ACPI));
+                map.push(MemoryMapEntry::new(
+                    gpa + size,
+                    end - gpa - size,
+                    MEMORY_MAP_RAM,
+                ));
+            }
+            _ => map.push(MemoryMapEntry::new(region.gpa, region.size, MEMORY_MAP_RAM)),
+        }
+    }
+    map.retain(|entry| entry.len != 0);
+    if let Some(ecam) = cfg.pci_ecam() {
+        map.push(MemoryMapEntry::new(
+            ecam.base,
+            ecam.size(),
+            MEMORY_MAP_RESERVED,
+        ));
+    }
+    map.sort_unstable_by_key(|entry| entry.base);
+    Ok(map)
+}
+
+/// Write the memory map of `vm_id` to its buffer of `len` bytes at `gpa`, see the
+/// [module documentation](self). Fails with `InvalidParam` if a page of the buffer is not RAM.
+pub(crate) fn write_memory_map(vm_id: u32, gpa: GuestPhysAddr, len: usize) -> Result<u32> {
+    let map = memory_map(vm_id)?;
+    let needed = map.len() * MEMORY_MAP_ENTRY_SIZE;
+    if len < needed {
+        return Ok(needed as u32 | MEMORY_MAP_TOO_SMALL);
+    }
+    let end = gpa.checked_add(needed).ok_or(Error::InvalidParam)?;
+    // Every page is checked before anything is written.
+    let first_page = gpa - gpa % PAGE_SIZE_4K;
+    let pages = (first_page..end)
+        .step_by(PAGE_SIZE_4K)
+        .map(|page| guest_ram_page_hva(vm_id, page))
+        .collect::<Result<Vec<_>>>()?;
+    let bytes = map.into_iter().flat_map(MemoryMapEntry::to_bytes);
+    for (i, byte) in bytes.enumerate() {
+        let offset = gpa % PAGE_SIZE_4K + i;
+        let page = pages[offset / PAGE_SIZE_4K];
+        // The guest may touch its buffer concurrently.
+        unsafe { page.add(offset % PAGE_SIZE_4K).write_volatile(byte) };
+    }
+    Ok((needed / MEMORY_MAP_ENTRY_SIZE) as u32)
+}
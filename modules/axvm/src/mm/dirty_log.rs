@@ -0,0 +1,315 @@
+//! Dirty page tracking of guest RAM, e.g. for incremental snapshots.
+//!
+//! [`start_dirty_tracking`] write-protects the RAM mapped in the nested page table of a guest. The
+//! first write of the guest to a page then faults: the page is marked in the dirty bitmap of the
+//! VM, mapped writable again and the write retried, see [`super::ram_fault`]. The pages faulted in
+//! later are mapped read-only until written in the same way. [`fetch_and_reset_dirty`] takes the
+//! pages marked since the last call and write-protects them again, with the memory sets locked,
+//! so that a write of another vCPU meanwhile is either returned now or marked for the next call.
+//!
+//! Only the RAM regions are tracked, not MMIO. The nested page tables only map 4 KiB pages, so
+//! there is no huge page to split. The writes of the hypervisor to guest RAM, e.g. the DMA of the
+//! devices, do not fault: the pages are marked by [`mark_dirty`] when the hypervisor looks them up
+//! to write, see [`VMCfgEntry::guest_ram_page_hpa`]. A write following a
+//! [`fetch_and_reset_dirty`] made between the lookup and the write is only returned by the next
+//! lookup of the page.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axhal::time::current_time_nanos;
+use memory_addr::PAGE_SIZE_4K;
+use page_table_entry::MappingFlags;
+
+use super::ram_fault::{lock_guest_memory, GuestMemory};
+use crate::config::entry::{vm_cfg_entry, VMCfgEntry};
+use crate::vm::HOST_VM_ID;
+use crate::{Error, GuestPhysAddr, Result};
+
+/// Number of VMs whose dirty pages are tracked, for the faults of the others to skip the lock.
+static TRACKED_VMS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counters of the dirty page tracking of a VM, see [`dirty_stats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirtyStats {
+    /// Pages marked dirty since the tracking started, a page once per [`fetch_and_reset_dirty`].
+    pub dirtied_pages: u64,
+    /// Pages marked dirty since the last [`fetch_and_reset_dirty`].
+    pub pending_pages: u64,
+    /// Number of [`fetch_and_reset_dirty`] calls.
+    pub fetches: u64,
+    /// Host time the tracking started, in nanoseconds.
+    pub started_ns: u64,
+}
+
+impl DirtyStats {
+    /// Mean number of pages dirtied per second between the start of the tracking and `now_ns`.
+    pub fn pages_per_sec(&self, now_ns: u64) -> u64 {
+        let elapsed_ns = now_ns.saturating_sub(self.started_ns).max(1);
+        (self.dirtied_pages as u128 * 1_000_000_000 / elapsed_ns as u128) as u64
+    }
+}
+
+/// The dirty bitmap of a VM, a bit per page of each RAM region.
+pub(super) struct DirtyLog {
+    regions: Vec<(GuestPhysAddr, usize, Vec<u64>)>,
+    stats: DirtyStats,
+}
+
+impl DirtyLog {
+    fn new(cfg: &VMCfgEntry) -> Self {
+        let regions = ram_regions(cfg)
+            .map(|(gpa, size, _)| {
+                let pages = size / PAGE_SIZE_4K;
+                (gpa, pages, alloc::vec![0; (pages + 63) / 64])
+            })
+            .collect();
+        Self {
+            regions,
+            stats: DirtyStats {
+                started_ns: current_time_nanos(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// The word and bit of the page at `gpa`.
+    fn bit(&self, gpa: GuestPhysAddr) -> Option<(usize, usize, u64)> {
+        self.regions
+            .iter()
+            .enumerate()
+            .find(|(_, (start, pages, _))| (*start..*start + pages * PAGE_SIZE_4K).contains(&gpa))
+            .map(|(region, (start, _, _))| {
+                let page = (gpa - start) / PAGE_SIZE_4K;
+                (region, page / 64, 1 << (page % 64))
+            })
+    }
+
+    pub fn is_dirty(&self, gpa: GuestPhysAddr) -> bool {
+        self.bit(gpa).map_or(false, |(region, word, bit)| {
+            self.regions[region].2[word] & bit != 0
+        })
+    }
+
+    /// Mark the page at `gpa` dirty.
+    pub fn mark(&mut self, gpa: GuestPhysAddr) {
+        if let Some((region, word, bit)) = self.bit(gpa) {
+            let words = &mut self.regions[region].2;
+            if words[word] & bit == 0 {
+                words[word] |= bit;
+                self.stats.dirtied_pages += 1;
+                self.stats.pending_pages += 1;
+            }
+        }
+    }
+
+    /// The dirty pages by increasing GPA, the bitmap cleared.
+    fn take(&mut self) -> Vec<GuestPhysAddr> {
+        let mut pages = Vec::with_capacity(self.stats.pending_pages as usize);
+        for (start, _, words) in self.regions.iter_mut() {
+            for (index, word) in words.iter_mut().enumerate() {
+                let mut bits = core::mem::take(word);
+                while bits != 0 {
+                    let page = index * 64 + bits.trailing_zeros() as usize;
+                    pages.push(*start + page * PAGE_SIZE_4K);
+                    bits &= bits - 1;
+                }
+            }
+        }
+        self.stats.pending_pages = 0;
+        self.stats.fetches += 1;
+        pages
+    }
+}
+
+/// The GPA, size and flags of the RAM regions of `cfg`.
+fn ram_regions(
+    cfg: &VMCfgEntry,
+) -> impl Iterator<Item = (GuestPhysAddr, usize, MappingFlags)> + '_ {
+    cfg.memory_regions()
+        .iter()
+        .filter(|region| !region.flags.contains(MappingFlags::DEVICE))
+        .map(|region| (region.gpa, region.size, region.flags))
+}
+
+/// Mark the page at `gpa` of guest `vm_id` dirty, before the hypervisor writes to it.
+pub(crate) fn mark_dirty(vm_id: u32, gpa: GuestPhysAddr) {
+    if !tracking_any() {
+        return;
+    }
+    if let Some(GuestMemory {
+        dirty: Some(log), ..
+    }) = lock_guest_memory().get_mut(&vm_id)
+    {
+        log.mark(gpa & !(PAGE_SIZE_4K - 1));
+    }
+}
+
+/// Whether the dirty pages of any VM are tracked.
+pub(super) fn tracking_any() -> bool {
+    TRACKED_VMS.load(Ordering::Acquire) != 0
+}
+
+/// The tracking of a VM ended with its memory set.
+pub(super) fn untrack_vm() {
+    TRACKED_VMS.fetch_sub(1, Ordering::Release);
+}
+
+/// Change the mapping of the RAM of VM `vm_id` with `f`, given its memory, configuration and
+/// the batch of changes. `NotSupported` for the host, `BadState` if the VM does not run.
+fn with_guest_memory<T>(
+    vm_id: u32,
+    f: impl FnOnce(&mut Option<DirtyLog>, &VMCfgEntry, &mut super::MappingBatch) -> Result<T>,
+) -> Result<T> {
+    if vm_id == HOST_VM_ID {
+        return Err(Error::NotSupported);
+    }
+    let cfg = vm_cfg_entry(vm_id as usize).ok_or(Error::InvalidParam)?;
+    let mut memory = lock_guest_memory();
+    let GuestMemory { gpm, dirty } = memory.get_mut(&vm_id).ok_or(Error::BadState)?;
+    let mut batch = gpm.batch(vm_id);
+    f(dirty, &cfg, &mut batch)
+}
+
+/// Track the pages guest `vm_id` writes to from now on, see the [module documentation](self).
+/// Fails with `BadState` if the VM does not run or is tracked already. If a page cannot be
+/// write-protected, the pages protected so far are mapped as they were and the VM not tracked.
+pub fn start_dirty_tracking(vm_id: u32) -> Result {
+    with_guest_memory(vm_id, |dirty, cfg, batch| {
+        if dirty.is_some() {
+            return Err(Error::BadState);
+        }
+        *dirty = Some(DirtyLog::new(cfg));
+        TRACKED_VMS.fetch_add(1, Ordering::Release);
+        // The pages already protected, with their mapping, to map them again on a failure.
+        let mut protected = Vec::new();
+        for (gpa, size, flags) in ram_regions(cfg) {
+            let mut read_only = flags;
+            read_only.remove(MappingFlags::WRITE);
+            for page in (gpa..gpa + size).step_by(PAGE_SIZE_4K) {
+                let Ok(hpa) = batch.translate(page) else {
+                    continue;
+                };
+                // A page shared with a fork is read-only already.
+                let shared = !matches!(cfg.present_ram_page(page), Some((_, false)));
+                protected.push((page, hpa, if shared { read_only } else { flags }));
+                if let Err(err) = batch.protect(page, read_only) {
+                    warn!(
+                        "VM [{}] dirty page tracking failed at {:#x}: {:?}",
+                        vm_id, page, err
+                    );
+                    // `protect` may have unmapped the page it failed on.
+                    for (page, hpa, flags) in protected {
+                        let _ = batch.remap(page, hpa, flags);
+                    }
+                    *dirty = None;
+                    untrack_vm();
+                    return Err(err);
+                }
+            }
+        }
+        info!("VM [{}] dirty page tracking started", vm_id);
+        Ok(())
+    })
+}
+
+/// Stop tracking the dirty pages of guest `vm_id`, the pages marked since the last
+/// [`fetch_and_reset_dirty`] being dropped. Fails with `BadState` if the VM is not tracked.
+pub fn stop_dirty_tracking(vm_id: u32) -> Result {
+    with_guest_memory(vm_id, |dirty, cfg, batch| {
+        dirty.take().ok_or(Error::BadState)?;
+        untrack_vm();
+        for (gpa, size, flags) in ram_regions(cfg) {
+            for page in (gpa..gpa + size).step_by(PAGE_SIZE_4K) {
+                // The pages shared with a fork stay read-only.
+                let private = matches!(cfg.present_ram_page(page), Some((_, false)));
+                if private && batch.translate(page).is_ok() {
+                    batch.protect(page, flags)?;
+                }
+            }
+        }
+        info!("VM [{}] dirty page tracking stopped", vm_id);
+        Ok(())
+    })
+}
+
+/// The pages guest `vm_id` wrote to since the tracking started or the last call, by increasing
+/// GPA, write-protected again to be tracked further. Fails with `BadState` if the VM is not
+/// tracked.
+pub fn fetch_and_reset_dirty(vm_id: u32) -> Result<Vec<GuestPhysAddr>> {
+    with_guest_memory(vm_id, |dirty, cfg, batch| {
+        let pages = dirty.as_mut().ok_or(Error::BadState)?.take();
+        for &page in &pages {
+            let mut flags = cfg.ram_region(page).ok_or(Error::BadState)?.flags;
+            flags.remove(MappingFlags::WRITE);
+            if batch.translate(page).is_ok() {
+                batch.protect(page, flags)?;
+            }
+        }
+        Ok(pages)
+    })
+}
+
+/// The counters of the dirty page tracking of guest `vm_id`, `None` if it is not tracked.
+pub fn dirty_stats(vm_id: u32) -> Option<DirtyStats> {
+    let memory = lock_guest_memory();
+    memory.get(&vm_id)?.dirty.as_ref().map(|log| log.stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log() -> DirtyLog {
+        // A region of 100 pages at 1 MiB, and one of 2 pages at 16 MiB.
+        DirtyLog {
+            regions: alloc::vec![
+                (0x10_0000, 100, alloc::vec![0; 2]),
+                (0x100_0000, 2, alloc::vec![0; 1]),
+            ],
+            stats: DirtyStats::default(),
+        }
+    }
+
+    #[test]
+    fn test_mark_and_take() {
+        let mut log = log();
+        log.mark(0x100_1000);
+        log.mark(0x10_0000 + 70 * PAGE_SIZE_4K);
+        log.mark(0x10_0000);
+        // Marked once until taken.
+        log.mark(0x10_0000);
+        assert!(log.is_dirty(0x10_0000));
+        assert!(!log.is_dirty(0x10_1000));
+        assert_eq!(log.stats.pending_pages, 3);
+        assert_eq!(
+            log.take(),
+            [0x10_0000, 0x10_0000 + 70 * PAGE_SIZE_4K, 0x100_1000]
+        );
+        assert!(!log.is_dirty(0x10_0000));
+        assert_eq!(log.stats.pending_pages, 0);
+        assert_eq!(log.stats.dirtied_pages, 3);
+        assert_eq!(log.stats.fetches, 1);
+    }
+
+    #[test]
+    fn test_mark_outside_ram() {
+        let mut log = log();
+        log.mark(0x10_0000 + 100 * PAGE_SIZE_4K);
+        log.mark(0);
+        assert!(log.take().is_empty());
+        assert_eq!(log.stats.dirtied_pages, 0);
+    }
+
+    #[test]
+    fn test_pages_per_sec() {
+        let stats = DirtyStats {
+            dirtied_pages: 500,
+            started_ns: 1_000_000_000,
+            ..Default::default()
+        };
+        assert_eq!(stats.pages_per_sec(3_000_000_000), 250);
+        // No time elapsed.
+        assert_eq!(stats.pages_per_sec(0), 500_000_000_000);
+    }
+}
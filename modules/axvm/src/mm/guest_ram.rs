@@ -0,0 +1,174 @@
+//! Accesses of the emulated devices to the RAM of their VM, by guest physical address.
+//!
+//! A device reads and writes the buffers the driver gave it as a DMA master would: the guest
+//! physical addresses are translated through the RAM regions of the VM, one page at a time, and
+//! an access reaching a hole or a device region fails as a whole, before anything is copied.
+
+use alloc::sync::Arc;
+use core::ptr;
+
+use memory_addr::PAGE_SIZE_4K;
+use pci::util::byte_code::ByteCode;
+
+use crate::config::entry::{vm_cfg_entry, VMCfgEntry};
+use crate::{phys_to_virt, Error, PhysAddr, Result};
+
+/// The RAM of a VM, as seen by its devices.
+#[derive(Clone)]
+pub struct GuestRam {
+    backing: Backing,
+}
+
+#[derive(Clone)]
+enum Backing {
+    Vm(Arc<VMCfgEntry>),
+    /// Host memory as the RAM at GPA 0, for the tests of the devices.
+    #[cfg(test)]
+    Host(Arc<HostRam>),
+}
+
+/// A zeroed buffer, leaked for the pointers into it to stay valid.
+#[cfg(test)]
+struct HostRam {
+    base: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the buffer is only accessed through `GuestRam`, as guest RAM is.
+#[cfg(test)]
+unsafe impl Send for HostRam {}
+#[cfg(test)]
+unsafe impl Sync for HostRam {}
+
+impl GuestRam {
+    /// The RAM of VM `vm_id`, which must be configured.
+    pub fn new(vm_id: u32) -> Result<Self> {
+        match vm_cfg_entry(vm_id as usize) {
+            Some(cfg) => Ok(Self {
+                backing: Backing::Vm(cfg),
+            }),
+            None => {
+                warn!("no configuration for VM [{}], its RAM is unknown", vm_id);
+                Err(Error::InvalidParam)
+            }
+        }
+    }
+
+    /// `len` bytes of zeroed RAM at GPA 0, of VM 0, everything above being a hole. `len` is a
+    /// multiple of the page size.
+    #[cfg(test)]
+    pub fn host(len: usize) -> Self {
+        assert_eq!(len % PAGE_SIZE_4K, 0);
+        let buf = alloc::vec![0_u8; len].into_boxed_slice();
+        let ram = HostRam {
+            base: alloc::boxed::Box::leak(buf).as_mut_ptr(),
+            len,
+        };
+        Self {
+            backing: Backing::Host(Arc::new(ram)),
+        }
+    }
+
+    pub fn vm_id(&self) -> u32 {
+        match &self.backing {
+            Backing::Vm(cfg) => cfg.get_vm_id() as u32,
+            #[cfg(test)]
+            Backing::Host(_) => 0,
+        }
+    }
+
+    /// Host pointer to `gpa`, valid up to the end of its page. Only the pages `write` to are
+    /// made private to the VM and marked dirty.
+    fn host_ptr(&self, gpa: u64, write: bool) -> Option<*mut u8> {
+        let page = gpa as usize & !(PAGE_SIZE_4K - 1);
+        let cfg = match &self.backing {
+            Backing::Vm(cfg) => cfg,
+            #[cfg(test)]
+            Backing::Host(ram) => {
+                // SAFETY: `gpa` is in the buffer, which ends at a page boundary.
+                return (gpa < ram.len as u64).then(|| unsafe { ram.base.add(gpa as usize) });
+            }
+        };
+        let hpa = if write {
+            cfg.guest_ram_page_hpa(page)?
+        } else {
+            cfg.ram_page(page)?.0
+        };
+        Some(phys_to_virt(PhysAddr::from(hpa + gpa as usize % PAGE_SIZE_4K)).as_mut_ptr())
+    }
+
+    /// Whether the `len` bytes at `gpa` are all RAM.
+    pub fn contains(&self, gpa: u64, len: u64) -> bool {
+        let end = match gpa.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        let mut page = gpa & !(PAGE_SIZE_4K as u64 - 1);
+        while page < end {
+            if self.host_ptr(page, false).is_none() {
+                return false;
+            }
+            page += PAGE_SIZE_4K as u64;
+        }
+        true
+    }
+
+    /// Call `f(host, offset, len)` for each page-bounded piece of the `len` bytes at `gpa`,
+    /// `offset` being the position of the piece in the range, to `write` to it or not.
+    fn for_each_page(
+        &self,
+        gpa: u64,
+        len: usize,
+        write: bool,
+        mut f: impl FnMut(*mut u8, usize, usize),
+    ) -> Result {
+        if !self.contains(gpa, len as u64) {
+            debug!(
+                "VM [{}] DMA to {:#x}..{:#x} outside of its RAM",
+                self.vm_id(),
+                gpa,
+                gpa.wrapping_add(len as u64)
+            );
+            return Err(Error::OutOfRange);
+        }
+        let mut done = 0;
+        while done < len {
+            let addr = gpa + done as u64;
+            let piece = (PAGE_SIZE_4K - addr as usize % PAGE_SIZE_4K).min(len - done);
+            // Checked above.
+            f(self.host_ptr(addr, write).unwrap(), done, piece);
+            done += piece;
+        }
+        Ok(())
+    }
+
+    /// Copy the guest memory at `gpa` to `buf`.
+    pub fn read(&self, gpa: u64, buf: &mut [u8]) -> Result {
+        let dst = buf.as_mut_ptr();
+        self.for_each_page(gpa, buf.len(), false, |src, offset, len| {
+            // SAFETY: `src` is valid for `len` bytes of guest RAM, `dst` for the whole buffer.
+            unsafe { ptr::copy_nonoverlapping(src, dst.add(offset), len) }
+        })
+    }
+
+    /// Copy `buf` to the guest memory at `gpa`.
+    pub fn write(&self, gpa: u64, buf: &[u8]) -> Result {
+        let src = buf.as_ptr();
+        self.for_each_page(gpa, buf.len(), true, |dst, offset, len| {
+            // SAFETY: as in `read`.
+            unsafe { ptr::copy_nonoverlapping(src.add(offset), dst, len) }
+        })
+    }
+
+    /// Read an object of the guest memory, in the layout of the guest.
+    pub fn read_obj<T: ByteCode>(&self, gpa: u64) -> Result<T> {
+        let mut obj = T::default();
+        self.read(gpa, obj.as_mut_bytes())?;
+        Ok(obj)
+    }
+
+    /// Write an object to the guest memory, in the layout of the guest.
+    pub fn write_obj<T: ByteCode>(&self, gpa: u64, obj: &T) -> Result {
+        self.write(gpa, obj.as_bytes())
+    }
+}
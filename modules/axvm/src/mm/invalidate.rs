@@ -0,0 +1,456 @@
+//! EPT invalidation after runtime changes of guest mappings.
+//!
+//! The CPU caches guest-physical translations tagged by EPTP, so once a present mapping of a VM
+//! is removed or its permissions change, every CPU which may run a vCPU of the VM must execute
+//! INVEPT before it enters the guest again. The CPU making the change invalidates locally, posts
+//! a request to the other CPUs the VM is placed on, and kicks those currently running the VM with
+//! an NMI, which forces a VM exit; it then waits until they acknowledged the request. A CPU which
+//! is not in the VM handles the request from `check_events` before its next VM entry. A kicked CPU
+//! whose vCPU was bound to another CPU meanwhile forwards the kick, and posts the request to that
+//! CPU as well, which is waited for in the same way.
+//!
+//! EPT has no per-address invalidation (INVVPID works on linear addresses), so a request is
+//! single-context if the hardware supports it, and all-context otherwise.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axconfig::SMP;
+use axhal::current_cpu_id;
+use x86::bits64::rflags::{self, RFlags};
+use x86::msr::{rdmsr, IA32_VMX_EPT_VPID_CAP};
+use x86::vmx::{Result as VmxResult, VmFail};
+
+use crate::nmi::{nmi_send_msg_by_core_id, NmiMessage, NmiRequest};
+use crate::HostPhysAddr;
+
+/// `PENDING_INVEPT` value of a CPU with nothing to invalidate.
+const NONE: u64 = 0;
+/// `PENDING_INVEPT` value of a CPU which must invalidate all contexts, because requests for
+/// different EPTPs were posted to it.
+const ALL_CONTEXTS: u64 = u64::MAX;
+
+const NO_PENDING_INVEPT: AtomicU64 = AtomicU64::new(NONE);
+/// EPTP to invalidate on each physical CPU, indexed by cpu id.
+static PENDING_INVEPT: [AtomicU64; SMP] = [NO_PENDING_INVEPT; SMP];
+
+/// The EPTP of the nested page table rooted at `root`, as loaded by hypercraft: write-back, 4-level.
+///
+/// Single-context INVEPT only looks at the root address, the low bits are for completeness.
+pub(crate) fn eptp(root: HostPhysAddr) -> u64 {
+    root as u64 | (3 << 3) | 6
+}
+
+/// INVEPT types (SDM Vol. 3C, 30.3), the x86 crate has no INVEPT.
+#[derive(Debug, Clone, Copy)]
+enum InvEptType {
+    Single = 1,
+    Global = 2,
+}
+
+unsafe fn invept(inv_type: InvEptType, eptp: u64) -> VmxResult<()> {
+    // The descriptor: the EPTP, then 64 reserved bits.
+    let descriptor = [eptp, 0];
+    asm!(
+        "invept {inv_type}, [{descriptor}]",
+        inv_type = in(reg) inv_type as u64,
+        descriptor = in(reg) &descriptor,
+        options(nostack)
+    );
+    // As `x86::bits64::vmx`, read RFLAGS right after the instruction.
+    let flags = rflags::read();
+    if flags.contains(RFlags::FLAGS_ZF) {
+        Err(VmFail::VmFailValid)
+    } else if flags.contains(RFlags::FLAGS_CF) {
+        Err(VmFail::VmFailInvalid)
+    } else {
+        Ok(())
+    }
+}
+
+fn invept_local(eptp: u64) {
+    // IA32_VMX_EPT_VPID_CAP: bit 25: single-context INVEPT, bit 26: all-context INVEPT.
+    let cap = unsafe { rdmsr(IA32_VMX_EPT_VPID_CAP) };
+    let result = if eptp != ALL_CONTEXTS && cap & (1 << 25) != 0 {
+        unsafe { invept(InvEptType::Single, eptp) }
+    } else {
+        unsafe { invept(InvEptType::Global, 0) }
+    };
+    if let Err(err) = result {
+        error!(
+            "CPU [{}] INVEPT({:#x}) failed: {:?}",
+            current_cpu_id(),
+            eptp,
+            err
+        );
+    }
+}
+
+/// The CPUs taking part in an invalidation: the physical ones, or threads in the tests.
+trait Cpus {
+    /// Id of the CPU running the caller.
+    fn current(&self) -> usize;
+    /// The VM whose vCPU runs on `cpu`.
+    fn running_vm(&self, cpu: usize) -> Option<u32>;
+    /// Force `cpu`, running vCPU `vcpu_id` of `vm_id`, out of the guest to invalidate `eptp`.
+    fn kick(&self, cpu: usize, vm_id: u32, vcpu_id: u32, eptp: u64);
+    /// Invalidate the translations cached by the current CPU for `eptp`, or for all EPTPs if it
+    /// is `ALL_CONTEXTS`.
+    fn invept(&self, eptp: u64);
+    /// Wait a little for another CPU.
+    fn relax(&self);
+}
+
+struct PhysicalCpus;
+
+impl Cpus for PhysicalCpus {
+    fn current(&self) -> usize {
+        current_cpu_id()
+    }
+
+    fn running_vm(&self, cpu: usize) -> Option<u32> {
+        crate::vm::cpu_running_vm(cpu)
+    }
+
+    fn kick(&self, cpu: usize, vm_id: u32, vcpu_id: u32, eptp: u64) {
+        let msg = NmiMessage {
+            vm_id,
+            vcpu_id,
+            request: NmiRequest::InvalidateEpt { eptp },
+        };
+        nmi_send_msg_by_core_id(axhal::cpu_id_to_core_id(cpu), msg);
+        // A halted vCPU is parked in the hypervisor and does not see the NMI.
+        crate::park::wake_cpu(cpu);
+    }
+
+    fn invept(&self, eptp: u64) {
+        invept_local(eptp);
+    }
+
+    fn relax(&self) {
+        core::hint::spin_loop();
+    }
+}
+
+/// Handle the invalidation request posted to the current CPU, if any.
+pub(crate) fn handle_pending_invalidation() {
+    handle_pending(&PhysicalCpus, &PENDING_INVEPT);
+}
+
+fn handle_pending(cpus: &impl Cpus, pending: &[AtomicU64]) {
+    let eptp = pending[cpus.current()].swap(NONE, Ordering::AcqRel);
+    if eptp != NONE {
+        cpus.invept(eptp);
+    }
+}
+
+/// Post the invalidation of `eptp` to CPU `cpu`, which handles it before its next VM entry.
+pub(crate) fn post_invalidation(cpu: usize, eptp: u64) {
+    post(&PENDING_INVEPT[cpu], eptp);
+}
+
+fn post(pending: &AtomicU64, eptp: u64) {
+    let mut current = pending.load(Ordering::Acquire);
+    loop {
+        let new = match current {
+            NONE => eptp,
+            current if current == eptp => return,
+            _ => ALL_CONTEXTS,
+        };
+        match pending.compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+/// Invalidate the translations cached for the nested page table `eptp` of `vm_id` on all CPUs.
+///
+/// Returns once no CPU can use a stale translation of the VM any more.
+pub(crate) fn invalidate_vm(vm_id: u32, eptp: u64) {
+    let kicked = {
+        let placement = crate::vm::VCPU_TO_PCPU.lock();
+        let vcpus = placement
+            .iter()
+            .map(|(&(vm, vcpu), &cpu)| (vm, vcpu, cpu as usize));
+        post_and_kick(&PhysicalCpus, &PENDING_INVEPT, vcpus, vm_id, eptp)
+    };
+    // Not waited for with the placement locked, the kicked CPUs route their messages with it.
+    PhysicalCpus.invept(eptp);
+    wait_acknowledged(&PhysicalCpus, &PENDING_INVEPT, &kicked, vm_id);
+}
+
+/// Post the invalidation of `eptp` to the other CPUs of the `(vm, vcpu, cpu)` of `vcpus` placed
+/// for `vm_id`, and kick the ones running it. Returns the CPUs kicked.
+fn post_and_kick(
+    cpus: &impl Cpus,
+    pending: &[AtomicU64],
+    vcpus: impl Iterator<Item = (u32, u32, usize)>,
+    vm_id: u32,
+    eptp: u64,
+) -> Vec<usize> {
+    let this_cpu = cpus.current();
+    let mut kicked = Vec::new();
+    for (vm, vcpu, cpu) in vcpus {
+        if vm != vm_id || cpu == this_cpu || cpu >= pending.len() {
+            continue;
+        }
+        // Post before looking at the running VM, a CPU entering the VM after the check
+        // still goes through `check_events`.
+        post(&pending[cpu], eptp);
+        if !kicked.contains(&cpu) && cpus.running_vm(cpu) == Some(vm_id) {
+            kicked.push(cpu);
+            cpus.kick(cpu, vm_id, vcpu, eptp);
+        }
+    }
+    kicked
+}
+
+/// Wait until the `kicked` CPUs handled their request, or left `vm_id`, and so did the CPUs they
+/// forwarded it to.
+fn wait_acknowledged(cpus: &impl Cpus, pending: &[AtomicU64], kicked: &[usize], vm_id: u32) {
+    // Whether the caller had to wait for `cpu`.
+    let wait = |cpu: usize| {
+        let mut waited = false;
+        // A CPU leaving the VM meanwhile handles the request before it enters a guest again.
+        while pending[cpu].load(Ordering::Acquire) != NONE && cpus.running_vm(cpu) == Some(vm_id) {
+            waited = true;
+            // Another CPU may be waiting for us in the same way.
+            handle_pending(cpus, pending);
+            cpus.relax();
+        }
+        waited
+    };
+    for &cpu in kicked {
+        wait(cpu);
+    }
+    // A kicked CPU forwards the request before it acknowledges its own, and the CPU forwarded to
+    // may forward it again: the other CPUs are checked until a pass waits for none of them.
+    let this_cpu = cpus.current();
+    loop {
+        let mut waited = false;
+        for cpu in (0..pending.len()).filter(|&cpu| cpu != this_cpu) {
+            waited |= wait(cpu);
+        }
+        if !waited {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    use super::*;
+
+    const VM: u32 = 1;
+    const EPTP: u64 = 0x8000_001e;
+    /// The frames the guest page is mapped to before and after the change.
+    const OLD_FRAME: u64 = 0x1000;
+    const NEW_FRAME: u64 = 0x2000;
+    /// `Machine::running` of a CPU out of the guest.
+    const NO_VM: u32 = 0;
+    const CPUS: usize = 3;
+
+    std::thread_local! {
+        static CPU: Cell<usize> = Cell::new(0);
+    }
+
+    /// Three CPUs, each thread being one of them, sharing the EPT entry of a single guest page. A
+    /// CPU in the guest translates through its TLB, filled from the entry on a miss.
+    #[derive(Default)]
+    struct Machine {
+        pending: [AtomicU64; CPUS],
+        running: [AtomicU32; CPUS],
+        /// The EPTP of the last kick of each CPU, NONE if it was handled.
+        kicks: [AtomicU64; CPUS],
+        /// The CPUs taking a while to leave the guest once kicked.
+        slow: [AtomicBool; CPUS],
+        tlb: [AtomicU64; CPUS],
+        ept_entry: AtomicU64,
+        /// The last frame the guest of each CPU accessed, and its accesses.
+        observed: [AtomicU64; CPUS],
+        accesses: [AtomicUsize; CPUS],
+        stop: AtomicBool,
+    }
+
+    impl Cpus for Machine {
+        fn current(&self) -> usize {
+            CPU.with(Cell::get)
+        }
+
+        fn running_vm(&self, cpu: usize) -> Option<u32> {
+            match self.running[cpu].load(Ordering::Acquire) {
+                NO_VM => None,
+                vm => Some(vm),
+            }
+        }
+
+        fn kick(&self, cpu: usize, vm_id: u32, _vcpu_id: u32, eptp: u64) {
+            assert_eq!(self.running_vm(cpu), Some(vm_id));
+            self.kicks[cpu].store(eptp, Ordering::Release);
+        }
+
+        fn invept(&self, eptp: u64) {
+            assert!(eptp == EPTP || eptp == ALL_CONTEXTS);
+            self.tlb[self.current()].store(0, Ordering::Release);
+        }
+
+        fn relax(&self) {
+            std::thread::yield_now();
+        }
+    }
+
+    impl Machine {
+        /// Run the guest of `cpu`, which keeps accessing its page, until stopped. A kick makes it
+        /// exit and handle its pending request, as the NMI exit does. With `forward_to`, its vCPU
+        /// is bound to that CPU now, and the kick is forwarded there, as `nmi::forward` does.
+        fn run_cpu(&self, cpu: usize, forward_to: Option<usize>) {
+            CPU.with(|current| current.set(cpu));
+            handle_pending(self, &self.pending);
+            self.running[cpu].store(VM, Ordering::Release);
+            while !self.stop.load(Ordering::Acquire) {
+                let eptp = self.kicks[cpu].swap(NONE, Ordering::AcqRel);
+                if eptp != NONE {
+                    if self.slow[cpu].load(Ordering::Acquire) {
+                        std::thread::sleep(std::time::Duration::from_millis(20));
+                    }
+                    if let Some(target) = forward_to {
+                        post(&self.pending[target], eptp);
+                        self.kick(target, VM, 0, eptp);
+                    }
+                    handle_pending(self, &self.pending);
+                }
+                let mut frame = self.tlb[cpu].load(Ordering::Acquire);
+                if frame == 0 {
+                    frame = self.ept_entry.load(Ordering::Acquire);
+                    self.tlb[cpu].store(frame, Ordering::Release);
+                }
+                self.observed[cpu].store(frame, Ordering::Release);
+                self.accesses[cpu].fetch_add(1, Ordering::AcqRel);
+                std::thread::yield_now();
+            }
+            self.running[cpu].store(NO_VM, Ordering::Release);
+        }
+
+        /// Wait for the guest of `cpu` to access its page twice more, and return the frame.
+        fn next_access(&self, cpu: usize) -> u64 {
+            let start = self.accesses[cpu].load(Ordering::Acquire);
+            while self.accesses[cpu].load(Ordering::Acquire) < start + 2 {
+                std::thread::yield_now();
+            }
+            self.observed[cpu].load(Ordering::Acquire)
+        }
+
+        /// Start the guests of `cpus`, with the `forward_to` of each, once they cached the old
+        /// mapping of the guest page.
+        fn start(
+            self: &Arc<Self>,
+            cpus: &[(usize, Option<usize>)],
+        ) -> Vec<std::thread::JoinHandle<()>> {
+            self.ept_entry.store(OLD_FRAME, Ordering::Release);
+            let threads = cpus
+                .iter()
+                .map(|&(cpu, forward_to)| {
+                    let machine = self.clone();
+                    std::thread::spawn(move || machine.run_cpu(cpu, forward_to))
+                })
+                .collect();
+            for &(cpu, _) in cpus {
+                assert_eq!(self.next_access(cpu), OLD_FRAME);
+            }
+            threads
+        }
+
+        fn stop(&self, threads: Vec<std::thread::JoinHandle<()>>) {
+            self.stop.store(true, Ordering::Release);
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        }
+
+        /// Remap the guest page on CPU 0, with a shootdown of the `vcpus` placed, the `(vm, vcpu,
+        /// cpu)` of `VCPU_TO_PCPU`. Returns the CPUs kicked.
+        fn shootdown(&self, vcpus: &[(u32, u32, usize)]) -> Vec<usize> {
+            self.ept_entry.store(NEW_FRAME, Ordering::Release);
+            let kicked = post_and_kick(self, &self.pending, vcpus.iter().copied(), VM, EPTP);
+            self.invept(EPTP);
+            wait_acknowledged(self, &self.pending, &kicked, VM);
+            kicked
+        }
+    }
+
+    #[test]
+    fn test_shootdown() {
+        let machine = Arc::new(Machine::default());
+        let threads = machine.start(&[(1, None)]);
+        let kicked = machine.shootdown(&[(VM, 0, 0), (VM, 1, 1), (VM + 1, 0, 1)]);
+        assert_eq!(kicked, [1]);
+        assert_eq!(machine.next_access(1), NEW_FRAME);
+        machine.stop(threads);
+        assert_eq!(machine.pending[1].load(Ordering::Acquire), NONE);
+    }
+
+    #[test]
+    fn test_stale_without_shootdown() {
+        // What the shootdown prevents: the local INVEPT leaves the TLB of CPU 1 alone.
+        let machine = Arc::new(Machine::default());
+        let threads = machine.start(&[(1, None)]);
+        machine.ept_entry.store(NEW_FRAME, Ordering::Release);
+        machine.invept(EPTP);
+        assert_eq!(machine.next_access(1), OLD_FRAME);
+        machine.stop(threads);
+    }
+
+    #[test]
+    fn test_forwarded() {
+        // vCPU 1 was bound to CPU 2 once the placement was read: CPU 1 forwards the kick there,
+        // and the shootdown returns once CPU 2 invalidated too.
+        let machine = Arc::new(Machine::default());
+        machine.slow[2].store(true, Ordering::Release);
+        let threads = machine.start(&[(1, Some(2)), (2, None)]);
+        let kicked = machine.shootdown(&[(VM, 1, 1)]);
+        assert_eq!(kicked, [1]);
+        assert_eq!(machine.pending[2].load(Ordering::Acquire), NONE);
+        assert_ne!(machine.tlb[2].load(Ordering::Acquire), OLD_FRAME);
+        assert_eq!(machine.next_access(2), NEW_FRAME);
+        machine.stop(threads);
+    }
+
+    #[test]
+    fn test_cpu_out_of_the_guest() {
+        let machine = Machine::default();
+        machine.tlb[1].store(OLD_FRAME, Ordering::Release);
+        // Not kicked nor waited for, CPU 1 invalidates before it enters the guest again.
+        let vcpus = [(VM, 1, 1)].into_iter();
+        let kicked = post_and_kick(&machine, &machine.pending, vcpus, VM, EPTP);
+        assert!(kicked.is_empty());
+        assert_eq!(machine.kicks[1].load(Ordering::Acquire), NONE);
+        assert_eq!(machine.pending[1].load(Ordering::Acquire), EPTP);
+        wait_acknowledged(&machine, &machine.pending, &kicked, VM);
+
+        CPU.with(|cpu| cpu.set(1));
+        handle_pending(&machine, &machine.pending);
+        assert_eq!(machine.tlb[1].load(Ordering::Acquire), 0);
+        assert_eq!(machine.pending[1].load(Ordering::Acquire), NONE);
+    }
+
+    #[test]
+    fn test_post() {
+        let pending = AtomicU64::new(NONE);
+        post(&pending, EPTP);
+        post(&pending, EPTP);
+        assert_eq!(pending.load(Ordering::Acquire), EPTP);
+        // Requests for two nested page tables become an all-context one.
+        post(&pending, EPTP + 0x1000);
+        assert_eq!(pending.load(Ordering::Acquire), ALL_CONTEXTS);
+        post(&pending, EPTP);
+        assert_eq!(pending.load(Ordering::Acquire), ALL_CONTEXTS);
+    }
+}
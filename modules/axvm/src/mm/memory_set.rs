@@ -28,10 +28,22 @@ pub struct GuestMemoryRegion {
     pub hpa: HostPhysAddr,
     pub size: usize,
     pub flags: MappingFlags,
+    /// RAM left unmapped, each page being allocated and mapped when the guest first touches it,
+    /// see [`crate::faulted_pages`]. `hpa` is meaningless then.
+    pub populate_on_demand: bool,
 }
 
 impl Display for GuestMemoryRegion {
     fn fmt(&self, f: &mut Formatter) -> Result {
+        if self.populate_on_demand {
+            return write!(
+                f,
+                "GuestMemoryRegion: GPA: [{:#x?}], populated on demand, size {:#x}, flags {:?}",
+                &(self.gpa..self.gpa + self.size),
+                &self.size,
+                &self.flags
+            );
+        }
         write!(
             f,
             "GuestMemoryRegion: GPA: [{:#x?}], HPA: [{:#x?}] size {:#x}, flags {:?}",
@@ -250,6 +262,103 @@ impl GuestPhysMemorySet {
     pub fn translate(&self, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr> {
         self.npt.translate(gpa)
     }
+
+    /// Map the page at `gpa` to `hpa`, in place of its current mapping if any, e.g. a page of a
+    /// region copied. For a memory set no vCPU uses yet: the CPUs are not told.
+    pub fn map_page(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hpa: HostPhysAddr,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        if self.npt.translate(gpa).is_ok() {
+            self.npt.unmap(gpa)?;
+        }
+        self.npt.map(gpa, hpa, flags)
+    }
+
+    /// Open a batch of runtime changes to the page mappings of VM `vm_id`.
+    ///
+    /// The translations cached by the CPUs are invalidated once, when the batch is closed.
+    #[cfg(target_arch = "x86_64")]
+    pub fn batch(&mut self, vm_id: u32) -> MappingBatch<'_> {
+        MappingBatch {
+            gpm: self,
+            vm_id,
+            stale: false,
+        }
+    }
+}
+
+/// Runtime changes of single pages of a [`GuestPhysMemorySet`], e.g. for lazy faults or write
+/// protection.
+///
+/// The changes only touch the nested page table, not the regions of the memory set. Dropping (or
+/// [finishing](Self::finish)) the batch invalidates the translations of the VM on all CPUs, if a
+/// present mapping was removed or changed; new mappings need no invalidation, since not-present
+/// entries are never cached.
+#[cfg(target_arch = "x86_64")]
+pub struct MappingBatch<'a> {
+    gpm: &'a mut GuestPhysMemorySet,
+    vm_id: u32,
+    stale: bool,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl MappingBatch<'_> {
+    /// Map the unmapped page at `gpa`.
+    pub fn map(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hpa: HostPhysAddr,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        self.gpm.npt.map(gpa, hpa, flags)
+    }
+
+    pub fn unmap(&mut self, gpa: GuestPhysAddr) -> HyperResult {
+        self.gpm.npt.unmap(gpa)?;
+        self.stale = true;
+        Ok(())
+    }
+
+    /// Map the page at `gpa` to `hpa` with `flags`, whether it was mapped or not.
+    pub fn remap(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hpa: HostPhysAddr,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        if self.gpm.npt.translate(gpa).is_ok() {
+            self.unmap(gpa)?;
+        }
+        self.map(gpa, hpa, flags)
+    }
+
+    pub fn translate(&self, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr> {
+        self.gpm.npt.translate(gpa)
+    }
+
+    /// Change the permissions of the mapped page at `gpa`.
+    pub fn protect(&mut self, gpa: GuestPhysAddr, flags: MappingFlags) -> HyperResult {
+        let hpa = self.gpm.npt.translate(gpa)?;
+        self.gpm.npt.unmap(gpa)?;
+        self.stale = true;
+        self.gpm.npt.map(gpa, hpa, flags)
+    }
+
+    /// Close the batch, see [`MappingBatch`].
+    pub fn finish(self) {}
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Drop for MappingBatch<'_> {
+    fn drop(&mut self) {
+        if self.stale {
+            let eptp = super::invalidate::eptp(self.gpm.nest_page_table_root());
+            super::invalidate::invalidate_vm(self.vm_id, eptp);
+        }
+    }
 }
 
 impl Drop for GuestPhysMemorySet {
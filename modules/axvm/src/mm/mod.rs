@@ -1,4 +1,32 @@
+#[cfg(target_arch = "x86_64")]
+mod bulk_copy;
+#[cfg(target_arch = "x86_64")]
+mod dirty_log;
+mod guest_ram;
+#[cfg(target_arch = "x86_64")]
+mod invalidate;
 mod mapper;
 mod memory_set;
+#[cfg(target_arch = "x86_64")]
+mod ram_fault;
+#[cfg(target_arch = "x86_64")]
+pub(crate) use invalidate::{handle_pending_invalidation, post_invalidation};
 
+#[cfg(target_arch = "x86_64")]
+pub use bulk_copy::{copy_to_guest, fill_guest};
+#[cfg(target_arch = "x86_64")]
+pub use dirty_log::{
+    dirty_stats, fetch_and_reset_dirty, start_dirty_tracking, stop_dirty_tracking, DirtyStats,
+};
+#[cfg(target_arch = "x86_64")]
+pub(crate) use dirty_log::mark_dirty;
+#[cfg(target_arch = "x86_64")]
+pub use ram_fault::{copied_pages, faulted_pages};
+#[cfg(target_arch = "x86_64")]
+pub(crate) use ram_fault::{
+    handle_ram_fault, map_device_memory, register_guest_memory, share_guest_memory,
+    unmap_device_memory, unregister_guest_memory, unshare_page,
+};
+
+pub use guest_ram::GuestRam;
 pub use memory_set::*;
@@ -0,0 +1,221 @@
+//! Nested page faults on guest RAM: the RAM populated on demand, and the RAM shared with a fork.
+//!
+//! The regions of a VM marked [`populate_on_demand`](super::GuestMemoryRegion) are left out of
+//! its nested page table when it boots. The first access of the guest to one of their pages
+//! faults, and the page is allocated zeroed, mapped with the flags of its region and the access
+//! retried, without decoding the instruction. A page the hypervisor already wrote, e.g. a guest
+//! image segment or a DMA buffer, is mapped with the content it has.
+//!
+//! While the dirty pages of a VM are tracked, see [`super::dirty_log`], its RAM is mapped
+//! read-only too until written, a write marking the page dirty.
+//!
+//! A VM forked with [`crate::fork_vm`] shares the RAM of the VM it was forked from, both mapping
+//! it read-only. The first write of either to a shared page faults, and the page is copied and
+//! the copy mapped writable for the writer; a page no other VM references any longer is mapped
+//! writable as it is. The hypervisor writes to a shared page copy it in the same way first, see
+//! [`VMCfgEntry::guest_ram_page_hpa`]. The memory is freed with the last configuration
+//! referencing it.
+//!
+//! The other faults are left to the devices.
+
+use alloc::collections::BTreeMap;
+
+use memory_addr::PAGE_SIZE_4K;
+use page_table_entry::MappingFlags;
+use spin::{Mutex, MutexGuard};
+
+use super::dirty_log::{self, DirtyLog};
+use super::GuestPhysMemorySet;
+use crate::config::entry::{vm_cfg_entry, VMCfgEntry};
+use crate::{Error, GuestPhysAddr, HostPhysAddr, Result};
+
+/// The memory set of a running guest VM, and its dirty log while its dirty pages are tracked.
+pub(super) struct GuestMemory {
+    pub gpm: GuestPhysMemorySet,
+    pub dirty: Option<DirtyLog>,
+}
+
+/// The memory of the running guest VMs.
+static GUEST_MEMORY: Mutex<BTreeMap<u32, GuestMemory>> = Mutex::new(BTreeMap::new());
+
+/// Keep `gpm`, the memory set of VM `vm_id`, for its nested page faults, until
+/// [`unregister_guest_memory`].
+pub(crate) fn register_guest_memory(vm_id: u32, gpm: GuestPhysMemorySet) {
+    lock_guest_memory().insert(vm_id, GuestMemory { gpm, dirty: None });
+}
+
+/// Drop the memory set of `vm_id`, once its vCPUs no longer run.
+pub(crate) fn unregister_guest_memory(vm_id: u32) {
+    // Unmapped outside of the lock.
+    let memory = lock_guest_memory().remove(&vm_id);
+    if matches!(memory, Some(GuestMemory { dirty: Some(_), .. })) {
+        dirty_log::untrack_vm();
+    }
+    drop(memory);
+}
+
+/// The memory of the guests, locked. The holder may wait for the other CPUs to invalidate their
+/// translations: a CPU waiting for the lock meanwhile invalidates its own.
+pub(super) fn lock_guest_memory() -> MutexGuard<'static, BTreeMap<u32, GuestMemory>> {
+    loop {
+        if let Some(memory) = GUEST_MEMORY.try_lock() {
+            return memory;
+        }
+        super::handle_pending_invalidation();
+        core::hint::spin_loop();
+    }
+}
+
+/// Handle the nested page fault of VM `vm_id` at `gpa`, an `access` of the guest. `None` if
+/// `gpa` is neither in a region populated on demand, nor in RAM shared with a fork or whose dirty
+/// pages are tracked, or if its region does not allow `access`: the fault is left to the devices.
+pub(crate) fn handle_ram_fault(
+    vm_id: u32,
+    gpa: GuestPhysAddr,
+    access: MappingFlags,
+) -> Option<Result> {
+    let cfg = vm_cfg_entry(vm_id as usize)?;
+    let region = cfg.ram_region(gpa)?;
+    let lazy = region.populate_on_demand || cfg.copy_on_write();
+    if !(lazy || dirty_log::tracking_any()) || !region.flags.contains(access) {
+        return None;
+    }
+    let (on_demand, mut flags) = (region.populate_on_demand, region.flags);
+    let page = gpa - gpa % PAGE_SIZE_4K;
+    let mut memory = lock_guest_memory();
+    let Some(GuestMemory { gpm, dirty }) = memory.get_mut(&vm_id) else {
+        warn!("VM [{}] faulted on its RAM without a memory set", vm_id);
+        return Some(Err(Error::BadState));
+    };
+    if !lazy && dirty.is_none() {
+        return None;
+    }
+    let mapped = gpm.translate(page).ok();
+    let (hpa, old) = if access.contains(MappingFlags::WRITE) {
+        match cfg.unshare_page(page) {
+            Ok(unshared) => (unshared.hpa, unshared.old),
+            Err(err) => return Some(Err(err)),
+        }
+    } else {
+        let Some((hpa, shared)) = cfg.ram_page(page) else {
+            return Some(Err(Error::NoMemory));
+        };
+        // Another vCPU may have mapped the page meanwhile.
+        if mapped == Some(hpa) {
+            return Some(Ok(()));
+        }
+        // Mapped writable once written, to be marked dirty.
+        if shared || dirty.as_ref().map_or(false, |log| !log.is_dirty(page)) {
+            flags.remove(MappingFlags::WRITE);
+        }
+        (hpa, None)
+    };
+    if let (Some(log), true) = (dirty.as_mut(), access.contains(MappingFlags::WRITE)) {
+        log.mark(page);
+    }
+    trace!("VM [{}] maps {:#x} -> {:#x} {:?}", vm_id, page, hpa, flags);
+    let result = gpm.batch(vm_id).remap(page, hpa, flags);
+    // The page it shared is released once no CPU translates to it.
+    drop(old);
+    if result.is_ok() && on_demand && mapped.is_none() {
+        cfg.record_faulted_page();
+    }
+    Some(result)
+}
+
+/// Make the RAM page at `gpa` of `cfg`, page aligned, private to its VM for the hypervisor to
+/// write to it, copying it if it is shared with a fork, and return its host physical address.
+pub(crate) fn unshare_page(cfg: &VMCfgEntry, gpa: GuestPhysAddr) -> Result<HostPhysAddr> {
+    let vm_id = cfg.get_vm_id() as u32;
+    let mut memory = lock_guest_memory();
+    let unshared = cfg.unshare_page(gpa)?;
+    if unshared.copied {
+        if let Some(GuestMemory { gpm, .. }) = memory.get_mut(&vm_id) {
+            let mut batch = gpm.batch(vm_id);
+            if batch.translate(gpa).is_ok() {
+                let flags = cfg.ram_region(gpa).ok_or(Error::InvalidParam)?.flags;
+                batch.remap(gpa, unshared.hpa, flags)?;
+            }
+        }
+    }
+    drop(unshared.old);
+    Ok(unshared.hpa)
+}
+
+/// Map the RAM of `cfg`, VM `vm_id`, read-only, now that it is shared with a fork.
+pub(crate) fn share_guest_memory(vm_id: u32, cfg: &VMCfgEntry) -> Result {
+    let mut memory = lock_guest_memory();
+    let memory = memory.get_mut(&vm_id).ok_or(Error::BadState)?;
+    let mut batch = memory.gpm.batch(vm_id);
+    let ram = cfg
+        .memory_regions()
+        .iter()
+        .filter(|region| !region.flags.contains(MappingFlags::DEVICE));
+    for region in ram {
+        let mut flags = region.flags;
+        flags.remove(MappingFlags::WRITE);
+        for page in (region.gpa..region.gpa + region.size).step_by(PAGE_SIZE_4K) {
+            if batch.translate(page).is_ok() {
+                batch.protect(page, flags)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Map the `size` bytes of host memory at `hpa` at `gpa` of the running VM `vm_id`, e.g. the
+/// memory a device exposes through a BAR, whose accesses then no longer exit. All page aligned.
+/// Fails with `BadState` if the VM has no memory set, and with `InvalidParam` if the range
+/// overlaps a memory region of the VM.
+pub(crate) fn map_device_memory(
+    vm_id: u32,
+    gpa: GuestPhysAddr,
+    hpa: HostPhysAddr,
+    size: usize,
+    flags: MappingFlags,
+) -> Result {
+    let cfg = vm_cfg_entry(vm_id as usize).ok_or(Error::BadState)?;
+    if let Some(region) = cfg.overlapping_region(gpa..gpa + size) {
+        warn!(
+            "VM [{}] cannot map device memory at {:#x}..{:#x} over {}",
+            vm_id,
+            gpa,
+            gpa + size,
+            region
+        );
+        return Err(Error::InvalidParam);
+    }
+    let mut memory = lock_guest_memory();
+    let memory = memory.get_mut(&vm_id).ok_or(Error::BadState)?;
+    let mut batch = memory.gpm.batch(vm_id);
+    for offset in (0..size).step_by(PAGE_SIZE_4K) {
+        batch.remap(gpa + offset, hpa + offset, flags)?;
+    }
+    Ok(())
+}
+
+/// Unmap the `size` bytes at `gpa` of `vm_id` mapped by [`map_device_memory`], the pages not
+/// mapped being skipped. Nothing to do once the VM stopped, its memory set is gone.
+pub(crate) fn unmap_device_memory(vm_id: u32, gpa: GuestPhysAddr, size: usize) -> Result {
+    let mut memory = lock_guest_memory();
+    let Some(memory) = memory.get_mut(&vm_id) else {
+        return Ok(());
+    };
+    let mut batch = memory.gpm.batch(vm_id);
+    for page in (gpa..gpa + size).step_by(PAGE_SIZE_4K) {
+        if batch.translate(page).is_ok() {
+            batch.unmap(page)?;
+        }
+    }
+    Ok(())
+}
+
+/// Number of pages populated on demand VM `vm_id` faulted in, `None` if there is no such VM.
+pub fn faulted_pages(vm_id: u32) -> Option<usize> {
+    vm_cfg_entry(vm_id as usize).map(|cfg| cfg.faulted_pages())
+}
+
+/// Number of pages shared with a fork VM `vm_id` copied, `None` if there is no such VM.
+pub fn copied_pages(vm_id: u32) -> Option<usize> {
+    vm_cfg_entry(vm_id as usize).map(|cfg| cfg.copied_pages())
+}
@@ -1,34 +1,316 @@
-use alloc::collections::LinkedList;
+//! Cross-CPU messages, delivered with an NMI.
+//!
+//! Every message is addressed to one vCPU of one VM. The target CPU takes its messages from the
+//! NMI exit (and from `check_events`, for the ones it had to keep), and routes each one by the
+//! current binding of the vCPU, see `route`:
+//!
+//! - handled now, when the vCPU is bound to this CPU and its VM is the one running here;
+//! - kept, when the vCPU is bound to this CPU while another VM runs here;
+//! - forwarded, when the vCPU is now bound to another CPU. An EPT invalidation is posted to that
+//!   CPU as well, which then runs the vCPU and may cache translations of its VM;
+//! - dropped, when the VM is gone. The messages of a VM are also purged from every queue when it
+//!   stops, see [`purge_vm_messages`].
+//!
+//! An NMI which brings no message is not one of ours: it comes from the machine, e.g. from a
+//! watchdog or a chipset error. It is counted, see [`nmi_stats`], and forwarded to the vCPU it
+//! interrupted.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 use spin::Mutex;
 
 use axconfig::SMP;
 
-const PER_CPU_NMI_MSG_QUEUE: Mutex<NmiMsgQueue> = Mutex::new(NmiMsgQueue::new());
-pub static CORE_NMI_LIST: [Mutex<NmiMsgQueue>; SMP] = [PER_CPU_NMI_MSG_QUEUE; SMP];
+use crate::vm::VmState;
+use crate::{Error, Result};
+
+const PER_CPU_NMI_MSG_QUEUE: NmiMsgQueue = NmiMsgQueue::new();
+/// Messages to each physical CPU, indexed by cpu id.
+static CPU_NMI_LIST: [NmiMsgQueue; SMP] = [PER_CPU_NMI_MSG_QUEUE; SMP];
+const NO_NMI: AtomicU64 = AtomicU64::new(0);
+/// NMIs injected into the vCPUs of each physical CPU.
+static INJECTED_NMIS: [AtomicU64; SMP] = [NO_NMI; SMP];
+/// NMIs which brought no message to each physical CPU.
+static UNEXPECTED_NMIS: [AtomicU64; SMP] = [NO_NMI; SMP];
+
+/// NMI statistics of a physical CPU, see [`nmi_stats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NmiStats {
+    /// NMIs injected into the vCPUs run by the CPU, sent by [`crate::send_nmi`] or forwarded.
+    pub injected: u64,
+    /// NMIs which brought no message, from the machine rather than from the hypervisor.
+    pub unexpected: u64,
+}
+
+struct NmiMsgQueue {
+    msg_queue: Mutex<VecDeque<NmiMessage>>,
+    /// Length of `msg_queue`, lets `check_events` skip the lock when there is no message.
+    len: AtomicUsize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NmiRequest {
+    /// Boot the VM, its vCPU 0 being created on the target CPU.
+    BootVm,
+    /// Create and run an AP of a VM being booted on the target CPU.
+    StartVcpu,
+    /// Kick out of the guest to handle the pending invalidation of the EPT `eptp`.
+    InvalidateEpt { eptp: u64 },
+    /// Kick out of the guest to take the vectors posted to the virtual local APIC of the vCPU.
+    KickVcpu,
+    /// Inject an NMI into the vCPU, see [`crate::send_nmi`].
+    InjectNmi,
+}
 
-pub struct NmiMsgQueue {
-    msg_queue: LinkedList<NmiMessage>,
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NmiMessage {
+    pub vm_id: u32,
+    pub vcpu_id: u32,
+    pub request: NmiRequest,
 }
 
-#[derive(Copy, Clone, Debug)]
-pub enum NmiMessage {
-    // vm_id
-    BootVm(usize),
+/// What the CPU draining a message does with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    Now,
+    Keep,
+    Forward(usize),
+    Drop,
 }
 
 impl NmiMsgQueue {
     const fn new() -> Self {
         Self {
-            msg_queue: LinkedList::new(),
+            msg_queue: Mutex::new(VecDeque::new()),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, msg: NmiMessage) {
+        let mut queue = self.msg_queue.lock();
+        queue.push_back(msg);
+        self.len.store(queue.len(), Ordering::Release);
+    }
+
+    fn take(&self) -> VecDeque<NmiMessage> {
+        let mut queue = self.msg_queue.lock();
+        self.len.store(0, Ordering::Release);
+        core::mem::take(&mut *queue)
+    }
+
+    /// Put `kept` back, ahead of the messages sent meanwhile.
+    fn put_back(&self, mut kept: VecDeque<NmiMessage>) {
+        let mut queue = self.msg_queue.lock();
+        kept.append(&mut queue);
+        *queue = kept;
+        self.len.store(queue.len(), Ordering::Release);
+    }
+
+    fn purge(&self, vm_id: u32) {
+        let mut queue = self.msg_queue.lock();
+        queue.retain(|msg| msg.vm_id != vm_id);
+        self.len.store(queue.len(), Ordering::Release);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len.load(Ordering::Acquire) == 0
+    }
+}
+
+/// What the messages are routed by: the VMs and the bindings of their vCPUs, or a model of them
+/// in the tests.
+trait Registry {
+    /// Whether `vm_id` is configured, and neither booted nor running.
+    fn bootable(&self, vm_id: u32) -> bool;
+    /// Whether the APs of `vm_id` are being started.
+    fn smp_boot_pending(&self, vm_id: u32) -> bool;
+    /// The state of `vm_id`, if it was booted.
+    fn vm_state(&self, vm_id: u32) -> Option<VmState>;
+    /// The CPU vCPU `vcpu_id` of `vm_id` is bound to.
+    fn vcpu_cpu(&self, vm_id: u32, vcpu_id: u32) -> Option<usize>;
+    /// The VM whose vCPU runs on `cpu`.
+    fn running_vm(&self, cpu: usize) -> Option<u32>;
+}
+
+struct Vms;
+
+impl Registry for Vms {
+    fn bootable(&self, vm_id: u32) -> bool {
+        matches!(crate::vm::vm_state(vm_id), None | Some(VmState::Stopped))
+            && crate::config::entry::vm_cfg_entry(vm_id as usize).is_some()
+    }
+
+    fn smp_boot_pending(&self, vm_id: u32) -> bool {
+        crate::vm::smp_boot_pending(vm_id)
+    }
+
+    fn vm_state(&self, vm_id: u32) -> Option<VmState> {
+        crate::vm::find_vm(vm_id).map(|vm| vm.state)
+    }
+
+    fn vcpu_cpu(&self, vm_id: u32, vcpu_id: u32) -> Option<usize> {
+        crate::vm::vcpu2pcpu(vm_id, vcpu_id).map(|cpu| cpu as usize)
+    }
+
+    fn running_vm(&self, cpu: usize) -> Option<u32> {
+        crate::vm::cpu_running_vm(cpu)
+    }
+}
+
+fn route(registry: &impl Registry, msg: &NmiMessage, this_cpu: usize, cpus: usize) -> Route {
+    match msg.request {
+        NmiRequest::BootVm if registry.bootable(msg.vm_id) => Route::Now,
+        // Booted meanwhile by another request, or removed.
+        NmiRequest::BootVm => Route::Drop,
+        // Sent by the CPU booting the VM, the AP runs in place of the host vCPU of this CPU.
+        NmiRequest::StartVcpu if registry.smp_boot_pending(msg.vm_id) => {
+            match registry.running_vm(this_cpu) {
+                Some(vm_id) if vm_id != crate::vm::HOST_VM_ID => Route::Keep,
+                _ => Route::Now,
+            }
+        }
+        NmiRequest::StartVcpu => Route::Drop,
+        // Only the VMs booted and still running have vCPUs to flush or interrupt.
+        NmiRequest::InvalidateEpt { .. } | NmiRequest::KickVcpu | NmiRequest::InjectNmi => {
+            match registry.vm_state(msg.vm_id) {
+                Some(VmState::Creating | VmState::Running | VmState::Paused) => {
+                    match registry.vcpu_cpu(msg.vm_id, msg.vcpu_id) {
+                        Some(cpu) if cpu == this_cpu => {
+                            if registry.running_vm(this_cpu) == Some(msg.vm_id) {
+                                Route::Now
+                            } else {
+                                Route::Keep
+                            }
+                        }
+                        Some(cpu) if cpu < cpus => Route::Forward(cpu),
+                        _ => Route::Drop,
+                    }
+                }
+                _ => Route::Drop,
+            }
         }
     }
-    pub fn push(&mut self, ipi_msg: NmiMessage) {
-        self.msg_queue.push_back(ipi_msg);
+}
+
+/// Take the messages of `queues[this_cpu]` which are to be handled now, keep the ones for another
+/// VM bound here, `forward` the ones of migrated vCPUs and drop the ones of the VMs gone. `None`
+/// if there was no new message.
+fn drain(
+    registry: &impl Registry,
+    queues: &[NmiMsgQueue],
+    this_cpu: usize,
+    mut forward: impl FnMut(usize, NmiMessage),
+) -> Option<Vec<NmiMessage>> {
+    // Routed without the queue lock, which is taken by senders holding the vCPU bindings.
+    let taken = queues[this_cpu].take();
+    let taken_len = taken.len();
+    let mut now = Vec::new();
+    let mut kept = VecDeque::new();
+    for msg in taken {
+        match route(registry, &msg, this_cpu, queues.len()) {
+            Route::Now => now.push(msg),
+            Route::Keep => kept.push_back(msg),
+            Route::Forward(cpu) => {
+                debug!(
+                    "CPU {} forwards NMI message {:?} to CPU {}",
+                    this_cpu, msg, cpu
+                );
+                forward(cpu, msg);
+            }
+            Route::Drop => debug!("CPU {} drops NMI message {:?}, VM gone", this_cpu, msg),
+        }
+    }
+    let all_kept = kept.len() == taken_len;
+    if !kept.is_empty() {
+        queues[this_cpu].put_back(kept);
     }
+    // Kept messages were already signaled, this NMI was not sent for them.
+    (!all_kept).then_some(now)
+}
 
-    pub fn pop(&mut self) -> Option<NmiMessage> {
-        self.msg_queue.pop_front()
+/// Send `msg` on to CPU `cpu`, the one its vCPU is bound to now.
+fn forward(cpu: usize, msg: NmiMessage) {
+    // Posted to this CPU, which the vCPU left: the CPU running it now must invalidate too.
+    #[cfg(target_arch = "x86_64")]
+    if let NmiRequest::InvalidateEpt { eptp } = msg.request {
+        crate::mm::post_invalidation(cpu, eptp);
+    }
+    send_to_cpu(cpu, msg);
+}
+
+/// Take the messages of the current CPU which are to be handled now, see [`drain`]. `None` if
+/// there was no new message, e.g. for an NMI not sent by the hypervisor.
+pub(crate) fn take_messages() -> Option<Vec<NmiMessage>> {
+    drain(&Vms, &CPU_NMI_LIST, axhal::current_cpu_id(), forward)
+}
+
+/// Whether the current CPU has messages, kept or not yet taken.
+pub(crate) fn has_messages() -> bool {
+    !CPU_NMI_LIST[axhal::current_cpu_id()].is_empty()
+}
+
+/// Drop the messages of `vm_id` from every queue, once the VM stopped.
+pub(crate) fn purge_vm_messages(vm_id: u32) {
+    for queue in CPU_NMI_LIST.iter() {
+        queue.purge(vm_id);
+    }
+}
+
+/// Queue `msg` for CPU `target_cpu_id`, another CPU, and send it an NMI.
+pub(crate) fn send_to_cpu(target_cpu_id: usize, msg: NmiMessage) {
+    let current_cpu = axhal::current_cpu_id();
+    if target_cpu_id == current_cpu {
+        warn!(
+            "CPU{} try send nmi to self, something is wrong",
+            current_cpu
+        );
+        return;
+    }
+    CPU_NMI_LIST[target_cpu_id].push(msg);
+    // Send ipi to target core through local APIC.
+    axhal::irq::send_nmi_to(target_cpu_id);
+}
+
+/// Send `request` to vCPU `vcpu_id` of `vm_id`, through the physical CPU it is bound to, and wake
+/// the vCPU if it is parked. Fails with `BadState` if it is bound to none.
+pub(crate) fn send_to_vcpu(vm_id: u32, vcpu_id: u32, request: NmiRequest) -> Result {
+    let cpu = crate::vm::vcpu2pcpu(vm_id, vcpu_id)
+        .map(|cpu| cpu as usize)
+        .filter(|&cpu| cpu < SMP)
+        .ok_or(Error::BadState)?;
+    let msg = NmiMessage {
+        vm_id,
+        vcpu_id,
+        request,
+    };
+    if cpu == axhal::current_cpu_id() {
+        // The vCPU is not in its guest, `check_events` takes the message before it enters it.
+        CPU_NMI_LIST[cpu].push(msg);
+    } else {
+        send_to_cpu(cpu, msg);
+    }
+    // A halted vCPU is parked in the hypervisor and does not see the NMI.
+    crate::park::wake_cpu(cpu);
+    Ok(())
+}
+
+/// Count an NMI injected into the vCPU running on the current CPU.
+pub(crate) fn count_injected_nmi() {
+    INJECTED_NMIS[axhal::current_cpu_id()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count an NMI which brought no message to the current CPU.
+pub(crate) fn count_unexpected_nmi() {
+    UNEXPECTED_NMIS[axhal::current_cpu_id()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// NMI statistics of physical CPU `cpu_id`.
+pub fn nmi_stats(cpu_id: usize) -> NmiStats {
+    NmiStats {
+        injected: INJECTED_NMIS[cpu_id].load(Ordering::Relaxed),
+        unexpected: UNEXPECTED_NMIS[cpu_id].load(Ordering::Relaxed),
     }
 }
 
@@ -36,24 +318,253 @@ pub fn nmi_send_msg_by_core_id(target_core_id: usize, msg: NmiMessage) {
     let current_cpu = axhal::current_cpu_id();
     let target_cpu_id = axhal::core_id_to_cpu_id(target_core_id);
     match target_cpu_id {
-        Some(target_cpu_id) => {
-            if target_cpu_id == current_cpu {
-                warn!(
-                    "CPU{} try send nmi to self, something is wrong",
-                    current_cpu
-                );
-                return;
-            }
+        Some(target_cpu_id) if target_cpu_id < SMP => {
             info!(
                 "CPU {} send nmi ipi to CPU{} (Linux processor ID {})",
                 current_cpu, target_cpu_id, target_core_id
             );
-            CORE_NMI_LIST[target_core_id].lock().push(msg);
-            // Send ipi to target core through local APIC.
-            axhal::irq::send_nmi_to(target_cpu_id);
+            send_to_cpu(target_cpu_id, msg);
         }
-        None => {
+        _ => {
             warn!("Core {} not existed, just skip it", target_core_id);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use alloc::vec;
+    use core::sync::atomic::{AtomicBool, AtomicU32};
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// A VM whose vCPU moves and which is destroyed, and another one, whose vCPU `n` is bound to
+    /// CPU `n`.
+    const VM: u32 = 1;
+    const OTHER_VM: u32 = 2;
+    const CPUS: usize = 3;
+    const EPTP: u64 = 0x8000_001e;
+    /// `Model::running` of a CPU out of the guests.
+    const NO_VM: u32 = 0;
+
+    #[derive(Default)]
+    struct Model {
+        /// Whether `VM` was destroyed.
+        destroyed: AtomicBool,
+        /// The CPU vCPU 0 of `VM` is bound to.
+        vcpu_cpu: AtomicUsize,
+        running: [AtomicU32; CPUS],
+    }
+
+    impl Registry for Model {
+        fn bootable(&self, _vm_id: u32) -> bool {
+            false
+        }
+
+        fn smp_boot_pending(&self, _vm_id: u32) -> bool {
+            false
+        }
+
+        fn vm_state(&self, vm_id: u32) -> Option<VmState> {
+            match vm_id {
+                VM if self.destroyed.load(Ordering::Acquire) => None,
+                VM | OTHER_VM => Some(VmState::Running),
+                _ => None,
+            }
+        }
+
+        fn vcpu_cpu(&self, vm_id: u32, vcpu_id: u32) -> Option<usize> {
+            match vm_id {
+                VM if !self.destroyed.load(Ordering::Acquire) => {
+                    Some(self.vcpu_cpu.load(Ordering::Acquire))
+                }
+                OTHER_VM => Some(vcpu_id as usize),
+                _ => None,
+            }
+        }
+
+        fn running_vm(&self, cpu: usize) -> Option<u32> {
+            match self.running[cpu].load(Ordering::Acquire) {
+                NO_VM => None,
+                vm_id => Some(vm_id),
+            }
+        }
+    }
+
+    impl Model {
+        fn new(vcpu_cpu: usize, running: [u32; CPUS]) -> Self {
+            let model = Self::default();
+            model.vcpu_cpu.store(vcpu_cpu, Ordering::Release);
+            for (cpu, vm_id) in model.running.iter().zip(running) {
+                cpu.store(vm_id, Ordering::Release);
+            }
+            model
+        }
+    }
+
+    fn queues() -> [NmiMsgQueue; CPUS] {
+        core::array::from_fn(|_| NmiMsgQueue::new())
+    }
+
+    fn msg(vm_id: u32, vcpu_id: u32, request: NmiRequest) -> NmiMessage {
+        NmiMessage {
+            vm_id,
+            vcpu_id,
+            request,
+        }
+    }
+
+    /// Drain the queue of `cpu`, queueing the messages forwarded to their CPU.
+    fn drain_cpu(model: &Model, queues: &[NmiMsgQueue], cpu: usize) -> Option<Vec<NmiMessage>> {
+        drain(model, queues, cpu, |target, msg| queues[target].push(msg))
+    }
+
+    #[test]
+    fn test_route() {
+        let model = Model::new(1, [NO_VM, VM, OTHER_VM]);
+        let queues = queues();
+        let kick = msg(VM, 0, NmiRequest::KickVcpu);
+        queues[1].push(kick);
+        assert_eq!(drain_cpu(&model, &queues, 1), Some(vec![kick]));
+        assert!(queues[1].is_empty());
+
+        // Another VM runs on CPU 1: kept, and not taken as the reason of the NMI.
+        model.running[1].store(OTHER_VM, Ordering::Release);
+        queues[1].push(kick);
+        assert_eq!(drain_cpu(&model, &queues, 1), None);
+        assert!(!queues[1].is_empty());
+        let nmi = msg(OTHER_VM, 1, NmiRequest::InjectNmi);
+        queues[1].push(nmi);
+        assert_eq!(drain_cpu(&model, &queues, 1), Some(vec![nmi]));
+        // Handled once its VM runs on CPU 1 again.
+        model.running[1].store(VM, Ordering::Release);
+        assert_eq!(drain_cpu(&model, &queues, 1), Some(vec![kick]));
+        assert!(queues[1].is_empty());
+    }
+
+    #[test]
+    fn test_put_back() {
+        let queue = NmiMsgQueue::new();
+        let kept = msg(VM, 0, NmiRequest::KickVcpu);
+        let sent = msg(OTHER_VM, 0, NmiRequest::KickVcpu);
+        queue.push(kept);
+        let taken = queue.take();
+        assert!(queue.is_empty());
+        // Sent while the taken messages were routed.
+        queue.push(sent);
+        queue.put_back(taken);
+        assert_eq!(queue.take(), [kept, sent]);
+    }
+
+    #[test]
+    fn test_migrated_vcpu() {
+        let model = Model::new(1, [NO_VM, VM, NO_VM]);
+        let queues = queues();
+        let invalidate = msg(VM, 0, NmiRequest::InvalidateEpt { eptp: EPTP });
+        let kick = msg(VM, 0, NmiRequest::KickVcpu);
+        queues[1].push(invalidate);
+        queues[1].push(kick);
+
+        // The vCPU was bound to CPU 2 before CPU 1 took its messages.
+        model.vcpu_cpu.store(2, Ordering::Release);
+        model.running[1].store(NO_VM, Ordering::Release);
+        model.running[2].store(VM, Ordering::Release);
+        let mut forwarded = Vec::new();
+        let now = drain(&model, &queues, 1, |target, msg| {
+            forwarded.push((target, msg))
+        });
+        // Not kept, the NMI did bring messages.
+        assert_eq!(now, Some(Vec::new()));
+        assert!(queues[1].is_empty());
+        assert_eq!(forwarded, [(2, invalidate), (2, kick)]);
+
+        for (target, msg) in forwarded {
+            queues[target].push(msg);
+        }
+        assert_eq!(drain_cpu(&model, &queues, 2), Some(vec![invalidate, kick]));
+
+        // Bound to no CPU the queues know of.
+        model.vcpu_cpu.store(CPUS, Ordering::Release);
+        queues[2].push(kick);
+        assert_eq!(drain_cpu(&model, &queues, 2), Some(Vec::new()));
+        assert!(queues.iter().all(NmiMsgQueue::is_empty));
+    }
+
+    #[test]
+    fn test_destroyed_vm() {
+        let model = Model::new(1, [NO_VM, OTHER_VM, NO_VM]);
+        let queues = queues();
+        let kick = msg(VM, 0, NmiRequest::KickVcpu);
+        let other = msg(OTHER_VM, 2, NmiRequest::KickVcpu);
+        // Kept while the other VM runs on CPU 1.
+        queues[1].push(kick);
+        assert_eq!(drain_cpu(&model, &queues, 1), None);
+        queues[2].push(kick);
+        queues[2].push(other);
+
+        // Its messages are purged, the others stay.
+        model.destroyed.store(true, Ordering::Release);
+        for queue in queues.iter() {
+            queue.purge(VM);
+        }
+        assert!(queues[1].is_empty());
+        assert_eq!(queues[2].take(), [other]);
+
+        // Sent before it was destroyed, taken after: dropped.
+        model.running[1].store(NO_VM, Ordering::Release);
+        queues[1].push(kick);
+        assert_eq!(drain_cpu(&model, &queues, 1), Some(Vec::new()));
+        assert!(queues[1].is_empty());
+    }
+
+    #[test]
+    fn test_race_with_destruction() {
+        const SENT: usize = 2000;
+        // vCPU 0 of VM and vCPU 1 of the other VM are bound to CPU 1, which runs VM until it is
+        // destroyed, then the other VM.
+        let model = Arc::new(Model::new(1, [NO_VM, VM, NO_VM]));
+        let queues = Arc::new(queues());
+        let sender = {
+            let (model, queues) = (model.clone(), queues.clone());
+            std::thread::spawn(move || {
+                for _ in 0..SENT {
+                    // As `send_to_vcpu`, which fails for a VM gone.
+                    if model.vcpu_cpu(VM, 0).is_some() {
+                        queues[1].push(msg(VM, 0, NmiRequest::KickVcpu));
+                    }
+                    queues[1].push(msg(OTHER_VM, 1, NmiRequest::KickVcpu));
+                    std::thread::yield_now();
+                }
+            })
+        };
+
+        let mut handled = 0;
+        let mut drains = 0;
+        while !sender.is_finished() || !queues[1].is_empty() {
+            drains += 1;
+            if drains == 50 {
+                model.destroyed.store(true, Ordering::Release);
+                for queue in queues.iter() {
+                    queue.purge(VM);
+                }
+                model.running[1].store(OTHER_VM, Ordering::Release);
+            }
+            let messages = drain_cpu(&model, &queues[..], 1).unwrap_or_default();
+            let destroyed = model.destroyed.load(Ordering::Acquire);
+            for msg in messages {
+                // No message of the VM is handled once it is destroyed.
+                assert!(!destroyed || msg.vm_id != VM, "{:?} handled", msg);
+                handled += (msg.vm_id == OTHER_VM) as usize;
+            }
+            std::thread::yield_now();
+        }
+        sender.join().unwrap();
+        assert!(model.destroyed.load(Ordering::Acquire));
+        // Kept while VM ran, the messages of the other VM are neither lost nor purged.
+        assert_eq!(handled, SENT);
+        assert!(queues.iter().all(NmiMsgQueue::is_empty));
+    }
+}
@@ -0,0 +1,249 @@
+//! Parking of halted vCPUs.
+//!
+//! A vCPU which executes HLT blocks its task on the wait queue of its physical CPU (vCPUs are
+//! pinned, so there is one parked vCPU per CPU at most) instead of polling `check_events`. It is
+//! woken by:
+//!
+//! - the timeout armed for the next emulated timer deadline, or for the next poll of the host
+//!   console input while the VM reads it, see [`crate::console_mux`];
+//! - [`wake_vcpu`], the single entry point for interrupt assertions, doorbells and kicks. The
+//!   sources which assert interrupts from outside of the vCPUs, e.g. a network backend receiving
+//!   a frame from its peer, are given a [`VcpuWaker`] when they are attached to the VM.
+//!
+//! A vCPU only parks once it took the interrupts its devices raised since its last exit and none
+//! is pending, a wakeup sent before it parks is not lost: it returns at once.
+//!
+//! The time spent parked is counted in the [`ParkStats`], the rest of the time of a CPU running
+//! an idle guest is spent in the hypervisor or in the guest.
+//! Wakeups may be spurious, the vCPU always goes through `check_events` before re-entering the
+//! guest and simply halts again if there is nothing to inject.
+//!
+//! `axtask` has a single run queue and no CPU affinity, so the task of a woken vCPU may be picked
+//! by another CPU first. The VMCS of the vCPU is only current on the CPU `bind_vcpu` loaded it on:
+//! moving it would take a VMCLEAR on that CPU before loading it on the new one, which
+//! `hypercraft` does not offer, so the task yields until its own CPU runs it again, see
+//! [`move_to_cpu`], and the vCPUs never migrate.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use axconfig::SMP;
+use axhal::current_cpu_id;
+use axhal::time::current_time_nanos;
+use axtask::WaitQueue;
+use spin::Mutex;
+
+/// Wakeup latency statistics of the parked vCPU of a physical CPU.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParkStats {
+    /// Parks ended by [`wake_vcpu`].
+    pub wakeups: u64,
+    /// Parks ended by the timer deadline.
+    pub timeouts: u64,
+    /// Sum and maximum of the delays between [`wake_vcpu`] and the vCPU resuming.
+    pub total_wake_latency_ns: u64,
+    pub max_wake_latency_ns: u64,
+    /// Maximum delay between a timer deadline and the vCPU resuming.
+    pub max_timer_jitter_ns: u64,
+    /// Time spent parked by the halted vCPUs.
+    pub parked_ns: u64,
+    /// Parks after which another CPU picked the task of the vCPU first.
+    pub migrations: u64,
+}
+
+impl ParkStats {
+    /// Count a park from `start_ns` to `now_ns`, woken at `woken_ns` by [`wake_vcpu`] if not 0,
+    /// or by `deadline_ns` otherwise.
+    fn record(
+        &mut self,
+        start_ns: u64,
+        now_ns: u64,
+        woken_ns: u64,
+        deadline_ns: Option<u64>,
+        migrated: bool,
+    ) {
+        self.parked_ns += now_ns.saturating_sub(start_ns);
+        self.migrations += migrated as u64;
+        if woken_ns != 0 {
+            let latency = now_ns.saturating_sub(woken_ns);
+            self.wakeups += 1;
+            self.total_wake_latency_ns += latency;
+            self.max_wake_latency_ns = self.max_wake_latency_ns.max(latency);
+        } else if let Some(deadline_ns) = deadline_ns {
+            self.timeouts += 1;
+            self.max_timer_jitter_ns = self
+                .max_timer_jitter_ns
+                .max(now_ns.saturating_sub(deadline_ns));
+        }
+    }
+}
+
+/// Wakes a vCPU if it is parked, see [`wake_vcpu`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VcpuWaker {
+    vm_id: u32,
+    vcpu_id: u32,
+}
+
+impl VcpuWaker {
+    /// A waker of vCPU `vcpu_id` of VM `vm_id`.
+    pub const fn new(vm_id: u32, vcpu_id: u32) -> Self {
+        Self { vm_id, vcpu_id }
+    }
+
+    pub fn wake(&self) {
+        wake_vcpu(self.vm_id, self.vcpu_id);
+    }
+}
+
+struct ParkSlot {
+    queue: WaitQueue,
+    wake_pending: AtomicBool,
+    /// Time of the first [`wake_vcpu`] of the current park, 0 if none.
+    woken_ns: AtomicU64,
+    stats: Mutex<ParkStats>,
+}
+
+impl ParkSlot {
+    const fn new() -> Self {
+        Self {
+            queue: WaitQueue::new(),
+            wake_pending: AtomicBool::new(false),
+            woken_ns: AtomicU64::new(0),
+            stats: Mutex::new(ParkStats {
+                wakeups: 0,
+                timeouts: 0,
+                total_wake_latency_ns: 0,
+                max_wake_latency_ns: 0,
+                max_timer_jitter_ns: 0,
+                parked_ns: 0,
+                migrations: 0,
+            }),
+        }
+    }
+}
+
+const PER_CPU_PARK_SLOT: ParkSlot = ParkSlot::new();
+/// Park slot of the vCPU running on each physical CPU, indexed by cpu id.
+static PARK_SLOTS: [ParkSlot; SMP] = [PER_CPU_PARK_SLOT; SMP];
+
+/// Block the current vCPU until it is woken, or until `deadline_ns` if there is one.
+pub(crate) fn park(deadline_ns: Option<u64>) {
+    let cpu_id = current_cpu_id();
+    let slot = &PARK_SLOTS[cpu_id];
+    let woken = || slot.wake_pending.load(Ordering::Acquire);
+    let start = current_time_nanos();
+    match deadline_ns {
+        Some(deadline_ns) => {
+            if deadline_ns > start {
+                slot.queue
+                    .wait_timeout_until(Duration::from_nanos(deadline_ns - start), woken);
+            }
+        }
+        None => slot.queue.wait_until(woken),
+    }
+    let migrated = current_cpu_id() != cpu_id;
+    move_to_cpu(cpu_id, None);
+
+    let now = current_time_nanos();
+    let woken_ns = slot.woken_ns.swap(0, Ordering::AcqRel);
+    slot.wake_pending.store(false, Ordering::Release);
+    slot.stats
+        .lock()
+        .record(start, now, woken_ns, deadline_ns, migrated);
+}
+
+/// Block the current vCPU until `ready()`, which is checked again on each wakeup. These parks are
+/// not counted in the [`ParkStats`].
+pub(crate) fn park_until(ready: impl Fn() -> bool) {
+    let cpu_id = current_cpu_id();
+    let slot = &PARK_SLOTS[cpu_id];
+    while !ready() {
+        slot.queue
+            .wait_until(|| ready() || slot.wake_pending.load(Ordering::Acquire));
+        move_to_cpu(cpu_id, None);
+        slot.woken_ns.store(0, Ordering::Release);
+        slot.wake_pending.store(false, Ordering::Release);
+    }
+}
+
+/// Yield until the current task runs on physical CPU `cpu_id`, giving up at `deadline_ns` if
+/// there is one. Returns whether it does.
+pub(crate) fn move_to_cpu(cpu_id: usize, deadline_ns: Option<u64>) -> bool {
+    while current_cpu_id() != cpu_id {
+        if deadline_ns.map_or(false, |deadline_ns| current_time_nanos() > deadline_ns) {
+            return false;
+        }
+        axtask::yield_now();
+    }
+    true
+}
+
+/// Wake the vCPU parked on physical CPU `cpu_id`, if any.
+pub(crate) fn wake_cpu(cpu_id: usize) {
+    let slot = &PARK_SLOTS[cpu_id];
+    let _ = slot.woken_ns.compare_exchange(
+        0,
+        current_time_nanos(),
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    );
+    slot.wake_pending.store(true, Ordering::Release);
+    slot.queue.notify_one(true);
+}
+
+/// Wake vCPU `vcpu_id` of VM `vm_id` if it is parked, e.g. because an interrupt was asserted to
+/// it. A vCPU which is not parked ignores the wakeup.
+pub fn wake_vcpu(vm_id: u32, vcpu_id: u32) {
+    if let Some(cpu_id) = crate::vm::vcpu2pcpu(vm_id, vcpu_id) {
+        if (cpu_id as usize) < SMP {
+            wake_cpu(cpu_id as usize);
+        }
+    }
+}
+
+/// Park statistics of physical CPU `cpu_id`.
+pub fn park_stats(cpu_id: usize) -> ParkStats {
+    *PARK_SLOTS[cpu_id].stats.lock()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_wakeup() {
+        let mut stats = ParkStats::default();
+        stats.record(1_000, 5_000, 4_000, Some(10_000), false);
+        stats.record(6_000, 6_500, 6_200, None, true);
+        assert_eq!(stats.wakeups, 2);
+        assert_eq!(stats.timeouts, 0);
+        assert_eq!(stats.total_wake_latency_ns, 1_000 + 300);
+        assert_eq!(stats.max_wake_latency_ns, 1_000);
+        assert_eq!(stats.parked_ns, 4_000 + 500);
+        assert_eq!(stats.migrations, 1);
+        assert_eq!(stats.max_timer_jitter_ns, 0);
+    }
+
+    #[test]
+    fn test_record_timeout() {
+        let mut stats = ParkStats::default();
+        stats.record(0, 10_200, 0, Some(10_000), false);
+        stats.record(20_000, 30_050, 0, Some(30_000), false);
+        // A park returning at once, its deadline already passed.
+        stats.record(40_000, 40_000, 0, Some(39_000), false);
+        assert_eq!(stats.timeouts, 3);
+        assert_eq!(stats.wakeups, 0);
+        assert_eq!(stats.max_timer_jitter_ns, 1_000);
+        assert_eq!(stats.parked_ns, 10_200 + 10_050);
+    }
+
+    #[test]
+    fn test_record_spurious() {
+        // Neither woken nor timed out, e.g. by the wakeup of a park before.
+        let mut stats = ParkStats::default();
+        stats.record(0, 100, 0, None, false);
+        assert_eq!(stats.wakeups + stats.timeouts, 0);
+        assert_eq!(stats.parked_ns, 100);
+    }
+}
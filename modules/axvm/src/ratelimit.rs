@@ -0,0 +1,82 @@
+//! Rate limiting of log messages on hot VM-exit paths.
+//!
+//! Each call site owns a `static` [`RateLimiter`] and logs through [`ratelimited!`], which emits
+//! at most `burst` messages per second and folds the rest into a "suppressed" summary.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+pub struct RateLimiter {
+    name: &'static str,
+    /// Messages allowed per second.
+    burst: u32,
+    /// Start of the current one-second window.
+    window_start_ns: AtomicU64,
+    /// Messages emitted (or attempted) in the current window.
+    emitted: AtomicU32,
+    /// Messages suppressed since the last emitted one.
+    pending: AtomicU64,
+    /// Messages suppressed since boot.
+    total_suppressed: AtomicU64,
+}
+
+impl RateLimiter {
+    pub const fn new(name: &'static str, burst: u32) -> Self {
+        Self {
+            name,
+            burst,
+            window_start_ns: AtomicU64::new(0),
+            emitted: AtomicU32::new(0),
+            pending: AtomicU64::new(0),
+            total_suppressed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Number of messages suppressed by this limiter since boot.
+    pub fn suppressed(&self) -> u64 {
+        self.total_suppressed.load(Ordering::Relaxed)
+    }
+
+    /// Take a token, returns the number of messages suppressed since the last emitted one, or
+    /// `None` if this message should be suppressed as well.
+    pub fn check(&self) -> Option<u64> {
+        let now = axhal::time::current_time_nanos();
+        let start = self.window_start_ns.load(Ordering::Relaxed);
+        if now.wrapping_sub(start) >= NANOS_PER_SEC
+            && self
+                .window_start_ns
+                .compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.emitted.store(0, Ordering::Relaxed);
+        }
+        if self.emitted.fetch_add(1, Ordering::Relaxed) < self.burst {
+            Some(self.pending.swap(0, Ordering::Relaxed))
+        } else {
+            self.pending.fetch_add(1, Ordering::Relaxed);
+            self.total_suppressed.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+/// Log through a [`RateLimiter`]: `ratelimited!(LIMITER, log::Level::Debug, "fmt", args..)`.
+///
+/// Nothing is counted when the level is disabled, so the limiter costs nothing on release logs.
+macro_rules! ratelimited {
+    ($limiter:expr, $lvl:expr, $($arg:tt)+) => {
+        if log_enabled!($lvl) {
+            if let Some(suppressed) = $limiter.check() {
+                if suppressed > 0 {
+                    log!($lvl, "[{}] suppressed {} similar messages", $limiter.name(), suppressed);
+                }
+                log!($lvl, $($arg)+);
+            }
+        }
+    };
+}
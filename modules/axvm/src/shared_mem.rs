@@ -0,0 +1,208 @@
+//! Guest memory shared with the hypervisor.
+//!
+//! A guest which wants to hand the hypervisor a buffer, e.g. a log buffer or a ring of its own
+//! protocol, without a whole emulated device, shares its guest physical range with
+//! `HVC_MEM_SHARE` and gets a handle back, which it passes to the hypervisor side of the protocol
+//! and eventually to `HVC_MEM_UNSHARE`.
+//!
+//! The range must be RAM mapped in the nested page table of the VM, and must not overlap the MMIO
+//! range of one of its emulated devices. Its pages are translated once, when it is shared, and
+//! the configuration of the VM, which owns them, is kept alive as long as the region is. All the
+//! regions of a VM are unshared when it stops.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::config::entry::{vm_cfg_entry, VMCfgEntry};
+use crate::{Error, GuestPhysAddr, Result};
+
+/// Regions a VM may share at once.
+pub const MAX_SHARED_REGIONS: usize = 16;
+/// Largest region, in bytes.
+pub const MAX_SHARED_REGION_SIZE: usize = 256 * PAGE_SIZE_4K;
+
+bitflags::bitflags! {
+    /// What the hypervisor may do with a shared region, the `flags` of `HVC_MEM_SHARE`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ShareFlags: usize {
+        /// The hypervisor writes the region, otherwise it only reads it.
+        const WRITE = 1 << 0;
+    }
+}
+
+/// A guest physical range shared by a VM.
+pub struct SharedRegion {
+    gpa: GuestPhysAddr,
+    len: usize,
+    flags: ShareFlags,
+    /// Hypervisor address of each page of the range, the first one starting at the page of `gpa`.
+    pages: Vec<*mut u8>,
+    /// Owns the RAM of a guest VM, the host RAM is never released.
+    _cfg: Option<Arc<VMCfgEntry>>,
+}
+
+// The pages stay mapped as long as the region is shared, see `_cfg`.
+unsafe impl Send for SharedRegion {}
+
+impl SharedRegion {
+    pub fn gpa(&self) -> GuestPhysAddr {
+        self.gpa
+    }
+
+    /// Size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    pub fn flags(&self) -> ShareFlags {
+        self.flags
+    }
+
+    /// Call `f(host, offset, len)` for each page-bounded piece of the `len` bytes at `offset` of
+    /// the region, `offset` being the position of the piece in the buffer.
+    fn for_each_page(
+        &self,
+        offset: usize,
+        len: usize,
+        mut f: impl FnMut(*mut u8, usize, usize),
+    ) -> Result {
+        if offset.checked_add(len).map_or(true, |end| end > self.len) {
+            return Err(Error::OutOfRange);
+        }
+        let mut done = 0;
+        while done < len {
+            let addr = self.gpa % PAGE_SIZE_4K + offset + done;
+            let in_page = addr % PAGE_SIZE_4K;
+            let chunk = (len - done).min(PAGE_SIZE_4K - in_page);
+            // SAFETY: the piece is inside the region, checked above.
+            let host = unsafe { self.pages[addr / PAGE_SIZE_4K].add(in_page) };
+            f(host, done, chunk);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Read `buf.len()` bytes at `offset` of the region.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result {
+        self.for_each_page(offset, buf.len(), |host, pos, len| {
+            for (i, byte) in buf[pos..pos + len].iter_mut().enumerate() {
+                // The guest may write the region concurrently.
+                *byte = unsafe { host.add(i).read_volatile() };
+            }
+        })
+    }
+
+    /// Write `buf` at `offset` of the region, if it was shared with [`ShareFlags::WRITE`].
+    pub fn write(&self, offset: usize, buf: &[u8]) -> Result {
+        if !self.flags.contains(ShareFlags::WRITE) {
+            return Err(Error::BadState);
+        }
+        self.for_each_page(offset, buf.len(), |host, pos, len| {
+            for (i, &byte) in buf[pos..pos + len].iter().enumerate() {
+                unsafe { host.add(i).write_volatile(byte) };
+            }
+        })
+    }
+}
+
+/// The regions shared by one VM, by handle.
+#[derive(Default)]
+struct SharedRegions {
+    regions: BTreeMap<u32, SharedRegion>,
+    next_handle: u32,
+}
+
+static SHARED_REGIONS: Mutex<BTreeMap<u32, SharedRegions>> = Mutex::new(BTreeMap::new());
+
+/// Share the `len` bytes at `gpa` of `vm_id` with the hypervisor, returns the handle of the
+/// region, never 0.
+pub fn share(vm_id: u32, gpa: GuestPhysAddr, len: usize, flags: usize) -> Result<u32> {
+    let flags = ShareFlags::from_bits(flags).ok_or(Error::InvalidParam)?;
+    let end = gpa.checked_add(len).ok_or(Error::InvalidParam)?;
+    if len == 0 || len > MAX_SHARED_REGION_SIZE {
+        return Err(Error::InvalidParam);
+    }
+    if vm_id != crate::vm::HOST_VM_ID && crate::vm::find_vm(vm_id).is_none() {
+        return Err(Error::BadState);
+    }
+    if crate::device::vm_claims_mmio(vm_id, gpa as u64..end as u64) {
+        warn!(
+            "VM [{}] cannot share {:#x}..{:#x}, it overlaps an emulated device",
+            vm_id, gpa, end
+        );
+        return Err(Error::InvalidParam);
+    }
+    let first_page = gpa - gpa % PAGE_SIZE_4K;
+    let pages = (first_page..end)
+        .step_by(PAGE_SIZE_4K)
+        .map(|page| crate::hvc::guest_ram_page_hva(vm_id, page))
+        .collect::<Result<Vec<_>>>()
+        .map_err(|err| {
+            warn!(
+                "VM [{}] cannot share {:#x}..{:#x}, it is not mapped RAM",
+                vm_id, gpa, end
+            );
+            err
+        })?;
+    let region = SharedRegion {
+        gpa,
+        len,
+        flags,
+        pages,
+        _cfg: vm_cfg_entry(vm_id as usize).filter(|_| vm_id != crate::vm::HOST_VM_ID),
+    };
+
+    let mut all = SHARED_REGIONS.lock();
+    let vm = all.entry(vm_id).or_default();
+    if vm.regions.len() >= MAX_SHARED_REGIONS {
+        return Err(Error::NoMemory);
+    }
+    vm.next_handle = vm.next_handle.wrapping_add(1).max(1);
+    while vm.regions.contains_key(&vm.next_handle) {
+        vm.next_handle = vm.next_handle.wrapping_add(1).max(1);
+    }
+    let handle = vm.next_handle;
+    vm.regions.insert(handle, region);
+    debug!(
+        "VM [{}] shared {:#x}..{:#x} {:?} as handle {}",
+        vm_id, gpa, end, flags, handle
+    );
+    Ok(handle)
+}
+
+/// Stop sharing the region `handle` of `vm_id`.
+pub fn unshare(vm_id: u32, handle: u32) -> Result {
+    let mut all = SHARED_REGIONS.lock();
+    let vm = all.get_mut(&vm_id).ok_or(Error::InvalidParam)?;
+    vm.regions.remove(&handle).ok_or(Error::InvalidParam)?;
+    if vm.regions.is_empty() {
+        all.remove(&vm_id);
+    }
+    debug!("VM [{}] unshared handle {}", vm_id, handle);
+    Ok(())
+}
+
+/// Call `f` with the region `handle` of `vm_id`, which stays shared until `f` returns.
+pub fn with_shared_region<R>(
+    vm_id: u32,
+    handle: u32,
+    f: impl FnOnce(&SharedRegion) -> R,
+) -> Option<R> {
+    let all = SHARED_REGIONS.lock();
+    all.get(&vm_id)?.regions.get(&handle).map(f)
+}
+
+/// Unshare all the regions of `vm_id`, once the VM stopped and its memory may be released.
+pub(crate) fn unshare_all(vm_id: u32) {
+    if let Some(vm) = SHARED_REGIONS.lock().remove(&vm_id) {
+        debug!(
+            "VM [{}] stopped, {} shared region(s) released",
+            vm_id,
+            vm.regions.len()
+        );
+    }
+}
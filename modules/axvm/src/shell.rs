@@ -0,0 +1,514 @@
+//! Host management shell on the host console.
+//!
+//! [`spawn_shell`] runs the shell in its own task and gives it the console input, see
+//! [`crate::console_mux`]. The commands only call the management functions of [`crate::vm`], so
+//! the shell never runs in, nor waits for, a vCPU exit path.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use axlog::{ax_print, ax_println};
+
+use crate::config::entry::{vm_cfg_entries, vm_cfg_entry, VmType};
+use crate::console_mux::{
+    can_focus, console_focus, host_getchar, set_console_focus, start_host_shell, ConsoleFocus,
+};
+use crate::vm::{self, VmState, VCPU_TO_PCPU};
+
+const PROMPT: &str = "axvm> ";
+const MAX_LINE_LEN: usize = 128;
+const HISTORY_LEN: usize = 16;
+/// Interval between two polls of the console input.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// RAM of a VM booted from an image in host memory, unless given.
+const IMAGE_MEMORY_MB: usize = 16;
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const BS: u8 = 0x08;
+const DEL: u8 = 0x7f;
+const ESC: u8 = 0x1b;
+const CTRL_C: u8 = 0x03;
+const CTRL_U: u8 = 0x15;
+
+type CmdResult = core::result::Result<(), String>;
+type CmdHandler = fn(&[&str]) -> CmdResult;
+
+struct Command {
+    /// The words of the command, e.g. `vm list`.
+    name: &'static str,
+    args: &'static str,
+    help: &'static str,
+    handler: CmdHandler,
+}
+
+const CMD_TABLE: &[Command] = &[
+    Command {
+        name: "help",
+        args: "",
+        help: "list the commands",
+        handler: do_help,
+    },
+    Command {
+        name: "vm list",
+        args: "",
+        help: "list the configured VMs and their states",
+        handler: do_vm_list,
+    },
+    Command {
+        name: "vm boot",
+        args: "<name|id> | <nimbos|linux> <paddr> [<MiB>]",
+        help: "start a configured VM, or the image bundle at a host address, in a new task",
+        handler: do_vm_boot,
+    },
+    Command {
+        name: "vm pause",
+        args: "<id>",
+        help: "pause a running VM",
+        handler: do_vm_pause,
+    },
+    Command {
+        name: "vm resume",
+        args: "<id>",
+        help: "resume a paused VM",
+        handler: do_vm_resume,
+    },
+    Command {
+        name: "vm kill",
+        args: "<id>",
+        help: "stop a VM before the next entry of its vCPUs",
+        handler: do_vm_kill,
+    },
+    Command {
+        name: "vm stats",
+        args: "<id>",
+        help: "show the state and the vCPUs of a VM, and log where its VM exits went",
+        handler: do_vm_stats,
+    },
+    Command {
+        name: "vm nmi",
+        args: "<id> [<vcpu>]",
+        help: "inject an NMI into a vCPU of a VM, vCPU 0 by default",
+        handler: do_vm_nmi,
+    },
+    Command {
+        name: "vm console",
+        args: "<id>",
+        help: "give the console input to a running VM, Ctrl-A gives it back",
+        handler: do_vm_console,
+    },
+];
+
+/// Start the shell in a new task, and give it the console input.
+pub fn spawn_shell() {
+    start_host_shell();
+    axtask::spawn_raw(run_shell, "axvm-shell".into(), axconfig::TASK_STACK_SIZE);
+}
+
+fn run_shell() {
+    ax_println!("axvm management shell, type `help` for the commands");
+    let mut editor = LineEditor::new();
+    let mut focused = true;
+    ax_print!("{}", PROMPT);
+    loop {
+        let c = match host_getchar() {
+            Some(c) => c,
+            None => {
+                // The focus came back from a VM.
+                let focus = console_focus() == ConsoleFocus::Host;
+                if focus && !focused {
+                    ax_println!("\nback to the host shell");
+                    editor.redraw();
+                }
+                focused = focus;
+                axtask::sleep(POLL_INTERVAL);
+                continue;
+            }
+        };
+        if let Some(line) = editor.feed(c) {
+            run_line(&line);
+            editor.push_history(line);
+            if console_focus() == ConsoleFocus::Host {
+                ax_print!("{}", PROMPT);
+            } else {
+                focused = false;
+            }
+        }
+    }
+}
+
+fn run_line(line: &str) {
+    let tokens = match tokenize(line) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            ax_println!("{}", err);
+            return;
+        }
+    };
+    if tokens.is_empty() {
+        return;
+    }
+    let words: Vec<&str> = tokens.iter().map(String::as_str).collect();
+    let command = CMD_TABLE.iter().find(|command| {
+        let name: Vec<&str> = command.name.split(' ').collect();
+        words.len() >= name.len() && words[..name.len()] == name[..]
+    });
+    match command {
+        Some(command) => {
+            let args = &words[command.name.split(' ').count()..];
+            if let Err(err) = (command.handler)(args) {
+                ax_println!("{}: {}", command.name, err);
+            }
+        }
+        None => ax_println!("{}: command not found, see `help`", line.trim()),
+    }
+}
+
+/// Split `line` at whitespaces, except inside double quotes.
+fn tokenize(line: &str) -> core::result::Result<Vec<String>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut token: Option<String> = None;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                token.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if let Some(token) = token.take() {
+                    tokens.push(token);
+                }
+            }
+            c => token.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quote");
+    }
+    tokens.extend(token);
+    Ok(tokens)
+}
+
+/// Escape sequence being received: only the arrows of the history are understood.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Esc,
+    Csi,
+}
+
+struct LineEditor {
+    line: String,
+    history: VecDeque<String>,
+    /// Position in the history while browsing it, 0 being the latest line.
+    history_pos: Option<usize>,
+    escape: Escape,
+}
+
+impl LineEditor {
+    fn new() -> Self {
+        Self {
+            line: String::new(),
+            history: VecDeque::new(),
+            history_pos: None,
+            escape: Escape::None,
+        }
+    }
+
+    /// Handle an input byte, returns the line once it is entered.
+    fn feed(&mut self, c: u8) -> Option<String> {
+        match (self.escape, c) {
+            (Escape::Esc, b'[') => {
+                self.escape = Escape::Csi;
+                return None;
+            }
+            (Escape::Csi, b'A') => self.browse_history(true),
+            (Escape::Csi, b'B') => self.browse_history(false),
+            (Escape::Esc | Escape::Csi, _) => {}
+            (Escape::None, CR | LF) => {
+                ax_print!("\r\n");
+                self.history_pos = None;
+                return Some(core::mem::take(&mut self.line));
+            }
+            (Escape::None, BS | DEL) => {
+                if self.line.pop().is_some() {
+                    ax_print!("\x08 \x08");
+                }
+            }
+            (Escape::None, CTRL_C) => {
+                ax_print!("^C\r\n{}", PROMPT);
+                self.line.clear();
+                self.history_pos = None;
+            }
+            (Escape::None, CTRL_U) => {
+                self.line.clear();
+                self.redraw();
+            }
+            (Escape::None, ESC) => {
+                self.escape = Escape::Esc;
+                return None;
+            }
+            (Escape::None, 0x20..=0x7e) => {
+                if self.line.len() < MAX_LINE_LEN {
+                    self.line.push(c as char);
+                    ax_print!("{}", c as char);
+                }
+            }
+            // Other control characters, e.g. a stray CONSOLE_ESCAPE.
+            (Escape::None, _) => {}
+        }
+        self.escape = Escape::None;
+        None
+    }
+
+    fn browse_history(&mut self, older: bool) {
+        let pos = match (self.history_pos, older) {
+            (None, true) if !self.history.is_empty() => Some(0),
+            (Some(pos), true) if pos + 1 < self.history.len() => Some(pos + 1),
+            (Some(pos), true) => Some(pos),
+            (Some(0), false) | (None, false) => None,
+            (Some(pos), false) => Some(pos - 1),
+            (None, true) => None,
+        };
+        self.history_pos = pos;
+        self.line = pos.map_or_else(String::new, |pos| self.history[pos].clone());
+        self.redraw();
+    }
+
+    fn push_history(&mut self, line: String) {
+        if line.trim().is_empty() || self.history.front() == Some(&line) {
+            return;
+        }
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_back();
+        }
+        self.history.push_front(line);
+    }
+
+    /// Print the prompt and the line again, on the current terminal line.
+    fn redraw(&self) {
+        ax_print!("\r\x1b[K{}{}", PROMPT, self.line);
+    }
+}
+
+fn parse_vm_id(args: &[&str]) -> core::result::Result<u32, String> {
+    match args {
+        [id] => id.parse().map_err(|_| format!("invalid VM id `{}`", id)),
+        _ => Err(String::from("expected one VM id")),
+    }
+}
+
+/// Parse a number, in hexadecimal with a `0x` prefix.
+fn parse_number(arg: &str) -> core::result::Result<usize, String> {
+    let parsed = match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number `{}`", arg))
+}
+
+fn do_help(_args: &[&str]) -> CmdResult {
+    ax_println!("commands:");
+    for command in CMD_TABLE {
+        ax_println!("  {} {}", command.name, command.args);
+        ax_println!("      {}", command.help);
+    }
+    ax_println!("type Ctrl-A to leave a VM console");
+    Ok(())
+}
+
+fn do_vm_list(_args: &[&str]) -> CmdResult {
+    ax_println!(
+        "{:>4}  {:<20} {:<12} {:>5}  {:<12} {}",
+        "ID",
+        "NAME",
+        "TYPE",
+        "VCPUS",
+        "CPUS",
+        "STATE"
+    );
+    for entry in vm_cfg_entries() {
+        let vm_id = entry.get_vm_id() as u32;
+        let state = match vm::vm_state(vm_id) {
+            Some(VmState::Stopped | VmState::Crashed) => {
+                format!("{:?}", vm::vm_exit(vm_id).unwrap_or(vm::VmExit::Stopped))
+            }
+            Some(state) => format!("{:?}", state),
+            None => String::from("Created"),
+        };
+        // The vCPUs booted and their CPUs, or those the VM will boot and the CPUs it pins them to.
+        let affinity = entry.vcpu_affinity();
+        let (vcpus, cpus, exclusive) = match vm::find_vm(vm_id) {
+            Some(info) => (info.vcpus, info.cpus, info.exclusive),
+            None => (
+                entry.get_vcpus(),
+                affinity
+                    .cores
+                    .iter()
+                    .filter_map(|&core| axhal::core_id_to_cpu_id(core))
+                    .collect(),
+                affinity.exclusive,
+            ),
+        };
+        ax_println!(
+            "{:>4}  {:<20} {:<12} {:>5}  {:<12} {}",
+            vm_id,
+            entry.get_name(),
+            format!("{:?}", entry.get_vm_type()),
+            vcpus,
+            format_cpus(&cpus, exclusive),
+            state
+        );
+    }
+    Ok(())
+}
+
+/// `cpus` as a list, `!` marking dedicated CPUs, `-` for none.
+fn format_cpus(cpus: &[usize], exclusive: bool) -> String {
+    if cpus.is_empty() {
+        return String::from("-");
+    }
+    let list: Vec<String> = cpus.iter().map(|cpu| format!("{}", cpu)).collect();
+    format!("{}{}", list.join(","), if exclusive { "!" } else { "" })
+}
+
+fn do_vm_boot(args: &[&str]) -> CmdResult {
+    let target = match args {
+        [target] => target,
+        [vm_type, paddr] => return boot_image(vm_type, paddr, None),
+        [vm_type, paddr, memory_mb] => return boot_image(vm_type, paddr, Some(*memory_mb)),
+        _ => {
+            return Err(String::from(
+                "expected a VM name or id, or a VM type and the address of an image",
+            ))
+        }
+    };
+    let entry = target
+        .parse::<usize>()
+        .ok()
+        .and_then(vm_cfg_entry)
+        .or_else(|| {
+            vm_cfg_entries()
+                .into_iter()
+                .find(|entry| entry.get_name() == *target)
+        })
+        .ok_or_else(|| format!("no VM config `{}`", target))?;
+    let handle = vm::spawn(entry.get_vm_id()).map_err(|err| format!("{:?}", err))?;
+    ax_println!("VM [{}] {} booting", handle.id(), entry.get_name());
+    Ok(())
+}
+
+fn boot_image(vm_type: &str, paddr: &str, memory_mb: Option<&str>) -> CmdResult {
+    let vm_type = match vm_type {
+        "nimbos" => VmType::VmTNimbOS,
+        "linux" => VmType::VmTLinux,
+        _ => {
+            return Err(format!(
+                "unknown VM type `{}`, expected nimbos or linux",
+                vm_type
+            ))
+        }
+    };
+    let paddr = parse_number(paddr)?;
+    let memory_mb = memory_mb.map_or(Ok(IMAGE_MEMORY_MB), parse_number)?;
+    if memory_mb == 0 || memory_mb.checked_mul(1 << 20).is_none() {
+        return Err(format!("invalid RAM size {} MiB", memory_mb));
+    }
+    let handle = vm::boot_image(vm_type, paddr, memory_mb).map_err(|err| format!("{:?}", err))?;
+    ax_println!("VM [{}] image at {:#x} booting", handle.id(), paddr);
+    Ok(())
+}
+
+fn do_vm_pause(args: &[&str]) -> CmdResult {
+    vm::pause_vm(parse_vm_id(args)?).map_err(|err| format!("{:?}", err))
+}
+
+fn do_vm_resume(args: &[&str]) -> CmdResult {
+    vm::resume_vm(parse_vm_id(args)?).map_err(|err| format!("{:?}", err))
+}
+
+fn do_vm_kill(args: &[&str]) -> CmdResult {
+    vm::kill_vm(parse_vm_id(args)?).map_err(|err| format!("{:?}", err))
+}
+
+fn do_vm_stats(args: &[&str]) -> CmdResult {
+    let vm_id = parse_vm_id(args)?;
+    let state = vm::vm_state(vm_id).ok_or_else(|| format!("VM [{}] never booted", vm_id))?;
+    ax_println!("VM [{}] {:?}", vm_id, state);
+    if let Some(exit) = vm::vm_exit(vm_id) {
+        ax_println!("  exit: {:?}", exit);
+    }
+    if let Some(info) = vm::find_vm(vm_id) {
+        ax_println!(
+            "  CPUs: {}{}",
+            format_cpus(&info.cpus, false),
+            if info.exclusive { " (dedicated)" } else { "" }
+        );
+    }
+    let mut vcpus: Vec<(u32, u32)> = VCPU_TO_PCPU
+        .lock()
+        .iter()
+        .filter(|((vm, _), _)| *vm == vm_id)
+        .map(|((_, vcpu), cpu)| (*vcpu, *cpu))
+        .collect();
+    vcpus.sort_unstable();
+    for (vcpu_id, cpu_id) in vcpus {
+        let park = crate::park_stats(cpu_id as usize);
+        let average = park
+            .total_wake_latency_ns
+            .checked_div(park.wakeups)
+            .unwrap_or(0);
+        ax_println!(
+            "  vCPU {} on CPU {}: {} wakeups (avg {} ns, max {} ns), {} timeouts (max jitter {} ns)",
+            vcpu_id,
+            cpu_id,
+            park.wakeups,
+            average,
+            park.max_wake_latency_ns,
+            park.timeouts,
+            park.max_timer_jitter_ns
+        );
+        let nmi = crate::nmi_stats(cpu_id as usize);
+        ax_println!(
+            "    CPU {}: parked {} ms, {} wakeups on another CPU, {} NMIs injected, {} unexpected",
+            cpu_id,
+            park.parked_ns / 1_000_000,
+            park.migrations,
+            nmi.injected,
+            nmi.unexpected
+        );
+    }
+    if crate::dump_exit_stats(vm_id) {
+        ax_println!("  exit statistics written to the log");
+    }
+    Ok(())
+}
+
+fn do_vm_nmi(args: &[&str]) -> CmdResult {
+    let (vm_id, vcpu_id) = match args {
+        [vm_id] => (parse_vm_id(&[*vm_id])?, 0),
+        [vm_id, vcpu_id] => {
+            let vcpu_id = vcpu_id
+                .parse()
+                .map_err(|_| format!("invalid vCPU id `{}`", vcpu_id))?;
+            (parse_vm_id(&[*vm_id])?, vcpu_id)
+        }
+        _ => return Err(String::from("expected a VM id and a vCPU id")),
+    };
+    vm::send_nmi(vm_id, vcpu_id).map_err(|err| format!("{:?}", err))
+}
+
+fn do_vm_console(args: &[&str]) -> CmdResult {
+    let vm_id = parse_vm_id(args)?;
+    // A paused VM would not read the input until resumed.
+    if !can_focus(vm_id) {
+        return Err(format!("VM [{}] is {:?}", vm_id, vm::vm_state(vm_id)));
+    }
+    ax_println!("console of VM [{}], type Ctrl-A to come back", vm_id);
+    set_console_focus(ConsoleFocus::Vm(vm_id));
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! Snapshots of guest VMs, restored later as new VMs, e.g. to go back to a guest shortly before a
+//! crash long to reproduce.
+//!
+//! A snapshot is taken with the VM paused, see [`crate::snapshot_vm`], and encoded as a device
+//! state (see [`crate::device::DeviceState`]) of version [`VM_SNAPSHOT_VERSION`]:
+//!
+//! | field         | encoding                                                             |
+//! |---------------|----------------------------------------------------------------------|
+//! | VM            | id, name of the configuration the VM was built from                  |
+//! | memory layout | number of regions, then GPA, size, flags and populated on demand     |
+//! | vCPUs         | number of them, then the id and the state of each                    |
+//! | RAM           | number of pages, then the GPA and the 4 KiB of each page             |
+//!
+//! The state of a vCPU holds its registers and the state of its devices. Only the RAM pages
+//! which are not zero are saved.
+//!
+//! [`crate::restore_vm`] builds the new VM from the configuration the VM was built from, which
+//! must still be there with the same memory layout, with RAM of its own. As for a fork, the virtio
+//! devices, their queues included, the paravirtual clocks and the shared regions are left out. A
+//! snapshot of another version, or which does not match the configuration, is rejected before
+//! anything is allocated.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use memory_addr::PAGE_SIZE_4K;
+use page_table_entry::MappingFlags;
+
+use crate::config::entry::{vm_cfg_add_vm_entry, vm_cfg_entry, VMCfgEntry};
+use crate::device::{self, StateReader, StateWriter, VcpuSnapshot};
+use crate::{phys_to_virt, Error, GuestPhysAddr, Result};
+
+/// Version of the snapshots written by [`crate::snapshot_vm`].
+pub const VM_SNAPSHOT_VERSION: u16 = 1;
+
+/// The snapshot of the paused guest `vm_id` of configuration `cfg`, whose vCPUs saved their state.
+pub(crate) fn encode(vm_id: u32, cfg: &VMCfgEntry) -> Vec<u8> {
+    let mut state = StateWriter::new(VM_SNAPSHOT_VERSION);
+    state.u32(vm_id).bytes(cfg.get_name().as_bytes());
+    state.u32(cfg.memory_regions().len() as u32);
+    for region in cfg.memory_regions() {
+        state
+            .u64(region.gpa as u64)
+            .u64(region.size as u64)
+            .u64(region.flags.bits() as u64)
+            .bool(region.populate_on_demand);
+    }
+    let vcpus = device::paused_snapshots(vm_id);
+    state.u32(vcpus.len() as u32);
+    for (vcpu_id, snapshot) in vcpus {
+        state.u32(vcpu_id).bytes(&snapshot.to_bytes());
+    }
+    let pages: Vec<_> = ram_pages(cfg)
+        .filter(|(_, page)| page.iter().any(|&byte| byte != 0))
+        .collect();
+    state.u32(pages.len() as u32);
+    for (gpa, page) in pages {
+        state.u64(gpa as u64).bytes(page);
+    }
+    state.finish()
+}
+
+/// The RAM pages of `cfg` allocated, by increasing GPA.
+fn ram_pages(cfg: &VMCfgEntry) -> impl Iterator<Item = (GuestPhysAddr, &[u8])> {
+    cfg.memory_regions()
+        .iter()
+        .filter(|region| !region.flags.contains(MappingFlags::DEVICE))
+        .flat_map(|region| (region.gpa..region.gpa + region.size).step_by(PAGE_SIZE_4K))
+        .filter_map(|gpa| {
+            let (hpa, _) = cfg.present_ram_page(gpa)?;
+            // The VM is paused, and its pages stay allocated as long as `cfg`.
+            let page = unsafe {
+                core::slice::from_raw_parts(phys_to_virt(hpa.into()).as_ptr(), PAGE_SIZE_4K)
+            };
+            Some((gpa, page))
+        })
+}
+
+/// Add a configuration for the VM of `snapshot` and return its id, see the
+/// [module documentation](self). Fails with `NotSupported` for a snapshot of another version,
+/// with `InvalidParam` if it is malformed or does not match the configuration it was taken from,
+/// with `NoMemory` if there is no RAM left for the VM.
+pub(crate) fn restore(snapshot: &[u8]) -> Result<u32> {
+    let mut state = StateReader::new(snapshot, VM_SNAPSHOT_VERSION)?;
+    let src_id = state.u32()?;
+    let name = core::str::from_utf8(state.bytes()?).map_err(|_| Error::InvalidParam)?;
+    let Some(cfg) = vm_cfg_entry(src_id as usize).filter(|cfg| cfg.get_name() == name) else {
+        warn!(
+            "no configuration of VM [{}] {} to restore its snapshot",
+            src_id, name
+        );
+        return Err(Error::InvalidParam);
+    };
+    if state.u32()? as usize != cfg.memory_regions().len() {
+        warn!("snapshot of VM [{}] for another memory layout", src_id);
+        return Err(Error::InvalidParam);
+    }
+    for region in cfg.memory_regions() {
+        let (gpa, size) = (state.u64()?, state.u64()?);
+        let (flags, on_demand) = (state.u64()?, state.bool()?);
+        if gpa != region.gpa as u64
+            || size != region.size as u64
+            || flags != region.flags.bits() as u64
+            || on_demand != region.populate_on_demand
+        {
+            warn!(
+                "snapshot of VM [{}] for another memory region than\n\t{}",
+                src_id, region
+            );
+            return Err(Error::InvalidParam);
+        }
+    }
+    let mut vcpus = Vec::new();
+    for _ in 0..state.u32()? {
+        let vcpu_id = state.u32()?;
+        if vcpu_id as usize >= cfg.get_vcpus() {
+            warn!("snapshot of VM [{}] for vCPU {}", src_id, vcpu_id);
+            return Err(Error::InvalidParam);
+        }
+        let snapshot = VcpuSnapshot::from_bytes(state.bytes()?)?;
+        vcpus.push((vcpu_id, Arc::new(snapshot)));
+    }
+    let mut pages = Vec::new();
+    for _ in 0..state.u32()? {
+        let gpa = state.u64()? as GuestPhysAddr;
+        let page = state.bytes()?;
+        if gpa % PAGE_SIZE_4K != 0 || page.len() != PAGE_SIZE_4K || cfg.ram_region(gpa).is_none() {
+            warn!("snapshot of VM [{}] with a page at {:#x}", src_id, gpa);
+            return Err(Error::InvalidParam);
+        }
+        pages.push((gpa, page));
+    }
+    state.finish()?;
+
+    let restored = cfg.blank_copy(alloc::format!("{}-restored", name))?;
+    for (gpa, page) in pages {
+        let hpa = restored.guest_ram_page_hpa(gpa).ok_or(Error::NoMemory)?;
+        let dst = phys_to_virt(hpa.into()).as_mut_ptr();
+        unsafe { core::ptr::copy_nonoverlapping(page.as_ptr(), dst, PAGE_SIZE_4K) };
+    }
+    let vm_id = vm_cfg_add_vm_entry(restored)? as u32;
+    device::set_pending_snapshots(vm_id, vcpus);
+    info!("VM [{}] restored from a snapshot of VM [{}]", vm_id, src_id);
+    Ok(vm_id)
+}
@@ -1,4 +1,4 @@
-use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 
 use hypercraft::{VCpu, VmCpus, VM};
 
@@ -6,11 +6,17 @@ use super::arch::new_vcpu;
 #[cfg(target_arch = "x86_64")]
 use super::device::{self, NimbosVmDevices, X64VcpuDevices, X64VmDevices};
 use crate::GuestPageTable;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use axconfig::SMP;
 use axhal::{current_cpu_id, hv::HyperCraftHalImpl};
+use axtask::WaitQueue;
 
-use crate::config::entry::vm_cfg_entry;
+use crate::config::entry::{vm_cfg_entry, ResetPolicy, VMCfgEntry, VmType};
 use crate::device::BarAllocImpl;
+use crate::{Error, GuestPhysAddr, HostPhysAddr, Result};
 
 use hashbrown::HashMap;
 use spin::Mutex;
@@ -21,10 +27,722 @@ lazy_static! {
         Mutex::new(HashMap::new());
 }
 
-static VM_CNT: AtomicU32 = AtomicU32::new(0);
+/// VM id of the host Linux in type 1.5 mode, guest VMs take their ids from the VM config table.
+pub(crate) const HOST_VM_ID: u32 = 0;
+
+/// Lifecycle state of a VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmState {
+    Creating,
+    Running,
+    Paused,
+    /// Stopped by a fatal VM exit, see the log for the report.
+    Crashed,
+    Stopped,
+}
+
+lazy_static! {
+    static ref VM_STATES: Mutex<HashMap<u32, VmState>> = Mutex::new(HashMap::new());
+}
+
+pub fn vm_state(vm_id: u32) -> Option<VmState> {
+    VM_STATES.lock().get(&vm_id).cloned()
+}
+
+/// Woken whenever a VM stops or crashes, see [`wait_vm_stopped`].
+static VM_STOPPED: WaitQueue = WaitQueue::new();
+
+pub fn set_vm_state(vm_id: u32, state: VmState) {
+    let mut lock = VM_STATES.lock();
+    debug!("VM [{}] state {:?} -> {:?}", vm_id, lock.get(&vm_id), state);
+    lock.insert(vm_id, state);
+    drop(lock);
+    if matches!(state, VmState::Stopped | VmState::Crashed) {
+        VM_STOPPED.notify_all(false);
+    }
+}
+
+/// Move the VM to `new` only if it is currently in `current` (`None` for a VM which has never
+/// been seen), otherwise return the state it is actually in.
+pub fn compare_exchange_vm_state(
+    vm_id: u32,
+    current: Option<VmState>,
+    new: VmState,
+) -> core::result::Result<(), Option<VmState>> {
+    let mut lock = VM_STATES.lock();
+    let state = lock.get(&vm_id).cloned();
+    if state != current {
+        return Err(state);
+    }
+    debug!("VM [{}] state {:?} -> {:?}", vm_id, state, new);
+    lock.insert(vm_id, new);
+    drop(lock);
+    if matches!(new, VmState::Stopped | VmState::Crashed) {
+        VM_STOPPED.notify_all(false);
+    }
+    Ok(())
+}
+
+/// Block until VM `vm_id` is stopped or crashed, and return which.
+pub(crate) fn wait_vm_stopped(vm_id: u32) -> VmState {
+    // Seen by the condition, the VM may boot again right after.
+    let last = core::cell::Cell::new(VmState::Stopped);
+    VM_STOPPED.wait_until(|| match vm_state(vm_id) {
+        Some(state @ (VmState::Stopped | VmState::Crashed)) => {
+            last.set(state);
+            true
+        }
+        _ => false,
+    });
+    last.get()
+}
+
+/// A VM booted by [`boot_vm`] and not stopped yet, see [`find_vm`].
+///
+/// The id is the one the VM config table allocated for the VM, which every other module (NMI
+/// messages, hypercalls, the shell) uses to name it.
+#[derive(Debug, Clone)]
+pub struct VmInfo {
+    pub vm_id: u32,
+    pub name: String,
+    pub vm_type: VmType,
+    pub vcpus: usize,
+    pub entry: GuestPhysAddr,
+    /// Physical CPU which booted the VM and runs its vCPU 0.
+    pub boot_cpu: usize,
+    /// Physical CPU of each vCPU, by vCPU id, see [`VcpuAffinity`](crate::VcpuAffinity).
+    pub cpus: Vec<usize>,
+    /// The CPUs are dedicated to the VM.
+    pub exclusive: bool,
+    pub state: VmState,
+}
+
+lazy_static! {
+    /// The VMs booted and not stopped yet, by id.
+    static ref VM_LIST: Mutex<BTreeMap<u32, VmInfo>> = Mutex::new(BTreeMap::new());
+}
+
+fn register_vm(cfg: &VMCfgEntry, cpus: &[usize]) {
+    let info = VmInfo {
+        vm_id: cfg.get_vm_id() as u32,
+        name: String::from(cfg.get_name()),
+        vm_type: cfg.get_vm_type(),
+        vcpus: cpus.len(),
+        entry: cfg.get_vm_entry(),
+        boot_cpu: current_cpu_id(),
+        cpus: cpus.to_vec(),
+        exclusive: cfg.vcpu_affinity().exclusive,
+        state: VmState::Creating,
+    };
+    info!(
+        "VM [{}] {} registered: {} vCPU(s), entry {:#x}, CPUs {:?}{}",
+        info.vm_id,
+        info.name,
+        info.vcpus,
+        info.entry,
+        info.cpus,
+        if info.exclusive { " (exclusive)" } else { "" }
+    );
+    if info.vm_id != HOST_VM_ID {
+        GUEST_VMS.fetch_add(1, Ordering::Relaxed);
+    }
+    VM_LIST.lock().insert(info.vm_id, info);
+}
+
+fn unregister_vm(vm_id: u32) {
+    if VM_LIST.lock().remove(&vm_id).is_some() && vm_id != HOST_VM_ID {
+        GUEST_VMS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Guest VMs registered, i.e. not counting the host.
+static GUEST_VMS: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of guest VMs booted and not stopped yet.
+pub(crate) fn guest_vm_count() -> usize {
+    GUEST_VMS.load(Ordering::Relaxed)
+}
+
+/// The VM `vm_id`, if it was booted and has not stopped.
+pub fn find_vm(vm_id: u32) -> Option<VmInfo> {
+    let mut info = VM_LIST.lock().get(&vm_id)?.clone();
+    info.state = vm_state(vm_id)?;
+    Some(info)
+}
+
+/// The VMs booted and not stopped yet, by increasing id.
+pub fn running_vms() -> Vec<VmInfo> {
+    let ids: Vec<u32> = VM_LIST.lock().keys().copied().collect();
+    ids.into_iter().filter_map(find_vm).collect()
+}
+
+/// How a VM stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExit {
+    /// Shut down by [`shutdown_vm`], e.g. by the guest with `HVC_VM_SHUTDOWN` or its debug exit
+    /// port, with this exit code.
+    Shutdown(u64),
+    /// Stopped by [`VmJoinHandle::kill`].
+    Killed,
+    /// Stopped by a fatal VM exit, see the log for the report.
+    Crashed,
+    /// The vCPU left its run loop on its own.
+    Stopped,
+    /// Reset by its guest, or by [`reset_vm`], and destroyed; or failed to reboot, see the log.
+    Reset,
+}
+
+lazy_static! {
+    /// Why each VM stops, set when the stop is requested and kept once the VM has stopped.
+    static ref VM_EXITS: Mutex<HashMap<u32, VmExit>> = Mutex::new(HashMap::new());
+}
+/// Number of stops requested and not yet done, lets `check_events` skip the lookup.
+static PENDING_STOPS: AtomicUsize = AtomicUsize::new(0);
+
+/// How VM `vm_id` stopped, `None` while it has not.
+pub fn vm_exit(vm_id: u32) -> Option<VmExit> {
+    match vm_state(vm_id) {
+        Some(VmState::Stopped | VmState::Crashed) => VM_EXITS.lock().get(&vm_id).cloned(),
+        _ => None,
+    }
+}
+
+/// Have the vCPUs of `vm_id` leave their run loops before their next VM entry, the VM stopping
+/// with `exit`. Only the first request counts, returns whether this one did.
+pub(crate) fn request_stop(vm_id: u32, exit: VmExit) -> bool {
+    {
+        let mut exits = VM_EXITS.lock();
+        if exits.contains_key(&vm_id) {
+            return false;
+        }
+        exits.insert(vm_id, exit);
+    }
+    PENDING_STOPS.fetch_add(1, Ordering::Release);
+    info!("VM [{}] stop requested: {:?}", vm_id, exit);
+    // A paused vCPU leaves its run loop once the VM is no longer paused.
+    let _ = compare_exchange_vm_state(vm_id, Some(VmState::Paused), VmState::Stopped);
+    wake_vcpus(vm_id);
+    true
+}
+
+/// Wake the parked vCPUs of `vm_id`.
+pub(crate) fn wake_vcpus(vm_id: u32) {
+    let vcpus: alloc::vec::Vec<u32> = VCPU_TO_PCPU
+        .lock()
+        .keys()
+        .filter(|(vm, _)| *vm == vm_id)
+        .map(|(_, vcpu)| *vcpu)
+        .collect();
+    for vcpu_id in vcpus {
+        crate::park::wake_vcpu(vm_id, vcpu_id);
+    }
+}
+
+/// Whether the VM running on the current CPU has to stop.
+pub(crate) fn stop_requested() -> bool {
+    if PENDING_STOPS.load(Ordering::Acquire) == 0 {
+        return false;
+    }
+    current_vm_id().map_or(false, |vm_id| VM_EXITS.lock().contains_key(&vm_id))
+}
+
+/// The vCPUs of a VM paused by [`pause_vm`] which stopped so far.
+#[derive(Default)]
+struct PauseClock {
+    vcpus_stopped: usize,
+    /// When the last one stopped.
+    last_stop_ns: u64,
+}
+
+lazy_static! {
+    /// The VMs paused by [`pause_vm`].
+    static ref PAUSED_VMS: Mutex<HashMap<u32, PauseClock>> = Mutex::new(HashMap::new());
+    /// Time each VM spent paused, hidden from the guest, see [`frozen_ns`].
+    static ref FROZEN_NS: Mutex<HashMap<u32, u64>> = Mutex::new(HashMap::new());
+}
+/// Size of [`PAUSED_VMS`], lets `check_events` skip the lookup.
+static PAUSED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Pause a running VM: its vCPUs finish the exits being handled and park before their next VM
+/// entry, until [`resume_vm`]. The events queued to them are injected once they resume.
+pub fn pause_vm(vm_id: u32) -> Result {
+    let mut paused = PAUSED_VMS.lock();
+    if let Err(state) = compare_exchange_vm_state(vm_id, Some(VmState::Running), VmState::Paused) {
+        warn!("VM [{}] is {:?}, cannot pause it", vm_id, state);
+        return Err(Error::BadState);
+    }
+    paused.insert(vm_id, PauseClock::default());
+    PAUSED_COUNT.store(paused.len(), Ordering::Release);
+    Ok(())
+}
+
+/// Resume a VM paused by [`pause_vm`] or by the exit watchdog.
+pub fn resume_vm(vm_id: u32) -> Result {
+    let mut paused = PAUSED_VMS.lock();
+    let state = vm_state(vm_id);
+    if state != Some(VmState::Paused) {
+        warn!("VM [{}] is {:?}, cannot resume it", vm_id, state);
+        return Err(Error::BadState);
+    }
+    // Added before the VM is running again: a vCPU seeing it so applies the whole pause.
+    if let Some(clock) = paused.get(&vm_id) {
+        // The guest time stood still from the moment the last vCPU stopped. If some vCPU did not
+        // stop, it may have run the guest until now and nothing can be hidden.
+        let vcpus = VCPU_TO_PCPU
+            .lock()
+            .keys()
+            .filter(|(vm, _)| *vm == vm_id)
+            .count();
+        if clock.vcpus_stopped != 0 && clock.vcpus_stopped >= vcpus {
+            let frozen = axhal::time::current_time_nanos() - clock.last_stop_ns;
+            debug!(
+                "VM [{}] resumed, {} ns hidden from the guest",
+                vm_id, frozen
+            );
+            *FROZEN_NS.lock().entry(vm_id).or_insert(0) += frozen;
+        }
+    }
+    // Published by the release of the state lock. The VM may only have stopped meanwhile, which
+    // discards its pause clock.
+    if let Err(state) = compare_exchange_vm_state(vm_id, Some(VmState::Paused), VmState::Running) {
+        warn!("VM [{}] is {:?}, cannot resume it", vm_id, state);
+        return Err(Error::BadState);
+    }
+    paused.remove(&vm_id);
+    PAUSED_COUNT.store(paused.len(), Ordering::Release);
+    drop(paused);
+    device::discard_paused_snapshots(vm_id);
+    wake_vcpus(vm_id);
+    crate::completion::release_vm(vm_id);
+    Ok(())
+}
+
+/// Total time VM `vm_id` spent paused with all its vCPUs stopped.
+///
+/// The guest does not see it: once its vCPUs are resumed, they move the TSC and the deadlines of
+/// the emulated timers forward by the part they have not applied yet, so that the guest clocks
+/// continue from where they stopped, without a jump or a burst of expired timers.
+pub fn frozen_ns(vm_id: u32) -> u64 {
+    FROZEN_NS.lock().get(&vm_id).cloned().unwrap_or(0)
+}
+
+/// Attach `device` to the per-VM devices of the running or paused VM `vm_id`, its vCPUs reaching
+/// it from their next exit to its range. Fails with `InvalidParam` if the range is empty or
+/// overlaps another device, a BAR or, for a MMIO device, a memory region of the VM.
+#[cfg(target_arch = "x86_64")]
+pub fn attach_device(vm_id: u32, device: device::HotplugDevice) -> Result {
+    match vm_state(vm_id) {
+        Some(VmState::Running | VmState::Paused) => device::attach_device(vm_id, device),
+        state => {
+            warn!("VM [{}] is {:?}, cannot attach a device", vm_id, state);
+            Err(Error::BadState)
+        }
+    }
+}
+
+/// Detach and return the per-VM device of VM `vm_id` claiming `address`, refused while one of
+/// its handlers is executing.
+#[cfg(target_arch = "x86_64")]
+pub fn detach_device(vm_id: u32, address: device::HotplugAddress) -> Result<device::HotplugDevice> {
+    match vm_state(vm_id) {
+        Some(VmState::Running | VmState::Paused) => device::detach_device(vm_id, address),
+        state => {
+            warn!("VM [{}] is {:?}, cannot detach a device", vm_id, state);
+            Err(Error::BadState)
+        }
+    }
+}
+
+/// Allocate `size` bytes of memory, a power of two of at least a page, shared by the guest VMs
+/// `vm_a` and `vm_b`, and return the id of the channel. Each VM reaches the memory through an
+/// ivshmem PCI device, see [`IvshmemDevice`](crate::IvshmemDevice), its doorbell raising an MSI
+/// of the device of the other VM.
+///
+/// Both VMs must be configured and not started yet: the devices are created when they boot.
+/// Fails with `NotSupported` for the host, and with `BadState` if a VM already started.
+#[cfg(all(target_arch = "x86_64", feature = "virtio-pci"))]
+pub fn create_shmem_channel(vm_a: u32, vm_b: u32, size: usize) -> Result<u32> {
+    if vm_a == vm_b {
+        return Err(Error::InvalidParam);
+    }
+    for vm_id in [vm_a, vm_b] {
+        if vm_id == HOST_VM_ID {
+            return Err(Error::NotSupported);
+        }
+        if vm_cfg_entry(vm_id as usize).is_none() {
+            warn!("VM [{}] is not configured, no shmem channel", vm_id);
+            return Err(Error::InvalidParam);
+        }
+        if let Some(state) = vm_state(vm_id) {
+            warn!("VM [{}] is {:?}, no shmem channel", vm_id, state);
+            return Err(Error::BadState);
+        }
+    }
+    device::create_shmem_channel(vm_a, vm_b, size)
+}
+
+/// How long [`fork_vm`] and [`snapshot_vm`] wait for the vCPUs of the VM to stop.
+const STOP_TIMEOUT_NS: u64 = 1_000_000_000;
+
+/// Fork the guest VM `src_id`: add a configuration for a copy of it, whose RAM is shared with
+/// `src_id` copy-on-write and whose vCPUs continue from where those of `src_id` are, and return
+/// its id. The fork is booted as any configured VM, e.g. with [`spawn`], on cores of the cpu set
+/// of `src_id` it does not run on.
+///
+/// `src_id` is paused meanwhile if it runs. The fork gets the state of the per-vCPU devices of
+/// `src_id`, e.g. the UARTs, the PICs and the local APICs, but none of its virtio devices, nor its
+/// paravirtual clocks, its shared regions or the MSRs the VMCS does not hold; the pages `src_id`
+/// shares with the hypervisor, e.g. its paravirtual clocks, keep being written by it. Fails with
+/// `NotSupported` for the host, and with `BadState` if `src_id` is neither running nor paused, or
+/// if its vCPUs do not stop within a second.
+pub fn fork_vm(src_id: u32) -> Result<u32> {
+    with_vcpus_stopped(src_id, "fork", |cfg| {
+        let fork = cfg.fork(alloc::format!("{}-fork", cfg.get_name()));
+        crate::mm::share_guest_memory(src_id, cfg)?;
+        let fork_id = crate::config::entry::vm_cfg_add_vm_entry(fork)? as u32;
+        device::set_pending_snapshots(fork_id, device::paused_snapshots(src_id));
+        info!("VM [{}] forked as VM [{}]", src_id, fork_id);
+        Ok(fork_id)
+    })
+}
+
+/// The snapshot of the guest VM `vm_id`, for [`restore_vm`]: its RAM, its vCPUs and the state of
+/// their devices, but not its virtio devices, in a format of version
+/// [`VM_SNAPSHOT_VERSION`](crate::VM_SNAPSHOT_VERSION).
+///
+/// `vm_id` is paused meanwhile if it runs. Fails as [`fork_vm`] does.
+pub fn snapshot_vm(vm_id: u32) -> Result<Vec<u8>> {
+    with_vcpus_stopped(vm_id, "snapshot", |cfg| {
+        Ok(crate::snapshot::encode(vm_id, cfg))
+    })
+}
+
+/// Add a configuration for a VM restored from `snapshot`, taken by [`snapshot_vm`], and return
+/// its id. The VM is booted as any configured VM, e.g. with [`spawn`], its vCPUs continuing from
+/// where they were.
+///
+/// The configuration the snapshot was taken from must still be there. Fails with
+/// `NotSupported` for a snapshot of another version, with `InvalidParam` if it does not match the
+/// configuration, with `NoMemory` if there is no RAM left for the VM.
+pub fn restore_vm(snapshot: &[u8]) -> Result<u32> {
+    crate::snapshot::restore(snapshot)
+}
+
+/// Call `f` with the configuration of the guest `vm_id` once its vCPUs are stopped, `vm_id`
+/// being paused meanwhile if it runs, and resumed afterwards. For [`fork_vm`] and
+/// [`snapshot_vm`], which `what` names.
+fn with_vcpus_stopped<T>(
+    vm_id: u32,
+    what: &str,
+    f: impl FnOnce(&VMCfgEntry) -> Result<T>,
+) -> Result<T> {
+    if vm_id == HOST_VM_ID {
+        warn!("VM [{}] is the host, cannot {} it", vm_id, what);
+        return Err(Error::NotSupported);
+    }
+    let cfg = vm_cfg_entry(vm_id as usize).ok_or(Error::InvalidParam)?;
+    let paused_here = match vm_state(vm_id) {
+        Some(VmState::Running) => {
+            pause_vm(vm_id)?;
+            true
+        }
+        Some(VmState::Paused) => false,
+        state => {
+            warn!("VM [{}] is {:?}, cannot {} it", vm_id, state, what);
+            return Err(Error::BadState);
+        }
+    };
+    let deadline_ns = axhal::time::current_time_nanos() + STOP_TIMEOUT_NS;
+    let mut result = Ok(());
+    while !(0..cfg.get_vcpus() as u32).all(|vcpu_id| device::vcpu_state_saved(vm_id, vcpu_id))
+    {
+        if axhal::time::current_time_nanos() > deadline_ns {
+            warn!("VM [{}] vCPUs did not stop, cannot {} it", vm_id, what);
+            result = Err(Error::BadState);
+            break;
+        }
+        axtask::yield_now();
+    }
+    let result = result.and_then(|_| f(&cfg));
+    if paused_here {
+        if let Err(err) = resume_vm(vm_id) {
+            warn!("VM [{}] not resumed after its {}: {:?}", vm_id, what, err);
+        }
+    }
+    result
+}
+
+/// Stop a VM before the next VM entry of its vCPUs, without waiting for it.
+pub fn kill_vm(vm_id: u32) -> Result {
+    match vm_state(vm_id) {
+        Some(VmState::Creating | VmState::Running | VmState::Paused) => {
+            // A VM rebooting is killed once its vCPUs left their run loops.
+            REBOOTS.lock().remove(&vm_id);
+            request_stop(vm_id, VmExit::Killed);
+            Ok(())
+        }
+        state => {
+            warn!("VM [{}] is {:?}, cannot kill it", vm_id, state);
+            Err(Error::BadState)
+        }
+    }
+}
+
+/// Inject an NMI into vCPU `vcpu_id` of VM `vm_id`, e.g. to have a hung guest report where it is
+/// through its NMI watchdog, without waiting for it. The guest takes it once it does not block
+/// NMIs; NMIs sent before it takes one are merged, as on hardware.
+#[cfg(target_arch = "x86_64")]
+pub fn send_nmi(vm_id: u32, vcpu_id: u32) -> Result {
+    let Some(info) = find_vm(vm_id) else {
+        warn!("VM [{}] not running, cannot send it an NMI", vm_id);
+        return Err(Error::BadState);
+    };
+    if vcpu_id as usize >= info.vcpus {
+        warn!(
+            "VM [{}] has no vCPU {}, cannot send it an NMI",
+            vm_id, vcpu_id
+        );
+        return Err(Error::InvalidParam);
+    }
+    match info.state {
+        VmState::Creating | VmState::Running | VmState::Paused => {
+            crate::nmi::send_to_vcpu(vm_id, vcpu_id, crate::nmi::NmiRequest::InjectNmi)
+        }
+        state => {
+            warn!("VM [{}] is {:?}, cannot send it an NMI", vm_id, state);
+            Err(Error::BadState)
+        }
+    }
+}
+
+/// Shut VM `vm_id` down with exit `code`, without waiting for it: its vCPUs leave their run loops
+/// before their next VM entry, then [`boot_vm`] tears the VM down, unmapping its memory, and
+/// returns. The host VM cannot be shut down.
+pub fn shutdown_vm(vm_id: u32, code: u64) -> Result {
+    if vm_id == HOST_VM_ID {
+        warn!("VM [{}] is the host, cannot shut it down", vm_id);
+        return Err(Error::NotSupported);
+    }
+    match vm_state(vm_id) {
+        Some(VmState::Creating | VmState::Running | VmState::Paused) => {
+            request_stop(vm_id, VmExit::Shutdown(code));
+            Ok(())
+        }
+        state => {
+            warn!("VM [{}] is {:?}, cannot shut it down", vm_id, state);
+            Err(Error::BadState)
+        }
+    }
+}
+
+lazy_static! {
+    /// The VMs to boot again in place once their vCPUs left their run loops, see [`reset_vm`].
+    static ref REBOOTS: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+}
+
+/// Reset VM `vm_id` as its guest would, without waiting for it: its vCPUs leave their run loops
+/// before their next VM entry, then [`boot_vm`] tears the VM down. With [`ResetPolicy::Reboot`],
+/// it then loads the images of the VM again in its zeroed RAM and boots it with fresh vCPUs and
+/// devices, in place and with the same id; with [`ResetPolicy::Destroy`], it returns, the VM
+/// stopped with [`VmExit::Reset`]. A VM not built by [`VmBuilder`](crate::VmBuilder), or forked,
+/// cannot reboot and is destroyed. The host VM cannot be reset.
+pub fn reset_vm(vm_id: u32, policy: ResetPolicy) -> Result {
+    if vm_id == HOST_VM_ID {
+        warn!("VM [{}] is the host, cannot reset it", vm_id);
+        return Err(Error::NotSupported);
+    }
+    match vm_state(vm_id) {
+        Some(VmState::Creating | VmState::Running | VmState::Paused) => {}
+        state => {
+            warn!("VM [{}] is {:?}, cannot reset it", vm_id, state);
+            return Err(Error::BadState);
+        }
+    }
+    let mut reboot = policy == ResetPolicy::Reboot;
+    if reboot {
+        let cfg = vm_cfg_entry(vm_id as usize).ok_or(Error::InvalidParam)?;
+        if cfg.boot_segments().is_empty() || cfg.copy_on_write() {
+            warn!(
+                "VM [{}] has no images of its own to reboot from, destroying it",
+                vm_id
+            );
+            reboot = false;
+        }
+    }
+    let inserted = reboot && REBOOTS.lock().insert(vm_id);
+    // Another stop requested first wins.
+    if !request_stop(vm_id, VmExit::Reset) && inserted {
+        REBOOTS.lock().remove(&vm_id);
+    }
+    Ok(())
+}
+
+/// Reset the VM running on the current CPU, at the request of its guest through `source`, with
+/// the [`ResetPolicy`] of its config.
+pub(crate) fn guest_reset(source: &str) -> Result {
+    let vm_id = current_vm_id().ok_or(Error::BadState)?;
+    let cfg = vm_cfg_entry(vm_id as usize).ok_or(Error::InvalidParam)?;
+    info!(
+        "VM [{}] reset by its guest through {}, {:?}",
+        vm_id,
+        source,
+        cfg.reset_policy()
+    );
+    reset_vm(vm_id, cfg.reset_policy())
+}
+
+/// Block the vCPU on the current CPU while its VM is paused by [`pause_vm`], `on_stop` being
+/// called with the id of the VM before it parks. Returns the [`frozen_ns`] of the VM once it is
+/// resumed, `None` if it was not paused.
+pub(crate) fn wait_while_paused(on_stop: impl FnOnce(u32)) -> Option<u64> {
+    if PAUSED_COUNT.load(Ordering::Acquire) == 0 {
+        return None;
+    }
+    let vm_id = current_vm_id()?;
+    {
+        let mut paused = PAUSED_VMS.lock();
+        let clock = paused.get_mut(&vm_id)?;
+        clock.vcpus_stopped += 1;
+        clock.last_stop_ns = axhal::time::current_time_nanos();
+    }
+    on_stop(vm_id);
+    park_while_paused(vm_id);
+    Some(frozen_ns(vm_id))
+}
+
+/// Park the vCPU on the current CPU while `vm_id` is paused, its console output drained first.
+pub(crate) fn park_while_paused(vm_id: u32) {
+    crate::console_ring::drain(vm_id);
+    crate::park::park_until(|| {
+        // The memory set of the VM may change meanwhile, e.g. for a fork.
+        crate::mm::handle_pending_invalidation();
+        vm_state(vm_id) != Some(VmState::Paused)
+    });
+}
+
+lazy_static! {
+    /// The vCPUs of each VM in their run loop, by VM id, see [`vcpu_left`].
+    static ref RUNNING_VCPUS: Mutex<HashMap<u32, usize>> = Mutex::new(HashMap::new());
+}
+
+/// Count a vCPU of `vm_id` about to enter its run loop.
+fn vcpu_entering(vm_id: u32) {
+    *RUNNING_VCPUS.lock().entry(vm_id).or_insert(0) += 1;
+}
+
+/// Uncount a vCPU of `vm_id` out of its run loop. Returns whether it was the last one, which
+/// alone calls [`vm_exited`]: the others still use the state of the VM until they leave. The
+/// vCPUs of the host VM are counted so, vCPU 0 of a guest waits for its APs instead, see
+/// [`run_vm`].
+fn vcpu_left(vm_id: u32) -> bool {
+    let mut running = RUNNING_VCPUS.lock();
+    match running.get_mut(&vm_id) {
+        Some(count) if *count > 1 => {
+            *count -= 1;
+            false
+        }
+        _ => {
+            running.remove(&vm_id);
+            true
+        }
+    }
+}
+
+/// Update the state of a VM whose last vCPU returned from its run loop, see [`vcpu_left`].
+/// Returns whether the VM is to boot again, see [`reset_vm`], its state being `Creating` then.
+fn vm_exited(vm_id: u32) -> bool {
+    let crashed = vm_state(vm_id) == Some(VmState::Crashed);
+    let reboot = REBOOTS.lock().remove(&vm_id) && !crashed;
+    {
+        let mut exits = VM_EXITS.lock();
+        match exits.get(&vm_id) {
+            Some(_) => {
+                PENDING_STOPS.fetch_sub(1, Ordering::Release);
+                if reboot {
+                    exits.remove(&vm_id);
+                }
+            }
+            None => {
+                let exit = if crashed {
+                    VmExit::Crashed
+                } else {
+                    VmExit::Stopped
+                };
+                exits.insert(vm_id, exit);
+            }
+        }
+    }
+    if reboot {
+        set_vm_state(vm_id, VmState::Creating);
+    } else if !crashed {
+        set_vm_state(vm_id, VmState::Stopped);
+    }
+    {
+        let mut paused = PAUSED_VMS.lock();
+        paused.remove(&vm_id);
+        PAUSED_COUNT.store(paused.len(), Ordering::Release);
+    }
+    FROZEN_NS.lock().remove(&vm_id);
+    unregister_vm(vm_id);
+    release_cpus(vm_id);
+    crate::console_ring::unregister(vm_id);
+    crate::console_mux::vm_exited(vm_id);
+    crate::hvc_console::unregister(vm_id);
+    crate::hvc_log::unregister(vm_id);
+    crate::shared_mem::unshare_all(vm_id);
+    crate::mm::unregister_guest_memory(vm_id);
+    crate::device::unregister_vm_ranges(vm_id);
+    crate::device::unregister_vm_devices(vm_id);
+    crate::device::unregister_vm_pvclocks(vm_id);
+    crate::device::unregister_vm_aps(vm_id);
+    crate::device::unregister_vm_snapshots(vm_id);
+    crate::device::unregister_vm_virtual_apics(vm_id);
+    #[cfg(feature = "legacy-pc-devices")]
+    crate::device::unregister_vm_irqs(vm_id);
+    crate::device::remove_vm_exit_observers(vm_id);
+    crate::device::remove_vm_exit_stats(vm_id);
+    crate::device::remove_vm_exit_logs(vm_id);
+    crate::nmi::purge_vm_messages(vm_id);
+    // Before cancelling the completions, a worker may still submit one for the VM.
+    #[cfg(feature = "virtio-pci")]
+    crate::device::close_virtio_queues(vm_id);
+    #[cfg(feature = "virtio-pci")]
+    crate::device::close_virtio_consoles(vm_id);
+    #[cfg(feature = "virtio-pci")]
+    crate::device::close_virtio_nets(vm_id);
+    #[cfg(feature = "virtio-pci")]
+    crate::device::close_shmem_channels(vm_id, reboot);
+    crate::completion::cancel_vm(vm_id);
+    set_current_vm(None);
+    reboot
+}
+
+const NO_VM: u32 = u32::MAX;
+const CPU_NO_VM: AtomicU32 = AtomicU32::new(NO_VM);
+/// Id of the VM running on each physical CPU, indexed by cpu id.
+static CPU_CURRENT_VM: [AtomicU32; SMP] = [CPU_NO_VM; SMP];
+
+/// Id of the VM whose vCPU runs on the current physical CPU.
+pub fn current_vm_id() -> Option<u32> {
+    match CPU_CURRENT_VM[current_cpu_id()].load(Ordering::Acquire) {
+        NO_VM => None,
+        vm_id => Some(vm_id),
+    }
+}
+
+/// Id of the VM whose vCPU runs on physical CPU `cpu_id`.
+pub(crate) fn cpu_running_vm(cpu_id: usize) -> Option<u32> {
+    match CPU_CURRENT_VM[cpu_id].load(Ordering::Acquire) {
+        NO_VM => None,
+        vm_id => Some(vm_id),
+    }
+}
+
+fn set_current_vm(vm_id: Option<u32>) {
+    CPU_CURRENT_VM[current_cpu_id()].store(vm_id.unwrap_or(NO_VM), Ordering::Release);
+}
 
 // use super::type1_5::cell;
-static INIT_GPM_OK: AtomicU32 = AtomicU32::new(0);
 static INITED_CPUS: AtomicUsize = AtomicUsize::new(0);
 
 pub fn vcpu2pcpu(vm_id: u32, vcpu_id: u32) -> Option<u32> {
@@ -46,20 +764,19 @@ pub fn config_boot_linux() {
 
     if hart_id == 0 {
         super::config::init_root_gpm().expect("init_root_gpm failed");
-        INIT_GPM_OK.store(1, Ordering::Release);
-    } else {
-        while INIT_GPM_OK.load(Ordering::Acquire) < 1 {
-            core::hint::spin_loop();
-        }
     }
+    let root_gpm = super::config::wait_root_gpm();
 
-    let ept = super::config::root_gpm().nest_page_table();
-    let ept_root = super::config::root_gpm().nest_page_table_root();
+    let ept = root_gpm.nest_page_table();
+    let ept_root = root_gpm.nest_page_table_root();
 
-    let vm_id = VM_CNT.load(Ordering::SeqCst);
-    VM_CNT.fetch_add(1, Ordering::SeqCst);
+    // Every CPU runs one vCPU of the same host VM.
+    let vm_id = HOST_VM_ID;
+    set_vm_state(vm_id, VmState::Creating);
 
     debug!("create vcpu {} for vm {}", hart_id, vm_id);
+    // The host Linux drives the whole PC.
+    device::set_vcpu_device_config(Some(device::VcpuDeviceConfig::pc()));
     let vcpu = new_vcpu(
         hart_id,
         crate::arch::cpu_vmcs_revision_id(),
@@ -82,27 +799,303 @@ pub fn config_boot_linux() {
     >::new(vcpus, Arc::new(ept), vm_id);
     // The bind_vcpu method should be decoupled with vm struct.
     vm.bind_vcpu(hart_id).expect("bind vcpu failed");
+    device::set_vcpu_device_config(None);
 
+    // Counted before any of them runs, so that the first one out does not take the VM down.
+    vcpu_entering(vm_id);
     INITED_CPUS.fetch_add(1, Ordering::SeqCst);
     while INITED_CPUS.load(Ordering::Acquire) < axconfig::SMP {
         core::hint::spin_loop();
     }
 
     debug!("CPU{} before run vcpu", hart_id);
+    set_current_vm(Some(vm_id));
+    set_vm_state(vm_id, VmState::Running);
     info!("{:?}", vm.run_type15_vcpu(hart_id, &linux_context));
+    if vcpu_left(vm_id) {
+        vm_exited(vm_id);
+    } else {
+        set_current_vm(None);
+    }
 
     // disable hardware virtualization todo
 }
 
-pub fn boot_vm(vm_id: usize) {
+/// What the APs of a VM share with its vCPU 0, from [`boot_vm`] to the end of the VM.
+struct SmpBoot {
+    npt: Arc<GuestPageTable>,
+    npt_root: HostPhysAddr,
+    /// APs taken by [`start_vcpu`] and not out of their run loop yet.
+    running: Arc<AtomicUsize>,
+}
+
+lazy_static! {
+    /// The VMs booted by [`boot_vm`] whose APs may start, by id.
+    static ref SMP_BOOTS: Mutex<HashMap<u32, SmpBoot>> = Mutex::new(HashMap::new());
+}
+
+/// Whether the APs of `vm_id` may still be started by [`start_vcpu`].
+pub(crate) fn smp_boot_pending(vm_id: u32) -> bool {
+    SMP_BOOTS.lock().contains_key(&vm_id)
+}
+
+/// The physical CPUs the vCPUs of a guest VM run on, from [`run_vm`] to the end of the VM.
+struct CpuPlacement {
+    /// The CPU of each vCPU, by vCPU id.
+    cpus: Vec<usize>,
+    /// No vCPU of another guest may run on `cpus`.
+    exclusive: bool,
+}
+
+lazy_static! {
+    /// The placements of the guest VMs booted and not stopped yet, by id.
+    static ref CPU_PLACEMENTS: Mutex<BTreeMap<u32, CpuPlacement>> = Mutex::new(BTreeMap::new());
+}
+
+/// Choose the physical CPUs of the vCPUs of VM `vm_id`, booted on the current CPU `hart_id`,
+/// and hold them until the VM stops.
+///
+/// The vCPUs pinned by the [`VcpuAffinity`](crate::VcpuAffinity) of the VM take their cores,
+/// vCPU 0 having to be pinned to the current one. Otherwise vCPU 0 runs on the current CPU and
+/// the APs on the first other cores of the cpu set which no other VM has dedicated to itself.
+/// Fails with `BadState` if a vCPU would share a CPU with another guest, either VM being
+/// exclusive.
+fn place_vcpus(cfg: &VMCfgEntry, vm_id: u32, hart_id: usize) -> Result<Vec<usize>> {
+    let affinity = cfg.vcpu_affinity();
+    let vcpu_count = cfg.get_vcpus();
+    let mut placements = CPU_PLACEMENTS.lock();
+    // The other VM holding `cpu` against a vCPU of this one, if any.
+    let holder = |cpu: usize, exclusive: bool| {
+        placements
+            .iter()
+            .filter(|&(&other, placement)| other != vm_id && (exclusive || placement.exclusive))
+            .find(|(_, placement)| placement.cpus.contains(&cpu))
+            .map(|(&other, _)| other)
+    };
+    let cpus: Vec<usize> = if affinity.cores.is_empty() {
+        let this_core = axhal::cpu_id_to_core_id(hart_id);
+        let ap_cpus = (0..usize::BITS as usize)
+            .filter(|&core| cfg.get_cpu_set() & (1 << core) != 0 && core != this_core)
+            .filter_map(axhal::core_id_to_cpu_id)
+            .filter(|&cpu| cpu < SMP && holder(cpu, false).is_none());
+        let cpus: Vec<usize> = core::iter::once(hart_id)
+            .chain(ap_cpus.take(vcpu_count - 1))
+            .collect();
+        if cpus.len() != vcpu_count {
+            warn!(
+                "VM {} has no {} other cores for its APs, boot vm failed",
+                vm_id,
+                vcpu_count - 1
+            );
+            return Err(Error::InvalidParam);
+        }
+        cpus
+    } else {
+        let cpus: Vec<usize> = affinity
+            .cores
+            .iter()
+            .filter_map(|&core| axhal::core_id_to_cpu_id(core))
+            .filter(|&cpu| cpu < SMP)
+            .collect();
+        if cpus.len() != affinity.cores.len() {
+            warn!(
+                "VM {} pinned to cores {:?}, some do not exist, boot vm failed",
+                vm_id, affinity.cores
+            );
+            return Err(Error::InvalidParam);
+        }
+        if cpus[0] != hart_id {
+            warn!(
+                "VM {} pins vCPU 0 to CPU {}, boot vm on CPU {} failed",
+                vm_id, cpus[0], hart_id
+            );
+            return Err(Error::InvalidParam);
+        }
+        cpus
+    };
+    for (vcpu_id, &cpu) in cpus.iter().enumerate() {
+        if let Some(other) = holder(cpu, affinity.exclusive) {
+            warn!(
+                "VM {} vCPU {} cannot share CPU {} with VM {}, boot vm failed",
+                vm_id, vcpu_id, cpu, other
+            );
+            return Err(Error::BadState);
+        }
+    }
+    placements.insert(
+        vm_id,
+        CpuPlacement {
+            cpus: cpus.clone(),
+            exclusive: affinity.exclusive,
+        },
+    );
+    Ok(cpus)
+}
+
+fn release_cpus(vm_id: u32) {
+    CPU_PLACEMENTS.lock().remove(&vm_id);
+}
+
+/// The devices of the vCPUs of the guest VM `cfg`, CPUID being filtered through the policy of
+/// its type unless it was configured one.
+fn guest_vcpu_devices(cfg: &VMCfgEntry) -> device::VcpuDeviceConfig {
+    let vm_type = cfg.get_vm_type();
+    cfg.vcpu_devices()
+        .clone()
+        .or_cpuid_policy(|| device::CpuidPolicy::for_vm_type(vm_type))
+}
+
+/// Create AP `vcpu_id` of the VM being booted by [`boot_vm`] on another CPU, and run it on the
+/// current CPU until the VM stops. The AP waits for its STARTUP IPI before entering the guest.
+pub(crate) fn start_vcpu(vm_id: u32, vcpu_id: u32) {
+    let hart_id = current_cpu_id();
+    let (npt, npt_root, running) = match SMP_BOOTS.lock().get(&vm_id) {
+        Some(boot) => {
+            boot.running.fetch_add(1, Ordering::AcqRel);
+            (boot.npt.clone(), boot.npt_root, boot.running.clone())
+        }
+        None => return,
+    };
+    let vm_cfg_entry = match vm_cfg_entry(vm_id as usize) {
+        Some(entry) => entry,
+        None => {
+            warn!("VM {} not existed, start vcpu {} failed", vm_id, vcpu_id);
+            running.fetch_sub(1, Ordering::AcqRel);
+            return;
+        }
+    };
+
+    debug!(
+        "create vcpu {} for vm {} on CPU {}",
+        vcpu_id, vm_id, hart_id
+    );
+    device::set_vcpu_device_config(Some(guest_vcpu_devices(&vm_cfg_entry)));
+    let vcpu = match VCpu::new(
+        vcpu_id as usize,
+        crate::arch::cpu_vmcs_revision_id(),
+        vm_cfg_entry.get_vm_entry(),
+        npt_root,
+    ) {
+        Ok(vcpu) => vcpu,
+        Err(err) => {
+            error!(
+                "VM [{}] failed to create vCPU {}: {:?}",
+                vm_id, vcpu_id, err
+            );
+            device::set_vcpu_device_config(None);
+            request_stop(vm_id, VmExit::Crashed);
+            running.fetch_sub(1, Ordering::AcqRel);
+            return;
+        }
+    };
+    let mut vcpus =
+        VmCpus::<HyperCraftHalImpl, X64VcpuDevices<HyperCraftHalImpl, BarAllocImpl>>::new();
+    vcpus.add_vcpu(vcpu).expect("add vcpu failed");
+
+    map_vcpu2pcpu(vm_id, vcpu_id, hart_id as u32);
+
+    let mut vm = VM::<
+        HyperCraftHalImpl,
+        X64VcpuDevices<HyperCraftHalImpl, BarAllocImpl>,
+        NimbosVmDevices<HyperCraftHalImpl, BarAllocImpl>,
+        GuestPageTable,
+    >::new(vcpus, npt, vm_id);
+    vm.bind_vcpu(vcpu_id as usize).expect("bind vcpu failed");
+    device::set_vcpu_device_config(None);
+
+    let interrupted_vm = current_vm_id();
+    set_current_vm(Some(vm_id));
+    info!(
+        "VM [{}] vCPU {}: {:?}",
+        vm_id,
+        vcpu_id,
+        vm.run_vcpu(vcpu_id as usize)
+    );
+    // The whole VM stops with any of its vCPUs.
+    let exit = match vm_state(vm_id) {
+        Some(VmState::Crashed) => VmExit::Crashed,
+        _ => VmExit::Stopped,
+    };
+    request_stop(vm_id, exit);
+    set_current_vm(interrupted_vm);
+    running.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Build the VM described by config entry `vm_id` and run its vCPU 0 on the current CPU.
+///
+/// The other vCPUs, the APs, are created on other cores of the cpu set of the VM, those they are
+/// pinned to if any, where they wait for the INIT-SIPI-SIPI sequence of vCPU 0, see
+/// [`start_vcpu`]. A VM pinning vCPU 0 to another core fails to boot here with `InvalidParam`.
+///
+/// Only the first boot request of a VM wins, later ones fail with `BadState` without touching
+/// it, until it is `Stopped`: it boots again then, from its images if [`crate::VmBuilder`] built
+/// it. Returns once every vCPU left its run loop, and the VM did not reboot, see [`reset_vm`].
+pub fn boot_vm(vm_id: usize) -> Result {
     let hart_id = current_cpu_id();
     let vm_cfg_entry = match vm_cfg_entry(vm_id) {
         Some(entry) => entry,
         None => {
             warn!("VM {} not existed, boot vm failed", vm_id);
-            return;
+            return Err(Error::InvalidParam);
+        }
+    };
+    let vm_id = vm_id as u32;
+
+    let restart = match compare_exchange_vm_state(vm_id, None, VmState::Creating) {
+        Ok(()) => false,
+        Err(Some(VmState::Stopped))
+            if compare_exchange_vm_state(vm_id, Some(VmState::Stopped), VmState::Creating)
+                .is_ok() =>
+        {
+            true
+        }
+        Err(state) => {
+            warn!("VM {} is already {:?}, boot vm failed", vm_id, state);
+            return Err(Error::BadState);
         }
     };
+    if let Err(err) = vm_cfg_entry.validate() {
+        warn!("VM {} invalid config, boot vm failed", vm_id);
+        set_vm_state(vm_id, VmState::Stopped);
+        return Err(err);
+    }
+    if restart {
+        // Why it stopped is forgotten, it would stop the new run at once.
+        VM_EXITS.lock().remove(&vm_id);
+        // The images of a VM loaded by the host are loaded by it again.
+        if !vm_cfg_entry.boot_segments().is_empty() {
+            if let Err(err) = crate::builder::reload_segments(&vm_cfg_entry) {
+                warn!("VM [{}] failed to reload its images: {:?}", vm_id, err);
+                set_vm_state(vm_id, VmState::Stopped);
+                return Err(err);
+            }
+        }
+        info!("VM [{}] booting again", vm_id);
+    }
+    while run_vm(&vm_cfg_entry, vm_id, hart_id)? {
+        // The vCPUs and devices are created again, the RAM was unmapped with the memory set.
+        if let Err(err) = crate::builder::reload_segments(&vm_cfg_entry) {
+            warn!("VM [{}] failed to reload its images: {:?}", vm_id, err);
+            VM_EXITS.lock().insert(vm_id, VmExit::Reset);
+            set_vm_state(vm_id, VmState::Stopped);
+            return Err(err);
+        }
+        info!("VM [{}] rebooting", vm_id);
+    }
+    Ok(())
+}
+
+/// Boot VM `vm_id`, in state `Creating`, on the current CPU `hart_id` and run it until every
+/// vCPU left its run loop. Returns whether it is to boot again.
+fn run_vm(vm_cfg_entry: &VMCfgEntry, vm_id: u32, hart_id: usize) -> Result<bool> {
+    let vcpu_count = vm_cfg_entry.get_vcpus();
+    let cpus = place_vcpus(vm_cfg_entry, vm_id, hart_id).map_err(|err| {
+        set_vm_state(vm_id, VmState::Stopped);
+        err
+    })?;
+    register_vm(vm_cfg_entry, &cpus);
+    // Before any vCPU creates its devices, the ports ignored by the previous run are forgotten.
+    device::reset_ignored_ports(vm_id);
 
     info!(
         "boot_vm {} {:?} on core {}, guest entry {:#x}",
@@ -114,24 +1107,62 @@ pub fn boot_vm(vm_id: usize) {
 
     let gpm = vm_cfg_entry
         .generate_guest_phys_memory_set()
-        .expect("Failed to generate GPM");
+        .map_err(|err| {
+            warn!("VM {} failed to generate GPM: {:?}", vm_id, err);
+            unregister_vm(vm_id);
+            release_cpus(vm_id);
+            set_vm_state(vm_id, VmState::Stopped);
+            err
+        })?;
 
-    let npt = gpm.nest_page_table();
+    let npt = Arc::new(gpm.nest_page_table());
     let npt_root = gpm.nest_page_table_root();
     info!("{:#x?}", gpm);
+    // The nested page faults on the RAM populated on demand or shared with a fork map it, until
+    // the VM exits.
+    crate::mm::register_guest_memory(vm_id, gpm);
+
+    let running_aps = Arc::new(AtomicUsize::new(0));
+    if vcpu_count > 1 {
+        SMP_BOOTS.lock().insert(
+            vm_id,
+            SmpBoot {
+                npt: npt.clone(),
+                npt_root,
+                running: running_aps.clone(),
+            },
+        );
+        // The APs of a fork or of a restored VM continue from where those they copy were.
+        device::register_aps(
+            vm_id,
+            (1..vcpu_count as u32).filter(|&ap| !device::has_pending_snapshot(vm_id, ap)),
+        );
+        for (ap, &cpu) in cpus.iter().enumerate().skip(1) {
+            let msg = crate::nmi::NmiMessage {
+                vm_id,
+                vcpu_id: ap as u32,
+                request: crate::nmi::NmiRequest::StartVcpu,
+            };
+            info!("VM [{}] starting vCPU {} on CPU {}", vm_id, ap, cpu);
+            crate::nmi::send_to_cpu(cpu, msg);
+        }
+    }
 
-    let vm_id = VM_CNT.load(Ordering::SeqCst);
-    VM_CNT.fetch_add(1, Ordering::SeqCst);
     let vcpu_id = 0;
     debug!("create vcpu {} for vm {}", vcpu_id, vm_id);
+    device::set_vcpu_device_config(Some(guest_vcpu_devices(vm_cfg_entry)));
     // Main scheduling item, managed by `axtask`
-    let vcpu = VCpu::new(
+    let mut vcpu = VCpu::new(
         vcpu_id,
         crate::arch::cpu_vmcs_revision_id(),
         vm_cfg_entry.get_vm_entry(),
         npt_root,
     )
     .unwrap();
+    // The command line of the guest image, see `crate::image`.
+    if let Some(cmdline_gpa) = vm_cfg_entry.cmdline_gpa() {
+        vcpu.regs_mut().rsi = cmdline_gpa as u64;
+    }
     let mut vcpus =
         VmCpus::<HyperCraftHalImpl, X64VcpuDevices<HyperCraftHalImpl, BarAllocImpl>>::new();
     vcpus.add_vcpu(vcpu).expect("add vcpu failed");
@@ -143,10 +1174,168 @@ pub fn boot_vm(vm_id: usize) {
         X64VcpuDevices<HyperCraftHalImpl, BarAllocImpl>,
         NimbosVmDevices<HyperCraftHalImpl, BarAllocImpl>,
         GuestPageTable,
-    >::new(vcpus, Arc::new(npt), vm_id);
+    >::new(vcpus, npt, vm_id);
     // The bind_vcpu method should be decoupled with vm struct.
     vm.bind_vcpu(vcpu_id).expect("bind vcpu failed");
+    device::set_vcpu_device_config(None);
 
     info!("Running guest...");
+    set_current_vm(Some(vm_id));
+    compare_exchange_vm_state(vm_id, Some(VmState::Creating), VmState::Running)
+        .expect("VM state changed while creating");
     info!("{:?}", vm.run_vcpu(0));
+    if SMP_BOOTS.lock().remove(&vm_id).is_some() {
+        let exit = match vm_state(vm_id) {
+            Some(VmState::Crashed) => VmExit::Crashed,
+            _ => VmExit::Stopped,
+        };
+        request_stop(vm_id, exit);
+        // The APs use the nested page table and the devices of the VM until they leave.
+        while running_aps.load(Ordering::Acquire) != 0 {
+            axtask::yield_now();
+        }
+    }
+    Ok(vm_exited(vm_id))
+}
+
+/// How long the task of [`spawn`] waits to run on the CPU vCPU 0 is pinned to.
+const SPAWN_PIN_TIMEOUT_NS: u64 = 1_000_000_000;
+
+/// A VM started by [`spawn`].
+pub struct VmJoinHandle {
+    vm_id: u32,
+    task: axtask::AxTaskRef,
+    /// Set by the task if the VM failed to boot, the error being logged.
+    boot_failed: Arc<AtomicBool>,
+}
+
+/// Build the VM described by config entry `vm_id` and run it in a new task, see [`boot_vm`].
+/// Returns at once.
+///
+/// `axtask` cannot bind a task to a CPU: the task of a VM pinning its vCPU 0 yields until the
+/// CPU of the vCPU picks it, for up to a second, the VM failing to boot otherwise. Once the vCPU
+/// runs, its task goes back to its CPU after each park, see [`crate::park`].
+pub fn spawn(vm_id: usize) -> Result<VmJoinHandle> {
+    let boot_cpu = match vm_cfg_entry(vm_id) {
+        Some(entry) => entry
+            .vcpu_affinity()
+            .cores
+            .first()
+            .and_then(|&core| axhal::core_id_to_cpu_id(core)),
+        None => {
+            warn!("VM {} not existed, spawn vm failed", vm_id);
+            return Err(Error::InvalidParam);
+        }
+    };
+    if let Some(state) = vm_state(vm_id as u32) {
+        warn!("VM {} is already {:?}, spawn vm failed", vm_id, state);
+        return Err(Error::BadState);
+    }
+    let boot_failed = Arc::new(AtomicBool::new(false));
+    let task_boot_failed = boot_failed.clone();
+    let task = axtask::spawn_raw(
+        move || {
+            if let Some(cpu) = boot_cpu {
+                let deadline_ns = axhal::time::current_time_nanos() + SPAWN_PIN_TIMEOUT_NS;
+                if !crate::park::move_to_cpu(cpu, Some(deadline_ns)) {
+                    warn!("VM {} failed to boot: CPU {} did not run it", vm_id, cpu);
+                    task_boot_failed.store(true, Ordering::Release);
+                    return;
+                }
+            }
+            if let Err(err) = boot_vm(vm_id) {
+                warn!("VM {} failed to boot: {:?}", vm_id, err);
+                task_boot_failed.store(true, Ordering::Release);
+            }
+        },
+        alloc::format!("vm-{}", vm_id),
+        axconfig::TASK_STACK_SIZE,
+    );
+    Ok(VmJoinHandle {
+        vm_id: vm_id as u32,
+        task,
+        boot_failed,
+    })
+}
+
+/// Build a VM from the guest image bundle at host physical address `paddr`, see
+/// [`crate::VmBuilder::load_bundle`], with `memory_mb` MiB of RAM populated on demand, and run it
+/// in a new task, see [`spawn`]. A bundle without a header holds the images of a `vm_type` guest.
+///
+/// The bundle is placed there beforehand, e.g. by the boot loader, and must stay there while the
+/// VM runs: a reset loads it again. Fails with `InvalidParam` if it is not within host RAM.
+#[cfg(target_arch = "x86_64")]
+pub fn boot_image(vm_type: VmType, paddr: HostPhysAddr, memory_mb: usize) -> Result<VmJoinHandle> {
+    use axhal::mem::{memory_regions, phys_to_virt, MemRegionFlags};
+    use memory_addr::PAGE_SIZE_4K;
+
+    let region_end = memory_regions()
+        .filter(|r| !r.flags.contains(MemRegionFlags::DEVICE))
+        .map(|r| (r.paddr.as_usize(), r.paddr.as_usize() + r.size))
+        .find(|&(start, end)| start <= paddr && paddr < end)
+        .map(|(_, end)| end)
+        .ok_or_else(|| {
+            warn!("guest image at {:#x} not in host RAM", paddr);
+            Error::InvalidParam
+        })?;
+    let ptr = phys_to_virt(paddr.into()).as_ptr();
+    // The header and the segment table, at most a page.
+    let prefix =
+        unsafe { core::slice::from_raw_parts(ptr, (region_end - paddr).min(PAGE_SIZE_4K)) };
+    let len = crate::GuestImageHeader::bundle_len(prefix, vm_type)?;
+    if len > region_end - paddr {
+        warn!(
+            "guest image at {:#x} ({:#x} bytes) past the end of host RAM",
+            paddr, len
+        );
+        return Err(Error::InvalidParam);
+    }
+    let bundle = unsafe { core::slice::from_raw_parts(ptr, len) };
+    let vm = crate::VmBuilder::new(&alloc::format!("image@{:#x}", paddr))
+        .vm_type(vm_type)
+        .memory_mb(memory_mb)
+        .memory_on_demand()
+        .load_bundle(bundle)
+        .build()?;
+    vm.spawn()
+}
+
+impl VmJoinHandle {
+    pub fn id(&self) -> u32 {
+        self.vm_id
+    }
+
+    /// The VM, once booted and until it stops, see [`find_vm`].
+    pub fn info(&self) -> Option<VmInfo> {
+        find_vm(self.vm_id)
+    }
+
+    /// Block until the VM has stopped, and return how. Fails if the VM could not boot.
+    pub fn wait(&self) -> Result<VmExit> {
+        self.task.join();
+        self.try_wait().unwrap_or(Err(Error::BadState))
+    }
+
+    /// How the VM stopped, `None` while it runs. Fails if the VM could not boot.
+    pub fn try_wait(&self) -> Option<Result<VmExit>> {
+        if self.boot_failed.load(Ordering::Acquire) {
+            return Some(Err(Error::BadState));
+        }
+        vm_exit(self.vm_id).map(Ok)
+    }
+
+    /// Pause the VM, see [`pause_vm`].
+    pub fn pause(&self) -> Result {
+        pause_vm(self.vm_id)
+    }
+
+    /// Resume the VM, see [`resume_vm`].
+    pub fn resume(&self) -> Result {
+        resume_vm(self.vm_id)
+    }
+
+    /// Stop the VM before the next VM entry of its vCPUs, without waiting for it.
+    pub fn kill(&self) -> Result {
+        kill_vm(self.vm_id)
+    }
 }
@@ -0,0 +1,204 @@
+//! The date and time of day, for the emulated RTCs.
+//!
+//! The hypervisor only has a monotonic clock, [`axhal::time::current_time_nanos`], so the wall
+//! clock is that clock plus the Unix time at which it started. The offset is read once from the
+//! RTC of the machine, the first time the wall clock is needed, or set with [`set_wall_clock`],
+//! e.g. by the host with `HVC_WALL_CLOCK_SET`, when it knows better: the host may be using the
+//! RTC at that moment, and a machine RTC is often in local time.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axhal::time::current_time_nanos;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: i64 = 86400;
+
+/// Unix time in nanoseconds at which the monotonic clock started, 0 until known.
+static BOOT_EPOCH_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Used when the RTC of the machine cannot be read: Saturday, January 1, 2000.
+const FALLBACK_UNIX_SECS: u64 = 946_684_800;
+
+/// Set the wall clock to `unix_ns` nanoseconds since the Unix epoch. The emulated RTCs keep the
+/// offset of their guest to it.
+pub fn set_wall_clock(unix_ns: u64) {
+    let epoch_ns = unix_ns.saturating_sub(current_time_nanos()).max(1);
+    BOOT_EPOCH_NS.store(epoch_ns, Ordering::Relaxed);
+    info!(
+        "wall clock set to {}",
+        DateTime::from_unix_secs((unix_ns / NANOS_PER_SEC) as i64)
+    );
+}
+
+/// Nanoseconds since the Unix epoch.
+pub fn wall_clock_ns() -> u64 {
+    boot_epoch_ns() + current_time_nanos()
+}
+
+/// Unix time in nanoseconds at which [`current_time_nanos`] was 0.
+pub(crate) fn boot_epoch_ns() -> u64 {
+    match BOOT_EPOCH_NS.load(Ordering::Relaxed) {
+        0 => {
+            let unix_secs = read_machine_rtc().unwrap_or_else(|| {
+                warn!("failed to read the RTC of the machine, the wall clock starts in 2000");
+                FALLBACK_UNIX_SECS
+            });
+            let epoch_ns = (unix_secs * NANOS_PER_SEC)
+                .saturating_sub(current_time_nanos())
+                .max(1);
+            // Another CPU, or `set_wall_clock`, may have been first.
+            match BOOT_EPOCH_NS.compare_exchange(0, epoch_ns, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => epoch_ns,
+                Err(current) => current,
+            }
+        }
+        epoch_ns => epoch_ns,
+    }
+}
+
+/// The Unix time in the MC146818 RTC of the machine, at CMOS ports 0x70 and 0x71, as UTC.
+fn read_machine_rtc() -> Option<u64> {
+    use x86::io::{inb, outb};
+
+    let read = |reg: u8| unsafe {
+        // Bit 7 clear, NMIs stay enabled.
+        outb(0x70, reg);
+        inb(0x71)
+    };
+    let read_time = || [0x00, 0x02, 0x04, 0x07, 0x08, 0x09, 0x32].map(read);
+    // Read twice outside of an update until both reads agree. An update takes about 2 ms, much
+    // less than the attempts.
+    let time = (0..10000).find_map(|_| {
+        // Update in progress.
+        if read(0x0a) & 0x80 != 0 {
+            return None;
+        }
+        let time = read_time();
+        (read(0x0a) & 0x80 == 0 && read_time() == time).then_some(time)
+    })?;
+    let status_b = read(0x0b);
+    let decode = |value: u8| match status_b & 0x04 {
+        0 => bcd_to_binary(value),
+        _ => value,
+    };
+    let [second, minute, hour, day, month, year, century] = time;
+    let hour = match status_b & 0x02 {
+        0 => decode(hour & 0x7f) % 12 + if hour & 0x80 != 0 { 12 } else { 0 },
+        _ => decode(hour),
+    };
+    // The century register is not standard, the 21st century is assumed without it.
+    let century = match decode(century) {
+        century @ 19..=21 => century as u16,
+        _ => 20,
+    };
+    let date = DateTime {
+        year: century * 100 + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    };
+    date.is_valid()
+        .then(|| date.to_unix_secs())
+        .filter(|&secs| secs > 0)
+        .map(|secs| secs as u64)
+}
+
+pub(crate) fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+/// A date and time of day, in the proleptic Gregorian calendar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The date `secs` seconds after the Unix epoch.
+    pub fn from_unix_secs(secs: i64) -> Self {
+        let days = secs.div_euclid(SECS_PER_DAY);
+        let time = secs.rem_euclid(SECS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year: year.clamp(0, u16::MAX as i64) as u16,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// Seconds since the Unix epoch. The day may be past the end of the month, it counts into
+    /// the next one.
+    pub fn to_unix_secs(&self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month.clamp(1, 12), self.day.max(1));
+        days * SECS_PER_DAY + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    /// Whether each field is in its range.
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && (1..=31).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Day of the week, 0 being Sunday.
+    pub fn weekday(&self) -> u8 {
+        // January 1, 1970 was a Thursday.
+        (self.to_unix_secs().div_euclid(SECS_PER_DAY) + 4).rem_euclid(7) as u8
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Days since the Unix epoch of a date, see http://howardhinnant.github.io/date_algorithms.html.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    // From March, so that the leap day is the last one.
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date of the day `days` days after the Unix epoch, the inverse of [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
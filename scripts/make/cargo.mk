@@ -36,6 +36,9 @@ ifeq ($(TYPE1_5), y)
   features-$(HV) += type1_5
 endif
 
+# The emulated devices replace the default ones of the hv app.
+features-$(HV) += $(HV_DEVICES)
+
 ifeq ($(ARCH), x86_64)
   features-$(HV) += libax/irq
 endif
@@ -46,6 +49,10 @@ endif
 
 default_features := y
 
+ifeq ($(HV), y)
+  default_features := n
+endif
+
 ifeq ($(APP_LANG),c)
   default_features := n
   ifneq ($(wildcard $(APP)/features.txt),)    # check features.txt exists